@@ -16,6 +16,12 @@ pub unsafe fn enable() {
     INTERRUPTS_ENABLED = true;
 }
 
+/// Check if interrupts are enabled
+#[inline(always)]
+pub fn enabled() -> bool {
+    unsafe { INTERRUPTS_ENABLED }
+}
+
 /// Halt instruction
 #[inline(always)]
 pub unsafe fn halt() {
@@ -41,3 +47,10 @@ pub unsafe fn enable_and_halt() {
     enable();
     halt();
 }
+
+pub mod level {
+    use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT};
+
+    pub static NESTED: AtomicUsize = ATOMIC_USIZE_INIT;
+    pub static DEFERRED: AtomicUsize = ATOMIC_USIZE_INIT;
+}
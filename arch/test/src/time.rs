@@ -5,3 +5,17 @@ pub fn monotonic() -> (u64, u64) {
 pub fn realtime() -> (u64, u64) {
     (0, 0)
 }
+
+pub const CLOCK_NAMES: [&'static str; 1] = ["none"];
+
+pub fn clock_source() -> usize {
+    0
+}
+
+pub fn clock_available(source: usize) -> bool {
+    source == 0
+}
+
+pub fn set_clock_source(source: usize) -> bool {
+    source == 0
+}
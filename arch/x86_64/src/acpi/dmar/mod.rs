@@ -2,8 +2,7 @@ use core::mem;
 
 use super::sdt::Sdt;
 use self::drhd::Drhd;
-use memory::Frame;
-use paging::{entry, ActivePageTable, PhysicalAddress};
+use paging::{entry, map_physical, ActivePageTable, PhysicalAddress};
 
 pub mod drhd;
 
@@ -59,8 +58,8 @@ pub struct DmarDrhd {
 
 impl DmarDrhd {
     pub fn get(&self, active_table: &mut ActivePageTable) -> &'static mut Drhd {
-        active_table.identity_map(Frame::containing_address(PhysicalAddress::new(self.base as usize)), entry::PRESENT | entry::WRITABLE | entry::NO_EXECUTE);
-        unsafe { &mut *(self.base as *mut Drhd) }
+        let address = map_physical(active_table, PhysicalAddress::new(self.base as usize), mem::size_of::<Drhd>(), entry::WRITABLE | entry::NO_CACHE);
+        unsafe { &mut *(address.get() as *mut Drhd) }
     }
 }
 
@@ -8,7 +8,7 @@ use device::local_apic::LOCAL_APIC;
 use interrupt;
 use memory::{allocate_frames, Frame};
 use paging::{entry, ActivePageTable, Page, PhysicalAddress, VirtualAddress};
-use start::{kstart_ap, CPU_COUNT, AP_READY};
+use start::{kstart_ap, CPU_COUNT, AP_READY, MAX_CPUS};
 
 use self::dmar::{Dmar, DmarEntry};
 use self::madt::{Madt, MadtEntry};
@@ -56,6 +56,8 @@ pub fn init_sdt(sdt: &'static Sdt, active_table: &mut ActivePageTable) {
             match madt_entry {
                 MadtEntry::LocalApic(ap_local_apic) => if ap_local_apic.id == me {
                     println!("        This is my local APIC");
+                } else if CPU_COUNT.load(Ordering::SeqCst) >= MAX_CPUS {
+                    println!("        CPU Skipped, limited to {} CPUs", MAX_CPUS);
                 } else {
                     if ap_local_apic.flags & 1 == 1 {
                         // Increase CPU ID
@@ -132,7 +134,7 @@ pub fn init_sdt(sdt: &'static Sdt, active_table: &mut ActivePageTable) {
         }
 
         // Unmap trampoline
-        active_table.unmap(trampoline_page);
+        active_table.unmap_return(trampoline_page);
         active_table.flush(trampoline_page);
     } else if let Some(dmar) = Dmar::new(sdt) {
         println!(": {}: {}", dmar.addr_width, dmar.flags);
@@ -194,7 +196,7 @@ pub unsafe fn init(active_table: &mut ActivePageTable) -> Option<Acpi> {
             drop(sdt);
             if mapped {
                 let sdt_page = Page::containing_address(VirtualAddress::new(sdt_address));
-                active_table.unmap(sdt_page);
+                active_table.unmap_return(sdt_page);
                 active_table.flush(sdt_page);
             }
         };
@@ -232,7 +234,7 @@ pub unsafe fn init(active_table: &mut ActivePageTable) -> Option<Acpi> {
         let end_frame = Frame::containing_address(PhysicalAddress::new(end_addr));
         for frame in Frame::range_inclusive(start_frame, end_frame) {
             let page = Page::containing_address(VirtualAddress::new(frame.start_address().get()));
-            active_table.unmap(page);
+            active_table.unmap_return(page);
             active_table.flush(page);
         }
     }
@@ -0,0 +1,64 @@
+//! Kernel command line
+//!
+//! The bootloader does not pass a command line, so it is read from the firmware configuration of
+//! QEMU, where `-append` puts it. It is empty on other machines. Options are separated by spaces
+
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+use core::{cmp, slice};
+
+use io::{Io, Pio};
+
+/// Longest command line that is kept, the rest is cut off
+pub const CMDLINE_MAX: usize = 4096;
+
+/// Firmware configuration selector and data ports
+const FW_CFG_SELECTOR: u16 = 0x510;
+const FW_CFG_DATA: u16 = 0x511;
+
+/// Firmware configuration keys
+const FW_CFG_SIGNATURE: u16 = 0x00;
+const FW_CFG_CMDLINE_SIZE: u16 = 0x14;
+const FW_CFG_CMDLINE_DATA: u16 = 0x15;
+
+/// The command line, written once by the BSP before other CPUs are started
+static mut CMDLINE: [u8; CMDLINE_MAX] = [0; CMDLINE_MAX];
+static CMDLINE_LEN: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Read an item of the firmware configuration
+unsafe fn fw_cfg_read(key: u16, buf: &mut [u8]) {
+    Pio::<u16>::new(FW_CFG_SELECTOR).write(key);
+    let data = Pio::<u8>::new(FW_CFG_DATA);
+    for b in buf.iter_mut() {
+        *b = data.read();
+    }
+}
+
+/// Read the command line, if the firmware configuration is found
+pub unsafe fn init() {
+    let mut signature = [0; 4];
+    fw_cfg_read(FW_CFG_SIGNATURE, &mut signature);
+    if &signature != b"QEMU" {
+        return;
+    }
+
+    let mut size = [0; 4];
+    fw_cfg_read(FW_CFG_CMDLINE_SIZE, &mut size);
+    let size = size[0] as usize | (size[1] as usize) << 8 | (size[2] as usize) << 16 | (size[3] as usize) << 24;
+
+    let len = cmp::min(size, CMDLINE_MAX);
+    fw_cfg_read(FW_CFG_CMDLINE_DATA, &mut CMDLINE[.. len]);
+
+    // The command line ends with a null byte
+    let len = CMDLINE[.. len].iter().position(|&b| b == 0).unwrap_or(len);
+    CMDLINE_LEN.store(len, Ordering::SeqCst);
+}
+
+/// The command line
+pub fn get() -> &'static [u8] {
+    unsafe { slice::from_raw_parts(CMDLINE.as_ptr(), CMDLINE_LEN.load(Ordering::SeqCst)) }
+}
+
+/// Check if the command line has the option `name`
+pub fn option(name: &str) -> bool {
+    get().split(|&b| b == b' ').any(|option| option == name.as_bytes())
+}
@@ -2,13 +2,189 @@ use core::fmt::{self, Write};
 use spin::Mutex;
 
 use device::serial::COM1;
+use pstore;
+use time;
 
-pub static CONSOLE: Mutex<Console> = Mutex::new(Console);
+pub static CONSOLE: Mutex<Console> = Mutex::new(Console::new());
 
-pub struct Console;
+/// Size of the kernel line buffer, longer lines are written in pieces
+const LINE_SIZE: usize = 256;
+
+/// Size of the kernel log, the oldest lines are overwritten when it is full
+pub const LOG_SIZE: usize = 65536;
+
+/// Importance of a kernel line, `println!` uses `Info`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug
+}
+
+impl Level {
+    fn tag(&self) -> &'static str {
+        match *self {
+            Level::Error => "error: ",
+            Level::Warn => "warn: ",
+            Level::Info => "",
+            Level::Debug => "debug: "
+        }
+    }
+}
+
+/// Writes `fmt::Arguments` into the log ring
+struct LogWriter<'a> {
+    log: &'a mut [u8; LOG_SIZE],
+    head: &'a mut usize
+}
+
+impl<'a> Write for LogWriter<'a> {
+    fn write_str(&mut self, s: &str) -> Result<(), fmt::Error> {
+        for &byte in s.as_bytes() {
+            self.log[*self.head % LOG_SIZE] = byte;
+            *self.head += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Arbitrates the console between the kernel log and userspace
+///
+/// Kernel output is line buffered, and a kernel line always starts on a fresh line, so that
+/// it is never interleaved with userspace output. Userspace output is written directly.
+/// Every kernel line is also kept in the log ring, with a timestamp, even while quiet
+pub struct Console {
+    /// Pending kernel output
+    line: [u8; LINE_SIZE],
+    /// Length of pending kernel output
+    len: usize,
+    /// Level of pending kernel output
+    level: Level,
+    /// Kernel lines, written round from `head`
+    log: [u8; LOG_SIZE],
+    /// Total bytes written to the log, the log holds the last `LOG_SIZE` of them
+    log_head: usize,
+    /// Pending kernel output continues a line that is already in the log
+    log_partial: bool,
+    /// Userspace has written a partial line
+    user_line: bool,
+    /// Kernel output is not written to the console
+    quiet: bool
+}
+
+impl Console {
+    const fn new() -> Console {
+        Console {
+            line: [0; LINE_SIZE],
+            len: 0,
+            level: Level::Info,
+            log: [0; LOG_SIZE],
+            log_head: 0,
+            log_partial: false,
+            user_line: false,
+            quiet: false
+        }
+    }
+
+    /// Suppress kernel output, used while a userspace process owns the console
+    pub fn set_quiet(&mut self, quiet: bool) {
+        self.quiet = quiet;
+    }
+
+    /// Check if kernel output is suppressed
+    pub fn quiet(&self) -> bool {
+        self.quiet
+    }
+
+    /// Set the level of the pending kernel line, used by `log!`
+    pub fn set_level(&mut self, level: Level) {
+        self.level = level;
+    }
+
+    /// The positions of the oldest and newest bytes of the log, counted from the first byte that
+    /// was ever logged
+    pub fn log_range(&self) -> (usize, usize) {
+        (self.log_head.saturating_sub(LOG_SIZE), self.log_head)
+    }
+
+    /// Read the log from a position in `log_range`, returning the number of bytes read
+    pub fn read_log(&self, position: usize, buf: &mut [u8]) -> usize {
+        let (start, end) = self.log_range();
+        let mut position = if position < start { start } else { position };
+        let mut i = 0;
+        while i < buf.len() && position < end {
+            buf[i] = self.log[position % LOG_SIZE];
+            i += 1;
+            position += 1;
+        }
+        i
+    }
+
+    /// Copy the whole log to the persistent log
+    pub fn persist_log(&self) {
+        let (start, end) = self.log_range();
+        pstore::save(&self.log, start, end);
+    }
+
+    /// Add pending kernel output to the log
+    fn log(&mut self) {
+        let start = self.log_head;
+        let complete = self.len > 0 && self.line[self.len - 1] == b'\n';
+        {
+            let mut writer = LogWriter {
+                log: &mut self.log,
+                head: &mut self.log_head
+            };
+            if ! self.log_partial {
+                let time = time::monotonic();
+                let _ = write!(writer, "[{:>5}.{:06}] {}", time.0, time.1 / 1000, self.level.tag());
+            }
+            for &byte in self.line[.. self.len].iter() {
+                writer.log[*writer.head % LOG_SIZE] = byte;
+                *writer.head += 1;
+            }
+        }
+        pstore::save(&self.log, start, self.log_head);
+        self.log_partial = ! complete;
+        if complete {
+            self.level = Level::Info;
+        }
+    }
+
+    /// Write output from userspace
+    pub fn write_user(&mut self, buf: &[u8]) {
+        if let Some(&last) = buf.last() {
+            COM1.lock().write_bytes(buf);
+            self.user_line = last != b'\n';
+        }
+    }
+
+    /// Write pending kernel output
+    fn flush(&mut self) {
+        self.log();
+        if ! self.quiet {
+            let mut serial = COM1.lock();
+            if self.user_line {
+                serial.write_bytes(b"\n");
+                self.user_line = false;
+            }
+            serial.write_bytes(&self.line[.. self.len]);
+        }
+        self.len = 0;
+    }
+}
 
 impl Write for Console {
     fn write_str(&mut self, s: &str) -> Result<(), fmt::Error> {
-        COM1.lock().write_str(s)
+        for &byte in s.as_bytes() {
+            self.line[self.len] = byte;
+            self.len += 1;
+            if byte == b'\n' || self.len >= self.line.len() {
+                self.flush();
+            }
+        }
+
+        Ok(())
     }
 }
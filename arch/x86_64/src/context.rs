@@ -53,6 +53,11 @@ impl Context {
         self.cr3
     }
 
+    /// The base pointer saved when the context was last switched away from
+    pub fn get_base_pointer(&self) -> usize {
+        self.rbp
+    }
+
     pub fn set_fx(&mut self, address: usize) {
         self.fx = address;
     }
@@ -4,6 +4,16 @@ use core::fmt::{Result, Write};
 
 use self::raw_cpuid::CpuId;
 
+/// Returns true if the CPU has the RDRAND instruction
+pub fn has_rdrand() -> bool {
+    CpuId::new().get_feature_info().map_or(false, |info| info.has_rdrand())
+}
+
+/// Returns true if the CPU has enhanced rep movsb and stosb
+pub fn has_erms() -> bool {
+    CpuId::new().get_extended_feature_info().map_or(false, |info| info.has_rep_movsb_stosb())
+}
+
 pub fn cpu_info<W: Write>(w: &mut W) -> Result {
     let cpuid = CpuId::new();
 
@@ -1,15 +1,40 @@
 use core::intrinsics::{volatile_load, volatile_store};
 use x86::cpuid::CpuId;
+use x86::io;
 use x86::msr::*;
 
-use memory::Frame;
-use paging::{entry, ActivePageTable, PhysicalAddress, Page, VirtualAddress};
+use paging::{entry, map_physical, ActivePageTable, PhysicalAddress};
 
 pub static mut LOCAL_APIC: LocalApic = LocalApic {
     address: 0,
-    x2: false
+    x2: false,
+    timer_rate: 0
 };
 
+/// Vector of the timer, at the clock level
+pub const TIMER_VECTOR: u8 = 0x30;
+
+/// Microseconds a context runs before the timer preempts it
+pub const TIMESLICE_US: u32 = 10000;
+
+/// Timer registers
+const LVT_TIMER: u32 = 0x320;
+const TIMER_INITIAL: u32 = 0x380;
+const TIMER_CURRENT: u32 = 0x390;
+const TIMER_DIVIDE: u32 = 0x3E0;
+
+/// The entry is masked
+const LVT_MASKED: u32 = 1 << 16;
+/// The timer reloads its initial count when it reaches zero
+const TIMER_PERIODIC: u32 = 1 << 17;
+/// The timer counts down once every 16 bus clocks
+const TIMER_DIVIDE_16: u32 = 0x3;
+
+/// PIT channel 2 is counted down for this long to calibrate the timer
+const CALIBRATE_MS: u32 = 10;
+/// Frequency of the PIT
+const PIT_HZ: u32 = 1193182;
+
 pub unsafe fn init(active_table: &mut ActivePageTable) {
     LOCAL_APIC.init(active_table);
 }
@@ -21,24 +46,34 @@ pub unsafe fn init_ap() {
 /// Local APIC
 pub struct LocalApic {
     pub address: usize,
-    pub x2: bool
+    pub x2: bool,
+    /// Timer ticks per millisecond, which is the same for every CPU
+    pub timer_rate: u32
 }
 
 impl LocalApic {
     unsafe fn init(&mut self, active_table: &mut ActivePageTable) {
-        self.address = (rdmsr(IA32_APIC_BASE) as usize & 0xFFFF0000) + ::KERNEL_OFFSET;
         self.x2 = CpuId::new().get_feature_info().unwrap().has_x2apic();
 
         if ! self.x2 {
-            let page = Page::containing_address(VirtualAddress::new(self.address));
-            let frame = Frame::containing_address(PhysicalAddress::new(self.address - ::KERNEL_OFFSET));
-            active_table.map_to(page, frame, entry::PRESENT | entry::WRITABLE | entry::NO_EXECUTE);
+            let address = PhysicalAddress::new(rdmsr(IA32_APIC_BASE) as usize & 0xFFFFF000);
+            self.address = map_physical(active_table, address, 4096, entry::WRITABLE | entry::NO_CACHE).get();
         }
 
-        self.init_ap();
+        self.enable();
+
+        self.timer_rate = self.calibrate();
+        println!("Local APIC timer: {} ticks per ms", self.timer_rate);
+
+        self.timer_periodic(TIMESLICE_US);
     }
 
     unsafe fn init_ap(&mut self) {
+        self.enable();
+        self.timer_periodic(TIMESLICE_US);
+    }
+
+    unsafe fn enable(&mut self) {
         if self.x2 {
             wrmsr(IA32_APIC_BASE, rdmsr(IA32_APIC_BASE) | 1 << 10);
             wrmsr(IA32_X2APIC_SIVR, 0x100);
@@ -55,6 +90,85 @@ impl LocalApic {
         volatile_store((self.address + reg as usize) as *mut u32, value);
     }
 
+    /// Read a register by its offset in the xAPIC page, which is read through its MSR in x2APIC mode
+    unsafe fn read_reg(&self, reg: u32) -> u32 {
+        if self.x2 {
+            rdmsr(0x800 + (reg >> 4)) as u32
+        } else {
+            self.read(reg)
+        }
+    }
+
+    /// Write a register by its offset in the xAPIC page, which is written through its MSR in x2APIC mode
+    unsafe fn write_reg(&mut self, reg: u32, value: u32) {
+        if self.x2 {
+            wrmsr(0x800 + (reg >> 4), value as u64);
+        } else {
+            self.write(reg, value);
+        }
+    }
+
+    /// Count the timer ticks in a millisecond, by letting it run while PIT channel 2 counts down.
+    /// The PIT is polled, as this runs before interrupts are enabled
+    unsafe fn calibrate(&mut self) -> u32 {
+        self.write_reg(TIMER_DIVIDE, TIMER_DIVIDE_16);
+        self.write_reg(LVT_TIMER, LVT_MASKED);
+
+        // Gate channel 2 on, with the speaker off
+        let port_b = io::inb(0x61);
+        io::outb(0x61, (port_b & !0x02) | 0x01);
+
+        // Channel 2, low then high byte, interrupt on terminal count
+        let count = PIT_HZ / 1000 * CALIBRATE_MS;
+        io::outb(0x43, 0xB0);
+        io::outb(0x42, count as u8);
+        io::outb(0x42, (count >> 8) as u8);
+
+        self.write_reg(TIMER_INITIAL, 0xFFFFFFFF);
+        // The output of channel 2 goes high when the count reaches zero
+        while io::inb(0x61) & 0x20 == 0 {}
+        let elapsed = 0xFFFFFFFF - self.read_reg(TIMER_CURRENT);
+        self.write_reg(TIMER_INITIAL, 0);
+
+        io::outb(0x61, port_b);
+
+        elapsed / CALIBRATE_MS
+    }
+
+    /// Ticks of the timer in `microseconds`, at least one
+    fn timer_ticks(&self, microseconds: u32) -> u32 {
+        let ticks = self.timer_rate as u64 * microseconds as u64 / 1000;
+        if ticks == 0 {
+            1
+        } else if ticks > 0xFFFFFFFF {
+            0xFFFFFFFF
+        } else {
+            ticks as u32
+        }
+    }
+
+    /// Interrupt this CPU every `microseconds`
+    pub unsafe fn timer_periodic(&mut self, microseconds: u32) {
+        let ticks = self.timer_ticks(microseconds);
+        self.write_reg(TIMER_DIVIDE, TIMER_DIVIDE_16);
+        self.write_reg(LVT_TIMER, TIMER_PERIODIC | TIMER_VECTOR as u32);
+        self.write_reg(TIMER_INITIAL, ticks);
+    }
+
+    /// Interrupt this CPU once, after `microseconds`
+    pub unsafe fn timer_oneshot(&mut self, microseconds: u32) {
+        let ticks = self.timer_ticks(microseconds);
+        self.write_reg(TIMER_DIVIDE, TIMER_DIVIDE_16);
+        self.write_reg(LVT_TIMER, TIMER_VECTOR as u32);
+        self.write_reg(TIMER_INITIAL, ticks);
+    }
+
+    /// Stop the timer of this CPU
+    pub unsafe fn timer_stop(&mut self) {
+        self.write_reg(LVT_TIMER, LVT_MASKED);
+        self.write_reg(TIMER_INITIAL, 0);
+    }
+
     pub fn id(&self) -> u32 {
         if self.x2 {
             unsafe { rdmsr(IA32_X2APIC_APICID) as u32 }
@@ -2,15 +2,18 @@ use paging::ActivePageTable;
 
 pub mod cpu;
 pub mod local_apic;
+pub mod pvclock;
 pub mod rtc;
 pub mod serial;
 
 pub unsafe fn init(active_table: &mut ActivePageTable){
     local_apic::init(active_table);
+    pvclock::init(active_table);
     rtc::init();
     serial::init();
 }
 
-pub unsafe fn init_ap() {
+pub unsafe fn init_ap(cpu_id: usize) {
     local_apic::init_ap();
+    pvclock::init_ap(cpu_id);
 }
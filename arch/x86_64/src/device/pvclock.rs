@@ -0,0 +1,339 @@
+//! Paravirtualized clocks, kvmclock and the Hyper-V reference TSC page
+
+use core::intrinsics::volatile_load;
+use core::sync::atomic::{fence, AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+use x86::msr::{rdmsr, wrmsr};
+
+use memory::{allocate_frames, Frame};
+use paging::{entry, ActivePageTable, PhysicalAddress, Page, VirtualAddress};
+use start::MAX_CPUS;
+
+/// Hypervisor vendor and maximum hypervisor leaf
+const CPUID_HYPERVISOR: u32 = 0x40000000;
+/// KVM features
+const CPUID_KVM_FEATURES: u32 = 0x40000001;
+/// Hyper-V features
+const CPUID_HYPERV_FEATURES: u32 = 0x40000003;
+/// TSC frequency in kHz, provided by KVM and VMware
+const CPUID_TIMING: u32 = 0x40000010;
+/// Advanced power management, for invariant TSC
+const CPUID_APM: u32 = 0x80000007;
+
+const KVM_FEATURE_CLOCKSOURCE: u32 = 1;
+const KVM_FEATURE_CLOCKSOURCE2: u32 = 1 << 3;
+const MSR_KVM_SYSTEM_TIME: u32 = 0x12;
+const MSR_KVM_SYSTEM_TIME_NEW: u32 = 0x4b564d01;
+
+/// The time is the same on all CPUs
+const PVCLOCK_TSC_STABLE: u8 = 1;
+
+const HV_FEATURE_TIME_REF_COUNT: u32 = 1 << 1;
+const HV_FEATURE_REFERENCE_TSC: u32 = 1 << 9;
+const HV_FEATURE_FREQUENCY: u32 = 1 << 11;
+const HV_MSR_TIME_REF_COUNT: u32 = 0x40000020;
+const HV_MSR_REFERENCE_TSC: u32 = 0x40000021;
+const HV_MSR_TSC_FREQUENCY: u32 = 0x40000022;
+
+/// Size of the kvmclock time information of each CPU
+const KVM_TIME_INFO_SIZE: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Hypervisor {
+    None,
+    Kvm,
+    HyperV,
+    Other
+}
+
+/// kvmclock time information, written by the hypervisor for each CPU
+#[repr(packed)]
+struct KvmTimeInfo {
+    version: u32,
+    _pad0: u32,
+    tsc_timestamp: u64,
+    system_time: u64,
+    tsc_to_system_mul: u32,
+    tsc_shift: i8,
+    flags: u8,
+    _pad: [u8; 2]
+}
+
+/// Hyper-V reference TSC page
+#[repr(packed)]
+struct HvReferenceTsc {
+    sequence: u32,
+    _reserved: u32,
+    scale: u64,
+    offset: i64
+}
+
+/// The MSR used for kvmclock, zero if not available
+static KVM_MSR: AtomicUsize = ATOMIC_USIZE_INIT;
+/// Physical address of the kvmclock time information, for all CPUs
+static KVM_INFO: AtomicUsize = ATOMIC_USIZE_INIT;
+/// Virtual address of the Hyper-V reference TSC page, zero if not available
+static HV_PAGE: AtomicUsize = ATOMIC_USIZE_INIT;
+/// Hyper-V features
+static HV_FEATURES: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Address of the kvmclock time information of this CPU
+#[thread_local]
+static mut KVM_CPU_INFO: usize = 0;
+
+unsafe fn cpuid(leaf: u32) -> (u32, u32, u32, u32) {
+    let eax: u32;
+    let ebx: u32;
+    let ecx: u32;
+    let edx: u32;
+    asm!("cpuid"
+        : "={eax}"(eax), "={ebx}"(ebx), "={ecx}"(ecx), "={edx}"(edx)
+        : "{eax}"(leaf), "{ecx}"(0)
+        :
+        : "intel", "volatile");
+    (eax, ebx, ecx, edx)
+}
+
+pub fn rdtsc() -> u64 {
+    let low: u32;
+    let high: u32;
+    unsafe { asm!("rdtsc" : "={eax}"(low), "={edx}"(high) : : : "intel", "volatile"); }
+    (high as u64) << 32 | low as u64
+}
+
+/// Multiply `delta`, shifted by `shift`, by the fraction `mul / 2^32`
+pub fn scale(delta: u64, mul: u32, shift: i8) -> u64 {
+    let delta = if shift < 0 {
+        delta >> -shift
+    } else {
+        delta << shift
+    };
+    ((delta & 0xFFFFFFFF) * mul as u64 >> 32) + (delta >> 32) * mul as u64
+}
+
+/// Find the multiplier and shift used by `scale` to convert ticks at `hz` to nanoseconds
+pub fn scale_for(hz: u64) -> (u32, i8) {
+    const NS: u64 = 1000000000;
+
+    let mut ticks = hz;
+    let mut shift = 0;
+    while ticks > 2 * NS {
+        ticks >>= 1;
+        shift -= 1;
+    }
+    while ticks <= NS {
+        ticks <<= 1;
+        shift += 1;
+    }
+
+    ((((NS << 32) / ticks) as u32), shift)
+}
+
+/// The high 64 bits of a 64 by 64 bit multiplication
+fn mul_high(a: u64, b: u64) -> u64 {
+    let (a_high, a_low) = (a >> 32, a & 0xFFFFFFFF);
+    let (b_high, b_low) = (b >> 32, b & 0xFFFFFFFF);
+
+    let low = a_low * b_low;
+    let middle_a = a_high * b_low;
+    let middle_b = a_low * b_high;
+    let carry = ((low >> 32) + (middle_a & 0xFFFFFFFF) + (middle_b & 0xFFFFFFFF)) >> 32;
+
+    a_high * b_high + (middle_a >> 32) + (middle_b >> 32) + carry
+}
+
+pub fn hypervisor() -> Hypervisor {
+    let (_eax, _ebx, ecx, _edx) = unsafe { cpuid(1) };
+    if ecx & 1 << 31 == 0 {
+        return Hypervisor::None;
+    }
+
+    let (_max, ebx, ecx, edx) = unsafe { cpuid(CPUID_HYPERVISOR) };
+    let mut vendor = [0; 12];
+    for i in 0..4 {
+        vendor[i] = (ebx >> (i * 8)) as u8;
+        vendor[i + 4] = (ecx >> (i * 8)) as u8;
+        vendor[i + 8] = (edx >> (i * 8)) as u8;
+    }
+
+    if &vendor == b"KVMKVMKVM\0\0\0" {
+        Hypervisor::Kvm
+    } else if &vendor == b"Microsoft Hv" {
+        Hypervisor::HyperV
+    } else {
+        Hypervisor::Other
+    }
+}
+
+/// Check if the TSC runs at a constant rate in all power states
+pub fn invariant_tsc() -> bool {
+    let (max, _ebx, _ecx, _edx) = unsafe { cpuid(0x80000000) };
+    if max < CPUID_APM {
+        return false;
+    }
+
+    let (_eax, _ebx, _ecx, edx) = unsafe { cpuid(CPUID_APM) };
+    edx & 1 << 8 != 0
+}
+
+/// The TSC frequency in Hz, if the hypervisor provides it
+pub fn tsc_hz() -> Option<u64> {
+    if HV_FEATURES.load(Ordering::SeqCst) as u32 & HV_FEATURE_FREQUENCY != 0 {
+        return Some(unsafe { rdmsr(HV_MSR_TSC_FREQUENCY) });
+    }
+
+    if hypervisor() != Hypervisor::None {
+        let (max, _ebx, _ecx, _edx) = unsafe { cpuid(CPUID_HYPERVISOR) };
+        if max >= CPUID_TIMING {
+            let (khz, _ebx, _ecx, _edx) = unsafe { cpuid(CPUID_TIMING) };
+            if khz > 0 {
+                return Some(khz as u64 * 1000);
+            }
+        }
+    }
+
+    // kvmclock gives the ratio of TSC ticks to nanoseconds
+    if let Some((mul, shift)) = kvm_scale() {
+        let hz = (1000000000u64 << 32) / mul as u64;
+        return Some(if shift < 0 {
+            hz << -shift
+        } else {
+            hz >> shift
+        });
+    }
+
+    None
+}
+
+pub unsafe fn init(active_table: &mut ActivePageTable) {
+    match hypervisor() {
+        Hypervisor::Kvm => {
+            let (features, _ebx, _ecx, _edx) = cpuid(CPUID_KVM_FEATURES);
+            let msr = if features & KVM_FEATURE_CLOCKSOURCE2 != 0 {
+                MSR_KVM_SYSTEM_TIME_NEW
+            } else if features & KVM_FEATURE_CLOCKSOURCE != 0 {
+                MSR_KVM_SYSTEM_TIME
+            } else {
+                return;
+            };
+
+            let pages = (MAX_CPUS * KVM_TIME_INFO_SIZE + 4095)/4096;
+            if let Some(frame) = allocate_frames(pages) {
+                let start = frame.start_address().get();
+                for i in 0..pages {
+                    let page = Page::containing_address(VirtualAddress::new(start + i * 4096 + ::KERNEL_OFFSET));
+                    let frame = Frame::containing_address(PhysicalAddress::new(start + i * 4096));
+                    active_table.map_to(page, frame, entry::PRESENT | entry::WRITABLE | entry::NO_EXECUTE);
+                    active_table.flush(page);
+                }
+
+                KVM_INFO.store(start, Ordering::SeqCst);
+                KVM_MSR.store(msr as usize, Ordering::SeqCst);
+
+                init_ap(0);
+            }
+        },
+        Hypervisor::HyperV => {
+            let (features, _ebx, _ecx, _edx) = cpuid(CPUID_HYPERV_FEATURES);
+            HV_FEATURES.store(features as usize, Ordering::SeqCst);
+
+            if features & HV_FEATURE_REFERENCE_TSC != 0 {
+                if let Some(frame) = allocate_frames(1) {
+                    let start = frame.start_address().get();
+                    let page = Page::containing_address(VirtualAddress::new(start + ::KERNEL_OFFSET));
+                    active_table.map_to(page, frame, entry::PRESENT | entry::NO_EXECUTE);
+                    active_table.flush(page);
+
+                    wrmsr(HV_MSR_REFERENCE_TSC, start as u64 | 1);
+                    HV_PAGE.store(start + ::KERNEL_OFFSET, Ordering::SeqCst);
+                }
+            }
+        },
+        _ => ()
+    }
+}
+
+/// Give the hypervisor the kvmclock time information of this CPU
+pub unsafe fn init_ap(cpu_id: usize) {
+    let msr = KVM_MSR.load(Ordering::SeqCst);
+    let info = KVM_INFO.load(Ordering::SeqCst);
+    if msr != 0 && info != 0 && cpu_id < MAX_CPUS {
+        let address = info + cpu_id * KVM_TIME_INFO_SIZE;
+        wrmsr(msr as u32, address as u64 | 1);
+        KVM_CPU_INFO = address + ::KERNEL_OFFSET;
+    }
+}
+
+/// Read the kvmclock time information of this CPU consistently
+fn kvm_read<T, F: Fn(&KvmTimeInfo) -> T>(f: F) -> Option<T> {
+    let address = unsafe { KVM_CPU_INFO };
+    if address == 0 {
+        return None;
+    }
+
+    let info = unsafe { &*(address as *const KvmTimeInfo) };
+    loop {
+        // An odd version means the hypervisor is updating the information
+        let version = unsafe { volatile_load(&info.version) };
+        if version & 1 == 1 {
+            continue;
+        }
+        fence(Ordering::SeqCst);
+
+        let value = f(info);
+
+        fence(Ordering::SeqCst);
+        if unsafe { volatile_load(&info.version) } == version {
+            return Some(value);
+        }
+    }
+}
+
+fn kvm_scale() -> Option<(u32, i8)> {
+    kvm_read(|info| unsafe { (volatile_load(&info.tsc_to_system_mul), volatile_load(&info.tsc_shift)) })
+}
+
+/// Nanoseconds from kvmclock
+pub fn kvm_nanoseconds() -> Option<u64> {
+    kvm_read(|info| unsafe {
+        let tsc = rdtsc();
+        let delta = tsc.wrapping_sub(volatile_load(&info.tsc_timestamp));
+        volatile_load(&info.system_time) + scale(delta, volatile_load(&info.tsc_to_system_mul), volatile_load(&info.tsc_shift))
+    })
+}
+
+/// Check if kvmclock gives the same time on all CPUs
+pub fn kvm_stable() -> bool {
+    kvm_read(|info| unsafe { volatile_load(&info.flags) } & PVCLOCK_TSC_STABLE == PVCLOCK_TSC_STABLE).unwrap_or(false)
+}
+
+/// Nanoseconds from the Hyper-V reference TSC page, or the reference counter if the page is not valid
+pub fn hyperv_nanoseconds() -> Option<u64> {
+    let address = HV_PAGE.load(Ordering::SeqCst);
+    if address != 0 {
+        let page = unsafe { &*(address as *const HvReferenceTsc) };
+        loop {
+            // A sequence of zero means the page must not be used
+            let sequence = unsafe { volatile_load(&page.sequence) };
+            if sequence == 0 {
+                break;
+            }
+            fence(Ordering::SeqCst);
+
+            let scale = unsafe { volatile_load(&page.scale) };
+            let offset = unsafe { volatile_load(&page.offset) };
+            let tsc = rdtsc();
+
+            fence(Ordering::SeqCst);
+            if unsafe { volatile_load(&page.sequence) } == sequence {
+                // The reference time is in units of 100 nanoseconds
+                return Some((mul_high(tsc, scale) as i64 + offset) as u64 * 100);
+            }
+        }
+    }
+
+    if HV_FEATURES.load(Ordering::SeqCst) as u32 & HV_FEATURE_TIME_REF_COUNT != 0 {
+        Some(unsafe { rdmsr(HV_MSR_TIME_REF_COUNT) } * 100)
+    } else {
+        None
+    }
+}
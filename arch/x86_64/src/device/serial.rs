@@ -11,6 +11,12 @@ pub unsafe fn init() {
     COM2.lock().init();
 }
 
+/// Baud rate the ports are set to
+const BAUD: u32 = 38400;
+
+/// Clock of the baud rate generator divided by 16, which the divisor divides
+const BAUD_BASE: u32 = 115200;
+
 bitflags! {
     /// Interrupt enable flags
     flags IntEnFlags: u8 {
@@ -22,11 +28,46 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// FIFO control flags
+    flags FifoCtrlFlags: u8 {
+        const FIFO_ENABLE = 1,
+        const CLEAR_RECEIVE = 1 << 1,
+        const CLEAR_TRANSMIT = 1 << 2,
+        // 3 selects the DMA mode, 4 and 5 are reserved
+        /// Interrupt when 14 bytes have been received
+        const TRIGGER_14 = 3 << 6,
+    }
+}
+
+bitflags! {
+    /// Line control flags
+    flags LineCtrlFlags: u8 {
+        /// 8 data bits, with 1 stop bit and no parity as the other bits are clear
+        const DATA_8 = 3,
+        // 2 selects 2 stop bits, 3 to 5 select the parity, 6 sends a break
+        /// The data and interrupt enable registers become the divisor latch
+        const DLAB = 1 << 7,
+    }
+}
+
+bitflags! {
+    /// Modem control flags
+    flags ModemCtrlFlags: u8 {
+        const DTR = 1,
+        const RTS = 1 << 1,
+        const OUT1 = 1 << 2,
+        /// Enables the interrupt line on PCs
+        const OUT2 = 1 << 3,
+        const LOOPBACK = 1 << 4,
+    }
+}
+
 bitflags! {
     /// Line status flags
     flags LineStsFlags: u8 {
         const INPUT_FULL = 1,
-        // 1 to 4 unknown
+        // 1 to 4 are errors and breaks
         const OUTPUT_EMPTY = 1 << 5,
         // 6 and 7 unknown
     }
@@ -48,6 +89,10 @@ pub struct SerialPort {
     line_sts: ReadOnly<Pio<u8>>,
     /// Modem status
     modem_sts: ReadOnly<Pio<u8>>,
+    /// Scratch, which has no effect
+    scratch: Pio<u8>,
+    /// A UART answered at this address when initialized
+    present: bool
 }
 
 impl SerialPort {
@@ -59,7 +104,9 @@ impl SerialPort {
             line_ctrl: Pio::new(base + 3),
             modem_ctrl: Pio::new(base + 4),
             line_sts: ReadOnly::new(Pio::new(base + 5)),
-            modem_sts: ReadOnly::new(Pio::new(base + 6))
+            modem_sts: ReadOnly::new(Pio::new(base + 6)),
+            scratch: Pio::new(base + 7),
+            present: true
         }
     }
 
@@ -68,8 +115,10 @@ impl SerialPort {
     }
 
     fn write(&mut self, data: u8) {
-        while ! self.line_sts().contains(OUTPUT_EMPTY) {}
-        self.data.write(data)
+        if self.present {
+            while ! self.line_sts().contains(OUTPUT_EMPTY) {}
+            self.data.write(data)
+        }
     }
 
     fn write_translate(&mut self, data: u8) {
@@ -88,16 +137,45 @@ impl SerialPort {
         }
     }
 
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes.iter() {
+            self.write_translate(byte);
+        }
+    }
+
+    /// Check that a UART is at this address, using the scratch register and a loopback test
+    fn probe(&mut self) -> bool {
+        self.scratch.write(0x5A);
+        if self.scratch.read() != 0x5A {
+            return false;
+        }
+
+        self.modem_ctrl.write((LOOPBACK | RTS | DTR | OUT1 | OUT2).bits());
+        self.data.write(0xAE);
+        let echoed = self.data.read() == 0xAE;
+        self.modem_ctrl.write(0);
+
+        echoed
+    }
+
+    /// Set up the UART as 8N1 at `BAUD`, with FIFOs and an interrupt for received data
     fn init(&mut self) {
-        //TODO: Cleanup
-        self.int_en.write(0x00);
-        self.line_ctrl.write(0x80);
-        self.data.write(0x03);
-        self.int_en.write(0x00);
-        self.line_ctrl.write(0x03);
-        self.fifo_ctrl.write(0xC7);
-        self.modem_ctrl.write(0x0B);
-        self.int_en.write(0x01);
+        self.int_en.write(0);
+
+        self.present = self.probe();
+        if ! self.present {
+            return;
+        }
+
+        let divisor = (BAUD_BASE / BAUD) as u16;
+        self.line_ctrl.write(DLAB.bits());
+        self.data.write(divisor as u8);
+        self.int_en.write((divisor >> 8) as u8);
+        self.line_ctrl.write(DATA_8.bits());
+
+        self.fifo_ctrl.write((FIFO_ENABLE | CLEAR_RECEIVE | CLEAR_TRANSMIT | TRIGGER_14).bits());
+        self.modem_ctrl.write((DTR | RTS | OUT2).bits());
+        self.int_en.write(RECEIVED.bits());
     }
 
     pub fn on_receive(&mut self) {
@@ -113,9 +191,7 @@ impl SerialPort {
 
 impl Write for SerialPort {
     fn write_str(&mut self, s: &str) -> Result<(), fmt::Error> {
-        for byte in s.bytes() {
-            self.write_translate(byte);
-        }
+        self.write_bytes(s.as_bytes());
 
         Ok(())
     }
@@ -1,13 +1,54 @@
+use core::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
+
+use device::cpu;
+
+/// Set if the CPU has enhanced rep movsb and stosb (ERMS), which then beat copying by quadwords.
+/// The string instructions are used rather than SSE or AVX, as the kernel does not save the
+/// vector registers of usermode on entry, and AVX state is not enabled
+static ERMS: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Select the implementation of the memory routines for this CPU, called at boot
+pub fn init() {
+    ERMS.store(cpu::has_erms(), Ordering::SeqCst);
+}
+
+/// Returns true if the memory routines use enhanced rep movsb and stosb
+pub fn erms() -> bool {
+    ERMS.load(Ordering::Relaxed)
+}
+
+#[inline(always)]
+unsafe fn copy_bytes(dest: usize, src: usize, n: usize) {
+    asm!("cld
+        rep movsb"
+        :
+        : "{rdi}"(dest), "{rsi}"(src), "{rcx}"(n)
+        : "cc", "memory", "rdi", "rsi", "rcx"
+        : "intel", "volatile");
+}
+
+#[inline(always)]
+unsafe fn copy_qwords(dest: usize, src: usize, n: usize) {
+    asm!("cld
+        rep movsq"
+        :
+        : "{rdi}"(dest), "{rsi}"(src), "{rcx}"(n)
+        : "cc", "memory", "rdi", "rsi", "rcx"
+        : "intel", "volatile");
+}
+
 /// Memcpy
 ///
 /// Copy N bytes of memory from one location to another.
 #[no_mangle]
 pub unsafe extern fn memcpy(dest: *mut u8, src: *const u8,
                             n: usize) -> *mut u8 {
-    let mut i = 0;
-    while i < n {
-        *((dest as usize + i) as *mut u8) = *((src as usize + i) as *const u8);
-        i += 1;
+    if erms() {
+        copy_bytes(dest as usize, src as usize, n);
+    } else {
+        let qwords = n / 8;
+        copy_qwords(dest as usize, src as usize, qwords);
+        copy_bytes(dest as usize + qwords * 8, src as usize + qwords * 8, n % 8);
     }
 
     dest
@@ -19,18 +60,19 @@ pub unsafe extern fn memcpy(dest: *mut u8, src: *const u8,
 #[no_mangle]
 pub unsafe extern fn memmove(dest: *mut u8, src: *const u8,
                              n: usize) -> *mut u8 {
-    if src < dest as *const u8 {
-        let mut i = n;
-        while i != 0 {
-            i -= 1;
-            *((dest as usize + i) as *mut u8) = *((src as usize + i) as *const u8);
+    if src < dest as *const u8 && (src as usize) + n > dest as usize {
+        // Copy backwards, so that the end of src is read before it is overwritten
+        if n > 0 {
+            asm!("std
+                rep movsb
+                cld"
+                :
+                : "{rdi}"(dest as usize + n - 1), "{rsi}"(src as usize + n - 1), "{rcx}"(n)
+                : "cc", "memory", "rdi", "rsi", "rcx"
+                : "intel", "volatile");
         }
     } else {
-        let mut i = 0;
-        while i < n {
-            *((dest as usize + i) as *mut u8) = *((src as usize + i) as *const u8);
-            i += 1;
-        }
+        memcpy(dest, src, n);
     }
 
     dest
@@ -41,10 +83,27 @@ pub unsafe extern fn memmove(dest: *mut u8, src: *const u8,
 /// Fill a block of memory with a specified value.
 #[no_mangle]
 pub unsafe extern fn memset(dest: *mut u8, c: i32, n: usize) -> *mut u8 {
-    let mut i = 0;
-    while i < n {
-        *((dest as usize + i) as *mut u8) = c as u8;
-        i += 1;
+    if erms() {
+        asm!("cld
+            rep stosb"
+            :
+            : "{rdi}"(dest as usize), "{al}"(c as u8), "{rcx}"(n)
+            : "cc", "memory", "rdi", "rcx"
+            : "intel", "volatile");
+    } else {
+        let qwords = n / 8;
+        asm!("cld
+            rep stosq"
+            :
+            : "{rdi}"(dest as usize), "{rax}"((c as u8 as u64) * 0x0101010101010101), "{rcx}"(qwords)
+            : "cc", "memory", "rdi", "rcx"
+            : "intel", "volatile");
+        asm!("cld
+            rep stosb"
+            :
+            : "{rdi}"(dest as usize + qwords * 8), "{al}"(c as u8), "{rcx}"(n % 8)
+            : "cc", "memory", "rdi", "rcx"
+            : "intel", "volatile");
     }
 
     dest
@@ -57,6 +116,11 @@ pub unsafe extern fn memset(dest: *mut u8, c: i32, n: usize) -> *mut u8 {
 pub unsafe extern fn memcmp(s1: *const u8, s2: *const u8, n: usize) -> i32 {
     let mut i = 0;
 
+    // Skip equal quadwords, then find the first differing byte
+    while i + 8 <= n && *((s1 as usize + i) as *const u64) == *((s2 as usize + i) as *const u64) {
+        i += 8;
+    }
+
     while i < n {
         let a = *((s1 as usize + i) as *const u8);
         let b = *((s2 as usize + i) as *const u8);
@@ -33,6 +33,14 @@ pub const GDT_F_PAGE_SIZE: u8 = 1 << 7;
 pub const GDT_F_PROTECTED_MODE: u8 = 1 << 6;
 pub const GDT_F_LONG_MODE: u8 = 1 << 5;
 
+/// Size of the I/O permission bitmap, with one bit for each port. A set bit denies access
+pub const IO_BITMAP_SIZE: usize = 65536/8;
+
+/// Interrupt stack table entry used by the double fault handler
+pub const IST_DOUBLE_FAULT: usize = 1;
+/// Size of the stack used by the double fault handler
+pub const DOUBLE_FAULT_STACK_SIZE: usize = 16384;
+
 static mut INIT_GDTR: DescriptorTablePointer = DescriptorTablePointer {
     limit: 0,
     base: 0
@@ -77,17 +85,52 @@ pub static mut GDT: [GdtEntry; 9] = [
     GdtEntry::new(0, 0, 0, 0),
 ];
 
+/// Task state segment, followed by the I/O permission bitmap
+#[repr(packed)]
+pub struct TaskState {
+    pub tss: TaskStateSegment,
+    /// The bitmap must be followed by a byte with all bits set
+    pub io_bitmap: [u8; IO_BITMAP_SIZE + 1]
+}
+
 #[thread_local]
-pub static mut TSS: TaskStateSegment = TaskStateSegment {
-    reserved: 0,
-    rsp: [0; 3],
-    reserved2: 0,
-    ist: [0; 7],
-    reserved3: 0,
-    reserved4: 0,
-    iomap_base: 0xFFFF
+pub static mut TSS: TaskState = TaskState {
+    tss: TaskStateSegment {
+        reserved: 0,
+        rsp: [0; 3],
+        reserved2: 0,
+        ist: [0; 7],
+        reserved3: 0,
+        reserved4: 0,
+        iomap_base: 0xFFFF
+    },
+    io_bitmap: [0xFF; IO_BITMAP_SIZE + 1]
 };
 
+/// A double fault may be caused by an overflow of the kernel stack, so its handler needs its own
+#[thread_local]
+static mut DOUBLE_FAULT_STACK: [u8; DOUBLE_FAULT_STACK_SIZE] = [0; DOUBLE_FAULT_STACK_SIZE];
+
+/// The I/O permission bitmap in the TSS allows some ports
+#[thread_local]
+static mut IO_BITMAP_LOADED: bool = false;
+
+/// Load the I/O permission bitmap of the next context, or deny all ports if it has none
+pub unsafe fn set_io_bitmap(bitmap: Option<&[u8]>) {
+    if let Some(bitmap) = bitmap {
+        for (dst, src) in TSS.io_bitmap[.. IO_BITMAP_SIZE].iter_mut().zip(bitmap.iter()) {
+            *dst = *src;
+        }
+        IO_BITMAP_LOADED = true;
+    } else if IO_BITMAP_LOADED {
+        // Most contexts have no port access, so the bitmap is only cleared when needed
+        for dst in TSS.io_bitmap[.. IO_BITMAP_SIZE].iter_mut() {
+            *dst = 0xFF;
+        }
+        IO_BITMAP_LOADED = false;
+    }
+}
+
 /// Initialize GDT
 pub unsafe fn init(tcb_offset: usize, stack_offset: usize) {
     // Setup the initial GDT with TLS, so we can setup the TLS GDT (a little confusing)
@@ -121,10 +164,16 @@ pub unsafe fn init(tcb_offset: usize, stack_offset: usize) {
 
     // We can now access our TSS, which is a thread local
     GDT[GDT_TSS].set_offset(&TSS as *const _ as u32);
-    GDT[GDT_TSS].set_limit(mem::size_of::<TaskStateSegment>() as u32);
+    GDT[GDT_TSS].set_limit(mem::size_of::<TaskState>() as u32);
+
+    // User port access is checked against the I/O permission bitmap
+    TSS.tss.iomap_base = mem::size_of::<TaskStateSegment>() as u16;
 
     // Set the stack pointer when coming back from userspace
-    TSS.rsp[0] = stack_offset as u64;
+    TSS.tss.rsp[0] = stack_offset as u64;
+
+    // Set the stack pointer for double faults
+    TSS.tss.ist[IST_DOUBLE_FAULT - 1] = (DOUBLE_FAULT_STACK.as_ptr() as usize + DOUBLE_FAULT_STACK_SIZE) as u64;
 
     // Load the new GDT, which is correctly located in thread local storage
     dtables::lgdt(&GDTR);
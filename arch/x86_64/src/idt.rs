@@ -1,6 +1,7 @@
 use core::mem;
 use x86::dtables::{self, DescriptorTablePointer};
 
+use gdt::IST_DOUBLE_FAULT;
 use interrupt::*;
 
 pub static mut IDTR: DescriptorTablePointer = DescriptorTablePointer {
@@ -24,6 +25,7 @@ pub unsafe fn init() {
     IDT[6].set_func(exception::invalid_opcode);
     IDT[7].set_func(exception::device_not_available);
     IDT[8].set_func(exception::double_fault);
+    IDT[8].set_ist(IST_DOUBLE_FAULT as u8);
     // 9 no longer available
     IDT[10].set_func(exception::invalid_tss);
     IDT[11].set_func(exception::segment_not_present);
@@ -58,7 +60,10 @@ pub unsafe fn init() {
     IDT[46].set_func(irq::ata1);
     IDT[47].set_func(irq::ata2);
 
-    // Set IPI handler (null)
+    // Set local APIC timer handler, which preempts userspace
+    IDT[0x30].set_func(timer::timer);
+
+    // Set IPI handler, which reschedules
     IDT[0x40].set_func(ipi::ipi);
 
     // Set syscall function
@@ -134,6 +139,11 @@ impl IdtEntry {
         self.offseth = (base >> 32) as u32;
     }
 
+    /// Switch to a stack from the interrupt stack table of the TSS, from 1 to 7, or 0 for none
+    pub fn set_ist(&mut self, ist: u8) {
+        self.zero = ist & 0x7;
+    }
+
     // A function to set the offset more easily
     pub fn set_func(&mut self, func: unsafe extern fn()) {
         self.set_flags(IDT_PRESENT | IDT_RING_0 | IDT_INTERRUPT);
@@ -1,21 +1,67 @@
-use super::{halt, stack_trace};
+use super::{halt, probe, stack_trace};
 
 use syscall::flag::*;
 
 extern {
     fn ksignal(signal: usize);
+    fn kcopy_on_write(address: usize, user: bool) -> bool;
+    fn kcrash(signal: usize, regs: &[usize; 27]) -> !;
+    fn kcontext_id() -> usize;
+    fn kuser_return();
+}
+
+/// Check if an address is in the guard of a kernel stack. Stacks are always mapped, so the only
+/// faults in their area are in the guards below them
+fn kernel_stack_guard(address: usize) -> bool {
+    address >= ::KERNEL_STACK_OFFSET && address < ::KERNEL_STACK_OFFSET + ::KERNEL_STACK_SIZE
+}
+
+/// Check if an address is in the guard below the user stack area, or in the area. Nothing but the
+/// stack, which starts at a random page, is mapped in the area
+fn user_stack_guard(address: usize) -> bool {
+    address >= ::USER_STACK_OFFSET - ::USER_STACK_GUARD_SIZE && address < ::USER_STACK_OFFSET + ::PML4_SIZE
+}
+
+/// If the fault came from userspace, kill the current context with `signal`, writing a core dump
+/// with registers in the order used by x86_64 ELF core files. Faults in the kernel are not handled
+macro_rules! user_fault {
+    ($stack:ident, $signal:expr) => {
+        if $stack.cs & 3 == 3 {
+            // The user stack pointer and segment are pushed by the CPU above the flags
+            let cpu_stack = &$stack.rflags as *const usize;
+            let regs = [
+                $stack.r15, $stack.r14, $stack.r13, $stack.r12, $stack.rbp, $stack.rbx,
+                $stack.r11, $stack.r10, $stack.r9, $stack.r8,
+                $stack.rax, $stack.rcx, $stack.rdx, $stack.rsi, $stack.rdi,
+                // orig_rax, which is -1 outside of a syscall
+                !0,
+                $stack.rip, $stack.cs, $stack.rflags,
+                *cpu_stack.offset(1), *cpu_stack.offset(2),
+                0, 0,
+                0, 0, $stack.fs, 0
+            ];
+            kcrash($signal, &regs);
+        }
+    };
 }
 
 interrupt_stack!(divide_by_zero, stack, {
     println!("Divide by zero fault at {:>02X}:{:>016X}", stack.cs, stack.rip);
     ksignal(SIGFPE);
+    user_fault!(stack, SIGFPE);
     stack_trace();
     loop { halt(); }
 });
 
 interrupt_stack!(debug, stack, {
+    if probe::step(stack) {
+        return;
+    }
     println!("Debug trap at {:>02X}:{:>016X}", stack.cs, stack.rip);
     ksignal(SIGTRAP);
+    if stack.cs & 3 == 3 {
+        kuser_return();
+    }
 });
 
 interrupt_stack!(non_maskable, stack, {
@@ -23,8 +69,14 @@ interrupt_stack!(non_maskable, stack, {
 });
 
 interrupt_stack!(breakpoint, stack, {
+    if stack.cs & 3 == 0 && probe::hit(stack) {
+        return;
+    }
     println!("Breakpoint trap at {:>02X}:{:>016X}", stack.cs, stack.rip);
     ksignal(SIGTRAP);
+    if stack.cs & 3 == 3 {
+        kuser_return();
+    }
 });
 
 interrupt_stack!(overflow, stack, {
@@ -35,6 +87,7 @@ interrupt_stack!(overflow, stack, {
 interrupt_stack!(bound_range, stack, {
     println!("Bound range exceeded fault at {:>02X}:{:>016X}", stack.cs, stack.rip);
     ksignal(SIGSEGV);
+    user_fault!(stack, SIGSEGV);
     stack_trace();
     loop { halt(); }
 });
@@ -42,6 +95,7 @@ interrupt_stack!(bound_range, stack, {
 interrupt_stack!(invalid_opcode, stack, {
     println!("Invalid opcode fault at {:>02X}:{:>016X}", stack.cs, stack.rip);
     ksignal(SIGILL);
+    user_fault!(stack, SIGILL);
     stack_trace();
     loop { halt(); }
 });
@@ -49,12 +103,21 @@ interrupt_stack!(invalid_opcode, stack, {
 interrupt_stack!(device_not_available, stack, {
     println!("Device not available fault at {:>02X}:{:>016X}", stack.cs, stack.rip);
     ksignal(SIGILL);
+    user_fault!(stack, SIGILL);
     stack_trace();
     loop { halt(); }
 });
 
 interrupt_error!(double_fault, stack, {
-    println!("Double fault: {:X} at {:>02X}:{:>016X}", stack.code, stack.cs, stack.rip);
+    let cr2: usize;
+    asm!("mov rax, cr2" : "={rax}"(cr2) : : : "intel", "volatile");
+
+    // Overflowing the kernel stack faults, and the fault cannot be handled on the same stack
+    if kernel_stack_guard(cr2) {
+        println!("Kernel stack overflow in context {} at {:>02X}:{:>016X}", kcontext_id(), stack.cs, stack.rip);
+    } else {
+        println!("Double fault: {:X} at {:>02X}:{:>016X}", stack.code, stack.cs, stack.rip);
+    }
     ksignal(SIGSEGV);
     stack_trace();
     loop { halt(); }
@@ -70,6 +133,7 @@ interrupt_error!(invalid_tss, stack, {
 interrupt_error!(segment_not_present, stack, {
     println!("Segment not present fault: {:X} at {:>02X}:{:>016X}", stack.code, stack.cs, stack.rip);
     ksignal(SIGSEGV);
+    user_fault!(stack, SIGSEGV);
     stack_trace();
     loop { halt(); }
 });
@@ -77,6 +141,7 @@ interrupt_error!(segment_not_present, stack, {
 interrupt_error!(stack_segment, stack, {
     println!("Stack segment fault: {:X} at {:>02X}:{:>016X}", stack.code, stack.cs, stack.rip);
     ksignal(SIGSEGV);
+    user_fault!(stack, SIGSEGV);
     stack_trace();
     loop { halt(); }
 });
@@ -84,6 +149,7 @@ interrupt_error!(stack_segment, stack, {
 interrupt_error!(protection, stack, {
     println!("Protection fault: {:X} at {:>02X}:{:>016X}", stack.code, stack.cs, stack.rip);
     ksignal(SIGSEGV);
+    user_fault!(stack, SIGSEGV);
     stack_trace();
     loop { halt(); }
 });
@@ -91,8 +157,24 @@ interrupt_error!(protection, stack, {
 interrupt_error!(page, stack, {
     let cr2: usize;
     asm!("mov rax, cr2" : "={rax}"(cr2) : : : "intel", "volatile");
-    println!("Page fault: {:>02X}:{:>016X} at {:>02X}:{:>016X}", stack.code, cr2, stack.cs, stack.rip);
+
+    // A write to a present page may be resolved by the kernel, if the page is copy-on-write
+    if stack.code & 0b11 == 0b11 && kcopy_on_write(cr2, stack.cs & 3 == 3) {
+        if stack.cs & 3 == 3 {
+            kuser_return();
+        }
+        return;
+    }
+
+    if stack.cs & 3 == 0 && kernel_stack_guard(cr2) {
+        println!("Kernel stack overflow in context {} at {:>02X}:{:>016X}", kcontext_id(), stack.cs, stack.rip);
+    } else if stack.cs & 3 == 3 && user_stack_guard(cr2) {
+        println!("Stack overflow in context {} at {:>02X}:{:>016X}", kcontext_id(), stack.cs, stack.rip);
+    } else {
+        println!("Page fault: {:>02X}:{:>016X} at {:>02X}:{:>016X}", stack.code, cr2, stack.cs, stack.rip);
+    }
     ksignal(SIGSEGV);
+    user_fault!(stack, SIGSEGV);
     stack_trace();
     loop { halt(); }
 });
@@ -100,6 +182,7 @@ interrupt_error!(page, stack, {
 interrupt_stack!(fpu, stack, {
     println!("FPU floating point fault at {:>02X}:{:>016X}", stack.cs, stack.rip);
     ksignal(SIGFPE);
+    user_fault!(stack, SIGFPE);
     stack_trace();
     loop { halt(); }
 });
@@ -107,6 +190,7 @@ interrupt_stack!(fpu, stack, {
 interrupt_error!(alignment_check, stack, {
     println!("Alignment check fault: {:X} at {:>02X}:{:>016X}", stack.code, stack.cs, stack.rip);
     ksignal(SIGBUS);
+    user_fault!(stack, SIGBUS);
     stack_trace();
     loop { halt(); }
 });
@@ -121,6 +205,7 @@ interrupt_stack!(machine_check, stack, {
 interrupt_stack!(simd, stack, {
     println!("SIMD floating point fault at {:>02X}:{:>016X}", stack.cs, stack.rip);
     ksignal(SIGFPE);
+    user_fault!(stack, SIGFPE);
     stack_trace();
     loop { halt(); }
 });
@@ -1,5 +1,17 @@
 use device::local_apic::LOCAL_APIC;
+use interrupt::level;
 
-interrupt!(ipi, {
+extern {
+    fn kpreempt();
+}
+
+// Sent to wake a CPU that was given a context, or that should check its run queue. A halted CPU
+// switches when it wakes, and userspace is preempted as it is by the timer
+interrupt_stack!(ipi, stack, {
+    level::enter();
     LOCAL_APIC.eoi();
+
+    if stack.cs & 3 == 3 {
+        kpreempt();
+    }
 });
@@ -1,6 +1,7 @@
 use x86::io;
 
 use device::serial::{COM1, COM2};
+use interrupt::{disable, enable, level};
 use time;
 
 extern {
@@ -18,7 +19,8 @@ unsafe fn slave_ack() {
     master_ack();
 }
 
-pub unsafe fn acknowledge(irq: usize) {
+#[inline(always)]
+unsafe fn eoi(irq: u8) {
     if irq >= 8 {
         slave_ack();
     } else {
@@ -26,13 +28,88 @@ pub unsafe fn acknowledge(irq: usize) {
     }
 }
 
+/// Mask an IRQ line in the PIC
+unsafe fn mask(irq: u8) {
+    if irq >= 8 {
+        let mask = io::inb(0xA1);
+        io::outb(0xA1, mask | 1 << (irq - 8));
+    } else {
+        let mask = io::inb(0x21);
+        io::outb(0x21, mask | 1 << irq);
+    }
+}
+
+/// Unmask an IRQ line in the PIC
+unsafe fn unmask(irq: u8) {
+    if irq >= 8 {
+        let mask = io::inb(0xA1);
+        io::outb(0xA1, mask & !(1 << (irq - 8)));
+    } else {
+        let mask = io::inb(0x21);
+        io::outb(0x21, mask & !(1 << irq));
+    }
+}
+
+/// Handle an IRQ at device level, where the clock and IPIs may preempt it
+unsafe fn device(irq: u8) {
+    match irq {
+        3 => {
+            irq_trigger(3);
+            COM2.lock().on_receive();
+            unmask(3);
+        },
+        4 => {
+            irq_trigger(4);
+            COM1.lock().on_receive();
+            unmask(4);
+        },
+        // The line stays masked until userspace acknowledges it
+        _ => irq_trigger(irq)
+    }
+}
+
+/// Pass an IRQ to a userspace driver. The line is masked until the driver acknowledges it,
+/// so that a device that keeps interrupting cannot starve the system, and the PIC is free to
+/// deliver other IRQs in the meantime.
+/// The handler runs at device level with interrupts enabled. Another device IRQ on this CPU is
+/// deferred until it is done, so handlers never run inside each other and take the same locks
+unsafe fn trigger(irq: u8) {
+    level::enter();
+
+    mask(irq);
+    eoi(irq);
+
+    if level::current() >= level::LEVEL_DEVICE {
+        level::defer(irq);
+        return;
+    }
+
+    let old = level::raise(level::LEVEL_DEVICE);
+    let mut next = Some(irq);
+    while let Some(irq) = next {
+        enable();
+        device(irq);
+        disable();
+        next = level::take_deferred();
+    }
+    level::lower(old);
+}
+
+/// Acknowledge an IRQ that was passed to userspace, allowing it to fire again
+pub unsafe fn acknowledge(irq: usize) {
+    unmask(irq as u8);
+}
+
 interrupt!(pit, {
+    // Runs with interrupts disabled, so it may preempt device handlers but not be preempted itself
+    level::enter();
+
     // Saves CPU time by not sending IRQ event irq_trigger(0);
 
     {
         const PIT_RATE: u64 = 2250286;
 
-        let mut offset = time::OFFSET.lock();
+        let mut offset = time::OFFSET.write();
         let sum = offset.1 + PIT_RATE;
         offset.1 = sum % 1000000000;
         offset.0 += sum / 1000000000;
@@ -42,74 +119,65 @@ interrupt!(pit, {
 });
 
 interrupt!(keyboard, {
-    irq_trigger(1);
+    trigger(1);
 });
 
 interrupt!(cascade, {
-    irq_trigger(2);
+    // The slave PIC is cascaded on this line, so it is never masked or deferred
+    if level::current() < level::LEVEL_DEVICE {
+        irq_trigger(2);
+    }
     master_ack();
 });
 
 interrupt!(com2, {
-    irq_trigger(3);
-    COM2.lock().on_receive();
-    master_ack();
+    trigger(3);
 });
 
 interrupt!(com1, {
-    irq_trigger(4);
-    COM1.lock().on_receive();
-    master_ack();
+    trigger(4);
 });
 
 interrupt!(lpt2, {
-    irq_trigger(5);
-    master_ack();
+    trigger(5);
 });
 
 interrupt!(floppy, {
-    irq_trigger(6);
-    master_ack();
+    trigger(6);
 });
 
 interrupt!(lpt1, {
-    irq_trigger(7);
-    master_ack();
+    trigger(7);
 });
 
 interrupt!(rtc, {
-    irq_trigger(8);
-    slave_ack();
+    trigger(8);
 });
 
 interrupt!(pci1, {
-    irq_trigger(9);
-    slave_ack();
+    trigger(9);
 });
 
 interrupt!(pci2, {
-    irq_trigger(10);
+    trigger(10);
 });
 
 interrupt!(pci3, {
-    irq_trigger(11);
+    trigger(11);
 });
 
 interrupt!(mouse, {
-    irq_trigger(12);
+    trigger(12);
 });
 
 interrupt!(fpu, {
-    irq_trigger(13);
-    slave_ack();
+    trigger(13);
 });
 
 interrupt!(ata1, {
-    irq_trigger(14);
-    slave_ack();
+    trigger(14);
 });
 
 interrupt!(ata2, {
-    irq_trigger(15);
-    slave_ack();
+    trigger(15);
 });
@@ -0,0 +1,84 @@
+//! Interrupt priority levels
+//!
+//! Each CPU runs at a level, and interrupts at or below it wait until the level is lowered.
+//! Interrupts from the local APIC are held back by the task priority register, which compares
+//! the level to the upper four bits of the vector. IRQs from the PIC are not affected by it,
+//! so device IRQs that arrive at device level are deferred here, and run by the handler that
+//! is already running once it is done
+
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+
+/// Normal execution, all interrupts are allowed
+pub const LEVEL_PASSIVE: u8 = 0;
+/// Device IRQs, vectors 0x20 to 0x2F
+pub const LEVEL_DEVICE: u8 = 2;
+/// The clock, which is the local APIC timer at vector 0x30
+pub const LEVEL_CLOCK: u8 = 3;
+/// Inter-processor interrupts, vector 0x40
+pub const LEVEL_IPI: u8 = 4;
+/// No interrupts from the local APIC are allowed
+pub const LEVEL_HIGH: u8 = 15;
+
+/// Interrupts that preempted a running handler
+pub static NESTED: AtomicUsize = ATOMIC_USIZE_INIT;
+/// Device IRQs deferred because a handler was already running at device level
+pub static DEFERRED: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The level of this CPU
+#[thread_local]
+static mut LEVEL: u8 = LEVEL_PASSIVE;
+
+/// Deferred IRQs of this CPU, one bit for each IRQ
+#[thread_local]
+static mut PENDING: u16 = 0;
+
+unsafe fn set_task_priority(level: u8) {
+    asm!("mov cr8, $0" : : "r"(level as u64) : "memory" : "intel", "volatile");
+}
+
+/// The level of this CPU
+pub fn current() -> u8 {
+    unsafe { LEVEL }
+}
+
+/// Raise the level of this CPU, returning the previous level to be given to `lower`
+pub unsafe fn raise(level: u8) -> u8 {
+    let old = LEVEL;
+    if level > old {
+        LEVEL = level;
+        set_task_priority(level);
+    }
+    old
+}
+
+/// Lower the level of this CPU to a level returned by `raise`
+pub unsafe fn lower(level: u8) {
+    LEVEL = level;
+    set_task_priority(level);
+}
+
+/// Record an interrupt, counting it if it preempted a handler
+pub fn enter() {
+    if current() > LEVEL_PASSIVE {
+        NESTED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Defer an IRQ until the handler running on this CPU is done
+/// Interrupts must be disabled
+pub unsafe fn defer(irq: u8) {
+    PENDING |= 1 << irq;
+    DEFERRED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Take the next deferred IRQ of this CPU
+/// Interrupts must be disabled
+pub unsafe fn take_deferred() -> Option<u8> {
+    if PENDING == 0 {
+        None
+    } else {
+        let irq = PENDING.trailing_zeros() as u8;
+        PENDING &= !(1 << irq);
+        Some(irq)
+    }
+}
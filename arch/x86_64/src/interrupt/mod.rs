@@ -7,7 +7,10 @@ use paging::{ActivePageTable, VirtualAddress};
 pub mod exception;
 pub mod ipi;
 pub mod irq;
+pub mod level;
+pub mod probe;
 pub mod syscall;
+pub mod timer;
 
 /// Clear interrupts
 #[inline(always)]
@@ -21,6 +24,16 @@ pub unsafe fn enable() {
     asm!("sti" : : : : "intel", "volatile");
 }
 
+/// Check if interrupts are enabled, by reading the interrupt flag
+#[inline(always)]
+pub fn enabled() -> bool {
+    let flags: usize;
+    unsafe { asm!("pushfq
+        pop $0"
+        : "=r"(flags) : : "memory" : "intel", "volatile"); }
+    flags & 1 << 9 == 1 << 9
+}
+
 /// Set interrupts and halt
 /// This will atomically wait for the next interrupt
 /// Performing enable followed by halt is not guaranteed to be atomic, use this instead!
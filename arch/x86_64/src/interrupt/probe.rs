@@ -0,0 +1,191 @@
+//! Probes, breakpoints placed in kernel text at runtime that record each time they are hit, along
+//! with the first three arguments, and let the kernel carry on.
+//!
+//! The first byte of the instruction is replaced with int3. When it is hit, the byte is put back,
+//! and the instruction is single stepped with interrupts disabled, before int3 is written again
+
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use interrupt::{disable, enable, enabled};
+use InterruptStack;
+
+/// Limit on number of probes
+pub const PROBE_MAX: usize = 8;
+
+/// Breakpoint instruction
+const INT3: u8 = 0xCC;
+
+/// Trap flag, for single stepping
+const FLAG_TRAP: usize = 1 << 8;
+
+/// Interrupt flag
+const FLAG_INTERRUPT: usize = 1 << 9;
+
+/// A probe slot, which is free if its address is zero
+pub struct Probe {
+    address: AtomicUsize,
+    original: AtomicUsize,
+    hits: AtomicUsize,
+    args: [AtomicUsize; 3]
+}
+
+impl Probe {
+    const fn new() -> Probe {
+        Probe {
+            address: AtomicUsize::new(0),
+            original: AtomicUsize::new(0),
+            hits: AtomicUsize::new(0),
+            args: [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)]
+        }
+    }
+}
+
+static PROBES: [Probe; PROBE_MAX] = [
+    Probe::new(), Probe::new(), Probe::new(), Probe::new(),
+    Probe::new(), Probe::new(), Probe::new(), Probe::new()
+];
+
+/// The probe being stepped over on this CPU, plus one, or zero if none is
+#[thread_local]
+static mut STEPPING: usize = 0;
+
+/// Whether interrupts were enabled before stepping
+#[thread_local]
+static mut STEPPING_INTERRUPTS: bool = false;
+
+/// State of a probe, as returned by `list`
+pub struct ProbeInfo {
+    pub address: usize,
+    pub hits: usize,
+    /// rdi, rsi and rdx at the last hit
+    pub args: [usize; 3]
+}
+
+/// Returns true if the address is in kernel text
+fn is_text(address: usize) -> bool {
+    extern {
+        /// The starting byte of the text (code) data segment.
+        static mut __text_start: u8;
+        /// The ending byte of the text (code) data segment.
+        static mut __text_end: u8;
+    }
+
+    unsafe { address >= & __text_start as *const u8 as usize && address < & __text_end as *const u8 as usize }
+}
+
+/// Write a byte of kernel text, which is mapped read-only
+unsafe fn write_text(address: usize, byte: u8) {
+    let interrupts = enabled();
+    disable();
+
+    let cr0: usize;
+    asm!("mov $0, cr0" : "=r"(cr0) : : "memory" : "intel", "volatile");
+    asm!("mov cr0, $0" : : "r"(cr0 & !(1 << 16)) : "memory" : "intel", "volatile");
+    ptr::write_volatile(address as *mut u8, byte);
+    asm!("mov cr0, $0" : : "r"(cr0) : "memory" : "intel", "volatile");
+
+    if interrupts {
+        enable();
+    }
+}
+
+/// Place a probe at the start of an instruction in kernel text
+pub fn insert(address: usize) -> Result<(), ()> {
+    if ! is_text(address) || PROBES.iter().any(|probe| probe.address.load(Ordering::SeqCst) == address) {
+        return Err(());
+    }
+
+    for probe in PROBES.iter() {
+        if probe.address.compare_and_swap(0, address, Ordering::SeqCst) == 0 {
+            probe.hits.store(0, Ordering::SeqCst);
+            unsafe {
+                probe.original.store(ptr::read_volatile(address as *const u8) as usize, Ordering::SeqCst);
+                write_text(address, INT3);
+            }
+            return Ok(());
+        }
+    }
+
+    Err(())
+}
+
+/// Remove the probe at an address
+pub fn remove(address: usize) -> Result<(), ()> {
+    if address == 0 {
+        return Err(());
+    }
+
+    for probe in PROBES.iter() {
+        if probe.address.load(Ordering::SeqCst) == address {
+            unsafe { write_text(address, probe.original.load(Ordering::SeqCst) as u8); }
+            probe.address.store(0, Ordering::SeqCst);
+            return Ok(());
+        }
+    }
+
+    Err(())
+}
+
+/// Call `f` with each probe that is in place
+pub fn list<F: FnMut(ProbeInfo)>(mut f: F) {
+    for probe in PROBES.iter() {
+        let address = probe.address.load(Ordering::SeqCst);
+        if address != 0 {
+            f(ProbeInfo {
+                address: address,
+                hits: probe.hits.load(Ordering::SeqCst),
+                args: [
+                    probe.args[0].load(Ordering::SeqCst),
+                    probe.args[1].load(Ordering::SeqCst),
+                    probe.args[2].load(Ordering::SeqCst)
+                ]
+            });
+        }
+    }
+}
+
+/// Handle a breakpoint in the kernel, returning true if it was a probe
+pub unsafe fn hit(stack: &mut InterruptStack) -> bool {
+    // The breakpoint has been executed, so the instruction pointer is after it
+    let address = stack.rip - 1;
+    for (i, probe) in PROBES.iter().enumerate() {
+        if probe.address.load(Ordering::SeqCst) == address {
+            probe.hits.fetch_add(1, Ordering::SeqCst);
+            probe.args[0].store(stack.rdi, Ordering::SeqCst);
+            probe.args[1].store(stack.rsi, Ordering::SeqCst);
+            probe.args[2].store(stack.rdx, Ordering::SeqCst);
+
+            write_text(address, probe.original.load(Ordering::SeqCst) as u8);
+            STEPPING = i + 1;
+            STEPPING_INTERRUPTS = stack.rflags & FLAG_INTERRUPT == FLAG_INTERRUPT;
+            stack.rip = address;
+            stack.rflags = (stack.rflags | FLAG_TRAP) & !FLAG_INTERRUPT;
+            return true;
+        }
+    }
+
+    false
+}
+
+/// Handle a debug trap, returning true if it finished stepping over a probe
+pub unsafe fn step(stack: &mut InterruptStack) -> bool {
+    if STEPPING == 0 {
+        return false;
+    }
+
+    let probe = &PROBES[STEPPING - 1];
+    STEPPING = 0;
+
+    // The probe may have been removed while stepping
+    let address = probe.address.load(Ordering::SeqCst);
+    if address != 0 {
+        write_text(address, INT3);
+    }
+
+    stack.rflags &= !FLAG_TRAP;
+    if STEPPING_INTERRUPTS {
+        stack.rflags |= FLAG_INTERRUPT;
+    }
+    true
+}
@@ -1,3 +1,31 @@
+/// User registers saved on the kernel stack on entry to a syscall, lowest address first.
+/// The frame ends 256 bytes below the top of the kernel stack, where the TSS points.
+/// rax is not saved, as it holds the syscall number and then the result
+#[derive(Clone, Copy)]
+#[repr(packed)]
+pub struct SyscallStack {
+    pub r15: usize,
+    pub r14: usize,
+    pub r13: usize,
+    pub r12: usize,
+    pub rbp: usize,
+    pub rbx: usize,
+    pub fs: usize,
+    pub r11: usize,
+    pub r10: usize,
+    pub r9: usize,
+    pub r8: usize,
+    pub rsi: usize,
+    pub rdi: usize,
+    pub rdx: usize,
+    pub rcx: usize,
+    pub rip: usize,
+    pub cs: usize,
+    pub rflags: usize,
+    pub rsp: usize,
+    pub ss: usize
+}
+
 #[naked]
 pub unsafe extern fn syscall() {
     #[inline(never)]
@@ -23,7 +51,8 @@ pub unsafe extern fn syscall() {
         asm!("" : : "{rax}"(a) : : "intel", "volatile");
     }
 
-    // Push scratch registers, minus rax for the return value
+    // Push scratch registers, minus rax for the return value, then preserved registers, so that
+    // all of the user registers are in a SyscallStack
     asm!("push rcx
         push rdx
         push rdi
@@ -34,13 +63,25 @@ pub unsafe extern fn syscall() {
         push r11
         push fs
         mov r11, 0x18
-        mov fs, r11"
+        mov fs, r11
+        push rbx
+        push rbp
+        push r12
+        push r13
+        push r14
+        push r15"
         : : : : "intel", "volatile");
 
     inner();
 
     // Interrupt return
-    asm!("pop fs
+    asm!("pop r15
+        pop r14
+        pop r13
+        pop r12
+        pop rbp
+        pop rbx
+        pop fs
         pop r11
         pop r10
         pop r9
@@ -59,3 +100,36 @@ pub unsafe extern fn clone_ret() -> usize {
         : : : : "intel", "volatile");
         0
 }
+
+/// Enter a context restored from a checkpoint. Its kernel stack holds the value of rax, followed by
+/// a SyscallStack, as if it was returning from a syscall
+#[naked]
+pub unsafe extern fn restore_ret() {
+    asm!("pop rax
+        pop r15
+        pop r14
+        pop r13
+        pop r12
+        pop rbp
+        pop rbx
+        pop fs
+        pop r11
+        pop r10
+        pop r9
+        pop r8
+        pop rsi
+        pop rdi
+        pop rdx
+        pop rcx
+        iretq"
+        : : : : "intel", "volatile");
+}
+
+/// Make a syscall from the kernel, through the same entry as usermode, without the change of
+/// privilege. Used to measure the cost of the entry and dispatch
+#[inline(never)]
+pub unsafe fn kernel_syscall(a: usize) -> usize {
+    let result;
+    asm!("int 0x80" : "={rax}"(result) : "{rax}"(a) : "memory" : "intel", "volatile");
+    result
+}
@@ -0,0 +1,18 @@
+use device::local_apic::LOCAL_APIC;
+use interrupt::level;
+
+extern {
+    fn kpreempt();
+}
+
+interrupt_stack!(timer, stack, {
+    level::enter();
+    LOCAL_APIC.eoi();
+
+    // Only userspace is preempted, as the kernel may hold locks that the next context needs.
+    // The kernel gives up the CPU itself when it blocks, and when it returns to userspace it
+    // will be preempted at the next tick
+    if stack.cs & 3 == 3 {
+        kpreempt();
+    }
+});
@@ -0,0 +1,69 @@
+//! Kernel address space layout randomization
+//!
+//! The kernel heap, kernel stacks, and user stacks are placed at random offsets in their parts of
+//! the address space. The kernel itself is linked at `KERNEL_OFFSET`, where the bootloader loads
+//! it, so it is not moved
+
+use core::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+
+use device::cpu::has_rdrand;
+use device::pvclock::rdtsc;
+
+/// Set if the CPU has RDRAND
+static RDRAND: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// State of the generator used without RDRAND, advanced with the time stamp counter
+static STATE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Check for RDRAND, and seed the generator from it, or from the time stamp counter
+pub fn init() {
+    RDRAND.store(has_rdrand(), Ordering::SeqCst);
+    STATE.store(rdrand().unwrap_or(rdtsc()) as usize, Ordering::SeqCst);
+}
+
+/// Read RDRAND, which may fail if its entropy is used up. It is tried a few times
+fn rdrand() -> Option<u64> {
+    if RDRAND.load(Ordering::Relaxed) {
+        for _ in 0..16 {
+            let value: u64;
+            let ok: u8;
+            unsafe {
+                asm!("rdrand rax
+                    setc bl"
+                    : "={rax}"(value), "={bl}"(ok)
+                    :
+                    : "cc"
+                    : "intel", "volatile");
+            }
+            if ok == 1 {
+                return Some(value);
+            }
+        }
+    }
+
+    None
+}
+
+/// A random number, from RDRAND if the CPU has it. Without it, the time stamp counter is mixed into
+/// a xorshift generator, which is enough to vary the layout from boot to boot
+pub fn random() -> u64 {
+    if let Some(value) = rdrand() {
+        return value;
+    }
+
+    let mut x = STATE.load(Ordering::SeqCst) as u64 ^ rdtsc();
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    STATE.store(x as usize, Ordering::SeqCst);
+    x
+}
+
+/// A random multiple of `align` that is below `limit`
+pub fn slide(limit: usize, align: usize) -> usize {
+    if limit < align {
+        0
+    } else {
+        (random() as usize % (limit / align)) * align
+    }
+}
@@ -11,11 +11,12 @@
 #![feature(unique)]
 #![no_std]
 
-extern crate hole_list_allocator as allocator;
+pub extern crate hole_list_allocator as allocator;
 
 #[macro_use]
 extern crate bitflags;
 extern crate io;
+extern crate seqlock;
 extern crate spin;
 extern crate syscall;
 pub extern crate x86;
@@ -34,11 +35,23 @@ pub extern crate x86;
     /// Offset of kernel
     pub const KERNEL_OFFSET: usize = RECURSIVE_PAGE_OFFSET - PML4_SIZE;
 
-    /// Offset to kernel heap
+    /// Offset to kernel stacks
+    pub const KERNEL_STACK_OFFSET: usize = KERNEL_OFFSET + PML4_SIZE/4;
+    /// Size of kernel stacks
+    pub const KERNEL_STACK_SIZE: usize = PML4_SIZE/4; // 128 GB
+    /// Space for each kernel stack. The stack is at the top, and the rest is an unmapped guard
+    pub const KERNEL_STACK_SLOT_SIZE: usize = 128 * 1024; // 128 KB
+
+    /// Offset to kernel heap area. The heap starts at a random page in it
     pub const KERNEL_HEAP_OFFSET: usize = KERNEL_OFFSET + PML4_SIZE/2;
     /// Size of kernel heap
     pub const KERNEL_HEAP_SIZE: usize = 64 * 1024 * 1024; // 64 MB
 
+    /// Offset to kernel mappings of device memory
+    pub const KERNEL_MMIO_OFFSET: usize = KERNEL_HEAP_OFFSET + PML4_SIZE/4;
+    /// Size of kernel mappings of device memory
+    pub const KERNEL_MMIO_SIZE: usize = PML4_SIZE/4; // 128 GB
+
     /// Offset to kernel percpu variables
     //TODO: Use 64-bit fs offset to enable this pub const KERNEL_PERCPU_OFFSET: usize = KERNEL_HEAP_OFFSET - PML4_SIZE;
     pub const KERNEL_PERCPU_OFFSET: usize = 0xC000_0000;
@@ -60,10 +73,12 @@ pub extern crate x86;
     /// Offset to user grants
     pub const USER_GRANT_OFFSET: usize = USER_HEAP_OFFSET + PML4_SIZE;
 
-    /// Offset to user stack
+    /// Offset to user stack area. Each stack starts at a random page in it
     pub const USER_STACK_OFFSET: usize = USER_GRANT_OFFSET + PML4_SIZE;
     /// Size of user stack
     pub const USER_STACK_SIZE: usize = 1024 * 1024; // 1 MB
+    /// Size of the unmapped guard below the user stack, which grants are not placed in
+    pub const USER_STACK_GUARD_SIZE: usize = 1024 * 1024; // 1 MB
 
     /// Offset to user TLS
     pub const USER_TLS_OFFSET: usize = USER_STACK_OFFSET + PML4_SIZE;
@@ -83,6 +98,21 @@ pub extern crate x86;
     /// Offset to user temporary tls (used when cloning)
     pub const USER_TMP_TLS_OFFSET: usize = USER_TMP_STACK_OFFSET + PML4_SIZE;
 
+    /// Offset to user temporary page for copy-on-write faults
+    pub const USER_TMP_COW_OFFSET: usize = USER_TMP_TLS_OFFSET + PML4_SIZE;
+
+    /// Offset to user temporary pages for merging identical pages
+    pub const USER_TMP_MERGE_OFFSET: usize = USER_TMP_COW_OFFSET + PML4_SIZE;
+
+    /// Offset to user temporary page for zeroing shared memory
+    pub const USER_TMP_SHM_OFFSET: usize = USER_TMP_MERGE_OFFSET + PML4_SIZE;
+
+    /// Offset to user temporary page for scanning the accessed bits of other contexts
+    pub const USER_TMP_WORKING_SET_OFFSET: usize = USER_TMP_SHM_OFFSET + PML4_SIZE;
+
+    /// Offset to user temporary page for counting the resident pages of other contexts
+    pub const USER_TMP_RSS_OFFSET: usize = USER_TMP_WORKING_SET_OFFSET + PML4_SIZE;
+
 
 /// Print to console
 #[macro_export]
@@ -100,6 +130,23 @@ macro_rules! println {
     ($fmt:expr, $($arg:tt)*) => (print!(concat!($fmt, "\n"), $($arg)*));
 }
 
+/// Print with new line to console, at a log level from `console::Level`
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $fmt:expr) => ({
+        use core::fmt::Write;
+        let mut console = $crate::console::CONSOLE.lock();
+        console.set_level($level);
+        let _ = write!(console, concat!($fmt, "\n"));
+    });
+    ($level:expr, $fmt:expr, $($arg:tt)*) => ({
+        use core::fmt::Write;
+        let mut console = $crate::console::CONSOLE.lock();
+        console.set_level($level);
+        let _ = write!(console, concat!($fmt, "\n"), $($arg)*);
+    });
+}
+
 /// Create an interrupt function that can safely run rust code
 #[macro_export]
 macro_rules! interrupt {
@@ -149,6 +196,12 @@ macro_rules! interrupt {
 #[repr(packed)]
 pub struct InterruptStack {
     fs: usize,
+    r15: usize,
+    r14: usize,
+    r13: usize,
+    r12: usize,
+    rbp: usize,
+    rbx: usize,
     r11: usize,
     r10: usize,
     r9: usize,
@@ -169,11 +222,11 @@ macro_rules! interrupt_stack {
         #[naked]
         pub unsafe extern fn $name () {
             #[inline(never)]
-            unsafe fn inner($stack: &$crate::InterruptStack) {
+            unsafe fn inner($stack: &mut $crate::InterruptStack) {
                 $func
             }
 
-            // Push scratch registers
+            // Push scratch registers, and preserved registers so that handlers can read them
             asm!("push rax
                 push rcx
                 push rdx
@@ -183,6 +236,12 @@ macro_rules! interrupt_stack {
                 push r9
                 push r10
                 push r11
+                push rbx
+                push rbp
+                push r12
+                push r13
+                push r14
+                push r15
                 push fs
                 mov rax, 0x18
                 mov fs, ax"
@@ -193,10 +252,16 @@ macro_rules! interrupt_stack {
             asm!("" : "={rsp}"(rsp) : : : "intel", "volatile");
 
             // Call inner rust function
-            inner(&*(rsp as *const $crate::InterruptStack));
+            inner(&mut *(rsp as *mut $crate::InterruptStack));
 
-            // Pop scratch registers and return
+            // Pop preserved and scratch registers and return
             asm!("pop fs
+                pop r15
+                pop r14
+                pop r13
+                pop r12
+                pop rbp
+                pop rbx
                 pop r11
                 pop r10
                 pop r9
@@ -215,6 +280,12 @@ macro_rules! interrupt_stack {
 #[repr(packed)]
 pub struct InterruptErrorStack {
     fs: usize,
+    r15: usize,
+    r14: usize,
+    r13: usize,
+    r12: usize,
+    rbp: usize,
+    rbx: usize,
     r11: usize,
     r10: usize,
     r9: usize,
@@ -240,7 +311,7 @@ macro_rules! interrupt_error {
                 $func
             }
 
-            // Push scratch registers
+            // Push scratch registers, and preserved registers so that handlers can read them
             asm!("xchg bx, bx
                 push rax
                 push rcx
@@ -251,6 +322,12 @@ macro_rules! interrupt_error {
                 push r9
                 push r10
                 push r11
+                push rbx
+                push rbp
+                push r12
+                push r13
+                push r14
+                push r15
                 push fs
                 mov rax, 0x18
                 mov fs, ax"
@@ -263,8 +340,14 @@ macro_rules! interrupt_error {
             // Call inner rust function
             inner(&*(rsp as *const $crate::InterruptErrorStack));
 
-            // Pop scratch registers, error code, and return
+            // Pop preserved and scratch registers, error code, and return
             asm!("pop fs
+                pop r15
+                pop r14
+                pop r13
+                pop r12
+                pop rbp
+                pop rbx
                 pop r11
                 pop r10
                 pop r9
@@ -284,6 +367,9 @@ macro_rules! interrupt_error {
 /// ACPI table parsing
 pub mod acpi;
 
+/// Kernel command line
+pub mod cmdline;
+
 /// Console handling
 pub mod console;
 
@@ -305,6 +391,9 @@ pub mod idt;
 /// Interrupt instructions
 pub mod interrupt;
 
+/// Randomized placement of kernel and user memory
+pub mod kaslr;
+
 /// Memory management
 pub mod memory;
 
@@ -314,6 +403,9 @@ pub mod paging;
 /// Panic
 pub mod panic;
 
+/// Kernel log kept across warm reboots
+pub mod pstore;
+
 /// Initialization and start function
 pub mod start;
 
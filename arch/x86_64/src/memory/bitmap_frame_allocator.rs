@@ -0,0 +1,192 @@
+//! # Bitmap frame allocator
+//! Keeps a bit for every frame, so that frames can be freed and runs of frames can be found
+
+use paging::PhysicalAddress;
+
+use super::{Frame, FrameAllocator, MemoryAreaIter, PAGE_SIZE};
+
+/// Frames the allocator can manage, which covers the first 16 GiB of physical memory.
+/// Memory above this is never allocated
+pub const MAX_FRAMES: usize = 16 * 1024 * 1024 * 1024 / PAGE_SIZE;
+
+/// Words of the bitmap, each holding 64 frames
+const BITMAP_WORDS: usize = MAX_FRAMES / 64;
+
+/// One bit per frame, set if the frame is allocated or is not free RAM. This is kept in the
+/// kernel's BSS, as there is no heap when the allocator is created
+static mut BITMAP: [u64; BITMAP_WORDS] = [0; BITMAP_WORDS];
+
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64; BITMAP_WORDS],
+    areas: MemoryAreaIter,
+    kernel_start: Frame,
+    kernel_end: Frame,
+    /// Number of frames of free RAM, outside of the kernel
+    total: usize,
+    /// Number of those frames that are not allocated
+    free: usize,
+    /// Word to start looking for a single frame from
+    next: usize
+}
+
+impl BitmapFrameAllocator {
+    pub fn new(kernel_start: usize, kernel_end: usize, memory_areas: MemoryAreaIter) -> BitmapFrameAllocator {
+        let mut allocator = BitmapFrameAllocator {
+            bitmap: unsafe { &mut BITMAP },
+            areas: memory_areas,
+            kernel_start: Frame::containing_address(PhysicalAddress::new(kernel_start)),
+            kernel_end: Frame::containing_address(PhysicalAddress::new(kernel_end)),
+            total: 0,
+            free: 0,
+            next: 0
+        };
+
+        for word in allocator.bitmap.iter_mut() {
+            *word = !0;
+        }
+
+        let mut ignored = 0;
+        for area in allocator.areas.clone() {
+            // Only whole frames inside of the area can be used
+            let start = (area.base_addr as usize + PAGE_SIZE - 1) / PAGE_SIZE;
+            let end = (area.base_addr + area.length) as usize / PAGE_SIZE;
+            for number in start..end {
+                if number >= MAX_FRAMES {
+                    ignored += 1;
+                } else if ! allocator.is_kernel(number) {
+                    allocator.clear(number);
+                    allocator.total += 1;
+                }
+            }
+        }
+        allocator.free = allocator.total;
+
+        if ignored > 0 {
+            println!("Frame allocator: ignoring {} KB above {} GB", ignored * PAGE_SIZE / 1024, MAX_FRAMES * PAGE_SIZE / 1024 / 1024 / 1024);
+        }
+
+        allocator
+    }
+
+    fn is_kernel(&self, number: usize) -> bool {
+        number >= self.kernel_start.number && number <= self.kernel_end.number
+    }
+
+    /// Check if a frame is free RAM outside of the kernel, which the allocator hands out
+    fn is_usable(&self, number: usize) -> bool {
+        if number >= MAX_FRAMES || self.is_kernel(number) {
+            return false;
+        }
+
+        let address = number * PAGE_SIZE;
+        self.areas.clone().any(|area| {
+            let start = area.base_addr as usize;
+            let end = start + area.length as usize;
+            address >= start && address + PAGE_SIZE <= end
+        })
+    }
+
+    fn is_set(&self, number: usize) -> bool {
+        self.bitmap[number / 64] & (1 << (number % 64)) != 0
+    }
+
+    fn set(&mut self, number: usize) {
+        self.bitmap[number / 64] |= 1 << (number % 64);
+    }
+
+    fn clear(&mut self, number: usize) {
+        self.bitmap[number / 64] &= !(1 << (number % 64));
+    }
+
+    /// Find a single frame, starting at the word the last one was found in
+    fn find_one(&mut self) -> Option<usize> {
+        for i in 0..BITMAP_WORDS {
+            let index = (self.next + i) % BITMAP_WORDS;
+            let word = self.bitmap[index];
+            if word != !0 {
+                self.next = index;
+                return Some(index * 64 + (!word).trailing_zeros() as usize);
+            }
+        }
+
+        None
+    }
+
+    /// Find the lowest run of `count` frames, as needed for DMA buffers
+    fn find_run(&self, count: usize) -> Option<usize> {
+        let mut start = 0;
+        let mut run = 0;
+        let mut number = 0;
+        while number < MAX_FRAMES {
+            if number % 64 == 0 && self.bitmap[number / 64] == !0 {
+                // Skip whole words of used frames
+                run = 0;
+                number += 64;
+                continue;
+            }
+
+            if self.is_set(number) {
+                run = 0;
+            } else {
+                if run == 0 {
+                    start = number;
+                }
+                run += 1;
+                if run == count {
+                    return Some(start);
+                }
+            }
+
+            number += 1;
+        }
+
+        None
+    }
+}
+
+impl FrameAllocator for BitmapFrameAllocator {
+    fn free_frames(&self) -> usize {
+        self.free
+    }
+
+    fn used_frames(&self) -> usize {
+        self.total - self.free
+    }
+
+    fn allocate_frames(&mut self, count: usize) -> Option<Frame> {
+        if count == 0 || count > self.free {
+            return None;
+        }
+
+        let start = if count == 1 {
+            self.find_one()
+        } else {
+            self.find_run(count)
+        };
+
+        start.map(|start| {
+            for number in start..start + count {
+                self.set(number);
+            }
+            self.free -= count;
+            Frame { number: start }
+        })
+    }
+
+    fn deallocate_frames(&mut self, frame: Frame, count: usize) {
+        for number in frame.number..frame.number + count {
+            // Device memory and memory the kernel was loaded in are unmapped with the same
+            // functions as allocated memory, but were never allocated
+            if ! self.is_usable(number) {
+                continue;
+            }
+
+            if self.is_set(number) {
+                self.clear(number);
+                self.free += 1;
+            } else {
+                println!("BitmapFrameAllocator::deallocate_frames: frame {:X} is already free", number * PAGE_SIZE);
+            }
+        }
+    }
+}
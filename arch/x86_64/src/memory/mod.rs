@@ -3,11 +3,15 @@
 
 pub use paging::{PAGE_SIZE, PhysicalAddress};
 
-use self::area_frame_allocator::AreaFrameAllocator;
+use self::bitmap_frame_allocator::BitmapFrameAllocator;
 
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
 use spin::Mutex;
 
-pub mod area_frame_allocator;
+use interrupt;
+use interrupt::level;
+
+pub mod bitmap_frame_allocator;
 
 /// The current memory map. It's size is maxed out to 512 entries, due to it being
 /// from 0x500 to 0x5000 (800 is the absolute total)
@@ -64,7 +68,53 @@ impl Iterator for MemoryAreaIter {
     }
 }
 
-static ALLOCATOR: Mutex<Option<AreaFrameAllocator>> = Mutex::new(None);
+static ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+
+/// Set while this CPU holds the allocator lock
+#[thread_local]
+static mut ALLOCATOR_HELD: bool = false;
+
+/// Number of frees that can wait on one CPU
+const DEFERRED_MAX: usize = 64;
+
+/// Frees that could not take the allocator lock, as first frame number and count, waiting for this
+/// CPU to reach a point where the lock can be taken
+#[thread_local]
+static mut DEFERRED: [(usize, usize); DEFERRED_MAX] = [(0, 0); DEFERRED_MAX];
+
+/// Number of entries in `DEFERRED`
+#[thread_local]
+static mut DEFERRED_LEN: usize = 0;
+
+/// Frees that were deferred, on all CPUs
+pub static DEFERRED_FREES: AtomicUsize = ATOMIC_USIZE_INIT;
+/// Frames that were lost because a CPU's deferred list was full while the allocator was busy
+pub static LEAKED_FRAMES: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Physical address of memory kept from the frame allocator for the persistent log, zero if
+/// there was no room
+pub static PSTORE_ADDRESS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Take `size` bytes from the end of the highest free area below 4 GiB, so that the address is
+/// the same on every boot with the same memory map
+unsafe fn reserve_top(size: usize) -> Option<usize> {
+    let mut best: Option<usize> = None;
+    for (i, entry) in MEMORY_MAP.iter().enumerate() {
+        let end = entry.base_addr + entry.length;
+        if entry._type == MEMORY_AREA_FREE && end <= 0x1_0000_0000 && entry.length >= size as u64 * 2 {
+            if best.map_or(true, |best| end > MEMORY_MAP[best].base_addr + MEMORY_MAP[best].length) {
+                best = Some(i);
+            }
+        }
+    }
+
+    best.map(|i| {
+        let entry = &mut MEMORY_MAP[i];
+        let address = (entry.base_addr + entry.length - size as u64) & !(PAGE_SIZE as u64 - 1);
+        entry.length = address - entry.base_addr;
+        address as usize
+    })
+}
 
 /// Init memory module
 /// Must be called once, and only once,
@@ -77,7 +127,20 @@ pub unsafe fn init(kernel_start: usize, kernel_end: usize) {
         }
     }
 
-    *ALLOCATOR.lock() = Some(AreaFrameAllocator::new(kernel_start, kernel_end, MemoryAreaIter::new(MEMORY_AREA_FREE)));
+    if let Some(address) = reserve_top(::pstore::PSTORE_SIZE) {
+        PSTORE_ADDRESS.store(address, Ordering::SeqCst);
+    }
+
+    *ALLOCATOR.lock() = Some(BitmapFrameAllocator::new(kernel_start, kernel_end, MemoryAreaIter::new(MEMORY_AREA_FREE)));
+}
+
+/// Check if any part of a physical range is usable RAM, according to the memory map
+pub fn is_ram(address: usize, size: usize) -> bool {
+    MemoryAreaIter::new(MEMORY_AREA_FREE).any(|area| {
+        let start = area.base_addr as usize;
+        let end = start + area.length as usize;
+        address < end && address + size > start
+    })
 }
 
 /// Allocate a frame
@@ -90,39 +153,121 @@ pub fn deallocate_frame(frame: Frame) {
     deallocate_frames(frame, 1)
 }
 
-/// Get the number of frames available
-pub fn free_frames() -> usize {
-    if let Some(ref allocator) = *ALLOCATOR.lock() {
-        allocator.free_frames()
+/// Run a function with the allocator locked, after returning the frames deferred on this CPU to it
+fn with_allocator<F, T>(f: F) -> T where F: FnOnce(&mut BitmapFrameAllocator) -> T {
+    // Set before locking and cleared after unlocking, so an interrupt never waits for this CPU
+    unsafe { ALLOCATOR_HELD = true; }
+
+    let result = {
+        let mut allocator_option = ALLOCATOR.lock();
+        let allocator = allocator_option.as_mut().expect("frame allocator not initialized");
+        while let Some((number, count)) = unsafe { take_deferred() } {
+            allocator.deallocate_frames(Frame { number: number }, count);
+        }
+        f(allocator)
+    };
+
+    unsafe { ALLOCATOR_HELD = false; }
+
+    result
+}
+
+/// Take the next deferred free of this CPU
+unsafe fn take_deferred() -> Option<(usize, usize)> {
+    let restore = interrupt::enabled();
+    interrupt::disable();
+
+    let entry = if DEFERRED_LEN > 0 {
+        DEFERRED_LEN -= 1;
+        Some(DEFERRED[DEFERRED_LEN])
+    } else {
+        None
+    };
+
+    if restore {
+        interrupt::enable();
+    }
+
+    entry
+}
+
+/// Add a free to the deferred list of this CPU, returning false if it is full
+unsafe fn defer(frame: &Frame, count: usize) -> bool {
+    let restore = interrupt::enabled();
+    interrupt::disable();
+
+    let deferred = if DEFERRED_LEN < DEFERRED_MAX {
+        DEFERRED[DEFERRED_LEN] = (frame.number, count);
+        DEFERRED_LEN += 1;
+        true
     } else {
-        panic!("frame allocator not initialized");
+        false
+    };
+
+    if restore {
+        interrupt::enable();
     }
+
+    deferred
+}
+
+/// Return frames deferred on this CPU to the allocator. Called by the kernel when it is idle
+pub fn drain_deferred() {
+    if unsafe { DEFERRED_LEN } > 0 {
+        with_allocator(|_| ());
+    }
+}
+
+/// Get the number of frames available
+pub fn free_frames() -> usize {
+    with_allocator(|allocator| allocator.free_frames())
 }
 
 /// Get the number of frames used
 pub fn used_frames() -> usize {
-    if let Some(ref allocator) = *ALLOCATOR.lock() {
-        allocator.used_frames()
-    } else {
-        panic!("frame allocator not initialized");
-    }
+    with_allocator(|allocator| allocator.used_frames())
 }
 
-/// Allocate a range of frames
+extern {
+    fn kout_of_memory(count: usize) -> bool;
+}
+
+fn try_allocate_frames(count: usize) -> Option<Frame> {
+    with_allocator(|allocator| allocator.allocate_frames(count))
+}
+
+/// Allocate a range of contiguous frames, such as for a DMA buffer
+/// If none are available, the kernel is asked to free memory, and the allocation is retried once
 pub fn allocate_frames(count: usize) -> Option<Frame> {
-    if let Some(ref mut allocator) = *ALLOCATOR.lock() {
-        allocator.allocate_frames(count)
+    if let Some(frame) = try_allocate_frames(count) {
+        Some(frame)
+    } else if unsafe { kout_of_memory(count) } {
+        try_allocate_frames(count)
     } else {
-        panic!("frame allocator not initialized");
+        None
     }
 }
 
 /// Deallocate a range of frames frame
+/// This may be called from interrupt handlers. When the handler interrupted code holding the
+/// allocator lock on this CPU, or runs at device level or above, the frames are put on a list for
+/// this CPU and returned the next time the allocator is used
 pub fn deallocate_frames(frame: Frame, count: usize) {
-    if let Some(ref mut allocator) = *ALLOCATOR.lock() {
-        allocator.deallocate_frames(frame, count)
+    if unsafe { ALLOCATOR_HELD } || level::current() > level::LEVEL_PASSIVE {
+        if unsafe { defer(&frame, count) } {
+            DEFERRED_FREES.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        // The list is full, the frames can only be returned if no one holds the lock
+        if let Some(mut allocator_option) = ALLOCATOR.try_lock() {
+            allocator_option.as_mut().expect("frame allocator not initialized").deallocate_frames(frame, count);
+            return;
+        }
+
+        LEAKED_FRAMES.fetch_add(count, Ordering::Relaxed);
     } else {
-        panic!("frame allocator not initialized");
+        with_allocator(|allocator| allocator.deallocate_frames(frame, count));
     }
 }
 
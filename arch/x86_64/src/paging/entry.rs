@@ -3,7 +3,7 @@
 
 use memory::Frame;
 
-use super::PhysicalAddress;
+use super::{PhysicalAddress, HUGE_PAGE_SIZE};
 
 /// A page table entry
 pub struct Entry(u64);
@@ -19,12 +19,20 @@ bitflags! {
         const DIRTY =           1 << 6,
         const HUGE_PAGE =       1 << 7,
         const GLOBAL =          1 << 8,
+        /// Available to the kernel - page is read-only until a write fault gives it a private frame
+        const COPY_ON_WRITE =   1 << 9,
+        /// The PAT bit of a page, the same bit as `HUGE_PAGE` in higher tables. Alone, this selects
+        /// the fifth attribute, which `init_pat` sets to write combining
+        const WRITE_COMBINING = 1 << 7,
         const NO_EXECUTE =      1 << 63,
     }
 }
 
 pub const ADDRESS_MASK: usize = 0x000f_ffff_ffff_f000;
 
+/// The PAT bit of a huge page, as bit 7 is taken by `HUGE_PAGE`
+const HUGE_PAT: u64 = 1 << 12;
+
 impl Entry {
     /// Is the entry unused?
     pub fn is_unused(&self) -> bool {
@@ -59,4 +67,37 @@ impl Entry {
         debug_assert!(frame.start_address().get() & !ADDRESS_MASK == 0);
         self.0 = (frame.start_address().get() as u64) | flags.bits();
     }
+
+    /// Clear the accessed bit, returning whether it was set
+    pub fn take_accessed(&mut self) -> bool {
+        let accessed = self.0 & ACCESSED.bits() == ACCESSED.bits();
+        self.0 &= !ACCESSED.bits();
+        accessed
+    }
+
+    /// Is the entry a present huge page?
+    pub fn is_huge(&self) -> bool {
+        self.flags().contains(PRESENT | HUGE_PAGE)
+    }
+
+    /// Set a huge page at `frame`, which must be aligned to the size of a huge page. `flags` are
+    /// those of a page, with `WRITE_COMBINING` moved to the PAT bit of a huge page
+    pub fn set_huge(&mut self, frame: Frame, flags: EntryFlags) {
+        debug_assert!(frame.start_address().get() % HUGE_PAGE_SIZE == 0);
+        let mut bits = (frame.start_address().get() as u64) | (flags - WRITE_COMBINING).bits() | HUGE_PAGE.bits();
+        if flags.contains(WRITE_COMBINING) {
+            bits |= HUGE_PAT;
+        }
+        self.0 = bits;
+    }
+
+    /// Get the first frame and the flags of a huge page, with the flags as those of a page
+    pub fn huge(&self) -> (Frame, EntryFlags) {
+        let address = self.0 as usize & ADDRESS_MASK & !(HUGE_PAGE_SIZE - 1);
+        let mut flags = self.flags() - HUGE_PAGE;
+        if self.0 & HUGE_PAT == HUGE_PAT {
+            flags.insert(WRITE_COMBINING);
+        }
+        (Frame::containing_address(PhysicalAddress::new(address)), flags)
+    }
 }
@@ -2,7 +2,7 @@ use core::ptr::Unique;
 
 use memory::{allocate_frame, deallocate_frame, Frame};
 
-use super::{Page, PAGE_SIZE, PhysicalAddress, VirtualAddress};
+use super::{Page, ENTRY_COUNT, PAGE_SIZE, HUGE_PAGE_SIZE, PhysicalAddress, VirtualAddress};
 use super::entry::{self, EntryFlags};
 use super::table::{self, Table, Level4};
 
@@ -30,6 +30,7 @@ impl Mapper {
     pub fn map_to(&mut self, page: Page, frame: Frame, flags: EntryFlags) {
         let mut p3 = self.p4_mut().next_table_create(page.p4_index());
         let mut p2 = p3.next_table_create(page.p3_index());
+        p2.split_huge(page.p2_index());
         let mut p1 = p2.next_table_create(page.p2_index());
 
         assert!(p1[page.p1_index()].is_unused(),
@@ -40,6 +41,63 @@ impl Mapper {
         p1[page.p1_index()].set(frame, flags | entry::PRESENT);
     }
 
+    /// Map a huge page, starting at `page`, to the huge page of frames starting at `frame`. Both must
+    /// be aligned to the size of a huge page, and the range must not have pages mapped in it
+    pub fn map_to_huge(&mut self, page: Page, frame: Frame, flags: EntryFlags) {
+        assert!(page.start_address().get() % HUGE_PAGE_SIZE == 0 && frame.start_address().get() % HUGE_PAGE_SIZE == 0,
+            "{:X}: huge page requested at {:X}, which is not aligned",
+            page.start_address().get(), frame.start_address().get());
+
+        let mut p3 = self.p4_mut().next_table_create(page.p4_index());
+        let mut p2 = p3.next_table_create(page.p3_index());
+
+        // A table left by pages that were unmapped is freed, as the huge page replaces it
+        if let Some(p1) = p2.next_table(page.p2_index()) {
+            for i in 0..ENTRY_COUNT {
+                assert!(p1[i].is_unused(),
+                    "{:X}: Set to {:X}: {:?}, requesting huge page at {:X}: {:?}",
+                    page.start_address().get() + i * PAGE_SIZE,
+                    p1[i].address().get(), p1[i].flags(),
+                    frame.start_address().get(), flags);
+            }
+        }
+        if let Some(table) = p2[page.p2_index()].pointed_frame() {
+            if ! p2[page.p2_index()].is_huge() {
+                p2[page.p2_index()].set_unused();
+                deallocate_frame(table);
+            }
+        }
+
+        assert!(p2[page.p2_index()].is_unused(),
+            "{:X}: Set to huge page at {:X}, requesting {:X}: {:?}",
+            page.start_address().get(),
+            p2[page.p2_index()].address().get(),
+            frame.start_address().get(), flags);
+        p2[page.p2_index()].set_huge(frame, flags | entry::PRESENT);
+    }
+
+    /// Map a range of pages to a range of frames, using huge pages where the page and frame are
+    /// both aligned to them. Only the first page needs to be flushed on each huge page
+    pub fn map_range_to(&mut self, page: Page, frame: Frame, count: usize, flags: EntryFlags) {
+        let start = page.start_address().get();
+        let start_frame = frame.start_address().get();
+
+        let mut i = 0;
+        while i < count {
+            let address = start + i * PAGE_SIZE;
+            let frame_address = start_frame + i * PAGE_SIZE;
+            let page = Page::containing_address(VirtualAddress::new(address));
+            let frame = Frame::containing_address(PhysicalAddress::new(frame_address));
+            if address % HUGE_PAGE_SIZE == 0 && frame_address % HUGE_PAGE_SIZE == 0 && count - i >= ENTRY_COUNT {
+                self.map_to_huge(page, frame, flags);
+                i += ENTRY_COUNT;
+            } else {
+                self.map_to(page, frame, flags);
+                i += 1;
+            }
+        }
+    }
+
     /// Map a page to the next free frame
     pub fn map(&mut self, page: Page, flags: EntryFlags) {
         let frame = allocate_frame().expect("out of frames");
@@ -50,6 +108,7 @@ impl Mapper {
     pub fn remap(&mut self, page: Page, flags: EntryFlags) {
         let mut p3 = self.p4_mut().next_table_mut(page.p4_index()).expect("failed to remap: no p3");
         let mut p2 = p3.next_table_mut(page.p3_index()).expect("failed to remap: no p2");
+        p2.split_huge(page.p2_index());
         let mut p1 = p2.next_table_mut(page.p2_index()).expect("failed to remap: no p1");
         let frame = p1[page.p1_index()].pointed_frame().expect("failed to remap: not mapped");
         p1[page.p1_index()].set(frame, flags | entry::PRESENT);
@@ -66,8 +125,11 @@ impl Mapper {
         let p1 = self.p4_mut()
                      .next_table_mut(page.p4_index())
                      .and_then(|p3| p3.next_table_mut(page.p3_index()))
-                     .and_then(|p2| p2.next_table_mut(page.p2_index()))
-                     .expect("mapping code does not support huge pages");
+                     .and_then(|p2| {
+                         p2.split_huge(page.p2_index());
+                         p2.next_table_mut(page.p2_index())
+                     })
+                     .expect("failed to unmap: no p1");
         let frame = p1[page.p1_index()].pointed_frame().unwrap();
         p1[page.p1_index()].set_unused();
         // TODO free p(1,2,3) table if empty
@@ -79,25 +141,97 @@ impl Mapper {
         let p1 = self.p4_mut()
                      .next_table_mut(page.p4_index())
                      .and_then(|p3| p3.next_table_mut(page.p3_index()))
-                     .and_then(|p2| p2.next_table_mut(page.p2_index()))
-                     .expect("mapping code does not support huge pages");
+                     .and_then(|p2| {
+                         p2.split_huge(page.p2_index());
+                         p2.next_table_mut(page.p2_index())
+                     })
+                     .expect("failed to unmap: no p1");
         let frame = p1[page.p1_index()].pointed_frame().unwrap();
         p1[page.p1_index()].set_unused();
         frame
     }
 
+    /// Check if a page is the start of a huge page
+    pub fn is_huge(&self, page: Page) -> bool {
+        page.start_address().get() % HUGE_PAGE_SIZE == 0 && self.p4().next_table(page.p4_index())
+            .and_then(|p3| p3.next_table(page.p3_index()))
+            .map_or(false, |p2| p2[page.p2_index()].is_huge())
+    }
+
+    /// Unmap the huge page starting at `page`, returning its first frame without freeing it
+    pub fn unmap_huge(&mut self, page: Page) -> Frame {
+        let p2 = self.p4_mut()
+                     .next_table_mut(page.p4_index())
+                     .and_then(|p3| p3.next_table_mut(page.p3_index()))
+                     .expect("failed to unmap huge page: no p2");
+        assert!(p2[page.p2_index()].is_huge(), "{:X}: not a huge page", page.start_address().get());
+        let (frame, _) = p2[page.p2_index()].huge();
+        p2[page.p2_index()].set_unused();
+        frame
+    }
+
+    /// Unmap a range of pages, as mapped by `map_range_to`, without freeing their frames. Huge
+    /// pages are unmapped whole where the range covers them
+    pub fn unmap_range_return(&mut self, page: Page, count: usize) {
+        let start = page.start_address().get();
+
+        let mut i = 0;
+        while i < count {
+            let page = Page::containing_address(VirtualAddress::new(start + i * PAGE_SIZE));
+            if count - i >= ENTRY_COUNT && self.is_huge(page) {
+                self.unmap_huge(page);
+                i += ENTRY_COUNT;
+            } else {
+                self.unmap_return(page);
+                i += 1;
+            }
+        }
+    }
+
     pub fn translate_page(&self, page: Page) -> Option<Frame> {
         self.p4().next_table(page.p4_index())
             .and_then(|p3| p3.next_table(page.p3_index()))
-            .and_then(|p2| p2.next_table(page.p2_index()))
-            .and_then(|p1| p1[page.p1_index()].pointed_frame())
+            .and_then(|p2| if p2[page.p2_index()].is_huge() {
+                let (frame, _) = p2[page.p2_index()].huge();
+                Some(Frame::containing_address(PhysicalAddress::new(frame.start_address().get() + page.p1_index() * PAGE_SIZE)))
+            } else {
+                p2.next_table(page.p2_index()).and_then(|p1| p1[page.p1_index()].pointed_frame())
+            })
+    }
+
+    /// Clear the accessed bit of a page, returning whether it was set, or None if the page is not
+    /// mapped. A huge page is not split, so its bit is that of every page in it. The TLB is not
+    /// flushed, so this is only meaningful for pages of inactive page tables
+    pub fn take_accessed(&mut self, page: Page) -> Option<bool> {
+        let p2 = match self.p4_mut().next_table_mut(page.p4_index()).and_then(|p3| p3.next_table_mut(page.p3_index())) {
+            Some(p2) => p2,
+            None => return None
+        };
+
+        if p2[page.p2_index()].is_huge() {
+            return Some(p2[page.p2_index()].take_accessed());
+        }
+
+        let p1 = match p2.next_table_mut(page.p2_index()) {
+            Some(p1) => p1,
+            None => return None
+        };
+
+        if p1[page.p1_index()].flags().contains(entry::PRESENT) {
+            Some(p1[page.p1_index()].take_accessed())
+        } else {
+            None
+        }
     }
 
     pub fn translate_page_flags(&self, page: Page) -> Option<EntryFlags> {
         self.p4().next_table(page.p4_index())
             .and_then(|p3| p3.next_table(page.p3_index()))
-            .and_then(|p2| p2.next_table(page.p2_index()))
-            .and_then(|p1| Some(p1[page.p1_index()].flags()))
+            .and_then(|p2| if p2[page.p2_index()].is_huge() {
+                Some(p2[page.p2_index()].huge().1)
+            } else {
+                p2.next_table(page.p2_index()).and_then(|p1| Some(p1[page.p1_index()].flags()))
+            })
     }
 
     /// Translate a virtual address to a physical one
@@ -3,6 +3,8 @@
 
 use core::mem;
 use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+use spin::Mutex;
 use x86::{msr, tlb};
 
 use memory::{allocate_frame, Frame};
@@ -22,7 +24,11 @@ pub const ENTRY_COUNT: usize = 512;
 /// Size of pages
 pub const PAGE_SIZE: usize = 4096;
 
-/// Setup page attribute table
+/// Size of huge pages, which are mapped by a single entry of a P2 table
+pub const HUGE_PAGE_SIZE: usize = PAGE_SIZE * ENTRY_COUNT;
+
+/// Setup page attribute table. The first four attributes match the power on defaults, so that
+/// `WRITE_THROUGH` and `NO_CACHE` keep their meaning, and the fifth is selected by `WRITE_COMBINING`
 unsafe fn init_pat() {
     let uncacheable = 0;
     let write_combining = 1;
@@ -135,12 +141,12 @@ pub unsafe fn init(cpu_id: usize, stack_start: usize, stack_end: usize) -> (Acti
 
             let mut remap = |start: usize, end: usize, flags: EntryFlags| {
                 if end > start {
+                    // Parts of large sections that are aligned are mapped with huge pages
                     let start_frame = Frame::containing_address(PhysicalAddress::new(start));
                     let end_frame = Frame::containing_address(PhysicalAddress::new(end - 1));
-                    for frame in Frame::range_inclusive(start_frame, end_frame) {
-                        let page = Page::containing_address(VirtualAddress::new(frame.start_address().get() + ::KERNEL_OFFSET));
-                        mapper.map_to(page, frame, flags);
-                    }
+                    let page = Page::containing_address(VirtualAddress::new(start_frame.start_address().get() + ::KERNEL_OFFSET));
+                    let count = (end_frame.start_address().get() - start_frame.start_address().get()) / PAGE_SIZE + 1;
+                    mapper.map_range_to(page, start_frame, count, flags);
                 }
             };
 
@@ -224,6 +230,59 @@ pub unsafe fn init_ap(cpu_id: usize, bsp_table: usize, stack_start: usize, stack
     init_tcb(cpu_id)
 }
 
+/// Bytes of the MMIO region that have been handed out
+static MMIO_USED: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Ranges recorded by `map_physical`, as physical address, virtual address, and size
+const MMIO_RANGES_MAX: usize = 64;
+
+/// Ranges mapped by `map_physical`, and the number of them. This is not a `Vec`, as devices are
+/// mapped before the heap exists. Ranges past the end of the array are mapped but not recorded
+static MMIO_RANGES: Mutex<([(usize, usize, usize); MMIO_RANGES_MAX], usize)> = Mutex::new(([(0, 0, 0); MMIO_RANGES_MAX], 0));
+
+/// Call `f` with the physical address, virtual address, and size of each range in the MMIO region
+pub fn mmio_ranges<F: FnMut(PhysicalAddress, VirtualAddress, usize)>(mut f: F) {
+    let ranges = MMIO_RANGES.lock();
+    for &(phys, virt, size) in ranges.0[.. ranges.1].iter() {
+        f(PhysicalAddress::new(phys), VirtualAddress::new(virt), size);
+    }
+}
+
+/// Map `size` bytes of device memory at the physical `address` into the kernel's MMIO region,
+/// which is shared by every context. Returns the virtual address of `address`. Mappings are
+/// never removed, so this is for registers that the kernel uses until it stops. Ranges of a huge
+/// page or more are placed so that they can be mapped with huge pages
+pub fn map_physical(active_table: &mut ActivePageTable, address: PhysicalAddress, size: usize, flags: EntryFlags) -> VirtualAddress {
+    let start = address.get() & !(PAGE_SIZE - 1);
+    let offset = address.get() - start;
+    let pages = (offset + size + PAGE_SIZE - 1) / PAGE_SIZE;
+
+    let padding = if pages >= ENTRY_COUNT { HUGE_PAGE_SIZE } else { 0 };
+    let used = MMIO_USED.fetch_add(pages * PAGE_SIZE + padding, Ordering::SeqCst);
+    assert!(used + pages * PAGE_SIZE + padding <= ::KERNEL_MMIO_SIZE, "map_physical: MMIO region is full");
+    let mut virt = ::KERNEL_MMIO_OFFSET + used;
+    if padding > 0 {
+        // Move up to where the virtual address lines up with the physical one in a huge page
+        virt += (start % HUGE_PAGE_SIZE + HUGE_PAGE_SIZE - virt % HUGE_PAGE_SIZE) % HUGE_PAGE_SIZE;
+    }
+
+    let page = Page::containing_address(VirtualAddress::new(virt));
+    let frame = Frame::containing_address(PhysicalAddress::new(start));
+    active_table.map_range_to(page, frame, pages, flags | PRESENT | NO_EXECUTE);
+    active_table.flush_all();
+
+    {
+        let mut ranges = MMIO_RANGES.lock();
+        let count = ranges.1;
+        if count < MMIO_RANGES_MAX {
+            ranges.0[count] = (address.get(), virt + offset, size);
+            ranges.1 += 1;
+        }
+    }
+
+    VirtualAddress::new(virt + offset)
+}
+
 pub struct ActivePageTable {
     mapper: Mapper,
 }
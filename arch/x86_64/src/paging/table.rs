@@ -4,10 +4,10 @@
 use core::marker::PhantomData;
 use core::ops::{Index, IndexMut};
 
-use memory::allocate_frame;
+use memory::{allocate_frame, Frame};
 
 use super::entry::*;
-use super::ENTRY_COUNT;
+use super::{ENTRY_COUNT, PAGE_SIZE, PhysicalAddress};
 
 pub const P4: *mut Table<Level4> = 0xffff_ffff_ffff_f000 as *mut _;
 
@@ -83,6 +83,24 @@ impl<L> Table<L> where L: HierarchicalLevel {
     }
 }
 
+impl Table<Level2> {
+    /// Replace the huge page at `index` with a table of pages that map the same frames, so that
+    /// one of them can be changed on its own
+    pub fn split_huge(&mut self, index: usize) {
+        if self[index].is_huge() {
+            let (start, flags) = self[index].huge();
+            let frame = allocate_frame().expect("no frames available");
+            self[index].set(frame, PRESENT | WRITABLE | USER_ACCESSIBLE);
+
+            let p1 = self.next_table_mut(index).unwrap();
+            for i in 0..ENTRY_COUNT {
+                let frame = Frame::containing_address(PhysicalAddress::new(start.start_address().get() + i * PAGE_SIZE));
+                p1[i].set(frame, flags);
+            }
+        }
+    }
+}
+
 impl<L> Index<usize> for Table<L> where L: TableLevel {
     type Output = Entry;
 
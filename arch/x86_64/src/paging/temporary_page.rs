@@ -36,8 +36,9 @@ impl TemporaryPage {
         unsafe { &mut *(self.map(frame, flags, active_table).get() as *mut Table<Level1>) }
     }
 
-    /// Unmaps the temporary page in the active table.
+    /// Unmaps the temporary page in the active table. The frame is not freed, as it belongs to
+    /// whoever mapped it
     pub fn unmap(&mut self, active_table: &mut ActivePageTable) {
-        active_table.unmap(self.page)
+        active_table.unmap_return(self.page);
     }
 }
@@ -1,6 +1,12 @@
 //! Intrinsics for panic handling
 
+use console::{CONSOLE, Level};
 use interrupt;
+use pstore;
+
+extern {
+    fn kpanic();
+}
 
 #[cfg(not(test))]
 #[lang = "eh_personality"]
@@ -10,13 +16,23 @@ extern "C" fn eh_personality() {}
 /// Required to handle panics
 #[lang = "panic_fmt"]
 extern "C" fn panic_fmt(fmt: ::core::fmt::Arguments, file: &str, line: u32) -> ! {
-    println!("PANIC: {}", fmt);
-    println!("FILE: {}", file);
-    println!("LINE: {}", line);
+    // The console may be held by the code that panicked, or by another CPU, which would never
+    // release it, so it is taken over rather than waited on
+    if CONSOLE.try_lock().is_none() {
+        unsafe { CONSOLE.force_unlock(); }
+    }
+    CONSOLE.lock().set_quiet(false);
+
+    log!(Level::Error, "PANIC: {}", fmt);
+    log!(Level::Error, "FILE: {}", file);
+    log!(Level::Error, "LINE: {}", line);
+
+    unsafe { kpanic(); }
 
     unsafe { interrupt::stack_trace(); }
 
     println!("HALT");
+    pstore::flush();
     loop {
         unsafe { interrupt::halt(); }
     }
@@ -0,0 +1,101 @@
+//! Keeps the tail of the kernel log in memory that the frame allocator does not use, so that the
+//! log of the previous boot can be read after a warm reboot
+
+use core::{cmp, ptr};
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+
+use console::{CONSOLE, LOG_SIZE};
+use memory::{Frame, PSTORE_ADDRESS};
+use paging::{entry, ActivePageTable, Page, PhysicalAddress, VirtualAddress};
+
+/// Size of the persistent log, a page for the header followed by the log ring
+pub const PSTORE_SIZE: usize = 4096 + LOG_SIZE;
+
+/// Marks a header written by this kernel, "RedoxLog"
+const MAGIC: u64 = 0x676F4C786F646552;
+
+/// Header of the persistent log
+#[repr(packed)]
+struct Header {
+    magic: u64,
+    /// Total bytes written to the log, as in `Console::log_range`
+    head: u64,
+    /// `magic ^ head`, so that memory that was cleared or scrambled by a cold boot is not used
+    check: u64
+}
+
+/// Virtual address of the persistent log, zero until it is mapped
+static PSTORE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Log of the previous boot, oldest byte first
+static mut LAST: [u8; LOG_SIZE] = [0; LOG_SIZE];
+static LAST_LEN: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Map the memory reserved by `memory::init`, keep the log of the previous boot if it holds one,
+/// and start saving this boot's log to it
+pub unsafe fn init(active_table: &mut ActivePageTable) {
+    let address = PSTORE_ADDRESS.load(Ordering::SeqCst);
+    if address == 0 {
+        return;
+    }
+
+    for i in 0..PSTORE_SIZE / 4096 {
+        let page = Page::containing_address(VirtualAddress::new(address + i * 4096 + ::KERNEL_OFFSET));
+        let frame = Frame::containing_address(PhysicalAddress::new(address + i * 4096));
+        active_table.map_to(page, frame, entry::PRESENT | entry::WRITABLE | entry::NO_EXECUTE);
+        active_table.flush(page);
+    }
+
+    let virt = address + ::KERNEL_OFFSET;
+    let header = &mut *(virt as *mut Header);
+    let ring = (virt + 4096) as *const u8;
+
+    let head = ptr::read_volatile(&header.head);
+    if ptr::read_volatile(&header.magic) == MAGIC && ptr::read_volatile(&header.check) == MAGIC ^ head {
+        let head = head as usize;
+        let len = cmp::min(head, LOG_SIZE);
+        for i in 0..len {
+            LAST[i] = ptr::read_volatile(ring.offset(((head - len + i) % LOG_SIZE) as isize));
+        }
+        LAST_LEN.store(len, Ordering::SeqCst);
+    }
+
+    ptr::write_volatile(&mut header.magic, MAGIC);
+    ptr::write_volatile(&mut header.head, 0);
+    ptr::write_volatile(&mut header.check, MAGIC);
+
+    PSTORE.store(virt, Ordering::SeqCst);
+
+    // Save what was logged before the memory was mapped
+    CONSOLE.lock().persist_log();
+}
+
+/// Copy the bytes of the log ring from position `start` to `end` to the persistent log
+pub fn save(log: &[u8; LOG_SIZE], start: usize, end: usize) {
+    let virt = PSTORE.load(Ordering::SeqCst);
+    if virt == 0 {
+        return;
+    }
+
+    unsafe {
+        let header = &mut *(virt as *mut Header);
+        let ring = (virt + 4096) as *mut u8;
+        for position in cmp::max(start, end.saturating_sub(LOG_SIZE))..end {
+            ptr::write_volatile(ring.offset((position % LOG_SIZE) as isize), log[position % LOG_SIZE]);
+        }
+        ptr::write_volatile(&mut header.head, end as u64);
+        ptr::write_volatile(&mut header.check, MAGIC ^ end as u64);
+    }
+}
+
+/// Write the persistent log back from the caches to memory, as a reset may not
+pub fn flush() {
+    if PSTORE.load(Ordering::SeqCst) != 0 {
+        unsafe { asm!("wbinvd" : : : "memory" : "intel", "volatile"); }
+    }
+}
+
+/// The log of the previous boot, empty if there was none or it did not survive the reboot
+pub fn last() -> &'static [u8] {
+    unsafe { &LAST[.. LAST_LEN.load(Ordering::SeqCst)] }
+}
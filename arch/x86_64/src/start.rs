@@ -7,13 +7,17 @@ use core::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, AtomicUsize, ATOMIC_USIZE
 
 use acpi;
 use allocator;
+use cmdline;
 use device;
-use externs::memset;
+use externs::{self, memset};
 use gdt;
 use idt;
 use interrupt;
+use kaslr;
 use memory;
 use paging::{self, entry, Page, VirtualAddress};
+use pstore;
+use time;
 
 /// Test of zero values in BSS.
 static BSS_TEST_ZERO: usize = 0;
@@ -26,6 +30,18 @@ static mut TBSS_TEST_ZERO: usize = 0;
 #[thread_local]
 static mut TDATA_TEST_NONZERO: usize = 0xFFFFFFFFFFFFFFFF;
 
+/// Maximum number of CPUs to start, including the BSP, set by the `no-smp` and `maxcpus-*` features
+#[cfg(feature = "no-smp")]
+pub const MAX_CPUS: usize = 1;
+#[cfg(all(not(feature = "no-smp"), feature = "maxcpus-2"))]
+pub const MAX_CPUS: usize = 2;
+#[cfg(all(not(any(feature = "no-smp", feature = "maxcpus-2")), feature = "maxcpus-4"))]
+pub const MAX_CPUS: usize = 4;
+#[cfg(all(not(any(feature = "no-smp", feature = "maxcpus-2", feature = "maxcpus-4")), feature = "maxcpus-8"))]
+pub const MAX_CPUS: usize = 8;
+#[cfg(not(any(feature = "no-smp", feature = "maxcpus-2", feature = "maxcpus-4", feature = "maxcpus-8")))]
+pub const MAX_CPUS: usize = 256;
+
 pub static CPU_COUNT: AtomicUsize = ATOMIC_USIZE_INIT;
 pub static AP_READY: AtomicBool = ATOMIC_BOOL_INIT;
 static BSP_READY: AtomicBool = ATOMIC_BOOL_INIT;
@@ -64,6 +80,9 @@ pub unsafe extern fn kstart() -> ! {
             assert_eq!(DATA_TEST_NONZERO, 0xFFFFFFFFFFFFFFFF);
         }
 
+        // Select the memory routines for this CPU
+        externs::init();
+
         // Initialize memory management
         memory::init(0, &__end as *const u8 as usize - ::KERNEL_OFFSET);
 
@@ -95,22 +114,37 @@ pub unsafe extern fn kstart() -> ! {
         AP_READY.store(false, Ordering::SeqCst);
         BSP_READY.store(false, Ordering::SeqCst);
 
+        // Read the kernel command line
+        cmdline::init();
+
+        // Seed the random placement of memory
+        kaslr::init();
+
         // Setup kernel heap
         {
+            // The heap starts at a random page of its area, which ends where device memory starts
+            let heap_start = ::KERNEL_HEAP_OFFSET + kaslr::slide(::KERNEL_MMIO_OFFSET - ::KERNEL_HEAP_OFFSET - ::KERNEL_HEAP_SIZE, 4096);
+
             // Map heap pages
-            let heap_start_page = Page::containing_address(VirtualAddress::new(::KERNEL_HEAP_OFFSET));
-            let heap_end_page = Page::containing_address(VirtualAddress::new(::KERNEL_HEAP_OFFSET + ::KERNEL_HEAP_SIZE-1));
+            let heap_start_page = Page::containing_address(VirtualAddress::new(heap_start));
+            let heap_end_page = Page::containing_address(VirtualAddress::new(heap_start + ::KERNEL_HEAP_SIZE-1));
             for page in Page::range_inclusive(heap_start_page, heap_end_page) {
                 active_table.map(page, entry::PRESENT | entry::GLOBAL | entry::WRITABLE | entry::NO_EXECUTE);
             }
 
             // Init the allocator
-            allocator::init(::KERNEL_HEAP_OFFSET, ::KERNEL_HEAP_SIZE);
+            allocator::init(heap_start, ::KERNEL_HEAP_SIZE);
         }
 
         // Initialize devices
         device::init(&mut active_table);
 
+        // Keep the log of the previous boot, and save this one
+        pstore::init(&mut active_table);
+
+        // Select the clock source
+        time::init();
+
         // Read ACPI tables, starts APs
         acpi::init(&mut active_table);
 
@@ -146,7 +180,7 @@ pub unsafe extern fn kstart_ap(cpu_id: usize, bsp_table: usize, stack_start: usi
         }
 
         // Initialize devices (for AP)
-        device::init_ap();
+        device::init_ap(cpu_id);
 
         AP_READY.store(true, Ordering::SeqCst);
     }
@@ -175,7 +209,7 @@ pub unsafe fn usermode(ip: usize, sp: usize) -> ! {
         :   "{rax}"(gdt::GDT_USER_DATA << 3 | 3), // Data segment
             "{rbx}"(gdt::GDT_USER_TLS << 3 | 3), // TLS segment
             "{rcx}"(sp), // Stack pointer
-            "{rdx}"(3 << 12 | 1 << 9), // Flags - Set IOPL and interrupt enable flag
+            "{rdx}"(1 << 9), // Flags - Set interrupt enable flag, IOPL is 0 so ports are checked against the TSS
             "{rsi}"(gdt::GDT_USER_CODE << 3 | 3), // Code segment
             "{rdi}"(ip) // IP
         : // No clobers because it never returns
@@ -1,15 +1,128 @@
-use spin::Mutex;
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+use seqlock::SeqLock;
 
-pub static START: Mutex<(u64, u64)> = Mutex::new((0, 0));
-pub static OFFSET: Mutex<(u64, u64)> = Mutex::new((0, 0));
+use device::pvclock;
+
+pub static START: SeqLock<(u64, u64)> = SeqLock::new((0, 0));
+pub static OFFSET: SeqLock<(u64, u64)> = SeqLock::new((0, 0));
+
+/// Clock sources for the monotonic clock, the PIT is always available
+pub const CLOCK_PIT: usize = 0;
+pub const CLOCK_TSC: usize = 1;
+pub const CLOCK_KVM: usize = 2;
+pub const CLOCK_HYPERV: usize = 3;
+
+pub const CLOCK_NAMES: [&'static str; 4] = ["pit", "tsc", "kvmclock", "hyperv"];
+
+/// The clock source used for the monotonic clock
+static SOURCE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The monotonic time when the source was selected and the reading of the source at that time, in
+/// nanoseconds. Time continues from where the previous source left off
+static BASE: SeqLock<(u64, u64)> = SeqLock::new((0, 0));
+
+/// The last monotonic time returned, in nanoseconds
+static LAST: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Multiplier and shift to convert TSC ticks to nanoseconds
+static TSC_SCALE: SeqLock<(u32, i8)> = SeqLock::new((0, 0));
+
+fn nanoseconds(time: (u64, u64)) -> u64 {
+    time.0 * 1000000000 + time.1
+}
+
+/// Read a clock source, in nanoseconds
+fn read(source: usize) -> Option<u64> {
+    match source {
+        CLOCK_PIT => Some(nanoseconds(OFFSET.read())),
+        CLOCK_TSC => {
+            let (mul, shift) = TSC_SCALE.read();
+            if mul == 0 {
+                None
+            } else {
+                Some(pvclock::scale(pvclock::rdtsc(), mul, shift))
+            }
+        },
+        CLOCK_KVM => pvclock::kvm_nanoseconds(),
+        CLOCK_HYPERV => pvclock::hyperv_nanoseconds(),
+        _ => None
+    }
+}
 
 pub fn monotonic() -> (u64, u64) {
-    *OFFSET.lock()
+    let source = SOURCE.load(Ordering::SeqCst);
+    let ns = match read(source) {
+        Some(current) => {
+            let base = BASE.read();
+            // Per CPU clocks may be slightly apart, so time is kept from going backwards
+            let ns = base.0 + current.saturating_sub(base.1);
+            let mut last = LAST.load(Ordering::SeqCst);
+            while ns > last as u64 {
+                let previous = LAST.compare_and_swap(last, ns as usize, Ordering::SeqCst);
+                if previous == last {
+                    last = ns as usize;
+                } else {
+                    last = previous;
+                }
+            }
+            last as u64
+        },
+        None => nanoseconds(OFFSET.read())
+    };
+
+    (ns / 1000000000, ns % 1000000000)
 }
 
 pub fn realtime() -> (u64, u64) {
     let offset = monotonic();
-    let start = *START.lock();
+    let start = START.read();
     let sum = start.1 + offset.1;
     (start.0 + offset.0 + sum / 1000000000, sum % 1000000000)
 }
+
+/// The current clock source
+pub fn clock_source() -> usize {
+    SOURCE.load(Ordering::SeqCst)
+}
+
+/// Check if a clock source can be used
+pub fn clock_available(source: usize) -> bool {
+    match source {
+        // The TSC is only trusted if it is invariant, and its frequency is known
+        CLOCK_TSC => pvclock::invariant_tsc() && pvclock::tsc_hz().is_some(),
+        _ => read(source).is_some()
+    }
+}
+
+/// Switch the monotonic clock to another source, returns false if the source is not available
+pub fn set_clock_source(source: usize) -> bool {
+    if ! clock_available(source) {
+        return false;
+    }
+
+    if source == CLOCK_TSC {
+        if let Some(hz) = pvclock::tsc_hz() {
+            TSC_SCALE.set(pvclock::scale_for(hz));
+        }
+    }
+
+    let now = nanoseconds(monotonic());
+    match read(source) {
+        Some(current) => {
+            BASE.set((now, current));
+            SOURCE.store(source, Ordering::SeqCst);
+            true
+        },
+        None => false
+    }
+}
+
+/// Select the best clock source, preferring a paravirtualized clock
+pub fn init() {
+    for &source in [CLOCK_KVM, CLOCK_HYPERV].iter() {
+        if set_clock_source(source) {
+            println!("Clock: {}", CLOCK_NAMES[source]);
+            break;
+        }
+    }
+}
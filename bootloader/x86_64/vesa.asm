@@ -17,6 +17,8 @@ vesa:
     mov [.miny], cx
     mov [config.xres], cx
     mov [config.yres], cx
+    ;accept 16 and 24 bit modes if there are no 32 bit ones
+    mov byte [.minbpp], 16
 .findmode:
     mov si, [VBECardInfo.videomodeptr]
     mov ax, [VBECardInfo.videomodeptr+2]
@@ -43,7 +45,8 @@ vesa:
     ret
 .foundmode:
     ;check minimum values, really not minimums from an OS perspective but ugly for users
-    cmp byte [VBEModeInfo.bitsperpixel], 32
+    mov cl, [.minbpp]
+    cmp byte [VBEModeInfo.bitsperpixel], cl
     jb .searchmodes
 .testx:
     mov cx, [VBEModeInfo.xresolution]
@@ -114,11 +117,23 @@ vesa:
     mov eax, 1
     ret
 .returngood:
+    ;read the EDID of the monitor for the display driver, invalidating its header if there is none
+    mov ax, 0x4F15
+    mov bl, 1
+    xor cx, cx
+    xor dx, dx
+    mov di, VBEEDID
+    int 0x10
+    cmp ax, 0x4F
+    je .edidgood
+    mov dword [VBEEDID.header], 0
+.edidgood:
     xor eax, eax
     ret
 
 .minx dw 640
 .miny dw 480
+.minbpp db 32
 
 .modeok db ": Is this OK? (s)ave/(y)es/(n)o",10,13,0
 
@@ -0,0 +1,371 @@
+//! AES, and the XTS mode used for disk encryption
+//!
+//! The key schedule is always computed here. The rounds use the AES-NI instructions when the CPU
+//! has them, the software rounds look up tables indexed by secret data, so they are not constant
+//! time
+
+use core::ptr;
+
+use cpu;
+
+const SBOX: [u8; 256] = [
+    0x63, 0x7c, 0x77, 0x7b, 0xf2, 0x6b, 0x6f, 0xc5, 0x30, 0x01, 0x67, 0x2b, 0xfe, 0xd7, 0xab, 0x76,
+    0xca, 0x82, 0xc9, 0x7d, 0xfa, 0x59, 0x47, 0xf0, 0xad, 0xd4, 0xa2, 0xaf, 0x9c, 0xa4, 0x72, 0xc0,
+    0xb7, 0xfd, 0x93, 0x26, 0x36, 0x3f, 0xf7, 0xcc, 0x34, 0xa5, 0xe5, 0xf1, 0x71, 0xd8, 0x31, 0x15,
+    0x04, 0xc7, 0x23, 0xc3, 0x18, 0x96, 0x05, 0x9a, 0x07, 0x12, 0x80, 0xe2, 0xeb, 0x27, 0xb2, 0x75,
+    0x09, 0x83, 0x2c, 0x1a, 0x1b, 0x6e, 0x5a, 0xa0, 0x52, 0x3b, 0xd6, 0xb3, 0x29, 0xe3, 0x2f, 0x84,
+    0x53, 0xd1, 0x00, 0xed, 0x20, 0xfc, 0xb1, 0x5b, 0x6a, 0xcb, 0xbe, 0x39, 0x4a, 0x4c, 0x58, 0xcf,
+    0xd0, 0xef, 0xaa, 0xfb, 0x43, 0x4d, 0x33, 0x85, 0x45, 0xf9, 0x02, 0x7f, 0x50, 0x3c, 0x9f, 0xa8,
+    0x51, 0xa3, 0x40, 0x8f, 0x92, 0x9d, 0x38, 0xf5, 0xbc, 0xb6, 0xda, 0x21, 0x10, 0xff, 0xf3, 0xd2,
+    0xcd, 0x0c, 0x13, 0xec, 0x5f, 0x97, 0x44, 0x17, 0xc4, 0xa7, 0x7e, 0x3d, 0x64, 0x5d, 0x19, 0x73,
+    0x60, 0x81, 0x4f, 0xdc, 0x22, 0x2a, 0x90, 0x88, 0x46, 0xee, 0xb8, 0x14, 0xde, 0x5e, 0x0b, 0xdb,
+    0xe0, 0x32, 0x3a, 0x0a, 0x49, 0x06, 0x24, 0x5c, 0xc2, 0xd3, 0xac, 0x62, 0x91, 0x95, 0xe4, 0x79,
+    0xe7, 0xc8, 0x37, 0x6d, 0x8d, 0xd5, 0x4e, 0xa9, 0x6c, 0x56, 0xf4, 0xea, 0x65, 0x7a, 0xae, 0x08,
+    0xba, 0x78, 0x25, 0x2e, 0x1c, 0xa6, 0xb4, 0xc6, 0xe8, 0xdd, 0x74, 0x1f, 0x4b, 0xbd, 0x8b, 0x8a,
+    0x70, 0x3e, 0xb5, 0x66, 0x48, 0x03, 0xf6, 0x0e, 0x61, 0x35, 0x57, 0xb9, 0x86, 0xc1, 0x1d, 0x9e,
+    0xe1, 0xf8, 0x98, 0x11, 0x69, 0xd9, 0x8e, 0x94, 0x9b, 0x1e, 0x87, 0xe9, 0xce, 0x55, 0x28, 0xdf,
+    0x8c, 0xa1, 0x89, 0x0d, 0xbf, 0xe6, 0x42, 0x68, 0x41, 0x99, 0x2d, 0x0f, 0xb0, 0x54, 0xbb, 0x16
+];
+
+const INV_SBOX: [u8; 256] = [
+    0x52, 0x09, 0x6a, 0xd5, 0x30, 0x36, 0xa5, 0x38, 0xbf, 0x40, 0xa3, 0x9e, 0x81, 0xf3, 0xd7, 0xfb,
+    0x7c, 0xe3, 0x39, 0x82, 0x9b, 0x2f, 0xff, 0x87, 0x34, 0x8e, 0x43, 0x44, 0xc4, 0xde, 0xe9, 0xcb,
+    0x54, 0x7b, 0x94, 0x32, 0xa6, 0xc2, 0x23, 0x3d, 0xee, 0x4c, 0x95, 0x0b, 0x42, 0xfa, 0xc3, 0x4e,
+    0x08, 0x2e, 0xa1, 0x66, 0x28, 0xd9, 0x24, 0xb2, 0x76, 0x5b, 0xa2, 0x49, 0x6d, 0x8b, 0xd1, 0x25,
+    0x72, 0xf8, 0xf6, 0x64, 0x86, 0x68, 0x98, 0x16, 0xd4, 0xa4, 0x5c, 0xcc, 0x5d, 0x65, 0xb6, 0x92,
+    0x6c, 0x70, 0x48, 0x50, 0xfd, 0xed, 0xb9, 0xda, 0x5e, 0x15, 0x46, 0x57, 0xa7, 0x8d, 0x9d, 0x84,
+    0x90, 0xd8, 0xab, 0x00, 0x8c, 0xbc, 0xd3, 0x0a, 0xf7, 0xe4, 0x58, 0x05, 0xb8, 0xb3, 0x45, 0x06,
+    0xd0, 0x2c, 0x1e, 0x8f, 0xca, 0x3f, 0x0f, 0x02, 0xc1, 0xaf, 0xbd, 0x03, 0x01, 0x13, 0x8a, 0x6b,
+    0x3a, 0x91, 0x11, 0x41, 0x4f, 0x67, 0xdc, 0xea, 0x97, 0xf2, 0xcf, 0xce, 0xf0, 0xb4, 0xe6, 0x73,
+    0x96, 0xac, 0x74, 0x22, 0xe7, 0xad, 0x35, 0x85, 0xe2, 0xf9, 0x37, 0xe8, 0x1c, 0x75, 0xdf, 0x6e,
+    0x47, 0xf1, 0x1a, 0x71, 0x1d, 0x29, 0xc5, 0x89, 0x6f, 0xb7, 0x62, 0x0e, 0xaa, 0x18, 0xbe, 0x1b,
+    0xfc, 0x56, 0x3e, 0x4b, 0xc6, 0xd2, 0x79, 0x20, 0x9a, 0xdb, 0xc0, 0xfe, 0x78, 0xcd, 0x5a, 0xf4,
+    0x1f, 0xdd, 0xa8, 0x33, 0x88, 0x07, 0xc7, 0x31, 0xb1, 0x12, 0x10, 0x59, 0x27, 0x80, 0xec, 0x5f,
+    0x60, 0x51, 0x7f, 0xa9, 0x19, 0xb5, 0x4a, 0x0d, 0x2d, 0xe5, 0x7a, 0x9f, 0x93, 0xc9, 0x9c, 0xef,
+    0xa0, 0xe0, 0x3b, 0x4d, 0xae, 0x2a, 0xf5, 0xb0, 0xc8, 0xeb, 0xbb, 0x3c, 0x83, 0x53, 0x99, 0x61,
+    0x17, 0x2b, 0x04, 0x7e, 0xba, 0x77, 0xd6, 0x26, 0xe1, 0x69, 0x14, 0x63, 0x55, 0x21, 0x0c, 0x7d
+];
+
+/// Size of a block, in bytes
+pub const BLOCK_SIZE: usize = 16;
+
+fn xtime(value: u8) -> u8 {
+    if value & 0x80 != 0 {
+        value << 1 ^ 0x1b
+    } else {
+        value << 1
+    }
+}
+
+fn mul(mut a: u8, mut b: u8) -> u8 {
+    let mut result = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            result ^= a;
+        }
+        a = xtime(a);
+        b >>= 1;
+    }
+    result
+}
+
+fn add_round_key(state: &mut [u8], key: &[u8]) {
+    for i in 0..BLOCK_SIZE {
+        state[i] ^= key[i];
+    }
+}
+
+fn sub_bytes(state: &mut [u8], sbox: &[u8; 256]) {
+    for byte in state.iter_mut() {
+        *byte = sbox[*byte as usize];
+    }
+}
+
+/// The state is stored by column, row `r` is shifted left by `r`
+fn shift_rows(state: &mut [u8]) {
+    let old = [
+        state[0], state[1], state[2], state[3], state[4], state[5], state[6], state[7],
+        state[8], state[9], state[10], state[11], state[12], state[13], state[14], state[15]
+    ];
+    for c in 0..4 {
+        for r in 0..4 {
+            state[r + 4 * c] = old[r + 4 * ((c + r) % 4)];
+        }
+    }
+}
+
+fn inv_shift_rows(state: &mut [u8]) {
+    let old = [
+        state[0], state[1], state[2], state[3], state[4], state[5], state[6], state[7],
+        state[8], state[9], state[10], state[11], state[12], state[13], state[14], state[15]
+    ];
+    for c in 0..4 {
+        for r in 0..4 {
+            state[r + 4 * ((c + r) % 4)] = old[r + 4 * c];
+        }
+    }
+}
+
+fn mix_columns(state: &mut [u8]) {
+    for column in state.chunks_mut(4) {
+        let (a0, a1, a2, a3) = (column[0], column[1], column[2], column[3]);
+        column[0] = xtime(a0) ^ xtime(a1) ^ a1 ^ a2 ^ a3;
+        column[1] = a0 ^ xtime(a1) ^ xtime(a2) ^ a2 ^ a3;
+        column[2] = a0 ^ a1 ^ xtime(a2) ^ xtime(a3) ^ a3;
+        column[3] = xtime(a0) ^ a0 ^ a1 ^ a2 ^ xtime(a3);
+    }
+}
+
+fn inv_mix_columns(state: &mut [u8]) {
+    for column in state.chunks_mut(4) {
+        let (a0, a1, a2, a3) = (column[0], column[1], column[2], column[3]);
+        column[0] = mul(a0, 14) ^ mul(a1, 11) ^ mul(a2, 13) ^ mul(a3, 9);
+        column[1] = mul(a0, 9) ^ mul(a1, 14) ^ mul(a2, 11) ^ mul(a3, 13);
+        column[2] = mul(a0, 13) ^ mul(a1, 9) ^ mul(a2, 14) ^ mul(a3, 11);
+        column[3] = mul(a0, 11) ^ mul(a1, 13) ^ mul(a2, 9) ^ mul(a3, 14);
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn encrypt_ni(block: &mut [u8; BLOCK_SIZE], keys: &[u8; 240], rounds: usize) {
+    unsafe {
+        asm!("
+            movdqu xmm0, [$0]
+            movdqu xmm1, [$1]
+            pxor xmm0, xmm1
+            mov rcx, 16
+        2:
+            movdqu xmm1, [$1 + rcx]
+            aesenc xmm0, xmm1
+            add rcx, 16
+            cmp rcx, $2
+            jne 2b
+            movdqu xmm1, [$1 + rcx]
+            aesenclast xmm0, xmm1
+            movdqu [$0], xmm0
+            "
+            :
+            : "r"(block.as_mut_ptr()), "r"(keys.as_ptr()), "r"(rounds * 16)
+            : "rcx", "xmm0", "xmm1", "memory"
+            : "intel", "volatile");
+    }
+}
+
+/// Decrypt with the keys of the equivalent inverse cipher, from `Aes::decrypt_keys`
+#[cfg(target_arch = "x86_64")]
+fn decrypt_ni(block: &mut [u8; BLOCK_SIZE], keys: &[u8; 240], rounds: usize) {
+    unsafe {
+        asm!("
+            movdqu xmm0, [$0]
+            movdqu xmm1, [$1]
+            pxor xmm0, xmm1
+            mov rcx, 16
+        2:
+            movdqu xmm1, [$1 + rcx]
+            aesdec xmm0, xmm1
+            add rcx, 16
+            cmp rcx, $2
+            jne 2b
+            movdqu xmm1, [$1 + rcx]
+            aesdeclast xmm0, xmm1
+            movdqu [$0], xmm0
+            "
+            :
+            : "r"(block.as_mut_ptr()), "r"(keys.as_ptr()), "r"(rounds * 16)
+            : "rcx", "xmm0", "xmm1", "memory"
+            : "intel", "volatile");
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn encrypt_ni(_block: &mut [u8; BLOCK_SIZE], _keys: &[u8; 240], _rounds: usize) {
+    unreachable!();
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn decrypt_ni(_block: &mut [u8; BLOCK_SIZE], _keys: &[u8; 240], _rounds: usize) {
+    unreachable!();
+}
+
+/// Check if the rounds are run by the CPU
+pub fn accelerated() -> bool {
+    cpu::has(cpu::AES)
+}
+
+/// An expanded AES key
+pub struct Aes {
+    rounds: usize,
+    encrypt_keys: [u8; 240],
+    /// Round keys for the AES-NI decryption instructions, in reverse order and passed through
+    /// InvMixColumns
+    decrypt_keys: [u8; 240]
+}
+
+impl Aes {
+    /// Expand a key of 16, 24 or 32 bytes, returning `None` for other sizes
+    pub fn new(key: &[u8]) -> Option<Aes> {
+        let rounds = match key.len() {
+            16 => 10,
+            24 => 12,
+            32 => 14,
+            _ => return None
+        };
+
+        let mut encrypt_keys = [0; 240];
+        let nk = key.len() / 4;
+        encrypt_keys[.. key.len()].copy_from_slice(key);
+        let mut rcon = 1;
+        for i in nk .. 4 * (rounds + 1) {
+            let mut word = [encrypt_keys[i * 4 - 4], encrypt_keys[i * 4 - 3], encrypt_keys[i * 4 - 2], encrypt_keys[i * 4 - 1]];
+            if i % nk == 0 {
+                word = [SBOX[word[1] as usize] ^ rcon, SBOX[word[2] as usize], SBOX[word[3] as usize], SBOX[word[0] as usize]];
+                rcon = xtime(rcon);
+            } else if nk > 6 && i % nk == 4 {
+                sub_bytes(&mut word, &SBOX);
+            }
+            for j in 0..4 {
+                encrypt_keys[i * 4 + j] = encrypt_keys[(i - nk) * 4 + j] ^ word[j];
+            }
+        }
+
+        let mut decrypt_keys = [0; 240];
+        for round in 0 .. rounds + 1 {
+            let key = &encrypt_keys[(rounds - round) * 16 .. (rounds - round + 1) * 16];
+            let decrypt_key = &mut decrypt_keys[round * 16 .. (round + 1) * 16];
+            decrypt_key.copy_from_slice(key);
+            if round > 0 && round < rounds {
+                inv_mix_columns(decrypt_key);
+            }
+        }
+
+        Some(Aes {
+            rounds: rounds,
+            encrypt_keys: encrypt_keys,
+            decrypt_keys: decrypt_keys
+        })
+    }
+
+    pub fn encrypt_block(&self, block: &mut [u8; BLOCK_SIZE]) {
+        if accelerated() {
+            return encrypt_ni(block, &self.encrypt_keys, self.rounds);
+        }
+
+        let keys = &self.encrypt_keys;
+        add_round_key(block, &keys[.. 16]);
+        for round in 1 .. self.rounds {
+            sub_bytes(block, &SBOX);
+            shift_rows(block);
+            mix_columns(block);
+            add_round_key(block, &keys[round * 16 ..]);
+        }
+        sub_bytes(block, &SBOX);
+        shift_rows(block);
+        add_round_key(block, &keys[self.rounds * 16 ..]);
+    }
+
+    pub fn decrypt_block(&self, block: &mut [u8; BLOCK_SIZE]) {
+        if accelerated() {
+            return decrypt_ni(block, &self.decrypt_keys, self.rounds);
+        }
+
+        let keys = &self.encrypt_keys;
+        add_round_key(block, &keys[self.rounds * 16 ..]);
+        for round in (1 .. self.rounds).rev() {
+            inv_shift_rows(block);
+            sub_bytes(block, &INV_SBOX);
+            add_round_key(block, &keys[round * 16 ..]);
+            inv_mix_columns(block);
+        }
+        inv_shift_rows(block);
+        sub_bytes(block, &INV_SBOX);
+        add_round_key(block, &keys[.. 16]);
+    }
+}
+
+impl Drop for Aes {
+    /// Clear the round keys, so they do not stay in freed memory
+    fn drop(&mut self) {
+        for byte in self.encrypt_keys.iter_mut().chain(self.decrypt_keys.iter_mut()) {
+            unsafe { ptr::write_volatile(byte, 0); }
+        }
+    }
+}
+
+/// AES in XTS mode, as in IEEE 1619. Each data unit, usually a sector, is encrypted with a tweak
+/// made from its number, so equal sectors do not look equal
+pub struct Xts {
+    data: Aes,
+    tweak: Aes
+}
+
+impl Xts {
+    /// Take a key of 32 bytes for AES-128 or 64 bytes for AES-256, the first half encrypts the data
+    /// and the second half encrypts the tweak
+    pub fn new(key: &[u8]) -> Option<Xts> {
+        if key.len() != 32 && key.len() != 64 {
+            return None;
+        }
+
+        let (data_key, tweak_key) = key.split_at(key.len() / 2);
+        match (Aes::new(data_key), Aes::new(tweak_key)) {
+            (Some(data), Some(tweak)) => Some(Xts {
+                data: data,
+                tweak: tweak
+            }),
+            _ => None
+        }
+    }
+
+    fn tweak(&self, unit: u64) -> [u8; BLOCK_SIZE] {
+        let mut tweak = [0; BLOCK_SIZE];
+        for i in 0..8 {
+            tweak[i] = (unit >> (i * 8)) as u8;
+        }
+        self.tweak.encrypt_block(&mut tweak);
+        tweak
+    }
+
+    /// Multiply the tweak by x in GF(2^128), for the next block
+    fn next_tweak(tweak: &mut [u8; BLOCK_SIZE]) {
+        let mut carry = 0;
+        for byte in tweak.iter_mut() {
+            let next_carry = *byte >> 7;
+            *byte = *byte << 1 | carry;
+            carry = next_carry;
+        }
+        if carry != 0 {
+            tweak[0] ^= 0x87;
+        }
+    }
+
+    fn process(&self, unit: u64, data: &mut [u8], encrypt: bool) {
+        assert!(data.len() % BLOCK_SIZE == 0, "XTS data must be a multiple of the block size");
+
+        let mut tweak = self.tweak(unit);
+        for chunk in data.chunks_mut(BLOCK_SIZE) {
+            let mut block = [0; BLOCK_SIZE];
+            for i in 0..BLOCK_SIZE {
+                block[i] = chunk[i] ^ tweak[i];
+            }
+            if encrypt {
+                self.data.encrypt_block(&mut block);
+            } else {
+                self.data.decrypt_block(&mut block);
+            }
+            for i in 0..BLOCK_SIZE {
+                chunk[i] = block[i] ^ tweak[i];
+            }
+            Xts::next_tweak(&mut tweak);
+        }
+    }
+
+    /// Encrypt a data unit in place, its length must be a multiple of 16
+    pub fn encrypt(&self, unit: u64, data: &mut [u8]) {
+        self.process(unit, data, true);
+    }
+
+    /// Decrypt a data unit in place, its length must be a multiple of 16
+    pub fn decrypt(&self, unit: u64, data: &mut [u8]) {
+        self.process(unit, data, false);
+    }
+}
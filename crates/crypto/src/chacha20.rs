@@ -0,0 +1,93 @@
+//! The ChaCha20 stream cipher, as described in RFC 7539
+
+/// A ChaCha20 key stream, with a 96 bit nonce and a 32 bit block counter
+pub struct ChaCha20 {
+    state: [u32; 16],
+    block: [u8; 64],
+    used: usize
+}
+
+impl Clone for ChaCha20 {
+    fn clone(&self) -> ChaCha20 {
+        ChaCha20 {
+            state: self.state,
+            block: self.block,
+            used: self.used
+        }
+    }
+}
+
+fn quarter_round(x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    x[a] = x[a].wrapping_add(x[b]); x[d] = (x[d] ^ x[a]).rotate_left(16);
+    x[c] = x[c].wrapping_add(x[d]); x[b] = (x[b] ^ x[c]).rotate_left(12);
+    x[a] = x[a].wrapping_add(x[b]); x[d] = (x[d] ^ x[a]).rotate_left(8);
+    x[c] = x[c].wrapping_add(x[d]); x[b] = (x[b] ^ x[c]).rotate_left(7);
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24
+}
+
+impl ChaCha20 {
+    pub fn new(key: &[u8; 32], nonce: &[u8; 12], counter: u32) -> ChaCha20 {
+        let mut state = [0x61707865, 0x3320646e, 0x79622d32, 0x6b206574, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        for i in 0..8 {
+            state[4 + i] = read_u32(&key[i * 4 ..]);
+        }
+        state[12] = counter;
+        for i in 0..3 {
+            state[13 + i] = read_u32(&nonce[i * 4 ..]);
+        }
+
+        ChaCha20 {
+            state: state,
+            block: [0; 64],
+            used: 64
+        }
+    }
+
+    /// Produce the next block of the key stream, and advance the counter
+    fn next_block(&mut self) {
+        let mut x = self.state;
+        for _ in 0..10 {
+            quarter_round(&mut x, 0, 4, 8, 12);
+            quarter_round(&mut x, 1, 5, 9, 13);
+            quarter_round(&mut x, 2, 6, 10, 14);
+            quarter_round(&mut x, 3, 7, 11, 15);
+            quarter_round(&mut x, 0, 5, 10, 15);
+            quarter_round(&mut x, 1, 6, 11, 12);
+            quarter_round(&mut x, 2, 7, 8, 13);
+            quarter_round(&mut x, 3, 4, 9, 14);
+        }
+
+        for i in 0..16 {
+            let word = x[i].wrapping_add(self.state[i]);
+            self.block[i * 4] = word as u8;
+            self.block[i * 4 + 1] = (word >> 8) as u8;
+            self.block[i * 4 + 2] = (word >> 16) as u8;
+            self.block[i * 4 + 3] = (word >> 24) as u8;
+        }
+        self.used = 0;
+
+        self.state[12] = self.state[12].wrapping_add(1);
+    }
+
+    /// Encrypt or decrypt data in place, by combining it with the key stream
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for byte in data.iter_mut() {
+            if self.used == 64 {
+                self.next_block();
+            }
+            *byte ^= self.block[self.used];
+            self.used += 1;
+        }
+    }
+
+    /// Fill a buffer with the key stream
+    pub fn fill(&mut self, data: &mut [u8]) {
+        for byte in data.iter_mut() {
+            *byte = 0;
+        }
+        self.apply(data);
+    }
+}
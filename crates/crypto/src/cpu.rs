@@ -0,0 +1,64 @@
+//! Detection of the CPU instructions used for acceleration
+
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+
+/// Set once the features have been read
+const DETECTED: usize = 1 << 0;
+/// The crc32 instruction, from SSE4.2
+pub const SSE4_2: usize = 1 << 1;
+/// The SHA-256 instructions, which also need SSSE3 and SSE4.1 for shuffling
+pub const SHA: usize = 1 << 2;
+/// The AES-NI instructions
+pub const AES: usize = 1 << 3;
+
+static FEATURES: AtomicUsize = ATOMIC_USIZE_INIT;
+
+#[cfg(target_arch = "x86_64")]
+fn cpuid(leaf: u32) -> (u32, u32, u32, u32) {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    unsafe {
+        asm!("cpuid"
+            : "={eax}"(eax), "={ebx}"(ebx), "={ecx}"(ecx), "={edx}"(edx)
+            : "{eax}"(leaf), "{ecx}"(0)
+            :
+            : "intel", "volatile");
+    }
+    (eax, ebx, ecx, edx)
+}
+
+#[cfg(target_arch = "x86_64")]
+fn detect() -> usize {
+    let mut features = DETECTED;
+
+    let max_leaf = cpuid(0).0;
+    let (_, _, ecx, _) = cpuid(1);
+    if ecx & 1 << 20 != 0 {
+        features |= SSE4_2;
+    }
+    if ecx & 1 << 25 != 0 {
+        features |= AES;
+    }
+    if max_leaf >= 7 && ecx & 1 << 9 != 0 && ecx & 1 << 19 != 0 {
+        let (_, ebx, _, _) = cpuid(7);
+        if ebx & 1 << 29 != 0 {
+            features |= SHA;
+        }
+    }
+
+    features
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn detect() -> usize {
+    DETECTED
+}
+
+/// Check if a feature is available
+pub fn has(feature: usize) -> bool {
+    let mut features = FEATURES.load(Ordering::Relaxed);
+    if features & DETECTED == 0 {
+        features = detect();
+        FEATURES.store(features, Ordering::Relaxed);
+    }
+    features & feature == feature
+}
@@ -0,0 +1,18 @@
+//! Checksums, hashes and ciphers, using CPU instructions when they are available
+//!
+//! This has no dependencies, so it is shared by the kernel, the filesystem and the rand scheme
+
+#![feature(asm)]
+#![no_std]
+
+pub use self::aes::{Aes, Xts};
+pub use self::chacha20::ChaCha20;
+pub use self::crc32c::crc32c;
+pub use self::sha256::{sha256, Sha256};
+
+pub mod aes;
+pub mod chacha20;
+pub mod crc32c;
+pub mod sha256;
+
+mod cpu;
@@ -0,0 +1,218 @@
+//! SHA-256
+//!
+//! The rounds are run by the SHA instructions when the CPU has them. The message schedule is always
+//! computed here, so the same words are fed to both versions of the rounds
+
+use cpu;
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2
+];
+
+const INITIAL: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19
+];
+
+/// The message schedule of a block, with the round constants added
+fn schedule(block: &[u8]) -> [u32; 64] {
+    let mut w = [0u32; 64];
+    for i in 0..16 {
+        w[i] = (block[i * 4] as u32) << 24 | (block[i * 4 + 1] as u32) << 16
+             | (block[i * 4 + 2] as u32) << 8 | block[i * 4 + 3] as u32;
+    }
+    for i in 16..64 {
+        let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+        let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+        w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+    }
+    for i in 0..64 {
+        w[i] = w[i].wrapping_add(K[i]);
+    }
+    w
+}
+
+fn rounds_software(state: &mut [u32; 8], wk: &[u32; 64]) {
+    let (mut a, mut b, mut c, mut d) = (state[0], state[1], state[2], state[3]);
+    let (mut e, mut f, mut g, mut h) = (state[4], state[5], state[6], state[7]);
+
+    for i in 0..64 {
+        let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+        let ch = (e & f) ^ (!e & g);
+        let t1 = h.wrapping_add(s1).wrapping_add(ch).wrapping_add(wk[i]);
+        let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+        let maj = (a & b) ^ (a & c) ^ (b & c);
+        let t2 = s0.wrapping_add(maj);
+
+        h = g;
+        g = f;
+        f = e;
+        e = d.wrapping_add(t1);
+        d = c;
+        c = b;
+        b = a;
+        a = t1.wrapping_add(t2);
+    }
+
+    state[0] = state[0].wrapping_add(a);
+    state[1] = state[1].wrapping_add(b);
+    state[2] = state[2].wrapping_add(c);
+    state[3] = state[3].wrapping_add(d);
+    state[4] = state[4].wrapping_add(e);
+    state[5] = state[5].wrapping_add(f);
+    state[6] = state[6].wrapping_add(g);
+    state[7] = state[7].wrapping_add(h);
+}
+
+/// sha256rnds2 keeps the state as ABEF and CDGH, and runs two rounds with the words in the low
+/// half of xmm0
+#[cfg(target_arch = "x86_64")]
+fn rounds_sha(state: &mut [u32; 8], wk: &[u32; 64]) {
+    unsafe {
+        asm!("
+            movdqu xmm7, [$0]
+            movdqu xmm2, [$0 + 16]
+            pshufd xmm7, xmm7, 0xB1
+            pshufd xmm2, xmm2, 0x1B
+            movdqa xmm1, xmm7
+            palignr xmm1, xmm2, 8
+            pblendw xmm2, xmm7, 0xF0
+            movdqa xmm3, xmm1
+            movdqa xmm4, xmm2
+
+            xor rcx, rcx
+        2:
+            movdqu xmm0, [$1 + rcx]
+            sha256rnds2 xmm2, xmm1
+            pshufd xmm0, xmm0, 0x0E
+            sha256rnds2 xmm1, xmm2
+            add rcx, 16
+            cmp rcx, 256
+            jne 2b
+
+            paddd xmm1, xmm3
+            paddd xmm2, xmm4
+            pshufd xmm1, xmm1, 0x1B
+            pshufd xmm2, xmm2, 0xB1
+            movdqa xmm7, xmm1
+            pblendw xmm1, xmm2, 0xF0
+            palignr xmm2, xmm7, 8
+            movdqu [$0], xmm1
+            movdqu [$0 + 16], xmm2
+            "
+            :
+            : "r"(state.as_mut_ptr()), "r"(wk.as_ptr())
+            : "rcx", "xmm0", "xmm1", "xmm2", "xmm3", "xmm4", "xmm7", "memory"
+            : "intel", "volatile");
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn rounds_sha(state: &mut [u32; 8], wk: &[u32; 64]) {
+    rounds_software(state, wk)
+}
+
+/// Check if the rounds are run by the CPU
+pub fn accelerated() -> bool {
+    cpu::has(cpu::SHA)
+}
+
+fn compress(state: &mut [u32; 8], block: &[u8]) {
+    let wk = schedule(block);
+    if accelerated() {
+        rounds_sha(state, &wk);
+    } else {
+        rounds_software(state, &wk);
+    }
+}
+
+/// A SHA-256 hash in progress
+pub struct Sha256 {
+    state: [u32; 8],
+    buffer: [u8; 64],
+    buffered: usize,
+    length: u64
+}
+
+impl Clone for Sha256 {
+    fn clone(&self) -> Sha256 {
+        Sha256 {
+            state: self.state,
+            buffer: self.buffer,
+            buffered: self.buffered,
+            length: self.length
+        }
+    }
+}
+
+impl Sha256 {
+    pub fn new() -> Sha256 {
+        Sha256 {
+            state: INITIAL,
+            buffer: [0; 64],
+            buffered: 0,
+            length: 0
+        }
+    }
+
+    /// Add data to the hash
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.length += data.len() as u64;
+
+        if self.buffered > 0 {
+            let count = ::core::cmp::min(64 - self.buffered, data.len());
+            self.buffer[self.buffered .. self.buffered + count].copy_from_slice(&data[.. count]);
+            self.buffered += count;
+            data = &data[count ..];
+
+            if self.buffered < 64 {
+                return;
+            }
+            compress(&mut self.state, &self.buffer);
+            self.buffered = 0;
+        }
+
+        let (blocks, remainder) = data.split_at(data.len() / 64 * 64);
+        for block in blocks.chunks(64) {
+            compress(&mut self.state, block);
+        }
+
+        self.buffer[.. remainder.len()].copy_from_slice(remainder);
+        self.buffered = remainder.len();
+    }
+
+    /// Pad the data and return the hash
+    pub fn finish(mut self) -> [u8; 32] {
+        let bits = self.length * 8;
+
+        let mut padding = [0u8; 72];
+        padding[0] = 0x80;
+        let zeros = if self.buffered < 56 { 55 - self.buffered } else { 119 - self.buffered };
+        for i in 0..8 {
+            padding[1 + zeros + i] = (bits >> (56 - i * 8)) as u8;
+        }
+        self.update(&padding[.. 1 + zeros + 8]);
+
+        let mut hash = [0u8; 32];
+        for (i, word) in self.state.iter().enumerate() {
+            hash[i * 4] = (word >> 24) as u8;
+            hash[i * 4 + 1] = (word >> 16) as u8;
+            hash[i * 4 + 2] = (word >> 8) as u8;
+            hash[i * 4 + 3] = *word as u8;
+        }
+        hash
+    }
+}
+
+/// Hash some data
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut hash = Sha256::new();
+    hash.update(data);
+    hash.finish()
+}
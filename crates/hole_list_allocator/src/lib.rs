@@ -4,31 +4,65 @@
 #![allocator]
 #![no_std]
 
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
 use spin::Mutex;
 use linked_list_allocator::Heap;
 
 extern crate spin;
 extern crate linked_list_allocator;
 
+pub mod slab;
+
 static HEAP: Mutex<Option<Heap>> = Mutex::new(None);
 
+/// Size of the heap, in bytes
+static HEAP_SIZE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Bytes of the heap that are allocated, including slabs, whether or not their objects are in use
+static HEAP_USED: AtomicUsize = ATOMIC_USIZE_INIT;
+
 pub unsafe fn init(offset: usize, size: usize) {
     *HEAP.lock() = Some(Heap::new(offset, size));
+    HEAP_SIZE.store(size, Ordering::SeqCst);
 }
 
-#[no_mangle]
-pub extern fn __rust_allocate(size: usize, align: usize) -> *mut u8 {
+/// The size of the heap, and the bytes of it that are allocated
+pub fn heap_stats() -> (usize, usize) {
+    (HEAP_SIZE.load(Ordering::Relaxed), HEAP_USED.load(Ordering::Relaxed))
+}
+
+/// Allocate from the heap itself, bypassing the slabs
+fn heap_allocate(size: usize, align: usize) -> Option<*mut u8> {
     if let Some(ref mut heap) = *HEAP.lock() {
-        heap.allocate_first_fit(size, align).expect("out of memory")
+        let ptr = heap.allocate_first_fit(size, align);
+        if ptr.is_some() {
+            HEAP_USED.fetch_add(size, Ordering::Relaxed);
+        }
+        ptr
     } else {
-        panic!("__rust_allocate: heap not initialized");
+        panic!("heap_allocate: heap not initialized");
     }
 }
 
+#[no_mangle]
+pub extern fn __rust_allocate(size: usize, align: usize) -> *mut u8 {
+    if let Some(class) = slab::class(size, align) {
+        return slab::allocate(class).expect("out of memory");
+    }
+
+    heap_allocate(size, align).expect("out of memory")
+}
+
 #[no_mangle]
 pub extern fn __rust_deallocate(ptr: *mut u8, size: usize, align: usize) {
+    if let Some(class) = slab::class(size, align) {
+        unsafe { slab::deallocate(class, ptr) };
+        return;
+    }
+
     if let Some(ref mut heap) = *HEAP.lock() {
         unsafe { heap.deallocate(ptr, size, align) };
+        HEAP_USED.fetch_sub(size, Ordering::Relaxed);
     } else {
         panic!("__rust_deallocate: heap not initialized");
     }
@@ -51,6 +85,13 @@ pub extern fn __rust_reallocate(ptr: *mut u8, size: usize, new_size: usize,
                                 align: usize) -> *mut u8 {
     use core::{ptr, cmp};
 
+    // Objects stay where they are if they do not change class
+    if let Some(class) = slab::class(size, align) {
+        if slab::class(new_size, align) == Some(class) {
+            return ptr;
+        }
+    }
+
     // from: https://github.com/rust-lang/rust/blob/
     //     c66d2380a810c9a2b3dbb4f93a830b101ee49cc2/
     //     src/liballoc_system/lib.rs#L98-L101
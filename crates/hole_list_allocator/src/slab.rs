@@ -0,0 +1,113 @@
+//! Caches of fixed size objects, in front of the heap
+//!
+//! Small allocations, such as contexts, grants, and scheme handles, are served from a free list
+//! for their size class. Each class has its own lock, so they do not contend on the heap lock,
+//! and objects of one size are packed together, so they do not fragment the heap.
+//! Pages given to a class are kept by it, as the objects on them are reused.
+//TODO: Per-CPU caches, once there is per-CPU data
+
+use spin::Mutex;
+
+use heap_allocate;
+
+/// Object sizes of each class. Slabs are aligned to their size, so each object is aligned to
+/// its size
+pub const SLAB_SIZES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Bytes taken from the heap when a class runs out of objects
+const SLAB_SIZE: usize = 4096;
+
+/// A free list of objects of one size
+pub struct Slab {
+    size: usize,
+    /// Address of the first free object, which holds the address of the next, or zero
+    free: usize,
+    /// Number of objects carved out of slabs
+    total: usize,
+    /// Number of those objects that are allocated
+    used: usize
+}
+
+impl Slab {
+    const fn new(size: usize) -> Slab {
+        Slab {
+            size: size,
+            free: 0,
+            total: 0,
+            used: 0
+        }
+    }
+
+    /// Take a slab from the heap and put its objects on the free list
+    fn refill(&mut self) -> bool {
+        let slab = match heap_allocate(SLAB_SIZE, SLAB_SIZE) {
+            Some(slab) => slab as usize,
+            None => return false
+        };
+
+        let mut offset = SLAB_SIZE;
+        while offset >= self.size {
+            offset -= self.size;
+            let object = slab + offset;
+            unsafe { *(object as *mut usize) = self.free; }
+            self.free = object;
+            self.total += 1;
+        }
+
+        true
+    }
+
+    fn allocate(&mut self) -> Option<*mut u8> {
+        if self.free == 0 && ! self.refill() {
+            return None;
+        }
+
+        let object = self.free;
+        self.free = unsafe { *(object as *const usize) };
+        self.used += 1;
+        Some(object as *mut u8)
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8) {
+        *(ptr as *mut usize) = self.free;
+        self.free = ptr as usize;
+        self.used -= 1;
+    }
+}
+
+static SLABS: [Mutex<Slab>; 8] = [
+    Mutex::new(Slab::new(16)),
+    Mutex::new(Slab::new(32)),
+    Mutex::new(Slab::new(64)),
+    Mutex::new(Slab::new(128)),
+    Mutex::new(Slab::new(256)),
+    Mutex::new(Slab::new(512)),
+    Mutex::new(Slab::new(1024)),
+    Mutex::new(Slab::new(2048))
+];
+
+/// The class of an allocation of `size` bytes aligned to `align`, if it is small enough for one
+pub fn class(size: usize, align: usize) -> Option<usize> {
+    let size = if align > size { align } else { size };
+    SLAB_SIZES.iter().position(|&class_size| size <= class_size)
+}
+
+/// Allocate an object of `class`, returning None if the heap is exhausted
+pub fn allocate(class: usize) -> Option<*mut u8> {
+    SLABS[class].lock().allocate()
+}
+
+/// Return an object to `class`
+pub unsafe fn deallocate(class: usize, ptr: *mut u8) {
+    SLABS[class].lock().deallocate(ptr);
+}
+
+/// Objects carved out of slabs, and objects allocated, for each class
+pub fn stats() -> [(usize, usize); 8] {
+    let mut stats = [(0, 0); 8];
+    for (i, slab) in SLABS.iter().enumerate() {
+        let slab = slab.lock();
+        stats[i] = (slab.total, slab.used);
+    }
+    stats
+}
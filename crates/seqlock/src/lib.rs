@@ -0,0 +1,91 @@
+//! Sequence lock, for small values that are read far more often than they are written
+//!
+//! Readers never write to shared memory, so they do not slow each other down. A reader copies the
+//! value and checks that the sequence number did not change while it was copying, retrying if a
+//! writer got in the way. Writers make the sequence number odd while they change the value.
+
+#![feature(const_fn)]
+#![no_std]
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::ptr;
+use core::sync::atomic::{fence, AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+
+pub struct SeqLock<T: Copy> {
+    seq: AtomicUsize,
+    data: UnsafeCell<T>
+}
+
+unsafe impl<T: Copy + Send> Sync for SeqLock<T> {}
+unsafe impl<T: Copy + Send> Send for SeqLock<T> {}
+
+impl<T: Copy> SeqLock<T> {
+    pub const fn new(value: T) -> SeqLock<T> {
+        SeqLock {
+            seq: ATOMIC_USIZE_INIT,
+            data: UnsafeCell::new(value)
+        }
+    }
+
+    /// Read a copy of the value
+    ///
+    /// This spins while a write is in progress, so it must not be called from an interrupt
+    /// handler that may have interrupted a writer on the same CPU
+    pub fn read(&self) -> T {
+        loop {
+            let seq = self.seq.load(Ordering::Acquire);
+            if seq & 1 == 0 {
+                let value = unsafe { ptr::read_volatile(self.data.get()) };
+                fence(Ordering::Acquire);
+                if self.seq.load(Ordering::Relaxed) == seq {
+                    return value;
+                }
+            }
+        }
+    }
+
+    /// Lock the value for writing, other writers wait and readers retry until the guard is dropped
+    pub fn write(&self) -> SeqLockGuard<T> {
+        loop {
+            let seq = self.seq.load(Ordering::Relaxed);
+            if seq & 1 == 0 && self.seq.compare_and_swap(seq, seq + 1, Ordering::Acquire) == seq {
+                break;
+            }
+        }
+        fence(Ordering::Release);
+
+        SeqLockGuard {
+            lock: self
+        }
+    }
+
+    /// Replace the value
+    pub fn set(&self, value: T) {
+        *self.write() = value;
+    }
+}
+
+pub struct SeqLockGuard<'a, T: Copy + 'a> {
+    lock: &'a SeqLock<T>
+}
+
+impl<'a, T: Copy> Deref for SeqLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: Copy> DerefMut for SeqLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T: Copy> Drop for SeqLockGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.seq.fetch_add(1, Ordering::Release);
+    }
+}
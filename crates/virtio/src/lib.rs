@@ -0,0 +1,275 @@
+//! Legacy virtio PCI transport and virtqueues
+
+extern crate dma;
+extern crate io;
+extern crate syscall;
+
+use std::{cmp, ptr};
+use std::sync::atomic::{fence, Ordering};
+
+use dma::Dma;
+use io::{Io, Pio};
+use syscall::error::{Error, EINVAL, ENOENT, Result};
+
+/// PCI vendor ID of virtio devices
+pub const VENDOR_ID: u16 = 0x1AF4;
+
+const DEVICE_FEATURES: u16 = 0x00;
+const GUEST_FEATURES: u16 = 0x04;
+const QUEUE_ADDRESS: u16 = 0x08;
+const QUEUE_SIZE: u16 = 0x0C;
+const QUEUE_SELECT: u16 = 0x0E;
+const QUEUE_NOTIFY: u16 = 0x10;
+const DEVICE_STATUS: u16 = 0x12;
+const ISR_STATUS: u16 = 0x13;
+/// Device specific configuration, at this offset as MSI-X is not enabled
+const DEVICE_CONFIG: u16 = 0x14;
+
+/// Size of the legacy registers and the largest device configuration used by the drivers
+pub const PORTS: u16 = 0x40;
+
+pub const STATUS_ACKNOWLEDGE: u8 = 1;
+pub const STATUS_DRIVER: u8 = 2;
+pub const STATUS_DRIVER_OK: u8 = 4;
+pub const STATUS_FAILED: u8 = 128;
+
+pub const ISR_QUEUE: u8 = 1;
+pub const ISR_CONFIG: u8 = 2;
+
+const DESC_F_WRITE: u16 = 2;
+
+/// Largest queue size supported, limited by the size of the ring allocation
+const QUEUE_MAX: usize = 256;
+/// Size of the ring allocation, holding the descriptors and available ring, then the used ring
+const RING_SIZE: usize = 16384;
+/// Alignment of the used ring
+const RING_ALIGN: usize = 4096;
+
+/// Size of each buffer
+pub const BUFFER_SIZE: usize = 1024;
+/// Number of buffers in each queue, each buffer has a fixed descriptor
+pub const BUFFERS: usize = 16;
+
+/// Registers of a legacy virtio device, in its I/O BAR
+pub struct Transport {
+    base: u16
+}
+
+impl Transport {
+    pub fn new(base: u16) -> Transport {
+        Transport {
+            base: base
+        }
+    }
+
+    fn read8(&self, register: u16) -> u8 {
+        Pio::<u8>::new(self.base + register).read()
+    }
+
+    fn read16(&self, register: u16) -> u16 {
+        Pio::<u16>::new(self.base + register).read()
+    }
+
+    fn read32(&self, register: u16) -> u32 {
+        Pio::<u32>::new(self.base + register).read()
+    }
+
+    fn write8(&self, register: u16, value: u8) {
+        Pio::<u8>::new(self.base + register).write(value);
+    }
+
+    fn write16(&self, register: u16, value: u16) {
+        Pio::<u16>::new(self.base + register).write(value);
+    }
+
+    fn write32(&self, register: u16, value: u32) {
+        Pio::<u32>::new(self.base + register).write(value);
+    }
+
+    /// Reset the device, then acknowledge it and announce a driver
+    pub fn reset(&self) {
+        self.write8(DEVICE_STATUS, 0);
+        self.write8(DEVICE_STATUS, STATUS_ACKNOWLEDGE);
+        self.write8(DEVICE_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+    }
+
+    /// Add to the device status
+    pub fn status(&self, status: u8) {
+        let current = self.read8(DEVICE_STATUS);
+        self.write8(DEVICE_STATUS, current | status);
+    }
+
+    /// Offer the features of the device that are also in `features`, returning the accepted features
+    pub fn features(&self, features: u32) -> u32 {
+        let accepted = self.read32(DEVICE_FEATURES) & features;
+        self.write32(GUEST_FEATURES, accepted);
+        accepted
+    }
+
+    /// Read and clear the interrupt status
+    pub fn isr(&self) -> u8 {
+        self.read8(ISR_STATUS)
+    }
+
+    pub fn config8(&self, offset: u16) -> u8 {
+        self.read8(DEVICE_CONFIG + offset)
+    }
+
+    pub fn config16(&self, offset: u16) -> u16 {
+        self.read16(DEVICE_CONFIG + offset)
+    }
+
+    pub fn config32(&self, offset: u16) -> u32 {
+        self.read32(DEVICE_CONFIG + offset)
+    }
+
+    pub fn set_config32(&self, offset: u16, value: u32) {
+        self.write32(DEVICE_CONFIG + offset, value);
+    }
+
+    /// Allocate a queue and give it to the device
+    pub fn queue(&self, index: u16) -> Result<Queue> {
+        self.write16(QUEUE_SELECT, index);
+        let queue = Queue::new(index, self.read16(QUEUE_SIZE) as usize)?;
+        self.write32(QUEUE_ADDRESS, (queue.ring.physical() / 4096) as u32);
+        Ok(queue)
+    }
+
+    /// Take a queue back from the device, which must be done before the queue is dropped
+    pub fn remove_queue(&self, queue: &Queue) {
+        self.write16(QUEUE_SELECT, queue.index);
+        self.write32(QUEUE_ADDRESS, 0);
+    }
+
+    /// Tell the device that buffers were made available in a queue
+    pub fn notify(&self, queue: &Queue) {
+        self.write16(QUEUE_NOTIFY, queue.index);
+    }
+}
+
+/// A split virtqueue, in the legacy layout
+pub struct Queue {
+    pub index: u16,
+    size: usize,
+    ring: Dma<[u8; RING_SIZE]>,
+    buffers: Dma<[[u8; BUFFER_SIZE]; BUFFERS]>,
+    free: Vec<u16>,
+    avail_index: u16,
+    used_index: u16
+}
+
+impl Queue {
+    fn new(index: u16, size: usize) -> Result<Queue> {
+        if size == 0 {
+            return Err(Error::new(ENOENT));
+        }
+        if size > QUEUE_MAX {
+            return Err(Error::new(EINVAL));
+        }
+
+        let mut free = Vec::new();
+        for desc in (0..cmp::min(size, BUFFERS)).rev() {
+            free.push(desc as u16);
+        }
+
+        Ok(Queue {
+            index: index,
+            size: size,
+            ring: Dma::zeroed()?,
+            buffers: Dma::zeroed()?,
+            free: free,
+            avail_index: 0,
+            used_index: 0
+        })
+    }
+
+    fn avail_offset(&self) -> usize {
+        16 * self.size
+    }
+
+    fn used_offset(&self) -> usize {
+        ((self.avail_offset() + 6 + 2 * self.size + RING_ALIGN - 1)/RING_ALIGN) * RING_ALIGN
+    }
+
+    unsafe fn ring_ptr<T>(&self, offset: usize) -> *mut T {
+        (self.ring.as_ptr() as *mut u8).offset(offset as isize) as *mut T
+    }
+
+    /// Make a descriptor available to the device
+    fn push(&mut self, desc: u16, len: usize, flags: u16) {
+        let address = (self.buffers.physical() + desc as usize * BUFFER_SIZE) as u64;
+        let avail_offset = self.avail_offset();
+        let slot = self.avail_index as usize % self.size;
+        unsafe {
+            let desc_offset = 16 * desc as usize;
+            ptr::write_volatile(self.ring_ptr::<u64>(desc_offset), address);
+            ptr::write_volatile(self.ring_ptr::<u32>(desc_offset + 8), len as u32);
+            ptr::write_volatile(self.ring_ptr::<u16>(desc_offset + 12), flags);
+            ptr::write_volatile(self.ring_ptr::<u16>(desc_offset + 14), 0);
+
+            ptr::write_volatile(self.ring_ptr::<u16>(avail_offset + 4 + 2 * slot), desc);
+        }
+
+        // The descriptor must be visible before the index
+        fence(Ordering::SeqCst);
+
+        self.avail_index = self.avail_index.wrapping_add(1);
+        unsafe { ptr::write_volatile(self.ring_ptr::<u16>(avail_offset + 2), self.avail_index); }
+    }
+
+    /// Copy data into a free buffer for the device to read, returning the number of bytes queued,
+    /// or `None` if all buffers are in use
+    pub fn send(&mut self, data: &[u8]) -> Option<usize> {
+        let desc = match self.free.pop() {
+            Some(desc) => desc,
+            None => return None
+        };
+
+        let count = cmp::min(data.len(), BUFFER_SIZE);
+        self.buffers[desc as usize][.. count].copy_from_slice(&data[.. count]);
+        self.push(desc, count, 0);
+
+        Some(count)
+    }
+
+    /// Give every free buffer to the device to write into, returning true if any were given
+    pub fn fill(&mut self) -> bool {
+        let mut filled = false;
+        while let Some(desc) = self.free.pop() {
+            self.push(desc, BUFFER_SIZE, DESC_F_WRITE);
+            filled = true;
+        }
+        filled
+    }
+
+    /// Take the next buffer the device is done with, returning the data it wrote
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let used_offset = self.used_offset();
+        let used_index = unsafe { ptr::read_volatile(self.ring_ptr::<u16>(used_offset + 2)) };
+        if used_index == self.used_index {
+            return None;
+        }
+
+        // The element must be read after the index
+        fence(Ordering::SeqCst);
+
+        let slot = self.used_index as usize % self.size;
+        let (desc, len) = unsafe {
+            (ptr::read_volatile(self.ring_ptr::<u32>(used_offset + 4 + 8 * slot)) as usize,
+             ptr::read_volatile(self.ring_ptr::<u32>(used_offset + 8 + 8 * slot)) as usize)
+        };
+        self.used_index = self.used_index.wrapping_add(1);
+
+        if desc >= BUFFERS {
+            return Some(Vec::new());
+        }
+
+        self.free.push(desc as u16);
+        Some(self.buffers[desc][.. cmp::min(len, BUFFER_SIZE)].to_vec())
+    }
+
+    /// Check if a buffer is free to send data
+    pub fn can_send(&self) -> bool {
+        ! self.free.is_empty()
+    }
+}
@@ -105,4 +105,9 @@ impl Disk {
 
         Ok(sector * 512)
     }
+
+    /// Make the data written so far durable
+    pub fn flush(&mut self) -> Result<()> {
+        self.port.ata_flush(&mut self.clb, &mut self.ctbas)
+    }
 }
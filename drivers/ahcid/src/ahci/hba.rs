@@ -10,6 +10,7 @@ use super::fis::{FisType, FisRegH2D};
 
 const ATA_CMD_READ_DMA_EXT: u8 = 0x25;
 const ATA_CMD_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_CMD_FLUSH_CACHE_EXT: u8 = 0xEA;
 const ATA_CMD_IDENTIFY: u8 = 0xEC;
 const ATA_DEV_BUSY: u8 = 0x80;
 const ATA_DEV_DRQ: u8 = 0x08;
@@ -340,6 +341,57 @@ impl HbaPort {
             Err(Error::new(EIO))
         }
     }
+
+    /// Write the drive's volatile cache to the media, returning once it is stored
+    pub fn ata_flush(&mut self, clb: &mut Dma<[HbaCmdHeader; 32]>, ctbas: &mut [Dma<HbaCmdTable>; 32]) -> Result<()> {
+        self.is.write(u32::MAX);
+
+        if let Some(slot) = self.slot() {
+            let cmdheader = &mut clb[slot as usize];
+            cmdheader.cfl.write((size_of::<FisRegH2D>() / size_of::<u32>()) as u8);
+            cmdheader.prdtl.write(0);
+
+            {
+                let cmdtbl = &mut ctbas[slot as usize];
+                unsafe { ptr::write_bytes(cmdtbl.deref_mut() as *mut HbaCmdTable as *mut u8, 0, size_of::<HbaCmdTable>()) };
+            }
+
+            {
+                let cmdfis = unsafe { &mut *(ctbas[slot as usize].cfis.as_mut_ptr() as *mut FisRegH2D) };
+
+                cmdfis.fis_type.write(FisType::RegH2D as u8);
+                cmdfis.pm.write(1 << 7);
+                cmdfis.command.write(ATA_CMD_FLUSH_CACHE_EXT);
+                cmdfis.device.write(1 << 6);
+            }
+
+            while self.tfd.readf((ATA_DEV_BUSY | ATA_DEV_DRQ) as u32) {
+                pause();
+            }
+
+            self.ci.writef(1 << slot, true);
+
+            self.start();
+
+            // A flush can take much longer than a transfer, as the whole cache may be written
+            while (self.ci.readf(1 << slot) || self.tfd.readf(0x80)) && self.is.read() & HBA_PORT_IS_ERR == 0 {
+                pause();
+            }
+
+            self.stop();
+
+            if self.is.read() & HBA_PORT_IS_ERR != 0 {
+                print!("{}", format!("FLUSH ERROR IS {:X} TFD {:X} SERR {:X}\n", self.is.read(), self.tfd.read(), self.serr.read()));
+                self.is.write(u32::MAX);
+                return Err(Error::new(EIO));
+            }
+
+            Ok(())
+        } else {
+            print!("No Command Slots\n");
+            Err(Error::new(EIO))
+        }
+    }
 }
 
 #[repr(packed)]
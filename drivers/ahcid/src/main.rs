@@ -37,8 +37,8 @@ fn main() {
             let mut socket = unsafe { File::from_raw_fd(socket_fd) };
             syscall::fevent(socket_fd, EVENT_READ).expect("ahcid: failed to fevent disk scheme");
 
-            let mut irq_file = File::open(&format!("irq:{}", irq)).expect("ahcid: failed to open irq file");
-            let irq_fd = irq_file.as_raw_fd();
+            let irq_fd = syscall::open(&format!("irq:{}", irq), syscall::O_RDWR | syscall::O_NONBLOCK).expect("ahcid: failed to open irq file");
+            let mut irq_file = unsafe { File::from_raw_fd(irq_fd) };
             syscall::fevent(irq_fd, EVENT_READ).expect("ahcid: failed to fevent irq file");
 
             let mut event_file = File::open("event:").expect("ahcid: failed to open event file");
@@ -62,7 +62,8 @@ fn main() {
                     let mut irq = [0; 8];
                     if irq_file.read(&mut irq).expect("ahcid: failed to read irq file") >= irq.len() {
                         //TODO : Test for IRQ
-                        //irq_file.write(&irq).expect("ahcid: failed to write irq file");
+                        // The line stays masked until it is acknowledged
+                        irq_file.write(&irq).expect("ahcid: failed to write irq file");
                     }
                 } else {
                     println!("Unknown event {}", event.id);
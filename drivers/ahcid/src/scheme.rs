@@ -7,9 +7,105 @@ use syscall::{Error, EACCES, EBADF, EINVAL, ENOENT, Result, Scheme, Stat, MODE_F
 
 use ahci::disk::Disk;
 
+/// Readahead starts at this size, and doubles on each sequential read up to the maximum
+const READAHEAD_MIN: usize = 16 * 1024;
+const READAHEAD_MAX: usize = 256 * 1024;
+
+/// How a handle expects to be read, set by duplicating it with the name of the advice
+#[derive(Clone, Copy, PartialEq)]
+enum Advice {
+    /// Read ahead once reads are seen to be sequential
+    Normal,
+    /// Always read ahead
+    Sequential,
+    /// Never read ahead
+    Random
+}
+
+#[derive(Clone)]
+struct Handle {
+    disk: Arc<Mutex<Disk>>,
+    seek: usize,
+    advice: Advice,
+    /// Where a read has to start to continue the last one
+    next: usize,
+    /// Size of the next readahead
+    window: usize,
+    /// Data read ahead, and its offset on the disk
+    cache: Vec<u8>,
+    cache_offset: usize
+}
+
+impl Handle {
+    fn new(disk: Arc<Mutex<Disk>>) -> Handle {
+        Handle {
+            disk: disk,
+            seek: 0,
+            advice: Advice::Normal,
+            next: 0,
+            window: 0,
+            cache: Vec::new(),
+            cache_offset: 0
+        }
+    }
+
+    /// Copy what can be read at the current position from the readahead
+    fn read_cache(&mut self, buf: &mut [u8]) -> usize {
+        if self.seek < self.cache_offset || self.seek >= self.cache_offset + self.cache.len() {
+            return 0;
+        }
+
+        let start = self.seek - self.cache_offset;
+        let count = cmp::min(buf.len(), self.cache.len() - start);
+        buf[.. count].copy_from_slice(&self.cache[start .. start + count]);
+        self.seek += count;
+        count
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let mut count = self.read_cache(buf);
+
+        if buf.len() - count >= 512 {
+            let sequential = match self.advice {
+                Advice::Normal => self.seek == self.next,
+                Advice::Sequential => true,
+                Advice::Random => false
+            };
+
+            let mut disk = self.disk.lock();
+            if sequential {
+                self.window = cmp::min(cmp::max(self.window * 2, READAHEAD_MIN), READAHEAD_MAX);
+
+                // Read the request and the window in one transfer, keeping what was not asked for
+                let wanted = (buf.len() - count)/512 * 512;
+                let size = cmp::min(wanted + self.window, (disk.size() as usize).saturating_sub(self.seek))/512 * 512;
+                let mut data = vec![0; size];
+                let read = disk.read((self.seek as u64)/512, &mut data)?;
+
+                let used = cmp::min(read, wanted);
+                buf[count .. count + used].copy_from_slice(&data[.. used]);
+                self.cache = data[used .. read].to_vec();
+                self.cache_offset = self.seek + used;
+                self.seek += used;
+                count += used;
+            } else {
+                self.window = 0;
+                self.cache.clear();
+
+                let read = disk.read((self.seek as u64)/512, &mut buf[count ..])?;
+                self.seek += read;
+                count += read;
+            }
+        }
+
+        self.next = self.seek;
+        Ok(count)
+    }
+}
+
 pub struct DiskScheme {
     disks: Box<[Arc<Mutex<Disk>>]>,
-    handles: Mutex<BTreeMap<usize, (Arc<Mutex<Disk>>, usize)>>,
+    handles: Mutex<BTreeMap<usize, Handle>>,
     next_id: AtomicUsize
 }
 
@@ -37,7 +133,7 @@ impl Scheme for DiskScheme {
 
             if let Some(disk) = self.disks.get(i) {
                 let id = self.next_id.fetch_add(1, Ordering::SeqCst);
-                self.handles.lock().insert(id, (disk.clone(), 0));
+                self.handles.lock().insert(id, Handle::new(disk.clone()));
                 Ok(id)
             } else {
                 Err(Error::new(ENOENT))
@@ -47,11 +143,20 @@ impl Scheme for DiskScheme {
         }
     }
 
-    fn dup(&self, id: usize, _buf: &[u8]) -> Result<usize> {
+    /// Duplicating a handle with `normal`, `sequential` or `random` sets how it reads ahead
+    fn dup(&self, id: usize, buf: &[u8]) -> Result<usize> {
         let mut handles = self.handles.lock();
         let new_handle = {
             let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
-            handle.clone()
+            let mut new_handle = handle.clone();
+            if buf == b"normal" {
+                new_handle.advice = Advice::Normal;
+            } else if buf == b"sequential" {
+                new_handle.advice = Advice::Sequential;
+            } else if buf == b"random" {
+                new_handle.advice = Advice::Random;
+            }
+            new_handle
         };
 
         let new_id = self.next_id.fetch_add(1, Ordering::SeqCst);
@@ -64,7 +169,7 @@ impl Scheme for DiskScheme {
         let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
 
         stat.st_mode = MODE_FILE;
-        stat.st_size = handle.0.lock().size();
+        stat.st_size = handle.disk.lock().size();
         Ok(0)
     }
 
@@ -72,19 +177,27 @@ impl Scheme for DiskScheme {
         let mut handles = self.handles.lock();
         let mut handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
 
-        let mut disk = handle.0.lock();
-        let count = disk.read((handle.1 as u64)/512, buf)?;
-        handle.1 += count;
-        Ok(count)
+        handle.read(buf)
     }
 
     fn write(&self, id: usize, buf: &[u8]) -> Result<usize> {
         let mut handles = self.handles.lock();
-        let mut handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
 
-        let mut disk = handle.0.lock();
-        let count = disk.write((handle.1 as u64)/512, buf)?;
-        handle.1 += count;
+        let (disk, count) = {
+            let mut handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+
+            let count = handle.disk.lock().write((handle.seek as u64)/512, buf)?;
+            handle.seek += count;
+            (handle.disk.clone(), count)
+        };
+
+        // Data read ahead from the disk may have been changed
+        for handle in handles.values_mut() {
+            if &*handle.disk as *const Mutex<Disk> == &*disk as *const Mutex<Disk> {
+                handle.cache.clear();
+            }
+        }
+
         Ok(count)
     }
 
@@ -92,15 +205,22 @@ impl Scheme for DiskScheme {
         let mut handles = self.handles.lock();
         let mut handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
 
-        let len = handle.0.lock().size() as usize;
-        handle.1 = match whence {
+        let len = handle.disk.lock().size() as usize;
+        handle.seek = match whence {
             SEEK_SET => cmp::min(len, pos),
-            SEEK_CUR => cmp::max(0, cmp::min(len as isize, handle.1 as isize + pos as isize)) as usize,
+            SEEK_CUR => cmp::max(0, cmp::min(len as isize, handle.seek as isize + pos as isize)) as usize,
             SEEK_END => cmp::max(0, cmp::min(len as isize, len as isize + pos as isize)) as usize,
             _ => return Err(Error::new(EINVAL))
         };
 
-        Ok(handle.1)
+        Ok(handle.seek)
+    }
+
+    fn fsync(&self, id: usize) -> Result<usize> {
+        let handles = self.handles.lock();
+        let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
+
+        handle.disk.lock().flush().and(Ok(0))
     }
 
     fn close(&self, id: usize) -> Result<usize> {
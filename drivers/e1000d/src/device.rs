@@ -2,7 +2,8 @@ use std::{cmp, mem, ptr, slice};
 
 use dma::Dma;
 use netutils::setcfg;
-use syscall::error::{Error, EACCES, EWOULDBLOCK, Result};
+use syscall;
+use syscall::error::{Error, EACCES, EMSGSIZE, EWOULDBLOCK, Result};
 use syscall::flag::O_NONBLOCK;
 use syscall::scheme::Scheme;
 
@@ -50,6 +51,10 @@ const RDLEN: u32 = 0x2808;
 const RDH: u32 = 0x2810;
 const RDT: u32 = 0x2818;
 
+const RXCSUM: u32 = 0x5000;
+const RXCSUM_IPOFL: u32 = 1 << 8;
+const RXCSUM_TUOFL: u32 = 1 << 9;
+
 const RAL0: u32 = 0x5400;
 const RAH0: u32 = 0x5404;
 
@@ -65,6 +70,11 @@ struct Rd {
 }
 const RD_DD: u8 = 1;
 const RD_EOP: u8 = 1 << 1;
+const RD_IXSM: u8 = 1 << 2;
+const RD_TCPCS: u8 = 1 << 5;
+const RD_IPCS: u8 = 1 << 6;
+const RD_ERR_TCPE: u8 = 1 << 5;
+const RD_ERR_IPE: u8 = 1 << 6;
 
 const TCTL: u32 = 0x400;
 const TCTL_EN: u32 = 1 << 1;
@@ -92,18 +102,131 @@ const TD_CMD_IFCS: u8 = 1 << 1;
 const TD_CMD_RS: u8 = 1 << 3;
 const TD_DD: u8 = 1;
 
+/// Context descriptor, which sets up checksum insertion and segmentation for the extended data
+/// descriptors after it
+#[derive(Debug)]
+#[repr(packed)]
+struct Tcd {
+    ipcss: u8,
+    ipcso: u8,
+    ipcse: u16,
+    tucss: u8,
+    tucso: u8,
+    tucse: u16,
+    paylen_dtyp_tucmd: u32,
+    status: u8,
+    hdrlen: u8,
+    mss: u16,
+}
+const TCD_TUCMD_TCP: u8 = 1;
+const TCD_TUCMD_IP: u8 = 1 << 1;
+const TCD_TUCMD_TSE: u8 = 1 << 2;
+const TCD_TUCMD_DEXT: u8 = 1 << 5;
+
+/// Extended data descriptor
+#[derive(Debug)]
+#[repr(packed)]
+struct Tdd {
+    buffer: u64,
+    length_dtyp_dcmd: u32,
+    status: u8,
+    popts: u8,
+    special: u16,
+}
+const TDD_DTYP_DATA: u32 = 1 << 20;
+const TDD_DCMD_EOP: u8 = 1;
+const TDD_DCMD_IFCS: u8 = 1 << 1;
+const TDD_DCMD_TSE: u8 = 1 << 2;
+const TDD_DCMD_RS: u8 = 1 << 3;
+const TDD_DCMD_DEXT: u8 = 1 << 5;
+const TDD_POPTS_IXSM: u8 = 1;
+const TDD_POPTS_TXSM: u8 = 1 << 1;
+
+/// Set in the ID of handles opened with `offload`. Frames written to them have their IPv4 and TCP
+/// checksums inserted, and are split into segments if they are larger than the MTU
+const ID_OFFLOAD: usize = 1 << 32;
+
+/// Largest payload of a frame. Receive buffers are 16 KB, with long packets enabled
+const MTU: usize = 9000;
+
+/// Largest frame that is sent, with its header. The CRC is added by the device
+const FRAME_MAX: usize = MTU + 14;
+
+/// Largest frame that is split into segments by the device
+const TSO_MAX: usize = 65535 + 14;
+
+const PAGE_SIZE: usize = 4096;
+
+/// The physical address and length of each page of a buffer, so a frame can be sent from the
+/// pages of the process that wrote it, which the kernel maps into this one
+fn physical_chunks(buf: &[u8]) -> Result<Vec<(u64, usize)>> {
+    let mut chunks = Vec::new();
+
+    let mut address = buf.as_ptr() as usize;
+    let end = address + buf.len();
+    while address < end {
+        let len = cmp::min(end, (address / PAGE_SIZE + 1) * PAGE_SIZE) - address;
+        let physical = unsafe { syscall::virttophys(address)? };
+        chunks.push((physical as u64, len));
+        address += len;
+    }
+
+    Ok(chunks)
+}
+
+/// Where the headers of an IPv4 TCP frame are, for the context descriptor
+#[derive(Clone, Copy)]
+struct Offload {
+    /// Offset of the TCP header
+    tcp: usize,
+    /// Length of the ethernet, IPv4 and TCP headers
+    hdrlen: usize,
+    /// The frame is larger than the MTU, and is split into segments
+    tso: bool,
+}
+
+impl Offload {
+    fn parse(frame: &[u8]) -> Option<Offload> {
+        // Only IPv4 TCP, as the stack leaves UDP checksums empty
+        if frame.len() < 14 + 20 || frame[12] != 0x08 || frame[13] != 0x00 || frame[14 + 9] != 6 {
+            return None;
+        }
+
+        let tcp = 14 + (frame[14] & 0xF) as usize * 4;
+        if frame.len() < tcp + 20 {
+            return None;
+        }
+
+        let hdrlen = tcp + (frame[tcp + 12] >> 4) as usize * 4;
+        if frame.len() < hdrlen {
+            return None;
+        }
+
+        Some(Offload {
+            tcp: tcp,
+            hdrlen: hdrlen,
+            tso: frame.len() > FRAME_MAX
+        })
+    }
+}
+
 pub struct Intel8254x {
     base: usize,
     receive_buffer: [Dma<[u8; 16384]>; 16],
     receive_ring: Dma<[Rd; 16]>,
     transmit_buffer: [Dma<[u8; 16384]>; 16],
-    transmit_ring: Dma<[Td; 16]>
+    /// Larger than the transmit buffers, so a segmentation offload of 64 KB fits
+    transmit_ring: Dma<[Td; 32]>
 }
 
 impl Scheme for Intel8254x {
-    fn open(&self, _path: &[u8], flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+    fn open(&self, path: &[u8], flags: usize, uid: u32, _gid: u32) -> Result<usize> {
         if uid == 0 {
-            Ok(flags)
+            if path == b"offload" {
+                Ok(flags | ID_OFFLOAD)
+            } else {
+                Ok(flags)
+            }
         } else {
             Err(Error::new(EACCES))
         }
@@ -114,29 +237,44 @@ impl Scheme for Intel8254x {
     }
 
     fn read(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
-        let head = unsafe { self.read(RDH) };
-        let mut tail = unsafe { self.read(RDT) };
+        loop {
+            let head = unsafe { self.read(RDH) };
+            let mut tail = unsafe { self.read(RDT) };
 
-        tail += 1;
-        if tail >= self.receive_ring.len() as u32 {
-            tail = 0;
-        }
+            tail += 1;
+            if tail >= self.receive_ring.len() as u32 {
+                tail = 0;
+            }
+
+            if tail == head {
+                break;
+            }
 
-        if tail != head {
             let rd = unsafe { &mut * (self.receive_ring.as_ptr().offset(tail as isize) as *mut Rd) };
-            if rd.status & RD_DD == RD_DD {
-                rd.status = 0;
+            if rd.status & RD_DD != RD_DD {
+                break;
+            }
 
-                let data = &self.receive_buffer[tail as usize][.. rd.length as usize];
+            // Frames with checksums that the device found to be wrong are dropped
+            let status = rd.status;
+            let bad = status & RD_IXSM != RD_IXSM
+                      && ((status & RD_IPCS == RD_IPCS && rd.error & RD_ERR_IPE == RD_ERR_IPE)
+                          || (status & RD_TCPCS == RD_TCPCS && rd.error & RD_ERR_TCPE == RD_ERR_TCPE));
+
+            rd.status = 0;
 
-                let mut i = 0;
+            let mut i = 0;
+            if ! bad {
+                let data = &self.receive_buffer[tail as usize][.. rd.length as usize];
                 while i < buf.len() && i < data.len() {
                     buf[i] = data[i];
                     i += 1;
                 }
+            }
 
-                unsafe { self.write(RDT, tail) };
+            unsafe { self.write(RDT, tail) };
 
+            if ! bad {
                 return Ok(i);
             }
         }
@@ -148,43 +286,117 @@ impl Scheme for Intel8254x {
         }
     }
 
-    fn write(&self, _id: usize, buf: &[u8]) -> Result<usize> {
-        loop {
-            let head = unsafe { self.read(TDH) };
-            let mut tail = unsafe { self.read(TDT) };
-            let old_tail = tail;
-
-            tail += 1;
-            if tail >= self.transmit_ring.len() as u32 {
-                tail = 0;
-            }
+    fn write(&self, id: usize, buf: &[u8]) -> Result<usize> {
+        let offload = if id & ID_OFFLOAD == ID_OFFLOAD {
+            Offload::parse(buf)
+        } else {
+            None
+        };
 
-            if tail != head {
-                let td = unsafe { &mut * (self.transmit_ring.as_ptr().offset(old_tail as isize) as *mut Td) };
+        let max = if offload.map_or(false, |offload| offload.tso) { TSO_MAX } else { FRAME_MAX };
+        if buf.len() > max {
+            return Err(Error::new(EMSGSIZE));
+        }
 
-                td.cso = 0;
-                td.command = TD_CMD_EOP | TD_CMD_IFCS | TD_CMD_RS;
-                td.status = 0;
-                td.css = 0;
-                td.special = 0;
+        // Frames that fit in a page are copied, larger frames are sent with a descriptor per page
+        let mut chunks = if buf.len() > PAGE_SIZE {
+            physical_chunks(buf)?
+        } else {
+            Vec::new()
+        };
+        let count = cmp::max(chunks.len(), 1) as u32 + if offload.is_some() { 1 } else { 0 };
 
-                td.length = (cmp::min(buf.len(), 0x3FFF)) as u16;
+        let ring_len = self.transmit_ring.len() as u32;
+        loop {
+            let head = unsafe { self.read(TDH) };
+            let tail = unsafe { self.read(TDT) };
+
+            // One descriptor is always left unused, so the tail does not reach the head
+            let used = (tail + ring_len - head) % ring_len;
+            if ring_len - 1 - used >= count {
+                let mut index = tail;
+
+                if let Some(offload) = offload {
+                    let tcd = unsafe { &mut * (self.transmit_ring.as_ptr().offset(index as isize) as *mut Tcd) };
+
+                    let mut tucmd = TCD_TUCMD_DEXT | TCD_TUCMD_IP | TCD_TUCMD_TCP;
+                    let mut paylen = 0;
+                    if offload.tso {
+                        tucmd |= TCD_TUCMD_TSE;
+                        paylen = (buf.len() - offload.hdrlen) as u32;
+                    }
+
+                    tcd.ipcss = 14;
+                    tcd.ipcso = 14 + 10;
+                    tcd.ipcse = (offload.tcp - 1) as u16;
+                    tcd.tucss = offload.tcp as u8;
+                    tcd.tucso = (offload.tcp + 16) as u8;
+                    tcd.tucse = 0;
+                    tcd.paylen_dtyp_tucmd = paylen & 0xFFFFF | (tucmd as u32) << 24;
+                    tcd.status = 0;
+                    tcd.hdrlen = offload.hdrlen as u8;
+                    tcd.mss = (MTU - (offload.hdrlen - 14)) as u16;
+
+                    index = (index + 1) % ring_len;
+                }
 
-                let mut data = unsafe { slice::from_raw_parts_mut(self.transmit_buffer[old_tail as usize].as_ptr() as *mut u8, td.length as usize) };
+                if chunks.is_empty() {
+                    let buffer = &self.transmit_buffer[index as usize % self.transmit_buffer.len()];
+                    let data = unsafe { slice::from_raw_parts_mut(buffer.as_ptr() as *mut u8, buf.len()) };
+                    data.copy_from_slice(buf);
+                    chunks.push((buffer.physical() as u64, buf.len()));
+                }
 
-                let mut i = 0;
-                while i < buf.len() && i < data.len() {
-                    data[i] = buf[i];
-                    i += 1;
+                let mut last = index;
+                for (i, &(physical, len)) in chunks.iter().enumerate() {
+                    last = index;
+                    let eop = i + 1 == chunks.len();
+
+                    if let Some(offload) = offload {
+                        let tdd = unsafe { &mut * (self.transmit_ring.as_ptr().offset(index as isize) as *mut Tdd) };
+
+                        let mut dcmd = TDD_DCMD_DEXT | TDD_DCMD_IFCS;
+                        if offload.tso {
+                            dcmd |= TDD_DCMD_TSE;
+                        }
+                        if eop {
+                            dcmd |= TDD_DCMD_EOP | TDD_DCMD_RS;
+                        }
+
+                        tdd.buffer = physical;
+                        tdd.length_dtyp_dcmd = len as u32 & 0xFFFFF | TDD_DTYP_DATA | (dcmd as u32) << 24;
+                        tdd.status = 0;
+                        tdd.popts = TDD_POPTS_IXSM | TDD_POPTS_TXSM;
+                        tdd.special = 0;
+                    } else {
+                        let td = unsafe { &mut * (self.transmit_ring.as_ptr().offset(index as isize) as *mut Td) };
+
+                        td.buffer = physical;
+                        td.length = len as u16;
+                        td.cso = 0;
+                        td.command = if eop {
+                            TD_CMD_EOP | TD_CMD_IFCS | TD_CMD_RS
+                        } else {
+                            TD_CMD_IFCS
+                        };
+                        td.status = 0;
+                        td.css = 0;
+                        td.special = 0;
+                    }
+
+                    index = (index + 1) % ring_len;
                 }
 
-                unsafe { self.write(TDT, tail) };
+                unsafe { self.write(TDT, index) };
 
-                while td.status == 0 {
+                // The pages of the writer must not be released until the device has read them.
+                // Legacy and extended data descriptors have their status in the same place
+                let td = unsafe { &* (self.transmit_ring.as_ptr().offset(last as isize) as *const Td) };
+                while unsafe { ptr::read_volatile(&td.status) } & TD_DD != TD_DD {
                     unsafe { asm!("pause" : : : "memory" : "intel", "volatile"); }
                 }
 
-                return Ok(i);
+                return Ok(buf.len());
             }
 
             unsafe { asm!("pause" : : : "memory" : "intel", "volatile"); }
@@ -294,6 +506,8 @@ impl Intel8254x {
                     (mac_high >> 8) as u8];
         print!("{}", format!("   - MAC: {:>02X}:{:>02X}:{:>02X}:{:>02X}:{:>02X}:{:>02X}\n", mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]));
         let _ = setcfg("mac", &format!("{:>02X}.{:>02X}.{:>02X}.{:>02X}.{:>02X}.{:>02X}", mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]));
+        let _ = setcfg("mtu", &format!("{}", MTU));
+        let _ = setcfg("offload", "rx_checksum tx_checksum tso");
 
         //
         // MTA => 0;
@@ -310,10 +524,7 @@ impl Intel8254x {
         self.write(RDH, 0);
         self.write(RDT, self.receive_ring.len() as u32 - 1);
 
-        // Transmit Buffer
-        for i in 0..self.transmit_ring.len() {
-            self.transmit_ring[i].buffer = self.transmit_buffer[i].physical() as u64;
-        }
+        // Transmit Buffer, set for each frame
 
         self.write(TDBAH, (self.transmit_ring.physical() >> 32) as u32);
         self.write(TDBAL, self.transmit_ring.physical() as u32);
@@ -325,7 +536,8 @@ impl Intel8254x {
 
         self.flag(RCTL, RCTL_EN, true);
         self.flag(RCTL, RCTL_UPE, true);
-        // self.flag(RCTL, RCTL_MPE, true);
+        // Multicast is needed for IPv6 neighbor discovery
+        self.flag(RCTL, RCTL_MPE, true);
         self.flag(RCTL, RCTL_LPE, true);
         self.flag(RCTL, RCTL_LBM, false);
         // RCTL.RDMTS = Minimum threshold size ???
@@ -336,6 +548,9 @@ impl Intel8254x {
         self.flag(RCTL, RCTL_BSEX, true);
         self.flag(RCTL, RCTL_SECRC, true);
 
+        // Check IPv4, TCP and UDP checksums of received frames
+        self.flag(RXCSUM, RXCSUM_IPOFL | RXCSUM_TUOFL, true);
+
         self.flag(TCTL, TCTL_EN, true);
         self.flag(TCTL, TCTL_PSP, true);
         // TCTL.CT = Collition threshold
@@ -45,13 +45,15 @@ fn main() {
             let device_irq = device.clone();
             let socket_irq = socket.clone();
             let todo_irq = todo.clone();
-            let mut irq_file = File::open(format!("irq:{}", irq)).expect("e1000d: failed to open IRQ file");
+            let irq_fd = syscall::open(&format!("irq:{}", irq), syscall::O_RDWR | syscall::O_NONBLOCK).expect("e1000d: failed to open IRQ file");
+            let mut irq_file = unsafe { File::from_raw_fd(irq_fd) };
             event_queue.add(irq_file.as_raw_fd(), move |_count: usize| -> Result<Option<usize>> {
                 let mut irq = [0; 8];
                 irq_file.read(&mut irq)?;
-                if unsafe { device_irq.irq() } {
-                    irq_file.write(&mut irq)?;
-
+                let claimed = unsafe { device_irq.irq() };
+                // Acknowledged even if the IRQ was for another device on the line, which is masked until then
+                irq_file.write(&mut irq)?;
+                if claimed {
                     let mut todo = todo_irq.borrow_mut();
                     let mut i = 0;
                     while i < todo.len() {
@@ -0,0 +1,190 @@
+use std::{cmp, ptr, str};
+use std::sync::atomic::{fence, Ordering};
+
+use dma::Dma;
+use io::{Io, Pio};
+use syscall::error::{Error, EIO, Result};
+
+/// Selector, data, and DMA address ports
+const SELECTOR: u16 = 0x510;
+const DATA: u16 = 0x511;
+const DMA_ADDRESS: u16 = 0x514;
+
+/// Ports to be allowed for the driver
+pub const PORTS: &'static str = "510-511\n514-51B\n";
+
+/// Selector keys
+const KEY_SIGNATURE: u16 = 0x00;
+const KEY_ID: u16 = 0x01;
+const KEY_CMDLINE_SIZE: u16 = 0x14;
+const KEY_CMDLINE_DATA: u16 = 0x15;
+const KEY_FILE_DIR: u16 = 0x19;
+
+/// Features in the ID
+const ID_DMA: u32 = 1 << 1;
+
+/// DMA control flags, the selector is in the upper 16 bits
+const DMA_ERROR: u32 = 1;
+const DMA_READ: u32 = 1 << 1;
+const DMA_SELECT: u32 = 1 << 3;
+
+/// Length of a file name in the directory
+const FILE_NAME_SIZE: usize = 56;
+
+/// Size of each DMA transfer
+const DMA_BUFFER_SIZE: usize = 65536;
+
+/// DMA access, all fields are big endian
+#[repr(packed)]
+struct DmaAccess {
+    control: u32,
+    length: u32,
+    address: u64
+}
+
+/// A named blob in the file directory
+pub struct File {
+    pub name: String,
+    pub size: usize,
+    pub key: u16
+}
+
+/// QEMU firmware configuration, read with DMA if supported or else with the data port
+pub struct FwCfg {
+    dma: Option<(Dma<DmaAccess>, Dma<[u8; DMA_BUFFER_SIZE]>)>
+}
+
+impl FwCfg {
+    /// Detect the firmware configuration by its signature
+    pub fn new() -> Result<Option<FwCfg>> {
+        let mut fw_cfg = FwCfg {
+            dma: None
+        };
+
+        let mut signature = [0; 4];
+        fw_cfg.read(KEY_SIGNATURE, &mut signature)?;
+        if &signature != b"QEMU" {
+            return Ok(None);
+        }
+
+        let mut id = [0; 4];
+        fw_cfg.read(KEY_ID, &mut id)?;
+        if FwCfg::le32(&id) & ID_DMA == ID_DMA {
+            fw_cfg.dma = Some((Dma::zeroed()?, Dma::zeroed()?));
+        }
+
+        Ok(Some(fw_cfg))
+    }
+
+    pub fn dma(&self) -> bool {
+        self.dma.is_some()
+    }
+
+    fn le32(data: &[u8]) -> u32 {
+        data[0] as u32 | (data[1] as u32) << 8 | (data[2] as u32) << 16 | (data[3] as u32) << 24
+    }
+
+    fn be32(data: &[u8]) -> u32 {
+        (data[0] as u32) << 24 | (data[1] as u32) << 16 | (data[2] as u32) << 8 | data[3] as u32
+    }
+
+    /// Read DMA transfers into the buffer, the first transfer selects the key
+    fn read_dma(access: &mut Dma<DmaAccess>, buffer: &mut Dma<[u8; DMA_BUFFER_SIZE]>, key: u16, buf: &mut [u8]) -> Result<()> {
+        let mut i = 0;
+        let mut select = true;
+        while i < buf.len() || select {
+            let count = cmp::min(buf.len() - i, DMA_BUFFER_SIZE);
+
+            let control = if select {
+                (key as u32) << 16 | DMA_SELECT | DMA_READ
+            } else {
+                DMA_READ
+            };
+            select = false;
+
+            unsafe {
+                ptr::write_volatile(&mut access.control, control.to_be());
+                ptr::write_volatile(&mut access.length, (count as u32).to_be());
+                ptr::write_volatile(&mut access.address, (buffer.physical() as u64).to_be());
+            }
+
+            // The access must be visible before it is started
+            fence(Ordering::SeqCst);
+
+            // Writing the low half of the address starts the transfer
+            let address = access.physical() as u64;
+            Pio::<u32>::new(DMA_ADDRESS).write(((address >> 32) as u32).to_be());
+            Pio::<u32>::new(DMA_ADDRESS + 4).write((address as u32).to_be());
+
+            loop {
+                let control = u32::from_be(unsafe { ptr::read_volatile(&access.control) });
+                if control & DMA_ERROR == DMA_ERROR {
+                    return Err(Error::new(EIO));
+                } else if control == 0 {
+                    break;
+                }
+                unsafe { asm!("pause" : : : "memory" : "intel", "volatile"); }
+            }
+
+            fence(Ordering::SeqCst);
+
+            buf[i .. i + count].copy_from_slice(&buffer[.. count]);
+            i += count;
+        }
+
+        Ok(())
+    }
+
+    /// Read the start of an item
+    pub fn read(&mut self, key: u16, buf: &mut [u8]) -> Result<()> {
+        if let Some((ref mut access, ref mut buffer)) = self.dma {
+            FwCfg::read_dma(access, buffer, key, buf)
+        } else {
+            Pio::<u16>::new(SELECTOR).write(key);
+            let data = Pio::<u8>::new(DATA);
+            for b in buf.iter_mut() {
+                *b = data.read();
+            }
+            Ok(())
+        }
+    }
+
+    /// Read the kernel command line given to QEMU
+    pub fn cmdline(&mut self) -> Result<Vec<u8>> {
+        let mut size = [0; 4];
+        self.read(KEY_CMDLINE_SIZE, &mut size)?;
+
+        let mut data = vec![0; FwCfg::le32(&size) as usize];
+        self.read(KEY_CMDLINE_DATA, &mut data)?;
+        while data.last() == Some(&0) {
+            data.pop();
+        }
+
+        Ok(data)
+    }
+
+    /// Read the file directory
+    pub fn files(&mut self) -> Result<Vec<File>> {
+        let mut count = [0; 4];
+        self.read(KEY_FILE_DIR, &mut count)?;
+
+        let entry_size = 8 + FILE_NAME_SIZE;
+        let mut data = vec![0; 4 + FwCfg::be32(&count) as usize * entry_size];
+        self.read(KEY_FILE_DIR, &mut data)?;
+
+        let mut files = Vec::new();
+        for entry in data[4..].chunks(entry_size) {
+            let name = &entry[8..];
+            let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+            if let Ok(name) = str::from_utf8(&name[.. len]) {
+                files.push(File {
+                    name: name.to_string(),
+                    size: FwCfg::be32(&entry[.. 4]) as usize,
+                    key: (entry[4] as u16) << 8 | entry[5] as u16
+                });
+            }
+        }
+
+        Ok(files)
+    }
+}
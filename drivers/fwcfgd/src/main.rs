@@ -0,0 +1,46 @@
+#![feature(asm)]
+
+extern crate dma;
+extern crate io;
+extern crate syscall;
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::io::FromRawFd;
+
+use syscall::{Packet, SchemeMut};
+
+use fwcfg::FwCfg;
+use scheme::FwCfgScheme;
+
+pub mod fwcfg;
+pub mod scheme;
+
+fn main() {
+    {
+        let path = format!("sys:{}/ports", syscall::getpid().unwrap());
+        let mut ports = OpenOptions::new().write(true).open(&path).expect("fwcfgd: failed to open ports");
+        ports.write(fwcfg::PORTS.as_bytes()).expect("fwcfgd: failed to allow ports");
+    }
+
+    let fw_cfg = match FwCfg::new().expect("fwcfgd: failed to allocate DMA buffers") {
+        Some(fw_cfg) => fw_cfg,
+        None => return
+    };
+
+    print!("{}", format!(" + QEMU firmware configuration{}\n", if fw_cfg.dma() { ", DMA" } else { "" }));
+
+    // Daemonize
+    if unsafe { syscall::clone(0).unwrap() } == 0 {
+        let socket_fd = syscall::open(":fwcfg", syscall::O_RDWR | syscall::O_CREAT).expect("fwcfgd: failed to create fwcfg scheme");
+        let mut socket = unsafe { File::from_raw_fd(socket_fd) };
+
+        let mut scheme = FwCfgScheme::new(fw_cfg);
+        loop {
+            let mut packet = Packet::default();
+            socket.read(&mut packet).expect("fwcfgd: failed to read fwcfg scheme");
+            scheme.handle(&mut packet);
+            socket.write(&packet).expect("fwcfgd: failed to write fwcfg scheme");
+        }
+    }
+}
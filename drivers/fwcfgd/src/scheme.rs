@@ -0,0 +1,133 @@
+use std::{cmp, str};
+use std::collections::BTreeMap;
+
+use syscall::{Error, EACCES, EBADF, EINVAL, ENOENT, Result, SchemeMut, Stat, MODE_DIR, MODE_FILE, SEEK_CUR, SEEK_END, SEEK_SET};
+
+use fwcfg::FwCfg;
+
+struct Handle {
+    path: String,
+    data: Vec<u8>,
+    mode: u16,
+    seek: usize
+}
+
+/// `fwcfg:` lists the blobs, `fwcfg:cmdline` is the kernel command line, and other paths are
+/// named blobs such as `fwcfg:opt/org.redox/ktest`
+pub struct FwCfgScheme {
+    fw_cfg: FwCfg,
+    handles: BTreeMap<usize, Handle>,
+    next_id: usize
+}
+
+impl FwCfgScheme {
+    pub fn new(fw_cfg: FwCfg) -> FwCfgScheme {
+        FwCfgScheme {
+            fw_cfg: fw_cfg,
+            handles: BTreeMap::new(),
+            next_id: 0
+        }
+    }
+}
+
+impl SchemeMut for FwCfgScheme {
+    fn open(&mut self, path: &[u8], _flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        if uid != 0 {
+            return Err(Error::new(EACCES));
+        }
+
+        let path_str = str::from_utf8(path).or(Err(Error::new(ENOENT)))?.trim_matches('/');
+
+        let (data, mode) = if path_str.is_empty() {
+            let mut list = String::from("cmdline\n");
+            for file in self.fw_cfg.files()? {
+                list.push_str(&file.name);
+                list.push('\n');
+            }
+            (list.into_bytes(), MODE_DIR | 0o500)
+        } else if path_str == "cmdline" {
+            (self.fw_cfg.cmdline()?, MODE_FILE | 0o400)
+        } else {
+            let file = self.fw_cfg.files()?.into_iter().find(|file| file.name == path_str).ok_or(Error::new(ENOENT))?;
+            let mut data = vec![0; file.size];
+            self.fw_cfg.read(file.key, &mut data)?;
+            (data, MODE_FILE | 0o400)
+        };
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.handles.insert(id, Handle {
+            path: path_str.to_string(),
+            data: data,
+            mode: mode,
+            seek: 0
+        });
+
+        Ok(id)
+    }
+
+    fn dup(&mut self, id: usize, _buf: &[u8]) -> Result<usize> {
+        let handle = {
+            let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+            Handle {
+                path: handle.path.clone(),
+                data: handle.data.clone(),
+                mode: handle.mode,
+                seek: handle.seek
+            }
+        };
+
+        let new_id = self.next_id;
+        self.next_id += 1;
+        self.handles.insert(new_id, handle);
+
+        Ok(new_id)
+    }
+
+    fn read(&mut self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let handle = self.handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+
+        let count = cmp::min(buf.len(), handle.data.len() - handle.seek);
+        buf[.. count].copy_from_slice(&handle.data[handle.seek .. handle.seek + count]);
+        handle.seek += count;
+
+        Ok(count)
+    }
+
+    fn seek(&mut self, id: usize, pos: usize, whence: usize) -> Result<usize> {
+        let handle = self.handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+
+        let len = handle.data.len();
+        handle.seek = match whence {
+            SEEK_SET => cmp::min(len, pos),
+            SEEK_CUR => cmp::max(0, cmp::min(len as isize, handle.seek as isize + pos as isize)) as usize,
+            SEEK_END => cmp::max(0, cmp::min(len as isize, len as isize + pos as isize)) as usize,
+            _ => return Err(Error::new(EINVAL))
+        };
+
+        Ok(handle.seek)
+    }
+
+    fn fpath(&mut self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+
+        let path = format!("fwcfg:{}", handle.path);
+        let count = cmp::min(buf.len(), path.len());
+        buf[.. count].copy_from_slice(&path.as_bytes()[.. count]);
+
+        Ok(count)
+    }
+
+    fn fstat(&mut self, id: usize, stat: &mut Stat) -> Result<usize> {
+        let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+
+        stat.st_mode = handle.mode;
+        stat.st_size = handle.data.len() as u64;
+
+        Ok(0)
+    }
+
+    fn close(&mut self, id: usize) -> Result<usize> {
+        self.handles.remove(&id).ok_or(Error::new(EBADF)).and(Ok(0))
+    }
+}
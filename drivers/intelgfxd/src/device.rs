@@ -0,0 +1,200 @@
+use std::ptr;
+
+use syscall::{self, MAP_WRITE};
+
+/// Size of the register range that is mapped, which holds every display register
+pub const MMIO_SIZE: usize = 0x100000;
+
+const HTOTAL_A: u32 = 0x60000;
+const VTOTAL_A: u32 = 0x6000C;
+const PIPEASRC: u32 = 0x6001C;
+const PIPEACONF: u32 = 0x70008;
+
+/// The eDP transcoder of Haswell and later, which can feed any pipe
+const HTOTAL_EDP: u32 = 0x6F000;
+const VTOTAL_EDP: u32 = 0x6F00C;
+const TRANS_DDI_FUNC_CTL_EDP: u32 = 0x6F400;
+const PIPE_CONF_EDP: u32 = 0x7F008;
+
+/// Panel fitter of pipe A, before Skylake
+const PF_CTL_A: u32 = 0x68080;
+const PF_WIN_SZ_A: u32 = 0x68074;
+
+/// Scalers of pipe A, which replace the panel fitter on Skylake
+const PS_CTRL_1_A: u32 = 0x68180;
+const PS_CTRL_2_A: u32 = 0x68280;
+
+/// Primary plane of pipe A, the layout of which changed on Skylake
+const DSPACNTR: u32 = 0x70180;
+const DSPALINOFF: u32 = 0x70184;
+const DSPASTRIDE: u32 = 0x70188;
+const PLANE_POS_1_A: u32 = 0x7018C;
+const PLANE_SIZE_1_A: u32 = 0x70190;
+const DSPASURF: u32 = 0x7019C;
+const DSPAOFFSET: u32 = 0x701A4;
+
+const PIPE_ENABLE: u32 = 1 << 31;
+const PLANE_ENABLE: u32 = 1 << 31;
+/// 32 bit xRGB, in the plane control register before Skylake
+const PLANE_FORMAT_XRGB8888: u32 = 6 << 26;
+/// 32 bit xRGB, in the plane control register of Skylake
+const PLANE_FORMAT_XRGB8888_SKL: u32 = 4 << 24;
+
+/// Generations of Intel graphics the driver knows the registers of
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Gen {
+    SandyBridge,
+    IvyBridge,
+    Haswell,
+    Broadwell,
+    Skylake
+}
+
+impl Gen {
+    /// Find the generation from the PCI device ID
+    pub fn from_device(device: u16) -> Option<Gen> {
+        match device >> 8 {
+            0x01 if device < 0x0150 => Some(Gen::SandyBridge),
+            0x01 => Some(Gen::IvyBridge),
+            0x04 | 0x0A | 0x0C | 0x0D => Some(Gen::Haswell),
+            0x16 => Some(Gen::Broadwell),
+            0x19 | 0x3E | 0x59 | 0x87 | 0x9B => Some(Gen::Skylake),
+            _ => None
+        }
+    }
+
+    /// The offset of the GTT in the register BAR, and the size of each entry
+    fn gtt(&self) -> (usize, usize) {
+        if *self >= Gen::Broadwell {
+            (8 * 1024 * 1024, 8)
+        } else {
+            (2 * 1024 * 1024, 4)
+        }
+    }
+}
+
+/// A mode, in pixels
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Mode {
+    pub width: usize,
+    pub height: usize
+}
+
+pub struct IntelGfx {
+    base: usize,
+    bar: usize,
+    pub gen: Gen
+}
+
+impl IntelGfx {
+    /// Map the registers in `bar`
+    pub unsafe fn new(bar: usize, gen: Gen) -> syscall::Result<IntelGfx> {
+        let base = syscall::physmap(bar, MMIO_SIZE, MAP_WRITE)?;
+        Ok(IntelGfx {
+            base: base,
+            bar: bar,
+            gen: gen
+        })
+    }
+
+    pub unsafe fn read(&self, register: u32) -> u32 {
+        ptr::read_volatile((self.base + register as usize) as *mut u32)
+    }
+
+    pub unsafe fn write(&self, register: u32, data: u32) {
+        ptr::write_volatile((self.base + register as usize) as *mut u32, data);
+    }
+
+    /// The timing registers of the transcoder that feeds pipe A, if it is running. Haswell and later
+    /// drive eDP panels from their own transcoder
+    unsafe fn transcoder(&self) -> Option<(u32, u32)> {
+        if self.gen >= Gen::Haswell {
+            let ddi = self.read(TRANS_DDI_FUNC_CTL_EDP);
+            // The input select is 0 for pipe A with power always on, or 4 for pipe A
+            let input = (ddi >> 12) & 7;
+            if ddi & (1 << 31) != 0 && (input == 0 || input == 4) && self.read(PIPE_CONF_EDP) & PIPE_ENABLE != 0 {
+                return Some((HTOTAL_EDP, VTOTAL_EDP));
+            }
+        }
+
+        if self.read(PIPEACONF) & PIPE_ENABLE != 0 {
+            Some((HTOTAL_A, VTOTAL_A))
+        } else {
+            None
+        }
+    }
+
+    /// The mode pipe A is driving the display at, which the firmware sets to the native mode of
+    /// the panel even when the VBE mode is smaller and scaled up by the panel fitter
+    pub unsafe fn active_mode(&self) -> Option<Mode> {
+        self.transcoder().map(|(htotal, vtotal)| Mode {
+            width: (self.read(htotal) & 0x1FFF) as usize + 1,
+            height: (self.read(vtotal) & 0x1FFF) as usize + 1
+        })
+    }
+
+    /// The graphics address of the surface scanned out by the primary plane of pipe A
+    pub unsafe fn surface(&self) -> usize {
+        (self.read(DSPASURF) & !0xFFF) as usize
+    }
+
+    /// Check that the GTT maps every page from `address` to `address + size`
+    pub unsafe fn gtt_mapped(&self, address: usize, size: usize) -> bool {
+        let (offset, entry_size) = self.gtt();
+
+        let first = address / 4096;
+        let count = (size + 4095) / 4096;
+        let start = offset + first * entry_size;
+        let aligned = start & !0xFFF;
+        let len = start + count * entry_size - aligned;
+
+        let gtt = match syscall::physmap(self.bar + aligned, (len + 4095) & !0xFFF, 0) {
+            Ok(gtt) => gtt,
+            Err(_) => return false
+        };
+
+        let mut mapped = true;
+        for i in 0..count {
+            let entry = ptr::read_volatile((gtt + start - aligned + i * entry_size) as *const u32);
+            if entry & 1 == 0 {
+                mapped = false;
+                break;
+            }
+        }
+
+        let _ = syscall::physunmap(gtt);
+
+        mapped
+    }
+
+    /// Scan out a linear 32 bit framebuffer at `surface` in the full `mode` of pipe A, with the
+    /// panel fitter off. The pipe must already be driving the display at `mode`
+    pub unsafe fn set_plane(&self, mode: Mode, surface: usize, stride: usize) {
+        let (width, height) = (mode.width as u32, mode.height as u32);
+
+        if self.gen >= Gen::Skylake {
+            self.write(PS_CTRL_1_A, 0);
+            self.write(PS_CTRL_2_A, 0);
+        } else {
+            self.write(PF_CTL_A, 0);
+            self.write(PF_WIN_SZ_A, 0);
+        }
+
+        self.write(PIPEASRC, (width - 1) << 16 | (height - 1));
+
+        if self.gen >= Gen::Skylake {
+            self.write(DSPACNTR, PLANE_ENABLE | PLANE_FORMAT_XRGB8888_SKL);
+            self.write(DSPASTRIDE, stride as u32 / 64);
+            self.write(PLANE_POS_1_A, 0);
+            self.write(PLANE_SIZE_1_A, (height - 1) << 16 | (width - 1));
+        } else {
+            self.write(DSPACNTR, PLANE_ENABLE | PLANE_FORMAT_XRGB8888);
+            self.write(DSPALINOFF, 0);
+            self.write(DSPASTRIDE, stride as u32);
+        }
+        self.write(DSPAOFFSET, 0);
+
+        // Writing the surface address latches the other plane registers at the next vblank
+        self.write(DSPASURF, surface as u32);
+    }
+}
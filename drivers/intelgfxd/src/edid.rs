@@ -0,0 +1,150 @@
+use device::{IntelGfx, Mode};
+
+/// AUX channel of DDI A, which eDP panels are connected to
+const DP_AUX_CH_CTL_A: u32 = 0x64010;
+const DP_AUX_CH_DATA_A: u32 = 0x64014;
+
+const AUX_SEND_BUSY: u32 = 1 << 31;
+const AUX_DONE: u32 = 1 << 30;
+const AUX_TIME_OUT_ERROR: u32 = 1 << 28;
+const AUX_RECEIVE_ERROR: u32 = 1 << 25;
+const AUX_MESSAGE_SIZE_SHIFT: u32 = 20;
+const AUX_MESSAGE_SIZE_MASK: u32 = 0x1F << AUX_MESSAGE_SIZE_SHIFT;
+
+/// I2C over AUX requests, with middle-of-transaction set so that the I2C transaction continues
+const I2C_WRITE_MOT: u8 = 0x4;
+const I2C_READ: u8 = 0x1;
+const I2C_READ_MOT: u8 = 0x5;
+
+/// I2C address of the EDID
+const EDID_ADDRESS: u8 = 0x50;
+
+const EDID_HEADER: [u8; 8] = [0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00];
+
+/// Times a deferred or failed transaction is tried again
+const RETRIES: usize = 32;
+
+/// Polls of the control register before a transaction is given up on
+const POLLS: usize = 1000000;
+
+/// Send an AUX message, returning the reply with its status byte
+unsafe fn aux(gfx: &IntelGfx, message: &[u8]) -> Option<Vec<u8>> {
+    for i in 0..(message.len() + 3) / 4 {
+        let mut data = 0;
+        for j in 0..4 {
+            data |= (*message.get(i * 4 + j).unwrap_or(&0) as u32) << (24 - j * 8);
+        }
+        gfx.write(DP_AUX_CH_DATA_A + i as u32 * 4, data);
+    }
+
+    // Keep the precharge, timeout and clock divider the firmware set up
+    let ctl = gfx.read(DP_AUX_CH_CTL_A) & !(AUX_SEND_BUSY | AUX_MESSAGE_SIZE_MASK);
+    gfx.write(DP_AUX_CH_CTL_A, ctl | AUX_SEND_BUSY | AUX_DONE | AUX_TIME_OUT_ERROR | AUX_RECEIVE_ERROR
+                               | (message.len() as u32) << AUX_MESSAGE_SIZE_SHIFT);
+
+    let mut status = gfx.read(DP_AUX_CH_CTL_A);
+    let mut polls = 0;
+    while status & AUX_SEND_BUSY != 0 {
+        polls += 1;
+        if polls >= POLLS {
+            return None;
+        }
+        status = gfx.read(DP_AUX_CH_CTL_A);
+    }
+
+    // Clear the status bits
+    gfx.write(DP_AUX_CH_CTL_A, status);
+
+    if status & (AUX_TIME_OUT_ERROR | AUX_RECEIVE_ERROR) != 0 {
+        return None;
+    }
+
+    let len = ((status & AUX_MESSAGE_SIZE_MASK) >> AUX_MESSAGE_SIZE_SHIFT) as usize;
+    let mut reply = Vec::with_capacity(len);
+    for i in 0..len {
+        let data = gfx.read(DP_AUX_CH_DATA_A + (i / 4) as u32 * 4);
+        reply.push((data >> (24 - (i % 4) * 8)) as u8);
+    }
+    Some(reply)
+}
+
+/// Send an I2C over AUX request, trying again while the sink defers. Returns the data of the reply
+unsafe fn i2c(gfx: &IntelGfx, request: u8, data: &[u8], read: usize) -> Option<Vec<u8>> {
+    let mut message = vec![request << 4, 0, EDID_ADDRESS];
+    if ! data.is_empty() {
+        message.push(data.len() as u8 - 1);
+        message.extend_from_slice(data);
+    } else if read > 0 {
+        message.push(read as u8 - 1);
+    }
+
+    for _retry in 0..RETRIES {
+        if let Some(reply) = aux(gfx, &message) {
+            if let Some(&status) = reply.first() {
+                let native = (status >> 4) & 3;
+                let i2c = (status >> 6) & 3;
+                if native == 0 && i2c == 0 {
+                    return Some(reply[1..].to_vec());
+                } else if native == 1 || i2c == 1 {
+                    // Not acknowledged, there is nothing at the address
+                    return None;
+                }
+            }
+        }
+    }
+
+    None
+}
+
+/// Read the EDID of the eDP panel, checking its header and checksum
+pub unsafe fn read(gfx: &IntelGfx) -> Option<[u8; 128]> {
+    if i2c(gfx, I2C_WRITE_MOT, &[0], 0).is_none() {
+        return None;
+    }
+
+    let mut edid = [0; 128];
+    let mut i = 0;
+    let mut retries = 0;
+    while i < edid.len() {
+        let data = match i2c(gfx, I2C_READ_MOT, &[], 16) {
+            Some(data) => data,
+            None => return None
+        };
+        if data.is_empty() {
+            // The sink had no data ready yet
+            retries += 1;
+            if retries >= RETRIES {
+                return None;
+            }
+            continue;
+        }
+        for &b in data.iter() {
+            if i < edid.len() {
+                edid[i] = b;
+                i += 1;
+            }
+        }
+    }
+
+    // End the I2C transaction
+    let _ = i2c(gfx, I2C_READ, &[], 0);
+
+    if edid[..8] != EDID_HEADER || edid.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) != 0 {
+        return None;
+    }
+
+    Some(edid)
+}
+
+/// The preferred mode of the display, which is the first detailed timing
+pub fn preferred_mode(edid: &[u8; 128]) -> Option<Mode> {
+    let timing = &edid[54..72];
+    if timing[0] == 0 && timing[1] == 0 {
+        return None;
+    }
+
+    Some(Mode {
+        width: timing[2] as usize | ((timing[4] as usize & 0xF0) << 4),
+        height: timing[5] as usize | ((timing[7] as usize & 0xF0) << 4)
+    })
+}
@@ -0,0 +1,80 @@
+extern crate syscall;
+
+use std::env;
+use std::fs::OpenOptions;
+use std::io::Write;
+
+use device::{Gen, IntelGfx};
+
+pub mod device;
+pub mod edid;
+
+fn main() {
+    let mut args = env::args().skip(1);
+
+    let bar_str = args.next().expect("intelgfxd: no register address provided");
+    let bar = usize::from_str_radix(&bar_str, 16).expect("intelgfxd: failed to parse register address");
+
+    let aperture_str = args.next().expect("intelgfxd: no aperture address provided");
+    let aperture = usize::from_str_radix(&aperture_str, 16).expect("intelgfxd: failed to parse aperture address");
+
+    let device_str = args.next().expect("intelgfxd: no device ID provided");
+    let device = u16::from_str_radix(&device_str, 16).expect("intelgfxd: failed to parse device ID");
+
+    let gen = match Gen::from_device(device) {
+        Some(gen) => gen,
+        None => {
+            println!("intelgfxd: device {:04X} is not supported", device);
+            return;
+        }
+    };
+
+    print!("{}", format!(" + Intel graphics ({:?}) on: {:X}, aperture: {:X}\n", gen, bar, aperture));
+
+    let gfx = unsafe { IntelGfx::new(bar, gen).expect("intelgfxd: failed to map registers") };
+
+    // Pipes are not brought up from scratch, as that needs the PLLs and link training. Instead, the
+    // pipe the firmware lit the panel with is given a plane at its full resolution
+    let active = match unsafe { gfx.active_mode() } {
+        Some(mode) => mode,
+        None => {
+            println!("intelgfxd: no display pipe is running");
+            return;
+        }
+    };
+
+    let mode = match unsafe { edid::read(&gfx) }.as_ref().and_then(edid::preferred_mode) {
+        Some(preferred) => {
+            if preferred != active {
+                println!("intelgfxd: pipe runs at {}x{}, not the {}x{} the panel prefers", active.width, active.height, preferred.width, preferred.height);
+                return;
+            }
+            preferred
+        },
+        None => {
+            println!("intelgfxd: no EDID, using the mode of the pipe");
+            active
+        }
+    };
+
+    // Planes need a stride that is a multiple of 64 bytes
+    let stride = (mode.width * 4 + 63) & !63;
+    let surface = unsafe { gfx.surface() };
+    if ! unsafe { gfx.gtt_mapped(surface, stride * mode.height) } {
+        println!("intelgfxd: framebuffer for {}x{} is not mapped by the GTT", mode.width, mode.height);
+        return;
+    }
+
+    unsafe { gfx.set_plane(mode, surface, stride) };
+
+    println!("   - Display at {}x{}", mode.width, mode.height);
+
+    match OpenOptions::new().write(true).open("display:framebuffer") {
+        Ok(mut display) => {
+            if let Err(err) = display.write(format!("{:X} {} {} {}", aperture + surface, mode.width, mode.height, stride).as_bytes()) {
+                println!("intelgfxd: failed to hand framebuffer to display: {}", err);
+            }
+        },
+        Err(err) => println!("intelgfxd: failed to open display:framebuffer: {}", err)
+    }
+}
@@ -5,10 +5,9 @@ extern crate syscall;
 extern crate toml;
 
 use std::env;
-use std::fs::File;
-use std::io::Read;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
 use std::process::Command;
-use syscall::iopl;
 
 use config::Config;
 use pci::{Pci, PciBar, PciClass};
@@ -29,7 +28,12 @@ fn main() {
         }
     }
 
-    unsafe { iopl(3).unwrap() };
+    {
+        // Configuration address and data ports
+        let path = format!("sys:{}/ports", syscall::getpid().unwrap());
+        let mut ports = OpenOptions::new().write(true).open(&path).unwrap();
+        ports.write(b"CF8-CFF\n").unwrap();
+    }
 
     print!("PCI BS/DV/FN VEND:DEVI CL.SC.IN.RV\n");
 
@@ -132,6 +136,7 @@ fn main() {
                                         "$BAR4" => bar_arg(4),
                                         "$BAR5" => bar_arg(5),
                                         "$IRQ" => format!("{}", header.interrupt_line),
+                                        "$DEVICE" => format!("{:>04X}", header.device_id),
                                         _ => arg.clone()
                                     };
                                     command.arg(&arg);
@@ -56,7 +56,9 @@ enum KeyboardCommand {
 
 #[repr(u8)]
 enum KeyboardCommandData {
-    ScancodeSet = 0xF0
+    SetLeds = 0xED,
+    ScancodeSet = 0xF0,
+    SetTypematic = 0xF3
 }
 
 #[repr(u8)]
@@ -143,6 +145,27 @@ impl Ps2 {
         self.read()
     }
 
+    /// Send a command and its data to the keyboard, returning false if either is not acknowledged.
+    /// Keyboard interrupts stay enabled, so the acknowledgements are also seen by the IRQ handler
+    fn keyboard_set(&mut self, command: KeyboardCommandData, data: u8) -> bool {
+        self.write(command as u8);
+        if self.read() != 0xFA {
+            return false;
+        }
+        self.write(data);
+        self.read() == 0xFA
+    }
+
+    /// Set the keyboard LEDs, scroll lock is bit 0, num lock bit 1 and caps lock bit 2
+    pub fn set_leds(&mut self, leds: u8) -> bool {
+        self.keyboard_set(KeyboardCommandData::SetLeds, leds & 0b111)
+    }
+
+    /// Set the typematic byte, the repeat rate is bits 0 to 4 and the delay bits 5 and 6
+    pub fn set_typematic(&mut self, typematic: u8) -> bool {
+        self.keyboard_set(KeyboardCommandData::SetTypematic, typematic & 0x7F)
+    }
+
     fn mouse_command(&mut self, command: MouseCommand) -> u8 {
         self.command(Command::WriteSecond);
         self.write(command as u8);
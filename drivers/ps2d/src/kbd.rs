@@ -0,0 +1,199 @@
+use std::{cmp, str};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use syscall::error::{Error, Result, EACCES, EBADF, EINVAL, EIO, ENOENT};
+use syscall::scheme::SchemeMut;
+
+use controller::Ps2;
+use keymap::Keymap;
+
+/// Repeat delays that the keyboard supports, in milliseconds
+const DELAYS: [u32; 4] = [250, 500, 750, 1000];
+
+/// The repeat rate, in tenths of a character per second, of a typematic rate value
+fn rate_tenths(rate: u8) -> u32 {
+    2400 / ((8 + (rate as u32 & 7)) << ((rate as u32 >> 3) & 3))
+}
+
+struct Handle {
+    data: Vec<u8>,
+    seek: usize,
+    uid: u32,
+    /// Opened as `kbd:keymap`
+    keymap: bool
+}
+
+/// Keyboard settings. Reading `kbd:` gives the settings as the commands that would set them,
+/// `delay MS`, `rate CPS` and `leds [caps] [num] [scroll]`, and root may write the same commands.
+/// `kbd:keymap` gives the keymap in the form `Keymap::from_str` parses, and root may replace it
+/// by writing a whole keymap in one write
+pub struct KbdScheme {
+    ps2: Ps2,
+    keymap: Rc<RefCell<Keymap>>,
+    /// Index into `DELAYS`
+    delay: u8,
+    /// Typematic rate value, from 0 for 30 characters per second to 31 for 2
+    rate: u8,
+    leds: u8,
+    next_id: usize,
+    handles: BTreeMap<usize, Handle>
+}
+
+impl KbdScheme {
+    /// Create the scheme for a controller that was just initialized, leaving the keyboard with
+    /// its default delay of 500 ms, rate of 10.9 characters per second, and LEDs off
+    pub fn new(ps2: Ps2, keymap: Rc<RefCell<Keymap>>) -> KbdScheme {
+        KbdScheme {
+            ps2: ps2,
+            keymap: keymap,
+            delay: 1,
+            rate: 0x0B,
+            leds: 0,
+            next_id: 1,
+            handles: BTreeMap::new()
+        }
+    }
+
+    fn settings_string(&self) -> String {
+        let mut leds = String::new();
+        for &(bit, name) in [(1 << 2, " caps"), (1 << 1, " num"), (1 << 0, " scroll")].iter() {
+            if self.leds & bit == bit {
+                leds.push_str(name);
+            }
+        }
+
+        format!("delay {}\nrate {}\nleds{}\n", DELAYS[self.delay as usize], (rate_tenths(self.rate) + 5) / 10, leds)
+    }
+
+    fn set_typematic(&mut self, delay: u8, rate: u8) -> Result<()> {
+        if self.ps2.set_typematic(delay << 5 | rate) {
+            self.delay = delay;
+            self.rate = rate;
+            Ok(())
+        } else {
+            Err(Error::new(EIO))
+        }
+    }
+
+    fn command(&mut self, line: &str) -> Result<()> {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            None => Ok(()),
+            Some("delay") => {
+                let ms = words.next().and_then(|ms| ms.parse::<u32>().ok()).ok_or(Error::new(EINVAL))?;
+                let delay = DELAYS.iter().position(|&delay| delay == ms).ok_or(Error::new(EINVAL))?;
+                let rate = self.rate;
+                self.set_typematic(delay as u8, rate)
+            },
+            Some("rate") => {
+                let cps = words.next().and_then(|cps| cps.parse::<u32>().ok()).ok_or(Error::new(EINVAL))?;
+                if cps < 2 || cps > 30 {
+                    return Err(Error::new(EINVAL));
+                }
+                // Use the closest rate the keyboard supports
+                let rate = (0..32).min_by_key(|&rate| (rate_tenths(rate) as i32 - cps as i32 * 10).abs()).unwrap_or(0);
+                let delay = self.delay;
+                self.set_typematic(delay, rate)
+            },
+            Some("leds") => {
+                let mut leds = 0;
+                for word in words {
+                    leds |= match word {
+                        "caps" => 1 << 2,
+                        "num" => 1 << 1,
+                        "scroll" => 1 << 0,
+                        _ => return Err(Error::new(EINVAL))
+                    };
+                }
+                if self.ps2.set_leds(leds) {
+                    self.leds = leds;
+                    Ok(())
+                } else {
+                    Err(Error::new(EIO))
+                }
+            },
+            Some(_) => Err(Error::new(EINVAL))
+        }
+    }
+}
+
+impl SchemeMut for KbdScheme {
+    fn open(&mut self, path: &[u8], _flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        let keymap = if path.is_empty() {
+            false
+        } else if path == b"keymap" {
+            true
+        } else {
+            return Err(Error::new(ENOENT));
+        };
+
+        let data = if keymap {
+            self.keymap.borrow().to_string()
+        } else {
+            self.settings_string()
+        };
+
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.handles.insert(id, Handle {
+            data: data.into_bytes(),
+            seek: 0,
+            uid: uid,
+            keymap: keymap
+        });
+
+        Ok(id)
+    }
+
+    fn read(&mut self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let handle = self.handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+
+        let count = cmp::min(buf.len(), handle.data.len() - handle.seek);
+        buf[.. count].copy_from_slice(&handle.data[handle.seek .. handle.seek + count]);
+        handle.seek += count;
+
+        Ok(count)
+    }
+
+    fn write(&mut self, id: usize, buf: &[u8]) -> Result<usize> {
+        let (uid, keymap) = {
+            let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+            (handle.uid, handle.keymap)
+        };
+        if uid != 0 {
+            return Err(Error::new(EACCES));
+        }
+
+        let text = str::from_utf8(buf).or(Err(Error::new(EINVAL)))?;
+        if keymap {
+            *self.keymap.borrow_mut() = Keymap::from_str(text).ok_or(Error::new(EINVAL))?;
+        } else {
+            for line in text.lines() {
+                self.command(line)?;
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    fn fpath(&mut self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+
+        let path: &[u8] = if handle.keymap { b"kbd:keymap" } else { b"kbd:" };
+
+        let mut i = 0;
+        while i < buf.len() && i < path.len() {
+            buf[i] = path[i];
+            i += 1;
+        }
+
+        Ok(i)
+    }
+
+    fn close(&mut self, id: usize) -> Result<usize> {
+        self.handles.remove(&id).ok_or(Error::new(EBADF)).and(Ok(0))
+    }
+}
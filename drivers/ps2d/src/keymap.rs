@@ -1,3 +1,6 @@
+use std::char;
+use std::collections::BTreeMap;
+
 pub mod english {
     static ENGLISH: [[char; 2]; 58] = [
         ['\0', '\0'],
@@ -146,3 +149,124 @@ pub mod dvorak {
         }
     }
 }
+
+/// Number of scancodes a keymap can give characters for
+const SCANCODES: usize = 0x80;
+
+/// Characters of each scancode without modifiers, with shift, and with AltGr, where `'\0'` is
+/// no character. Keys without an AltGr character give the character without modifiers
+pub struct Keymap {
+    keys: Vec<[char; 3]>
+}
+
+/// Parse a character of a keymap, either itself or `U+` and its code point in hex
+fn parse_char(word: &str) -> Option<char> {
+    if word.starts_with("U+") {
+        u32::from_str_radix(&word[2..], 16).ok().and_then(char::from_u32)
+    } else {
+        let mut chars = word.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Some(c),
+            _ => None
+        }
+    }
+}
+
+fn char_string(c: char) -> String {
+    if c.is_whitespace() || c.is_control() || c == '#' {
+        format!("U+{:04X}", c as u32)
+    } else {
+        c.to_string()
+    }
+}
+
+impl Keymap {
+    /// One of the keymaps built into ps2d
+    pub fn builtin(get_char: fn(u8, bool) -> char) -> Keymap {
+        Keymap {
+            keys: (0..SCANCODES).map(|scancode| [get_char(scancode as u8, false), get_char(scancode as u8, true), '\0']).collect()
+        }
+    }
+
+    /// Parse a keymap, with a line for each key like `10 q Q @`, giving the scancode in hex and
+    /// then the characters without modifiers, with shift, and optionally with AltGr. Keys that are
+    /// not listed give no character, and lines starting with `#` are ignored. Returns `None` if
+    /// any line is not valid or a scancode is listed twice
+    pub fn from_str(text: &str) -> Option<Keymap> {
+        let mut keys = BTreeMap::new();
+
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut words = line.split_whitespace();
+            let scancode = match words.next().and_then(|word| usize::from_str_radix(word, 16).ok()) {
+                Some(scancode) if scancode < SCANCODES => scancode,
+                _ => return None
+            };
+
+            let mut key = ['\0'; 3];
+            let mut count = 0;
+            for word in words {
+                if count >= key.len() {
+                    return None;
+                }
+                key[count] = match parse_char(word) {
+                    Some(c) => c,
+                    None => return None
+                };
+                count += 1;
+            }
+            if count < 2 {
+                return None;
+            }
+
+            if keys.insert(scancode, key).is_some() {
+                return None;
+            }
+        }
+
+        if keys.is_empty() {
+            return None;
+        }
+
+        let mut keymap = Keymap {
+            keys: vec![['\0'; 3]; SCANCODES]
+        };
+        for (scancode, key) in keys {
+            keymap.keys[scancode] = key;
+        }
+        Some(keymap)
+    }
+
+    pub fn get_char(&self, scancode: u8, shift: bool, altgr: bool) -> char {
+        if let Some(key) = self.keys.get(scancode as usize) {
+            if altgr && key[2] != '\0' {
+                key[2]
+            } else if shift {
+                key[1]
+            } else {
+                key[0]
+            }
+        } else {
+            '\0'
+        }
+    }
+
+    /// The keymap in the form `from_str` parses, listing only keys that give characters
+    pub fn to_string(&self) -> String {
+        let mut string = String::new();
+        for (scancode, key) in self.keys.iter().enumerate() {
+            if key[0] != '\0' || key[1] != '\0' || key[2] != '\0' {
+                string.push_str(&format!("{:02X} {} {}", scancode, char_string(key[0]), char_string(key[1])));
+                if key[2] != '\0' {
+                    string.push_str(&format!(" {}", char_string(key[2])));
+                }
+                string.push('\n');
+            }
+        }
+        string
+    }
+}
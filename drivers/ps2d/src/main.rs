@@ -7,17 +7,23 @@ extern crate io;
 extern crate orbclient;
 extern crate syscall;
 
+use std::cell::RefCell;
 use std::env;
-use std::fs::File;
+use std::fs::{File, OpenOptions};
 use std::io::{Read, Write, Result};
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::mem;
+use std::rc::Rc;
 
 use event::EventQueue;
 use orbclient::{KeyEvent, MouseEvent};
-use syscall::iopl;
+use syscall::{Packet, SchemeMut};
+
+use kbd::KbdScheme;
+use keymap::Keymap;
 
 mod controller;
+mod kbd;
 mod keymap;
 
 bitflags! {
@@ -33,46 +39,66 @@ bitflags! {
     }
 }
 
-struct Ps2d<'a> {
+struct Ps2d {
     input: File,
     lshift: bool,
     rshift: bool,
+    altgr: bool,
+    /// Set after an 0xE0 prefix, for the scancode that follows
+    extended: bool,
     packets: [u8; 4],
     packet_i: usize,
     extra_packet: bool,
-    //Keymap function
-    get_char: &'a Fn(u8,bool) -> char
+    /// Shared with kbd:, which can replace it
+    keymap: Rc<RefCell<Keymap>>
 }
 
-impl<'a> Ps2d<'a> {
-    fn new(input: File, extra_packet: bool, keymap: &'a Fn(u8,bool) -> char) -> Self {
+impl Ps2d {
+    fn new(input: File, extra_packet: bool, keymap: Rc<RefCell<Keymap>>) -> Self {
         Ps2d {
             input: input,
             lshift: false,
             rshift: false,
+            altgr: false,
+            extended: false,
             packets: [0; 4],
             packet_i: 0,
             extra_packet: extra_packet,
-            get_char: keymap
+            keymap: keymap
         }
     }
 
     fn handle(&mut self, keyboard: bool, data: u8) {
         if keyboard {
+            // Acknowledgements and resend requests from commands sent by kbd: are not keys
+            if data == 0xFA || data == 0xFE {
+                return;
+            }
+
+            if data == 0xE0 {
+                self.extended = true;
+                return;
+            }
+
             let (scancode, pressed) = if data >= 0x80 {
                 (data - 0x80, false)
             } else {
                 (data, true)
             };
 
+            let extended = self.extended;
+            self.extended = false;
+
             if scancode == 0x2A {
                 self.lshift = pressed;
             } else if scancode == 0x36 {
                 self.rshift = pressed;
+            } else if scancode == 0x38 && extended {
+                self.altgr = pressed;
             }
 
             self.input.write(&KeyEvent {
-                character: (self.get_char)(scancode, self.lshift || self.rshift),
+                character: self.keymap.borrow().get_char(scancode, self.lshift || self.rshift, self.altgr),
                 scancode: scancode,
                 pressed: pressed
             }.to_event()).expect("ps2d: failed to write key event");
@@ -125,27 +151,33 @@ impl<'a> Ps2d<'a> {
 fn main() {
     // Daemonize
     if unsafe { syscall::clone(0).unwrap() } == 0 {
-        unsafe {
-            iopl(3).expect("ps2d: failed to get I/O permission");
-            asm!("cli" : : : : "intel", "volatile");
+        {
+            let path = format!("sys:{}/ports", syscall::getpid().expect("ps2d: failed to get PID"));
+            let mut ports = OpenOptions::new().write(true).open(&path).expect("ps2d: failed to open ports");
+            ports.write(b"60\n64\n").expect("ps2d: failed to get I/O permission");
         }
 
         let input = File::open("display:input").expect("ps2d: failed to open display:input");
 
-        let extra_packet = controller::Ps2::new().init();
-        let keymap = match env::args().skip(1).next() {
+        let mut ps2 = controller::Ps2::new();
+        let extra_packet = ps2.init();
+
+        let keymap = Rc::new(RefCell::new(Keymap::builtin(match env::args().skip(1).next() {
             Some(k) => match k.to_lowercase().as_ref() {
-                "dvorak" => (keymap::dvorak::get_char),
-                "english" => (keymap::english::get_char),
-                &_ => (keymap::english::get_char)
+                "dvorak" => keymap::dvorak::get_char,
+                "english" => keymap::english::get_char,
+                &_ => keymap::english::get_char
             },
-            None => (keymap::english::get_char)
-        };
-        let mut ps2d = Ps2d::new(input, extra_packet,&keymap);
+            None => keymap::english::get_char
+        })));
+
+        let mut kbd = KbdScheme::new(ps2, keymap.clone());
+        let mut ps2d = Ps2d::new(input, extra_packet, keymap);
 
         let mut event_queue = EventQueue::<(bool, u8)>::new().expect("ps2d: failed to create event queue");
 
-        let mut key_irq = File::open("irq:1").expect("ps2d: failed to open irq:1");
+        let key_irq_fd = syscall::open("irq:1", syscall::O_RDWR | syscall::O_NONBLOCK).expect("ps2d: failed to open irq:1");
+        let mut key_irq = unsafe { File::from_raw_fd(key_irq_fd) };
 
         event_queue.add(key_irq.as_raw_fd(), move |_count: usize| -> Result<Option<(bool, u8)>> {
             let mut irq = [0; 8];
@@ -163,7 +195,8 @@ fn main() {
             }
         }).expect("ps2d: failed to poll irq:1");
 
-        let mut mouse_irq = File::open("irq:12").expect("ps2d: failed to open irq:12");
+        let mouse_irq_fd = syscall::open("irq:12", syscall::O_RDWR | syscall::O_NONBLOCK).expect("ps2d: failed to open irq:12");
+        let mut mouse_irq = unsafe { File::from_raw_fd(mouse_irq_fd) };
 
         event_queue.add(mouse_irq.as_raw_fd(), move |_count: usize| -> Result<Option<(bool, u8)>> {
             let mut irq = [0; 8];
@@ -181,6 +214,21 @@ fn main() {
             }
         }).expect("ps2d: failed to poll irq:12");
 
+        let socket_fd = syscall::open(":kbd", syscall::O_RDWR | syscall::O_CREAT | syscall::O_NONBLOCK).expect("ps2d: failed to create kbd scheme");
+        let mut socket = unsafe { File::from_raw_fd(socket_fd) };
+
+        event_queue.add(socket_fd, move |_count: usize| -> Result<Option<(bool, u8)>> {
+            loop {
+                let mut packet = Packet::default();
+                if socket.read(&mut packet)? == 0 {
+                    break;
+                }
+                kbd.handle(&mut packet);
+                socket.write(&packet)?;
+            }
+            Ok(None)
+        }).expect("ps2d: failed to poll kbd scheme");
+
         for (keyboard, data) in event_queue.trigger_all(0).expect("ps2d: failed to trigger events") {
             ps2d.handle(keyboard, data);
         }
@@ -35,7 +35,8 @@ fn main() {
         let socket_fd = syscall::open(":network", syscall::O_RDWR | syscall::O_CREAT | syscall::O_NONBLOCK).expect("rtl8168d: failed to create network scheme");
         let socket = Arc::new(RefCell::new(unsafe { File::from_raw_fd(socket_fd) }));
 
-        let mut irq_file = File::open(format!("irq:{}", irq)).expect("rtl8168d: failed to open IRQ file");
+        let irq_fd = syscall::open(&format!("irq:{}", irq), syscall::O_RDWR | syscall::O_NONBLOCK).expect("rtl8168d: failed to open IRQ file");
+        let mut irq_file = unsafe { File::from_raw_fd(irq_fd) };
 
         let address = unsafe { syscall::physmap(bar, 256, MAP_WRITE).expect("rtl8168d: failed to map address") };
         {
@@ -53,9 +54,9 @@ fn main() {
                 irq_file.read(&mut irq)?;
 
                 let isr = unsafe { device_irq.borrow_mut().irq() };
+                // Acknowledged whether or not the ISR was set, as the line is shared
+                irq_file.write(&mut irq)?;
                 if isr != 0 {
-                    irq_file.write(&mut irq)?;
-
                     let mut todo = todo_irq.borrow_mut();
                     let mut i = 0;
                     while i < todo.len() {
@@ -0,0 +1,95 @@
+use display::PixelFormat;
+
+const INDEX_PORT: u16 = 0x1CE;
+const DATA_PORT: u16 = 0x1CF;
+
+const INDEX_ID: u16 = 0;
+const INDEX_XRES: u16 = 1;
+const INDEX_YRES: u16 = 2;
+const INDEX_BPP: u16 = 3;
+const INDEX_ENABLE: u16 = 4;
+
+const ENABLED: u16 = 1;
+/// While set, the resolution registers read back the largest supported values
+const GETCAPS: u16 = 1 << 1;
+const LFB_ENABLED: u16 = 1 << 6;
+
+unsafe fn inw(port: u16) -> u16 {
+    let value: u16;
+    asm!("in ax, dx" : "={ax}"(value) : "{dx}"(port) : : "intel", "volatile");
+    value
+}
+
+unsafe fn outw(port: u16, value: u16) {
+    asm!("out dx, ax" : : "{dx}"(port), "{ax}"(value) : : "intel", "volatile");
+}
+
+/// The Bochs graphics adapter of Bochs, QEMU and VirtualBox, which can change modes without
+/// calling the VBE BIOS. The linear framebuffer stays at the address the bootloader found
+pub struct Bga {
+    max_width: usize,
+    max_height: usize,
+    max_bpp: usize
+}
+
+impl Bga {
+    /// Find the adapter, the ports must already be allowed
+    pub fn new() -> Option<Bga> {
+        let id = Bga::read(INDEX_ID);
+        if id < 0xB0C0 || id > 0xB0C5 {
+            return None;
+        }
+
+        let enable = Bga::read(INDEX_ENABLE);
+        Bga::write(INDEX_ENABLE, enable | GETCAPS);
+        let bga = Bga {
+            max_width: Bga::read(INDEX_XRES) as usize,
+            max_height: Bga::read(INDEX_YRES) as usize,
+            max_bpp: Bga::read(INDEX_BPP) as usize
+        };
+        Bga::write(INDEX_ENABLE, enable);
+
+        Some(bga)
+    }
+
+    fn read(index: u16) -> u16 {
+        unsafe {
+            outw(INDEX_PORT, index);
+            inw(DATA_PORT)
+        }
+    }
+
+    fn write(index: u16, value: u16) {
+        unsafe {
+            outw(INDEX_PORT, index);
+            outw(DATA_PORT, value);
+        }
+    }
+
+    /// The layout of pixels in a supported depth
+    pub fn pixel_format(bpp: usize) -> Option<PixelFormat> {
+        match bpp {
+            32 => Some(PixelFormat::rgb32()),
+            24 => Some(PixelFormat { bytes: 3, .. PixelFormat::rgb32() }),
+            16 => Some(PixelFormat { bytes: 2, red: (5, 11), green: (6, 5), blue: (5, 0) }),
+            15 => Some(PixelFormat { bytes: 2, red: (5, 10), green: (5, 5), blue: (5, 0) }),
+            _ => None
+        }
+    }
+
+    /// Switch modes, returning false if the adapter does not support the mode
+    pub fn set_mode(&mut self, width: usize, height: usize, bpp: usize) -> bool {
+        if width == 0 || width > self.max_width || height == 0 || height > self.max_height
+            || bpp > self.max_bpp || Bga::pixel_format(bpp).is_none() {
+            return false;
+        }
+
+        Bga::write(INDEX_ENABLE, 0);
+        Bga::write(INDEX_XRES, width as u16);
+        Bga::write(INDEX_YRES, height as u16);
+        Bga::write(INDEX_BPP, bpp as u16);
+        Bga::write(INDEX_ENABLE, ENABLED | LFB_ENABLED);
+
+        true
+    }
+}
@@ -3,15 +3,14 @@ extern crate rusttype;
 
 use alloc::heap;
 use std::{cmp, slice};
+use std::rc::Rc;
 
+use font::Font;
 use primitive::{fast_set32, fast_set64, fast_copy, fast_copy64};
 
 #[cfg(feature="rusttype")]
 use self::rusttype::{Font, FontCollection, Scale, point};
 
-#[cfg(not(feature="rusttype"))]
-static FONT: &'static [u8] = include_bytes!("../../../res/fonts/unifont.font");
-
 #[cfg(feature="rusttype")]
 static FONT: &'static [u8] = include_bytes!("../../../res/fonts/DejaVuSansMono.ttf");
 #[cfg(feature="rusttype")]
@@ -21,13 +20,52 @@ static FONT_BOLD_ITALIC: &'static [u8] = include_bytes!("../../../res/fonts/Deja
 #[cfg(feature="rusttype")]
 static FONT_ITALIC: &'static [u8] = include_bytes!("../../../res/fonts/DejaVuSansMono-Oblique.ttf");
 
+/// The layout of onscreen pixels. Offscreen pixels are always 32 bit, with 8 bits of red, green
+/// and blue at 16, 8 and 0
+#[derive(Clone, Copy, Debug)]
+pub struct PixelFormat {
+    /// Bytes per pixel, from 2 to 4
+    pub bytes: usize,
+    /// Size and position of each color, in bits
+    pub red: (u8, u8),
+    pub green: (u8, u8),
+    pub blue: (u8, u8)
+}
+
+impl PixelFormat {
+    /// The format of offscreen pixels
+    pub fn rgb32() -> PixelFormat {
+        PixelFormat {
+            bytes: 4,
+            red: (8, 16),
+            green: (8, 8),
+            blue: (8, 0)
+        }
+    }
+
+    fn is_rgb32(&self) -> bool {
+        self.bytes == 4 && self.red == (8, 16) && self.green == (8, 8) && self.blue == (8, 0)
+    }
+
+    /// Convert an offscreen pixel
+    fn pack(&self, color: u32) -> u32 {
+        let channel = |value: u32, (size, position): (u8, u8)| (value >> (8 - size as u32)) << position as u32;
+        channel((color >> 16) & 0xFF, self.red) | channel((color >> 8) & 0xFF, self.green) | channel(color & 0xFF, self.blue)
+    }
+}
+
 /// A display
 #[cfg(not(feature="rusttype"))]
 pub struct Display {
     pub width: usize,
     pub height: usize,
-    pub onscreen: &'static mut [u32],
-    pub offscreen: &'static mut [u32]
+    /// Bytes from the start of one onscreen row to the next, which may be more than the width
+    pub stride: usize,
+    pub format: PixelFormat,
+    pub onscreen: &'static mut [u8],
+    pub offscreen: &'static mut [u32],
+    /// Characters are drawn with this, and the console is laid out in cells of its size
+    pub font: Rc<Font>
 }
 
 /// A display
@@ -35,7 +73,10 @@ pub struct Display {
 pub struct Display {
     pub width: usize,
     pub height: usize,
-    pub onscreen: &'static mut [u32],
+    /// Bytes from the start of one onscreen row to the next, which may be more than the width
+    pub stride: usize,
+    pub format: PixelFormat,
+    pub onscreen: &'static mut [u8],
     pub offscreen: &'static mut [u32],
     #[cfg(feature="rusttype")]
     pub font: Font<'static>,
@@ -49,27 +90,33 @@ pub struct Display {
 
 impl Display {
     #[cfg(not(feature="rusttype"))]
-    pub fn new(width: usize, height: usize, onscreen: usize) -> Display {
+    pub fn new(width: usize, height: usize, stride: usize, format: PixelFormat, onscreen: usize, font: Rc<Font>) -> Display {
         let size = width * height;
         let offscreen = unsafe { heap::allocate(size * 4, 4096) };
         unsafe { fast_set64(offscreen as *mut u64, 0, size/2) };
         Display {
             width: width,
             height: height,
-            onscreen: unsafe { slice::from_raw_parts_mut(onscreen as *mut u32, size) },
-            offscreen: unsafe { slice::from_raw_parts_mut(offscreen as *mut u32, size) }
+            stride: stride,
+            format: format,
+            onscreen: unsafe { slice::from_raw_parts_mut(onscreen as *mut u8, stride * height) },
+            offscreen: unsafe { slice::from_raw_parts_mut(offscreen as *mut u32, size) },
+            font: font
         }
     }
 
+    /// The bitmap font is not used, as characters are drawn with DejaVu Sans Mono at 8x16
     #[cfg(feature="rusttype")]
-    pub fn new(width: usize, height: usize, onscreen: usize) -> Display {
+    pub fn new(width: usize, height: usize, stride: usize, format: PixelFormat, onscreen: usize, _font: Rc<Font>) -> Display {
         let size = width * height;
         let offscreen = unsafe { heap::allocate(size * 4, 4096) };
         unsafe { fast_set64(offscreen as *mut u64, 0, size/2) };
         Display {
             width: width,
             height: height,
-            onscreen: unsafe { slice::from_raw_parts_mut(onscreen as *mut u32, size) },
+            stride: stride,
+            format: format,
+            onscreen: unsafe { slice::from_raw_parts_mut(onscreen as *mut u8, stride * height) },
             offscreen: unsafe { slice::from_raw_parts_mut(offscreen as *mut u32, size) },
             font: FontCollection::from_bytes(FONT).into_font().unwrap(),
             font_bold: FontCollection::from_bytes(FONT_BOLD).into_font().unwrap(),
@@ -78,6 +125,44 @@ impl Display {
         }
     }
 
+    /// Width of a character cell
+    #[cfg(not(feature="rusttype"))]
+    pub fn char_width(&self) -> usize {
+        self.font.width
+    }
+
+    /// Height of a character cell
+    #[cfg(not(feature="rusttype"))]
+    pub fn char_height(&self) -> usize {
+        self.font.height
+    }
+
+    /// Width of a character cell
+    #[cfg(feature="rusttype")]
+    pub fn char_width(&self) -> usize {
+        8
+    }
+
+    /// Height of a character cell
+    #[cfg(feature="rusttype")]
+    pub fn char_height(&self) -> usize {
+        16
+    }
+
+    /// Switch to a new mode, with a cleared offscreen buffer of the new size. The old buffer is
+    /// not freed, as clients of a graphic screen may still have it mapped
+    pub fn set_mode(&mut self, width: usize, height: usize, stride: usize, format: PixelFormat, onscreen: usize) {
+        let size = width * height;
+        let offscreen = unsafe { heap::allocate(size * 4, 4096) };
+        unsafe { fast_set64(offscreen as *mut u64, 0, size/2) };
+        self.width = width;
+        self.height = height;
+        self.stride = stride;
+        self.format = format;
+        self.onscreen = unsafe { slice::from_raw_parts_mut(onscreen as *mut u8, stride * height) };
+        self.offscreen = unsafe { slice::from_raw_parts_mut(offscreen as *mut u32, size) };
+    }
+
     /// Draw a rectangle
     pub fn rect(&mut self, x: usize, y: usize, w: usize, h: usize, color: u32) {
         let start_y = cmp::min(self.height - 1, y);
@@ -103,6 +188,52 @@ impl Display {
         }
     }
 
+    /// Draw an image of `w` by `h` pixels, clipping it to the display
+    pub fn blit(&mut self, x: usize, y: usize, w: usize, h: usize, data: &[u32]) {
+        if x >= self.width || y >= self.height {
+            return;
+        }
+
+        let cols = cmp::min(w, self.width - x);
+        let rows = cmp::min(h, self.height - y);
+        for row in 0..rows {
+            let src = &data[row * w..row * w + cols];
+            let dst = (y + row) * self.width + x;
+            self.offscreen[dst..dst + cols].copy_from_slice(src);
+        }
+    }
+
+    /// Draw a line from (x0, y0) to (x1, y1), clipping it to the display
+    pub fn line(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, color: u32) {
+        let dx = (x1 - x0).abs();
+        let dy = -(y1 - y0).abs();
+        let sx = if x0 < x1 { 1 } else { -1 };
+        let sy = if y0 < y1 { 1 } else { -1 };
+
+        let mut x = x0;
+        let mut y = y0;
+        let mut err = dx + dy;
+        loop {
+            if x >= 0 && y >= 0 && (x as usize) < self.width && (y as usize) < self.height {
+                self.offscreen[y as usize * self.width + x as usize] = color;
+            }
+
+            if x == x1 && y == y1 {
+                break;
+            }
+
+            let e2 = 2 * err;
+            if e2 >= dy {
+                err += dy;
+                x += sx;
+            }
+            if e2 <= dx {
+                err += dx;
+                y += sy;
+            }
+        }
+    }
+
     /// Invert a rectangle
     pub fn invert(&mut self, x: usize, y: usize, w: usize, h: usize) {
         let start_y = cmp::min(self.height - 1, y);
@@ -138,15 +269,14 @@ impl Display {
     /// Draw a character
     #[cfg(not(feature="rusttype"))]
     pub fn char(&mut self, x: usize, y: usize, character: char, color: u32, _bold: bool, _italic: bool) {
-        if x + 8 <= self.width && y + 16 <= self.height {
-            let mut dst = self.offscreen.as_mut_ptr() as usize + (y * self.width + x) * 4;
-
-            let font_i = 16 * (character as usize);
-            if font_i + 16 <= FONT.len() {
-                for row in 0..16 {
-                    let row_data = FONT[font_i + row];
-                    for col in 0..8 {
-                        if (row_data >> (7 - col)) & 1 == 1 {
+        let font = self.font.clone();
+        if let Some((glyph, glyph_width)) = font.glyph(character) {
+            if x + glyph_width <= self.width && y + font.height <= self.height {
+                let mut dst = self.offscreen.as_mut_ptr() as usize + (y * self.width + x) * 4;
+
+                for row_data in glyph.chunks((glyph_width + 7) / 8) {
+                    for col in 0..glyph_width {
+                        if (row_data[col / 8] >> (7 - col % 8)) & 1 == 1 {
                             unsafe { *((dst + col * 4) as *mut u32)  = color; }
                         }
                     }
@@ -223,6 +353,12 @@ impl Display {
                 fast_copy64(data_ptr, data_ptr.offset(off1 as isize), off2);
                 fast_set64(data_ptr.offset(off2 as isize), data, off1);
             }
+        } else if rows >= height {
+            // Everything scrolls off, so only the clear is needed
+            unsafe {
+                let data_ptr = self.offscreen.as_mut_ptr() as *mut u64;
+                fast_set64(data_ptr, data, height * width);
+            }
         }
     }
 
@@ -232,25 +368,46 @@ impl Display {
         let end_y = cmp::min(self.height, y + h);
 
         let start_x = cmp::min(self.width - 1, x);
-        let len = (cmp::min(self.width, x + w) - start_x) * 4;
+        let cols = cmp::min(self.width, x + w) - start_x;
+
+        let bytes = self.format.bytes;
+        let copy = self.format.is_rgb32();
 
         let mut offscreen_ptr = self.offscreen.as_mut_ptr() as usize;
         let mut onscreen_ptr = self.onscreen.as_mut_ptr() as usize;
 
-        let stride = self.width * 4;
+        let offscreen_stride = self.width * 4;
 
-        let offset = y * stride + start_x * 4;
-        offscreen_ptr += offset;
-        onscreen_ptr += offset;
+        offscreen_ptr += start_y * offscreen_stride + start_x * 4;
+        onscreen_ptr += start_y * self.stride + start_x * bytes;
 
         let mut rows = end_y - start_y;
         while rows > 0 {
-            unsafe {
-                fast_copy(onscreen_ptr as *mut u8, offscreen_ptr as *const u8, len);
+            if copy {
+                unsafe {
+                    fast_copy(onscreen_ptr as *mut u8, offscreen_ptr as *const u8, cols * 4);
+                }
+            } else {
+                // Pixels are converted one at a time, and written a byte at a time, as 24 bit pixels
+                // are not aligned
+                for col in 0..cols {
+                    let value = self.format.pack(unsafe { *((offscreen_ptr + col * 4) as *const u32) });
+                    for byte in 0..bytes {
+                        unsafe { *((onscreen_ptr + col * bytes + byte) as *mut u8) = (value >> (byte * 8)) as u8; }
+                    }
+                }
             }
-            offscreen_ptr += stride;
-            onscreen_ptr += stride;
+            offscreen_ptr += offscreen_stride;
+            onscreen_ptr += self.stride;
             rows -= 1;
         }
     }
 }
+
+impl Drop for Display {
+    /// Free the offscreen buffer. Buffers replaced by `set_mode` are not freed, but screens are
+    /// never dropped, so only buffers that no client can have mapped are freed here
+    fn drop(&mut self) {
+        unsafe { heap::deallocate(self.offscreen.as_mut_ptr() as *mut u8, self.offscreen.len() * 4, 4096) };
+    }
+}
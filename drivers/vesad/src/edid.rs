@@ -0,0 +1,133 @@
+use std::fmt::Write;
+
+const HEADER: [u8; 8] = [0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00];
+
+/// Display descriptor tags
+const DESCRIPTOR_SERIAL: u8 = 0xFF;
+const DESCRIPTOR_NAME: u8 = 0xFC;
+
+/// Modes of the established timings, by byte and then bit from the highest
+const ESTABLISHED: [[(usize, usize); 8]; 3] = [
+    [(720, 400), (720, 400), (640, 480), (640, 480), (640, 480), (640, 480), (800, 600), (800, 600)],
+    [(800, 600), (800, 600), (832, 624), (1024, 768), (1024, 768), (1024, 768), (1024, 768), (1280, 1024)],
+    [(1152, 870), (0, 0), (0, 0), (0, 0), (0, 0), (0, 0), (0, 0), (0, 0)]
+];
+
+/// A mode, in pixels
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct Mode {
+    pub width: usize,
+    pub height: usize
+}
+
+/// The identity and modes of a monitor, from its EDID
+#[derive(Clone, Debug)]
+pub struct Edid {
+    /// Three letter ID of the manufacturer
+    pub manufacturer: String,
+    pub product: u16,
+    pub serial: String,
+    pub name: Option<String>,
+    /// Size of the screen in centimeters, zero if unknown
+    pub size: (usize, usize),
+    /// The native mode of the monitor, which is the first detailed timing
+    pub preferred: Option<Mode>,
+    /// Every mode the monitor supports, largest first
+    pub modes: Vec<Mode>
+}
+
+/// The text of a display descriptor, which ends at a newline and is padded with spaces
+fn descriptor_text(data: &[u8]) -> String {
+    data.iter().take_while(|&&b| b != b'\n').map(|&b| b as char).collect::<String>().trim_right().to_string()
+}
+
+impl Edid {
+    /// Parse an EDID, returning None if its header or checksum is wrong
+    pub fn parse(data: &[u8; 128]) -> Option<Edid> {
+        if data[..8] != HEADER || data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) != 0 {
+            return None;
+        }
+
+        let id = (data[8] as u16) << 8 | data[9] as u16;
+        let mut manufacturer = String::new();
+        for &shift in [10, 5, 0].iter() {
+            manufacturer.push((b'A' - 1 + ((id >> shift) & 0x1F) as u8) as char);
+        }
+
+        let mut edid = Edid {
+            manufacturer: manufacturer,
+            product: data[10] as u16 | (data[11] as u16) << 8,
+            serial: format!("{}", data[12] as u32 | (data[13] as u32) << 8 | (data[14] as u32) << 16 | (data[15] as u32) << 24),
+            name: None,
+            size: (data[21] as usize, data[22] as usize),
+            preferred: None,
+            modes: Vec::new()
+        };
+
+        for (i, row) in ESTABLISHED.iter().enumerate() {
+            for (bit, &(width, height)) in row.iter().enumerate() {
+                if width > 0 && data[35 + i] & (0x80 >> bit) != 0 {
+                    edid.modes.push(Mode { width: width, height: height });
+                }
+            }
+        }
+
+        for timing in data[38..54].chunks(2) {
+            if (timing[0] == 1 && timing[1] == 1) || timing[0] == 0 {
+                continue;
+            }
+
+            let width = (timing[0] as usize + 31) * 8;
+            let height = match timing[1] >> 6 {
+                // Before EDID 1.3, this was 1:1
+                0 if data[18] == 1 && data[19] < 3 => width,
+                0 => width * 10 / 16,
+                1 => width * 3 / 4,
+                2 => width * 4 / 5,
+                _ => width * 9 / 16
+            };
+            edid.modes.push(Mode { width: width, height: height });
+        }
+
+        for descriptor in data[54..126].chunks(18) {
+            if descriptor[0] != 0 || descriptor[1] != 0 {
+                let mode = Mode {
+                    width: descriptor[2] as usize | ((descriptor[4] as usize & 0xF0) << 4),
+                    height: descriptor[5] as usize | ((descriptor[7] as usize & 0xF0) << 4)
+                };
+                if edid.preferred.is_none() {
+                    edid.preferred = Some(mode);
+                }
+                edid.modes.push(mode);
+            } else if descriptor[3] == DESCRIPTOR_NAME {
+                edid.name = Some(descriptor_text(&descriptor[5..]));
+            } else if descriptor[3] == DESCRIPTOR_SERIAL {
+                edid.serial = descriptor_text(&descriptor[5..]);
+            }
+        }
+
+        edid.modes.sort_by(|a, b| b.cmp(a));
+        edid.modes.dedup();
+
+        Some(edid)
+    }
+
+    /// Describe the monitor as lines of `KEY VALUE`, followed by a `mode WxH` line for each mode
+    pub fn to_string(&self) -> String {
+        let mut string = String::new();
+        if let Some(ref name) = self.name {
+            let _ = write!(string, "name {}\n", name);
+        }
+        let _ = write!(string, "manufacturer {}\nproduct {:04X}\nserial {}\n", self.manufacturer, self.product, self.serial);
+        if self.size.0 > 0 && self.size.1 > 0 {
+            let _ = write!(string, "size {}x{}\n", self.size.0, self.size.1);
+        }
+        if let Some(preferred) = self.preferred {
+            let _ = write!(string, "preferred {}x{}\n", preferred.width, preferred.height);
+        }
+        for mode in self.modes.iter() {
+            let _ = write!(string, "mode {}x{}\n", mode.width, mode.height);
+        }
+        string
+    }
+}
@@ -0,0 +1,234 @@
+use std::borrow::Cow;
+use std::char;
+use std::collections::BTreeMap;
+
+/// Unifont, with an 8x16 glyph for each code point of the basic multilingual plane
+static UNIFONT: &'static [u8] = include_bytes!("../../../res/fonts/unifont.font");
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+/// The font has 512 glyphs instead of 256
+const PSF1_MODE512: u8 = 0x01;
+/// The font has a unicode table
+const PSF1_MODEHASTAB: u8 = 0x02;
+const PSF1_MODESEQ: u8 = 0x04;
+const PSF1_SEPARATOR: u16 = 0xFFFF;
+const PSF1_STARTSEQ: u16 = 0xFFFE;
+
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xB5, 0x4A, 0x86];
+/// The font has a unicode table
+const PSF2_HAS_UNICODE_TABLE: u32 = 0x01;
+const PSF2_SEPARATOR: u8 = 0xFF;
+const PSF2_STARTSEQ: u8 = 0xFE;
+
+/// Ranges of East Asian wide and fullwidth characters, which take two cells
+const WIDE: [(u32, u32); 14] = [
+    (0x1100, 0x115F),
+    (0x2E80, 0x303E),
+    (0x3041, 0x33FF),
+    (0x3400, 0x4DBF),
+    (0x4E00, 0x9FFF),
+    (0xA000, 0xA4CF),
+    (0xAC00, 0xD7A3),
+    (0xF900, 0xFAFF),
+    (0xFE30, 0xFE4F),
+    (0xFF00, 0xFF60),
+    (0xFFE0, 0xFFE6),
+    (0x1F300, 0x1F64F),
+    (0x20000, 0x2FFFD),
+    (0x30000, 0x3FFFD)
+];
+
+/// Check if a character takes two cells
+pub fn is_wide(character: char) -> bool {
+    let c = character as u32;
+    WIDE.iter().any(|&(start, end)| c >= start && c <= end)
+}
+
+fn read_u16(data: &[u8], i: usize) -> u16 {
+    data[i] as u16 | (data[i + 1] as u16) << 8
+}
+
+fn read_u32(data: &[u8], i: usize) -> u32 {
+    read_u16(data, i) as u32 | (read_u16(data, i + 2) as u32) << 16
+}
+
+/// A bitmap font. Each glyph is `height` rows, and each row is `width` bits from the highest bit,
+/// padded to a whole byte
+pub struct Font {
+    pub width: usize,
+    pub height: usize,
+    glyphs: Cow<'static, [u8]>,
+    /// Glyph of each character. Without a table, characters are glyph indexes
+    unicode: Option<BTreeMap<char, usize>>,
+    /// Glyphs of wide characters, which are twice as wide
+    wide: Option<Box<Font>>
+}
+
+impl Font {
+    /// The compiled in unifont
+    pub fn unifont() -> Font {
+        Font {
+            width: 8,
+            height: 16,
+            glyphs: Cow::Borrowed(UNIFONT),
+            unicode: None,
+            wide: None
+        }
+    }
+
+    /// Parse a PSF1 or PSF2 font, returning None if it is neither or is cut short
+    pub fn parse(data: &[u8]) -> Option<Font> {
+        if data.len() >= 4 && data[..2] == PSF1_MAGIC {
+            let mode = data[2];
+            let height = data[3] as usize;
+            let count = if mode & PSF1_MODE512 == PSF1_MODE512 { 512 } else { 256 };
+            let end = 4 + count * height;
+            if height == 0 || data.len() < end {
+                return None;
+            }
+
+            let unicode = if mode & (PSF1_MODEHASTAB | PSF1_MODESEQ) != 0 {
+                Some(Font::psf1_table(&data[end..], count))
+            } else {
+                None
+            };
+
+            Some(Font {
+                width: 8,
+                height: height,
+                glyphs: Cow::Owned(data[4..end].to_vec()),
+                unicode: unicode,
+                wide: None
+            })
+        } else if data.len() >= 32 && data[..4] == PSF2_MAGIC {
+            let header_size = read_u32(data, 8) as usize;
+            let flags = read_u32(data, 12);
+            let count = read_u32(data, 16) as usize;
+            let glyph_size = read_u32(data, 20) as usize;
+            let height = read_u32(data, 24) as usize;
+            let width = read_u32(data, 28) as usize;
+            if width == 0 || height == 0 || glyph_size != (width + 7) / 8 * height {
+                return None;
+            }
+
+            let end = header_size + count * glyph_size;
+            if header_size < 32 || data.len() < end {
+                return None;
+            }
+
+            let unicode = if flags & PSF2_HAS_UNICODE_TABLE == PSF2_HAS_UNICODE_TABLE {
+                Some(Font::psf2_table(&data[end..], count))
+            } else {
+                None
+            };
+
+            Some(Font {
+                width: width,
+                height: height,
+                glyphs: Cow::Owned(data[header_size..end].to_vec()),
+                unicode: unicode,
+                wide: None
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Read the UCS-2 table of a PSF1 font. Sequences of combining characters are skipped
+    fn psf1_table(data: &[u8], count: usize) -> BTreeMap<char, usize> {
+        let mut unicode = BTreeMap::new();
+
+        let mut glyph = 0;
+        let mut sequence = false;
+        let mut i = 0;
+        while glyph < count && i + 2 <= data.len() {
+            let value = read_u16(data, i);
+            i += 2;
+
+            if value == PSF1_SEPARATOR {
+                glyph += 1;
+                sequence = false;
+            } else if value == PSF1_STARTSEQ {
+                sequence = true;
+            } else if ! sequence {
+                if let Some(c) = char::from_u32(value as u32) {
+                    unicode.entry(c).or_insert(glyph);
+                }
+            }
+        }
+
+        unicode
+    }
+
+    /// Read the UTF-8 table of a PSF2 font. Sequences of combining characters are skipped
+    fn psf2_table(data: &[u8], count: usize) -> BTreeMap<char, usize> {
+        let mut unicode = BTreeMap::new();
+
+        let mut glyph = 0;
+        for entry in data.split(|&b| b == PSF2_SEPARATOR) {
+            if glyph >= count {
+                break;
+            }
+
+            let single = match entry.iter().position(|&b| b == PSF2_STARTSEQ) {
+                Some(start) => &entry[..start],
+                None => entry
+            };
+            for c in String::from_utf8_lossy(single).chars() {
+                if c != '\u{FFFD}' {
+                    unicode.entry(c).or_insert(glyph);
+                }
+            }
+
+            glyph += 1;
+        }
+
+        unicode
+    }
+
+    /// Draw wide characters with `wide`, which must be the same height and twice the width.
+    /// Returns false if it is not
+    pub fn set_wide(&mut self, wide: Font) -> bool {
+        if wide.width == self.width * 2 && wide.height == self.height {
+            self.wide = Some(Box::new(wide));
+            true
+        } else {
+            false
+        }
+    }
+
+    /// The rows of the glyph of `character`, with its width in pixels. Wide characters use the
+    /// wide font, and characters without a glyph are drawn as a replacement character or `?`
+    pub fn glyph(&self, character: char) -> Option<(&[u8], usize)> {
+        if is_wide(character) {
+            if let Some(ref wide) = self.wide {
+                if let Some(glyph) = wide.find(character) {
+                    return Some((glyph, wide.width));
+                }
+            }
+        }
+
+        self.find(character)
+            .or_else(|| self.find('\u{FFFD}'))
+            .or_else(|| self.find('?'))
+            .map(|glyph| (glyph, self.width))
+    }
+
+    /// The rows of the glyph of `character` in this font only
+    fn find(&self, character: char) -> Option<&[u8]> {
+        let index = match self.unicode {
+            Some(ref unicode) => match unicode.get(&character) {
+                Some(&index) => index,
+                None => return None
+            },
+            None => character as usize
+        };
+
+        let size = (self.width + 7) / 8 * self.height;
+        if (index + 1) * size <= self.glyphs.len() {
+            Some(&self.glyphs[index * size..(index + 1) * size])
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,64 @@
+/// An image, with pixels in the offscreen format
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<u32>
+}
+
+fn read_u16(data: &[u8], i: usize) -> u16 {
+    data[i] as u16 | (data[i + 1] as u16) << 8
+}
+
+fn read_u32(data: &[u8], i: usize) -> u32 {
+    read_u16(data, i) as u32 | (read_u16(data, i + 2) as u32) << 16
+}
+
+/// Uncompressed pixels, or for 32 bit images, pixels in the usual BGRA masks
+const BI_RGB: u32 = 0;
+const BI_BITFIELDS: u32 = 3;
+
+impl Image {
+    /// Parse an uncompressed 24 or 32 bit BMP, returning None if it is anything else
+    pub fn from_bmp(data: &[u8]) -> Option<Image> {
+        if data.len() < 54 || &data[..2] != b"BM" {
+            return None;
+        }
+
+        let offset = read_u32(data, 10) as usize;
+        let width = read_u32(data, 18) as i32;
+        let height = read_u32(data, 22) as i32;
+        let bpp = read_u16(data, 28) as usize;
+        let compression = read_u32(data, 30);
+
+        if width <= 0 || height == 0 || (bpp != 24 && bpp != 32)
+            || ! (compression == BI_RGB || (compression == BI_BITFIELDS && bpp == 32)) {
+            return None;
+        }
+
+        // Rows are stored from the bottom up, unless the height is negative
+        let bottom_up = height > 0;
+        let width = width as usize;
+        let height = height.abs() as usize;
+        let bytes = bpp / 8;
+        let row_size = (width * bytes + 3) & !3;
+        if data.len() < offset + row_size * height {
+            return None;
+        }
+
+        let mut pixels = Vec::with_capacity(width * height);
+        for y in 0..height {
+            let row = if bottom_up { height - 1 - y } else { y };
+            let start = offset + row * row_size;
+            for x in 0..width {
+                let i = start + x * bytes;
+                pixels.push((data[i + 2] as u32) << 16 | (data[i + 1] as u32) << 8 | data[i] as u32);
+            }
+        }
+
+        Some(Image {
+            width: width,
+            height: height,
+            data: pixels
+        })
+    }
+}
@@ -6,29 +6,88 @@ extern crate alloc;
 extern crate orbclient;
 extern crate syscall;
 
-use std::{env, mem};
-use std::fs::File;
+use std::{cmp, env, mem, ptr};
+use std::fs::{File, OpenOptions};
 use std::io::{Read, Write};
+use orbclient::KeyEvent;
 use syscall::{physmap, physunmap, Packet, SchemeMut, EVENT_READ, MAP_WRITE, MAP_WRITE_COMBINE};
 
+use bga::Bga;
+use edid::Edid;
+use font::Font;
+use image::Image;
 use mode_info::VBEModeInfo;
-use primitive::fast_set64;
 use scheme::DisplayScheme;
 
+pub mod bga;
 pub mod display;
+pub mod edid;
+pub mod font;
+pub mod image;
 pub mod mode_info;
 pub mod primitive;
 pub mod scheme;
 pub mod screen;
+pub mod splash;
+pub mod vsync;
+
+/// Forward input from the serial console to the display, as if it was typed
+fn serial_input() -> ! {
+    let mut serial = File::open("debug:").expect("vesad: failed to open serial console");
+    let mut input = OpenOptions::new().write(true).open("display:input").expect("vesad: failed to open display:input");
+
+    let mut buf = [0; 4096];
+    loop {
+        let count = serial.read(&mut buf).expect("vesad: failed to read serial console");
+        for &b in buf[..count].iter() {
+            // Escape sequences from the terminal are passed through unchanged
+            input.write(&KeyEvent {
+                character: b as char,
+                scancode: 0,
+                pressed: true
+            }.to_event()).expect("vesad: failed to write key event");
+        }
+    }
+}
+
+/// Load a PSF font
+fn load_font(path: &str) -> Option<Font> {
+    let mut data = Vec::new();
+    match File::open(path).and_then(|mut file| file.read_to_end(&mut data)) {
+        Ok(_) => match Font::parse(&data) {
+            Some(font) => Some(font),
+            None => {
+                println!("vesad: {} is not a PSF font", path);
+                None
+            }
+        },
+        Err(err) => {
+            println!("vesad: failed to read font {}: {}", path, err);
+            None
+        }
+    }
+}
 
 fn main() {
     let mut spec = Vec::new();
+    let mut mirror = false;
+    let mut font_path = None;
+    let mut wide_font_path = None;
+    let mut splash_path = None;
 
     for arg in env::args().skip(1) {
         if arg == "T" {
             spec.push(false);
         } else if arg == "G" {
             spec.push(true);
+        } else if arg == "console=both" {
+            mirror = true;
+        } else if arg.starts_with("font=") {
+            font_path = Some(arg[5..].to_string());
+        } else if arg.starts_with("widefont=") {
+            wide_font_path = Some(arg[9..].to_string());
+        } else if arg.starts_with("splash=") {
+            splash_path = Some(arg[7..].to_string());
         } else {
             println!("vesad: unknown screen type: {}", arg);
         }
@@ -36,6 +95,8 @@ fn main() {
 
     let width;
     let height;
+    let stride;
+    let format;
     let physbaseptr;
 
     {
@@ -43,22 +104,74 @@ fn main() {
 
         width = mode_info.xresolution as usize;
         height = mode_info.yresolution as usize;
+        format = mode_info.pixel_format();
+        stride = cmp::max(width * format.bytes, mode_info.bytes_per_scanline());
         physbaseptr = mode_info.physbaseptr as usize;
 
         unsafe { let _ = physunmap(mode_info as *const _ as usize); }
     }
 
+    let edid = {
+        let data = unsafe { &*(physmap(0x5400, 4096, 0).expect("vesad: failed to map EDID") as *const [u8; 128]) };
+        let edid = Edid::parse(data);
+        unsafe { let _ = physunmap(data as *const _ as usize); }
+        edid
+    };
+
+    // A PSF font replaces unifont if it can be loaded
+    let mut font = font_path.and_then(|path| load_font(&path)).unwrap_or_else(Font::unifont);
+    if let Some(wide) = wide_font_path.and_then(|path| load_font(&path)) {
+        if ! font.set_wide(wide) {
+            println!("vesad: wide font must be twice as wide as the font, and as high");
+        }
+    }
+
+    let logo = splash_path.and_then(|path| {
+        let mut data = Vec::new();
+        match File::open(&path).and_then(|mut file| file.read_to_end(&mut data)) {
+            Ok(_) => {
+                let logo = Image::from_bmp(&data);
+                if logo.is_none() {
+                    println!("vesad: {} is not an uncompressed 24 or 32 bit BMP", path);
+                }
+                logo
+            },
+            Err(err) => {
+                println!("vesad: failed to read boot logo {}: {}", path, err);
+                None
+            }
+        }
+    });
+
     if physbaseptr > 0 {
         // Daemonize
         if unsafe { syscall::clone(0).unwrap() } == 0 {
             let mut socket = File::create(":display").expect("vesad: failed to create display scheme");
 
-            let size = width * height;
+            if mirror && unsafe { syscall::clone(0).unwrap() } == 0 {
+                drop(socket);
+                serial_input();
+            }
 
-            let onscreen = unsafe { physmap(physbaseptr, size * 4, MAP_WRITE | MAP_WRITE_COMBINE).expect("vesad: failed to map VBE LFB") };
-            unsafe { fast_set64(onscreen as *mut u64, 0, size/2) };
+            if unsafe { syscall::clone(0).unwrap() } == 0 {
+                drop(socket);
+                vsync::vsync();
+            }
 
-            let mut scheme = DisplayScheme::new(width, height, onscreen, &spec);
+            let size = stride * height;
+
+            let onscreen = unsafe { physmap(physbaseptr, size, MAP_WRITE | MAP_WRITE_COMBINE).expect("vesad: failed to map VBE LFB") };
+            unsafe { ptr::write_bytes(onscreen as *mut u8, 0, size) };
+
+            let mut scheme = DisplayScheme::new(width, height, stride, format, physbaseptr, onscreen, font, logo, &spec, mirror);
+            scheme.edid = edid;
+
+            // Modes can only be switched with the Bochs graphics adapter, which is looked for if
+            // its ports can be used
+            scheme.bga = {
+                let path = format!("sys:{}/ports", syscall::getpid().expect("vesad: failed to get PID"));
+                OpenOptions::new().write(true).open(&path).and_then(|mut ports| ports.write(b"1CE-1CF\n")).ok().and_then(|_| Bga::new())
+            };
 
             let mut blocked = Vec::new();
             loop {
@@ -103,6 +216,21 @@ fn main() {
                         socket.write(&event_packet).expect("vesad: failed to write display event");
                     }
                 }
+
+                for vsync_id in scheme.vsync_events() {
+                    let event_packet = Packet {
+                        id: 0,
+                        pid: 0,
+                        uid: 0,
+                        gid: 0,
+                        a: syscall::number::SYS_FEVENT,
+                        b: vsync_id,
+                        c: EVENT_READ,
+                        d: 8
+                    };
+
+                    socket.write(&event_packet).expect("vesad: failed to write display event");
+                }
             }
         }
     }
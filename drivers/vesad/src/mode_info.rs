@@ -1,3 +1,5 @@
+use display::PixelFormat;
+
 /// The info of the VBE mode
 #[derive(Copy, Clone, Default, Debug)]
 #[repr(packed)]
@@ -35,3 +37,25 @@ pub struct VBEModeInfo {
     offscreenmemoryoffset: u32,
     offscreenmemsize: u16,
 }
+
+impl VBEModeInfo {
+    pub fn bytes_per_scanline(&self) -> usize {
+        self.bytesperscanline as usize
+    }
+
+    /// The layout of pixels, from the color masks. Modes that do not give masks are taken to be
+    /// 32 bit
+    pub fn pixel_format(&self) -> PixelFormat {
+        let bytes = (self.bitsperpixel as usize + 7)/8;
+        if bytes < 2 || bytes > 4 || self.redmasksize == 0 || self.greenmasksize == 0 || self.bluemasksize == 0 {
+            PixelFormat::rgb32()
+        } else {
+            PixelFormat {
+                bytes: bytes,
+                red: (self.redmasksize, self.redfieldposition),
+                green: (self.greenmasksize, self.greenfieldposition),
+                blue: (self.bluemasksize, self.bluefieldposition)
+            }
+        }
+    }
+}
@@ -1,44 +1,245 @@
 use std::collections::BTreeMap;
-use std::{mem, slice, str};
+use std::fs::OpenOptions;
+use std::rc::Rc;
+use std::{cmp, mem, ptr, slice, str};
 
 use orbclient::{Event, EventOption};
-use syscall::{Result, Error, EACCES, EBADF, ENOENT, SchemeMut};
+use syscall::{physmap, physunmap, Result, Error, EACCES, EBADF, EINVAL, ENODEV, ENOENT, MAP_WRITE, MAP_WRITE_COMBINE, EVENT_READ, SchemeMut};
 
-use display::Display;
+use bga::Bga;
+use display::{Display, PixelFormat};
+use edid::Edid;
+use font::Font;
+use image::Image;
 use screen::{Screen, GraphicScreen, TextScreen};
+use splash::Splash;
+
+/// Handle of `display:mode`, which root may write a mode like `1280x800x32` to, leaving out the
+/// depth to keep the current one, or `preferred` for the native mode of the monitor
+const HANDLE_MODE: usize = !0;
+
+/// Handle of `display:framebuffer`, which a display driver writes a 32 bit framebuffer it has set
+/// up to, as `ADDRESS WIDTH HEIGHT STRIDE` with the physical address in hex and the stride in bytes
+const HANDLE_FRAMEBUFFER: usize = !0 - 1;
+
+/// Handle of `display:splash`, which root may write `progress PERCENT` to, to fill the progress
+/// bar of the boot logo, or `done` to remove the logo and show the screens
+const HANDLE_SPLASH: usize = !0 - 2;
+
+/// Handles of `display:modes`, `display:vsync` and page flipping are numbered from here, above
+/// any screen
+const HANDLE_FIRST: usize = 0x10000;
+
+enum Handle {
+    /// An open `display:modes`, which holds the current mode and what the EDID says of the monitor
+    Modes {
+        data: Vec<u8>,
+        seek: usize
+    },
+    /// An open `display:vsync`. Reads block until the next vertical blank and give the number of
+    /// vertical blanks so far as a u64, and root may write to report a vertical blank
+    Vsync {
+        uid: u32,
+        /// Vertical blanks when last read
+        seen: u64,
+        /// Events requested with fevent
+        events: usize,
+        /// Vertical blanks when an event was last sent
+        notified: u64
+    },
+    /// Page flipping of a graphic screen, from a dup of the screen with `flip`. The screen has a
+    /// second buffer, mapped after the first. Writing the number of a buffer shows it at the next
+    /// vertical blank, and reads block until it is shown, giving its number
+    Flip {
+        screen: usize,
+        page: usize
+    }
+}
 
 pub struct DisplayScheme {
     active: usize,
-    pub screens: BTreeMap<usize, Box<Screen>>
+    /// Alt is held, which makes the function keys switch screens
+    alt: bool,
+    pub screens: BTreeMap<usize, Box<Screen>>,
+    /// Physical address of the linear framebuffer
+    physbaseptr: usize,
+    /// Address the framebuffer is mapped at
+    onscreen: usize,
+    /// The current mode
+    width: usize,
+    height: usize,
+    /// Bits per pixel of the current mode
+    bpp: usize,
+    /// The adapter, if modes can be switched
+    pub bga: Option<Bga>,
+    /// The monitor, if the bootloader could read its EDID
+    pub edid: Option<Edid>,
+    /// Vertical blanks so far
+    vblanks: u64,
+    /// The boot logo, which is shown instead of the active screen until it is removed
+    splash: Option<Splash>,
+    next_id: usize,
+    handles: BTreeMap<usize, Handle>
 }
 
 impl DisplayScheme {
-    pub fn new(width: usize, height: usize, onscreen: usize, spec: &[bool]) -> DisplayScheme {
+    /// Create the screens described by `spec`, drawing text with `font`, and show `logo` if there
+    /// is one. If `mirror` is set, output to the first text screen is also written to the serial
+    /// console
+    pub fn new(width: usize, height: usize, stride: usize, format: PixelFormat, physbaseptr: usize, onscreen: usize, font: Font, logo: Option<Image>, spec: &[bool], mut mirror: bool) -> DisplayScheme {
+        let font = Rc::new(font);
+
         let mut screens: BTreeMap<usize, Box<Screen>> = BTreeMap::new();
 
         let mut screen_i = 1;
         for &screen_type in spec.iter() {
             if screen_type {
-                screens.insert(screen_i, Box::new(GraphicScreen::new(Display::new(width, height, onscreen))));
+                screens.insert(screen_i, Box::new(GraphicScreen::new(Display::new(width, height, stride, format, onscreen, font.clone()))));
             } else {
-                screens.insert(screen_i, Box::new(TextScreen::new(Display::new(width, height, onscreen))));
+                let mut screen = TextScreen::new(Display::new(width, height, stride, format, onscreen, font.clone()));
+                if mirror {
+                    screen.mirror = OpenOptions::new().write(true).open("debug:").ok();
+                    mirror = false;
+                }
+                screens.insert(screen_i, Box::new(screen));
             }
             screen_i += 1;
         }
 
+        let splash = logo.map(|logo| {
+            let mut splash = Splash::new(Display::new(width, height, stride, format, onscreen, font.clone()), logo);
+            splash.draw();
+            splash
+        });
+
         DisplayScheme {
             active: 1,
-            screens: screens
+            alt: false,
+            screens: screens,
+            physbaseptr: physbaseptr,
+            onscreen: onscreen,
+            width: width,
+            height: height,
+            bpp: format.bytes * 8,
+            bga: None,
+            edid: None,
+            vblanks: 0,
+            splash: splash,
+            next_id: HANDLE_FIRST,
+            handles: BTreeMap::new()
         }
     }
 
+    /// Switch the display mode with the Bochs graphics adapter
+    fn set_mode(&mut self, width: usize, height: usize, bpp: usize) -> Result<()> {
+        let format = Bga::pixel_format(bpp).ok_or(Error::new(EINVAL))?;
+        let stride = width * format.bytes;
+        let size = stride * height;
+
+        let onscreen = unsafe { physmap(self.physbaseptr, size, MAP_WRITE | MAP_WRITE_COMBINE)? };
+
+        let switched = match self.bga {
+            Some(ref mut bga) => bga.set_mode(width, height, bpp),
+            None => {
+                unsafe { let _ = physunmap(onscreen); }
+                return Err(Error::new(ENODEV));
+            }
+        };
+        if ! switched {
+            unsafe { let _ = physunmap(onscreen); }
+            return Err(Error::new(EINVAL));
+        }
+
+        self.bpp = bpp;
+        let physbaseptr = self.physbaseptr;
+        self.use_framebuffer(physbaseptr, onscreen, width, height, stride, format);
+
+        Ok(())
+    }
+
+    /// Use a framebuffer set up by a display driver, which is 32 bit
+    fn set_framebuffer(&mut self, physbaseptr: usize, width: usize, height: usize, stride: usize) -> Result<()> {
+        if width == 0 || height == 0 || stride < width * 4 {
+            return Err(Error::new(EINVAL));
+        }
+
+        let onscreen = unsafe { physmap(physbaseptr, stride * height, MAP_WRITE | MAP_WRITE_COMBINE)? };
+
+        // A display driver replaces the adapter that modes were switched with
+        self.bga = None;
+        self.bpp = 32;
+        self.use_framebuffer(physbaseptr, onscreen, width, height, stride, PixelFormat::rgb32());
+
+        Ok(())
+    }
+
+    /// Switch to a mapped framebuffer and resize every screen, which clears them
+    fn use_framebuffer(&mut self, physbaseptr: usize, onscreen: usize, width: usize, height: usize, stride: usize, format: PixelFormat) {
+        unsafe {
+            let _ = physunmap(self.onscreen);
+            ptr::write_bytes(onscreen as *mut u8, 0, stride * height);
+        }
+        self.physbaseptr = physbaseptr;
+        self.onscreen = onscreen;
+        self.width = width;
+        self.height = height;
+
+        for screen in self.screens.values_mut() {
+            screen.set_mode(width, height, stride, format, onscreen);
+        }
+        if let Some(ref mut splash) = self.splash {
+            splash.display.set_mode(width, height, stride, format, onscreen);
+            splash.draw();
+        } else if let Some(mut screen) = self.screens.get_mut(&self.active) {
+            screen.redraw();
+        }
+    }
+
+    /// Remove the boot logo, showing the active screen with what was written to it meanwhile
+    fn remove_splash(&mut self) {
+        if self.splash.take().is_some() {
+            if let Some(mut screen) = self.screens.get_mut(&self.active) {
+                screen.redraw();
+            }
+        }
+    }
+
+    /// The current mode, followed by the identity and modes of the monitor
+    fn modes_string(&self) -> String {
+        let mut string = format!("current {}x{}x{}\n", self.width, self.height, self.bpp);
+        if let Some(ref edid) = self.edid {
+            string.push_str(&edid.to_string());
+        }
+        string
+    }
+
     pub fn will_block(&self, id: usize) -> bool {
-        if let Some(screen) = self.screens.get(&id) {
+        if let Some(&Handle::Vsync { seen, .. }) = self.handles.get(&id) {
+            seen == self.vblanks
+        } else if let Some(&Handle::Flip { screen, .. }) = self.handles.get(&id) {
+            self.screens.get(&screen).map_or(false, |screen| screen.flip_pending())
+        } else if let Some(screen) = self.screens.get(&id) {
             screen.will_block()
         } else {
             false
         }
     }
+
+    /// The `display:vsync` handles that asked for events and have not been sent one for the last
+    /// vertical blank, which are marked as sent
+    pub fn vsync_events(&mut self) -> Vec<usize> {
+        let vblanks = self.vblanks;
+        let mut ids = Vec::new();
+        for (&id, handle) in self.handles.iter_mut() {
+            if let Handle::Vsync { seen, events, ref mut notified, .. } = *handle {
+                if events & EVENT_READ == EVENT_READ && seen != vblanks && *notified != vblanks {
+                    *notified = vblanks;
+                    ids.push(id);
+                }
+            }
+        }
+        ids
+    }
 }
 
 impl SchemeMut for DisplayScheme {
@@ -49,6 +250,43 @@ impl SchemeMut for DisplayScheme {
             } else {
                 Err(Error::new(EACCES))
             }
+        } else if path == b"mode" {
+            if uid == 0 {
+                Ok(HANDLE_MODE)
+            } else {
+                Err(Error::new(EACCES))
+            }
+        } else if path == b"framebuffer" {
+            if uid == 0 {
+                Ok(HANDLE_FRAMEBUFFER)
+            } else {
+                Err(Error::new(EACCES))
+            }
+        } else if path == b"splash" {
+            if uid == 0 {
+                Ok(HANDLE_SPLASH)
+            } else {
+                Err(Error::new(EACCES))
+            }
+        } else if path == b"modes" {
+            let id = self.next_id;
+            self.next_id += 1;
+            let data = self.modes_string().into_bytes();
+            self.handles.insert(id, Handle::Modes {
+                data: data,
+                seek: 0
+            });
+            Ok(id)
+        } else if path == b"vsync" {
+            let id = self.next_id;
+            self.next_id += 1;
+            self.handles.insert(id, Handle::Vsync {
+                uid: uid,
+                seen: self.vblanks,
+                events: 0,
+                notified: self.vblanks
+            });
+            Ok(id)
         } else {
             let path_str = str::from_utf8(path).unwrap_or("");
             let id = path_str.parse::<usize>().unwrap_or(0);
@@ -60,12 +298,30 @@ impl SchemeMut for DisplayScheme {
         }
     }
 
-    fn dup(&mut self, id: usize, _buf: &[u8]) -> Result<usize> {
-        Ok(id)
+    fn dup(&mut self, id: usize, buf: &[u8]) -> Result<usize> {
+        if buf == b"flip" {
+            match self.screens.get_mut(&id) {
+                Some(mut screen) => screen.double_buffer()?,
+                None => return Err(Error::new(EBADF))
+            }
+
+            let flip_id = self.next_id;
+            self.next_id += 1;
+            self.handles.insert(flip_id, Handle::Flip {
+                screen: id,
+                page: 0
+            });
+            Ok(flip_id)
+        } else {
+            Ok(id)
+        }
     }
 
     fn fevent(&mut self, id: usize, flags: usize) -> Result<usize> {
-        if let Some(mut screen) = self.screens.get_mut(&id) {
+        if let Some(&mut Handle::Vsync { ref mut events, .. }) = self.handles.get_mut(&id) {
+            *events = flags;
+            Ok(id)
+        } else if let Some(mut screen) = self.screens.get_mut(&id) {
             screen.event(flags).and(Ok(id))
         } else {
             Err(Error::new(EBADF))
@@ -83,6 +339,18 @@ impl SchemeMut for DisplayScheme {
     fn fpath(&mut self, id: usize, buf: &mut [u8]) -> Result<usize> {
         let path_str = if id == 0 {
             format!("display:input")
+        } else if id == HANDLE_MODE {
+            format!("display:mode")
+        } else if id == HANDLE_FRAMEBUFFER {
+            format!("display:framebuffer")
+        } else if id == HANDLE_SPLASH {
+            format!("display:splash")
+        } else if let Some(handle) = self.handles.get(&id) {
+            match *handle {
+                Handle::Modes { .. } => format!("display:modes"),
+                Handle::Vsync { .. } => format!("display:vsync"),
+                Handle::Flip { screen, .. } => format!("display:{}/flip", screen)
+            }
         } else if let Some(screen) = self.screens.get(&id) {
             format!("display:{}/{}/{}", id, screen.width(), screen.height())
         } else {
@@ -102,7 +370,7 @@ impl SchemeMut for DisplayScheme {
 
     fn fsync(&mut self, id: usize) -> Result<usize> {
         if let Some(mut screen) = self.screens.get_mut(&id) {
-            if id == self.active {
+            if id == self.active && self.splash.is_none() {
                 screen.sync();
             }
             Ok(0)
@@ -112,7 +380,32 @@ impl SchemeMut for DisplayScheme {
     }
 
     fn read(&mut self, id: usize, buf: &mut [u8]) -> Result<usize> {
-        if let Some(mut screen) = self.screens.get_mut(&id) {
+        if let Some(handle) = self.handles.get_mut(&id) {
+            match *handle {
+                Handle::Modes { ref data, ref mut seek } => {
+                    let count = cmp::min(buf.len(), data.len() - *seek);
+                    buf[.. count].copy_from_slice(&data[*seek .. *seek + count]);
+                    *seek += count;
+                    Ok(count)
+                },
+                Handle::Vsync { ref mut seen, .. } => {
+                    if buf.len() < 8 {
+                        return Err(Error::new(EINVAL));
+                    }
+                    *seen = self.vblanks;
+                    for i in 0..8 {
+                        buf[i] = (self.vblanks >> (i * 8)) as u8;
+                    }
+                    Ok(8)
+                },
+                Handle::Flip { page, .. } => {
+                    let page = format!("{}\n", page);
+                    let count = cmp::min(buf.len(), page.len());
+                    buf[.. count].copy_from_slice(&page.as_bytes()[.. count]);
+                    Ok(count)
+                }
+            }
+        } else if let Some(mut screen) = self.screens.get_mut(&id) {
             screen.read(buf)
         } else {
             Err(Error::new(EBADF))
@@ -120,7 +413,63 @@ impl SchemeMut for DisplayScheme {
     }
 
     fn write(&mut self, id: usize, buf: &[u8]) -> Result<usize> {
-        if id == 0 {
+        if let Some(&Handle::Vsync { uid, .. }) = self.handles.get(&id) {
+            if uid != 0 {
+                return Err(Error::new(EACCES));
+            }
+            self.vblanks += 1;
+
+            // Flips are done while the display is blank, so that they do not tear
+            let showing = self.splash.is_none();
+            for (&screen_id, screen) in self.screens.iter_mut() {
+                screen.flip(showing && screen_id == self.active);
+            }
+
+            Ok(buf.len())
+        } else if let Some(&mut Handle::Flip { screen, ref mut page }) = self.handles.get_mut(&id) {
+            let new_page = str::from_utf8(buf).or(Err(Error::new(EINVAL)))?.trim().parse::<usize>().or(Err(Error::new(EINVAL)))?;
+            self.screens.get_mut(&screen).ok_or(Error::new(EBADF))?.queue_flip(new_page)?;
+            *page = new_page;
+            Ok(buf.len())
+        } else if id == HANDLE_MODE {
+            let mode = str::from_utf8(buf).or(Err(Error::new(EINVAL)))?.trim();
+            if mode == "preferred" {
+                let preferred = self.edid.as_ref().and_then(|edid| edid.preferred).ok_or(Error::new(ENODEV))?;
+                let bpp = self.bpp;
+                return self.set_mode(preferred.width, preferred.height, bpp).and(Ok(buf.len()));
+            }
+            let mut parts = mode.split('x').map(|part| part.parse::<usize>().or(Err(Error::new(EINVAL))));
+            let width = parts.next().unwrap_or(Err(Error::new(EINVAL)))?;
+            let height = parts.next().unwrap_or(Err(Error::new(EINVAL)))?;
+            let bpp = parts.next().unwrap_or(Ok(self.bpp))?;
+            self.set_mode(width, height, bpp).and(Ok(buf.len()))
+        } else if id == HANDLE_FRAMEBUFFER {
+            let framebuffer = str::from_utf8(buf).or(Err(Error::new(EINVAL)))?;
+            let mut parts = framebuffer.split_whitespace();
+            let address = parts.next().and_then(|part| usize::from_str_radix(part, 16).ok()).ok_or(Error::new(EINVAL))?;
+            let mut parts = parts.map(|part| part.parse::<usize>().or(Err(Error::new(EINVAL))));
+            let width = parts.next().unwrap_or(Err(Error::new(EINVAL)))?;
+            let height = parts.next().unwrap_or(Err(Error::new(EINVAL)))?;
+            let stride = parts.next().unwrap_or(Err(Error::new(EINVAL)))?;
+            self.set_framebuffer(address, width, height, stride).and(Ok(buf.len()))
+        } else if id == HANDLE_SPLASH {
+            let command = str::from_utf8(buf).or(Err(Error::new(EINVAL)))?.trim();
+            if command == "done" {
+                self.remove_splash();
+            } else if command.starts_with("progress ") {
+                let progress = command[9..].trim().parse::<usize>().or(Err(Error::new(EINVAL)))?;
+                if let Some(ref mut splash) = self.splash {
+                    splash.progress = Some(cmp::min(progress, 100));
+                    splash.draw();
+                }
+            } else {
+                return Err(Error::new(EINVAL));
+            }
+            Ok(buf.len())
+        } else if id == 0 {
+            // Any input removes the boot logo
+            self.remove_splash();
+
             if buf.len() == 1 && buf[0] >= 0xF4 {
                 let new_active = (buf[0] - 0xF4) as usize + 1;
                 if let Some(mut screen) = self.screens.get_mut(&new_active) {
@@ -132,18 +481,35 @@ impl SchemeMut for DisplayScheme {
                 let events = unsafe { slice::from_raw_parts(buf.as_ptr() as *const Event, buf.len()/mem::size_of::<Event>()) };
 
                 for event in events.iter() {
+                    // Screens are switched with Alt and a function key, which are still given to
+                    // the screen when pressed on their own
                     let new_active_opt = if let EventOption::Key(key_event) = event.to_option() {
-                        match key_event.scancode {
-                            f @ 0x3B ... 0x44 => { // F1 through F10
-                                Some((f - 0x3A) as usize)
-                            },
-                            0x57 => { // F11
-                                Some(11)
-                            },
-                            0x58 => { // F12
-                                Some(12)
-                            },
-                            _ => None
+                        if key_event.scancode == 0x38 {
+                            self.alt = key_event.pressed;
+                        }
+
+                        if ! self.alt {
+                            None
+                        } else if ! key_event.pressed {
+                            // The release of a function key that switched is dropped, as there is no
+                            // screen 0
+                            match key_event.scancode {
+                                0x3B ... 0x44 | 0x57 | 0x58 => Some(0),
+                                _ => None
+                            }
+                        } else {
+                            match key_event.scancode {
+                                f @ 0x3B ... 0x44 => { // F1 through F10
+                                    Some((f - 0x3A) as usize)
+                                },
+                                0x57 => { // F11
+                                    Some(11)
+                                },
+                                0x58 => { // F12
+                                    Some(12)
+                                },
+                                _ => None
+                            }
                         }
                     } else {
                         None
@@ -164,7 +530,7 @@ impl SchemeMut for DisplayScheme {
                 Ok(events.len() * mem::size_of::<Event>())
             }
         } else if let Some(mut screen) = self.screens.get_mut(&id) {
-            screen.write(buf, id == self.active)
+            screen.write(buf, id == self.active && self.splash.is_none())
         } else {
             Err(Error::new(EBADF))
         }
@@ -178,7 +544,8 @@ impl SchemeMut for DisplayScheme {
         }
     }
 
-    fn close(&mut self, _id: usize) -> Result<usize> {
+    fn close(&mut self, id: usize) -> Result<usize> {
+        self.handles.remove(&id);
         Ok(0)
     }
 }
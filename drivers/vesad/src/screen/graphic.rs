@@ -1,12 +1,13 @@
 use std::collections::VecDeque;
+use alloc::heap;
 use std::{cmp, mem, slice};
 
 use orbclient::{Event, EventOption};
 use syscall::error::*;
 use syscall::flag::{SEEK_SET, SEEK_CUR, SEEK_END};
 
-use display::Display;
-use primitive::fast_copy;
+use display::{Display, PixelFormat};
+use primitive::{fast_copy, fast_set64};
 use screen::Screen;
 
 pub struct GraphicScreen {
@@ -15,12 +16,20 @@ pub struct GraphicScreen {
     pub mouse_x: i32,
     pub mouse_y: i32,
     pub input: VecDeque<Event>,
-    pub requested: usize
+    pub requested: usize,
+    /// Addresses of the buffers, the second of which is zero until the screen is double buffered.
+    /// The offscreen buffer of the display is the one that is shown
+    pub pages: [usize; 2],
+    /// Buffer to show at the next vertical blank
+    pub pending: Option<usize>
 }
 
 impl GraphicScreen {
     pub fn new(display: Display) -> GraphicScreen {
+        let first = display.offscreen.as_ptr() as usize;
         GraphicScreen {
+            pages: [first, 0],
+            pending: None,
             display: display,
             seek: 0,
             mouse_x: 0,
@@ -46,8 +55,10 @@ impl Screen for GraphicScreen {
     }
 
     fn map(&self, offset: usize, size: usize) -> Result<usize> {
-        if offset + size <= self.display.offscreen.len() * 4 {
-            Ok(self.display.offscreen.as_ptr() as usize + offset)
+        let page_size = self.display.offscreen.len() * 4;
+        let page = offset / page_size;
+        if page < self.pages.len() && self.pages[page] != 0 && offset % page_size + size <= page_size {
+            Ok(self.pages[page] + offset % page_size)
         } else {
             Err(Error::new(EINVAL))
         }
@@ -92,9 +103,14 @@ impl Screen for GraphicScreen {
         if size > 0 {
             unsafe {
                 fast_copy(self.display.offscreen.as_mut_ptr().offset(self.seek as isize) as *mut u8, buf.as_ptr(), size * 4);
-                if sync {
-                    fast_copy(self.display.onscreen.as_mut_ptr().offset(self.seek as isize) as *mut u8, buf.as_ptr(), size * 4);
-                }
+            }
+
+            if sync {
+                // Onscreen rows may be padded, so the rows that were written are copied
+                let width = self.display.width;
+                let start_y = self.seek / width;
+                let end_y = (self.seek + size + width - 1) / width;
+                self.display.sync(0, start_y, width, end_y - start_y);
             }
         }
 
@@ -123,4 +139,51 @@ impl Screen for GraphicScreen {
         let height = self.display.height;
         self.display.sync(0, 0, width, height);
     }
+
+    fn double_buffer(&mut self) -> Result<()> {
+        if self.pages[1] == 0 {
+            let size = self.display.offscreen.len();
+            let page = unsafe { heap::allocate(size * 4, 4096) };
+            if page.is_null() {
+                return Err(Error::new(ENOMEM));
+            }
+            unsafe { fast_set64(page as *mut u64, 0, size/2) };
+            self.pages[1] = page as usize;
+        }
+        Ok(())
+    }
+
+    fn queue_flip(&mut self, page: usize) -> Result<()> {
+        if page < self.pages.len() && self.pages[page] != 0 {
+            self.pending = Some(page);
+            Ok(())
+        } else {
+            Err(Error::new(EINVAL))
+        }
+    }
+
+    fn flip_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    fn flip(&mut self, sync: bool) {
+        if let Some(page) = self.pending.take() {
+            let size = self.display.offscreen.len();
+            self.display.offscreen = unsafe { slice::from_raw_parts_mut(self.pages[page] as *mut u32, size) };
+            if sync {
+                self.redraw();
+            }
+        }
+    }
+
+    /// Clients have to open the screen again, and map the new buffer, to see the new size. The
+    /// screen is single buffered again
+    fn set_mode(&mut self, width: usize, height: usize, stride: usize, format: PixelFormat, onscreen: usize) {
+        self.display.set_mode(width, height, stride, format, onscreen);
+        self.pages = [self.display.offscreen.as_ptr() as usize, 0];
+        self.pending = None;
+        self.seek = 0;
+        self.mouse_x = cmp::min(self.mouse_x, width as i32);
+        self.mouse_y = cmp::min(self.mouse_y, height as i32);
+    }
 }
@@ -4,6 +4,8 @@ pub use self::text::TextScreen;
 use orbclient::Event;
 use syscall::Result;
 
+use display::PixelFormat;
+
 mod graphic;
 mod text;
 
@@ -29,4 +31,19 @@ pub trait Screen {
     fn sync(&mut self);
 
     fn redraw(&mut self);
+
+    /// Give the screen a second buffer, which is mapped after the first, for page flipping
+    fn double_buffer(&mut self) -> Result<()>;
+
+    /// Show buffer `page` at the next vertical blank
+    fn queue_flip(&mut self, page: usize) -> Result<()>;
+
+    /// Check if a flip is waiting for the vertical blank
+    fn flip_pending(&self) -> bool;
+
+    /// Show the buffer of a queued flip, copying it to the display if `sync` is set
+    fn flip(&mut self, sync: bool);
+
+    /// Follow a display mode switch, clearing the screen
+    fn set_mode(&mut self, width: usize, height: usize, stride: usize, format: PixelFormat, onscreen: usize);
 }
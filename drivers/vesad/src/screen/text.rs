@@ -1,11 +1,15 @@
 extern crate ransid;
 
+use std::{cmp, str};
 use std::collections::{BTreeSet, VecDeque};
+use std::fs::File;
+use std::io::Write;
 
 use orbclient::{Event, EventOption};
 use syscall::error::*;
 
-use display::Display;
+use display::{Display, PixelFormat};
+use font;
 use screen::Screen;
 
 pub struct TextScreen {
@@ -13,25 +17,143 @@ pub struct TextScreen {
     pub display: Display,
     pub changed: BTreeSet<usize>,
     pub ctrl: bool,
+    pub shift: bool,
+    /// Character of each cell, kept for copying text. The second cell of a wide character is '\0'
+    pub cells: Vec<char>,
+    /// Bytes of a UTF-8 sequence that was not finished by the last write
+    pub utf8: Vec<u8>,
+    /// Pointer position, in pixels
+    pub mouse_x: i32,
+    pub mouse_y: i32,
+    pub mouse_left: bool,
+    pub mouse_middle: bool,
+    /// Cells where the selection was started and where it ends now, which are highlighted
+    pub selection: Option<(usize, usize)>,
     pub input: VecDeque<u8>,
     pub end_of_input: bool,
     pub cooked: VecDeque<u8>,
-    pub requested: usize
+    pub requested: usize,
+    /// Output is copied here, with escape sequences preserved
+    pub mirror: Option<File>
 }
 
 impl TextScreen {
     pub fn new(display: Display) -> TextScreen {
+        let cols = display.width/display.char_width();
+        let rows = display.height/display.char_height();
         TextScreen {
-            console: ransid::Console::new(display.width/8, display.height/16),
+            console: ransid::Console::new(cols, rows),
+            cells: vec![' '; cols * rows],
+            utf8: Vec::new(),
             display: display,
             changed: BTreeSet::new(),
             ctrl: false,
+            shift: false,
+            mouse_x: 0,
+            mouse_y: 0,
+            mouse_left: false,
+            mouse_middle: false,
+            selection: None,
             input: VecDeque::new(),
             end_of_input: false,
             cooked: VecDeque::new(),
-            requested: 0
+            requested: 0,
+            mirror: None
         }
     }
+
+    /// The first and last cell of the selection
+    fn selection_range(&self) -> Option<(usize, usize)> {
+        self.selection.map(|(a, b)| (cmp::min(a, b), cmp::max(a, b)))
+    }
+
+    /// Toggle the highlight of the selection
+    fn invert_selection(&mut self) {
+        if let Some((start, end)) = self.selection_range() {
+            let w = self.console.w;
+            let (cw, ch) = (self.display.char_width(), self.display.char_height());
+            for i in start..end + 1 {
+                self.display.invert((i % w) * cw, (i / w) * ch, cw, ch);
+                self.changed.insert(i / w);
+            }
+        }
+    }
+
+    /// Remove the selection and its highlight
+    fn clear_selection(&mut self) {
+        self.invert_selection();
+        self.selection = None;
+    }
+
+    /// The selected text, with the trailing spaces of each row removed
+    fn selected_text(&self) -> Vec<u8> {
+        let mut text = Vec::new();
+        if let Some((start, end)) = self.selection_range() {
+            let w = self.console.w;
+            for y in start / w..end / w + 1 {
+                let row: String = self.cells[cmp::max(start, y * w)..cmp::min(end + 1, (y + 1) * w)].iter().cloned().filter(|&c| c != '\0').collect();
+                text.extend_from_slice(row.trim_right().as_bytes());
+                if y < end / w {
+                    text.push(b'\n');
+                }
+            }
+        }
+        text
+    }
+
+    /// Move the pointer, selecting from where the left button was pressed to the pointer
+    fn mouse(&mut self, dx: i32, dy: i32, left: bool) {
+        self.mouse_x = cmp::max(0, cmp::min(self.display.width as i32 - 1, self.mouse_x + dx));
+        self.mouse_y = cmp::max(0, cmp::min(self.display.height as i32 - 1, self.mouse_y + dy));
+
+        if left {
+            let w = self.console.w;
+            let x = cmp::min(self.mouse_x as usize / self.display.char_width(), w - 1);
+            let y = cmp::min(self.mouse_y as usize / self.display.char_height(), self.console.h - 1);
+            let cell = y * w + x;
+
+            let start = match self.selection {
+                Some((start, _)) if self.mouse_left => start,
+                _ => cell
+            };
+
+            self.invert_selection();
+            self.selection = Some((start, cell));
+            self.invert_selection();
+            self.sync();
+        }
+
+        self.mouse_left = left;
+    }
+
+    /// Move the cursor forward after each wide character, as the console gives every character
+    /// one cell, and the glyph is drawn over two
+    fn widen(&mut self, buf: &[u8]) -> Vec<u8> {
+        let mut output = Vec::with_capacity(buf.len());
+        for &b in buf.iter() {
+            output.push(b);
+
+            if b < 0x80 {
+                self.utf8.clear();
+                continue;
+            } else if b & 0xC0 != 0x80 {
+                // Start of a new sequence
+                self.utf8.clear();
+            }
+
+            self.utf8.push(b);
+            let c = str::from_utf8(&self.utf8).ok().and_then(|s| s.chars().next());
+            if let Some(c) = c {
+                if font::is_wide(c) {
+                    output.extend_from_slice(b"\x1B[C");
+                }
+                self.utf8.clear();
+            } else if self.utf8.len() >= 4 {
+                self.utf8.clear();
+            }
+        }
+        output
+    }
 }
 
 impl Screen for TextScreen {
@@ -59,6 +181,8 @@ impl Screen for TextScreen {
             EventOption::Key(key_event) => {
                 if key_event.scancode == 0x1D {
                     self.ctrl = key_event.pressed;
+                } else if key_event.scancode == 0x2A || key_event.scancode == 0x36 {
+                    self.shift = key_event.pressed;
                 } else if key_event.pressed {
                     match key_event.scancode {
                         0x47 => { // Home
@@ -85,8 +209,12 @@ impl Screen for TextScreen {
                         0x51 => { // Page down
                             buf.extend_from_slice(b"\x1B[6~");
                         },
-                        0x52 => { // Insert
-                            buf.extend_from_slice(b"\x1B[2~");
+                        0x52 => { // Insert, or paste the selection with shift
+                            if self.shift {
+                                buf.extend_from_slice(&self.selected_text());
+                            } else {
+                                buf.extend_from_slice(b"\x1B[2~");
+                            }
                         },
                         0x53 => { // Delete
                             buf.extend_from_slice(b"\x1B[3~");
@@ -105,7 +233,16 @@ impl Screen for TextScreen {
                     }
                 }
             },
-            _ => () //TODO: Mouse in terminal
+            EventOption::Mouse(mouse_event) => {
+                self.mouse(mouse_event.x, mouse_event.y, mouse_event.left_button);
+
+                // The middle button pastes the selection
+                if mouse_event.middle_button && ! self.mouse_middle {
+                    buf.extend_from_slice(&self.selected_text());
+                }
+                self.mouse_middle = mouse_event.middle_button;
+            },
+            _ => ()
         }
 
         if self.console.raw_mode {
@@ -160,33 +297,63 @@ impl Screen for TextScreen {
     }
 
     fn write(&mut self, buf: &[u8], sync: bool) -> Result<usize> {
+        if let Some(ref mut mirror) = self.mirror {
+            let _ = mirror.write(buf);
+        }
+
+        // The text under the selection may change
+        self.clear_selection();
+
         if self.console.cursor && self.console.x < self.console.w && self.console.y < self.console.h {
             let x = self.console.x;
             let y = self.console.y;
-            self.display.invert(x * 8, y * 16, 8, 16);
+            let (cw, ch) = (self.display.char_width(), self.display.char_height());
+            self.display.invert(x * cw, y * ch, cw, ch);
             self.changed.insert(y);
         }
 
+        let buf_len = buf.len();
+        let buf = self.widen(buf);
+
         {
             let display = &mut self.display;
             let changed = &mut self.changed;
-            self.console.write(buf, |event| {
+            let cells = &mut self.cells;
+            let cols = self.console.w;
+            let (cw, ch) = (display.char_width(), display.char_height());
+            self.console.write(&buf, |event| {
                 match event {
                     ransid::Event::Char { x, y, c, color, bold, .. } => {
-                        display.char(x * 8, y * 16, c, color.data, bold, false);
+                        display.char(x * cw, y * ch, c, color.data, bold, false);
                         changed.insert(y);
+                        if let Some(cell) = cells.get_mut(y * cols + x) {
+                            *cell = c;
+                        }
+                        if font::is_wide(c) && x + 1 < cols {
+                            if let Some(cell) = cells.get_mut(y * cols + x + 1) {
+                                *cell = '\0';
+                            }
+                        }
                     },
                     ransid::Event::Rect { x, y, w, h, color } => {
-                        display.rect(x * 8, y * 16, w * 8, h * 16, color.data);
+                        display.rect(x * cw, y * ch, w * cw, h * ch, color.data);
                         for y2 in y..y + h {
                             changed.insert(y2);
+                            for x2 in x..x + w {
+                                if let Some(cell) = cells.get_mut(y2 * cols + x2) {
+                                    *cell = ' ';
+                                }
+                            }
                         }
                     },
                     ransid::Event::Scroll { rows, color } => {
-                        display.scroll(rows * 16, color.data);
-                        for y in 0..display.height/16 {
+                        display.scroll(rows * ch, color.data);
+                        for y in 0..display.height/ch {
                             changed.insert(y);
                         }
+                        let scrolled = cmp::min(rows * cols, cells.len());
+                        cells.drain(..scrolled);
+                        cells.extend((0..scrolled).map(|_| ' '));
                     }
                 }
             });
@@ -195,7 +362,8 @@ impl Screen for TextScreen {
         if self.console.cursor && self.console.x < self.console.w && self.console.y < self.console.h {
             let x = self.console.x;
             let y = self.console.y;
-            self.display.invert(x * 8, y * 16, 8, 16);
+            let (cw, ch) = (self.display.char_width(), self.display.char_height());
+            self.display.invert(x * cw, y * ch, cw, ch);
             self.changed.insert(y);
         }
 
@@ -203,7 +371,7 @@ impl Screen for TextScreen {
             self.sync();
         }
 
-        Ok(buf.len())
+        Ok(buf_len)
     }
 
     fn seek(&mut self, _pos: usize, _whence: usize) -> Result<usize> {
@@ -212,8 +380,9 @@ impl Screen for TextScreen {
 
     fn sync(&mut self) {
         let width = self.display.width;
+        let ch = self.display.char_height();
         for change in self.changed.iter() {
-            self.display.sync(0, change * 16, width, 16);
+            self.display.sync(0, change * ch, width, ch);
         }
         self.changed.clear();
     }
@@ -224,4 +393,28 @@ impl Screen for TextScreen {
         self.display.sync(0, 0, width, height);
         self.changed.clear();
     }
+
+    fn double_buffer(&mut self) -> Result<()> {
+        Err(Error::new(EBADF))
+    }
+
+    fn queue_flip(&mut self, _page: usize) -> Result<()> {
+        Err(Error::new(EBADF))
+    }
+
+    fn flip_pending(&self) -> bool {
+        false
+    }
+
+    fn flip(&mut self, _sync: bool) {}
+
+    fn set_mode(&mut self, width: usize, height: usize, stride: usize, format: PixelFormat, onscreen: usize) {
+        self.display.set_mode(width, height, stride, format, onscreen);
+        let cols = width/self.display.char_width();
+        let rows = height/self.display.char_height();
+        self.console = ransid::Console::new(cols, rows);
+        self.cells = vec![' '; cols * rows];
+        self.selection = None;
+        self.changed.clear();
+    }
 }
@@ -0,0 +1,58 @@
+use display::Display;
+use image::Image;
+
+/// Size of the progress bar, and its gap below the logo, in pixels
+const PROGRESS_WIDTH: usize = 256;
+const PROGRESS_HEIGHT: usize = 8;
+const PROGRESS_GAP: usize = 32;
+
+const BACKGROUND: u32 = 0x000000;
+const PROGRESS_BORDER: u32 = 0x808080;
+const PROGRESS_FILL: u32 = 0xFFFFFF;
+
+/// A boot logo, shown in place of the screens until boot is done or a key is pressed, with a
+/// progress bar that drivers may fill during long operations
+pub struct Splash {
+    pub display: Display,
+    pub logo: Image,
+    /// Percent done, or None before any progress is reported
+    pub progress: Option<usize>
+}
+
+impl Splash {
+    pub fn new(display: Display, logo: Image) -> Splash {
+        Splash {
+            display: display,
+            logo: logo,
+            progress: None
+        }
+    }
+
+    /// Draw the logo centered, and the progress bar below it
+    pub fn draw(&mut self) {
+        let (width, height) = (self.display.width, self.display.height);
+        self.display.rect(0, 0, width, height, BACKGROUND);
+
+        let x = width.saturating_sub(self.logo.width) / 2;
+        let y = height.saturating_sub(self.logo.height) / 2;
+        self.display.blit(x, y, self.logo.width, self.logo.height, &self.logo.data);
+
+        let bar_x = width.saturating_sub(PROGRESS_WIDTH) / 2;
+        let bar_y = y + self.logo.height + PROGRESS_GAP;
+        if let (Some(progress), true) = (self.progress, bar_y + PROGRESS_HEIGHT <= height) {
+            let (left, top) = (bar_x as i32, bar_y as i32);
+            let (right, bottom) = (left + PROGRESS_WIDTH as i32 - 1, top + PROGRESS_HEIGHT as i32 - 1);
+            self.display.line(left, top, right, top, PROGRESS_BORDER);
+            self.display.line(left, bottom, right, bottom, PROGRESS_BORDER);
+            self.display.line(left, top, left, bottom, PROGRESS_BORDER);
+            self.display.line(right, top, right, bottom, PROGRESS_BORDER);
+
+            let filled = (PROGRESS_WIDTH - 4) * progress / 100;
+            if filled > 0 {
+                self.display.rect(bar_x + 2, bar_y + 2, filled, PROGRESS_HEIGHT - 4, PROGRESS_FILL);
+            }
+        }
+
+        self.display.sync(0, 0, width, height);
+    }
+}
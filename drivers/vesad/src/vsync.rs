@@ -0,0 +1,70 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use syscall;
+
+/// Input status register of VGA compatible adapters, including the Bochs graphics adapter
+const INPUT_STATUS_PORT: u16 = 0x3DA;
+/// Set during the vertical retrace
+const VERTICAL_RETRACE: u8 = 1 << 3;
+
+/// Time between vertical blanks when the adapter does not report them, which is 60 Hz
+const FALLBACK_NANOS: u32 = 16666667;
+
+/// Time slept after a retrace before polling for the next, which is short of the frame time of
+/// displays up to 75 Hz, so that the retrace is not polled for the whole frame
+const POLL_DELAY_NANOS: u32 = 12000000;
+
+unsafe fn inb(port: u16) -> u8 {
+    let value: u8;
+    asm!("in al, dx" : "={al}"(value) : "{dx}"(port) : : "intel", "volatile");
+    value
+}
+
+fn retrace() -> bool {
+    unsafe { inb(INPUT_STATUS_PORT) & VERTICAL_RETRACE == VERTICAL_RETRACE }
+}
+
+/// Wait for the next vertical retrace to start, returning false if none started within `timeout`.
+/// The retrace is short, so it is polled without sleeping
+fn wait_retrace(timeout: Duration) -> bool {
+    let start = Instant::now();
+    let mut last = retrace();
+    while start.elapsed() < timeout {
+        let now = retrace();
+        if now && ! last {
+            return true;
+        }
+        last = now;
+        thread::yield_now();
+    }
+    false
+}
+
+/// Report each vertical blank to `display:vsync`, from the retrace bit of the VGA input status
+/// register if it can be used and toggles, or from a 60 Hz timer otherwise
+pub fn vsync() -> ! {
+    let mut display = OpenOptions::new().write(true).open("display:vsync").expect("vesad: failed to open display:vsync");
+
+    let mut hardware = {
+        let path = format!("sys:{}/ports", syscall::getpid().expect("vesad: failed to get PID"));
+        OpenOptions::new().write(true).open(&path).and_then(|mut ports| ports.write(b"3DA-3DA\n")).is_ok()
+            && wait_retrace(Duration::from_millis(100))
+    };
+
+    loop {
+        if hardware {
+            thread::sleep(Duration::new(0, POLL_DELAY_NANOS));
+            if ! wait_retrace(Duration::from_millis(100)) {
+                // The adapter stopped reporting them, such as when a display driver took over
+                hardware = false;
+            }
+        } else {
+            thread::sleep(Duration::new(0, FALLBACK_NANOS));
+        }
+
+        display.write(b"vblank").expect("vesad: failed to write display:vsync");
+    }
+}
@@ -0,0 +1,207 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::Read;
+
+use syscall::error::Result;
+use virtio::{Queue, Transport, BUFFER_SIZE, ISR_CONFIG, ISR_QUEUE, STATUS_DRIVER_OK};
+
+/// Statistics queue
+const F_STATS_VQ: u32 = 1 << 1;
+
+/// Device configuration offsets
+const CONFIG_NUM_PAGES: u16 = 0;
+const CONFIG_ACTUAL: u16 = 4;
+
+const QUEUE_INFLATE: u16 = 0;
+const QUEUE_DEFLATE: u16 = 1;
+const QUEUE_STATS: u16 = 2;
+
+/// Statistics tags
+const STAT_MEMFREE: u16 = 4;
+const STAT_MEMTOT: u16 = 5;
+
+/// Size of a balloon page
+const PAGE_SIZE: usize = 4096;
+
+/// Pages reported in each buffer, as 32-bit page numbers
+const BATCH: usize = BUFFER_SIZE / 4;
+
+pub struct Balloon {
+    transport: Transport,
+    inflate: Queue,
+    deflate: Queue,
+    stats: Option<Queue>,
+    /// Physical addresses of the pages in the balloon, including those not yet acknowledged
+    pages: Vec<usize>,
+    /// Sizes of the batches being inflated
+    inflating: VecDeque<usize>,
+    /// Batches being deflated, freed when the device acknowledges them
+    deflating: VecDeque<Vec<usize>>,
+    /// Set when allocation fails, until the target changes
+    exhausted: bool
+}
+
+impl Balloon {
+    pub fn new(transport: Transport) -> Result<Balloon> {
+        transport.reset();
+
+        let features = transport.features(F_STATS_VQ);
+
+        let inflate = transport.queue(QUEUE_INFLATE)?;
+        let deflate = transport.queue(QUEUE_DEFLATE)?;
+        let stats = if features & F_STATS_VQ == F_STATS_VQ {
+            Some(transport.queue(QUEUE_STATS)?)
+        } else {
+            None
+        };
+
+        transport.status(STATUS_DRIVER_OK);
+
+        let mut balloon = Balloon {
+            transport: transport,
+            inflate: inflate,
+            deflate: deflate,
+            stats: stats,
+            pages: Vec::new(),
+            inflating: VecDeque::new(),
+            deflating: VecDeque::new(),
+            exhausted: false
+        };
+
+        // The device asks for statistics by returning this buffer
+        balloon.send_stats();
+        balloon.update();
+
+        Ok(balloon)
+    }
+
+    fn target(&self) -> usize {
+        self.transport.config32(CONFIG_NUM_PAGES) as usize
+    }
+
+    /// Pages the device has been told about
+    fn actual(&self) -> usize {
+        let inflating = self.inflating.iter().fold(0, |sum, count| sum + count);
+        let deflating = self.deflating.iter().fold(0, |sum, batch| sum + batch.len());
+        self.pages.len() - inflating + deflating
+    }
+
+    fn send_stats(&mut self) {
+        if let Some(ref mut stats) = self.stats {
+            let mut used = 0;
+            let mut free = 0;
+
+            let mut string = String::new();
+            if let Ok(mut file) = File::open("sys:memory") {
+                let _ = file.read_to_string(&mut string);
+            }
+            for line in string.lines() {
+                let mut parts = line.split(':');
+                let name = parts.next().unwrap_or("").trim();
+                let kb = parts.next().unwrap_or("").trim().trim_right_matches("KB").trim().parse::<u64>().unwrap_or(0);
+                match name {
+                    "Memory Used" => used = kb * 1024,
+                    "Memory Free" => free = kb * 1024,
+                    _ => ()
+                }
+            }
+
+            let mut data = Vec::new();
+            for &(tag, value) in [(STAT_MEMFREE, free), (STAT_MEMTOT, used + free)].iter() {
+                data.push(tag as u8);
+                data.push((tag >> 8) as u8);
+                for i in 0..8 {
+                    data.push((value >> (i * 8)) as u8);
+                }
+            }
+
+            if stats.send(&data).is_some() {
+                self.transport.notify(stats);
+            }
+        }
+    }
+
+    fn send_pages(transport: &Transport, queue: &mut Queue, pages: &[usize]) {
+        let mut data = Vec::with_capacity(pages.len() * 4);
+        for &page in pages.iter() {
+            let pfn = (page / PAGE_SIZE) as u32;
+            for i in 0..4 {
+                data.push((pfn >> (i * 8)) as u8);
+            }
+        }
+
+        queue.send(&data).expect("virtballoond: no buffer for pages");
+        transport.notify(queue);
+    }
+
+    /// Handle an IRQ, returns true if it was from this device
+    pub fn irq(&mut self) -> bool {
+        let isr = self.transport.isr();
+        if isr & ISR_CONFIG == ISR_CONFIG {
+            self.exhausted = false;
+        }
+        if isr & (ISR_QUEUE | ISR_CONFIG) != 0 {
+            self.update();
+        }
+        isr != 0
+    }
+
+    /// Process acknowledged buffers, then move towards the target size
+    pub fn update(&mut self) {
+        while let Some(_) = self.inflate.pop() {
+            self.inflating.pop_front();
+        }
+
+        while let Some(_) = self.deflate.pop() {
+            if let Some(batch) = self.deflating.pop_front() {
+                for &page in batch.iter() {
+                    let _ = unsafe { syscall::physfree(page, PAGE_SIZE) };
+                }
+            }
+        }
+
+        let mut stats_requested = false;
+        if let Some(ref mut stats) = self.stats {
+            while let Some(_) = stats.pop() {
+                stats_requested = true;
+            }
+        }
+        if stats_requested {
+            self.send_stats();
+        }
+
+        // One batch is sent at a time, so a change in the target is seen quickly
+        let target = self.target();
+        if self.inflating.is_empty() && self.deflating.is_empty() {
+            if self.pages.len() < target && ! self.exhausted {
+                let mut batch = Vec::new();
+                while batch.len() < cmp::min(BATCH, target - self.pages.len()) {
+                    match unsafe { syscall::physalloc(PAGE_SIZE) } {
+                        Ok(page) => batch.push(page),
+                        Err(_) => {
+                            println!("virtballoond: out of memory, balloon has {} of {} pages", self.pages.len() + batch.len(), target);
+                            self.exhausted = true;
+                            break;
+                        }
+                    }
+                }
+
+                if ! batch.is_empty() {
+                    Balloon::send_pages(&self.transport, &mut self.inflate, &batch);
+                    self.inflating.push_back(batch.len());
+                    self.pages.extend_from_slice(&batch);
+                }
+            } else if self.pages.len() > target {
+                let count = cmp::min(BATCH, self.pages.len() - target);
+                let start = self.pages.len() - count;
+                let batch = self.pages.split_off(start);
+
+                Balloon::send_pages(&self.transport, &mut self.deflate, &batch);
+                self.deflating.push_back(batch);
+            }
+        }
+
+        self.transport.set_config32(CONFIG_ACTUAL, self.actual() as u32);
+    }
+}
@@ -0,0 +1,47 @@
+extern crate syscall;
+extern crate virtio;
+
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::io::FromRawFd;
+
+use virtio::Transport;
+
+pub mod device;
+
+fn main() {
+    let mut args = env::args().skip(1);
+
+    let bar_str = args.next().expect("virtballoond: no address provided");
+    let bar = u16::from_str_radix(&bar_str, 16).expect("virtballoond: failed to parse address");
+
+    let irq_str = args.next().expect("virtballoond: no irq provided");
+    let irq = irq_str.parse::<u8>().expect("virtballoond: failed to parse irq");
+
+    print!("{}", format!(" + Virtio balloon on: {:X}, IRQ: {}\n", bar, irq));
+
+    {
+        // Legacy virtio registers
+        let path = format!("sys:{}/ports", syscall::getpid().unwrap());
+        let mut ports = OpenOptions::new().write(true).open(&path).expect("virtballoond: failed to open ports");
+        ports.write(format!("{:X}-{:X}\n", bar, bar + virtio::PORTS - 1).as_bytes()).expect("virtballoond: failed to allow ports");
+    }
+
+    // Daemonize
+    if unsafe { syscall::clone(0).unwrap() } == 0 {
+        let mut balloon = device::Balloon::new(Transport::new(bar)).expect("virtballoond: failed to allocate device");
+
+        let irq_fd = syscall::open(&format!("irq:{}", irq), syscall::O_RDWR).expect("virtballoond: failed to open IRQ file");
+        let mut irq_file = unsafe { File::from_raw_fd(irq_fd) };
+        loop {
+            // Blocks until the IRQ fires
+            let mut irq = [0; 8];
+            if irq_file.read(&mut irq).expect("virtballoond: failed to read IRQ file") > 0 {
+                balloon.irq();
+                // The line stays masked until it is acknowledged, even if another device sharing it raised the IRQ
+                irq_file.write(&irq).expect("virtballoond: failed to acknowledge IRQ");
+            }
+        }
+    }
+}
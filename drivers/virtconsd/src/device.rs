@@ -0,0 +1,463 @@
+use std::{cmp, str};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, VecDeque};
+
+use syscall::error::{Error, EACCES, EBADF, EINVAL, ENOENT, EWOULDBLOCK, Result};
+use syscall::flag::O_NONBLOCK;
+use syscall::scheme::Scheme;
+use virtio::{Queue, Transport, ISR_QUEUE, STATUS_DRIVER_OK};
+
+/// Size of the console, in the device configuration
+const F_SIZE: u32 = 1;
+/// Multiple ports and control queues
+const F_MULTIPORT: u32 = 1 << 1;
+
+/// Device configuration offsets
+const CONFIG_COLS: u16 = 0;
+const CONFIG_ROWS: u16 = 2;
+const CONFIG_MAX_PORTS: u16 = 4;
+
+/// Queues of the control port, used with multiport
+const CONTROL_RECEIVE: u16 = 2;
+const CONTROL_TRANSMIT: u16 = 3;
+
+/// Control events
+const DEVICE_READY: u16 = 0;
+const DEVICE_ADD: u16 = 1;
+const DEVICE_REMOVE: u16 = 2;
+const PORT_READY: u16 = 3;
+const CONSOLE_PORT: u16 = 4;
+const RESIZE: u16 = 5;
+const PORT_OPEN: u16 = 6;
+const PORT_NAME: u16 = 7;
+
+/// Attempts to move received data into the input of a port per IRQ, each handles one buffer
+const RECEIVE_BUDGET: usize = 64;
+
+/// Path prefix of ports, `serial:virtio/N`
+const PATH: &'static str = "virtio";
+
+struct Port {
+    receive: Queue,
+    transmit: Queue,
+    input: VecDeque<u8>,
+    name: Option<String>,
+    console: bool,
+    /// Number of open handles
+    users: usize
+}
+
+enum Handle {
+    /// Listing of ports
+    List {
+        data: Vec<u8>,
+        seek: usize
+    },
+    Port {
+        port: u32,
+        flags: usize
+    }
+}
+
+/// A virtio console, each port is `serial:virtio/N`
+pub struct VirtioConsole {
+    transport: Transport,
+    multiport: bool,
+    /// Receive and transmit queues of the control port
+    control: RefCell<Option<(Queue, Queue)>>,
+    ports: RefCell<BTreeMap<u32, Port>>,
+    handles: RefCell<BTreeMap<usize, Handle>>,
+    next_id: Cell<usize>
+}
+
+impl VirtioConsole {
+    pub fn new(transport: Transport) -> Result<VirtioConsole> {
+        transport.reset();
+
+        let features = transport.features(F_SIZE | F_MULTIPORT);
+        let multiport = features & F_MULTIPORT == F_MULTIPORT;
+
+        if features & F_SIZE == F_SIZE {
+            print!("{}", format!("   - Size: {}x{}\n", transport.config16(CONFIG_COLS), transport.config16(CONFIG_ROWS)));
+        }
+
+        let control = if multiport {
+            print!("{}", format!("   - Ports: {}\n", transport.config32(CONFIG_MAX_PORTS)));
+
+            let mut receive = transport.queue(CONTROL_RECEIVE)?;
+            let transmit = transport.queue(CONTROL_TRANSMIT)?;
+            receive.fill();
+            Some((receive, transmit))
+        } else {
+            None
+        };
+
+        let console = VirtioConsole {
+            transport: transport,
+            multiport: multiport,
+            control: RefCell::new(control),
+            ports: RefCell::new(BTreeMap::new()),
+            handles: RefCell::new(BTreeMap::new()),
+            next_id: Cell::new(0)
+        };
+
+        console.transport.status(STATUS_DRIVER_OK);
+
+        if multiport {
+            if let Some((ref receive, _)) = *console.control.borrow() {
+                console.transport.notify(receive);
+            }
+            // Ports are added by the device once it knows the driver is ready
+            console.control_send(0, DEVICE_READY, 1);
+        } else {
+            // Without multiport, there is only the console port
+            console.add_port(0)?;
+            if let Some(port) = console.ports.borrow_mut().get_mut(&0) {
+                port.console = true;
+            }
+        }
+
+        Ok(console)
+    }
+
+    /// The queues of a port, port 0 uses the first queues and the control queues come before port 1
+    fn queue_indexes(port: u32) -> (u16, u16) {
+        if port == 0 {
+            (0, 1)
+        } else {
+            (port as u16 * 2 + 2, port as u16 * 2 + 3)
+        }
+    }
+
+    fn add_port(&self, id: u32) -> Result<()> {
+        if self.ports.borrow().contains_key(&id) {
+            return Ok(());
+        }
+
+        // Legacy devices accept queue addresses after the driver is ready, so queues are set up as ports are added
+        let (receive_index, transmit_index) = VirtioConsole::queue_indexes(id);
+        let mut receive = self.transport.queue(receive_index)?;
+        let transmit = self.transport.queue(transmit_index)?;
+        receive.fill();
+        self.transport.notify(&receive);
+
+        self.ports.borrow_mut().insert(id, Port {
+            receive: receive,
+            transmit: transmit,
+            input: VecDeque::new(),
+            name: None,
+            console: false,
+            users: 0
+        });
+
+        Ok(())
+    }
+
+    fn remove_port(&self, id: u32) {
+        if let Some(port) = self.ports.borrow_mut().remove(&id) {
+            self.transport.remove_queue(&port.receive);
+            self.transport.remove_queue(&port.transmit);
+        }
+    }
+
+    /// Send a control message, ignored without multiport
+    fn control_send(&self, id: u32, event: u16, value: u16) {
+        if let Some((_, ref mut transmit)) = *self.control.borrow_mut() {
+            while let Some(_) = transmit.pop() {}
+
+            let mut message = [0; 8];
+            for i in 0..4 {
+                message[i] = (id >> (i * 8)) as u8;
+            }
+            message[4] = event as u8;
+            message[5] = (event >> 8) as u8;
+            message[6] = value as u8;
+            message[7] = (value >> 8) as u8;
+
+            if transmit.send(&message).is_some() {
+                self.transport.notify(transmit);
+            } else {
+                println!("virtconsd: control queue full, dropped event {} for port {}", event, id);
+            }
+        }
+    }
+
+    fn control_receive(&self, message: &[u8]) {
+        if message.len() < 8 {
+            return;
+        }
+
+        let id = message[0] as u32 | (message[1] as u32) << 8 | (message[2] as u32) << 16 | (message[3] as u32) << 24;
+        let event = message[4] as u16 | (message[5] as u16) << 8;
+        let value = message[6] as u16 | (message[7] as u16) << 8;
+
+        match event {
+            DEVICE_ADD => match self.add_port(id) {
+                Ok(()) => self.control_send(id, PORT_READY, 1),
+                Err(err) => {
+                    println!("virtconsd: failed to add port {}: {}", id, err);
+                    self.control_send(id, PORT_READY, 0);
+                }
+            },
+            DEVICE_REMOVE => self.remove_port(id),
+            CONSOLE_PORT => {
+                if let Some(port) = self.ports.borrow_mut().get_mut(&id) {
+                    port.console = true;
+                }
+                self.control_send(id, PORT_OPEN, 1);
+            },
+            RESIZE => if message.len() >= 12 {
+                let cols = message[8] as u16 | (message[9] as u16) << 8;
+                let rows = message[10] as u16 | (message[11] as u16) << 8;
+                println!("virtconsd: port {} resized to {}x{}", id, cols, rows);
+            },
+            PORT_OPEN => (),
+            PORT_NAME => if let Some(port) = self.ports.borrow_mut().get_mut(&id) {
+                port.name = str::from_utf8(&message[8..]).ok().map(|name| name.trim_right_matches('\0').to_string());
+            },
+            _ => println!("virtconsd: unknown control event {} for port {}, value {}", event, id, value)
+        }
+    }
+
+    /// Handle an IRQ, returns true if it was from this device
+    pub fn irq(&self) -> bool {
+        let isr = self.transport.isr();
+        if isr & ISR_QUEUE == ISR_QUEUE {
+            self.poll();
+        }
+        isr != 0
+    }
+
+    /// Process control messages and received data
+    pub fn poll(&self) {
+        let mut messages = Vec::new();
+        if let Some((ref mut receive, _)) = *self.control.borrow_mut() {
+            while let Some(message) = receive.pop() {
+                messages.push(message);
+            }
+            if receive.fill() {
+                self.transport.notify(receive);
+            }
+        }
+        for message in messages.iter() {
+            self.control_receive(message);
+        }
+
+        for (_id, port) in self.ports.borrow_mut().iter_mut() {
+            let mut budget = RECEIVE_BUDGET;
+            while budget > 0 {
+                match port.receive.pop() {
+                    Some(data) => port.input.extend(data.iter()),
+                    None => break
+                }
+                budget -= 1;
+            }
+            if port.receive.fill() {
+                self.transport.notify(&port.receive);
+            }
+
+            while let Some(_) = port.transmit.pop() {}
+        }
+    }
+
+    /// Handles with input available, and the amount available
+    pub fn events(&self) -> Vec<(usize, usize)> {
+        let mut events = Vec::new();
+        let ports = self.ports.borrow();
+        for (id, handle) in self.handles.borrow().iter() {
+            if let Handle::Port { port, .. } = *handle {
+                if let Some(port) = ports.get(&port) {
+                    if ! port.input.is_empty() {
+                        events.push((*id, port.input.len()));
+                    }
+                }
+            }
+        }
+        events
+    }
+
+    fn list(&self) -> Vec<u8> {
+        let mut string = String::new();
+        for (id, port) in self.ports.borrow().iter() {
+            string.push_str(&format!("{}", id));
+            if port.console {
+                string.push_str(" console");
+            }
+            if let Some(ref name) = port.name {
+                string.push(' ');
+                string.push_str(name);
+            }
+            string.push('\n');
+        }
+        string.into_bytes()
+    }
+}
+
+impl Scheme for VirtioConsole {
+    fn open(&self, path: &[u8], flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        if uid != 0 {
+            return Err(Error::new(EACCES));
+        }
+
+        let path_str = str::from_utf8(path).or(Err(Error::new(ENOENT)))?.trim_matches('/');
+        let mut parts = path_str.splitn(2, '/');
+        if parts.next() != Some(PATH) {
+            return Err(Error::new(ENOENT));
+        }
+
+        let handle = match parts.next() {
+            None | Some("") => Handle::List {
+                data: self.list(),
+                seek: 0
+            },
+            Some(port_str) => {
+                let id = port_str.parse::<u32>().or(Err(Error::new(ENOENT)))?;
+                let first = {
+                    let mut ports = self.ports.borrow_mut();
+                    let port = ports.get_mut(&id).ok_or(Error::new(ENOENT))?;
+                    port.users += 1;
+                    port.users == 1 && ! port.console
+                };
+                if first && self.multiport {
+                    self.control_send(id, PORT_OPEN, 1);
+                }
+                Handle::Port {
+                    port: id,
+                    flags: flags
+                }
+            }
+        };
+
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+        self.handles.borrow_mut().insert(id, handle);
+
+        Ok(id)
+    }
+
+    fn dup(&self, file: usize, _buf: &[u8]) -> Result<usize> {
+        let handle = match *self.handles.borrow().get(&file).ok_or(Error::new(EBADF))? {
+            Handle::List { ref data, seek } => Handle::List {
+                data: data.clone(),
+                seek: seek
+            },
+            Handle::Port { port, flags } => {
+                if let Some(port) = self.ports.borrow_mut().get_mut(&port) {
+                    port.users += 1;
+                }
+                Handle::Port {
+                    port: port,
+                    flags: flags
+                }
+            }
+        };
+
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+        self.handles.borrow_mut().insert(id, handle);
+
+        Ok(id)
+    }
+
+    fn read(&self, file: usize, buf: &mut [u8]) -> Result<usize> {
+        let mut handles = self.handles.borrow_mut();
+        match *handles.get_mut(&file).ok_or(Error::new(EBADF))? {
+            Handle::List { ref data, ref mut seek } => {
+                let count = cmp::min(buf.len(), data.len() - *seek);
+                buf[.. count].copy_from_slice(&data[*seek .. *seek + count]);
+                *seek += count;
+                Ok(count)
+            },
+            Handle::Port { port, flags } => {
+                let mut ports = self.ports.borrow_mut();
+                let port = ports.get_mut(&port).ok_or(Error::new(EBADF))?;
+
+                let mut i = 0;
+                while i < buf.len() {
+                    match port.input.pop_front() {
+                        Some(b) => buf[i] = b,
+                        None => break
+                    }
+                    i += 1;
+                }
+
+                if i > 0 || flags & O_NONBLOCK == O_NONBLOCK {
+                    Ok(i)
+                } else {
+                    Err(Error::new(EWOULDBLOCK))
+                }
+            }
+        }
+    }
+
+    fn write(&self, file: usize, buf: &[u8]) -> Result<usize> {
+        let (port, flags) = match *self.handles.borrow().get(&file).ok_or(Error::new(EBADF))? {
+            Handle::List { .. } => return Err(Error::new(EBADF)),
+            Handle::Port { port, flags } => (port, flags)
+        };
+
+        let mut ports = self.ports.borrow_mut();
+        let port = ports.get_mut(&port).ok_or(Error::new(EBADF))?;
+
+        while let Some(_) = port.transmit.pop() {}
+
+        let mut i = 0;
+        while i < buf.len() {
+            match port.transmit.send(&buf[i..]) {
+                Some(count) => i += count,
+                None => break
+            }
+        }
+
+        if i > 0 {
+            self.transport.notify(&port.transmit);
+            Ok(i)
+        } else if buf.is_empty() || flags & O_NONBLOCK == O_NONBLOCK {
+            Ok(0)
+        } else {
+            Err(Error::new(EWOULDBLOCK))
+        }
+    }
+
+    fn fevent(&self, file: usize, _flags: usize) -> Result<usize> {
+        // Events are triggered with the handle as the ID
+        match *self.handles.borrow().get(&file).ok_or(Error::new(EBADF))? {
+            Handle::List { .. } => Err(Error::new(EINVAL)),
+            Handle::Port { .. } => Ok(file)
+        }
+    }
+
+    fn fpath(&self, file: usize, buf: &mut [u8]) -> Result<usize> {
+        let path = match *self.handles.borrow().get(&file).ok_or(Error::new(EBADF))? {
+            Handle::List { .. } => format!("serial:{}", PATH),
+            Handle::Port { port, .. } => format!("serial:{}/{}", PATH, port)
+        };
+
+        let count = cmp::min(buf.len(), path.len());
+        buf[.. count].copy_from_slice(&path.as_bytes()[.. count]);
+        Ok(count)
+    }
+
+    fn fsync(&self, file: usize) -> Result<usize> {
+        self.handles.borrow().get(&file).ok_or(Error::new(EBADF)).and(Ok(0))
+    }
+
+    fn close(&self, file: usize) -> Result<usize> {
+        let handle = self.handles.borrow_mut().remove(&file).ok_or(Error::new(EBADF))?;
+
+        if let Handle::Port { port, .. } = handle {
+            let last = match self.ports.borrow_mut().get_mut(&port) {
+                Some(port) => {
+                    port.users -= 1;
+                    port.users == 0 && ! port.console
+                },
+                None => false
+            };
+            if last && self.multiport {
+                self.control_send(port, PORT_OPEN, 0);
+            }
+        }
+
+        Ok(0)
+    }
+}
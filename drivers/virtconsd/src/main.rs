@@ -0,0 +1,135 @@
+extern crate event;
+extern crate syscall;
+extern crate virtio;
+
+use std::cell::RefCell;
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write, Result};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::Arc;
+
+use event::EventQueue;
+use syscall::{Packet, Scheme};
+use syscall::error::EWOULDBLOCK;
+use virtio::Transport;
+
+pub mod device;
+
+/// Send events for handles with input available
+fn send_events(device: &device::VirtioConsole, socket: &mut File) -> Result<()> {
+    for (id, count) in device.events() {
+        socket.write(&Packet {
+            id: 0,
+            pid: 0,
+            uid: 0,
+            gid: 0,
+            a: syscall::number::SYS_FEVENT,
+            b: id,
+            c: syscall::flag::EVENT_READ,
+            d: count
+        })?;
+    }
+    Ok(())
+}
+
+/// Retry requests that would have blocked, returning the ones that still would
+fn retry(device: &device::VirtioConsole, socket: &mut File, todo: &mut Vec<Packet>) -> Result<()> {
+    let mut i = 0;
+    while i < todo.len() {
+        let a = todo[i].a;
+        device.handle(&mut todo[i]);
+        if todo[i].a == (-EWOULDBLOCK) as usize {
+            todo[i].a = a;
+            i += 1;
+        } else {
+            socket.write(&mut todo[i])?;
+            todo.remove(i);
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+
+    let bar_str = args.next().expect("virtconsd: no address provided");
+    let bar = u16::from_str_radix(&bar_str, 16).expect("virtconsd: failed to parse address");
+
+    let irq_str = args.next().expect("virtconsd: no irq provided");
+    let irq = irq_str.parse::<u8>().expect("virtconsd: failed to parse irq");
+
+    print!("{}", format!(" + Virtio console on: {:X}, IRQ: {}\n", bar, irq));
+
+    {
+        // Legacy virtio registers
+        let path = format!("sys:{}/ports", syscall::getpid().unwrap());
+        let mut ports = OpenOptions::new().write(true).open(&path).expect("virtconsd: failed to open ports");
+        ports.write(format!("{:X}-{:X}\n", bar, bar + virtio::PORTS - 1).as_bytes()).expect("virtconsd: failed to allow ports");
+    }
+
+    // Daemonize
+    if unsafe { syscall::clone(0).unwrap() } == 0 {
+        let socket_fd = syscall::open(":serial", syscall::O_RDWR | syscall::O_CREAT | syscall::O_NONBLOCK).expect("virtconsd: failed to create serial scheme");
+        let socket = Arc::new(RefCell::new(unsafe { File::from_raw_fd(socket_fd) }));
+
+        let device = Arc::new(device::VirtioConsole::new(Transport::new(bar)).expect("virtconsd: failed to allocate device"));
+
+        let mut event_queue = EventQueue::<()>::new().expect("virtconsd: failed to create event queue");
+
+        let todo = Arc::new(RefCell::new(Vec::<Packet>::new()));
+
+        let device_irq = device.clone();
+        let socket_irq = socket.clone();
+        let todo_irq = todo.clone();
+        let irq_fd = syscall::open(&format!("irq:{}", irq), syscall::O_RDWR | syscall::O_NONBLOCK).expect("virtconsd: failed to open IRQ file");
+        let mut irq_file = unsafe { File::from_raw_fd(irq_fd) };
+        event_queue.add(irq_file.as_raw_fd(), move |_count: usize| -> Result<Option<()>> {
+            let mut irq = [0; 8];
+            if irq_file.read(&mut irq)? > 0 {
+                let claimed = device_irq.irq();
+                // Acknowledged first, as other devices may share the line
+                irq_file.write(&mut irq)?;
+                if ! claimed {
+                    return Ok(None);
+                }
+
+                let mut socket = socket_irq.borrow_mut();
+                retry(&device_irq, &mut socket, &mut todo_irq.borrow_mut())?;
+                send_events(&device_irq, &mut socket)?;
+            }
+            Ok(None)
+        }).expect("virtconsd: failed to catch events on IRQ file");
+
+        let socket_packet = socket.clone();
+        event_queue.add(socket_fd, move |_count: usize| -> Result<Option<()>> {
+            let mut socket = socket_packet.borrow_mut();
+            loop {
+                let mut packet = Packet::default();
+                if socket.read(&mut packet)? == 0 {
+                    break;
+                }
+
+                let a = packet.a;
+                device.handle(&mut packet);
+                if packet.a == (-EWOULDBLOCK) as usize {
+                    packet.a = a;
+                    todo.borrow_mut().push(packet);
+                } else {
+                    socket.write(&mut packet)?;
+                }
+            }
+
+            // Input may already be waiting for a handle that just registered for events
+            send_events(&device, &mut socket)?;
+
+            Ok(None)
+        }).expect("virtconsd: failed to catch events on scheme");
+
+        event_queue.trigger_all(0).expect("virtconsd: failed to trigger events");
+
+        loop {
+            event_queue.run().expect("virtconsd: failed to handle events");
+        }
+    }
+}
@@ -0,0 +1,131 @@
+//! # Benchmarks
+//! Measures memory bandwidth, frame allocation, context switches and syscalls, so that changes to
+//! paging and scheduling can be compared. Results are printed one per line, as
+//! `BENCH <name> <value> <unit>`, so that they can be collected from the serial console
+
+use collections::Vec;
+use core::{cmp, intrinsics};
+use spin::{Mutex, Once};
+
+use arch;
+use arch::device::pvclock::rdtsc;
+use arch::memory::{allocate_frame, deallocate_frames};
+use context;
+use syscall::error::{Error, ENOMEM, Result};
+use syscall::number::SYS_GETPID;
+
+/// Size of the buffers used to measure memory bandwidth
+const BENCH_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+
+/// Repetitions of each benchmark
+const BENCH_MEMORY_ROUNDS: usize = 64;
+const BENCH_FRAME_ROUNDS: usize = 4096;
+const BENCH_SWITCH_ROUNDS: usize = 4096;
+const BENCH_SYSCALL_ROUNDS: usize = 65536;
+
+/// Results of the last run
+static RESULTS: Once<Mutex<Vec<u8>>> = Once::new();
+
+/// Initialize results, called if needed
+fn init_results() -> Mutex<Vec<u8>> {
+    Mutex::new(Vec::new())
+}
+
+/// Time in nanoseconds, and cycles, since the start of a benchmark
+struct Timer {
+    time: u64,
+    cycles: u64
+}
+
+impl Timer {
+    fn start() -> Timer {
+        let time = arch::time::monotonic();
+        Timer {
+            time: time.0 * 1000000000 + time.1,
+            cycles: rdtsc()
+        }
+    }
+
+    fn stop(&self) -> (u64, u64) {
+        let time = arch::time::monotonic();
+        let cycles = rdtsc();
+        ((time.0 * 1000000000 + time.1).saturating_sub(self.time), cycles.wrapping_sub(self.cycles))
+    }
+}
+
+fn report(results: &mut Vec<u8>, name: &str, value: u64, unit: &str) {
+    let line = format!("BENCH {} {} {}\n", name, value, unit);
+    print!("{}", line);
+    results.extend_from_slice(line.as_bytes());
+}
+
+/// Report bandwidth in MB/s, and cycles for each round
+fn report_bandwidth(results: &mut Vec<u8>, name: &str, (time, cycles): (u64, u64)) {
+    let bytes = (BENCH_BUFFER_SIZE * BENCH_MEMORY_ROUNDS) as u64;
+    report(results, name, bytes * 1000 / cmp::max(time, 1), "MB/s");
+    report(results, &format!("{}_cycles", name), cycles / BENCH_MEMORY_ROUNDS as u64, "cycles");
+}
+
+/// Report the time and cycles taken by one of `rounds`
+fn report_latency(results: &mut Vec<u8>, name: &str, (time, cycles): (u64, u64), rounds: usize) {
+    report(results, name, time / rounds as u64, "ns");
+    report(results, &format!("{}_cycles", name), cycles / rounds as u64, "cycles");
+}
+
+/// Run the benchmarks, printing each result as it is measured
+pub fn run() -> Result<()> {
+    let mut results = Vec::new();
+
+    {
+        let mut src = vec![1u8; BENCH_BUFFER_SIZE];
+        let mut dst = vec![0u8; BENCH_BUFFER_SIZE];
+
+        let timer = Timer::start();
+        for i in 0..BENCH_MEMORY_ROUNDS {
+            unsafe { intrinsics::volatile_set_memory(dst.as_mut_ptr(), i as u8, dst.len()); }
+        }
+        report_bandwidth(&mut results, "memset", timer.stop());
+
+        let timer = Timer::start();
+        for i in 0..BENCH_MEMORY_ROUNDS {
+            src[0] = i as u8;
+            unsafe { intrinsics::volatile_copy_nonoverlapping_memory(dst.as_mut_ptr(), src.as_ptr(), dst.len()); }
+        }
+        report_bandwidth(&mut results, "memcpy", timer.stop());
+    }
+
+    {
+        let timer = Timer::start();
+        for _ in 0..BENCH_FRAME_ROUNDS {
+            let frame = allocate_frame().ok_or(Error::new(ENOMEM))?;
+            deallocate_frames(frame, 1);
+        }
+        report_latency(&mut results, "frame_alloc", timer.stop(), BENCH_FRAME_ROUNDS);
+    }
+
+    {
+        // The idle context is always runnable, so each switch goes there and back at least
+        let timer = Timer::start();
+        for _ in 0..BENCH_SWITCH_ROUNDS {
+            unsafe { context::switch(); }
+        }
+        report_latency(&mut results, "switch", timer.stop(), BENCH_SWITCH_ROUNDS);
+    }
+
+    {
+        let timer = Timer::start();
+        for _ in 0..BENCH_SYSCALL_ROUNDS {
+            unsafe { arch::interrupt::syscall::kernel_syscall(SYS_GETPID); }
+        }
+        report_latency(&mut results, "syscall", timer.stop(), BENCH_SYSCALL_ROUNDS);
+    }
+
+    *RESULTS.call_once(init_results).lock() = results;
+
+    Ok(())
+}
+
+/// Results of the last run
+pub fn results() -> Vec<u8> {
+    RESULTS.call_once(init_results).lock().clone()
+}
@@ -0,0 +1,474 @@
+//! # Checkpoints
+//! A frozen user context can be saved, with its registers and memory, and started again later as a
+//! new context. The context saves itself when asked to at its safe point, as its memory is only
+//! mapped in its own address space.
+//!
+//! Handles are not saved, as they are mostly provided by schemes that are frozen along with the
+//! context. A restored context is given copies of the handles of the context that restored it.
+
+use alloc::arc::Arc;
+use alloc::boxed::Box;
+use collections::{BTreeMap, Vec};
+use core::{intrinsics, mem, slice};
+use spin::Mutex;
+
+use arch;
+use arch::gdt;
+use arch::interrupt::syscall::{restore_ret, SyscallStack};
+use arch::memory::{allocate_frame, deallocate_frame, Frame};
+use arch::paging::{ActivePageTable, InactivePageTable, Page, PhysicalAddress, VirtualAddress, PAGE_SIZE, entry};
+use arch::paging::temporary_page::TemporaryPage;
+use context::{self, sched, Status};
+use context::kstack::KernelStack;
+use context::memory::{Memory, Tls};
+use scheme;
+use syscall;
+use syscall::error::{Error, EBADF, EBUSY, EINVAL, ENOMEM, ESRCH, Result};
+
+/// Identifies a checkpoint, and the version of its format
+const MAGIC: &'static [u8; 8] = b"RDXCKPT2";
+
+/// Limit on the size of a checkpoint, which is held in kernel memory
+pub const CHECKPOINT_MAX_SIZE: usize = 16 * 1024 * 1024;
+
+/// Kinds of memory saved in a checkpoint
+const REGION_IMAGE: u64 = 0;
+const REGION_HEAP: u64 = 1;
+const REGION_STACK: u64 = 2;
+const REGION_TLS: u64 = 3;
+
+/// Progress of a checkpoint of a frozen context
+#[derive(Debug)]
+pub enum Checkpoint {
+    /// The context will save itself when it is next scheduled
+    Requested,
+    /// The context has been saved, or could not be
+    Done(Result<Vec<u8>>)
+}
+
+fn push_u64(data: &mut Vec<u8>, value: u64) {
+    for i in 0..8 {
+        data.push((value >> (i * 8)) as u8);
+    }
+}
+
+fn push_bytes(data: &mut Vec<u8>, bytes: &[u8]) {
+    push_u64(data, bytes.len() as u64);
+    data.extend_from_slice(bytes);
+}
+
+/// Reads the fields of a checkpoint in order
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.data.len() - self.offset {
+            return Err(Error::new(EINVAL));
+        }
+        let bytes = &self.data[self.offset .. self.offset + len];
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        let bytes = self.take(8)?;
+        let mut value = 0;
+        for i in 0..8 {
+            value |= (bytes[i] as u64) << (i * 8);
+        }
+        Ok(value)
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.u64()? as usize;
+        self.take(len)
+    }
+}
+
+/// Ask the frozen context `pid` to save itself, and wait for the result
+pub fn request(pid: usize) -> Result<Vec<u8>> {
+    if pid == context::context_id() {
+        return Err(Error::new(EINVAL));
+    }
+
+    {
+        let contexts = context::contexts();
+        let context_lock = contexts.get(pid).ok_or(Error::new(ESRCH))?;
+        let mut context = context_lock.write();
+        // Only a frozen context is stopped where its registers are known
+        if ! context.frozen || context.checkpoint.is_some() {
+            return Err(Error::new(EBUSY));
+        }
+        context.checkpoint = Some(Checkpoint::Requested);
+        context.unblock();
+    }
+
+    loop {
+        {
+            let contexts = context::contexts();
+            let context_lock = contexts.get(pid).ok_or(Error::new(ESRCH))?;
+            let mut context = context_lock.write();
+            if let Status::Exited(_) = context.status {
+                context.checkpoint = None;
+                return Err(Error::new(ESRCH));
+            }
+            match context.checkpoint.take() {
+                Some(Checkpoint::Done(result)) => return result,
+                other => context.checkpoint = other
+            }
+        }
+
+        unsafe { context::switch(); }
+    }
+}
+
+/// Save the current context, which is leaving a syscall with the result `rax`
+pub fn save(rax: usize) -> Result<Vec<u8>> {
+    let contexts = context::contexts();
+    let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+    let context = context_lock.read();
+
+    // Grants map memory of other contexts and devices, which cannot be given back
+    if ! context.grants.read().is_empty() {
+        return Err(Error::new(EBUSY));
+    }
+
+    let mut regions: Vec<(u64, usize, usize, entry::EntryFlags)> = Vec::new();
+    for shared_mem in context.image.iter() {
+        shared_mem.with(|mem| {
+            regions.push((REGION_IMAGE, mem.start_address().get(), mem.size(), mem.flags()));
+        });
+    }
+    if let Some(ref heap) = context.heap {
+        heap.with(|heap| {
+            regions.push((REGION_HEAP, heap.start_address().get(), heap.size(), heap.flags()));
+        });
+    }
+    if let Some(ref stack) = context.stack {
+        regions.push((REGION_STACK, stack.start_address().get(), stack.size(), stack.flags()));
+    }
+    if let Some(ref tls) = context.tls {
+        regions.push((REGION_TLS, tls.mem.start_address().get(), tls.mem.size(), tls.mem.flags()));
+    }
+    regions.retain(|&(_kind, _start, size, flags)| size > 0 && flags.contains(entry::PRESENT));
+
+    if regions.iter().fold(0, |total, &(_kind, _start, size, _flags)| total + size) > CHECKPOINT_MAX_SIZE {
+        return Err(Error::new(ENOMEM));
+    }
+
+    let mut data = Vec::new();
+    data.extend_from_slice(MAGIC);
+
+    // Registers, saved on the kernel stack when the context entered the syscall it is frozen in
+    let kstack = context.kstack.as_ref().ok_or(Error::new(ESRCH))?;
+    push_u64(&mut data, rax as u64);
+    data.extend_from_slice(unsafe {
+        let regs = kstack.as_ptr().offset((kstack.len() - 256 - mem::size_of::<SyscallStack>()) as isize);
+        slice::from_raw_parts(regs, mem::size_of::<SyscallStack>())
+    });
+    match context.kfx {
+        Some(ref fx) => data.extend_from_slice(&fx[..512]),
+        None => data.extend_from_slice(&[0; 512])
+    }
+
+    push_u64(&mut data, context.ruid as u64);
+    push_u64(&mut data, context.rgid as u64);
+    push_u64(&mut data, context.euid as u64);
+    push_u64(&mut data, context.egid as u64);
+
+    // The monotonic time the context saw, so that it continues from there when restored
+    let (seconds, nanoseconds) = syscall::monotonic(context.clock_offset);
+    push_u64(&mut data, seconds * 1000000000 + nanoseconds);
+
+    push_bytes(&mut data, &context.name.lock());
+    push_bytes(&mut data, &context.exe.lock());
+    push_bytes(&mut data, &context.cwd.lock());
+
+    {
+        let env = context.env.lock();
+        push_u64(&mut data, env.len() as u64);
+        for (name, value) in env.iter() {
+            push_bytes(&mut data, name);
+            push_bytes(&mut data, &value.lock());
+        }
+    }
+
+    if let Some(ref tls) = context.tls {
+        push_u64(&mut data, tls.master.get() as u64);
+        push_u64(&mut data, tls.file_size as u64);
+    } else {
+        push_u64(&mut data, 0);
+        push_u64(&mut data, 0);
+    }
+
+    push_u64(&mut data, regions.len() as u64);
+    for &(kind, start, size, flags) in regions.iter() {
+        push_u64(&mut data, kind);
+        push_u64(&mut data, start as u64);
+        push_u64(&mut data, size as u64);
+        push_u64(&mut data, flags.bits());
+    }
+    for &(_kind, start, size, _flags) in regions.iter() {
+        data.extend_from_slice(unsafe { slice::from_raw_parts(start as *const u8, size) });
+    }
+
+    Ok(data)
+}
+
+/// Start a new context from a checkpoint, as a child of the current context.
+/// Returns the ID of the new context
+pub fn restore(data: &[u8]) -> Result<usize> {
+    let mut reader = Reader {
+        data: data,
+        offset: 0
+    };
+
+    if reader.take(MAGIC.len())? != &MAGIC[..] {
+        return Err(Error::new(EINVAL));
+    }
+
+    let rax = reader.u64()? as usize;
+    let mut regs = unsafe { *(reader.take(mem::size_of::<SyscallStack>())?.as_ptr() as *const SyscallStack) };
+    // The context must return to usermode, with the usual segments, and without port access
+    regs.cs = gdt::GDT_USER_CODE << 3 | 3;
+    regs.ss = gdt::GDT_USER_DATA << 3 | 3;
+    regs.fs = gdt::GDT_USER_TLS << 3 | 3;
+    regs.rflags = (regs.rflags & !(3 << 12)) | 1 << 9;
+    let fx_data = reader.take(512)?;
+
+    let ruid = reader.u64()? as u32;
+    let rgid = reader.u64()? as u32;
+    let euid = reader.u64()? as u32;
+    let egid = reader.u64()? as u32;
+    let time = reader.u64()? as i64;
+    let name = reader.bytes()?.to_vec();
+    let exe = reader.bytes()?.to_vec();
+    let cwd = reader.bytes()?.to_vec();
+
+    let mut env = BTreeMap::new();
+    for _ in 0..reader.u64()? {
+        let name = reader.bytes()?.to_vec().into_boxed_slice();
+        let value = reader.bytes()?.to_vec();
+        env.insert(name, Arc::new(Mutex::new(value)));
+    }
+
+    let tls_master = reader.u64()? as usize;
+    let tls_file_size = reader.u64()? as usize;
+
+    let allowed = entry::PRESENT | entry::WRITABLE | entry::USER_ACCESSIBLE | entry::NO_EXECUTE;
+    let mut regions = Vec::new();
+    for _ in 0..reader.u64()? {
+        let kind = reader.u64()?;
+        let start = reader.u64()? as usize;
+        let size = reader.u64()? as usize;
+        let flags = entry::EntryFlags::from_bits_truncate(reader.u64()?) & allowed;
+
+        // Each kind of memory has to stay in its own part of the address space
+        let (base, tmp) = match kind {
+            REGION_IMAGE => (arch::USER_OFFSET, arch::USER_TMP_OFFSET),
+            REGION_HEAP => (arch::USER_HEAP_OFFSET, arch::USER_TMP_HEAP_OFFSET),
+            REGION_STACK => (arch::USER_STACK_OFFSET, arch::USER_TMP_STACK_OFFSET),
+            REGION_TLS => (arch::USER_TLS_OFFSET, arch::USER_TMP_TLS_OFFSET),
+            _ => return Err(Error::new(EINVAL))
+        };
+        if start < base || size > arch::PML4_SIZE || start - base > arch::PML4_SIZE - size {
+            return Err(Error::new(EINVAL));
+        }
+        if kind != REGION_IMAGE && regions.iter().any(|&(other, _start, _size, _flags, _tmp)| other == kind) {
+            return Err(Error::new(EINVAL));
+        }
+        // Regions are mapped a page at a time, so they must not share a page
+        let first_page = start / PAGE_SIZE;
+        let end_page = (start + size + PAGE_SIZE - 1) / PAGE_SIZE;
+        if regions.iter().any(|&(_kind, other_start, other_size, _flags, _tmp)| {
+            first_page < (other_start + other_size + PAGE_SIZE - 1) / PAGE_SIZE && other_start / PAGE_SIZE < end_page
+        }) {
+            return Err(Error::new(EINVAL));
+        }
+
+        regions.push((kind, start, size, flags, start - base + tmp));
+    }
+
+    // Copy memory into the current address space, to be moved to the new one
+    let mut image = Vec::new();
+    let mut heap_option = None;
+    let mut stack_option = None;
+    let mut tls_option = None;
+    for &(kind, _start, size, flags, tmp) in regions.iter() {
+        let contents = reader.take(size)?;
+
+        let mut memory = Memory::new(
+            VirtualAddress::new(tmp),
+            size,
+            entry::PRESENT | entry::NO_EXECUTE | entry::WRITABLE,
+            true,
+            false
+        );
+
+        unsafe {
+            intrinsics::copy(contents.as_ptr(), memory.start_address().get() as *mut u8, size);
+        }
+
+        memory.remap(flags | entry::USER_ACCESSIBLE, true);
+
+        match kind {
+            REGION_IMAGE => image.push(memory),
+            REGION_HEAP => heap_option = Some(memory),
+            REGION_STACK => stack_option = Some(memory),
+            _ => tls_option = Some(memory)
+        }
+    }
+
+    let mut fx = unsafe { Box::from_raw(::alloc::heap::allocate(512, 16) as *mut [u8; 512]) };
+    for (b, fx_b) in fx.iter_mut().zip(fx_data.iter()) {
+        *b = *fx_b;
+    }
+
+    // The context starts by popping rax and the other registers, and returning to usermode
+    let mut kstack = KernelStack::new()?;
+    let regs_offset = kstack.len() - 256 - mem::size_of::<SyscallStack>();
+    let offset = regs_offset - 2 * mem::size_of::<usize>();
+    unsafe {
+        let stack_ptr = kstack.as_mut_ptr();
+        *(stack_ptr.offset(regs_offset as isize) as *mut SyscallStack) = regs;
+        *(stack_ptr.offset((regs_offset - mem::size_of::<usize>()) as isize) as *mut usize) = rax;
+        *(stack_ptr.offset(offset as isize) as *mut usize) = restore_ret as usize;
+    }
+
+    let (ppid, arch, files) = {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+        let files = context.files.lock().clone();
+        (context.id, context.arch.clone(), files)
+    };
+
+    // Give the new context its own numbers for the handles of this one
+    let mut new_files = Vec::new();
+    for file_option in files.iter() {
+        new_files.push(if let Some(ref file) = *file_option {
+            let scheme = {
+                let schemes = scheme::schemes();
+                let scheme = schemes.get(file.scheme).ok_or(Error::new(EBADF))?;
+                scheme.clone()
+            };
+            scheme.dup(file.number, b"clone").ok().map(|new_number| file.dup(new_number))
+        } else {
+            None
+        });
+    }
+
+    let mut active_table = unsafe { ActivePageTable::new() };
+
+    let mut temporary_page = TemporaryPage::new(Page::containing_address(VirtualAddress::new(0x8_0000_0000)));
+
+    // Allocated before the context list is locked, so that the allocator can look for memory to free
+    let mut new_table = {
+        let frame = allocate_frame().ok_or(Error::new(ENOMEM))?;
+        InactivePageTable::new(frame, &mut active_table, &mut temporary_page)
+    };
+
+    let mut contexts = context::contexts_mut();
+    let context_lock = match contexts.new_context() {
+        Ok(context_lock) => context_lock.clone(),
+        Err(err) => {
+            deallocate_frame(Frame::containing_address(PhysicalAddress::new(unsafe { new_table.address() })));
+            return Err(err);
+        }
+    };
+    let mut context = context_lock.write();
+
+    context.ppid = ppid;
+    context.ruid = ruid;
+    context.rgid = rgid;
+    context.euid = euid;
+    context.egid = egid;
+
+    let (seconds, nanoseconds) = arch::time::monotonic();
+    context.clock_offset = time - (seconds * 1000000000 + nanoseconds) as i64;
+
+    context.arch = arch;
+
+    context.arch.set_page_table(unsafe { new_table.address() });
+
+    // Copy kernel mapping
+    {
+        let frame = active_table.p4()[510].pointed_frame().expect("kernel table not mapped");
+        let flags = active_table.p4()[510].flags();
+        active_table.with(&mut new_table, &mut temporary_page, |mapper| {
+            mapper.p4_mut()[510].set(frame, flags);
+        });
+    }
+
+    // Copy percpu mapping
+    for cpu_id in 0..::cpu_count() {
+        extern {
+            /// The starting byte of the thread data segment
+            static mut __tdata_start: u8;
+            /// The ending byte of the thread BSS segment
+            static mut __tbss_end: u8;
+        }
+
+        let size = unsafe { & __tbss_end as *const _ as usize - & __tdata_start as *const _ as usize };
+
+        let start = arch::KERNEL_PERCPU_OFFSET + arch::KERNEL_PERCPU_SIZE * cpu_id;
+        let end = start + size;
+
+        let start_page = Page::containing_address(VirtualAddress::new(start));
+        let end_page = Page::containing_address(VirtualAddress::new(end - 1));
+        for page in Page::range_inclusive(start_page, end_page) {
+            let frame = active_table.translate_page(page).expect("kernel percpu not mapped");
+            active_table.with(&mut new_table, &mut temporary_page, |mapper| {
+                mapper.map_to(page, frame, entry::PRESENT | entry::NO_EXECUTE | entry::WRITABLE);
+            });
+        }
+    }
+
+    context.arch.set_fx(fx.as_ptr() as usize);
+    context.kfx = Some(fx);
+
+    context.arch.set_stack(kstack.as_ptr() as usize + offset);
+    context.kstack = Some(kstack);
+
+    let image_regions = regions.iter().filter(|&&(kind, _start, _size, _flags, _tmp)| kind == REGION_IMAGE);
+    for (mut memory, &(_kind, start, _size, _flags, _tmp)) in image.into_iter().zip(image_regions) {
+        memory.move_to(VirtualAddress::new(start), &mut new_table, &mut temporary_page, true);
+        context.image.push(memory.to_shared());
+    }
+
+    for &(kind, start, _size, _flags, _tmp) in regions.iter() {
+        match kind {
+            REGION_HEAP => if let Some(mut heap) = heap_option.take() {
+                heap.move_to(VirtualAddress::new(start), &mut new_table, &mut temporary_page, true);
+                context.heap = Some(heap.to_shared());
+            },
+            REGION_STACK => if let Some(mut stack) = stack_option.take() {
+                stack.move_to(VirtualAddress::new(start), &mut new_table, &mut temporary_page, true);
+                context.stack = Some(stack);
+            },
+            REGION_TLS => if let Some(mut mem) = tls_option.take() {
+                mem.move_to(VirtualAddress::new(start), &mut new_table, &mut temporary_page, true);
+                context.tls = Some(Tls {
+                    master: VirtualAddress::new(tls_master),
+                    file_size: tls_file_size,
+                    mem: mem
+                });
+            },
+            _ => ()
+        }
+    }
+
+    context.name = Arc::new(Mutex::new(name));
+    context.exe = Arc::new(Mutex::new(exe));
+    context.cwd = Arc::new(Mutex::new(cwd));
+    context.env = Arc::new(Mutex::new(env));
+    context.files = Arc::new(Mutex::new(new_files));
+
+    context.status = Status::Runnable;
+    sched::place(&mut context, &contexts);
+
+    Ok(context.id)
+}
@@ -4,10 +4,17 @@ use collections::{BTreeMap, Vec};
 use spin::Mutex;
 
 use arch;
+use context::checkpoint::Checkpoint;
 use context::file::File;
+use context::kstack::KernelStack;
 use context::memory::{Grant, Memory, SharedMemory, Tls};
+use context::pidns::PidNamespace;
+use context::sched::CpuMask;
+use context::trace::Tracer;
+use context::usage::{Rusage, Usage};
+use context::working_set::WorkingSet;
 use syscall::data::Event;
-use sync::{WaitMap, WaitQueue};
+use sync::{RwLock, WaitMap, WaitQueue, GRANT_LOCKS};
 
 /// The status of a context - used for scheduling
 /// See syscall::process::waitpid and the sync module for examples of usage
@@ -39,18 +46,55 @@ pub struct Context {
     pub running: bool,
     /// CPU ID, if locked
     pub cpu_id: Option<usize>,
+    /// The CPUs this context may run on. Kept across fork and exec
+    pub affinity: CpuMask,
     /// Context is halting parent
     pub vfork: bool,
     /// Context is being waited on
     pub waitpid: Arc<WaitMap<usize, usize>>,
     /// Context should wake up at specified time
     pub wake: Option<(u64, u64)>,
+    /// Context will never be selected by the out of memory killer
+    pub oom_protected: bool,
+    /// Pages of the image, heap, and stack may be merged with identical pages of other contexts
+    /// that allow it. Kept across fork and exec
+    pub merge: bool,
+    /// Nanoseconds added to the monotonic clock as this context reads it. Contexts that inherit it
+    /// share a view of time, so that a container, or a context restored from a checkpoint, sees
+    /// time continue from where it was. Kept across fork and exec
+    pub clock_offset: i64,
+    /// The PID namespace of this context, None if it is in the root namespace. Kept across fork
+    /// and exec
+    pub pid_ns: Option<Arc<PidNamespace>>,
+    /// The next context started by this one is the init of a new PID namespace
+    pub new_pid_ns: bool,
+    /// Ages of the pages of this context, if its working set is tracked. Not kept across fork
+    pub working_set: Option<WorkingSet>,
+    /// Context has been killed, and will exit with this status when it next leaves the kernel
+    pub killed: Option<usize>,
+    /// Context is allocating without holding any locks, so the OOM killer may switch away from it
+    pub oom_wait: bool,
+    /// Context is stopped by the freezer, and will not run until thawed
+    pub frozen: bool,
+    /// Checkpoint of this context, while it is frozen
+    pub checkpoint: Option<Checkpoint>,
+    /// Tracer that is sent a record of each syscall. Not kept across fork
+    pub trace: Option<Arc<Tracer>>,
+    /// Resources used by this context. Not kept across fork
+    pub usage: Arc<Usage>,
+    /// Resources used by the children this context has reaped, and the children they reaped
+    pub children_usage: Rusage,
     /// The architecture specific context
     pub arch: arch::context::Context,
     /// Kernel FX - used to store SIMD and FPU registers on context switch
     pub kfx: Option<Box<[u8]>>,
     /// Kernel stack
-    pub kstack: Option<Box<[u8]>>,
+    pub kstack: Option<KernelStack>,
+    /// I/O permission bitmap, in the format used by the TSS. No ports are accessible if unset
+    pub io_bitmap: Option<Box<[u8]>>,
+    /// Set when the I/O bitmap was changed while the context was running on another CPU, until
+    /// that CPU loads it again
+    pub io_bitmap_changed: bool,
     /// Executable image
     pub image: Vec<SharedMemory>,
     /// User heap
@@ -60,9 +104,11 @@ pub struct Context {
     /// User Thread local storage
     pub tls: Option<Tls>,
     /// User grants
-    pub grants: Arc<Mutex<Vec<Grant>>>,
-    /// The name of the context
+    pub grants: Arc<RwLock<Vec<Grant>>>,
+    /// The name of the context, set to the executable path by exec, but may be changed
     pub name: Arc<Mutex<Vec<u8>>>,
+    /// The path of the executable
+    pub exe: Arc<Mutex<Vec<u8>>>,
     /// The current working directory
     pub cwd: Arc<Mutex<Vec<u8>>>,
     /// Kernel events
@@ -86,18 +132,35 @@ impl Context {
             status: Status::Blocked,
             running: false,
             cpu_id: None,
+            affinity: CpuMask::all(),
             vfork: false,
             waitpid: Arc::new(WaitMap::new()),
             wake: None,
+            oom_protected: false,
+            merge: false,
+            clock_offset: 0,
+            pid_ns: None,
+            new_pid_ns: false,
+            working_set: None,
+            killed: None,
+            oom_wait: false,
+            frozen: false,
+            checkpoint: None,
+            trace: None,
+            usage: Arc::new(Usage::new()),
+            children_usage: Rusage::default(),
             arch: arch::context::Context::new(),
             kfx: None,
             kstack: None,
+            io_bitmap: None,
+            io_bitmap_changed: false,
             image: Vec::new(),
             heap: None,
             stack: None,
             tls: None,
-            grants: Arc::new(Mutex::new(Vec::new())),
+            grants: Arc::new(RwLock::new(Vec::new(), &GRANT_LOCKS)),
             name: Arc::new(Mutex::new(Vec::new())),
+            exe: Arc::new(Mutex::new(Vec::new())),
             cwd: Arc::new(Mutex::new(Vec::new())),
             events: Arc::new(WaitQueue::new()),
             env: Arc::new(Mutex::new(BTreeMap::new())),
@@ -201,7 +264,7 @@ impl Context {
     pub fn get_file(&self, i: usize) -> Option<File> {
         let files = self.files.lock();
         if i < files.len() {
-            files[i]
+            files[i].clone()
         } else {
             None
         }
@@ -0,0 +1,221 @@
+use collections::Vec;
+use core::slice;
+use spin::{Mutex, Once};
+
+use arch::paging::entry::{self, EntryFlags};
+use context;
+use elf::{header, program_header};
+use syscall;
+use syscall::error::{Error, EIO, ESRCH, Result};
+use syscall::flag::{O_CREAT, O_TRUNC, O_WRONLY};
+use syscall::number::SYS_WRITE;
+
+/// Number of general registers in an x86_64 core file
+pub const CORE_NGREG: usize = 27;
+
+/// Note type for process status, including registers
+const NT_PRSTATUS: u32 = 1;
+
+/// Size of the process status note description
+const PRSTATUS_SIZE: usize = 336;
+
+/// Alignment of memory segments in the core file
+const CORE_ALIGN: usize = 4096;
+
+/// Path prefix that core dumps are written to, the PID is appended. Empty if disabled
+static CORE_PATH: Once<Mutex<Vec<u8>>> = Once::new();
+
+/// Initialize core path, called if needed
+fn init_core_path() -> Mutex<Vec<u8>> {
+    Mutex::new(Vec::new())
+}
+
+/// Get the path prefix for core dumps
+pub fn core_path() -> Vec<u8> {
+    CORE_PATH.call_once(init_core_path).lock().clone()
+}
+
+/// Set the path prefix for core dumps, an empty path disables them
+pub fn set_core_path(path: &[u8]) {
+    *CORE_PATH.call_once(init_core_path).lock() = path.to_vec();
+}
+
+fn push_u16(data: &mut Vec<u8>, value: u16) {
+    for i in 0..2 {
+        data.push((value >> (i * 8)) as u8);
+    }
+}
+
+fn push_u32(data: &mut Vec<u8>, value: u32) {
+    for i in 0..4 {
+        data.push((value >> (i * 8)) as u8);
+    }
+}
+
+fn push_u64(data: &mut Vec<u8>, value: u64) {
+    for i in 0..8 {
+        data.push((value >> (i * 8)) as u8);
+    }
+}
+
+fn write_all(file: usize, buf: &[u8]) -> Result<()> {
+    let mut i = 0;
+    while i < buf.len() {
+        let count = syscall::file_op_slice(SYS_WRITE, file, &buf[i..])?;
+        if count == 0 {
+            return Err(Error::new(EIO));
+        }
+        i += count;
+    }
+    Ok(())
+}
+
+/// Write an ELF core file for the current context, which must be a user context that is
+/// about to exit with `signal`. Nothing is written if core dumps are disabled
+pub fn dump(signal: usize, regs: &[usize; CORE_NGREG]) -> Result<()> {
+    let path = core_path();
+    if path.is_empty() {
+        return Ok(());
+    }
+
+    // Collect memory regions, these are mapped in the current address space
+    let mut regions: Vec<(usize, usize, EntryFlags)> = Vec::new();
+    let (pid, ppid) = {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+
+        for shared_mem in context.image.iter() {
+            shared_mem.with(|mem| {
+                regions.push((mem.start_address().get(), mem.size(), mem.flags()));
+            });
+        }
+        if let Some(ref heap) = context.heap {
+            heap.with(|heap| {
+                regions.push((heap.start_address().get(), heap.size(), heap.flags()));
+            });
+        }
+        if let Some(ref stack) = context.stack {
+            regions.push((stack.start_address().get(), stack.size(), stack.flags()));
+        }
+        if let Some(ref tls) = context.tls {
+            regions.push((tls.mem.start_address().get(), tls.mem.size(), tls.mem.flags()));
+        }
+
+        (context.id, context.ppid)
+    };
+    regions.retain(|&(_start, size, flags)| size > 0 && flags.contains(entry::PRESENT));
+
+    let phnum = regions.len() + 1;
+    let note_offset = header::SIZEOF_EHDR + phnum * program_header::SIZEOF_PHDR;
+    let note_size = 12 + 8 + PRSTATUS_SIZE;
+    let mut offset = ((note_offset + note_size + CORE_ALIGN - 1)/CORE_ALIGN) * CORE_ALIGN;
+
+    let mut data = Vec::with_capacity(offset);
+
+    // ELF header
+    data.extend_from_slice(header::ELFMAG);
+    data.push(header::ELFCLASS);
+    data.push(1); // Little endian
+    data.push(1); // Version
+    while data.len() < 16 {
+        data.push(0);
+    }
+    push_u16(&mut data, header::ET_CORE);
+    push_u16(&mut data, header::EM_X86_64);
+    push_u32(&mut data, 1);
+    push_u64(&mut data, 0); // Entry
+    push_u64(&mut data, header::SIZEOF_EHDR as u64); // Program headers
+    push_u64(&mut data, 0); // Section headers
+    push_u32(&mut data, 0);
+    push_u16(&mut data, header::SIZEOF_EHDR as u16);
+    push_u16(&mut data, program_header::SIZEOF_PHDR as u16);
+    push_u16(&mut data, phnum as u16);
+    push_u16(&mut data, 0);
+    push_u16(&mut data, 0);
+    push_u16(&mut data, 0);
+
+    // Note program header
+    push_u32(&mut data, program_header::PT_NOTE);
+    push_u32(&mut data, 0);
+    push_u64(&mut data, note_offset as u64);
+    push_u64(&mut data, 0);
+    push_u64(&mut data, 0);
+    push_u64(&mut data, note_size as u64);
+    push_u64(&mut data, 0);
+    push_u64(&mut data, 4);
+
+    // Memory program headers
+    for &(start, size, flags) in regions.iter() {
+        let mut p_flags = program_header::PF_R;
+        if flags.contains(entry::WRITABLE) || flags.contains(entry::COPY_ON_WRITE) {
+            p_flags |= program_header::PF_W;
+        }
+        if ! flags.contains(entry::NO_EXECUTE) {
+            p_flags |= program_header::PF_X;
+        }
+
+        push_u32(&mut data, program_header::PT_LOAD);
+        push_u32(&mut data, p_flags);
+        push_u64(&mut data, offset as u64);
+        push_u64(&mut data, start as u64);
+        push_u64(&mut data, 0);
+        push_u64(&mut data, size as u64);
+        push_u64(&mut data, size as u64);
+        push_u64(&mut data, CORE_ALIGN as u64);
+
+        offset += ((size + CORE_ALIGN - 1)/CORE_ALIGN) * CORE_ALIGN;
+    }
+
+    // Process status note
+    push_u32(&mut data, 5);
+    push_u32(&mut data, PRSTATUS_SIZE as u32);
+    push_u32(&mut data, NT_PRSTATUS);
+    data.extend_from_slice(b"CORE\0\0\0\0");
+    push_u32(&mut data, signal as u32); // Signal number
+    push_u32(&mut data, 0); // Signal code
+    push_u32(&mut data, 0); // Signal errno
+    push_u16(&mut data, signal as u16); // Current signal
+    push_u16(&mut data, 0);
+    push_u64(&mut data, 0); // Pending signals
+    push_u64(&mut data, 0); // Held signals
+    push_u32(&mut data, pid as u32);
+    push_u32(&mut data, ppid as u32);
+    push_u32(&mut data, 0); // Process group
+    push_u32(&mut data, 0); // Session
+    for _ in 0..8 {
+        push_u64(&mut data, 0); // User, system, and children times
+    }
+    for &reg in regs.iter() {
+        push_u64(&mut data, reg as u64);
+    }
+    push_u32(&mut data, 0); // FPU registers are not included
+    push_u32(&mut data, 0);
+
+    debug_assert_eq!(data.len(), note_offset + note_size);
+    while data.len() % CORE_ALIGN != 0 {
+        data.push(0);
+    }
+
+    let mut core_path = path;
+    core_path.extend_from_slice(format!("{}", pid).as_bytes());
+
+    let file = syscall::open(&core_path, O_CREAT | O_TRUNC | O_WRONLY | 0o600)?;
+    let mut result = write_all(file, &data);
+
+    let padding = [0; CORE_ALIGN];
+    for &(start, size, _flags) in regions.iter() {
+        if result.is_err() {
+            break;
+        }
+
+        result = write_all(file, unsafe { slice::from_raw_parts(start as *const u8, size) });
+        if result.is_ok() && size % CORE_ALIGN != 0 {
+            result = write_all(file, &padding[.. CORE_ALIGN - size % CORE_ALIGN]);
+        }
+    }
+
+    let _ = syscall::close(file);
+
+    result
+}
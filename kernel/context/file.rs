@@ -1,13 +1,91 @@
 //! File struct
 
+use alloc::arc::Arc;
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use arch;
+use syscall::error::Result;
+use syscall::number::{SYS_LSEEK, SYS_READ, SYS_WRITE};
+
+/// What a file was opened as, and what has been done with it, listed in `sys:<pid>/fd`
+#[derive(Debug)]
+pub struct FileInfo {
+    /// The path the file was opened with, after mounts were resolved
+    pub path: Box<[u8]>,
+    /// The flags the file was opened with
+    pub flags: usize,
+    /// The offset, as last reported by a read, write, or seek
+    pub offset: AtomicUsize,
+    /// Bytes read from the file
+    pub read: AtomicU64,
+    /// Bytes written to the file
+    pub written: AtomicU64,
+    /// Monotonic time of the last operation, in nanoseconds, zero if there was none
+    pub last: AtomicU64
+}
+
 /// A file
 //TODO: Close on exec
-#[derive(Copy, Clone, Debug)]
+#[derive(Clone, Debug)]
 pub struct File {
     /// The scheme that this file refers to
     pub scheme: usize,
     /// The number the scheme uses to refer to this file
     pub number: usize,
+    /// The mount this file was opened through, if any
+    pub mount: Option<usize>,
     /// If events are on, this is the event ID
     pub event: Option<usize>,
+    /// Path, flags, and counters, shared by contexts that share their files
+    pub info: Arc<FileInfo>
+}
+
+impl File {
+    /// A file the scheme numbered `number`, opened with `path` and `flags`
+    pub fn new(scheme: usize, number: usize, mount: Option<usize>, path: &[u8], flags: usize) -> File {
+        File {
+            scheme: scheme,
+            number: number,
+            mount: mount,
+            event: None,
+            info: Arc::new(FileInfo {
+                path: path.to_vec().into_boxed_slice(),
+                flags: flags,
+                offset: AtomicUsize::new(0),
+                read: AtomicU64::new(0),
+                written: AtomicU64::new(0),
+                last: AtomicU64::new(0)
+            })
+        }
+    }
+
+    /// The file the scheme numbered `number` when this one was duplicated. It starts with its own
+    /// counters, at the offset of this one
+    pub fn dup(&self, number: usize) -> File {
+        let file = File::new(self.scheme, number, self.mount, &self.info.path, self.info.flags);
+        file.info.offset.store(self.info.offset.load(Ordering::Relaxed), Ordering::Relaxed);
+        file
+    }
+
+    /// Count the result of the operation `a` on the file
+    pub fn count(&self, a: usize, result: &Result<usize>) {
+        if let Ok(count) = *result {
+            match a {
+                SYS_READ => {
+                    self.info.read.fetch_add(count as u64, Ordering::Relaxed);
+                    self.info.offset.fetch_add(count, Ordering::Relaxed);
+                },
+                SYS_WRITE => {
+                    self.info.written.fetch_add(count as u64, Ordering::Relaxed);
+                    self.info.offset.fetch_add(count, Ordering::Relaxed);
+                },
+                SYS_LSEEK => self.info.offset.store(count, Ordering::Relaxed),
+                _ => ()
+            }
+        }
+
+        let (seconds, nanoseconds) = arch::time::monotonic();
+        self.info.last.store(seconds * 1000000000 + nanoseconds, Ordering::Relaxed);
+    }
 }
@@ -0,0 +1,164 @@
+//! # Freezer
+//! Stops every user context, other than the one that asked, at a point where it holds no kernel
+//! resources, so that the system can be suspended or a context saved. Contexts are frozen on their
+//! way out of a syscall, or back to userspace from an interrupt, so that contexts that never make a
+//! syscall are stopped too. Contexts are thawed when the context that froze them exits.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT, Ordering};
+
+use context::{self, checkpoint, Status};
+use context::checkpoint::Checkpoint;
+use syscall::error::{Error, EBUSY, EINVAL, ESRCH, Result};
+
+/// Set while contexts are frozen
+static FROZEN: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// The context that froze the others, and that may thaw them
+static FREEZER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Returns true if contexts are frozen
+pub fn frozen() -> bool {
+    FROZEN.load(Ordering::SeqCst)
+}
+
+/// Returns true if the context is not frozen, and would run user code if it was scheduled
+fn thawed(context: &context::Context) -> bool {
+    context.stack.is_some() && context.status == Status::Runnable && ! context.frozen
+}
+
+/// Freeze all user contexts but the current one, and wait until they have stopped.
+/// Contexts that are blocked in the kernel are frozen once they are woken
+pub fn freeze() -> Result<usize> {
+    let current = context::context_id();
+
+    if FROZEN.compare_and_swap(false, true, Ordering::SeqCst) {
+        return Err(Error::new(EBUSY));
+    }
+    FREEZER.store(current, Ordering::SeqCst);
+
+    loop {
+        let running = {
+            let contexts = context::contexts();
+            contexts.iter().filter(|&(id, context_lock)| {
+                *id != current && thawed(&context_lock.read())
+            }).count()
+        };
+
+        if running == 0 {
+            break;
+        }
+
+        unsafe { context::switch(); }
+    }
+
+    let contexts = context::contexts();
+    Ok(contexts.iter().filter(|&(_id, context_lock)| context_lock.read().frozen).count())
+}
+
+/// Let frozen contexts run again. Only the context that froze them may do this
+pub fn thaw() -> Result<usize> {
+    if ! frozen() {
+        return Err(Error::new(EINVAL));
+    }
+    if FREEZER.load(Ordering::SeqCst) != context::context_id() {
+        return Err(Error::new(EBUSY));
+    }
+
+    Ok(thaw_all())
+}
+
+/// Unfreeze every context, returning how many were frozen
+fn thaw_all() -> usize {
+    FROZEN.store(false, Ordering::SeqCst);
+
+    let mut count = 0;
+    let contexts = context::contexts();
+    for (_id, context_lock) in contexts.iter() {
+        let mut context = context_lock.write();
+        if context.frozen {
+            context.frozen = false;
+            context.unblock();
+            count += 1;
+        }
+    }
+
+    count
+}
+
+/// Called when the context `pid` exits. If it froze the other contexts, they are thawed, as
+/// nothing else could
+pub fn release(pid: usize) {
+    if frozen() && FREEZER.load(Ordering::SeqCst) == pid {
+        thaw_all();
+    }
+}
+
+/// Called when the current context leaves a syscall with `result`. Blocks while contexts are
+/// frozen, unless this context froze them, or it has been killed. A frozen context saves itself here
+/// when a checkpoint is requested
+pub fn safe_point(result: &Result<usize>) -> Result<()> {
+    let rax = match *result {
+        Ok(value) => value,
+        Err(ref err) => -err.errno as usize
+    };
+    stop(Some(rax))
+}
+
+/// Called when the current context returns to userspace from an interrupt or exception. Blocks
+/// while contexts are frozen, like `safe_point`. Its registers were saved by the interrupt rather
+/// than the syscall entry, so a checkpoint cannot be taken here, and fails with EBUSY
+pub fn preempt_point() -> Result<()> {
+    stop(None)
+}
+
+/// Block while contexts are frozen. `rax` is the return value of the syscall being left, None if
+/// the context was interrupted
+fn stop(rax: Option<usize>) -> Result<()> {
+    if ! frozen() {
+        return Ok(());
+    }
+
+    loop {
+        let saving = {
+            let contexts = context::contexts();
+            let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+            let mut context = context_lock.write();
+            let requested = match context.checkpoint {
+                Some(Checkpoint::Requested) => true,
+                _ => false
+            };
+
+            if ! frozen() || context.id == FREEZER.load(Ordering::SeqCst) || context.killed.is_some() {
+                if requested {
+                    context.checkpoint = Some(Checkpoint::Done(Err(Error::new(EBUSY))));
+                }
+                context.frozen = false;
+                return Ok(());
+            }
+
+            context.frozen = true;
+            let saving = match (requested, rax) {
+                (true, Some(rax)) => Some(rax),
+                (true, None) => {
+                    context.checkpoint = Some(Checkpoint::Done(Err(Error::new(EBUSY))));
+                    None
+                },
+                _ => None
+            };
+            if saving.is_none() {
+                context.block();
+            }
+            saving
+        };
+
+        if let Some(rax) = saving {
+            let saved = checkpoint::save(rax);
+
+            let contexts = context::contexts();
+            let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+            context_lock.write().checkpoint = Some(Checkpoint::Done(saved));
+        } else {
+            unsafe { context::switch(); }
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use collections::{BTreeSet, Vec};
+use core::intrinsics;
+use core::ops::{Deref, DerefMut};
+use core::slice;
+use spin::Mutex;
+
+use arch;
+use arch::kaslr;
+use arch::memory::{allocate_frame, deallocate_frame};
+use arch::paging::{ActivePageTable, Page, VirtualAddress, PAGE_SIZE};
+use arch::paging::entry;
+use syscall::error::{Error, Result, ENOMEM};
+
+/// Size of a kernel stack. The rest of its slot is left unmapped, as a guard
+pub const KSTACK_SIZE: usize = 65536;
+
+/// Slots that have been given a stack
+static USED_SLOTS: Mutex<Option<BTreeSet<usize>>> = Mutex::new(None);
+
+/// Addresses of stacks that are not in use. Stacks stay mapped when freed, and are reused, as
+/// other CPUs may still have their pages in the TLB
+static FREE_STACKS: Mutex<Option<Vec<usize>>> = Mutex::new(None);
+
+/// Map a stack at the top of an unused slot, returning its address
+fn map_stack() -> Result<usize> {
+    let mut frames = Vec::with_capacity(KSTACK_SIZE / PAGE_SIZE);
+    for _ in 0 .. KSTACK_SIZE / PAGE_SIZE {
+        match allocate_frame() {
+            Some(frame) => frames.push(frame),
+            None => break
+        }
+    }
+
+    // Stacks are placed in random slots, so that their addresses cannot be guessed
+    let slots = arch::KERNEL_STACK_SIZE / arch::KERNEL_STACK_SLOT_SIZE;
+    let slot_option = if frames.len() == KSTACK_SIZE / PAGE_SIZE {
+        let mut used_option = USED_SLOTS.lock();
+        if used_option.is_none() {
+            *used_option = Some(BTreeSet::new());
+        }
+        let used = used_option.as_mut().unwrap();
+        if used.len() < slots {
+            let mut slot = kaslr::random() as usize % slots;
+            while used.contains(&slot) {
+                slot = kaslr::random() as usize % slots;
+            }
+            used.insert(slot);
+            Some(slot)
+        } else {
+            None
+        }
+    } else {
+        None
+    };
+
+    let slot = match slot_option {
+        Some(slot) => slot,
+        None => {
+            for frame in frames {
+                deallocate_frame(frame);
+            }
+            return Err(Error::new(ENOMEM));
+        }
+    };
+
+    let address = arch::KERNEL_STACK_OFFSET + (slot + 1) * arch::KERNEL_STACK_SLOT_SIZE - KSTACK_SIZE;
+
+    let mut active_table = unsafe { ActivePageTable::new() };
+    for (i, frame) in frames.into_iter().enumerate() {
+        let page = Page::containing_address(VirtualAddress::new(address + i * PAGE_SIZE));
+        active_table.map_to(page, frame, entry::PRESENT | entry::GLOBAL | entry::WRITABLE | entry::NO_EXECUTE);
+        active_table.flush(page);
+    }
+
+    Ok(address)
+}
+
+/// A kernel stack, above an unmapped guard, so that an overflow faults instead of corrupting
+/// other memory
+#[derive(Debug)]
+pub struct KernelStack {
+    address: usize
+}
+
+impl KernelStack {
+    /// Take a zeroed stack, mapping a new one if none are free
+    pub fn new() -> Result<KernelStack> {
+        let free = FREE_STACKS.lock().as_mut().and_then(|stacks| stacks.pop());
+        let address = match free {
+            Some(address) => address,
+            None => map_stack()?
+        };
+
+        unsafe { intrinsics::write_bytes(address as *mut u8, 0, KSTACK_SIZE); }
+
+        Ok(KernelStack {
+            address: address
+        })
+    }
+}
+
+impl Deref for KernelStack {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.address as *const u8, KSTACK_SIZE) }
+    }
+}
+
+impl DerefMut for KernelStack {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.address as *mut u8, KSTACK_SIZE) }
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let mut free = FREE_STACKS.lock();
+        if free.is_none() {
+            *free = Some(Vec::new());
+        }
+        if let Some(ref mut stacks) = *free {
+            stacks.push(self.address);
+        }
+    }
+}
@@ -8,6 +8,7 @@ use spin::RwLock;
 use arch;
 use syscall::error::{Result, Error, EAGAIN};
 use super::context::Context;
+use super::kstack::KernelStack;
 
 /// Context list type
 pub struct ContextList {
@@ -60,16 +61,16 @@ impl ContextList {
         Ok(self.map.get(&id).expect("Failed to insert new context. ID is out of bounds."))
     }
 
-    /// Spawn a context from a function.
+    /// Spawn a context from a function, placing it on a run queue
     pub fn spawn(&mut self, func: extern fn()) -> Result<&Arc<RwLock<Context>>> {
-        let context_lock = self.new_context()?;
+        let context_lock = self.new_context()?.clone();
         {
             let mut context = context_lock.write();
             let mut fx = unsafe { Box::from_raw(::alloc::heap::allocate(512, 16) as *mut [u8; 512]) };
             for b in fx.iter_mut() {
                 *b = 0;
             }
-            let mut stack = vec![0; 65536].into_boxed_slice();
+            let mut stack = KernelStack::new()?;
             let offset = stack.len() - mem::size_of::<usize>();
             unsafe {
                 let offset = stack.len() - mem::size_of::<usize>();
@@ -81,11 +82,20 @@ impl ContextList {
             context.arch.set_stack(stack.as_ptr() as usize + offset);
             context.kfx = Some(fx);
             context.kstack = Some(stack);
+            // Kernel threads share the page table they were spawned with, see `context::sched`
+            context.cpu_id = Some(::cpu_id());
+            super::sched::place(&mut context, self);
         }
-        Ok(context_lock)
+        let id = context_lock.read().id;
+        Ok(self.map.get(&id).expect("Failed to find spawned context."))
     }
 
     pub fn remove(&mut self, id: usize) -> Option<Arc<RwLock<Context>>> {
-        self.map.remove(&id)
+        let context_option = self.map.remove(&id);
+        if let Some(ref context_lock) = context_option {
+            let cpu_id = context_lock.read().cpu_id;
+            super::sched::remove(id, cpu_id);
+        }
+        context_option
     }
 }
@@ -1,46 +1,225 @@
 use alloc::arc::{Arc, Weak};
-use collections::VecDeque;
-use core::intrinsics;
-use spin::Mutex;
-
-use arch::memory::Frame;
-use arch::paging::{ActivePageTable, InactivePageTable, Page, PageIter, PhysicalAddress, VirtualAddress};
+use alloc::heap;
+use collections::{BTreeMap, String, VecDeque};
+use core::{cmp, intrinsics};
+use spin::{Mutex, Once};
+
+use arch;
+use arch::memory::{allocate_frame, deallocate_frame, Frame};
+use arch::paging::{ActivePageTable, InactivePageTable, Page, PageIter, PhysicalAddress, VirtualAddress, PAGE_SIZE};
 use arch::paging::entry::{self, EntryFlags};
+use arch::paging::mapper::Mapper;
 use arch::paging::temporary_page::TemporaryPage;
+use sync::{TicketMutex, SHARED_FRAME_LOCKS};
+use syscall::error::{Error, ENOMEM, Result};
+
+/// Physical address of the frame shared by all untouched anonymous pages
+static ZERO_FRAME: Once<usize> = Once::new();
+
+/// Allocate a zeroed page from the kernel heap, which is never freed, and find its frame
+fn init_zero_frame() -> usize {
+    let page = unsafe { heap::allocate(PAGE_SIZE, PAGE_SIZE) };
+    assert!(! page.is_null(), "failed to allocate zero page");
+    unsafe { intrinsics::write_bytes(page, 0, PAGE_SIZE); }
+
+    let active_table = unsafe { ActivePageTable::new() };
+    active_table.translate(VirtualAddress::new(page as usize)).expect("zero page not mapped").get()
+}
+
+/// Get the frame shared by all untouched anonymous pages. It must never be written or freed
+pub fn zero_frame() -> Frame {
+    Frame::containing_address(PhysicalAddress::new(*ZERO_FRAME.call_once(init_zero_frame)))
+}
+
+/// Frames of anonymous memory that are mapped by more than one address space after a fork, by
+/// physical address, with the number of mappings besides the first. A frame is only freed when it
+/// is unmapped with no other mappings left
+static SHARED_FRAMES: TicketMutex<Option<BTreeMap<usize, usize>>> = TicketMutex::new(None, &SHARED_FRAME_LOCKS);
+
+/// Run a function with the share counts locked. Interrupts are disabled, as the page fault handler
+/// takes the lock, and the copy-on-write temporary page is only used with it held
+pub fn with_shared<F, T>(f: F) -> T where F: FnOnce(&mut BTreeMap<usize, usize>) -> T {
+    let mut shared_option = SHARED_FRAMES.lock_irqsave();
+    if shared_option.is_none() {
+        *shared_option = Some(BTreeMap::new());
+    }
+    f(shared_option.as_mut().unwrap())
+}
+
+/// Get the number of frames that are shared copy-on-write
+pub fn shared_frames() -> usize {
+    with_shared(|shared| shared.len())
+}
+
+/// Check if a page with `frame` must stay copy-on-write, as the frame is mapped elsewhere
+pub fn is_shared(shared: &BTreeMap<usize, usize>, frame: &Frame) -> bool {
+    *frame == zero_frame() || shared.contains_key(&frame.start_address().get())
+}
+
+/// Add a mapping of a frame, unless it is the zero frame, which is never freed
+pub fn share(shared: &mut BTreeMap<usize, usize>, frame: &Frame) {
+    if *frame != zero_frame() {
+        *shared.entry(frame.start_address().get()).or_insert(0) += 1;
+    }
+}
+
+/// Remove one of the other mappings of a frame. Returns false if it had none, so it can be freed
+pub fn unshare(shared: &mut BTreeMap<usize, usize>, frame: &Frame) -> bool {
+    let address = frame.start_address().get();
+    let remove = match shared.get_mut(&address) {
+        Some(count) => {
+            *count -= 1;
+            *count == 0
+        },
+        None => return false
+    };
+    if remove {
+        shared.remove(&address);
+    }
+    true
+}
+
+/// Flags to use when a page with `flags` is backed by the zero frame or a shared frame
+pub fn zero_flags(flags: EntryFlags) -> EntryFlags {
+    if flags.contains(entry::WRITABLE) {
+        (flags - entry::WRITABLE) | entry::COPY_ON_WRITE
+    } else {
+        flags
+    }
+}
+
+/// Unmap a page, freeing its frame unless it is the zero frame or still mapped elsewhere
+fn unmap_page(mapper: &mut Mapper, page: Page) {
+    let frame = mapper.unmap_return(page);
+    if frame != zero_frame() && ! with_shared(|shared| unshare(shared, &frame)) {
+        deallocate_frame(frame);
+    }
+}
+
+/// Resolve a write to a copy-on-write page. A page backed by the zero frame gets a private, zeroed
+/// frame, a page backed by a shared frame gets a private copy of it, and a page that is the last
+/// mapping of its frame is made writable again
+/// Returns false if the fault could not be resolved, in which case it is a genuine fault
+pub fn copy_on_write(address: VirtualAddress) -> bool {
+    let mut active_table = unsafe { ActivePageTable::new() };
+
+    let page = Page::containing_address(address);
+    match active_table.translate_page_flags(page) {
+        Some(flags) => if ! flags.contains(entry::PRESENT | entry::COPY_ON_WRITE) {
+            return false;
+        },
+        None => return false
+    }
+
+    // Allocated before the lock is taken, as the allocator may have to look for a context to kill
+    let new_frame = match allocate_frame() {
+        Some(frame) => frame,
+        None => return false
+    };
+
+    let (resolved, unused) = with_shared(|shared| {
+        // Another thread of this address space may have resolved the fault in the meantime
+        let flags = active_table.translate_page_flags(page).unwrap_or(EntryFlags::empty());
+        if ! flags.contains(entry::PRESENT | entry::COPY_ON_WRITE) {
+            return (flags.contains(entry::PRESENT | entry::WRITABLE | entry::USER_ACCESSIBLE), Some(new_frame));
+        }
+
+        let frame = active_table.translate_page(page).expect("copy-on-write page not mapped");
+        let new_flags = (flags - entry::COPY_ON_WRITE) | entry::WRITABLE;
+
+        if frame == zero_frame() {
+            active_table.unmap_return(page);
+            active_table.map_to(page, new_frame, new_flags);
+            active_table.flush(page);
+
+            unsafe {
+                intrinsics::write_bytes(page.start_address().get() as *mut u8, 0, PAGE_SIZE);
+            }
+
+            (true, None)
+        } else if is_shared(shared, &frame) {
+            // The page keeps the shared frame until the copy made through the temporary page is done
+            let mut temporary_page = TemporaryPage::new(Page::containing_address(VirtualAddress::new(arch::USER_TMP_COW_OFFSET)));
+            let copy = temporary_page.map(new_frame.clone(), entry::PRESENT | entry::WRITABLE | entry::NO_EXECUTE, &mut active_table);
+            active_table.flush(Page::containing_address(copy));
+            unsafe {
+                intrinsics::copy(page.start_address().get() as *const u8, copy.get() as *mut u8, PAGE_SIZE);
+            }
+            temporary_page.unmap(&mut active_table);
+            active_table.flush(Page::containing_address(copy));
+
+            active_table.unmap_return(page);
+            active_table.map_to(page, new_frame, new_flags);
+            active_table.flush(page);
+
+            unshare(shared, &frame);
+
+            (true, None)
+        } else {
+            // The other mappings are gone, so the frame belongs to this page
+            active_table.remap(page, new_flags);
+            active_table.flush(page);
+
+            (true, Some(new_frame))
+        }
+    });
+
+    if let Some(frame) = unused {
+        deallocate_frame(frame);
+    }
+
+    resolved
+}
+
+/// Give a page of the current address space a frame of its own, if it is copy-on-write, as its
+/// frame is about to be used elsewhere. Returns false if it is still copy-on-write, because no
+/// frame could be allocated
+pub fn make_private(address: VirtualAddress) -> bool {
+    copy_on_write(address);
+
+    let active_table = unsafe { ActivePageTable::new() };
+    active_table.translate_page_flags(Page::containing_address(address)).map_or(true, |flags| {
+        ! flags.contains(entry::PRESENT | entry::COPY_ON_WRITE)
+    })
+}
 
 #[derive(Debug)]
 pub struct Grant {
     start: VirtualAddress,
     size: usize,
-    flags: EntryFlags
+    flags: EntryFlags,
+    /// The device memory this grant maps, if it was made by `physmap`
+    physical: Option<PhysicalAddress>,
+    /// The frames are counted in the share table, so that the last unmap of them frees them
+    shared: bool,
+    /// What the memory belongs to, shown in `sys:<pid>/maps`
+    name: String
 }
 
 impl Grant {
     pub fn physmap(from: PhysicalAddress, to: VirtualAddress, size: usize, flags: EntryFlags) -> Grant {
         let mut active_table = unsafe { ActivePageTable::new() };
 
-        let mut flush_all = false;
-
+        // Large ranges, like framebuffers, use huge pages where they line up
         let start_page = Page::containing_address(to);
-        let end_page = Page::containing_address(VirtualAddress::new(to.get() + size - 1));
-        for page in Page::range_inclusive(start_page, end_page) {
-            let frame = Frame::containing_address(PhysicalAddress::new(page.start_address().get() - to.get() + from.get()));
-            active_table.map_to(page, frame, flags);
-            flush_all = true;
-        }
-
-        if flush_all {
+        let start_frame = Frame::containing_address(from);
+        let count = (to.get() % PAGE_SIZE + size + PAGE_SIZE - 1) / PAGE_SIZE;
+        if count > 0 {
+            active_table.map_range_to(start_page, start_frame, count, flags);
             active_table.flush_all();
         }
 
         Grant {
             start: to,
             size: size,
-            flags: flags
+            flags: flags,
+            physical: Some(from),
+            shared: false,
+            name: String::new()
         }
     }
 
-    pub fn map_inactive(from: VirtualAddress, to: VirtualAddress, size: usize, flags: EntryFlags, new_table: &mut InactivePageTable, temporary_page: &mut TemporaryPage) -> Grant {
+    pub fn map_inactive(from: VirtualAddress, to: VirtualAddress, size: usize, flags: EntryFlags, name: String, new_table: &mut InactivePageTable, temporary_page: &mut TemporaryPage) -> Result<Grant> {
         let mut active_table = unsafe { ActivePageTable::new() };
 
         let mut frames = VecDeque::new();
@@ -48,6 +227,11 @@ impl Grant {
         let start_page = Page::containing_address(from);
         let end_page = Page::containing_address(VirtualAddress::new(from.get() + size - 1));
         for page in Page::range_inclusive(start_page, end_page) {
+            // A writable grant must not share the zero frame
+            if flags.contains(entry::WRITABLE) && ! make_private(page.start_address()) {
+                return Err(Error::new(ENOMEM));
+            }
+
             let frame = active_table.translate_page(page).expect("grant references unmapped memory");
             frames.push_back(frame);
         }
@@ -61,10 +245,37 @@ impl Grant {
             }
         });
 
-        Grant {
+        Ok(Grant {
             start: to,
             size: size,
-            flags: flags
+            flags: flags,
+            physical: None,
+            shared: false,
+            name: name
+        })
+    }
+
+    /// Map `frames` at `to` in the current address space, adding a mapping of each to the share
+    /// table. The frames are freed when the last mapping is unmapped
+    pub fn share_frames(frames: &[Frame], to: VirtualAddress, flags: EntryFlags, name: String) -> Grant {
+        let mut active_table = unsafe { ActivePageTable::new() };
+
+        with_shared(|shared| {
+            for (i, frame) in frames.iter().enumerate() {
+                share(shared, frame);
+                let page = Page::containing_address(VirtualAddress::new(to.get() + i * PAGE_SIZE));
+                active_table.map_to(page, frame.clone(), flags);
+            }
+        });
+        active_table.flush_all();
+
+        Grant {
+            start: to,
+            size: frames.len() * PAGE_SIZE,
+            flags: flags,
+            physical: None,
+            shared: true,
+            name: name
         }
     }
 
@@ -80,19 +291,27 @@ impl Grant {
         self.flags
     }
 
+    pub fn physical_address(&self) -> Option<PhysicalAddress> {
+        self.physical
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
     pub fn unmap(self) {
         let mut active_table = unsafe { ActivePageTable::new() };
 
-        let mut flush_all = false;
-
         let start_page = Page::containing_address(self.start);
-        let end_page = Page::containing_address(VirtualAddress::new(self.start.get() + self.size - 1));
-        for page in Page::range_inclusive(start_page, end_page) {
-            active_table.unmap_return(page);
-            flush_all = true;
-        }
-
-        if flush_all {
+        let count = (self.start.get() % PAGE_SIZE + self.size + PAGE_SIZE - 1) / PAGE_SIZE;
+        if count > 0 {
+            if self.shared {
+                for i in 0..count {
+                    unmap_page(&mut active_table, Page::containing_address(VirtualAddress::new(start_page.start_address().get() + i * PAGE_SIZE)));
+                }
+            } else {
+                active_table.unmap_range_return(start_page, count);
+            }
             active_table.flush_all();
         }
     }
@@ -102,9 +321,13 @@ impl Grant {
 
         active_table.with(new_table, temporary_page, |mapper| {
             let start_page = Page::containing_address(self.start);
-            let end_page = Page::containing_address(VirtualAddress::new(self.start.get() + self.size - 1));
-            for page in Page::range_inclusive(start_page, end_page) {
-                mapper.unmap_return(page);
+            let count = (self.start.get() % PAGE_SIZE + self.size + PAGE_SIZE - 1) / PAGE_SIZE;
+            if self.shared {
+                for i in 0..count {
+                    unmap_page(mapper, Page::containing_address(VirtualAddress::new(start_page.start_address().get() + i * PAGE_SIZE)));
+                }
+            } else {
+                mapper.unmap_range_return(start_page, count);
             }
         });
     }
@@ -186,9 +409,18 @@ impl Memory {
 
         let mut flush_all = false;
 
-        //TODO: Clear pages?
+        // Cleared memory is backed by the zero frame, and pages get a frame when they are first
+        // written, by the kernel filling them or by userspace
+        if clear {
+            assert!(flush && self.flags.contains(entry::WRITABLE));
+        }
+
         for page in self.pages() {
-            active_table.map(page, self.flags);
+            if clear {
+                active_table.map_to(page, zero_frame(), zero_flags(self.flags));
+            } else {
+                active_table.map(page, self.flags);
+            }
 
             if flush {
                 //active_table.flush(page);
@@ -199,13 +431,6 @@ impl Memory {
         if flush_all {
             active_table.flush_all();
         }
-
-        if clear {
-            assert!(flush && self.flags.contains(entry::WRITABLE));
-            unsafe {
-                intrinsics::write_bytes(self.start_address().get() as *mut u8, 0, self.size);
-            }
-        }
     }
 
     fn unmap(&mut self, flush: bool) {
@@ -214,7 +439,7 @@ impl Memory {
         let mut flush_all = false;
 
         for page in self.pages() {
-            active_table.unmap(page);
+            unmap_page(&mut active_table, page);
 
             if flush {
                 //active_table.flush(page);
@@ -227,6 +452,35 @@ impl Memory {
         }
     }
 
+    /// Map the frames of this memory again at `new_start`, copy-on-write in both places, for a fork.
+    /// The new memory is then moved to the new page table, and the first write to a page on either
+    /// side copies it
+    pub fn share(&self, new_start: VirtualAddress, flush: bool) -> Memory {
+        let mut active_table = unsafe { ActivePageTable::new() };
+
+        let flags = zero_flags(self.flags);
+        with_shared(|shared| {
+            for page in self.pages() {
+                let frame = active_table.translate_page(page).expect("memory not mapped");
+                share(shared, &frame);
+
+                active_table.remap(page, flags);
+                let new_page = Page::containing_address(VirtualAddress::new(page.start_address().get() - self.start.get() + new_start.get()));
+                active_table.map_to(new_page, frame, flags);
+            }
+        });
+
+        if flush {
+            active_table.flush_all();
+        }
+
+        Memory {
+            start: new_start,
+            size: self.size,
+            flags: self.flags
+        }
+    }
+
     /// A complicated operation to move a piece of memory to a new page table
     /// It also allows for changing the address at the same time
     pub fn move_to(&mut self, new_start: VirtualAddress, new_table: &mut InactivePageTable, temporary_page: &mut TemporaryPage, flush: bool) {
@@ -236,10 +490,15 @@ impl Memory {
 
         for page in self.pages() {
             let frame = active_table.unmap_return(page);
+            let flags = if with_shared(|shared| is_shared(shared, &frame)) {
+                zero_flags(self.flags)
+            } else {
+                self.flags
+            };
 
             active_table.with(new_table, temporary_page, |mapper| {
                 let new_page = Page::containing_address(VirtualAddress::new(page.start_address().get() - self.start.get() + new_start.get()));
-                mapper.map_to(new_page, frame, self.flags);
+                mapper.map_to(new_page, frame, flags);
             });
 
             if flush {
@@ -261,7 +520,12 @@ impl Memory {
         let mut flush_all = false;
 
         for page in self.pages() {
-            active_table.remap(page, new_flags);
+            let shared = active_table.translate_page(page).map_or(false, |frame| with_shared(|shared| is_shared(shared, &frame)));
+            if shared {
+                active_table.remap(page, zero_flags(new_flags));
+            } else {
+                active_table.remap(page, new_flags);
+            }
 
             if flush {
                 //active_table.flush(page);
@@ -285,9 +549,15 @@ impl Memory {
 
             let start_page = Page::containing_address(VirtualAddress::new(self.start.get() + self.size));
             let end_page = Page::containing_address(VirtualAddress::new(self.start.get() + new_size - 1));
+            // Cleared, writable pages are backed by the zero frame until they are written
+            let zero = clear && self.flags.contains(entry::WRITABLE);
             for page in Page::range_inclusive(start_page, end_page) {
                 if active_table.translate_page(page).is_none() {
-                    active_table.map(page, self.flags);
+                    if zero {
+                        active_table.map_to(page, zero_frame(), zero_flags(self.flags));
+                    } else {
+                        active_table.map(page, self.flags);
+                    }
 
                     if flush {
                         //active_table.flush(page);
@@ -302,8 +572,22 @@ impl Memory {
 
             if clear {
                 assert!(flush);
-                unsafe {
-                    intrinsics::write_bytes((self.start.get() + self.size) as *mut u8, 0, new_size - self.size);
+                let clear_start = self.start.get() + self.size;
+                let clear_end = if zero {
+                    // Only the remainder of the last page that was already mapped needs clearing
+                    let clear_page = Page::containing_address(VirtualAddress::new(clear_start));
+                    if active_table.translate_page(clear_page) == Some(zero_frame()) {
+                        clear_start
+                    } else {
+                        cmp::min(self.start.get() + new_size, (clear_start + PAGE_SIZE - 1)/PAGE_SIZE * PAGE_SIZE)
+                    }
+                } else {
+                    self.start.get() + new_size
+                };
+                if clear_end > clear_start {
+                    unsafe {
+                        intrinsics::write_bytes(clear_start as *mut u8, 0, clear_end - clear_start);
+                    }
                 }
             }
         } else if new_size < self.size {
@@ -313,7 +597,7 @@ impl Memory {
             let end_page = Page::containing_address(VirtualAddress::new(self.start.get() + self.size - 1));
             for page in Page::range_inclusive(start_page, end_page) {
                 if active_table.translate_page(page).is_some() {
-                    active_table.unmap(page);
+                    unmap_page(&mut active_table, page);
 
                     if flush {
                         //active_table.flush(page);
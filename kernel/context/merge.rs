@@ -0,0 +1,274 @@
+//! Merging of identical pages
+//!
+//! Contexts opt in through `sys:<pid>/merge`. The `[merge]` kernel thread hashes the pages of the
+//! image, heap, and stack of each of them, and maps pages with the same contents to one frame,
+//! copy-on-write, freeing the others. The first write to a merged page copies it again
+//!
+//! A CPU may keep the pages of the address space it runs in its TLB, and another context may share
+//! the address space of one that is not running. A page table is only changed while every context
+//! that uses it is locked and none of them is running, so none of them can be switched to until
+//! the change is done
+
+use collections::Vec;
+use core::slice;
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+use spin::RwLockReadGuard;
+
+use arch;
+use arch::memory::{deallocate_frame, Frame};
+use arch::paging::{ActivePageTable, InactivePageTable, Page, VirtualAddress, PAGE_SIZE};
+use arch::paging::entry;
+use arch::paging::temporary_page::TemporaryPage;
+use context::{self, Context, ContextList};
+use context::memory::{share, unshare, with_shared, zero_flags, zero_frame};
+
+/// Seconds between scans
+pub const MERGE_INTERVAL: u64 = 10;
+
+/// Number of pages that have been merged. Some may have been copied again since
+pub static MERGED_PAGES: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// A page of a context that allows merging
+struct Candidate {
+    pid: usize,
+    page: Page,
+    frame: Frame,
+    hash: u64
+}
+
+/// The temporary page used to reach the page tables of other contexts
+fn table_page() -> TemporaryPage {
+    TemporaryPage::new(Page::containing_address(VirtualAddress::new(arch::USER_TMP_MERGE_OFFSET)))
+}
+
+/// Map `frame` read only through the temporary page at `index`, after the one for page tables
+fn map_frame(active_table: &mut ActivePageTable, frame: &Frame, index: usize) -> (TemporaryPage, &'static [u8]) {
+    let mut temporary_page = TemporaryPage::new(Page::containing_address(VirtualAddress::new(arch::USER_TMP_MERGE_OFFSET + (index + 1) * PAGE_SIZE)));
+    let address = temporary_page.map(frame.clone(), entry::PRESENT | entry::NO_EXECUTE, active_table);
+    active_table.flush(Page::containing_address(address));
+    (temporary_page, unsafe { slice::from_raw_parts(address.get() as *const u8, PAGE_SIZE) })
+}
+
+fn unmap_frame(active_table: &mut ActivePageTable, mut temporary_page: TemporaryPage) {
+    temporary_page.unmap(active_table);
+    active_table.flush(Page::containing_address(temporary_page.start_address()));
+}
+
+/// FNV-1a hash of the contents of a frame
+fn hash_frame(active_table: &mut ActivePageTable, frame: &Frame) -> u64 {
+    let (temporary_page, data) = map_frame(active_table, frame, 0);
+    let mut hash = 0xcbf29ce484222325;
+    for &b in data.iter() {
+        hash = (hash ^ b as u64).wrapping_mul(0x100000001b3);
+    }
+    unmap_frame(active_table, temporary_page);
+    hash
+}
+
+fn same_contents(active_table: &mut ActivePageTable, a: &Frame, b: &Frame) -> bool {
+    let (temporary_page_a, data_a) = map_frame(active_table, a, 0);
+    let (temporary_page_b, data_b) = map_frame(active_table, b, 1);
+    let same = data_a == data_b;
+    unmap_frame(active_table, temporary_page_b);
+    unmap_frame(active_table, temporary_page_a);
+    same
+}
+
+/// Add the mapped pages of a context to `candidates`, unless it has not opted in, or its page
+/// table is one of `skip`
+fn find_candidates(active_table: &mut ActivePageTable, pid: usize, skip: &[usize], candidates: &mut Vec<Candidate>) {
+    let contexts = context::contexts();
+    let context_lock = match contexts.get(pid) {
+        Some(context_lock) => context_lock,
+        None => return
+    };
+    let context = context_lock.read();
+    let table = context.arch.get_page_table();
+    if ! context.merge || context.running || skip.contains(&table) {
+        return;
+    }
+
+    let mut pages = Vec::new();
+    for memory in context.image.iter() {
+        memory.with(|memory| pages.extend(memory.pages()));
+    }
+    if let Some(ref heap) = context.heap {
+        heap.with(|heap| pages.extend(heap.pages()));
+    }
+    if let Some(ref stack) = context.stack {
+        pages.extend(stack.pages());
+    }
+
+    let mut frames = Vec::new();
+    let mut new_table = unsafe { InactivePageTable::from_address(table) };
+    let mut temporary_page = table_page();
+    active_table.with(&mut new_table, &mut temporary_page, |mapper| {
+        for page in pages {
+            if let Some(frame) = mapper.translate_page(page) {
+                if frame != zero_frame() {
+                    frames.push((page, frame));
+                }
+            }
+        }
+    });
+
+    for (page, frame) in frames {
+        let hash = hash_frame(active_table, &frame);
+        candidates.push(Candidate {
+            pid: pid,
+            page: page,
+            frame: frame,
+            hash: hash
+        });
+    }
+}
+
+/// Make the page of a candidate copy-on-write, returning false if it has been given another frame
+/// since it was found
+fn protect(active_table: &mut ActivePageTable, table: usize, candidate: &Candidate) -> bool {
+    let mut new_table = unsafe { InactivePageTable::from_address(table) };
+    let mut temporary_page = table_page();
+    let mut protected = false;
+    active_table.with(&mut new_table, &mut temporary_page, |mapper| {
+        if mapper.translate_page(candidate.page).as_ref() == Some(&candidate.frame) {
+            if let Some(flags) = mapper.translate_page_flags(candidate.page) {
+                mapper.remap(candidate.page, zero_flags(flags));
+                protected = true;
+            }
+        }
+    });
+    protected
+}
+
+/// Lock every context that uses one of `tables`, so that none of them is switched to. Returns
+/// None if one of them is running, or if a context could not be locked to find its table
+fn lock_users<'a>(contexts: &'a ContextList, tables: &[usize]) -> Option<Vec<RwLockReadGuard<'a, Context>>> {
+    let mut users = Vec::new();
+    for (_id, context_lock) in contexts.iter() {
+        let context = match context_lock.try_read() {
+            Some(context) => context,
+            None => return None
+        };
+        if tables.contains(&context.arch.get_page_table()) {
+            if context.running {
+                return None;
+            }
+            users.push(context);
+        }
+    }
+    Some(users)
+}
+
+/// Map the page of `b` to the frame of `a`, if both are unchanged and still have the same contents
+fn merge(active_table: &mut ActivePageTable, a: &Candidate, b: &Candidate, skip: &[usize]) -> bool {
+    let contexts = context::contexts();
+    let (table_a, table_b) = match (contexts.get(a.pid), contexts.get(b.pid)) {
+        (Some(context_a), Some(context_b)) => (context_a.read().arch.get_page_table(), context_b.read().arch.get_page_table()),
+        _ => return false
+    };
+    if skip.contains(&table_a) || skip.contains(&table_b) {
+        return false;
+    }
+
+    // Held until the tables are changed
+    let users = match lock_users(&contexts, &[table_a, table_b]) {
+        Some(users) => users,
+        None => return false
+    };
+    if ! users.iter().any(|context| context.id == a.pid && context.merge)
+        || ! users.iter().any(|context| context.id == b.pid && context.merge) {
+        return false;
+    }
+
+    // Once both pages are read only, neither can change before the frame of `b` is replaced.
+    // If they are no longer the same, their next write makes them writable again
+    if ! protect(active_table, table_a, a) || ! protect(active_table, table_b, b)
+        || ! same_contents(active_table, &a.frame, &b.frame) {
+        return false;
+    }
+
+    let free = with_shared(|shared| {
+        let mut new_table = unsafe { InactivePageTable::from_address(table_b) };
+        let mut temporary_page = table_page();
+        active_table.with(&mut new_table, &mut temporary_page, |mapper| {
+            let flags = mapper.translate_page_flags(b.page).expect("merged page not mapped");
+            mapper.unmap_return(b.page);
+            mapper.map_to(b.page, a.frame.clone(), flags);
+        });
+
+        share(shared, &a.frame);
+        ! unshare(shared, &b.frame)
+    });
+
+    drop(users);
+
+    if free {
+        deallocate_frame(b.frame.clone());
+    }
+
+    true
+}
+
+/// Merge the identical pages of contexts that allow it, returning the number of pages merged
+pub fn scan() -> usize {
+    let mut active_table = unsafe { ActivePageTable::new() };
+
+    // Address spaces that are in use now are left alone, and the others are checked again before
+    // each merge
+    let mut pids = Vec::new();
+    let mut skip = Vec::new();
+    {
+        let contexts = context::contexts();
+        for (pid, context_lock) in contexts.iter() {
+            let context = context_lock.read();
+            if context.merge {
+                pids.push(*pid);
+            }
+            if context.running {
+                skip.push(context.arch.get_page_table());
+            }
+        }
+    }
+
+    let mut candidates = Vec::new();
+    for pid in pids {
+        find_candidates(&mut active_table, pid, &skip, &mut candidates);
+    }
+    candidates.sort_by_key(|candidate| candidate.hash);
+
+    // The first page with a hash keeps its frame, and the others are merged into it
+    let mut merged = 0;
+    let mut i = 0;
+    while i < candidates.len() {
+        let mut j = i + 1;
+        while j < candidates.len() && candidates[j].hash == candidates[i].hash {
+            if candidates[j].frame != candidates[i].frame && merge(&mut active_table, &candidates[i], &candidates[j], &skip) {
+                merged += 1;
+            }
+            j += 1;
+        }
+        i = j;
+    }
+
+    MERGED_PAGES.fetch_add(merged, Ordering::SeqCst);
+    merged
+}
+
+/// The `[merge]` kernel thread, which scans every `MERGE_INTERVAL` seconds
+pub extern fn merge_thread() {
+    loop {
+        scan();
+
+        {
+            let contexts = context::contexts();
+            if let Some(context_lock) = contexts.current() {
+                let mut context = context_lock.write();
+                let current = arch::time::monotonic();
+                context.wake = Some((current.0 + MERGE_INTERVAL, current.1));
+                context.block();
+            }
+        }
+
+        unsafe { context::switch(); }
+    }
+}
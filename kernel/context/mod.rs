@@ -10,6 +10,15 @@ pub use self::switch::switch;
 /// Context struct
 mod context;
 
+/// Saving user contexts, and starting them again
+pub mod checkpoint;
+
+/// Core dumps of crashed user contexts
+pub mod coredump;
+
+/// Kernel stacks
+pub mod kstack;
+
 /// Context list
 mod list;
 
@@ -22,9 +31,36 @@ pub mod event;
 /// File struct - defines a scheme and a file number
 pub mod file;
 
+/// Stopping user contexts for suspend and checkpointing
+pub mod freezer;
+
 /// Memory struct - contains a set of pages for a context
 pub mod memory;
 
+/// Merging of identical pages of contexts that allow it
+pub mod merge;
+
+/// Out of memory handling
+pub mod oom;
+
+/// PID namespaces
+pub mod pidns;
+
+/// Memory pressure levels
+pub mod pressure;
+
+/// Per-CPU run queues and load balancing
+pub mod sched;
+
+/// Syscall tracing
+pub mod trace;
+
+/// Resource usage of contexts and the children they reaped
+pub mod usage;
+
+/// Working set estimation from accessed bits
+pub mod working_set;
+
 /// Limit on number of contexts
 pub const CONTEXT_MAX_CONTEXTS: usize = usize::max_value() - 1;
 
@@ -39,7 +75,7 @@ static CONTEXT_ID: AtomicUsize = ATOMIC_USIZE_INIT;
 
 pub fn init() {
     let mut contexts = contexts_mut();
-    let context_lock = contexts.new_context().expect("could not initialize first context");
+    let context_lock = contexts.new_context().expect("could not initialize first context").clone();
     let mut context = context_lock.write();
     let mut fx = unsafe { Box::from_raw(::alloc::heap::allocate(512, 16) as *mut [u8; 512]) };
     for b in fx.iter_mut() {
@@ -51,6 +87,8 @@ pub fn init() {
     context.status = Status::Runnable;
     context.running = true;
     context.cpu_id = Some(::cpu_id());
+    sched::init();
+    sched::place(&mut context, &contexts);
     CONTEXT_ID.store(context.id, Ordering::SeqCst);
 }
 
@@ -64,6 +102,11 @@ pub fn contexts() -> RwLockReadGuard<'static, ContextList> {
     CONTEXTS.call_once(init_contexts).read()
 }
 
+/// Get the global schemes list, const, if it is not locked for writing
+pub fn try_contexts() -> Option<RwLockReadGuard<'static, ContextList>> {
+    CONTEXTS.call_once(init_contexts).try_read()
+}
+
 /// Get the global schemes list, mutable
 pub fn contexts_mut() -> RwLockWriteGuard<'static, ContextList> {
     CONTEXTS.call_once(init_contexts).write()
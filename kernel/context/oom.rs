@@ -0,0 +1,241 @@
+use alloc::arc::Arc;
+use collections::Vec;
+use core::str;
+
+use arch;
+use arch::paging::{ActivePageTable, InactivePageTable, Page, VirtualAddress, PAGE_SIZE};
+use arch::paging::temporary_page::TemporaryPage;
+use context::{self, sched, Context, Status};
+use context::memory::{is_shared, with_shared, SharedMemory};
+use syscall::flag::SIGKILL;
+
+/// The number of pages mapped for a context, including pages backed by the zero frame or shared
+/// copy-on-write. The page tables are not walked, so this can be used by the frame allocator, which
+/// may be in the middle of changing one
+pub fn mapped_pages(context: &Context) -> usize {
+    fn shared_size(shared: &SharedMemory) -> usize {
+        // Borrowed memory belongs to another context, and locked memory may be in use by the allocator
+        if let SharedMemory::Owned(ref memory_lock) = *shared {
+            if let Some(memory) = memory_lock.try_lock() {
+                return memory.size();
+            }
+        }
+        0
+    }
+
+    let mut size = 0;
+    for shared_mem in context.image.iter() {
+        size += shared_size(shared_mem);
+    }
+    if let Some(ref heap) = context.heap {
+        size += shared_size(heap);
+    }
+    if let Some(ref stack) = context.stack {
+        size += stack.size();
+    }
+    if let Some(ref tls) = context.tls {
+        size += tls.mem.size();
+    }
+    (size + PAGE_SIZE - 1)/PAGE_SIZE
+}
+
+/// The number of frames mapped only by a context, which it would release on exit. Pages backed by
+/// the zero frame, or by frames shared copy-on-write, are left out. The page table of the context
+/// is walked, so this must not be called by the frame allocator
+pub fn resident_pages(context: &Context) -> usize {
+    let mut pages = Vec::new();
+    // Borrowed memory belongs to another context
+    for shared_mem in context.image.iter() {
+        if let SharedMemory::Owned(ref memory_lock) = *shared_mem {
+            pages.extend(memory_lock.lock().pages());
+        }
+    }
+    if let Some(SharedMemory::Owned(ref memory_lock)) = context.heap {
+        pages.extend(memory_lock.lock().pages());
+    }
+    if let Some(ref stack) = context.stack {
+        pages.extend(stack.pages());
+    }
+    if let Some(ref tls) = context.tls {
+        pages.extend(tls.mem.pages());
+    }
+    if pages.is_empty() {
+        return 0;
+    }
+
+    let mut frames = Vec::new();
+    let mut active_table = unsafe { ActivePageTable::new() };
+    let table = context.arch.get_page_table();
+    if table == unsafe { active_table.address() } {
+        for page in pages {
+            if let Some(frame) = active_table.translate_page(page) {
+                frames.push(frame);
+            }
+        }
+    } else {
+        let mut new_table = unsafe { InactivePageTable::from_address(table) };
+        let mut temporary_page = TemporaryPage::new(Page::containing_address(VirtualAddress::new(arch::USER_TMP_RSS_OFFSET)));
+        active_table.with(&mut new_table, &mut temporary_page, |mapper| {
+            for page in pages {
+                if let Some(frame) = mapper.translate_page(page) {
+                    frames.push(frame);
+                }
+            }
+        });
+    }
+
+    with_shared(|shared| frames.iter().filter(|frame| ! is_shared(shared, frame)).count())
+}
+
+/// How good a victim this context would make, zero if it must never be killed
+pub fn badness(context: &Context) -> usize {
+    // Kernel contexts, protected contexts, and contexts already on their way out are spared
+    if context.stack.is_none() || context.oom_protected || context.killed.is_some() {
+        return 0;
+    }
+    if let Status::Exited(_) = context.status {
+        return 0;
+    }
+
+    let mut points = mapped_pages(context);
+
+    // Privileged contexts are more likely to be doing something important
+    if context.euid == 0 {
+        points /= 4;
+    }
+
+    points
+}
+
+/// Nanoseconds that an allocation waits for its victim to exit
+pub const OOM_WAIT_MAX: u64 = 1000000000;
+
+/// Select the context with the highest badness, and mark it to be killed. It is woken if it is
+/// blocked, and its CPU is interrupted if it is running, so that it exits as soon as it can
+fn kill_victim(count: usize) -> Option<usize> {
+    // The allocator may be called with these locks held, so they must not be waited on
+    let contexts = match context::try_contexts() {
+        Some(contexts) => contexts,
+        None => {
+            println!("OOM: {} frames requested, context list locked", count);
+            return None;
+        }
+    };
+
+    let mut victim = None;
+    let mut victim_points = 0;
+    for (id, context_lock) in contexts.iter() {
+        if let Some(context) = context_lock.try_read() {
+            let points = badness(&context);
+            if points > victim_points {
+                victim = Some(*id);
+                victim_points = points;
+            }
+        }
+    }
+
+    if let Some(context_lock) = victim.and_then(|id| contexts.get(id)) {
+        if let Some(mut context) = context_lock.try_write() {
+            {
+                let name = context.name.lock();
+                println!("OOM: {} frames requested, killing PID {} ({}) with badness {}",
+                         count, context.id, str::from_utf8(&name).unwrap_or(""), victim_points);
+            }
+            context.killed = Some(SIGKILL);
+            if ! context.unblock() && context.running {
+                if let Some(cpu_id) = context.cpu_id {
+                    sched::reschedule(cpu_id);
+                }
+            }
+            return Some(context.id);
+        }
+    }
+
+    println!("OOM: {} frames requested, no victim found", count);
+    None
+}
+
+/// Check if the current context may switch away while it allocates. It must be in `allow_wait`,
+/// as other CPUs would spin on any lock it holds, and must not hold the context list or itself. It
+/// must be a user context that does not share its address space, as its page table may be in the
+/// middle of being changed
+fn can_wait() -> bool {
+    if arch::interrupt::level::current() > arch::interrupt::level::LEVEL_PASSIVE {
+        return false;
+    }
+
+    let contexts = match context::try_contexts() {
+        Some(contexts) => contexts,
+        None => return false
+    };
+    let context_lock = match contexts.current() {
+        Some(context_lock) => context_lock,
+        None => return false
+    };
+    let can_wait = match context_lock.try_write() {
+        Some(context) => context.oom_wait && context.stack.is_some() && Arc::strong_count(&context.grants) == 1,
+        None => false
+    };
+    can_wait
+}
+
+/// Run `f`, which allocates while holding no locks, allowing the OOM killer to switch away while
+/// it waits for its victim. Other allocations kill a victim and fail at once
+pub fn allow_wait<T, F: FnOnce() -> T>(f: F) -> T {
+    set_wait(true);
+    let result = f();
+    set_wait(false);
+    result
+}
+
+fn set_wait(oom_wait: bool) {
+    let contexts = context::contexts();
+    if let Some(context_lock) = contexts.current() {
+        context_lock.write().oom_wait = oom_wait;
+    }
+}
+
+/// Wait until the context `victim` has exited and released its memory, or `OOM_WAIT_MAX` has
+/// passed, as the allocation may hold a lock that the victim needs on its way out. Returns true if
+/// it exited
+fn wait_for(victim: usize) -> bool {
+    if victim == context::context_id() || ! can_wait() {
+        return false;
+    }
+
+    let (seconds, nanoseconds) = arch::time::monotonic();
+    let end = seconds * 1000000000 + nanoseconds + OOM_WAIT_MAX;
+    loop {
+        {
+            let contexts = context::contexts();
+            match contexts.get(victim) {
+                Some(context_lock) => if let Status::Exited(_) = context_lock.read().status {
+                    return true;
+                },
+                None => return true
+            }
+        }
+
+        let (seconds, nanoseconds) = arch::time::monotonic();
+        if seconds * 1000000000 + nanoseconds >= end {
+            println!("OOM: PID {} did not exit in time", victim);
+            return false;
+        }
+
+        if ! unsafe { context::switch() } {
+            arch::interrupt::pause();
+        }
+    }
+}
+
+/// Called when the frame allocator is exhausted. Kills the context with the highest badness, and
+/// waits for it to exit if the current context can, so that the allocation can be retried.
+/// Returns true if memory was released
+pub fn out_of_memory(count: usize) -> bool {
+    context::pressure::reclaimed();
+
+    match kill_victim(count) {
+        Some(victim) => wait_for(victim),
+        None => false
+    }
+}
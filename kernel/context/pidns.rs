@@ -0,0 +1,154 @@
+//! # PID namespaces
+//! A context started after `sys:<pid>/pid_namespace` is set for its parent is the init of a new
+//! namespace, and it and the contexts it starts see their own PIDs, with the init as PID 1. A
+//! namespace may be inside another one, and contexts in it also have a PID in each outer namespace.
+//! Contexts in the root namespace see the IDs of the context list. Contexts outside of a
+//! namespace that a context can see have no PID in it, so they cannot be named
+//!
+//! Orphans in a namespace are given to its init, and when the init exits, the rest of the
+//! namespace is killed
+
+use alloc::arc::Arc;
+use collections::BTreeMap;
+use spin::Mutex;
+
+use context::{self, Status};
+use syscall::flag::SIGKILL;
+
+/// PIDs of the contexts in a namespace and the ones inside it
+#[derive(Debug)]
+struct Pids {
+    /// PID in this namespace of each context, by ID
+    local: BTreeMap<usize, usize>,
+    /// ID of each context, by PID in this namespace
+    global: BTreeMap<usize, usize>,
+    /// The next PID to give out
+    next: usize
+}
+
+/// A PID namespace
+#[derive(Debug)]
+pub struct PidNamespace {
+    /// ID of the first context of the namespace
+    pub init: usize,
+    /// The namespace this one was created in, None if it is the root namespace
+    pub parent: Option<Arc<PidNamespace>>,
+    pids: Mutex<Pids>
+}
+
+impl PidNamespace {
+    /// A namespace inside `parent`, with the context `init` as its first context
+    pub fn new(parent: Option<Arc<PidNamespace>>, init: usize) -> PidNamespace {
+        PidNamespace {
+            init: init,
+            parent: parent,
+            pids: Mutex::new(Pids {
+                local: BTreeMap::new(),
+                global: BTreeMap::new(),
+                next: 1
+            })
+        }
+    }
+
+    /// Check if this namespace is `other`, or is inside of it
+    pub fn is_inside(&self, other: &PidNamespace) -> bool {
+        if self as *const PidNamespace == other as *const PidNamespace {
+            true
+        } else if let Some(ref parent) = self.parent {
+            parent.is_inside(other)
+        } else {
+            false
+        }
+    }
+}
+
+/// Give the context `id` a PID in the namespace `ns`, and each namespace it is in
+pub fn register(ns: &Option<Arc<PidNamespace>>, id: usize) {
+    let mut ns_option = ns.clone();
+    while let Some(ns) = ns_option {
+        {
+            let mut pids = ns.pids.lock();
+            let pid = pids.next;
+            pids.next += 1;
+            pids.local.insert(id, pid);
+            pids.global.insert(pid, id);
+        }
+        ns_option = ns.parent.clone();
+    }
+}
+
+/// Remove the PIDs of the context `id`, after it was reaped
+pub fn unregister(ns: &Option<Arc<PidNamespace>>, id: usize) {
+    let mut ns_option = ns.clone();
+    while let Some(ns) = ns_option {
+        {
+            let mut pids = ns.pids.lock();
+            if let Some(pid) = pids.local.remove(&id) {
+                pids.global.remove(&pid);
+            }
+        }
+        ns_option = ns.parent.clone();
+    }
+}
+
+/// The PID of the context `id` as seen from `ns`, None if it is outside of it
+pub fn to_local(ns: &Option<Arc<PidNamespace>>, id: usize) -> Option<usize> {
+    match *ns {
+        Some(ref ns) => ns.pids.lock().local.get(&id).map(|&pid| pid),
+        None => Some(id)
+    }
+}
+
+/// The ID of the context with the PID `pid` as seen from `ns`, None if there is none
+pub fn to_global(ns: &Option<Arc<PidNamespace>>, pid: usize) -> Option<usize> {
+    match *ns {
+        Some(ref ns) => ns.pids.lock().global.get(&pid).map(|&id| id),
+        None => Some(pid)
+    }
+}
+
+/// The namespace of the current context
+pub fn current() -> Option<Arc<PidNamespace>> {
+    let contexts = context::contexts();
+    contexts.current().and_then(|context_lock| context_lock.read().pid_ns.clone())
+}
+
+/// The ID of the context with the PID `pid` as seen from the current context
+pub fn resolve(pid: usize) -> Option<usize> {
+    to_global(&current(), pid)
+}
+
+/// The context that the orphans of the context `id` are given to: the init of its namespace, if
+/// that is still running and is not `id`, otherwise the parent `ppid`
+pub fn reaper(ns: &Option<Arc<PidNamespace>>, id: usize, ppid: usize) -> usize {
+    if let Some(ref ns) = *ns {
+        if ns.init != id {
+            let contexts = context::contexts();
+            if let Some(context_lock) = contexts.get(ns.init) {
+                if let Status::Exited(_) = context_lock.read().status {
+                } else {
+                    return ns.init;
+                }
+            }
+        }
+    }
+    ppid
+}
+
+/// Kill every context in `ns`, and the namespaces inside it, other than its init. The namespace
+/// ends when its init exits
+pub fn kill(ns: &PidNamespace) {
+    let contexts = context::contexts();
+    for (&id, context_lock) in contexts.iter() {
+        if id == ns.init {
+            continue;
+        }
+
+        let mut context = context_lock.write();
+        let inside = context.pid_ns.as_ref().map_or(false, |other| other.is_inside(ns));
+        if inside && context.killed.is_none() {
+            context.killed = Some(SIGKILL);
+            context.unblock();
+        }
+    }
+}
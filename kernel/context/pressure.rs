@@ -0,0 +1,89 @@
+//! Memory pressure, for userspace caches to shed memory before the out of memory killer acts
+//!
+//! The level is derived from the share of frames that are free, and is raised to critical when the
+//! allocator has run out and the killer was called. Changes are sent as events on
+//! `memory:pressure`
+
+use core::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+
+use arch;
+use arch::memory::{free_frames, used_frames};
+use context;
+use scheme::memory::MEMORY_SCHEME_ID;
+use syscall::flag::EVENT_READ;
+
+pub const PRESSURE_NONE: usize = 0;
+pub const PRESSURE_LOW: usize = 1;
+pub const PRESSURE_MEDIUM: usize = 2;
+pub const PRESSURE_CRITICAL: usize = 3;
+
+/// Percent of frames free below which each level is reached, from low to critical
+const WATERMARKS: [usize; 3] = [20, 10, 5];
+
+/// Milliseconds between checks of the level
+pub const PRESSURE_INTERVAL: u64 = 250;
+
+/// The level, as of the last check
+static LEVEL: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Set when the out of memory killer is called, until the next check
+static RECLAIMED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// The current level
+pub fn level() -> usize {
+    LEVEL.load(Ordering::SeqCst)
+}
+
+/// The name of a level
+pub fn level_name(level: usize) -> &'static str {
+    match level {
+        PRESSURE_LOW => "low",
+        PRESSURE_MEDIUM => "medium",
+        PRESSURE_CRITICAL => "critical",
+        _ => "none"
+    }
+}
+
+/// Record that the allocator ran out, which makes the next check critical. This is called by the
+/// allocator, so it only sets a flag
+pub fn reclaimed() {
+    RECLAIMED.store(true, Ordering::SeqCst);
+}
+
+/// Find the level, sending an event to the watchers of `memory:pressure` if it changed
+pub fn check() -> usize {
+    let level = if RECLAIMED.swap(false, Ordering::SeqCst) {
+        PRESSURE_CRITICAL
+    } else {
+        let free = free_frames();
+        let total = free + used_frames();
+        let percent = if total > 0 { free * 100 / total } else { 100 };
+        WATERMARKS.iter().filter(|&&watermark| percent < watermark).count()
+    };
+
+    if LEVEL.swap(level, Ordering::SeqCst) != level {
+        context::event::trigger(MEMORY_SCHEME_ID.load(Ordering::SeqCst), 0, EVENT_READ, level);
+    }
+
+    level
+}
+
+/// The `[memory_pressure]` kernel thread, which checks every `PRESSURE_INTERVAL` milliseconds
+pub extern fn pressure_thread() {
+    loop {
+        check();
+
+        {
+            let contexts = context::contexts();
+            if let Some(context_lock) = contexts.current() {
+                let mut context = context_lock.write();
+                let current = arch::time::monotonic();
+                let nanoseconds = current.1 + PRESSURE_INTERVAL * 1000000;
+                context.wake = Some((current.0 + nanoseconds / 1000000000, nanoseconds % 1000000000));
+                context.block();
+            }
+        }
+
+        unsafe { context::switch(); }
+    }
+}
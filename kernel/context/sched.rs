@@ -0,0 +1,221 @@
+//! # SMP scheduling
+//! Each CPU has a run queue of the contexts assigned to it, and only switches to those. A new
+//! context is placed on the CPU in its affinity with the fewest runnable contexts, and that CPU is
+//! sent an IPI so that it stops halting. Every `BALANCE_INTERVAL`, each CPU gives away the contexts
+//! that are no longer allowed to run on it, and one more if it has at least two more runnable
+//! contexts than another CPU. Contexts are only moved by the CPU they are leaving, while it holds
+//! the switch lock, so a context is never moved while its registers are being saved
+//!
+//! Contexts that share memory stay on the CPU of the context that started them, as pages that are
+//! unmapped are not flushed from the TLB of other CPUs. Kernel threads share the page table they
+//! were spawned with, and change it through its recursive mapping, so they stay on the CPU that
+//! spawned them. The idle context of a CPU never moves
+//!
+//! A run queue may be locked and then a context, but a context that is in a run queue is never
+//! locked while locking a run queue
+
+use alloc::arc::Arc;
+use collections::{BTreeMap, BTreeSet, Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::{Mutex, Once, RwLock, RwLockReadGuard};
+
+use arch;
+use context::{Context, ContextList, Status};
+
+/// Nanoseconds between each time a CPU balances its run queue
+pub const BALANCE_INTERVAL: u64 = 100000000;
+
+/// Largest CPU ID that can be in an affinity mask, plus one
+pub const CPU_MASK_MAX: usize = 256;
+
+/// A set of CPUs, by ID
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CpuMask([u64; CPU_MASK_MAX / 64]);
+
+impl CpuMask {
+    /// Every CPU
+    pub fn all() -> CpuMask {
+        CpuMask([!0; CPU_MASK_MAX / 64])
+    }
+
+    /// No CPUs
+    pub fn empty() -> CpuMask {
+        CpuMask([0; CPU_MASK_MAX / 64])
+    }
+
+    pub fn contains(&self, cpu_id: usize) -> bool {
+        cpu_id < CPU_MASK_MAX && self.0[cpu_id / 64] & 1 << (cpu_id % 64) != 0
+    }
+
+    /// Add a CPU, returning false if its ID is too large
+    pub fn insert(&mut self, cpu_id: usize) -> bool {
+        if cpu_id < CPU_MASK_MAX {
+            self.0[cpu_id / 64] |= 1 << (cpu_id % 64);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// The contexts assigned to a CPU
+pub struct RunQueue {
+    /// IDs of the contexts, in the order they are switched to
+    pub contexts: Mutex<BTreeSet<usize>>,
+    /// Monotonic time in nanoseconds at which the CPU next balances
+    next_balance: AtomicU64
+}
+
+/// Run queues, by CPU ID. A CPU is added when it starts scheduling
+static RUN_QUEUES: Once<RwLock<BTreeMap<usize, Arc<RunQueue>>>> = Once::new();
+
+/// Initialize run queues, called if needed
+fn init_run_queues() -> RwLock<BTreeMap<usize, Arc<RunQueue>>> {
+    RwLock::new(BTreeMap::new())
+}
+
+/// Get the run queues
+pub fn run_queues() -> RwLockReadGuard<'static, BTreeMap<usize, Arc<RunQueue>>> {
+    RUN_QUEUES.call_once(init_run_queues).read()
+}
+
+/// Add the run queue of this CPU, so that contexts are placed on it
+pub fn init() {
+    RUN_QUEUES.call_once(init_run_queues).write().insert(::cpu_id(), Arc::new(RunQueue {
+        contexts: Mutex::new(BTreeSet::new()),
+        next_balance: AtomicU64::new(0)
+    }));
+}
+
+/// The run queue of a CPU
+pub fn run_queue(cpu_id: usize) -> Option<Arc<RunQueue>> {
+    run_queues().get(&cpu_id).map(|queue| queue.clone())
+}
+
+/// Make a CPU switch contexts, if it is halted or running userspace
+pub fn reschedule(cpu_id: usize) {
+    if cpu_id != ::cpu_id() {
+        // TODO: Make this more architecture independent
+        unsafe { arch::device::local_apic::LOCAL_APIC.ipi(cpu_id) };
+    }
+}
+
+/// The number of runnable contexts in a run queue. Contexts that are locked are counted, as
+/// they are likely to be in use
+pub fn load(queue: &RunQueue, contexts: &ContextList) -> usize {
+    let ids = queue.contexts.lock();
+    ids.iter().filter(|&&id| {
+        contexts.get(id).map_or(false, |context_lock| {
+            context_lock.try_read().map_or(true, |context| context.status == Status::Runnable)
+        })
+    }).count()
+}
+
+/// The CPU in `affinity` with the fewest runnable contexts, and how many it has
+fn idlest(affinity: &CpuMask, loads: &[(usize, usize)]) -> Option<(usize, usize)> {
+    let mut idlest: Option<(usize, usize)> = None;
+    for &(cpu_id, load) in loads.iter() {
+        if affinity.contains(cpu_id) && idlest.map_or(true, |(_, idlest_load)| load < idlest_load) {
+            idlest = Some((cpu_id, load));
+        }
+    }
+    idlest
+}
+
+/// Put a context on a run queue. A context that has a CPU stays on it, and one that does not is
+/// given the idlest CPU in its affinity, or this CPU if none of those are running
+pub fn place(context: &mut Context, contexts: &ContextList) {
+    let queues = run_queues();
+
+    let cpu_id = match context.cpu_id {
+        Some(cpu_id) if queues.contains_key(&cpu_id) => cpu_id,
+        _ => {
+            let loads: Vec<(usize, usize)> = queues.iter().map(|(&cpu_id, queue)| (cpu_id, load(queue, contexts))).collect();
+            idlest(&context.affinity, &loads).map_or(::cpu_id(), |(cpu_id, _load)| cpu_id)
+        }
+    };
+
+    context.cpu_id = Some(cpu_id);
+    if let Some(queue) = queues.get(&cpu_id) {
+        queue.contexts.lock().insert(context.id);
+    }
+
+    reschedule(cpu_id);
+}
+
+/// Take a context off of its run queue, once it is removed
+pub fn remove(id: usize, cpu_id: Option<usize>) {
+    if let Some(queue) = cpu_id.and_then(|cpu_id| run_queue(cpu_id)) {
+        queue.contexts.lock().remove(&id);
+    }
+}
+
+/// Check if a context may be moved to another CPU by the CPU it is on
+fn movable(context: &Context) -> bool {
+    ! context.running && context.stack.is_some() && Arc::strong_count(&context.grants) == 1
+}
+
+/// Give away contexts of this CPU, if it is time to balance
+///
+/// # Safety
+///
+/// Only call this from `switch`, with the switch lock held and without holding the context
+/// that is being switched from, which is not moved
+pub unsafe fn balance(queue: &RunQueue, contexts: &ContextList, from_id: usize) {
+    let cpu_id = ::cpu_id();
+
+    let (seconds, nanoseconds) = arch::time::monotonic();
+    let now = seconds * 1000000000 + nanoseconds;
+    if now < queue.next_balance.load(Ordering::Relaxed) {
+        return;
+    }
+    queue.next_balance.store(now + BALANCE_INTERVAL, Ordering::Relaxed);
+
+    let queues = run_queues();
+    let loads: Vec<(usize, usize)> = queues.iter().map(|(&cpu_id, queue)| (cpu_id, load(queue, contexts))).collect();
+    let own_load = loads.iter().find(|&&(other, _)| other == cpu_id).map_or(0, |&(_, load)| load);
+
+    let mut moves = Vec::new();
+    {
+        let mut balanced = false;
+        let ids = queue.contexts.lock();
+        for &id in ids.iter() {
+            if id == from_id {
+                continue;
+            }
+
+            if let Some(mut context) = contexts.get(id).and_then(|context_lock| context_lock.try_write()) {
+                if ! movable(&context) {
+                    continue;
+                }
+
+                let target = if ! context.affinity.contains(cpu_id) {
+                    idlest(&context.affinity, &loads).map(|(target, _load)| target)
+                } else if ! balanced && context.status == Status::Runnable {
+                    match idlest(&context.affinity, &loads) {
+                        Some((target, load)) if target != cpu_id && own_load >= load + 2 => {
+                            balanced = true;
+                            Some(target)
+                        },
+                        _ => None
+                    }
+                } else {
+                    None
+                };
+
+                if let Some(target) = target {
+                    context.cpu_id = Some(target);
+                    moves.push((id, target));
+                }
+            }
+        }
+    }
+
+    for (id, target) in moves {
+        queue.contexts.lock().remove(&id);
+        if let Some(target_queue) = queues.get(&target) {
+            target_queue.contexts.lock().insert(id);
+        }
+        reschedule(target);
+    }
+}
@@ -1,9 +1,9 @@
 use core::sync::atomic::Ordering;
 
 use arch;
-use super::{contexts, Context, Status, CONTEXT_ID};
+use super::{contexts, sched, Context, Status, CONTEXT_ID};
 
-/// Switch to the next context
+/// Switch to the next context in the run queue of this CPU
 ///
 /// # Safety
 ///
@@ -28,12 +28,10 @@ pub unsafe fn switch() -> bool {
             from_ptr = context.deref_mut() as *mut Context;
         }
 
-        let check_context = |context: &mut Context| -> bool {
-            if context.cpu_id == None && cpu_id == 0 {
-                context.cpu_id = Some(cpu_id);
-                // println!("{}: take {} {}", cpu_id, context.id, ::core::str::from_utf8_unchecked(&context.name.lock()));
-            }
+        let queue = sched::run_queue(cpu_id).expect("context::switch: no run queue for this CPU");
+        sched::balance(&queue, &contexts, (*from_ptr).id);
 
+        let check_context = |context: &mut Context| -> bool {
             if context.status == Status::Blocked && context.wake.is_some() {
                 let wake = context.wake.expect("context::switch: wake not set");
 
@@ -52,23 +50,29 @@ pub unsafe fn switch() -> bool {
             false
         };
 
-        for (pid, context_lock) in contexts.iter() {
+        let ids = queue.contexts.lock();
+
+        for pid in ids.iter() {
             if *pid > (*from_ptr).id {
-                let mut context = context_lock.write();
-                if check_context(&mut context) {
-                    to_ptr = context.deref_mut() as *mut Context;
-                    break;
+                if let Some(context_lock) = contexts.get(*pid) {
+                    let mut context = context_lock.write();
+                    if check_context(&mut context) {
+                        to_ptr = context.deref_mut() as *mut Context;
+                        break;
+                    }
                 }
             }
         }
 
         if to_ptr as usize == 0 {
-            for (pid, context_lock) in contexts.iter() {
+            for pid in ids.iter() {
                 if *pid < (*from_ptr).id {
-                    let mut context = context_lock.write();
-                    if check_context(&mut context) {
-                        to_ptr = context.deref_mut() as *mut Context;
-                        break;
+                    if let Some(context_lock) = contexts.get(*pid) {
+                        let mut context = context_lock.write();
+                        if check_context(&mut context) {
+                            to_ptr = context.deref_mut() as *mut Context;
+                            break;
+                        }
                     }
                 }
             }
@@ -81,11 +85,23 @@ pub unsafe fn switch() -> bool {
         return false;
     }
 
+    // A context that is still runnable was preempted, or yielded
+    {
+        let from = &*from_ptr;
+        if from.status == Status::Runnable {
+            from.usage.involuntary_switches.fetch_add(1, Ordering::Relaxed);
+        } else {
+            from.usage.voluntary_switches.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
     (&mut *from_ptr).running = false;
     (&mut *to_ptr).running = true;
     if let Some(ref stack) = (*to_ptr).kstack {
-        arch::gdt::TSS.rsp[0] = (stack.as_ptr() as usize + stack.len() - 256) as u64;
+        arch::gdt::TSS.tss.rsp[0] = (stack.as_ptr() as usize + stack.len() - 256) as u64;
     }
+    arch::gdt::set_io_bitmap((*to_ptr).io_bitmap.as_ref().map(|bitmap| &bitmap[..]));
+    (*to_ptr).io_bitmap_changed = false;
     CONTEXT_ID.store((&mut *to_ptr).id, Ordering::SeqCst);
 
     // Unset global lock before switch, as arch is only usable by the current CPU at this time
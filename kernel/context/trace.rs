@@ -0,0 +1,180 @@
+//! # Syscall tracing
+//! A context that is traced sends a record to its tracer when it enters and leaves each syscall.
+//! Records are lines of text, `> PID NAME(ARGS)` on entry and `< PID NAME = RESULT TIME` on exit,
+//! where `TIME` is the time spent in the syscall in nanoseconds. Errors are shown as the negated
+//! error number followed by its description. When records are dropped because the tracer is not
+//! reading them, the next record is preceded by `! DROPPED`. Tracers are attached by opening
+//! `trace:<pid>`
+
+use alloc::arc::Arc;
+use collections::String;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use arch;
+use context;
+use sync::WaitQueue;
+use syscall::error::Result;
+use syscall::number::*;
+use syscall::validate::validate_slice;
+
+/// Bytes of unread records a tracer may hold. Records that do not fit are dropped
+pub const TRACE_BUFFER_SIZE: usize = 65536;
+
+/// Longest path shown in a record, longer paths are cut short
+const PATH_MAX_SHOWN: usize = 64;
+
+/// The records of a traced context, waiting to be read by the tracer
+#[derive(Debug)]
+pub struct Tracer {
+    /// The handle of `trace:` that this tracer was opened as
+    pub id: usize,
+    /// Unread records
+    pub records: WaitQueue<u8>,
+    /// Records that were dropped because the tracer was not reading them
+    pub dropped: AtomicUsize
+}
+
+impl Tracer {
+    pub fn new(id: usize) -> Tracer {
+        Tracer {
+            id: id,
+            records: WaitQueue::new(),
+            dropped: AtomicUsize::new(0)
+        }
+    }
+
+    /// Queue a record, unless it would not fit
+    fn send(&self, record: &str) {
+        let full = self.records.inner.lock().len() + record.len() > TRACE_BUFFER_SIZE;
+        if full {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        } else {
+            let dropped = self.dropped.swap(0, Ordering::Relaxed);
+            if dropped > 0 {
+                self.records.send_from(format!("! {}\n", dropped).as_bytes());
+            }
+            self.records.send_from(record.as_bytes());
+        }
+    }
+}
+
+/// The tracer of the current context, if it is traced
+pub fn tracer() -> Option<(usize, Arc<Tracer>)> {
+    let contexts = context::contexts();
+    let context_lock = match contexts.current() {
+        Some(context_lock) => context_lock,
+        None => return None
+    };
+    let context = context_lock.read();
+    context.trace.as_ref().map(|tracer| (context.id, tracer.clone()))
+}
+
+/// The name of a syscall, as used in records
+fn name(a: usize) -> &'static str {
+    match a {
+        SYS_OPEN => "open",
+        SYS_MKDIR => "mkdir",
+        SYS_RMDIR => "rmdir",
+        SYS_UNLINK => "unlink",
+        SYS_CLOSE => "close",
+        SYS_DUP => "dup",
+        SYS_READ => "read",
+        SYS_WRITE => "write",
+        SYS_LSEEK => "lseek",
+        SYS_FCNTL => "fcntl",
+        SYS_FEVENT => "fevent",
+        SYS_FMAP => "fmap",
+        SYS_FUNMAP => "funmap",
+        SYS_FPATH => "fpath",
+        SYS_FSTAT => "fstat",
+        SYS_FSYNC => "fsync",
+        SYS_FTRUNCATE => "ftruncate",
+        SYS_EXIT => "exit",
+        SYS_WAITPID => "waitpid",
+        SYS_EXECVE => "execve",
+        SYS_CHDIR => "chdir",
+        SYS_GETPID => "getpid",
+        SYS_BRK => "brk",
+        SYS_IOPL => "iopl",
+        SYS_CLONE => "clone",
+        SYS_YIELD => "yield",
+        SYS_NANOSLEEP => "nanosleep",
+        SYS_GETCWD => "getcwd",
+        SYS_GETUID => "getuid",
+        SYS_GETGID => "getgid",
+        SYS_GETEUID => "geteuid",
+        SYS_GETEGID => "getegid",
+        SYS_SETUID => "setuid",
+        SYS_SETGID => "setgid",
+        SYS_CLOCK_GETTIME => "clock_gettime",
+        SYS_FUTEX => "futex",
+        SYS_PIPE2 => "pipe2",
+        SYS_PHYSALLOC => "physalloc",
+        SYS_PHYSFREE => "physfree",
+        SYS_PHYSMAP => "physmap",
+        SYS_PHYSUNMAP => "physunmap",
+        SYS_VIRTTOPHYS => "virttophys",
+        _ => "unknown"
+    }
+}
+
+/// A path argument, quoted, or its address if it cannot be read
+fn path(address: usize, len: usize) -> String {
+    match validate_slice(address as *const u8, len) {
+        Ok(path) => {
+            let shown = &path[.. if path.len() > PATH_MAX_SHOWN { PATH_MAX_SHOWN } else { path.len() }];
+            let ellipsis = if shown.len() < path.len() { "..." } else { "" };
+            format!("\"{}{}\"", String::from_utf8_lossy(shown), ellipsis)
+        },
+        Err(_) => format!("{:#X}", address)
+    }
+}
+
+/// The arguments of a syscall. Paths are shown for calls that take them, buffers are shown as
+/// their address and length, and unknown calls show every argument
+fn args(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize) -> String {
+    match a {
+        SYS_OPEN => format!("{}, {:#X}", path(b, c), d),
+        SYS_MKDIR => format!("{}, {:#o}", path(b, c), d),
+        SYS_RMDIR | SYS_UNLINK | SYS_CHDIR => path(b, c),
+        SYS_EXECVE => format!("{}, {:#X}, {}", path(b, c), d, e),
+        SYS_READ | SYS_WRITE | SYS_FPATH | SYS_DUP => format!("{}, {:#X}, {}", b, c, d),
+        SYS_LSEEK | SYS_FCNTL | SYS_FEVENT => format!("{}, {}, {}", b, c, d),
+        SYS_CLOSE | SYS_FUNMAP | SYS_FSYNC | SYS_EXIT | SYS_SETUID | SYS_SETGID | SYS_IOPL => format!("{}", b),
+        SYS_FTRUNCATE => format!("{}, {}", b, c),
+        SYS_WAITPID => format!("{}, {:#X}, {:#X}", b, c, d),
+        SYS_BRK | SYS_CLONE | SYS_PHYSUNMAP | SYS_VIRTTOPHYS => format!("{:#X}", b),
+        SYS_GETPID | SYS_GETUID | SYS_GETGID | SYS_GETEUID | SYS_GETEGID | SYS_YIELD => String::new(),
+        _ => format!("{:#X}, {:#X}, {:#X}, {:#X}, {:#X}", b, c, d, e, f)
+    }
+}
+
+/// Record the entry to a syscall, returning the monotonic time it started at
+pub fn enter(pid: usize, tracer: &Tracer, a: usize, b: usize, c: usize, d: usize, e: usize, f: usize) -> (u64, u64) {
+    tracer.send(&format!("> {} {}({})\n", pid, name(a), args(a, b, c, d, e, f)));
+    arch::time::monotonic()
+}
+
+/// Record the return from a syscall that started at `start`
+pub fn exit(pid: usize, tracer: &Tracer, a: usize, result: &Result<usize>, start: (u64, u64)) {
+    let end = arch::time::monotonic();
+    let time = (end.0 * 1000000000 + end.1).saturating_sub(start.0 * 1000000000 + start.1);
+
+    let value = match *result {
+        Ok(value) => format!("{}", value),
+        Err(ref err) => format!("-{} {}", err.errno, err)
+    };
+    tracer.send(&format!("< {} {} = {} {}\n", pid, name(a), value, time));
+}
+
+/// Detach the tracer opened as the handle `id` from the context `pid`, if it is still attached
+pub fn detach(pid: usize, id: usize) {
+    let contexts = context::contexts();
+    if let Some(context_lock) = contexts.get(pid) {
+        let mut context = context_lock.write();
+        let attached = context.trace.as_ref().map_or(false, |tracer| tracer.id == id);
+        if attached {
+            context.trace = None;
+        }
+    }
+}
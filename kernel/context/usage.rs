@@ -0,0 +1,115 @@
+//! # Resource usage
+//! Each context counts the resources it uses, and when it is reaped its counts, along with those
+//! of the children it reaped, are added to the children totals of the context that reaped it, as
+//! `getrusage` reports them for `RUSAGE_SELF` and `RUSAGE_CHILDREN`. Shown in `sys:<pid>/rusage`
+
+use core::cmp;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use context;
+use syscall::error::Result;
+use syscall::number::{SYS_READ, SYS_WRITE};
+
+/// Resource usage, as counted so far
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Rusage {
+    /// Most pages resident at once, of those seen when the usage was read, and at exit
+    pub max_rss: usize,
+    /// Page faults resolved without I/O. Nothing is paged in from storage, so every fault is minor
+    pub minor_faults: u64,
+    /// Reads of files that returned data
+    pub reads: u64,
+    /// Writes to files that wrote data
+    pub writes: u64,
+    /// Switches away from the context while it was blocked
+    pub voluntary_switches: u64,
+    /// Switches away from the context while it was still runnable, by preemption or yielding
+    pub involuntary_switches: u64
+}
+
+impl Rusage {
+    /// Add the usage of a child. The largest resident size is kept, rather than added
+    pub fn add(&mut self, other: &Rusage) {
+        self.max_rss = cmp::max(self.max_rss, other.max_rss);
+        self.minor_faults += other.minor_faults;
+        self.reads += other.reads;
+        self.writes += other.writes;
+        self.voluntary_switches += other.voluntary_switches;
+        self.involuntary_switches += other.involuntary_switches;
+    }
+}
+
+/// The counters of a context, which are updated without locking it
+#[derive(Debug)]
+pub struct Usage {
+    pub max_rss: AtomicUsize,
+    pub minor_faults: AtomicU64,
+    pub reads: AtomicU64,
+    pub writes: AtomicU64,
+    pub voluntary_switches: AtomicU64,
+    pub involuntary_switches: AtomicU64
+}
+
+impl Usage {
+    pub fn new() -> Usage {
+        Usage {
+            max_rss: AtomicUsize::new(0),
+            minor_faults: AtomicU64::new(0),
+            reads: AtomicU64::new(0),
+            writes: AtomicU64::new(0),
+            voluntary_switches: AtomicU64::new(0),
+            involuntary_switches: AtomicU64::new(0)
+        }
+    }
+
+    /// Record the number of pages that are resident, keeping the largest
+    pub fn sample_rss(&self, pages: usize) {
+        let mut max_rss = self.max_rss.load(Ordering::Relaxed);
+        while pages > max_rss {
+            let old = self.max_rss.compare_and_swap(max_rss, pages, Ordering::Relaxed);
+            if old == max_rss {
+                break;
+            }
+            max_rss = old;
+        }
+    }
+
+    /// Count the result of the file operation `a`
+    pub fn count(&self, a: usize, result: &Result<usize>) {
+        if let Ok(count) = *result {
+            if count > 0 {
+                match a {
+                    SYS_READ => {
+                        self.reads.fetch_add(1, Ordering::Relaxed);
+                    },
+                    SYS_WRITE => {
+                        self.writes.fetch_add(1, Ordering::Relaxed);
+                    },
+                    _ => ()
+                }
+            }
+        }
+    }
+
+    /// The counts so far
+    pub fn rusage(&self) -> Rusage {
+        Rusage {
+            max_rss: self.max_rss.load(Ordering::Relaxed),
+            minor_faults: self.minor_faults.load(Ordering::Relaxed),
+            reads: self.reads.load(Ordering::Relaxed),
+            writes: self.writes.load(Ordering::Relaxed),
+            voluntary_switches: self.voluntary_switches.load(Ordering::Relaxed),
+            involuntary_switches: self.involuntary_switches.load(Ordering::Relaxed)
+        }
+    }
+}
+
+/// Count a page fault of the current context that was resolved. The fault may have been taken
+/// while the context list or the context was locked, so it is not counted if they are
+pub fn fault() {
+    if let Some(contexts) = context::try_contexts() {
+        if let Some(context) = contexts.current().and_then(|context_lock| context_lock.try_read()) {
+            context.usage.minor_faults.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
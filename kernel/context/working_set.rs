@@ -0,0 +1,151 @@
+//! # Working set estimation
+//!
+//! Contexts opt in through `sys:<pid>/working_set`. Every `WORKING_SET_INTERVAL` seconds, the
+//! `[working_set]` kernel thread clears the accessed bit of each page of their image, heap, stack,
+//! and TLS. Each page has an age, the number of scans since it was last found accessed, so the
+//! pages younger than a number of scans are the working set of the context over that many
+//! intervals, and the oldest pages are the ones that would be reclaimed first
+//!
+//! Running contexts are skipped, as their CPU may have their pages in its TLB, and would not set
+//! the accessed bit again. Contexts that share an address space are scanned once, for the first of
+//! them that opted in
+
+use collections::{BTreeMap, Vec};
+use core::cmp;
+
+use arch;
+use arch::paging::{ActivePageTable, InactivePageTable, Page, VirtualAddress};
+use arch::paging::temporary_page::TemporaryPage;
+use context;
+use context::memory::zero_frame;
+
+/// Seconds between scans
+pub const WORKING_SET_INTERVAL: u64 = 5;
+
+/// Ages stop counting at this many scans
+pub const WORKING_SET_MAX_AGE: u8 = 8;
+
+/// The ages of the pages of a context
+#[derive(Debug, Default)]
+pub struct WorkingSet {
+    /// Scans since each page was last accessed, by address. Pages backed by the zero frame are
+    /// not resident, and are left out
+    pub ages: BTreeMap<usize, u8>,
+    /// Scans of the context
+    pub scans: u64,
+    /// Scans skipped because the context was running
+    pub skipped: u64
+}
+
+impl WorkingSet {
+    /// The number of pages accessed within the last `scans` scans
+    pub fn pages(&self, scans: u8) -> usize {
+        self.ages.values().filter(|&&age| age < scans).count()
+    }
+}
+
+/// Clear and sample the accessed bits of a context, unless its page table is one of `seen`
+fn scan_context(active_table: &mut ActivePageTable, pid: usize, seen: &mut Vec<usize>) {
+    let contexts = context::contexts();
+    let context_lock = match contexts.get(pid) {
+        Some(context_lock) => context_lock,
+        None => return
+    };
+    // Locked for writing, so that it is not switched to, and its pages are not merged, meanwhile
+    let mut context = context_lock.write();
+    let table = context.arch.get_page_table();
+    if context.working_set.is_none() || seen.contains(&table) {
+        return;
+    }
+    seen.push(table);
+
+    if context.running {
+        if let Some(ref mut working_set) = context.working_set {
+            working_set.skipped += 1;
+        }
+        return;
+    }
+
+    let mut pages = Vec::new();
+    for memory in context.image.iter() {
+        memory.with(|memory| pages.extend(memory.pages()));
+    }
+    if let Some(ref heap) = context.heap {
+        heap.with(|heap| pages.extend(heap.pages()));
+    }
+    if let Some(ref stack) = context.stack {
+        pages.extend(stack.pages());
+    }
+    if let Some(ref tls) = context.tls {
+        pages.extend(tls.mem.pages());
+    }
+
+    let mut accessed = Vec::new();
+    let mut new_table = unsafe { InactivePageTable::from_address(table) };
+    let mut temporary_page = TemporaryPage::new(Page::containing_address(VirtualAddress::new(arch::USER_TMP_WORKING_SET_OFFSET)));
+    active_table.with(&mut new_table, &mut temporary_page, |mapper| {
+        for page in pages {
+            if mapper.translate_page(page).map_or(false, |frame| frame != zero_frame()) {
+                if let Some(was_accessed) = mapper.take_accessed(page) {
+                    accessed.push((page.start_address().get(), was_accessed));
+                }
+            }
+        }
+    });
+
+    if let Some(ref mut working_set) = context.working_set {
+        // Pages that are no longer mapped are dropped, and new pages start at the oldest age
+        // unless they were accessed
+        let mut ages = BTreeMap::new();
+        for (address, was_accessed) in accessed {
+            let age = if was_accessed {
+                0
+            } else {
+                let old = working_set.ages.get(&address).map_or(WORKING_SET_MAX_AGE, |&age| age);
+                cmp::min(old.saturating_add(1), WORKING_SET_MAX_AGE)
+            };
+            ages.insert(address, age);
+        }
+        working_set.ages = ages;
+        working_set.scans += 1;
+    }
+}
+
+/// Scan the contexts that opted in
+pub fn scan() {
+    let mut active_table = unsafe { ActivePageTable::new() };
+
+    let mut pids = Vec::new();
+    {
+        let contexts = context::contexts();
+        for (pid, context_lock) in contexts.iter() {
+            if context_lock.read().working_set.is_some() {
+                pids.push(*pid);
+            }
+        }
+    }
+
+    let mut seen = Vec::new();
+    for pid in pids {
+        scan_context(&mut active_table, pid, &mut seen);
+    }
+}
+
+/// The `[working_set]` kernel thread, which scans every `WORKING_SET_INTERVAL` seconds
+pub extern fn working_set_thread() {
+    loop {
+        scan();
+
+        {
+            let contexts = context::contexts();
+            if let Some(context_lock) = contexts.current() {
+                let mut context = context_lock.write();
+                let current = arch::time::monotonic();
+                context.wake = Some((current.0 + WORKING_SET_INTERVAL, current.1));
+                context.block();
+            }
+        }
+
+        unsafe { context::switch(); }
+    }
+}
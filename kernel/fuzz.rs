@@ -0,0 +1,582 @@
+//! # Fuzzing
+//! Calls syscalls with random arguments, and sends malformed packets to in-kernel schemes, to find
+//! panics in argument validation. Inputs that reach a result not seen before are kept and mutated,
+//! so that later inputs get past the first checks. Results are printed one per line, as
+//! `FUZZ <name> <value>`, so that they can be collected from the serial console, and a run is
+//! repeated by giving the same seed
+
+use alloc::arc::Arc;
+use alloc::boxed::Box;
+use collections::{BTreeSet, Vec};
+use core::{cmp, mem};
+use spin::{Mutex, Once};
+
+use arch::device::pvclock::rdtsc;
+use scheme;
+use syscall;
+use syscall::data::{Packet, Stat, TimeSpec};
+use syscall::error::Error;
+use syscall::number::*;
+use syscall::scheme::Scheme;
+
+/// Inputs tried by the run started at boot
+pub const FUZZ_BOOT_ITERATIONS: usize = 100000;
+
+/// Bytes of the buffer that pointers point into. It is followed by a guard of the same size,
+/// which is checked after each input to find writes past the end of a validated buffer
+const FUZZ_BUFFER_SIZE: usize = 4096;
+
+/// Limit on number of inputs kept for mutation
+const FUZZ_CORPUS_MAX: usize = 1024;
+
+/// Limit on number of handles kept open at once, beyond which handles are closed
+const FUZZ_HANDLES_MAX: usize = 64;
+
+/// Byte the guard is filled with
+const FUZZ_GUARD: u8 = 0xA5;
+
+/// Paths opened by syscalls. Schemes that block, or that create schemes, are left out, as are
+/// relative paths, which would open the root scheme from a kernel context
+const PATHS: [&'static [u8]; 20] = [
+    b"sys:",
+    b"sys:context",
+    b"sys:memory",
+    b"sys:scheme",
+    b"sys:uname",
+    b"sys:1/name",
+    b"sys:999999/maps",
+    b"sys:/../context",
+    b"zero:",
+    b"null:",
+    b"env:",
+    b"env:FUZZ",
+    b"log:",
+    b"log:last",
+    b"memory:",
+    b"initfs:",
+    b"initfs:bin/",
+    b"initfs:etc/init.rc",
+    b"nonexistent:",
+    b"zero:\0\xFF/../"
+];
+
+/// Paths of schemes whose handles are never written or truncated, as writing to `sys:` changes the
+/// system, and truncating `env:` to a large size runs the kernel out of memory
+const READ_ONLY: [&'static [u8]; 2] = [b"sys:", b"env:"];
+
+/// Schemes that are sent packets, with whether their handles may be written
+const PACKET_SCHEMES: [(&'static [u8], bool); 6] = [
+    (b"null", true),
+    (b"zero", true),
+    (b"memory", true),
+    (b"log", true),
+    (b"initfs", true),
+    (b"sys", false)
+];
+
+/// Addresses that are never mapped writable in a kernel context: the first pages, the top of the
+/// userspace half, and addresses that are not canonical
+const ADDRESSES: [usize; 6] = [0, 1, 0xFFF, 0x7FFF_FFFF_F000, 0x8000_0000_0000, 0xFFFF_0000_0000_0000];
+
+/// Lengths past the end of the kernel heap, which validation must refuse for any pointer
+const HUGE_LENGTHS: [usize; 4] = [1 << 40, 1 << 62, usize::max_value() / 2, usize::max_value()];
+
+/// Values that tend to find edge cases
+const VALUES: [usize; 14] = [0, 1, 2, 3, 0x7F, 0x80, 0xFF, 0x1000, 0xFFFF, 0x7FFF_FFFF, 0xFFFF_FFFF, 1 << 32, 1 << 63, usize::max_value()];
+
+/// Handles that are never open, used when an input names a handle that is not
+const INVALID_HANDLES: [usize; 4] = [1 << 32, 1 << 40, 1 << 62, usize::max_value()];
+
+/// The arguments an operation takes
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Shape {
+    /// A path
+    Path,
+    /// A path and a value, such as flags
+    PathValue,
+    /// A handle
+    Handle,
+    /// A handle and a buffer
+    HandleBuffer,
+    /// A handle and a value
+    HandleValue,
+    /// A handle and two values
+    HandleValues,
+    /// A buffer
+    Buffer,
+    /// A value and a buffer
+    ValueBuffer,
+    /// Nothing
+    Nothing
+}
+
+/// Operations, by syscall number. Syscalls that block, exit, or change memory are left out
+const OPERATIONS: [(usize, Shape); 18] = [
+    (SYS_OPEN, Shape::PathValue),
+    (SYS_MKDIR, Shape::PathValue),
+    (SYS_RMDIR, Shape::Path),
+    (SYS_UNLINK, Shape::Path),
+    (SYS_CHDIR, Shape::Path),
+    (SYS_CLOSE, Shape::Handle),
+    (SYS_DUP, Shape::HandleBuffer),
+    (SYS_READ, Shape::HandleBuffer),
+    (SYS_WRITE, Shape::HandleBuffer),
+    (SYS_FPATH, Shape::HandleBuffer),
+    (SYS_FSTAT, Shape::HandleBuffer),
+    (SYS_LSEEK, Shape::HandleValues),
+    (SYS_FSYNC, Shape::Handle),
+    (SYS_FTRUNCATE, Shape::HandleValue),
+    (SYS_FEVENT, Shape::HandleValue),
+    (SYS_GETCWD, Shape::Buffer),
+    (SYS_CLOCK_GETTIME, Shape::ValueBuffer),
+    (SYS_GETPID, Shape::Nothing)
+];
+
+/// Where a pointer argument points
+#[derive(Clone, Copy, Debug)]
+enum Pointer {
+    /// An offset in the buffer
+    Buffer(usize),
+    /// One of `ADDRESSES`
+    Address(usize)
+}
+
+/// One call, either a syscall or a packet sent to a scheme
+#[derive(Clone, Copy, Debug)]
+struct Input {
+    /// Index in `PACKET_SCHEMES`, or `None` for a syscall
+    scheme: Option<usize>,
+    /// Index in `OPERATIONS`, or a number that is not a syscall for a packet if it is not an index
+    operation: usize,
+    /// Index in the open handles. Beyond them, one of `INVALID_HANDLES` is used
+    handle: usize,
+    /// Index in `PATHS`
+    path: usize,
+    pointer: Pointer,
+    /// Length of the buffer, which is cut to the end of the buffer unless it is huge
+    len: usize,
+    values: [usize; 2],
+    /// User ID of a packet
+    uid: u32
+}
+
+/// An open handle
+struct Handle {
+    /// File descriptor for a syscall, or file ID for a packet
+    id: usize,
+    writable: bool
+}
+
+/// xorshift64*, which is enough to spread inputs
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> usize {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545F4914F6CDD1D) as usize
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        self.next() % n
+    }
+
+    fn value(&mut self) -> usize {
+        if self.below(2) == 0 {
+            VALUES[self.below(VALUES.len())]
+        } else {
+            self.next() >> self.below(64)
+        }
+    }
+}
+
+struct Fuzzer {
+    rng: Rng,
+    buffer: Vec<u8>,
+    /// Handles opened by syscalls
+    files: Vec<Handle>,
+    /// Handles opened by packets, for each of `PACKET_SCHEMES`
+    scheme_files: Vec<Vec<Handle>>,
+    /// Results seen, as the scheme (zero for syscalls), operation, path and error
+    coverage: BTreeSet<(usize, usize, usize, usize)>,
+    corpus: Vec<Input>,
+    overflows: usize,
+    results: Vec<u8>
+}
+
+/// Results of the last run
+static RESULTS: Once<Mutex<Vec<u8>>> = Once::new();
+
+/// Initialize results, called if needed
+fn init_results() -> Mutex<Vec<u8>> {
+    Mutex::new(Vec::new())
+}
+
+impl Fuzzer {
+    fn new(seed: u64) -> Fuzzer {
+        let mut buffer = vec![0; FUZZ_BUFFER_SIZE];
+        buffer.extend_from_slice(&[FUZZ_GUARD; FUZZ_BUFFER_SIZE]);
+
+        Fuzzer {
+            // xorshift never leaves zero
+            rng: Rng(cmp::max(seed, 1)),
+            buffer: buffer,
+            files: Vec::new(),
+            scheme_files: PACKET_SCHEMES.iter().map(|_| Vec::new()).collect(),
+            coverage: BTreeSet::new(),
+            corpus: Vec::new(),
+            overflows: 0,
+            results: Vec::new()
+        }
+    }
+
+    fn report(&mut self, name: &str, value: &str) {
+        let line = format!("FUZZ {} {}\n", name, value);
+        print!("{}", line);
+        self.results.extend_from_slice(line.as_bytes());
+    }
+
+    fn generate(&mut self) -> Input {
+        let scheme = if self.rng.below(2) == 0 {
+            None
+        } else {
+            Some(self.rng.below(PACKET_SCHEMES.len()))
+        };
+
+        let mut input = Input {
+            scheme: scheme,
+            operation: self.rng.below(OPERATIONS.len()),
+            handle: 0,
+            path: self.rng.below(PATHS.len()),
+            pointer: Pointer::Buffer(0),
+            len: 0,
+            values: [self.rng.value(), self.rng.value()],
+            uid: if self.rng.below(2) == 0 { 0 } else { self.rng.value() as u32 }
+        };
+        self.mutate_handle(&mut input);
+        self.mutate_pointer(&mut input);
+
+        // Packets may have a number that is not a syscall, to reach the default of the dispatch.
+        // Syscall numbers never have the top bit set
+        if input.scheme.is_some() && self.rng.below(8) == 0 {
+            input.operation = self.rng.next() | 1 << 63;
+        }
+
+        input
+    }
+
+    fn mutate_handle(&mut self, input: &mut Input) {
+        let open = match input.scheme {
+            Some(scheme) => self.scheme_files[scheme].len(),
+            None => self.files.len()
+        };
+        input.handle = self.rng.below(open + INVALID_HANDLES.len());
+    }
+
+    fn mutate_pointer(&mut self, input: &mut Input) {
+        // Packets are not validated, so they only point into the buffer
+        if input.scheme.is_none() && self.rng.below(4) == 0 {
+            input.pointer = Pointer::Address(self.rng.below(ADDRESSES.len()));
+        } else {
+            input.pointer = Pointer::Buffer(self.rng.below(FUZZ_BUFFER_SIZE + 1));
+        }
+
+        input.len = if input.scheme.is_none() && self.rng.below(8) == 0 {
+            HUGE_LENGTHS[self.rng.below(HUGE_LENGTHS.len())]
+        } else {
+            self.rng.below(FUZZ_BUFFER_SIZE + 1)
+        };
+    }
+
+    /// Change one argument of an input from the corpus
+    fn mutate(&mut self, mut input: Input) -> Input {
+        match self.rng.below(6) {
+            0 => input.operation = self.rng.below(OPERATIONS.len()),
+            1 => self.mutate_handle(&mut input),
+            2 => input.path = self.rng.below(PATHS.len()),
+            3 => self.mutate_pointer(&mut input),
+            4 => {
+                let i = self.rng.below(2);
+                input.values[i] = self.rng.value();
+            },
+            _ => input.uid = self.rng.value() as u32
+        }
+        input
+    }
+
+    /// The handle an input names, and whether it may be written
+    fn handle(&self, input: &Input) -> (usize, bool) {
+        let files = match input.scheme {
+            Some(scheme) => &self.scheme_files[scheme],
+            None => &self.files
+        };
+        match files.get(input.handle) {
+            Some(handle) => (handle.id, handle.writable),
+            None => (INVALID_HANDLES[(input.handle - files.len()) % INVALID_HANDLES.len()], true)
+        }
+    }
+
+    /// The address and length of the buffer argument of an input
+    fn buffer(&mut self, input: &Input) -> (usize, usize) {
+        match input.pointer {
+            Pointer::Buffer(offset) => {
+                let offset = cmp::min(offset, FUZZ_BUFFER_SIZE);
+                let len = if HUGE_LENGTHS.contains(&input.len) {
+                    input.len
+                } else {
+                    cmp::min(input.len, FUZZ_BUFFER_SIZE - offset)
+                };
+                (self.buffer.as_mut_ptr() as usize + offset, len)
+            },
+            Pointer::Address(i) => (ADDRESSES[i], input.len)
+        }
+    }
+
+    /// Copy a path into the start of the buffer, returning its address and length. Packets are
+    /// given the reference after the scheme name
+    fn path(&mut self, input: &Input) -> (usize, usize) {
+        let path = PATHS[input.path];
+        let path = match input.scheme {
+            Some(_) => match path.iter().position(|&b| b == b':') {
+                Some(i) => &path[i + 1..],
+                None => path
+            },
+            None => path
+        };
+        self.buffer[..path.len()].copy_from_slice(path);
+        (self.buffer.as_ptr() as usize, path.len())
+    }
+
+    /// Run an input, returning its operation and result
+    fn execute(&mut self, input: &Input) -> (usize, usize) {
+        let (number, shape) = match OPERATIONS.get(input.operation) {
+            Some(&operation) => operation,
+            None => (input.operation, Shape::HandleValues)
+        };
+
+        let (handle, writable) = self.handle(input);
+        if ! writable && (number == SYS_WRITE || number == SYS_FTRUNCATE) {
+            return (number, Error::mux(Err(Error::new(syscall::error::EBADF))));
+        }
+
+        let (b, c, d) = match shape {
+            Shape::Path => {
+                let (address, len) = self.path(input);
+                (address, len, 0)
+            },
+            Shape::PathValue => {
+                let (address, len) = self.path(input);
+                (address, len, input.values[0])
+            },
+            Shape::Handle => (handle, 0, 0),
+            Shape::HandleBuffer => if input.scheme.is_some() && number == SYS_FSTAT {
+                // The scheme dispatch writes a whole stat, whatever the length
+                (handle, self.buffer.as_mut_ptr() as usize, mem::size_of::<Stat>())
+            } else {
+                let (address, len) = self.buffer(input);
+                (handle, address, len)
+            },
+            Shape::HandleValue => (handle, input.values[0], 0),
+            Shape::HandleValues => (handle, input.values[0], input.values[1]),
+            Shape::Buffer => {
+                let (address, len) = self.buffer(input);
+                (address, len, 0)
+            },
+            Shape::ValueBuffer => {
+                // The time is written whole, so it has to fit before the guard
+                let address = match input.pointer {
+                    Pointer::Buffer(offset) => self.buffer.as_mut_ptr() as usize + cmp::min(offset, FUZZ_BUFFER_SIZE - mem::size_of::<TimeSpec>()),
+                    Pointer::Address(i) => ADDRESSES[i]
+                };
+                (input.values[0], address, 0)
+            },
+            Shape::Nothing => (0, 0, 0)
+        };
+
+        let result = match input.scheme {
+            Some(scheme_index) => {
+                let scheme_option = {
+                    let schemes = scheme::schemes();
+                    schemes.get_name(PACKET_SCHEMES[scheme_index].0).map(|(_id, scheme)| scheme.clone())
+                };
+                match scheme_option {
+                    Some(scheme) => self.send(&scheme, input.uid, number, b, c, d),
+                    None => Error::mux(Err(Error::new(syscall::error::ENODEV)))
+                }
+            },
+            None => syscall::syscall(number, b, c, d, 0, 0, 0)
+        };
+
+        // Keep handles that were opened, so that later inputs use them
+        if let Ok(id) = Error::demux(result) {
+            if number == SYS_OPEN || number == SYS_DUP {
+                let opened_writable = match input.scheme {
+                    Some(scheme) => PACKET_SCHEMES[scheme].1,
+                    None => ! READ_ONLY.iter().any(|prefix| PATHS[input.path].starts_with(prefix))
+                };
+                // A dup keeps the access of the handle it was made from
+                let writable = if number == SYS_DUP { writable } else { opened_writable };
+                self.keep(input.scheme, id, writable);
+            } else if number == SYS_CLOSE {
+                let files = match input.scheme {
+                    Some(scheme) => &mut self.scheme_files[scheme],
+                    None => &mut self.files
+                };
+                files.retain(|file| file.id != handle);
+            }
+        }
+
+        (number, result)
+    }
+
+    /// Send a packet to an in-kernel scheme, as the syscalls do
+    fn send(&self, scheme: &Arc<Box<Scheme + Send + Sync>>, uid: u32, a: usize, b: usize, c: usize, d: usize) -> usize {
+        let mut packet = Packet {
+            id: 0,
+            pid: 0,
+            uid: uid,
+            gid: uid,
+            a: a,
+            b: b,
+            c: c,
+            d: d
+        };
+        scheme.handle(&mut packet);
+        packet.a
+    }
+
+    /// Keep an opened handle, closing the oldest if there are too many
+    fn keep(&mut self, scheme: Option<usize>, id: usize, writable: bool) {
+        let handle = Handle {
+            id: id,
+            writable: writable
+        };
+        match scheme {
+            Some(scheme) => {
+                if self.scheme_files[scheme].len() >= FUZZ_HANDLES_MAX {
+                    let old = self.scheme_files[scheme].remove(0);
+                    self.close(Some(scheme), old.id);
+                }
+                self.scheme_files[scheme].push(handle);
+            },
+            None => {
+                if self.files.len() >= FUZZ_HANDLES_MAX {
+                    let old = self.files.remove(0);
+                    self.close(None, old.id);
+                }
+                self.files.push(handle);
+            }
+        }
+    }
+
+    fn close(&self, scheme: Option<usize>, id: usize) {
+        match scheme {
+            Some(scheme_index) => {
+                let scheme_option = {
+                    let schemes = scheme::schemes();
+                    schemes.get_name(PACKET_SCHEMES[scheme_index].0).map(|(_id, scheme)| scheme.clone())
+                };
+                if let Some(scheme) = scheme_option {
+                    let _ = scheme.close(id);
+                }
+            },
+            None => {
+                let _ = syscall::close(id);
+            }
+        }
+    }
+
+    /// Check that the guard after the buffer is untouched, restoring it if it is not
+    fn check_guard(&mut self, input: &Input) {
+        if self.buffer[FUZZ_BUFFER_SIZE..].iter().any(|&b| b != FUZZ_GUARD) {
+            self.overflows += 1;
+            self.report("overflow", &format!("{:?}", input));
+            for b in self.buffer[FUZZ_BUFFER_SIZE..].iter_mut() {
+                *b = FUZZ_GUARD;
+            }
+        }
+    }
+
+    fn run(&mut self, iterations: usize) {
+        for _ in 0..iterations {
+            let input = if ! self.corpus.is_empty() && self.rng.below(4) != 0 {
+                let i = self.rng.below(self.corpus.len());
+                let input = self.corpus[i];
+                self.mutate(input)
+            } else {
+                self.generate()
+            };
+
+            // Fill the buffer with random bytes, so that writes are not all zero
+            if self.rng.below(4) == 0 {
+                for i in 0..FUZZ_BUFFER_SIZE {
+                    self.buffer[i] = self.rng.next() as u8;
+                }
+            }
+
+            let (number, result) = self.execute(&input);
+            self.check_guard(&input);
+
+            let error = match Error::demux(result) {
+                Ok(_) => 0,
+                Err(err) => err.errno as usize
+            };
+            let path = match OPERATIONS.get(input.operation) {
+                Some(&(_, Shape::Path)) | Some(&(_, Shape::PathValue)) => input.path,
+                _ => 0
+            };
+            let key = (input.scheme.map_or(0, |scheme| scheme + 1), number, path, error);
+            if self.coverage.insert(key) {
+                self.report("new", &format!("{:?} {:?}", key, input));
+                if self.corpus.len() < FUZZ_CORPUS_MAX {
+                    self.corpus.push(input);
+                }
+            }
+        }
+    }
+
+    /// Close every handle that is still open
+    fn finish(&mut self) {
+        while let Some(handle) = self.files.pop() {
+            self.close(None, handle.id);
+        }
+        for scheme in 0..self.scheme_files.len() {
+            while let Some(handle) = self.scheme_files[scheme].pop() {
+                self.close(Some(scheme), handle.id);
+            }
+        }
+    }
+}
+
+/// Try `iterations` inputs, starting from `seed`, or from the time stamp counter if it is zero
+pub fn run(iterations: usize, seed: u64) {
+    let seed = if seed == 0 { rdtsc() } else { seed };
+
+    let mut fuzzer = Fuzzer::new(seed);
+    fuzzer.report("seed", &format!("{}", seed));
+    fuzzer.run(iterations);
+    fuzzer.finish();
+
+    let coverage = fuzzer.coverage.len();
+    let corpus = fuzzer.corpus.len();
+    let overflows = fuzzer.overflows;
+    fuzzer.report("iterations", &format!("{}", iterations));
+    fuzzer.report("coverage", &format!("{}", coverage));
+    fuzzer.report("corpus", &format!("{}", corpus));
+    fuzzer.report("overflows", &format!("{}", overflows));
+
+    *RESULTS.call_once(init_results).lock() = fuzzer.results;
+}
+
+/// Results of the last run
+pub fn results() -> Vec<u8> {
+    RESULTS.call_once(init_results).lock().clone()
+}
+
+/// Fuzz from boot, in its own context, then exit
+pub extern fn fuzz_boot() {
+    run(FUZZ_BOOT_ITERATIONS, 0);
+    println!("FUZZ done");
+    syscall::exit(0);
+}
@@ -39,17 +39,25 @@ extern crate collections;
 
 #[macro_use]
 extern crate bitflags;
+extern crate crypto;
 extern crate goblin;
+extern crate seqlock;
 extern crate spin;
 
 use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
 
+/// Benchmarks
+pub mod bench;
+
 /// Context management
 pub mod context;
 
 /// ELF file parsing
 pub mod elf;
 
+/// Fuzzing of syscalls and schemes
+pub mod fuzz;
+
 /// Schemes, filesystem handlers
 pub mod scheme;
 
@@ -109,6 +117,82 @@ pub extern fn ksignal(signal: usize) {
     }
 }
 
+/// Allow exception handlers to name the context that faulted
+#[no_mangle]
+pub extern fn kcontext_id() -> usize {
+    context::context_id()
+}
+
+/// Allow the timer to switch away from a context that was interrupted in userspace at the end of
+/// its timeslice, and another CPU to make this one switch when it gives it a context
+#[no_mangle]
+pub extern fn kpreempt() {
+    unsafe { context::switch(); }
+    kuser_return();
+}
+
+/// Allow interrupt and exception handlers to stop a context on its way back to userspace. A context
+/// is frozen here while contexts are frozen, reloads its I/O bitmap if another CPU changed it, and
+/// exits here if it was killed, as it may never make a syscall
+#[no_mangle]
+pub extern fn kuser_return() {
+    let _ = context::freezer::preempt_point();
+    syscall::reload_io_bitmap();
+
+    let killed = {
+        let contexts = context::contexts();
+        contexts.current().and_then(|context_lock| context_lock.read().killed)
+    };
+    if let Some(status) = killed {
+        syscall::exit(status);
+    }
+}
+
+/// Allow exception handlers to kill a crashed user context, after writing its core dump
+#[no_mangle]
+pub extern fn kcrash(signal: usize, regs: &[usize; context::coredump::CORE_NGREG]) -> ! {
+    if let Err(err) = context::coredump::dump(signal, regs) {
+        println!("failed to dump core of PID {}: {}", context::context_id(), err);
+    }
+    syscall::exit(signal);
+}
+
+/// Allow the panic handler to report which context panicked
+#[no_mangle]
+pub extern fn kpanic() {
+    // Locks may be held by the code that panicked, so they must not be waited on
+    println!("CPU {}, PID {}", cpu_id(), context::context_id());
+    if let Some(contexts) = context::try_contexts() {
+        if let Some(context) = contexts.current().and_then(|context_lock| context_lock.try_read()) {
+            if let Some(name) = context.name.try_lock() {
+                println!("NAME {}", unsafe { ::core::str::from_utf8_unchecked(&name) });
+            }
+        }
+    }
+}
+
+/// Allow the frame allocator to ask the arch-independant kernel to free memory
+#[no_mangle]
+pub extern fn kout_of_memory(count: usize) -> bool {
+    context::oom::out_of_memory(count)
+}
+
+/// Allow the page fault handler to resolve writes to copy-on-write pages. A fault from userspace
+/// holds no locks, so its allocation may wait for the OOM killer
+#[no_mangle]
+pub extern fn kcopy_on_write(address: usize, user: bool) -> bool {
+    let address = arch::paging::VirtualAddress::new(address);
+    let resolved = if user {
+        context::oom::allow_wait(|| context::memory::copy_on_write(address))
+    } else {
+        context::memory::copy_on_write(address)
+    };
+    if resolved {
+        context::usage::fault();
+    }
+    resolved
+}
+
 /// This is the kernel entry point for the primary CPU. The arch crate is responsible for calling this
 #[no_mangle]
 pub extern fn kmain(cpus: usize) {
@@ -120,10 +204,85 @@ pub extern fn kmain(cpus: usize) {
     let pid = syscall::getpid();
     println!("BSP: {:?} {}", pid, cpus);
 
+    match context::contexts_mut().spawn(scheme::debug::debug_output) {
+        Ok(context_lock) => {
+            let mut context = context_lock.write();
+            *context.name.lock() = b"[debug_output]".to_vec();
+            context.status = context::Status::Runnable;
+            context.oom_protected = true;
+        },
+        Err(err) => {
+            panic!("failed to spawn debug_output: {:?}", err);
+        }
+    }
+
+    match context::contexts_mut().spawn(scheme::user::watchdog) {
+        Ok(context_lock) => {
+            let mut context = context_lock.write();
+            *context.name.lock() = b"[scheme_watchdog]".to_vec();
+            context.status = context::Status::Runnable;
+            context.oom_protected = true;
+        },
+        Err(err) => {
+            panic!("failed to spawn scheme_watchdog: {:?}", err);
+        }
+    }
+
+    match context::contexts_mut().spawn(context::merge::merge_thread) {
+        Ok(context_lock) => {
+            let mut context = context_lock.write();
+            *context.name.lock() = b"[merge]".to_vec();
+            context.status = context::Status::Runnable;
+            context.oom_protected = true;
+        },
+        Err(err) => {
+            panic!("failed to spawn merge: {:?}", err);
+        }
+    }
+
+    match context::contexts_mut().spawn(context::pressure::pressure_thread) {
+        Ok(context_lock) => {
+            let mut context = context_lock.write();
+            *context.name.lock() = b"[memory_pressure]".to_vec();
+            context.status = context::Status::Runnable;
+            context.oom_protected = true;
+        },
+        Err(err) => {
+            panic!("failed to spawn memory_pressure: {:?}", err);
+        }
+    }
+
+    match context::contexts_mut().spawn(context::working_set::working_set_thread) {
+        Ok(context_lock) => {
+            let mut context = context_lock.write();
+            *context.name.lock() = b"[working_set]".to_vec();
+            context.status = context::Status::Runnable;
+            context.oom_protected = true;
+        },
+        Err(err) => {
+            panic!("failed to spawn working_set: {:?}", err);
+        }
+    }
+
+    // Fuzz kernels start fuzzing at boot, printing their results to the console
+    if cfg!(feature = "fuzz") {
+        match context::contexts_mut().spawn(fuzz::fuzz_boot) {
+            Ok(context_lock) => {
+                let mut context = context_lock.write();
+                *context.name.lock() = b"[fuzz]".to_vec();
+                context.status = context::Status::Runnable;
+            },
+            Err(err) => {
+                panic!("failed to spawn fuzz: {:?}", err);
+            }
+        }
+    }
+
     match context::contexts_mut().spawn(userspace_init) {
         Ok(context_lock) => {
             let mut context = context_lock.write();
             context.status = context::Status::Runnable;
+            context.oom_protected = true;
         },
         Err(err) => {
             panic!("failed to spawn userspace_init: {:?}", err);
@@ -131,6 +290,9 @@ pub extern fn kmain(cpus: usize) {
     }
 
     loop {
+        // Frames freed by interrupt handlers are returned to the allocator while idle
+        arch::memory::drain_deferred();
+
         unsafe {
             interrupt::disable();
             if context::switch() {
@@ -153,12 +315,10 @@ pub extern fn kmain_ap(id: usize) {
     let pid = syscall::getpid();
     println!("AP {}: {:?}", id, pid);
 
-    // Disable APs for now
     loop {
-        unsafe { interrupt::enable_and_halt(); }
-    }
+        // Frames freed by interrupt handlers are returned to the allocator while idle
+        arch::memory::drain_deferred();
 
-    loop {
         unsafe {
             interrupt::disable();
             if context::switch() {
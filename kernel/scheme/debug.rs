@@ -1,40 +1,199 @@
-use core::str;
+use collections::{BTreeMap, Vec, VecDeque};
+use core::cmp;
 use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
-use spin::Once;
+use spin::{Mutex, Once};
 
+use arch;
+use arch::console::CONSOLE;
 use context;
-use sync::WaitQueue;
+use sync::{WaitCondition, WaitQueue};
 use syscall::error::*;
 use syscall::flag::EVENT_READ;
 use syscall::scheme::Scheme;
 
 pub static DEBUG_SCHEME_ID: AtomicUsize = ATOMIC_USIZE_INIT;
 
+/// Handle for normal console access
+const HANDLE_NORMAL: usize = 0;
+
+/// Handle opened with `debug:quiet`, kernel output is suppressed while any are open
+const HANDLE_QUIET: usize = 1;
+
+/// Number of open quiet handles
+static QUIET_HANDLES: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Add a quiet handle, suppressing kernel output unless the kernel command line has `verbose`
+fn quiet_open() -> usize {
+    QUIET_HANDLES.fetch_add(1, Ordering::SeqCst);
+    if ! arch::cmdline::option("verbose") {
+        CONSOLE.lock().set_quiet(true);
+    }
+    HANDLE_QUIET
+}
+
+/// Remove a quiet handle, restoring kernel output when none are left
+fn quiet_close() {
+    if QUIET_HANDLES.fetch_sub(1, Ordering::SeqCst) == 1 {
+        CONSOLE.lock().set_quiet(false);
+    }
+}
+
+/// Limit on unread input, the oldest input is dropped to make room when it is reached
+const INPUT_MAX: usize = 16384;
+
 /// Input queue
 static INPUT: Once<WaitQueue<u8>> = Once::new();
 
+/// Number of input bytes dropped because nothing read them in time
+static INPUT_DROPPED: AtomicUsize = ATOMIC_USIZE_INIT;
+
 /// Initialize input queue, called if needed
 fn init_input() -> WaitQueue<u8> {
-    WaitQueue::new()
+    let queue = WaitQueue::new();
+    queue.inner.lock().reserve(INPUT_MAX);
+    queue
 }
 
 /// Add to the input queue
 #[no_mangle]
 pub extern fn debug_input(b: u8) {
-    let len = INPUT.call_once(init_input).send(b);
+    let input = INPUT.call_once(init_input);
+
+    let len = {
+        let mut inner = input.inner.lock();
+        if inner.len() >= INPUT_MAX {
+            inner.pop_front();
+            INPUT_DROPPED.fetch_add(1, Ordering::SeqCst);
+        }
+        inner.push_back(b);
+        inner.len()
+    };
+    input.condition.notify();
 
     context::event::trigger(DEBUG_SCHEME_ID.load(Ordering::SeqCst), 0, EVENT_READ, len);
 }
 
+/// Bytes of input that are queued, the limit, and the number dropped so far
+pub fn input_stats() -> (usize, usize, usize) {
+    let queued = INPUT.call_once(init_input).inner.lock().len();
+    (queued, INPUT_MAX, INPUT_DROPPED.load(Ordering::SeqCst))
+}
+
+/// Limit on the output a writer may have queued, writes block when it is reached
+const OUTPUT_MAX: usize = 4096;
+
+/// Limit on the output written for one writer before moving on to the next
+const OUTPUT_LINE_MAX: usize = 256;
+
+/// Output waiting to be written to the console by `debug_output`
+struct Output {
+    /// Queued output of each writer, by PID
+    queues: BTreeMap<usize, VecDeque<u8>>,
+    /// The writer that was drained last, the writer after it is drained next
+    last: usize
+}
+
+/// Output queues
+static OUTPUT: Once<Mutex<Output>> = Once::new();
+
+/// Notified when output is queued
+static OUTPUT_QUEUED: Once<WaitCondition> = Once::new();
+
+/// Notified when output is written, freeing space in a queue
+static OUTPUT_WRITTEN: Once<WaitCondition> = Once::new();
+
+/// Initialize output queues, called if needed
+fn init_output() -> Mutex<Output> {
+    Mutex::new(Output {
+        queues: BTreeMap::new(),
+        last: 0
+    })
+}
+
+/// Initialize output conditions, called if needed
+fn init_condition() -> WaitCondition {
+    WaitCondition::new()
+}
+
+/// Take the next line of output, from the writer after the one that was drained last, so that
+/// every writer gets a turn no matter how much another writes
+fn next_output() -> Option<Vec<u8>> {
+    let mut output = OUTPUT.call_once(init_output).lock();
+
+    let pid = {
+        let last = output.last;
+        match output.queues.keys().find(|&&pid| pid > last).or(output.queues.keys().next()) {
+            Some(&pid) => pid,
+            None => return None
+        }
+    };
+
+    let mut line = Vec::new();
+    let empty = {
+        let queue = output.queues.get_mut(&pid).expect("debug: output queue missing");
+        while let Some(b) = queue.pop_front() {
+            line.push(b);
+            if b == b'\n' || line.len() >= OUTPUT_LINE_MAX {
+                break;
+            }
+        }
+        queue.is_empty()
+    };
+
+    if empty {
+        output.queues.remove(&pid);
+    }
+    output.last = pid;
+
+    Some(line)
+}
+
+/// Write queued output to the console, a line from each writer in turn. This runs in its own
+/// context, so that a process writing output as fast as it can does not hold up the others
+pub extern fn debug_output() {
+    loop {
+        let mut written = 0;
+        while let Some(line) = next_output() {
+            CONSOLE.lock().write_user(&line);
+            OUTPUT_WRITTEN.call_once(init_condition).notify();
+
+            written += 1;
+            if written >= OUTPUT.call_once(init_output).lock().queues.len() {
+                break;
+            }
+        }
+
+        if written > 0 {
+            // Let the writers run before their queues are drained again
+            unsafe { context::switch(); }
+        } else {
+            // Checked again with the waiters locked, so output queued on another CPU cannot be missed
+            OUTPUT_QUEUED.call_once(init_condition).wait_unless(|| {
+                ! OUTPUT.call_once(init_output).lock().queues.is_empty()
+            });
+        }
+    }
+}
+
 pub struct DebugScheme;
 
 impl Scheme for DebugScheme {
-    fn open(&self, _path: &[u8], _flags: usize, _uid: u32, _gid: u32) -> Result<usize> {
-        Ok(0)
+    /// Open the console. A foreground process may open `debug:quiet` to take ownership of it,
+    /// keeping kernel output off the console until the handle is closed
+    fn open(&self, path: &[u8], _flags: usize, _uid: u32, _gid: u32) -> Result<usize> {
+        if path == b"quiet" {
+            Ok(quiet_open())
+        } else {
+            Ok(HANDLE_NORMAL)
+        }
     }
 
-    fn dup(&self, _file: usize, _buf: &[u8]) -> Result<usize> {
-        Ok(0)
+    fn dup(&self, file: usize, _buf: &[u8]) -> Result<usize> {
+        if file == HANDLE_QUIET {
+            Ok(quiet_open())
+        } else {
+            Ok(HANDLE_NORMAL)
+        }
     }
 
     /// Read the file `number` into the `buffer`
@@ -47,9 +206,29 @@ impl Scheme for DebugScheme {
     /// Write the `buffer` to the `file`
     ///
     /// Returns the number of bytes written
+    /// Output is queued for `debug_output`, blocking while the queue of this writer is full
     fn write(&self, _file: usize, buffer: &[u8]) -> Result<usize> {
-        //TODO: Write bytes, do not convert to str
-        print!("{}", unsafe { str::from_utf8_unchecked(buffer) });
+        let pid = context::context_id();
+
+        let mut i = 0;
+        while i < buffer.len() {
+            {
+                let mut output = OUTPUT.call_once(init_output).lock();
+                let queue = output.queues.entry(pid).or_insert_with(VecDeque::new);
+                let count = cmp::min(OUTPUT_MAX.saturating_sub(queue.len()), buffer.len() - i);
+                queue.extend(&buffer[i .. i + count]);
+                i += count;
+            }
+
+            OUTPUT_QUEUED.call_once(init_condition).notify();
+
+            if i < buffer.len() {
+                OUTPUT_WRITTEN.call_once(init_condition).wait_unless(|| {
+                    OUTPUT.call_once(init_output).lock().queues.get(&pid).map_or(true, |queue| queue.len() < OUTPUT_MAX)
+                });
+            }
+        }
+
         Ok(buffer.len())
     }
 
@@ -57,12 +236,22 @@ impl Scheme for DebugScheme {
         Ok(0)
     }
 
+    /// Wait until the output of this writer has been written to the console
     fn fsync(&self, _file: usize) -> Result<usize> {
+        let pid = context::context_id();
+        while OUTPUT.call_once(init_output).lock().queues.contains_key(&pid) {
+            OUTPUT_WRITTEN.call_once(init_condition).wait_unless(|| {
+                ! OUTPUT.call_once(init_output).lock().queues.contains_key(&pid)
+            });
+        }
         Ok(0)
     }
 
     /// Close the file `number`
-    fn close(&self, _file: usize) -> Result<usize> {
+    fn close(&self, file: usize) -> Result<usize> {
+        if file == HANDLE_QUIET {
+            quiet_close();
+        }
         Ok(0)
     }
 }
@@ -1,36 +1,89 @@
+use collections::{BTreeMap, Vec};
 use core::{mem, str};
 use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
-use spin::Mutex;
+use spin::{Once, RwLock};
 
 use arch::interrupt::irq::acknowledge;
 use context;
+use sync::{TicketMutex, WaitCondition, IRQ_LOCKS};
 use syscall::error::*;
-use syscall::flag::EVENT_READ;
+use syscall::flag::{EVENT_READ, F_GETFL, F_SETFL, O_NONBLOCK};
 use syscall::scheme::Scheme;
 
 pub static IRQ_SCHEME_ID: AtomicUsize = ATOMIC_USIZE_INIT;
 
-/// IRQ queues
-static ACKS: Mutex<[usize; 16]> = Mutex::new([0; 16]);
-static COUNTS: Mutex<[usize; 16]> = Mutex::new([0; 16]);
+/// Number of IRQs
+pub const IRQ_COUNT: usize = 16;
+
+/// IRQ queues, the counts are changed by device IRQ handlers that run with interrupts enabled
+static ACKS: TicketMutex<[usize; IRQ_COUNT]> = TicketMutex::new([0; IRQ_COUNT], &IRQ_LOCKS);
+static COUNTS: TicketMutex<[usize; IRQ_COUNT]> = TicketMutex::new([0; IRQ_COUNT], &IRQ_LOCKS);
+
+/// Contexts waiting for each IRQ
+static WAITS: Once<Vec<WaitCondition>> = Once::new();
+
+/// Initialize wait conditions, called if needed
+fn init_waits() -> Vec<WaitCondition> {
+    let mut waits = Vec::with_capacity(IRQ_COUNT);
+    for _ in 0..IRQ_COUNT {
+        waits.push(WaitCondition::new());
+    }
+    waits
+}
 
 /// Add to the input queue
+/// The line is masked by the architecture code until the IRQ is acknowledged by a write
 #[no_mangle]
 pub extern fn irq_trigger(irq: u8) {
-    COUNTS.lock()[irq as usize] += 1;
+    COUNTS.lock_irqsave()[irq as usize] += 1;
+    WAITS.call_once(init_waits)[irq as usize].notify();
     context::event::trigger(IRQ_SCHEME_ID.load(Ordering::SeqCst), irq as usize, EVENT_READ, mem::size_of::<usize>());
 }
 
-pub struct IrqScheme;
+/// The number of times each IRQ has fired
+pub fn counts() -> [usize; IRQ_COUNT] {
+    *COUNTS.lock()
+}
+
+#[derive(Clone, Copy)]
+struct Handle {
+    irq: usize,
+    flags: usize
+}
+
+/// `irq:<n>` - reads return the count of IRQ `n` once it is larger than the last acknowledged count,
+/// blocking unless `O_NONBLOCK` is set. Writing the count back acknowledges the IRQ, and unmasks it
+pub struct IrqScheme {
+    next_id: AtomicUsize,
+    handles: RwLock<BTreeMap<usize, Handle>>
+}
+
+impl IrqScheme {
+    pub fn new() -> IrqScheme {
+        IrqScheme {
+            next_id: AtomicUsize::new(0),
+            handles: RwLock::new(BTreeMap::new())
+        }
+    }
+
+    fn handle(&self, id: usize) -> Result<Handle> {
+        self.handles.read().get(&id).map(|handle| *handle).ok_or(Error::new(EBADF))
+    }
+}
 
 impl Scheme for IrqScheme {
-    fn open(&self, path: &[u8], _flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+    fn open(&self, path: &[u8], flags: usize, uid: u32, _gid: u32) -> Result<usize> {
         if uid == 0 {
             let path_str = str::from_utf8(path).or(Err(Error::new(ENOENT)))?;
 
-            let id = path_str.parse::<usize>().or(Err(Error::new(ENOENT)))?;
+            let irq = path_str.parse::<usize>().or(Err(Error::new(ENOENT)))?;
 
-            if id < COUNTS.lock().len() {
+            if irq < IRQ_COUNT {
+                let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+                self.handles.write().insert(id, Handle {
+                    irq: irq,
+                    flags: flags
+                });
                 Ok(id)
             } else {
                 Err(Error::new(ENOENT))
@@ -41,21 +94,34 @@ impl Scheme for IrqScheme {
     }
 
     fn dup(&self, file: usize, _buf: &[u8]) -> Result<usize> {
-        Ok(file)
+        let handle = self.handle(file)?;
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.handles.write().insert(id, handle);
+        Ok(id)
     }
 
     fn read(&self, file: usize, buffer: &mut [u8]) -> Result<usize> {
+        let handle = self.handle(file)?;
+
         // Ensures that the length of the buffer is larger than the size of a usize
         if buffer.len() >= mem::size_of::<usize>() {
-            let ack = ACKS.lock()[file];
-            let current = COUNTS.lock()[file];
-            if ack != current {
-                // Safe if the length of the buffer is larger than the size of a usize
-                assert!(buffer.len() >= mem::size_of::<usize>());
-                unsafe { *(buffer.as_mut_ptr() as *mut usize) = current; }
-                Ok(mem::size_of::<usize>())
-            } else {
-                Ok(0)
+            loop {
+                let ack = ACKS.lock()[handle.irq];
+                let current = COUNTS.lock()[handle.irq];
+                if ack != current {
+                    // Safe if the length of the buffer is larger than the size of a usize
+                    assert!(buffer.len() >= mem::size_of::<usize>());
+                    unsafe { *(buffer.as_mut_ptr() as *mut usize) = current; }
+                    return Ok(mem::size_of::<usize>());
+                } else if handle.flags & O_NONBLOCK == O_NONBLOCK {
+                    return Ok(0);
+                } else {
+                    // Checked again with the waiters locked, so an IRQ handled on another CPU cannot be missed
+                    WAITS.call_once(init_waits)[handle.irq].wait_unless(|| {
+                        let ack = ACKS.lock()[handle.irq];
+                        ack != COUNTS.lock()[handle.irq]
+                    });
+                }
             }
         } else {
             Err(Error::new(EINVAL))
@@ -63,13 +129,15 @@ impl Scheme for IrqScheme {
     }
 
     fn write(&self, file: usize, buffer: &[u8]) -> Result<usize> {
+        let handle = self.handle(file)?;
+
         if buffer.len() >= mem::size_of::<usize>() {
             assert!(buffer.len() >= mem::size_of::<usize>());
             let ack = unsafe { *(buffer.as_ptr() as *const usize) };
-            let current = COUNTS.lock()[file];
+            let current = COUNTS.lock()[handle.irq];
             if ack == current {
-                ACKS.lock()[file] = ack;
-                unsafe { acknowledge(file); }
+                ACKS.lock()[handle.irq] = ack;
+                unsafe { acknowledge(handle.irq); }
                 Ok(mem::size_of::<usize>())
             } else {
                 Ok(0)
@@ -79,15 +147,29 @@ impl Scheme for IrqScheme {
         }
     }
 
+    fn fcntl(&self, file: usize, cmd: usize, arg: usize) -> Result<usize> {
+        let mut handles = self.handles.write();
+        let mut handle = handles.get_mut(&file).ok_or(Error::new(EBADF))?;
+        match cmd {
+            F_GETFL => Ok(handle.flags),
+            F_SETFL => {
+                handle.flags = arg;
+                Ok(0)
+            },
+            _ => Err(Error::new(EINVAL))
+        }
+    }
+
     fn fevent(&self, file: usize, _flags: usize) -> Result<usize> {
-        Ok(file)
+        // Events are triggered with the IRQ number as the ID
+        self.handle(file).map(|handle| handle.irq)
     }
 
     fn fsync(&self, _file: usize) -> Result<usize> {
         Ok(0)
     }
 
-    fn close(&self, _file: usize) -> Result<usize> {
-        Ok(0)
+    fn close(&self, file: usize) -> Result<usize> {
+        self.handles.write().remove(&file).ok_or(Error::new(EBADF)).and(Ok(0))
     }
 }
@@ -0,0 +1,137 @@
+use collections::BTreeMap;
+use core::cmp;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::RwLock;
+
+use arch::console::CONSOLE;
+use arch::pstore;
+use syscall::error::*;
+use syscall::flag::{SEEK_CUR, SEEK_END, SEEK_SET};
+use syscall::scheme::Scheme;
+
+struct Handle {
+    /// Opened as `log:last`, the log of the previous boot
+    last: bool,
+    /// Position in the log. For this boot's log, it is counted from the first byte ever logged
+    position: usize
+}
+
+/// Reads the kernel log, from the oldest line it still holds. `log:last` reads the log of the
+/// boot before a warm reboot, if it survived
+pub struct LogScheme {
+    next_id: AtomicUsize,
+    handles: RwLock<BTreeMap<usize, Handle>>
+}
+
+impl LogScheme {
+    pub fn new() -> LogScheme {
+        LogScheme {
+            next_id: AtomicUsize::new(0),
+            handles: RwLock::new(BTreeMap::new())
+        }
+    }
+}
+
+impl Scheme for LogScheme {
+    fn open(&self, path: &[u8], _flags: usize, _uid: u32, _gid: u32) -> Result<usize> {
+        let handle = if path.is_empty() {
+            Handle {
+                last: false,
+                position: CONSOLE.lock().log_range().0
+            }
+        } else if path == b"last" {
+            Handle {
+                last: true,
+                position: 0
+            }
+        } else {
+            return Err(Error::new(ENOENT));
+        };
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.handles.write().insert(id, handle);
+        Ok(id)
+    }
+
+    fn dup(&self, file: usize, _buf: &[u8]) -> Result<usize> {
+        let handle = {
+            let handles = self.handles.read();
+            let handle = handles.get(&file).ok_or(Error::new(EBADF))?;
+            Handle {
+                last: handle.last,
+                position: handle.position
+            }
+        };
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.handles.write().insert(id, handle);
+        Ok(id)
+    }
+
+    /// Read the log, lines that were overwritten since the last read are skipped
+    fn read(&self, file: usize, buf: &mut [u8]) -> Result<usize> {
+        let mut handles = self.handles.write();
+        let handle = handles.get_mut(&file).ok_or(Error::new(EBADF))?;
+
+        if handle.last {
+            let last = pstore::last();
+            let start = cmp::min(handle.position, last.len());
+            let count = cmp::min(buf.len(), last.len() - start);
+            buf[.. count].copy_from_slice(&last[start .. start + count]);
+            handle.position = start + count;
+            return Ok(count);
+        }
+
+        let console = CONSOLE.lock();
+        let (start, _end) = console.log_range();
+        if handle.position < start {
+            handle.position = start;
+        }
+        let count = console.read_log(handle.position, buf);
+        handle.position += count;
+
+        Ok(count)
+    }
+
+    /// Seek within the log, where 0 is the oldest byte it still holds
+    fn seek(&self, file: usize, pos: usize, whence: usize) -> Result<usize> {
+        let mut handles = self.handles.write();
+        let handle = handles.get_mut(&file).ok_or(Error::new(EBADF))?;
+
+        let (start, end) = if handle.last {
+            (0, pstore::last().len())
+        } else {
+            CONSOLE.lock().log_range()
+        };
+        let len = end - start;
+        let current = handle.position.saturating_sub(start);
+        let new = match whence {
+            SEEK_SET => pos as isize,
+            SEEK_CUR => current as isize + pos as isize,
+            SEEK_END => len as isize + pos as isize,
+            _ => return Err(Error::new(EINVAL))
+        };
+        let new = if new < 0 { 0 } else if new as usize > len { len } else { new as usize };
+        handle.position = start + new;
+
+        Ok(new)
+    }
+
+    fn fpath(&self, file: usize, buf: &mut [u8]) -> Result<usize> {
+        let last = self.handles.read().get(&file).ok_or(Error::new(EBADF))?.last;
+
+        let path: &[u8] = if last { b"log:last" } else { b"log:" };
+
+        let mut i = 0;
+        while i < buf.len() && i < path.len() {
+            buf[i] = path[i];
+            i += 1;
+        }
+
+        Ok(i)
+    }
+
+    fn close(&self, file: usize) -> Result<usize> {
+        self.handles.write().remove(&file).ok_or(Error::new(EBADF)).and(Ok(0))
+    }
+}
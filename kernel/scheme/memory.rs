@@ -0,0 +1,200 @@
+use collections::{BTreeMap, String, Vec};
+use core::{cmp, str};
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+use spin::RwLock;
+
+use arch::allocator;
+use arch::allocator::slab::{self, SLAB_SIZES};
+use arch::memory::{free_frames, used_frames, DEFERRED_FREES, LEAKED_FRAMES, PAGE_SIZE};
+use arch::paging::mmio_ranges;
+use context;
+use context::memory::shared_frames;
+use context::merge::MERGED_PAGES;
+use context::oom::resident_pages;
+use context::pressure;
+use syscall::error::*;
+use syscall::flag::{SEEK_CUR, SEEK_END, SEEK_SET};
+use syscall::scheme::Scheme;
+
+/// The ID of the memory scheme, used to send memory pressure events
+pub static MEMORY_SCHEME_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+struct Handle {
+    /// The counters when the handle was opened, or the memory pressure level when it was last read
+    /// from the start
+    data: Vec<u8>,
+    seek: usize,
+    /// The handle was opened as `memory:pressure`
+    pressure: bool
+}
+
+/// Frame allocator counters, as lines of `KEY VALUE`. Counts are in frames of `frame_size` bytes,
+/// except for `heap_size` and `heap_used`, which are the kernel heap in bytes. These are followed by
+///
+/// - `slab_SIZE USED TOTAL` for each kernel heap object cache
+/// - `context PID PAGES NAME` for each context, with the pages it would release on exit
+/// - `physmap PID PHYSICAL SIZE` for each range of device memory, like a framebuffer, mapped by a context
+/// - `mmio PHYSICAL VIRTUAL SIZE` for each range of device memory mapped by the kernel
+///
+/// Addresses are in hexadecimal, and sizes of ranges are in bytes.
+///
+/// `memory:pressure` is the memory pressure level, `none`, `low`, `medium`, or `critical`. It is
+/// read again when read from the start, and sends an event, with the new level as the data, when
+/// the level changes
+pub struct MemoryScheme {
+    next_id: AtomicUsize,
+    handles: RwLock<BTreeMap<usize, Handle>>
+}
+
+impl MemoryScheme {
+    pub fn new() -> MemoryScheme {
+        MemoryScheme {
+            next_id: AtomicUsize::new(0),
+            handles: RwLock::new(BTreeMap::new())
+        }
+    }
+}
+
+/// Take a snapshot of the counters
+fn counters() -> Vec<u8> {
+    let free = free_frames();
+    let used = used_frames();
+    let mut string = format!("frame_size {}\ntotal {}\nfree {}\nused {}\nshared {}\nmerged {}\ndeferred_frees {}\nleaked {}\n",
+            PAGE_SIZE,
+            free + used,
+            free,
+            used,
+            shared_frames(),
+            MERGED_PAGES.load(Ordering::Relaxed),
+            DEFERRED_FREES.load(Ordering::Relaxed),
+            LEAKED_FRAMES.load(Ordering::Relaxed));
+    let (heap_size, heap_used) = allocator::heap_stats();
+    string.push_str(&format!("heap_size {}\nheap_used {}\n", heap_size, heap_used));
+    for (size, &(objects, allocated)) in SLAB_SIZES.iter().zip(slab::stats().iter()) {
+        string.push_str(&format!("slab_{} {} {}\n", size, allocated, objects));
+    }
+
+    let mut physmaps = String::new();
+    {
+        let contexts = context::contexts();
+        for (_id, context_lock) in contexts.iter() {
+            let context = context_lock.read();
+
+            let name_bytes = context.name.lock();
+            let name = str::from_utf8(&name_bytes).unwrap_or("");
+            string.push_str(&format!("context {} {} {}\n", context.id, resident_pages(&context), name));
+
+            for grant in context.grants.read().iter() {
+                if let Some(physical) = grant.physical_address() {
+                    physmaps.push_str(&format!("physmap {} {:X} {}\n", context.id, physical.get(), grant.size()));
+                }
+            }
+        }
+    }
+    string.push_str(&physmaps);
+
+    mmio_ranges(|physical, virt, size| {
+        string.push_str(&format!("mmio {:X} {:X} {}\n", physical.get(), virt.get(), size));
+    });
+
+    string.into_bytes()
+}
+
+/// The memory pressure level, as a line
+fn pressure_level() -> Vec<u8> {
+    format!("{}\n", pressure::level_name(pressure::level())).into_bytes()
+}
+
+impl Scheme for MemoryScheme {
+    fn open(&self, path: &[u8], _flags: usize, _uid: u32, _gid: u32) -> Result<usize> {
+        let pressure = if path.is_empty() {
+            false
+        } else if path == b"pressure" {
+            true
+        } else {
+            return Err(Error::new(ENOENT));
+        };
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.handles.write().insert(id, Handle {
+            data: if pressure { pressure_level() } else { counters() },
+            seek: 0,
+            pressure: pressure
+        });
+        Ok(id)
+    }
+
+    /// Duplicate a handle, which takes a new snapshot of the counters
+    fn dup(&self, file: usize, _buf: &[u8]) -> Result<usize> {
+        let pressure = self.handles.read().get(&file).ok_or(Error::new(EBADF))?.pressure;
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.handles.write().insert(id, Handle {
+            data: if pressure { pressure_level() } else { counters() },
+            seek: 0,
+            pressure: pressure
+        });
+        Ok(id)
+    }
+
+    fn read(&self, file: usize, buf: &mut [u8]) -> Result<usize> {
+        let mut handles = self.handles.write();
+        let handle = handles.get_mut(&file).ok_or(Error::new(EBADF))?;
+
+        if handle.pressure && handle.seek == 0 {
+            handle.data = pressure_level();
+        }
+
+        let start = cmp::min(handle.seek, handle.data.len());
+        let count = cmp::min(buf.len(), handle.data.len() - start);
+        buf[.. count].copy_from_slice(&handle.data[start .. start + count]);
+        handle.seek = start + count;
+
+        Ok(count)
+    }
+
+    fn seek(&self, file: usize, pos: usize, whence: usize) -> Result<usize> {
+        let mut handles = self.handles.write();
+        let handle = handles.get_mut(&file).ok_or(Error::new(EBADF))?;
+
+        let len = handle.data.len();
+        let new = match whence {
+            SEEK_SET => pos as isize,
+            SEEK_CUR => handle.seek as isize + pos as isize,
+            SEEK_END => len as isize + pos as isize,
+            _ => return Err(Error::new(EINVAL))
+        };
+        handle.seek = if new < 0 { 0 } else if new as usize > len { len } else { new as usize };
+
+        Ok(handle.seek)
+    }
+
+    /// Only `memory:pressure` sends events
+    fn fevent(&self, file: usize, _flags: usize) -> Result<usize> {
+        let handles = self.handles.read();
+        let handle = handles.get(&file).ok_or(Error::new(EBADF))?;
+        if handle.pressure {
+            Ok(0)
+        } else {
+            Err(Error::new(EINVAL))
+        }
+    }
+
+    fn fpath(&self, file: usize, buf: &mut [u8]) -> Result<usize> {
+        let pressure = self.handles.read().get(&file).ok_or(Error::new(EBADF))?.pressure;
+
+        let path: &[u8] = if pressure { b"memory:pressure" } else { b"memory:" };
+
+        let mut i = 0;
+        while i < buf.len() && i < path.len() {
+            buf[i] = path[i];
+            i += 1;
+        }
+
+        Ok(i)
+    }
+
+    fn close(&self, file: usize) -> Result<usize> {
+        self.handles.write().remove(&file).ok_or(Error::new(EBADF)).and(Ok(0))
+    }
+}
@@ -10,8 +10,9 @@ use alloc::arc::Arc;
 use alloc::boxed::Box;
 use collections::BTreeMap;
 use core::sync::atomic::Ordering;
-use spin::{Once, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use spin::Once;
 
+use sync::{RwLock, RwLockReadGuard, RwLockWriteGuard, SCHEME_LOCKS};
 use syscall::error::*;
 use syscall::scheme::Scheme;
 
@@ -20,10 +21,14 @@ use self::event::EventScheme;
 use self::env::EnvScheme;
 use self::initfs::InitFsScheme;
 use self::irq::{IRQ_SCHEME_ID, IrqScheme};
+use self::log::LogScheme;
+use self::memory::{MEMORY_SCHEME_ID, MemoryScheme};
 use self::null::NullScheme;
 use self::pipe::{PIPE_SCHEME_ID, PipeScheme};
 use self::root::{ROOT_SCHEME_ID, RootScheme};
+use self::shm::ShmScheme;
 use self::sys::SysScheme;
+use self::trace::TraceScheme;
 use self::zero::ZeroScheme;
 
 /// `debug:` - provides access to serial console
@@ -41,6 +46,15 @@ pub mod initfs;
 /// `irq:` - allows userspace handling of IRQs
 pub mod irq;
 
+/// `log:` - the kernel log, with the lines the kernel has printed since boot
+pub mod log;
+
+/// `memory:` - counters of the physical frame allocator, and memory pressure events
+pub mod memory;
+
+/// Mount table, which attaches schemes at paths in the namespace
+pub mod mount;
+
 /// `null:` - a scheme that will discard all writes, and read no bytes
 pub mod null;
 
@@ -50,9 +64,15 @@ pub mod pipe;
 /// `:` - allows the creation of userspace schemes, tightly dependent on `user`
 pub mod root;
 
+/// `shm:` - named segments of memory that contexts can map to share it
+pub mod shm;
+
 /// `sys:` - system information, such as the context list and scheme list
 pub mod sys;
 
+/// `trace:` - syscall records of a traced context
+pub mod trace;
+
 /// A wrapper around userspace schemes, tightly dependent on `root`
 pub mod user;
 
@@ -126,6 +146,15 @@ impl SchemeList {
 
         Ok(id)
     }
+
+    /// Remove a scheme, so that its name can be used again.
+    pub fn remove(&mut self, id: usize) -> Option<Arc<Box<Scheme + Send + Sync>>> {
+        let name_option = self.names.iter().find(|&(_name, &other_id)| other_id == id).map(|(name, _id)| name.clone());
+        if let Some(name) = name_option {
+            self.names.remove(&name);
+        }
+        self.map.remove(&id)
+    }
 }
 
 /// Schemes list
@@ -139,12 +168,16 @@ fn init_schemes() -> RwLock<SchemeList> {
     list.insert(Box::new(*b"event"), Arc::new(Box::new(EventScheme::new()))).expect("failed to insert event scheme");
     list.insert(Box::new(*b"env"), Arc::new(Box::new(EnvScheme::new()))).expect("failed to insert env scheme");
     list.insert(Box::new(*b"initfs"), Arc::new(Box::new(InitFsScheme::new()))).expect("failed to insert initfs scheme");
-    IRQ_SCHEME_ID.store(list.insert(Box::new(*b"irq"), Arc::new(Box::new(IrqScheme))).expect("failed to insert irq scheme"), Ordering::SeqCst);
+    IRQ_SCHEME_ID.store(list.insert(Box::new(*b"irq"), Arc::new(Box::new(IrqScheme::new()))).expect("failed to insert irq scheme"), Ordering::SeqCst);
+    list.insert(Box::new(*b"log"), Arc::new(Box::new(LogScheme::new()))).expect("failed to insert log scheme");
+    MEMORY_SCHEME_ID.store(list.insert(Box::new(*b"memory"), Arc::new(Box::new(MemoryScheme::new()))).expect("failed to insert memory scheme"), Ordering::SeqCst);
     list.insert(Box::new(*b"null"), Arc::new(Box::new(NullScheme))).expect("failed to insert null scheme");
     PIPE_SCHEME_ID.store(list.insert(Box::new(*b"pipe"), Arc::new(Box::new(PipeScheme))).expect("failed to insert pipe scheme"), Ordering::SeqCst);
+    list.insert(Box::new(*b"shm"), Arc::new(Box::new(ShmScheme::new()))).expect("failed to insert shm scheme");
     list.insert(Box::new(*b"sys"), Arc::new(Box::new(SysScheme::new()))).expect("failed to insert sys scheme");
+    list.insert(Box::new(*b"trace"), Arc::new(Box::new(TraceScheme::new()))).expect("failed to insert trace scheme");
     list.insert(Box::new(*b"zero"), Arc::new(Box::new(ZeroScheme))).expect("failed to insert zero scheme");
-    RwLock::new(list)
+    RwLock::new(list, &SCHEME_LOCKS)
 }
 
 /// Get the global schemes list, const
@@ -0,0 +1,150 @@
+//! # Mounts
+//! A mount attaches a scheme, or a directory of one, at a path in the namespace. Paths are
+//! rewritten by the mount table after they are made canonical, and before the scheme is looked up,
+//! so a mount of `tmp:` at `file:/tmp` sends `file:/tmp/a` to `tmp:/a`.
+
+use collections::Vec;
+use spin::Once;
+
+use context;
+use sync::{RwLock, RwLockReadGuard, RwLockWriteGuard, MOUNT_LOCKS};
+use syscall::error::*;
+
+/// A mounted scheme
+pub struct Mount {
+    /// Identifies the files opened through this mount
+    pub id: usize,
+    /// Canonical path that the mount covers
+    pub path: Vec<u8>,
+    /// Canonical path that the covered paths are sent to
+    pub target: Vec<u8>
+}
+
+impl Mount {
+    /// Return the part of `path` below this mount, if it is covered by it
+    fn strip<'a>(&self, path: &'a [u8]) -> Option<&'a [u8]> {
+        if path.starts_with(&self.path) {
+            let rest = &path[self.path.len()..];
+            if rest.is_empty() || rest[0] == b'/' || self.path.ends_with(b":") {
+                return Some(rest);
+            }
+        }
+        None
+    }
+}
+
+/// Remove trailing slashes, keeping the one after the scheme name
+fn trim(path: &[u8]) -> Vec<u8> {
+    let mut end = path.len();
+    while end > 1 && path[end - 1] == b'/' && path[end - 2] != b':' {
+        end -= 1;
+    }
+    path[..end].to_vec()
+}
+
+/// Mount table type
+pub struct MountTable {
+    mounts: Vec<Mount>,
+    next_id: usize
+}
+
+impl MountTable {
+    /// Create a new mount table.
+    pub fn new() -> Self {
+        MountTable {
+            mounts: Vec::new(),
+            next_id: 1
+        }
+    }
+
+    pub fn iter(&self) -> ::core::slice::Iter<Mount> {
+        self.mounts.iter()
+    }
+
+    /// Rewrite a canonical path through the mount that covers the most of it.
+    /// Returns the new path, and the ID of the mount if there was one
+    pub fn resolve(&self, path: Vec<u8>) -> (Vec<u8>, Option<usize>) {
+        let mut best: Option<&Mount> = None;
+        for mount in self.mounts.iter() {
+            if mount.strip(&path).is_some() && best.map_or(true, |other| mount.path.len() > other.path.len()) {
+                best = Some(mount);
+            }
+        }
+
+        if let Some(mount) = best {
+            let rest = &path[mount.path.len()..];
+            let mut resolved = mount.target.clone();
+            if resolved.ends_with(b"/") && rest.starts_with(b"/") {
+                resolved.extend_from_slice(&rest[1..]);
+            } else {
+                resolved.extend_from_slice(rest);
+            }
+            (resolved, Some(mount.id))
+        } else {
+            (path, None)
+        }
+    }
+
+    /// Mount `target` at `path`, both canonical
+    pub fn insert(&mut self, path: &[u8], target: &[u8]) -> Result<usize> {
+        if ! path.contains(&b':') || ! target.contains(&b':') {
+            return Err(Error::new(EINVAL));
+        }
+
+        let path = trim(path);
+        if self.mounts.iter().any(|mount| mount.path == path) {
+            return Err(Error::new(EEXIST));
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.mounts.push(Mount {
+            id: id,
+            path: path,
+            target: trim(target)
+        });
+
+        Ok(id)
+    }
+
+    /// Remove the mount at `path`, unless a context has a file open through it, or is working in it
+    pub fn remove(&mut self, path: &[u8]) -> Result<Mount> {
+        let path = trim(path);
+        let index = self.mounts.iter().position(|mount| mount.path == path).ok_or(Error::new(ENOENT))?;
+
+        {
+            let mount = &self.mounts[index];
+            let contexts = context::contexts();
+            for (_id, context_lock) in contexts.iter() {
+                let context = context_lock.read();
+                if mount.strip(&context.cwd.lock()).is_some() {
+                    return Err(Error::new(EBUSY));
+                }
+                if context.files.lock().iter().any(|file_option| file_option.as_ref().map_or(false, |file| file.mount == Some(mount.id))) {
+                    return Err(Error::new(EBUSY));
+                }
+            }
+        }
+
+        Ok(self.mounts.remove(index))
+    }
+}
+
+/// Mount table
+static MOUNTS: Once<RwLock<MountTable>> = Once::new();
+
+/// Initialize the mount table, called if needed
+fn init_mounts() -> RwLock<MountTable> {
+    RwLock::new(MountTable::new(), &MOUNT_LOCKS)
+}
+
+/// Get the global mount table, const
+pub fn mounts() -> RwLockReadGuard<'static, MountTable> {
+    MOUNTS.call_once(init_mounts).read()
+}
+
+/// Get the global mount table, mutable
+pub fn mounts_mut() -> RwLockWriteGuard<'static, MountTable> {
+    MOUNTS.call_once(init_mounts).write()
+}
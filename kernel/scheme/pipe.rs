@@ -186,7 +186,10 @@ impl PipeRead {
             } else if self.flags.load(Ordering::SeqCst) & O_NONBLOCK == O_NONBLOCK {
                 return Err(Error::new(EAGAIN));
             } else {
-                self.condition.wait();
+                // Checked again with the waiters locked, so a write or close on another CPU cannot be missed
+                self.condition.wait_unless(|| {
+                    ! self.vec.lock().is_empty() || Arc::weak_count(&self.vec) == 0
+                });
             }
         }
     }
@@ -1,27 +1,78 @@
 use alloc::arc::Arc;
 use alloc::boxed::Box;
-use collections::BTreeMap;
+use collections::{BTreeMap, Vec};
+use core::mem;
 use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
 use spin::RwLock;
 
 use context;
 use syscall::error::*;
+use syscall::flag::{EVENT_READ, O_NONBLOCK};
 use syscall::scheme::Scheme;
 use scheme;
-use scheme::user::{UserInner, UserScheme};
+use scheme::user::{UserInner, UserScheme, UserSlot};
 
 pub static ROOT_SCHEME_ID: AtomicUsize = ATOMIC_USIZE_INIT;
 
+/// Opening `:supervise/<name>` makes the caller a supervisor of the scheme `<name>`
+const SUPERVISE_PREFIX: &'static [u8] = b"supervise/";
+
+#[derive(Clone)]
+enum Handle {
+    /// Provides a scheme, reading requests and writing responses
+    Provider(Arc<UserInner>, Arc<UserSlot>),
+    /// Supervises a scheme, reading the number of times its provider has closed it, followed by the
+    /// number of times a provider has stalled if the buffer has room for it.
+    /// While a scheme is supervised, its name is kept when the provider closes it, and
+    /// opens wait for a new provider
+    Supervisor {
+        slot: Arc<UserSlot>,
+        flags: usize,
+        seen: (usize, usize)
+    }
+}
+
 pub struct RootScheme {
     next_id: AtomicUsize,
-    handles: RwLock<BTreeMap<usize, Arc<UserInner>>>
+    handles: RwLock<BTreeMap<usize, Handle>>,
+    /// Userspace schemes, by name
+    slots: RwLock<BTreeMap<Box<[u8]>, Arc<UserSlot>>>
 }
 
 impl RootScheme {
     pub fn new() -> RootScheme {
         RootScheme {
             next_id: AtomicUsize::new(0),
-            handles: RwLock::new(BTreeMap::new())
+            handles: RwLock::new(BTreeMap::new()),
+            slots: RwLock::new(BTreeMap::new())
+        }
+    }
+
+    /// Get the slot for a userspace scheme, creating it if the name is not used
+    fn slot(&self, name: &[u8]) -> Result<Arc<UserSlot>> {
+        let mut slots = self.slots.write();
+        if let Some(slot) = slots.get(name) {
+            return Ok(slot.clone());
+        }
+
+        let mut schemes = scheme::schemes_mut();
+        if schemes.get_name(name).is_some() {
+            return Err(Error::new(EEXIST));
+        }
+
+        let slot = Arc::new(UserSlot::new(name.to_vec().into_boxed_slice()));
+        let scheme_id = schemes.insert(name.to_vec().into_boxed_slice(), Arc::new(Box::new(UserScheme::new(slot.clone()))))?;
+        slot.scheme_id.store(scheme_id, Ordering::SeqCst);
+        slots.insert(name.to_vec().into_boxed_slice(), slot.clone());
+
+        Ok(slot)
+    }
+
+    /// Remove a userspace scheme that has neither a provider nor a supervisor
+    fn release(&self, slot: &Arc<UserSlot>) {
+        if slot.supervisors.load(Ordering::SeqCst) == 0 && slot.provider().is_none() {
+            self.slots.write().remove(&slot.name);
+            scheme::schemes_mut().remove(slot.scheme_id.load(Ordering::SeqCst));
         }
     }
 }
@@ -37,18 +88,26 @@ impl Scheme for RootScheme {
 
             let id = self.next_id.fetch_add(1, Ordering::SeqCst);
 
-            let inner = {
-                let mut schemes = scheme::schemes_mut();
-                if schemes.get_name(path).is_some() {
-                    return Err(Error::new(EEXIST));
+            let handle = if path.starts_with(SUPERVISE_PREFIX) {
+                let slot = self.slot(&path[SUPERVISE_PREFIX.len()..])?;
+                slot.supervisors.fetch_add(1, Ordering::SeqCst);
+                slot.watchers.lock().insert(id);
+                let seen = (slot.exits.load(Ordering::SeqCst), slot.stalls.load(Ordering::SeqCst));
+                Handle::Supervisor {
+                    slot: slot,
+                    flags: flags,
+                    seen: seen
                 }
-                let inner = Arc::new(UserInner::new(id, flags, context));
-                let scheme_id = schemes.insert(path.to_vec().into_boxed_slice(), Arc::new(Box::new(UserScheme::new(Arc::downgrade(&inner))))).expect("failed to insert user scheme");
-                inner.scheme_id.store(scheme_id, Ordering::SeqCst);
-                inner
+            } else {
+                let slot = self.slot(path)?;
+                let inner = Arc::new(UserInner::new(id, flags, path.to_vec().into_boxed_slice(), context, Arc::downgrade(&slot)));
+                inner.scheme_id.store(slot.scheme_id.load(Ordering::SeqCst), Ordering::SeqCst);
+                slot.register(&inner)?;
+                UserInner::register(&inner);
+                Handle::Provider(inner, slot)
             };
 
-            self.handles.write().insert(id, inner);
+            self.handles.write().insert(id, handle);
 
             Ok(id)
         } else {
@@ -58,58 +117,139 @@ impl Scheme for RootScheme {
 
     fn dup(&self, file: usize, _buf: &[u8]) -> Result<usize> {
         let mut handles = self.handles.write();
-        let inner = {
-            let inner = handles.get(&file).ok_or(Error::new(EBADF))?;
-            inner.clone()
+        let handle = {
+            let handle = handles.get(&file).ok_or(Error::new(EBADF))?;
+            handle.clone()
         };
 
         let id = self.next_id.fetch_add(1, Ordering::SeqCst);
-        handles.insert(id, inner);
+
+        if let Handle::Supervisor { ref slot, .. } = handle {
+            slot.supervisors.fetch_add(1, Ordering::SeqCst);
+            slot.watchers.lock().insert(id);
+        }
+
+        handles.insert(id, handle);
 
         Ok(id)
     }
 
     fn read(&self, file: usize, buf: &mut [u8]) -> Result<usize> {
-        let inner = {
+        let handle = {
             let handles = self.handles.read();
-            let inner = handles.get(&file).ok_or(Error::new(EBADF))?;
-            inner.clone()
+            let handle = handles.get(&file).ok_or(Error::new(EBADF))?;
+            handle.clone()
         };
 
-        inner.read(buf)
+        match handle {
+            Handle::Provider(inner, _slot) => inner.read(buf),
+            Handle::Supervisor { slot, flags, seen } => {
+                if buf.len() < mem::size_of::<usize>() {
+                    return Err(Error::new(EINVAL));
+                }
+
+                loop {
+                    let exits = slot.exits.load(Ordering::SeqCst);
+                    let stalls = slot.stalls.load(Ordering::SeqCst);
+                    if (exits, stalls) != seen {
+                        if let Some(&mut Handle::Supervisor { ref mut seen, .. }) = self.handles.write().get_mut(&file) {
+                            *seen = (exits, stalls);
+                        }
+
+                        // Safe if the length of the buffer is larger than the size of a usize
+                        assert!(buf.len() >= mem::size_of::<usize>());
+                        unsafe { *(buf.as_mut_ptr() as *mut usize) = exits; }
+                        if buf.len() >= mem::size_of::<usize>() * 2 {
+                            unsafe { *(buf.as_mut_ptr() as *mut usize).offset(1) = stalls; }
+                            return Ok(mem::size_of::<usize>() * 2);
+                        }
+                        return Ok(mem::size_of::<usize>());
+                    } else if flags & O_NONBLOCK == O_NONBLOCK {
+                        return Ok(0);
+                    } else {
+                        // Checked again with the waiters locked, so an exit or stall on another CPU cannot be missed
+                        slot.exited.wait_unless(|| {
+                            (slot.exits.load(Ordering::SeqCst), slot.stalls.load(Ordering::SeqCst)) != seen
+                        });
+                    }
+                }
+            }
+        }
     }
 
     fn write(&self, file: usize, buf: &[u8]) -> Result<usize> {
-        let inner = {
+        let handle = {
             let handles = self.handles.read();
-            let inner = handles.get(&file).ok_or(Error::new(EBADF))?;
-            inner.clone()
+            let handle = handles.get(&file).ok_or(Error::new(EBADF))?;
+            handle.clone()
         };
 
-        inner.write(buf)
+        match handle {
+            Handle::Provider(inner, _slot) => inner.write(buf),
+            Handle::Supervisor { .. } => Err(Error::new(EINVAL))
+        }
     }
 
     fn fevent(&self, file: usize, flags: usize) -> Result<usize> {
-        let inner = {
+        let handle = {
             let handles = self.handles.read();
-            let inner = handles.get(&file).ok_or(Error::new(EBADF))?;
-            inner.clone()
+            let handle = handles.get(&file).ok_or(Error::new(EBADF))?;
+            handle.clone()
         };
 
-        inner.fevent(flags)
+        match handle {
+            Handle::Provider(inner, _slot) => inner.fevent(flags),
+            Handle::Supervisor { .. } => Ok(file)
+        }
     }
 
     fn fsync(&self, file: usize) -> Result<usize> {
-        let inner = {
+        let handle = {
             let handles = self.handles.read();
-            let inner = handles.get(&file).ok_or(Error::new(EBADF))?;
-            inner.clone()
+            let handle = handles.get(&file).ok_or(Error::new(EBADF))?;
+            handle.clone()
         };
 
-        inner.fsync()
+        match handle {
+            Handle::Provider(inner, _slot) => inner.fsync(),
+            Handle::Supervisor { .. } => Ok(0)
+        }
     }
 
     fn close(&self, file: usize) -> Result<usize> {
-        self.handles.write().remove(&file).ok_or(Error::new(EBADF)).and(Ok(0))
+        let handle = self.handles.write().remove(&file).ok_or(Error::new(EBADF))?;
+
+        match handle {
+            Handle::Provider(inner, slot) => {
+                // The provider has closed the scheme once its last handle is closed
+                let mut supervisors = Vec::new();
+                let mut last = true;
+                for (id, handle) in self.handles.read().iter() {
+                    match *handle {
+                        Handle::Provider(ref other, _) => if &**other as *const UserInner == &*inner as *const UserInner {
+                            last = false;
+                        },
+                        Handle::Supervisor { slot: ref other, .. } => if &**other as *const UserSlot == &*slot as *const UserSlot {
+                            supervisors.push(*id);
+                        }
+                    }
+                }
+
+                if last {
+                    slot.unregister(&inner);
+                    for id in supervisors.iter() {
+                        context::event::trigger(ROOT_SCHEME_ID.load(Ordering::SeqCst), *id, EVENT_READ, mem::size_of::<usize>());
+                    }
+                    self.release(&slot);
+                }
+            },
+            Handle::Supervisor { slot, .. } => {
+                slot.watchers.lock().remove(&file);
+                slot.unsupervise();
+                self.release(&slot);
+            }
+        }
+
+        Ok(0)
     }
 }
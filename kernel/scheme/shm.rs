@@ -0,0 +1,277 @@
+use alloc::arc::Arc;
+use collections::{BTreeMap, Vec};
+use core::{intrinsics, str};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::{Mutex, RwLock};
+
+use arch;
+use arch::memory::{allocate_frame, deallocate_frame, Frame};
+use arch::paging::{ActivePageTable, Page, VirtualAddress, PAGE_SIZE};
+use arch::paging::entry;
+use arch::paging::temporary_page::TemporaryPage;
+use context;
+use context::memory::{unshare, with_shared, Grant};
+use syscall::data::Stat;
+use syscall::error::*;
+use syscall::flag::{MODE_FILE, O_ACCMODE, O_CREAT, O_EXCL, O_RDONLY};
+use syscall::scheme::Scheme;
+
+/// Limit on the size of a segment, as its frames are allocated when it is sized
+pub const SHM_MAX_SIZE: usize = 256 * 1024 * 1024;
+
+/// A named segment of memory. Its frames are counted in the share table like copy-on-write frames,
+/// with the segment holding the first mapping and each grant of it holding another, so a frame is
+/// freed when the segment is dropped and the last grant of it is unmapped
+struct Segment {
+    /// The user that created the segment, who may open it along with root
+    uid: u32,
+    /// Frames of the segment, allocated and zeroed when it is sized
+    frames: Mutex<Vec<Frame>>
+}
+
+impl Drop for Segment {
+    fn drop(&mut self) {
+        for frame in self.frames.lock().drain(..) {
+            if ! with_shared(|shared| unshare(shared, &frame)) {
+                deallocate_frame(frame);
+            }
+        }
+    }
+}
+
+struct Handle {
+    segment: Arc<Segment>,
+    name: Vec<u8>,
+    writable: bool
+}
+
+/// Shared memory. `shm:NAME` opens the segment named `NAME`, creating it with `O_CREAT`. A new
+/// segment is empty until it is sized with `ftruncate`, which can only be done once. `fmap` maps
+/// part of it into the caller, writable if the handle was opened for writing, and the mapping is
+/// removed with `funmap`. Unlinking removes the name, and the memory is freed once every handle
+/// is closed and every mapping is removed
+pub struct ShmScheme {
+    next_id: AtomicUsize,
+    segments: RwLock<BTreeMap<Vec<u8>, Arc<Segment>>>,
+    handles: RwLock<BTreeMap<usize, Handle>>
+}
+
+impl ShmScheme {
+    pub fn new() -> ShmScheme {
+        ShmScheme {
+            next_id: AtomicUsize::new(0),
+            segments: RwLock::new(BTreeMap::new()),
+            handles: RwLock::new(BTreeMap::new())
+        }
+    }
+}
+
+/// Allocate `count` frames, zeroed through the temporary page. Frames are freed again if they
+/// cannot all be allocated
+fn allocate_zeroed(count: usize) -> Result<Vec<Frame>> {
+    let mut active_table = unsafe { ActivePageTable::new() };
+    let mut temporary_page = TemporaryPage::new(Page::containing_address(VirtualAddress::new(arch::USER_TMP_SHM_OFFSET)));
+
+    let mut frames = Vec::with_capacity(count);
+    for _ in 0..count {
+        let frame = match allocate_frame() {
+            Some(frame) => frame,
+            None => {
+                for frame in frames.drain(..) {
+                    deallocate_frame(frame);
+                }
+                return Err(Error::new(ENOMEM));
+            }
+        };
+
+        let address = temporary_page.map(frame.clone(), entry::PRESENT | entry::WRITABLE | entry::NO_EXECUTE, &mut active_table);
+        active_table.flush(Page::containing_address(address));
+        unsafe { intrinsics::write_bytes(address.get() as *mut u8, 0, PAGE_SIZE); }
+        temporary_page.unmap(&mut active_table);
+        active_table.flush(Page::containing_address(address));
+
+        frames.push(frame);
+    }
+
+    Ok(frames)
+}
+
+impl Scheme for ShmScheme {
+    fn open(&self, path: &[u8], flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        let name = str::from_utf8(path).or(Err(Error::new(ENOENT)))?.trim_matches('/').as_bytes().to_vec();
+        if name.is_empty() {
+            return Err(Error::new(ENOENT));
+        }
+
+        let segment = {
+            let mut segments = self.segments.write();
+            if let Some(segment) = segments.get(&name).map(|segment| segment.clone()) {
+                if flags & (O_CREAT | O_EXCL) == O_CREAT | O_EXCL {
+                    return Err(Error::new(EEXIST));
+                }
+                if uid != 0 && uid != segment.uid {
+                    return Err(Error::new(EACCES));
+                }
+                segment
+            } else if flags & O_CREAT == O_CREAT {
+                let segment = Arc::new(Segment {
+                    uid: uid,
+                    frames: Mutex::new(Vec::new())
+                });
+                segments.insert(name.clone(), segment.clone());
+                segment
+            } else {
+                return Err(Error::new(ENOENT));
+            }
+        };
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.handles.write().insert(id, Handle {
+            segment: segment,
+            name: name,
+            writable: flags & O_ACCMODE != O_RDONLY
+        });
+        Ok(id)
+    }
+
+    fn dup(&self, file: usize, _buf: &[u8]) -> Result<usize> {
+        let handle = {
+            let handles = self.handles.read();
+            let handle = handles.get(&file).ok_or(Error::new(EBADF))?;
+            Handle {
+                segment: handle.segment.clone(),
+                name: handle.name.clone(),
+                writable: handle.writable
+            }
+        };
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.handles.write().insert(id, handle);
+        Ok(id)
+    }
+
+    /// Size the segment, which allocates its memory. It cannot be resized, as it may be mapped
+    fn ftruncate(&self, file: usize, len: usize) -> Result<usize> {
+        let segment = self.handles.read().get(&file).ok_or(Error::new(EBADF))?.segment.clone();
+
+        if len > SHM_MAX_SIZE {
+            return Err(Error::new(ENOMEM));
+        }
+        let count = (len + PAGE_SIZE - 1) / PAGE_SIZE;
+
+        let mut frames = segment.frames.lock();
+        if frames.len() == count {
+            return Ok(0);
+        } else if ! frames.is_empty() {
+            return Err(Error::new(EBUSY));
+        }
+        *frames = allocate_zeroed(count)?;
+
+        Ok(0)
+    }
+
+    /// Map `size` bytes of the segment, from the page aligned `offset`, into the caller
+    fn fmap(&self, file: usize, offset: usize, size: usize) -> Result<usize> {
+        let (segment, writable, name) = {
+            let handles = self.handles.read();
+            let handle = handles.get(&file).ok_or(Error::new(EBADF))?;
+            (handle.segment.clone(), handle.writable, format!("shm:{}", str::from_utf8(&handle.name).unwrap_or("")))
+        };
+
+        if size == 0 || offset % PAGE_SIZE != 0 {
+            return Err(Error::new(EINVAL));
+        }
+
+        let frames = segment.frames.lock();
+        let start = offset / PAGE_SIZE;
+        let count = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+        if start > frames.len() || count > frames.len() - start {
+            return Err(Error::new(EINVAL));
+        }
+        let full_size = count * PAGE_SIZE;
+
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+
+        let mut grants = context.grants.write();
+
+        let mut flags = entry::PRESENT | entry::NO_EXECUTE | entry::USER_ACCESSIBLE;
+        if writable {
+            flags |= entry::WRITABLE;
+        }
+
+        let mut to_address = arch::USER_GRANT_OFFSET;
+        for i in 0 .. grants.len() {
+            let start_address = grants[i].start_address().get();
+            if to_address + full_size < start_address {
+                grants.insert(i, Grant::share_frames(&frames[start .. start + count], VirtualAddress::new(to_address), flags, name.clone()));
+
+                return Ok(to_address);
+            } else {
+                let pages = (grants[i].size() + 4095) / 4096;
+                let end = start_address + pages * 4096;
+                to_address = end;
+            }
+        }
+
+        // Grants are kept out of the guard below the stack
+        if to_address + full_size > arch::USER_STACK_OFFSET - arch::USER_STACK_GUARD_SIZE {
+            return Err(Error::new(ENOMEM));
+        }
+
+        grants.push(Grant::share_frames(&frames[start .. start + count], VirtualAddress::new(to_address), flags, name));
+
+        Ok(to_address)
+    }
+
+    fn fpath(&self, file: usize, buf: &mut [u8]) -> Result<usize> {
+        let handles = self.handles.read();
+        let handle = handles.get(&file).ok_or(Error::new(EBADF))?;
+
+        let mut i = 0;
+        let scheme_path = b"shm:";
+        while i < buf.len() && i < scheme_path.len() {
+            buf[i] = scheme_path[i];
+            i += 1;
+        }
+
+        let mut j = 0;
+        while i < buf.len() && j < handle.name.len() {
+            buf[i] = handle.name[j];
+            i += 1;
+            j += 1;
+        }
+
+        Ok(i)
+    }
+
+    fn fstat(&self, file: usize, stat: &mut Stat) -> Result<usize> {
+        let handles = self.handles.read();
+        let handle = handles.get(&file).ok_or(Error::new(EBADF))?;
+
+        stat.st_mode = MODE_FILE | if handle.writable { 0o666 } else { 0o444 };
+        stat.st_uid = handle.segment.uid;
+        stat.st_size = (handle.segment.frames.lock().len() * PAGE_SIZE) as u64;
+
+        Ok(0)
+    }
+
+    /// Remove the name of a segment. Handles and mappings of it stay valid
+    fn unlink(&self, path: &[u8], uid: u32, _gid: u32) -> Result<usize> {
+        let name = str::from_utf8(path).or(Err(Error::new(ENOENT)))?.trim_matches('/').as_bytes().to_vec();
+
+        let mut segments = self.segments.write();
+        let owner = segments.get(&name).ok_or(Error::new(ENOENT))?.uid;
+        if uid != 0 && uid != owner {
+            return Err(Error::new(EACCES));
+        }
+        segments.remove(&name);
+
+        Ok(0)
+    }
+
+    fn close(&self, file: usize) -> Result<usize> {
+        self.handles.write().remove(&file).ok_or(Error::new(EBADF)).and(Ok(0))
+    }
+}
@@ -0,0 +1,87 @@
+use alloc::arc::Arc;
+use collections::{String, Vec};
+use core::str;
+
+use context;
+use context::sched::{self, CpuMask, CPU_MASK_MAX};
+use syscall::error::{Error, EBUSY, EINVAL, ESRCH, Result};
+
+/// The CPUs the context may run on, as a list of IDs and ranges of IDs, such as `0,2-3`
+pub fn resource(pid: usize) -> Result<Vec<u8>> {
+    let affinity = {
+        let contexts = context::contexts();
+        let context_lock = contexts.get(pid).ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+        context.affinity
+    };
+
+    let mut string = String::new();
+    let mut cpu_id = 0;
+    while cpu_id < CPU_MASK_MAX {
+        if affinity.contains(cpu_id) {
+            let start = cpu_id;
+            while cpu_id + 1 < CPU_MASK_MAX && affinity.contains(cpu_id + 1) {
+                cpu_id += 1;
+            }
+
+            if ! string.is_empty() {
+                string.push(',');
+            }
+            if start == cpu_id {
+                string.push_str(&format!("{}", start));
+            } else {
+                string.push_str(&format!("{}-{}", start, cpu_id));
+            }
+        }
+        cpu_id += 1;
+    }
+    string.push('\n');
+
+    Ok(string.into_bytes())
+}
+
+/// Write a list of CPU IDs and ranges of IDs to choose the CPUs the context may run on. It moves
+/// when its CPU next balances, and contexts it starts afterwards inherit the list. A context that
+/// shares memory with another cannot be moved off of its CPU, and at least one of the CPUs must be
+/// running
+pub fn set(pid: usize, buf: &[u8]) -> Result<usize> {
+    let mut affinity = CpuMask::empty();
+    for part in str::from_utf8(buf).or(Err(Error::new(EINVAL)))?.trim().split(',') {
+        let mut range = part.splitn(2, '-');
+        let start = range.next().unwrap_or("").trim().parse::<usize>().or(Err(Error::new(EINVAL)))?;
+        let end = match range.next() {
+            Some(end) => end.trim().parse::<usize>().or(Err(Error::new(EINVAL)))?,
+            None => start
+        };
+
+        // Checked before the range is built, as `end + 1` could overflow
+        if start > end || end >= CPU_MASK_MAX {
+            return Err(Error::new(EINVAL));
+        }
+        for cpu_id in start..end + 1 {
+            affinity.insert(cpu_id);
+        }
+    }
+
+    // A context with no CPU it may run on would never be switched to again
+    if ! sched::run_queues().keys().any(|&cpu_id| affinity.contains(cpu_id)) {
+        return Err(Error::new(EINVAL));
+    }
+
+    let contexts = context::contexts();
+    let context_lock = contexts.get(pid).ok_or(Error::new(ESRCH))?;
+    let mut context = context_lock.write();
+
+    // Contexts that share memory stay on one CPU, see `context::sched`
+    if Arc::strong_count(&context.grants) > 1 {
+        if let Some(cpu_id) = context.cpu_id {
+            if ! affinity.contains(cpu_id) {
+                return Err(Error::new(EBUSY));
+            }
+        }
+    }
+
+    context.affinity = affinity;
+
+    Ok(buf.len())
+}
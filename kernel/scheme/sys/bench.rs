@@ -0,0 +1,22 @@
+use collections::Vec;
+
+use bench;
+use syscall::error::{Error, EINVAL, Result};
+
+/// Results of the last run of the benchmarks
+pub fn resource() -> Result<Vec<u8>> {
+    Ok(bench::results())
+}
+
+/// Write `run` to run the benchmarks, which also prints their results to the console
+pub fn set(buf: &[u8]) -> Result<usize> {
+    let command = super::trim_newline(buf);
+
+    if command == b"run" {
+        bench::run()?;
+    } else {
+        return Err(Error::new(EINVAL));
+    }
+
+    Ok(buf.len())
+}
@@ -0,0 +1,16 @@
+use collections::Vec;
+
+use context;
+use syscall::error::Result;
+
+/// Save the context, which must be frozen. The checkpoint is read from the handle, which can be
+/// kept until the context is thawed, and the schemes needed to store it are running again
+pub fn resource(pid: usize) -> Result<Vec<u8>> {
+    context::checkpoint::request(pid)
+}
+
+/// Start a new context from a checkpoint, written in one piece
+pub fn restore(buf: &[u8]) -> Result<usize> {
+    context::checkpoint::restore(buf)?;
+    Ok(buf.len())
+}
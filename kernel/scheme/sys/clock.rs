@@ -0,0 +1,38 @@
+use collections::{String, Vec};
+use core::str;
+
+use arch::time;
+use syscall::error::{Error, EINVAL, ENODEV, Result};
+
+/// List the available clock sources, the current source is marked with `*`
+pub fn resource() -> Result<Vec<u8>> {
+    let current = time::clock_source();
+
+    let mut string = String::new();
+    for (source, name) in time::CLOCK_NAMES.iter().enumerate() {
+        if source == current {
+            string.push_str("* ");
+            string.push_str(name);
+            string.push('\n');
+        } else if time::clock_available(source) {
+            string.push_str("  ");
+            string.push_str(name);
+            string.push('\n');
+        }
+    }
+
+    Ok(string.into_bytes())
+}
+
+/// Switch the monotonic clock to the source with the given name
+pub fn set(buf: &[u8]) -> Result<usize> {
+    let name = str::from_utf8(buf).or(Err(Error::new(EINVAL)))?.trim();
+    let source = time::CLOCK_NAMES.iter().position(|&other| other == name).ok_or(Error::new(EINVAL))?;
+
+    if time::set_clock_source(source) {
+        println!("Clock: {}", name);
+        Ok(buf.len())
+    } else {
+        Err(Error::new(ENODEV))
+    }
+}
@@ -0,0 +1,30 @@
+use collections::Vec;
+use core::str;
+
+use context;
+use syscall::error::{Error, EINVAL, ESRCH, Result};
+
+/// Nanoseconds added to the monotonic clock as the context reads it
+pub fn resource(pid: usize) -> Result<Vec<u8>> {
+    let contexts = context::contexts();
+    let context_lock = contexts.get(pid).ok_or(Error::new(ESRCH))?;
+    let context = context_lock.read();
+    Ok(format!("{}\n", context.clock_offset).into_bytes())
+}
+
+/// Write a number of nanoseconds, which may be negative, to change the monotonic clock of the
+/// context. Contexts it starts afterwards inherit the offset, so this is set before starting the
+/// contexts of a container
+pub fn set(pid: usize, buf: &[u8]) -> Result<usize> {
+    let offset = str::from_utf8(buf).or(Err(Error::new(EINVAL)))?
+        .trim()
+        .parse::<i64>()
+        .or(Err(Error::new(EINVAL)))?;
+
+    let contexts = context::contexts();
+    let context_lock = contexts.get(pid).ok_or(Error::new(ESRCH))?;
+    let mut context = context_lock.write();
+    context.clock_offset = offset;
+
+    Ok(buf.len())
+}
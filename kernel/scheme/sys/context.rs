@@ -14,11 +14,19 @@ pub fn resource() -> Result<Vec<u8>> {
                              "CPU",
                              "MEM",
                              "NAME");
+    // Only contexts in the namespace of the caller are listed, by their PID in it
+    let pid_ns = context::pidns::current();
     {
         let contexts = context::contexts();
         for (_id, context_lock) in contexts.iter() {
             let context = context_lock.read();
 
+            let pid = match context::pidns::to_local(&pid_ns, context.id) {
+                Some(pid) => pid,
+                None => continue
+            };
+            let ppid = context::pidns::to_local(&pid_ns, context.ppid).unwrap_or(0);
+
             let mut stat_string = String::new();
             if context.stack.is_some() {
                 stat_string.push('U');
@@ -38,6 +46,9 @@ pub fn resource() -> Result<Vec<u8>> {
                     stat_string.push('Z');
                 }
             }
+            if context.frozen {
+                stat_string.push('F');
+            }
             if context.running {
                 stat_string.push('+');
             }
@@ -83,8 +94,8 @@ pub fn resource() -> Result<Vec<u8>> {
             let name = str::from_utf8(&name_bytes).unwrap_or("");
 
             string.push_str(&format!("{:<6}{:<6}{:<6}{:<6}{:<6}{:<6}{:<8}{}\n",
-                               context.id,
-                               context.ppid,
+                               pid,
+                               ppid,
                                context.euid,
                                context.egid,
                                stat_string,
@@ -0,0 +1,18 @@
+use collections::Vec;
+
+use context;
+use syscall::error::Result;
+
+pub fn resource() -> Result<Vec<u8>> {
+    let mut path = context::coredump::core_path();
+    path.push(b'\n');
+    Ok(path)
+}
+
+pub fn set(buf: &[u8]) -> Result<usize> {
+    let path = super::trim_newline(buf);
+
+    context::coredump::set_core_path(path);
+
+    Ok(buf.len())
+}
@@ -0,0 +1,16 @@
+use collections::{String, Vec};
+
+use crypto::{aes, crc32c, sha256};
+use syscall::error::Result;
+
+pub fn resource() -> Result<Vec<u8>> {
+    let implementation = |accelerated| if accelerated { "hardware" } else { "software" };
+
+    let mut string = String::new();
+    string.push_str(&format!("{:<12}{}\n", "aes", implementation(aes::accelerated())));
+    string.push_str(&format!("{:<12}{}\n", "crc32c", implementation(crc32c::accelerated())));
+    string.push_str(&format!("{:<12}{}\n", "sha256", implementation(sha256::accelerated())));
+    string.push_str(&format!("{:<12}{}\n", "chacha20", implementation(false)));
+
+    Ok(string.into_bytes())
+}
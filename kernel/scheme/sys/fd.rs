@@ -0,0 +1,60 @@
+use collections::{BTreeMap, String, Vec};
+use core::str;
+use core::sync::atomic::Ordering;
+
+use arch;
+use context;
+use scheme;
+use syscall::error::{Error, ESRCH, Result};
+
+/// List the files the context has open, with what has been done with them. LAST is the time since
+/// the last operation, in seconds
+pub fn resource(pid: usize) -> Result<Vec<u8>> {
+    let names: BTreeMap<usize, String> = scheme::schemes().iter_name().map(|(name, &id)| {
+        (id, String::from_utf8_lossy(name).into_owned())
+    }).collect();
+
+    let (seconds, nanoseconds) = arch::time::monotonic();
+    let now = seconds * 1000000000 + nanoseconds;
+
+    let mut string = format!("{:<6}{:<12}{:<10}{:<14}{:<14}{:<14}{:<12}{}\n",
+                             "FD",
+                             "SCHEME",
+                             "FLAGS",
+                             "OFFSET",
+                             "READ",
+                             "WRITTEN",
+                             "LAST",
+                             "PATH");
+    {
+        let contexts = context::contexts();
+        let context_lock = contexts.get(pid).ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+
+        for (fd, file_option) in context.files.lock().iter().enumerate() {
+            if let Some(ref file) = *file_option {
+                let info = &file.info;
+
+                let last = info.last.load(Ordering::Relaxed);
+                let last_string = if last == 0 {
+                    format!("-")
+                } else {
+                    let ago = now.saturating_sub(last) / 1000000;
+                    format!("{}.{:>03}", ago / 1000, ago % 1000)
+                };
+
+                string.push_str(&format!("{:<6}{:<12}{:<10X}{:<14}{:<14}{:<14}{:<12}{}\n",
+                                         fd,
+                                         names.get(&file.scheme).map_or("?", |name| name.as_str()),
+                                         info.flags,
+                                         info.offset.load(Ordering::Relaxed),
+                                         info.read.load(Ordering::Relaxed),
+                                         info.written.load(Ordering::Relaxed),
+                                         last_string,
+                                         str::from_utf8(&info.path).unwrap_or("?")));
+            }
+        }
+    }
+
+    Ok(string.into_bytes())
+}
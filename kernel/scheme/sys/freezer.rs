@@ -0,0 +1,35 @@
+use collections::Vec;
+
+use context;
+use syscall::error::{Error, EINVAL, Result};
+
+/// Whether user contexts are frozen, and how many have stopped
+pub fn resource() -> Result<Vec<u8>> {
+    let count = {
+        let contexts = context::contexts();
+        contexts.iter().filter(|&(_id, context_lock)| context_lock.read().frozen).count()
+    };
+
+    let state = if context::freezer::frozen() {
+        "frozen"
+    } else {
+        "thawed"
+    };
+
+    Ok(format!("{} {}\n", state, count).into_bytes())
+}
+
+/// Write `freeze` to stop all other user contexts, and `thaw` to let them run again
+pub fn set(buf: &[u8]) -> Result<usize> {
+    let command = super::trim_newline(buf);
+
+    if command == b"freeze" {
+        context::freezer::freeze()?;
+    } else if command == b"thaw" {
+        context::freezer::thaw()?;
+    } else {
+        return Err(Error::new(EINVAL));
+    }
+
+    Ok(buf.len())
+}
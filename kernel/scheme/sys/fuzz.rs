@@ -0,0 +1,30 @@
+use collections::Vec;
+use core::str;
+
+use fuzz;
+use syscall::error::{Error, EINVAL, Result};
+
+/// Results of the last fuzzing run
+pub fn resource() -> Result<Vec<u8>> {
+    Ok(fuzz::results())
+}
+
+/// Write `run <iterations> [seed]` to fuzz in the writing context, which also prints the results
+/// to the console. Without a seed, one is taken from the time stamp counter
+pub fn set(buf: &[u8]) -> Result<usize> {
+    let string = str::from_utf8(buf).or(Err(Error::new(EINVAL)))?;
+
+    let mut args = string.split_whitespace();
+    if args.next() != Some("run") {
+        return Err(Error::new(EINVAL));
+    }
+    let iterations = args.next().ok_or(Error::new(EINVAL))?.parse::<usize>().or(Err(Error::new(EINVAL)))?;
+    let seed = match args.next() {
+        Some(arg) => arg.parse::<u64>().or(Err(Error::new(EINVAL)))?,
+        None => 0
+    };
+
+    fuzz::run(iterations, seed);
+
+    Ok(buf.len())
+}
@@ -0,0 +1,16 @@
+use collections::{String, Vec};
+
+use scheme::debug;
+use syscall::error::Result;
+
+/// The console input queue, with the input that was dropped because it filled up
+pub fn resource() -> Result<Vec<u8>> {
+    let (queued, max, dropped) = debug::input_stats();
+
+    let mut string = String::new();
+    string.push_str(&format!("queued: {}\n", queued));
+    string.push_str(&format!("max: {}\n", max));
+    string.push_str(&format!("dropped: {}\n", dropped));
+
+    Ok(string.into_bytes())
+}
@@ -0,0 +1,18 @@
+use collections::Vec;
+use core::sync::atomic::Ordering;
+
+use arch::interrupt::level::{DEFERRED, NESTED};
+use scheme::irq;
+use syscall::error::Result;
+
+pub fn resource() -> Result<Vec<u8>> {
+    let mut string = format!("{:<6}{}\n", "IRQ", "COUNT");
+    for (irq, count) in irq::counts().iter().enumerate() {
+        string.push_str(&format!("{:<6}{}\n", irq, count));
+    }
+
+    string.push_str(&format!("Nested: {}\n", NESTED.load(Ordering::Relaxed)));
+    string.push_str(&format!("Deferred: {}\n", DEFERRED.load(Ordering::Relaxed)));
+
+    Ok(string.into_bytes())
+}
@@ -0,0 +1,18 @@
+use collections::Vec;
+use core::sync::atomic::Ordering;
+
+use sync::LOCK_CLASSES;
+use syscall::error::Result;
+
+pub fn resource() -> Result<Vec<u8>> {
+    let mut string = format!("{:<16}{:<12}{:<12}{}\n", "NAME", "ACQUIRED", "CONTENDED", "SPINS");
+    for class in LOCK_CLASSES.iter() {
+        string.push_str(&format!("{:<16}{:<12}{:<12}{}\n",
+                                 class.name,
+                                 class.acquired.load(Ordering::Relaxed),
+                                 class.contended.load(Ordering::Relaxed),
+                                 class.spins.load(Ordering::Relaxed)));
+    }
+
+    Ok(string.into_bytes())
+}
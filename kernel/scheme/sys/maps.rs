@@ -0,0 +1,72 @@
+use collections::{String, Vec};
+
+use arch::paging::entry::{self, EntryFlags};
+use context;
+use syscall::error::{Error, ESRCH, Result};
+
+fn map_string(start: usize, size: usize, flags: EntryFlags, name: &str) -> String {
+    let mut flags_string = String::new();
+    flags_string.push('r');
+    if flags.contains(entry::WRITABLE) {
+        flags_string.push('w');
+    } else {
+        flags_string.push('-');
+    }
+    if flags.contains(entry::NO_EXECUTE) {
+        flags_string.push('-');
+    } else {
+        flags_string.push('x');
+    }
+    if flags.contains(entry::USER_ACCESSIBLE) {
+        flags_string.push('u');
+    } else {
+        flags_string.push('k');
+    }
+
+    format!("{:>016X}-{:>016X} {:<6}{:<12}{}\n",
+            start,
+            start + size,
+            flags_string,
+            size,
+            name)
+}
+
+pub fn resource(pid: usize) -> Result<Vec<u8>> {
+    let mut string = format!("{:<34}{:<6}{:<12}{}\n",
+                             "RANGE",
+                             "FLAGS",
+                             "SIZE",
+                             "NAME");
+    {
+        let contexts = context::contexts();
+        let context_lock = contexts.get(pid).ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+
+        for shared_mem in context.image.iter() {
+            shared_mem.with(|mem| {
+                string.push_str(&map_string(mem.start_address().get(), mem.size(), mem.flags(), "[image]"));
+            });
+        }
+        if let Some(ref heap) = context.heap {
+            heap.with(|heap| {
+                string.push_str(&map_string(heap.start_address().get(), heap.size(), heap.flags(), "[heap]"));
+            });
+        }
+        if let Some(ref stack) = context.stack {
+            string.push_str(&map_string(stack.start_address().get(), stack.size(), stack.flags(), "[stack]"));
+        }
+        if let Some(ref tls) = context.tls {
+            string.push_str(&map_string(tls.mem.start_address().get(), tls.mem.size(), tls.mem.flags(), "[tls]"));
+        }
+        for grant in context.grants.read().iter() {
+            // Device memory is named by its physical address, other grants by what they share
+            let name = match grant.physical_address() {
+                Some(physical) => format!("[physmap {:>016X}]", physical.get()),
+                None => String::from(grant.name())
+            };
+            string.push_str(&map_string(grant.start_address().get(), grant.size(), grant.flags(), &name));
+        }
+    }
+
+    Ok(string.into_bytes())
+}
@@ -1,10 +1,15 @@
 use collections::Vec;
+use core::sync::atomic::Ordering;
 
-use arch::memory::{free_frames, used_frames};
+use arch::memory::{free_frames, used_frames, DEFERRED_FREES, LEAKED_FRAMES};
 use syscall::error::Result;
 
 pub fn resource() -> Result<Vec<u8>> {
-    let string = format!("Memory Used: {} KB\nMemory Free: {} KB\n", used_frames() * 4, free_frames() * 4);
+    let string = format!("Memory Used: {} KB\nMemory Free: {} KB\nDeferred Frees: {}\nLeaked: {} KB\n",
+                         used_frames() * 4,
+                         free_frames() * 4,
+                         DEFERRED_FREES.load(Ordering::Relaxed),
+                         LEAKED_FRAMES.load(Ordering::Relaxed) * 4);
 
     Ok(string.into_bytes())
 }
@@ -0,0 +1,33 @@
+use collections::Vec;
+
+use context;
+use syscall::error::{Error, EINVAL, ESRCH, Result};
+
+/// `1` if the pages of the context may be merged with identical pages, `0` if not
+pub fn resource(pid: usize) -> Result<Vec<u8>> {
+    let contexts = context::contexts();
+    let context_lock = contexts.get(pid).ok_or(Error::new(ESRCH))?;
+    let context = context_lock.read();
+    Ok(format!("{}\n", if context.merge { 1 } else { 0 }).into_bytes())
+}
+
+/// Write `1` to allow merging the pages of the context, and `0` to stop merging them. Pages that
+/// were merged stay merged until they are written
+pub fn set(pid: usize, buf: &[u8]) -> Result<usize> {
+    let value = super::trim_newline(buf);
+
+    let merge = if value == b"1" {
+        true
+    } else if value == b"0" {
+        false
+    } else {
+        return Err(Error::new(EINVAL));
+    };
+
+    let contexts = context::contexts();
+    let context_lock = contexts.get(pid).ok_or(Error::new(ESRCH))?;
+    let mut context = context_lock.write();
+    context.merge = merge;
+
+    Ok(buf.len())
+}
@@ -4,33 +4,93 @@ use core::{cmp, str};
 use core::sync::atomic::{AtomicUsize, Ordering};
 use spin::RwLock;
 
+use context;
 use syscall::data::Stat;
-use syscall::error::{Error, EBADF, EINVAL, ENOENT, Result};
+use syscall::error::{Error, EACCES, EBADF, EINVAL, ENOENT, Result};
 use syscall::flag::{MODE_DIR, MODE_FILE, SEEK_CUR, SEEK_END, SEEK_SET};
 use syscall::scheme::Scheme;
 
+mod affinity;
+mod bench;
+mod checkpoint;
+mod clock;
+mod clock_offset;
 mod context;
+mod coredump;
 mod cpu;
+mod crypto;
 mod exe;
+mod fd;
+mod freezer;
+mod fuzz;
+mod input;
+mod interrupt;
+mod locks;
+mod maps;
 mod memory;
+mod merge;
+mod mounts;
+mod name;
+mod pid_namespace;
+mod ports;
+mod probes;
+mod rusage;
+mod sched;
 mod scheme;
-//mod interrupt;
+mod scheme_stats;
+mod scheme_watchdog;
+mod stack;
+mod uname;
+mod working_set;
 //mod log;
 //mod test;
 
 struct Handle {
-    path: &'static [u8],
+    path: Vec<u8>,
     data: Vec<u8>,
     mode: u16,
-    seek: usize
+    seek: usize,
+    /// What writes to this handle change, if anything
+    target: Option<Target>
+}
+
+/// A writable file
+#[derive(Clone, Copy)]
+enum Target {
+    /// A file in `sys:`, which only root may change
+    File(&'static [u8]),
+    /// A file in `sys:<pid>/`, for the context with the given ID
+    Pid(usize, &'static [u8])
 }
 
 type SysFn = Fn() -> Result<Vec<u8>> + Send + Sync;
 
+/// A writable file under `sys:`
+type SetFn = Fn(&[u8]) -> Result<usize> + Send + Sync;
+
+/// A file under `sys:<pid>/`, generated for the context with the given ID
+type PidFn = Fn(usize) -> Result<Vec<u8>> + Send + Sync;
+
+/// A writable file under `sys:<pid>/`, which changes the context with the given ID
+type PidSetFn = Fn(usize, &[u8]) -> Result<usize> + Send + Sync;
+
+/// Remove a trailing newline from a value written to a file, so that the output of a command can
+/// be used directly
+fn trim_newline(buf: &[u8]) -> &[u8] {
+    if buf.ends_with(b"\n") {
+        &buf[..buf.len() - 1]
+    } else {
+        buf
+    }
+}
+
 /// System information scheme
 pub struct SysScheme {
     next_id: AtomicUsize,
     files: BTreeMap<&'static [u8], Box<SysFn>>,
+    setters: BTreeMap<&'static [u8], Box<SetFn>>,
+    pid_files: BTreeMap<&'static [u8], Box<PidFn>>,
+    pid_setters: BTreeMap<&'static [u8], Box<PidSetFn>>,
     handles: RwLock<BTreeMap<usize, Handle>>
 }
 
@@ -38,25 +98,92 @@ impl SysScheme {
     pub fn new() -> SysScheme {
         let mut files: BTreeMap<&'static [u8], Box<SysFn>> = BTreeMap::new();
 
+        files.insert(b"bench", Box::new(move || bench::resource()));
+        files.insert(b"clock", Box::new(move || clock::resource()));
         files.insert(b"context", Box::new(move || context::resource()));
+        files.insert(b"coredump", Box::new(move || coredump::resource()));
         files.insert(b"cpu", Box::new(move || cpu::resource()));
+        files.insert(b"crypto", Box::new(move || crypto::resource()));
         files.insert(b"exe", Box::new(move || exe::resource()));
+        files.insert(b"freezer", Box::new(move || freezer::resource()));
+        files.insert(b"fuzz", Box::new(move || fuzz::resource()));
+        files.insert(b"input", Box::new(move || input::resource()));
+        files.insert(b"interrupt", Box::new(move || interrupt::resource()));
+        files.insert(b"locks", Box::new(move || locks::resource()));
         files.insert(b"memory", Box::new(move || memory::resource()));
+        files.insert(b"mounts", Box::new(move || mounts::resource()));
+        files.insert(b"probes", Box::new(move || probes::resource()));
+        files.insert(b"restore", Box::new(move || Ok(Vec::new())));
+        files.insert(b"sched", Box::new(move || sched::resource()));
         files.insert(b"scheme", Box::new(move || scheme::resource()));
-        //files.insert(b"interrupt", Box::new(move || interrupt::resource()));
+        files.insert(b"scheme_stats", Box::new(move || scheme_stats::resource()));
+        files.insert(b"scheme_watchdog", Box::new(move || scheme_watchdog::resource()));
+        files.insert(b"uname", Box::new(move || uname::resource()));
         //files.insert(b"log", Box::new(move || log::resource()));
         //files.insert(b"test", Box::new(move || test::resource()));
 
+        let mut setters: BTreeMap<&'static [u8], Box<SetFn>> = BTreeMap::new();
+
+        setters.insert(b"bench", Box::new(move |buf| bench::set(buf)));
+        setters.insert(b"clock", Box::new(move |buf| clock::set(buf)));
+        setters.insert(b"coredump", Box::new(move |buf| coredump::set(buf)));
+        setters.insert(b"freezer", Box::new(move |buf| freezer::set(buf)));
+        setters.insert(b"fuzz", Box::new(move |buf| fuzz::set(buf)));
+        setters.insert(b"mounts", Box::new(move |buf| mounts::set(buf)));
+        setters.insert(b"probes", Box::new(move |buf| probes::set(buf)));
+        setters.insert(b"restore", Box::new(move |buf| checkpoint::restore(buf)));
+        setters.insert(b"scheme_watchdog", Box::new(move |buf| scheme_watchdog::set(buf)));
+
+        let mut pid_files: BTreeMap<&'static [u8], Box<PidFn>> = BTreeMap::new();
+
+        pid_files.insert(b"affinity", Box::new(move |pid| affinity::resource(pid)));
+        pid_files.insert(b"checkpoint", Box::new(move |pid| checkpoint::resource(pid)));
+        pid_files.insert(b"clock_offset", Box::new(move |pid| clock_offset::resource(pid)));
+        pid_files.insert(b"fd", Box::new(move |pid| fd::resource(pid)));
+        pid_files.insert(b"maps", Box::new(move |pid| maps::resource(pid)));
+        pid_files.insert(b"merge", Box::new(move |pid| merge::resource(pid)));
+        pid_files.insert(b"name", Box::new(move |pid| name::resource(pid)));
+        pid_files.insert(b"pid_namespace", Box::new(move |pid| pid_namespace::resource(pid)));
+        pid_files.insert(b"ports", Box::new(move |pid| ports::resource(pid)));
+        pid_files.insert(b"rusage", Box::new(move |pid| rusage::resource(pid)));
+        pid_files.insert(b"stack", Box::new(move |pid| stack::resource(pid)));
+        pid_files.insert(b"working_set", Box::new(move |pid| working_set::resource(pid)));
+
+        let mut pid_setters: BTreeMap<&'static [u8], Box<PidSetFn>> = BTreeMap::new();
+
+        pid_setters.insert(b"affinity", Box::new(move |pid, buf| affinity::set(pid, buf)));
+        pid_setters.insert(b"clock_offset", Box::new(move |pid, buf| clock_offset::set(pid, buf)));
+        pid_setters.insert(b"merge", Box::new(move |pid, buf| merge::set(pid, buf)));
+        pid_setters.insert(b"name", Box::new(move |pid, buf| name::set(pid, buf)));
+        pid_setters.insert(b"pid_namespace", Box::new(move |pid, buf| pid_namespace::set(pid, buf)));
+        pid_setters.insert(b"ports", Box::new(move |pid, buf| ports::set(pid, buf)));
+        pid_setters.insert(b"working_set", Box::new(move |pid, buf| working_set::set(pid, buf)));
+
         SysScheme {
             next_id: AtomicUsize::new(0),
             files: files,
+            setters: setters,
+            pid_files: pid_files,
+            pid_setters: pid_setters,
             handles: RwLock::new(BTreeMap::new())
         }
     }
 }
 
+/// Only the owner of a context, or root, may inspect it
+fn check_access(pid: usize, uid: u32) -> Result<()> {
+    let contexts = context::contexts();
+    let context_lock = contexts.get(pid).ok_or(Error::new(ENOENT))?;
+    let context = context_lock.read();
+    if uid == 0 || uid == context.ruid || uid == context.euid {
+        Ok(())
+    } else {
+        Err(Error::new(EACCES))
+    }
+}
+
 impl Scheme for SysScheme {
-    fn open(&self, path: &[u8], _flags: usize, _uid: u32, _gid: u32) -> Result<usize> {
+    fn open(&self, path: &[u8], _flags: usize, uid: u32, _gid: u32) -> Result<usize> {
         let path_utf8 = str::from_utf8(path).map_err(|_err| Error::new(ENOENT))?;
         let path_trimmed = path_utf8.trim_matches('/');
 
@@ -71,36 +198,91 @@ impl Scheme for SysScheme {
 
             let id = self.next_id.fetch_add(1, Ordering::SeqCst);
             self.handles.write().insert(id, Handle {
-                path: b"",
+                path: Vec::new(),
                 data: data,
                 mode: MODE_DIR | 0o444,
-                seek: 0
+                seek: 0,
+                target: None
             });
             return Ok(id)
         } else {
             //Have to iterate to get the path without allocation
             for entry in self.files.iter() {
                 if entry.0 == &path_trimmed.as_bytes() {
+                    let writable = self.setters.contains_key(entry.0);
+                    let target = if writable && uid == 0 {
+                        Some(Target::File(*entry.0))
+                    } else {
+                        None
+                    };
                     let id = self.next_id.fetch_add(1, Ordering::SeqCst);
                     self.handles.write().insert(id, Handle {
-                        path: entry.0,
+                        path: entry.0.to_vec(),
                         data: entry.1()?,
-                        mode: MODE_FILE | 0o444,
-                        seek: 0
+                        mode: MODE_FILE | if writable { 0o644 } else { 0o444 },
+                        seek: 0,
+                        target: target
                     });
                     return Ok(id)
                 }
             }
+
+            let mut parts = path_trimmed.splitn(2, '/');
+            if let Some(local_pid) = parts.next().and_then(|part| part.parse::<usize>().ok()) {
+                // Contexts are named by their PID in the namespace of the caller
+                let pid = context::pidns::resolve(local_pid).ok_or(Error::new(ENOENT))?;
+                check_access(pid, uid)?;
+
+                let file = parts.next().unwrap_or("").trim_matches('/');
+                if file.is_empty() {
+                    let mut data = Vec::new();
+                    for entry in self.pid_files.iter() {
+                        if ! data.is_empty() {
+                            data.push(b'\n');
+                        }
+                        data.extend_from_slice(entry.0);
+                    }
+
+                    let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+                    self.handles.write().insert(id, Handle {
+                        path: format!("{}", local_pid).into_bytes(),
+                        data: data,
+                        mode: MODE_DIR | 0o444,
+                        seek: 0,
+                        target: None
+                    });
+                    return Ok(id)
+                } else {
+                    for entry in self.pid_files.iter() {
+                        if entry.0 == &file.as_bytes() {
+                            let target = if self.pid_setters.contains_key(entry.0) {
+                                Some(Target::Pid(pid, *entry.0))
+                            } else {
+                                None
+                            };
+                            let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+                            self.handles.write().insert(id, Handle {
+                                path: format!("{}/{}", local_pid, file).into_bytes(),
+                                data: entry.1(pid)?,
+                                mode: MODE_FILE | if target.is_some() { 0o644 } else { 0o444 },
+                                seek: 0,
+                                target: target
+                            });
+                            return Ok(id)
+                        }
+                    }
+                }
+            }
         }
 
         Err(Error::new(ENOENT))
     }
 
     fn dup(&self, id: usize, _buf: &[u8]) -> Result<usize> {
-        let (path, data, mode, seek) = {
+        let (path, data, mode, seek, target) = {
             let handles = self.handles.read();
             let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
-            (handle.path, handle.data.clone(), handle.mode, handle.seek)
+            (handle.path.clone(), handle.data.clone(), handle.mode, handle.seek, handle.target)
         };
 
         let id = self.next_id.fetch_add(1, Ordering::SeqCst);
@@ -108,7 +290,8 @@ impl Scheme for SysScheme {
             path: path,
             data: data,
             mode: mode,
-            seek: seek
+            seek: seek,
+            target: target
         });
 
         Ok(id)
@@ -128,6 +311,26 @@ impl Scheme for SysScheme {
         Ok(i)
     }
 
+    fn write(&self, id: usize, buffer: &[u8]) -> Result<usize> {
+        let target = {
+            let handles = self.handles.read();
+            let handle = handles.get(&id).ok_or(Error::new(EBADF))?;
+            handle.target
+        };
+
+        match target {
+            Some(Target::File(file)) => {
+                let setter = self.setters.get(file).ok_or(Error::new(EBADF))?;
+                setter(buffer)
+            },
+            Some(Target::Pid(pid, file)) => {
+                let setter = self.pid_setters.get(file).ok_or(Error::new(EBADF))?;
+                setter(pid, buffer)
+            },
+            None => Err(Error::new(EBADF))
+        }
+    }
+
     fn seek(&self, id: usize, pos: usize, whence: usize) -> Result<usize> {
         let mut handles = self.handles.write();
         let mut handle = handles.get_mut(&id).ok_or(Error::new(EBADF))?;
@@ -0,0 +1,49 @@
+use collections::Vec;
+use core::str;
+
+use context;
+use scheme::mount;
+use syscall::error::{Error, EINVAL, ESRCH, Result};
+
+/// List the mounts, each as its path followed by its target
+pub fn resource() -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+
+    let mounts = mount::mounts();
+    for mount in mounts.iter() {
+        data.extend_from_slice(&mount.path);
+        data.push(b' ');
+        data.extend_from_slice(&mount.target);
+        data.push(b'\n');
+    }
+
+    Ok(data)
+}
+
+/// Run `mount <target> <path>` or `unmount <path>`, with paths relative to the writer
+pub fn set(buf: &[u8]) -> Result<usize> {
+    let line = str::from_utf8(buf).or(Err(Error::new(EINVAL)))?;
+
+    let canonicalize = |path: &str| -> Result<Vec<u8>> {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+        Ok(context.canonicalize(path.as_bytes()))
+    };
+
+    let mut args = line.split_whitespace();
+    match (args.next(), args.next(), args.next(), args.next()) {
+        (Some("mount"), Some(target), Some(path), None) => {
+            let target = canonicalize(target)?;
+            let path = canonicalize(path)?;
+            mount::mounts_mut().insert(&path, &target)?;
+        },
+        (Some("unmount"), Some(path), None, None) => {
+            let path = canonicalize(path)?;
+            mount::mounts_mut().remove(&path)?;
+        },
+        _ => return Err(Error::new(EINVAL))
+    }
+
+    Ok(buf.len())
+}
@@ -0,0 +1,35 @@
+use collections::Vec;
+
+use context;
+use syscall::error::{Error, EINVAL, ESRCH, Result};
+
+/// Maximum length of a context name
+const NAME_MAX: usize = 4096;
+
+pub fn resource(pid: usize) -> Result<Vec<u8>> {
+    let mut name = {
+        let contexts = context::contexts();
+        let context_lock = contexts.get(pid).ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+        let name = context.name.lock();
+        name.clone()
+    };
+    name.push(b'\n');
+    Ok(name)
+}
+
+pub fn set(pid: usize, buf: &[u8]) -> Result<usize> {
+    let new_name = super::trim_newline(buf);
+
+    if new_name.len() > NAME_MAX {
+        return Err(Error::new(EINVAL));
+    }
+
+    let contexts = context::contexts();
+    let context_lock = contexts.get(pid).ok_or(Error::new(ESRCH))?;
+    let context = context_lock.read();
+    let mut name = context.name.lock();
+    *name = new_name.to_vec();
+
+    Ok(buf.len())
+}
@@ -0,0 +1,34 @@
+use collections::Vec;
+
+use context;
+use syscall::error::{Error, EINVAL, ESRCH, Result};
+
+/// `1` if the next context started by the context will begin a new PID namespace, `0` if not
+pub fn resource(pid: usize) -> Result<Vec<u8>> {
+    let contexts = context::contexts();
+    let context_lock = contexts.get(pid).ok_or(Error::new(ESRCH))?;
+    let context = context_lock.read();
+    Ok(format!("{}\n", if context.new_pid_ns { 1 } else { 0 }).into_bytes())
+}
+
+/// Write `1` so that the next context started by the context is the init of a new PID namespace,
+/// inside the namespace of the context, and `0` to cancel it. The context itself stays in its
+/// namespace
+pub fn set(pid: usize, buf: &[u8]) -> Result<usize> {
+    let value = super::trim_newline(buf);
+
+    let new_pid_ns = if value == b"1" {
+        true
+    } else if value == b"0" {
+        false
+    } else {
+        return Err(Error::new(EINVAL));
+    };
+
+    let contexts = context::contexts();
+    let context_lock = contexts.get(pid).ok_or(Error::new(ESRCH))?;
+    let mut context = context_lock.write();
+    context.new_pid_ns = new_pid_ns;
+
+    Ok(buf.len())
+}
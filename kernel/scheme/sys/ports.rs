@@ -0,0 +1,75 @@
+use collections::{String, Vec};
+use core::str;
+
+use context;
+use syscall;
+use syscall::error::{Error, EINVAL, EPERM, ESRCH, Result};
+
+/// List the ranges of ports the context may access
+pub fn resource(pid: usize) -> Result<Vec<u8>> {
+    let mut string = String::new();
+    {
+        let contexts = context::contexts();
+        let context_lock = contexts.get(pid).ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+
+        if let Some(ref bitmap) = context.io_bitmap {
+            let allowed = |port: usize| bitmap[port/8] & 1 << (port % 8) == 0;
+
+            let mut port = 0;
+            while port < bitmap.len() * 8 {
+                if allowed(port) {
+                    let start = port;
+                    while port + 1 < bitmap.len() * 8 && allowed(port + 1) {
+                        port += 1;
+                    }
+                    string.push_str(&format!("{:>04X}-{:>04X}\n", start, port));
+                }
+                port += 1;
+            }
+        }
+    }
+
+    Ok(string.into_bytes())
+}
+
+/// Each line is a port or an inclusive range of ports in hex, such as `60` or `1F0-1F7`.
+/// The ports are allowed, or denied if the line starts with `!`
+pub fn set(pid: usize, buf: &[u8]) -> Result<usize> {
+    {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+        if context.euid != 0 {
+            return Err(Error::new(EPERM));
+        }
+    }
+
+    let buf_str = str::from_utf8(buf).or(Err(Error::new(EINVAL)))?;
+    for line in buf_str.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let (allow, range) = if line.starts_with('!') {
+            (false, &line[1..])
+        } else {
+            (true, line)
+        };
+
+        let mut parts = range.splitn(2, '-');
+        let start = usize::from_str_radix(parts.next().unwrap_or(""), 16).or(Err(Error::new(EINVAL)))?;
+        let end = match parts.next() {
+            Some(part) => usize::from_str_radix(part, 16).or(Err(Error::new(EINVAL)))?,
+            None => start
+        };
+        if end < start {
+            return Err(Error::new(EINVAL));
+        }
+
+        syscall::ioperm(pid, start, end - start + 1, allow)?;
+    }
+
+    Ok(buf.len())
+}
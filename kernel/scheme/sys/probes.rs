@@ -0,0 +1,44 @@
+use collections::Vec;
+use core::str;
+
+use arch::interrupt::probe;
+use syscall::error::{Error, EEXIST, EINVAL, ENOENT, Result};
+
+/// List the probes, with the number of hits and the first three arguments of the last one
+pub fn resource() -> Result<Vec<u8>> {
+    let mut string = format!("{:<18}{:<10}{}\n", "ADDRESS", "HITS", "ARGS");
+    probe::list(|info| {
+        string.push_str(&format!("{:<18}{:<10}{:X} {:X} {:X}\n",
+                                 format!("{:X}", info.address),
+                                 info.hits,
+                                 info.args[0],
+                                 info.args[1],
+                                 info.args[2]));
+    });
+
+    Ok(string.into_bytes())
+}
+
+/// Run `add <address>` or `remove <address>`, with the address of an instruction in kernel text in
+/// hexadecimal, such as the entry of a function found in the kernel symbols
+pub fn set(buf: &[u8]) -> Result<usize> {
+    let line = str::from_utf8(buf).or(Err(Error::new(EINVAL)))?;
+
+    let parse = |address: &str| -> Result<usize> {
+        usize::from_str_radix(address.trim_left_matches("0x"), 16).or(Err(Error::new(EINVAL)))
+    };
+
+    let mut args = line.split_whitespace();
+    match (args.next(), args.next(), args.next()) {
+        (Some("add"), Some(address), None) => {
+            // Fails if the address is outside of kernel text, already probed, or all probes are used
+            probe::insert(parse(address)?).or(Err(Error::new(EEXIST)))?;
+        },
+        (Some("remove"), Some(address), None) => {
+            probe::remove(parse(address)?).or(Err(Error::new(ENOENT)))?;
+        },
+        _ => return Err(Error::new(EINVAL))
+    }
+
+    Ok(buf.len())
+}
@@ -0,0 +1,29 @@
+use collections::Vec;
+
+use arch::paging::PAGE_SIZE;
+use context;
+use syscall::error::{Error, ESRCH, Result};
+
+/// Resources used by the context, and by the children it has reaped, with the largest resident
+/// size in KiB. Nothing is paged in from storage, so there are no major faults
+pub fn resource(pid: usize) -> Result<Vec<u8>> {
+    let (own, children) = {
+        let contexts = context::contexts();
+        let context_lock = contexts.get(pid).ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+        // The resident size is sampled when it is read, and when the context exits
+        context.usage.sample_rss(context::oom::resident_pages(&context));
+        (context.usage.rusage(), context.children_usage)
+    };
+
+    let mut string = format!("{:<10}{:<12}{}\n", "USAGE", "SELF", "CHILDREN");
+    string.push_str(&format!("{:<10}{:<12}{}\n", "maxrss", own.max_rss * PAGE_SIZE / 1024, children.max_rss * PAGE_SIZE / 1024));
+    string.push_str(&format!("{:<10}{:<12}{}\n", "minflt", own.minor_faults, children.minor_faults));
+    string.push_str(&format!("{:<10}{:<12}{}\n", "majflt", 0, 0));
+    string.push_str(&format!("{:<10}{:<12}{}\n", "inblock", own.reads, children.reads));
+    string.push_str(&format!("{:<10}{:<12}{}\n", "oublock", own.writes, children.writes));
+    string.push_str(&format!("{:<10}{:<12}{}\n", "nvcsw", own.voluntary_switches, children.voluntary_switches));
+    string.push_str(&format!("{:<10}{:<12}{}\n", "nivcsw", own.involuntary_switches, children.involuntary_switches));
+
+    Ok(string.into_bytes())
+}
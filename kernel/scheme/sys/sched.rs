@@ -0,0 +1,22 @@
+use collections::Vec;
+
+use context;
+use syscall::error::Result;
+
+/// The run queue of each CPU, with the number of contexts assigned to it and how many of those
+/// are runnable
+pub fn resource() -> Result<Vec<u8>> {
+    let mut string = format!("{:<6}{:<10}{}\n", "CPU", "CONTEXTS", "RUNNABLE");
+
+    {
+        let contexts = context::contexts();
+        let queues = context::sched::run_queues();
+        for (cpu_id, queue) in queues.iter() {
+            let count = queue.contexts.lock().len();
+            let load = context::sched::load(queue, &contexts);
+            string.push_str(&format!("{:<6}{:<10}{}\n", cpu_id, count, load));
+        }
+    }
+
+    Ok(string.into_bytes())
+}
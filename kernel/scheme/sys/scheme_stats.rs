@@ -0,0 +1,32 @@
+use collections::Vec;
+use core::str;
+use core::sync::atomic::Ordering;
+
+use scheme::user;
+use syscall::error::Result;
+
+/// Handle and request counters for userspace schemes. Stalled providers have their name marked with `*`
+pub fn resource() -> Result<Vec<u8>> {
+    let mut string = format!("{:<8}{:<8}{:<10}{:<10}{:<8}{}\n",
+                             "OPEN",
+                             "QUEUED",
+                             "REJECTED",
+                             "THROTTLED",
+                             "STALLS",
+                             "NAME");
+
+    for inner in user::user_schemes().iter() {
+        let quota = inner.quota.lock();
+        let stalls = inner.stalls.load(Ordering::SeqCst);
+        string.push_str(&format!("{:<8}{:<8}{:<10}{:<10}{:<8}{}{}\n",
+                                 quota.open,
+                                 quota.queued,
+                                 quota.rejected,
+                                 quota.throttled,
+                                 stalls,
+                                 if inner.stalled() { "*" } else { "" },
+                                 str::from_utf8(&inner.name).unwrap_or("")));
+    }
+
+    Ok(string.into_bytes())
+}
@@ -0,0 +1,20 @@
+use collections::Vec;
+use core::str;
+use core::sync::atomic::Ordering;
+
+use scheme::user::USER_SCHEME_WATCHDOG_TIMEOUT;
+use syscall::error::{Error, EINVAL, Result};
+
+/// Seconds a userspace scheme may leave requests unread before they fail, zero if they never do
+pub fn resource() -> Result<Vec<u8>> {
+    Ok(format!("{}\n", USER_SCHEME_WATCHDOG_TIMEOUT.load(Ordering::SeqCst)).into_bytes())
+}
+
+pub fn set(buf: &[u8]) -> Result<usize> {
+    let string = str::from_utf8(buf).or(Err(Error::new(EINVAL)))?;
+    let timeout = string.trim().parse::<u64>().or(Err(Error::new(EINVAL)))?;
+
+    USER_SCHEME_WATCHDOG_TIMEOUT.store(timeout, Ordering::SeqCst);
+
+    Ok(buf.len())
+}
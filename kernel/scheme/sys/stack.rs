@@ -0,0 +1,71 @@
+use collections::{String, Vec};
+use core::mem;
+
+use arch::interrupt::syscall::SyscallStack;
+use context;
+use syscall::error::{Error, EACCES, ESRCH, Result};
+
+/// Limit on the number of kernel frames shown
+const FRAMES_MAX: usize = 64;
+
+/// Follow the saved base pointers through a kernel stack, stopping at the first frame that
+/// is not inside it
+fn kernel_trace(string: &mut String, kstack: &[u8], mut rbp: usize) {
+    let start = kstack.as_ptr() as usize;
+    let end = start + kstack.len();
+
+    for _frame in 0..FRAMES_MAX {
+        if rbp < start || rbp + 2 * mem::size_of::<usize>() > end {
+            break;
+        }
+
+        let rip = unsafe { *((rbp + mem::size_of::<usize>()) as *const usize) };
+        if rip == 0 {
+            break;
+        }
+        string.push_str(&format!("  {:>016X}: {:>016X}\n", rbp, rip));
+        rbp = unsafe { *(rbp as *const usize) };
+    }
+}
+
+/// The kernel backtrace of a context that is not running, and its user registers as they were
+/// when it entered the kernel. Only root may read this, as it shows kernel addresses
+pub fn resource(pid: usize) -> Result<Vec<u8>> {
+    {
+        let contexts = context::contexts();
+        let current = contexts.current().ok_or(Error::new(ESRCH))?;
+        if current.read().euid != 0 {
+            return Err(Error::new(EACCES));
+        }
+    }
+
+    let contexts = context::contexts();
+    let context_lock = contexts.get(pid).ok_or(Error::new(ESRCH))?;
+    let context = context_lock.read();
+
+    let mut string = format!("status: {:?}\n", context.status);
+
+    string.push_str("kernel:\n");
+    if context.running {
+        string.push_str("  running\n");
+    } else if let Some(ref kstack) = context.kstack {
+        kernel_trace(&mut string, kstack, context.arch.get_base_pointer());
+    }
+
+    // Contexts only leave user mode through syscalls, which save the registers at the top of
+    // the kernel stack
+    if let (Some(kstack), true) = (context.kstack.as_ref(), context.stack.is_some()) {
+        let regs = unsafe {
+            *(kstack.as_ptr().offset((kstack.len() - 256 - mem::size_of::<SyscallStack>()) as isize) as *const SyscallStack)
+        };
+
+        string.push_str("user:\n");
+        string.push_str(&format!("  rip: {:>016X} rsp: {:>016X} rflags: {:>016X}\n", regs.rip, regs.rsp, regs.rflags));
+        string.push_str(&format!("  rdi: {:>016X} rsi: {:>016X} rdx: {:>016X}\n", regs.rdi, regs.rsi, regs.rdx));
+        string.push_str(&format!("  r10: {:>016X} r8:  {:>016X} r9:  {:>016X}\n", regs.r10, regs.r8, regs.r9));
+        string.push_str(&format!("  rbx: {:>016X} rbp: {:>016X} r12: {:>016X}\n", regs.rbx, regs.rbp, regs.r12));
+        string.push_str(&format!("  r13: {:>016X} r14: {:>016X} r15: {:>016X}\n", regs.r13, regs.r14, regs.r15));
+    }
+
+    Ok(string.into_bytes())
+}
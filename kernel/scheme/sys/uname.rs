@@ -0,0 +1,47 @@
+use collections::{String, Vec};
+use core::str;
+
+use arch;
+use syscall::error::Result;
+
+/// Kernel features that were enabled when it was built
+fn features() -> String {
+    let mut features = String::new();
+    for &(name, enabled) in [
+        ("no-graphics", cfg!(feature = "no-graphics")),
+        ("no-smp", cfg!(feature = "no-smp")),
+        ("maxcpus-2", cfg!(feature = "maxcpus-2")),
+        ("maxcpus-4", cfg!(feature = "maxcpus-4")),
+        ("maxcpus-8", cfg!(feature = "maxcpus-8"))
+    ].iter() {
+        if enabled {
+            if ! features.is_empty() {
+                features.push(' ');
+            }
+            features.push_str(name);
+        }
+    }
+    features
+}
+
+/// Identify the running kernel. The git hash and build time are set by the Makefile, and are
+/// `unknown` if the kernel was built without it
+pub fn resource() -> Result<Vec<u8>> {
+    let mut string = String::new();
+
+    string.push_str("sysname: Redox\n");
+    string.push_str(&format!("release: {}\n", env!("CARGO_PKG_VERSION")));
+    string.push_str(&format!("git: {}\n", option_env!("KERNEL_GIT_HASH").unwrap_or("unknown")));
+    string.push_str(&format!("built: {}\n", option_env!("KERNEL_BUILD_TIME").unwrap_or("unknown")));
+    string.push_str(&format!("features: {}\n", features()));
+    string.push_str(&format!("cmdline: {}\n", str::from_utf8(arch::cmdline::get()).unwrap_or("")));
+    string.push_str(&format!("machine: {}\n", if cfg!(target_arch = "x86_64") {
+        "x86_64"
+    } else if cfg!(target_arch = "arm") {
+        "arm"
+    } else {
+        "unknown"
+    }));
+
+    Ok(string.into_bytes())
+}
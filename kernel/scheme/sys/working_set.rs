@@ -0,0 +1,53 @@
+use collections::Vec;
+
+use arch::paging::PAGE_SIZE;
+use context;
+use context::working_set::{WorkingSet, WORKING_SET_INTERVAL, WORKING_SET_MAX_AGE};
+use syscall::error::{Error, EINVAL, ESRCH, Result};
+
+/// `0` if the working set of the context is not tracked. Otherwise, the number of scans, the
+/// scans skipped because the context was running, and for each number of intervals, the KiB of
+/// pages accessed within that many intervals. Pages that were not accessed for longer are idle
+pub fn resource(pid: usize) -> Result<Vec<u8>> {
+    let contexts = context::contexts();
+    let context_lock = contexts.get(pid).ok_or(Error::new(ESRCH))?;
+    let context = context_lock.read();
+
+    let working_set = match context.working_set {
+        Some(ref working_set) => working_set,
+        None => return Ok(b"0\n".to_vec())
+    };
+
+    let mut string = format!("interval {}\nscans {}\nskipped {}\n", WORKING_SET_INTERVAL, working_set.scans, working_set.skipped);
+    for scans in 1..WORKING_SET_MAX_AGE + 1 {
+        string.push_str(&format!("within {} {}\n", scans, working_set.pages(scans) * PAGE_SIZE / 1024));
+    }
+    let idle = working_set.ages.len() - working_set.pages(WORKING_SET_MAX_AGE);
+    string.push_str(&format!("idle {}\n", idle * PAGE_SIZE / 1024));
+
+    Ok(string.into_bytes())
+}
+
+/// Write `1` to start tracking the working set of the context, and `0` to stop and forget it
+pub fn set(pid: usize, buf: &[u8]) -> Result<usize> {
+    let value = super::trim_newline(buf);
+
+    let track = if value == b"1" {
+        true
+    } else if value == b"0" {
+        false
+    } else {
+        return Err(Error::new(EINVAL));
+    };
+
+    let contexts = context::contexts();
+    let context_lock = contexts.get(pid).ok_or(Error::new(ESRCH))?;
+    let mut context = context_lock.write();
+    if ! track {
+        context.working_set = None;
+    } else if context.working_set.is_none() {
+        context.working_set = Some(WorkingSet::default());
+    }
+
+    Ok(buf.len())
+}
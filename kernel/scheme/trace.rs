@@ -0,0 +1,126 @@
+use alloc::arc::Arc;
+use collections::BTreeMap;
+use core::str;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::RwLock;
+
+use context;
+use context::pidns;
+use context::trace::{self, Tracer};
+use syscall::error::*;
+use syscall::flag::O_NONBLOCK;
+use syscall::scheme::Scheme;
+
+struct Handle {
+    /// The traced context
+    pid: usize,
+    /// The PID it was opened by, in the namespace of the tracer
+    local_pid: usize,
+    flags: usize,
+    tracer: Arc<Tracer>
+}
+
+/// Traces the syscalls of a context. Only root may open `trace:<pid>`, and a context has one
+/// tracer at a time. Reads return the records described in `context::trace`, blocking until there
+/// are some unless opened with `O_NONBLOCK`, and return 0 once the context has exited and every
+/// record has been read. Closing the handle stops the trace
+pub struct TraceScheme {
+    next_id: AtomicUsize,
+    handles: RwLock<BTreeMap<usize, Handle>>
+}
+
+impl TraceScheme {
+    pub fn new() -> TraceScheme {
+        TraceScheme {
+            next_id: AtomicUsize::new(0),
+            handles: RwLock::new(BTreeMap::new())
+        }
+    }
+}
+
+/// Check if the tracer is still attached to the context
+fn attached(pid: usize, tracer: &Tracer) -> bool {
+    let contexts = context::contexts();
+    if let Some(context_lock) = contexts.get(pid) {
+        let context = context_lock.read();
+        context.trace.as_ref().map_or(false, |other| other.id == tracer.id)
+    } else {
+        false
+    }
+}
+
+impl Scheme for TraceScheme {
+    fn open(&self, path: &[u8], flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        if uid != 0 {
+            return Err(Error::new(EPERM));
+        }
+
+        let local_pid = str::from_utf8(path).ok()
+            .and_then(|path| path.trim_matches('/').parse::<usize>().ok())
+            .ok_or(Error::new(ENOENT))?;
+        let pid = pidns::resolve(local_pid).ok_or(Error::new(ESRCH))?;
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let tracer = Arc::new(Tracer::new(id));
+        {
+            let contexts = context::contexts();
+            let context_lock = contexts.get(pid).ok_or(Error::new(ESRCH))?;
+            let mut context = context_lock.write();
+            if context.trace.is_some() {
+                return Err(Error::new(EBUSY));
+            }
+            context.trace = Some(tracer.clone());
+        }
+
+        self.handles.write().insert(id, Handle {
+            pid: pid,
+            local_pid: local_pid,
+            flags: flags,
+            tracer: tracer
+        });
+        Ok(id)
+    }
+
+    fn read(&self, file: usize, buf: &mut [u8]) -> Result<usize> {
+        // Clone to prevent deadlocks
+        let (pid, flags, tracer) = {
+            let handles = self.handles.read();
+            let handle = handles.get(&file).ok_or(Error::new(EBADF))?;
+            (handle.pid, handle.flags, handle.tracer.clone())
+        };
+
+        loop {
+            let count = tracer.records.receive_into(buf, false);
+            if count > 0 || buf.is_empty() || ! attached(pid, &tracer) {
+                return Ok(count);
+            } else if flags & O_NONBLOCK == O_NONBLOCK {
+                return Err(Error::new(EAGAIN));
+            }
+
+            // Checked again with the waiters locked, so a record or exit on another CPU cannot be missed
+            tracer.records.condition.wait_unless(|| {
+                ! tracer.records.is_empty() || ! attached(pid, &tracer)
+            });
+        }
+    }
+
+    fn fpath(&self, file: usize, buf: &mut [u8]) -> Result<usize> {
+        let pid = self.handles.read().get(&file).ok_or(Error::new(EBADF))?.local_pid;
+
+        let path = format!("trace:{}", pid).into_bytes();
+
+        let mut i = 0;
+        while i < buf.len() && i < path.len() {
+            buf[i] = path[i];
+            i += 1;
+        }
+
+        Ok(i)
+    }
+
+    fn close(&self, file: usize) -> Result<usize> {
+        let handle = self.handles.write().remove(&file).ok_or(Error::new(EBADF))?;
+        trace::detach(handle.pid, handle.tracer.id);
+        Ok(0)
+    }
+}
@@ -1,8 +1,9 @@
 use alloc::arc::{Arc, Weak};
-use collections::BTreeMap;
-use core::sync::atomic::{AtomicUsize, AtomicU64, Ordering};
-use core::{mem, slice, usize};
-use spin::{Mutex, RwLock};
+use alloc::boxed::Box;
+use collections::{BTreeMap, BTreeSet, String, Vec};
+use core::sync::atomic::{AtomicBool, AtomicUsize, AtomicU64, Ordering};
+use core::{cmp, mem, slice, str, usize};
+use spin::{Mutex, Once, RwLock};
 
 use arch;
 use arch::paging::{InactivePageTable, Page, VirtualAddress, entry};
@@ -10,35 +11,334 @@ use arch::paging::temporary_page::TemporaryPage;
 use context::{self, Context};
 use context::memory::Grant;
 use scheme::root::ROOT_SCHEME_ID;
-use sync::{WaitQueue, WaitMap};
+use sync::{WaitCondition, WaitQueue, WaitMap};
 use syscall::data::{Packet, Stat};
 use syscall::error::*;
 use syscall::flag::{EVENT_READ, O_NONBLOCK};
 use syscall::number::*;
 use syscall::scheme::Scheme;
+use syscall::validate::{validate_slice, validate_slice_mut};
+
+/// Limit on number of open handles for a userspace scheme
+pub const USER_SCHEME_MAX_HANDLES: usize = 65536;
+
+/// Limit on number of open handles for a userspace scheme from one context
+pub const USER_SCHEME_MAX_CLIENT_HANDLES: usize = 4096;
+
+/// Limit on number of requests waiting to be read by a userspace scheme
+pub const USER_SCHEME_MAX_QUEUED: usize = 1024;
+
+/// Limit on the length of a buffer shared with a userspace scheme in one request.
+/// Longer reads and writes are cut short, and longer paths are refused
+pub const USER_SCHEME_MAX_COPY: usize = 64 * 1024 * 1024;
+
+/// Check the length a userspace scheme reports for a buffer of `len` bytes. A scheme that claims to
+/// have copied more than the buffer holds would have the caller read past it
+pub fn checked_copy(result: Result<usize>, len: usize) -> Result<usize> {
+    match result {
+        Ok(count) if count > len => Err(Error::new(EIO)),
+        _ => result
+    }
+}
+
+/// Seconds to wait for a supervised scheme to be registered again before an open fails
+pub const USER_SCHEME_RESTART_TIMEOUT: u64 = 10;
+
+/// Seconds a userspace scheme may leave requests unread before they fail, zero to never fail them.
+/// This is changed by writing to `sys:scheme_watchdog`
+pub static USER_SCHEME_WATCHDOG_TIMEOUT: AtomicU64 = AtomicU64::new(30);
+
+/// Limit on number of requests waiting to be read by a userspace scheme from one user.
+/// Each context has at most one request in flight, so this limits contexts with the same user
+pub const USER_SCHEME_MAX_USER_QUEUED: usize = 256;
+
+/// Handle and request accounting for a userspace scheme
+pub struct UserQuota {
+    /// Open handles, with the ID of the context that opened them
+    handles: BTreeMap<usize, usize>,
+    /// Number of handles open or being opened
+    pub open: usize,
+    /// Number of handles open or being opened by each context
+    client_open: BTreeMap<usize, usize>,
+    /// Number of requests waiting to be read
+    pub queued: usize,
+    /// Number of requests waiting to be read from each user
+    user_queued: BTreeMap<u32, usize>,
+    /// Number of opens refused for exceeding a handle limit
+    pub rejected: usize,
+    /// Number of requests delayed for exceeding a queue limit
+    pub throttled: usize
+}
+
+impl UserQuota {
+    fn new() -> UserQuota {
+        UserQuota {
+            handles: BTreeMap::new(),
+            open: 0,
+            client_open: BTreeMap::new(),
+            queued: 0,
+            user_queued: BTreeMap::new(),
+            rejected: 0,
+            throttled: 0
+        }
+    }
+
+    fn release_open(&mut self, pid: usize) {
+        self.open -= 1;
+        let remove = if let Some(count) = self.client_open.get_mut(&pid) {
+            *count -= 1;
+            *count == 0
+        } else {
+            false
+        };
+        if remove {
+            self.client_open.remove(&pid);
+        }
+    }
+
+    fn release_queued(&mut self, uid: u32) {
+        self.queued -= 1;
+        let remove = if let Some(count) = self.user_queued.get_mut(&uid) {
+            *count -= 1;
+            *count == 0
+        } else {
+            false
+        };
+        if remove {
+            self.user_queued.remove(&uid);
+        }
+    }
+}
+
+/// Userspace schemes, used to report their statistics
+static USER_SCHEMES: Once<Mutex<Vec<Weak<UserInner>>>> = Once::new();
+
+/// Initialize userspace scheme list, called if needed
+fn init_user_schemes() -> Mutex<Vec<Weak<UserInner>>> {
+    Mutex::new(Vec::new())
+}
+
+/// Get the userspace schemes that are still registered
+pub fn user_schemes() -> Vec<Arc<UserInner>> {
+    let mut user_schemes = USER_SCHEMES.call_once(init_user_schemes).lock();
+    user_schemes.retain(|inner_weak| inner_weak.upgrade().is_some());
+    user_schemes.iter().filter_map(|inner_weak| inner_weak.upgrade()).collect()
+}
 
 pub struct UserInner {
     handle_id: usize,
     flags: usize,
+    pub name: Box<[u8]>,
     pub scheme_id: AtomicUsize,
     next_id: AtomicU64,
     context: Weak<RwLock<Context>>,
     todo: WaitQueue<Packet>,
     fmap: Mutex<BTreeMap<u64, (Weak<RwLock<Context>>, usize)>>,
-    done: WaitMap<u64, usize>
+    done: WaitMap<u64, usize>,
+    /// Requests that have been sent, and are waiting for a response
+    pending: Mutex<BTreeSet<u64>>,
+    /// False once the provider has closed the scheme
+    alive: AtomicBool,
+    /// The slot the provider registered the scheme in
+    slot: Weak<UserSlot>,
+    /// Time the oldest request waiting to be read was queued, if there was no read since
+    waiting_since: Mutex<(u64, u64)>,
+    /// True when the provider left requests unread for longer than the watchdog timeout, until it reads again
+    stalled: AtomicBool,
+    /// Number of times the provider has stalled
+    pub stalls: AtomicUsize,
+    pub quota: Mutex<UserQuota>,
+    /// Notified when queued requests are read
+    space: WaitCondition
 }
 
 impl UserInner {
-    pub fn new(handle_id: usize, flags: usize, context: Weak<RwLock<Context>>) -> UserInner {
+    pub fn new(handle_id: usize, flags: usize, name: Box<[u8]>, context: Weak<RwLock<Context>>, slot: Weak<UserSlot>) -> UserInner {
         UserInner {
             handle_id: handle_id,
             flags: flags,
+            name: name,
             scheme_id: AtomicUsize::new(0),
             next_id: AtomicU64::new(1),
             context: context,
             todo: WaitQueue::new(),
             fmap: Mutex::new(BTreeMap::new()),
-            done: WaitMap::new()
+            done: WaitMap::new(),
+            pending: Mutex::new(BTreeSet::new()),
+            alive: AtomicBool::new(true),
+            slot: slot,
+            waiting_since: Mutex::new((0, 0)),
+            stalled: AtomicBool::new(false),
+            stalls: AtomicUsize::new(0),
+            quota: Mutex::new(UserQuota::new()),
+            space: WaitCondition::new()
+        }
+    }
+
+    /// Add to the list of userspace schemes
+    pub fn register(inner: &Arc<UserInner>) {
+        USER_SCHEMES.call_once(init_user_schemes).lock().push(Arc::downgrade(inner));
+    }
+
+    /// Check if the provider is still serving requests
+    pub fn alive(&self) -> bool {
+        self.alive.load(Ordering::SeqCst)
+    }
+
+    /// Called when the provider has closed the scheme. Requests waiting to be read are dropped,
+    /// and every request in flight fails
+    pub fn shutdown(&self) {
+        {
+            // Cleared with `pending` locked, so that `call_inner` either fails or queues its
+            // request before the queue is drained
+            let _pending = self.pending.lock();
+            self.alive.store(false, Ordering::SeqCst);
+        }
+
+        {
+            let mut quota = self.quota.lock();
+            let mut todo = self.todo.inner.lock();
+            while let Some(packet) = todo.pop_front() {
+                quota.release_queued(packet.uid);
+            }
+        }
+
+        for id in self.pending.lock().iter() {
+            self.done.send(*id, Error::mux(Err(Error::new(ENODEV))));
+        }
+
+        self.space.notify();
+    }
+
+    /// Check if the provider has left requests unread for longer than the watchdog timeout
+    pub fn stalled(&self) -> bool {
+        self.stalled.load(Ordering::SeqCst)
+    }
+
+    /// Called by the watchdog. If the oldest request has waited to be read since before `end`, the
+    /// provider is marked as stalled, and the requests waiting to be read fail. Returns true if
+    /// the provider has just stalled
+    fn check_stalled(&self, end: (u64, u64)) -> bool {
+        if ! self.alive() || self.stalled() || self.todo.is_empty() {
+            return false;
+        }
+
+        let since = *self.waiting_since.lock();
+        if since.0 > end.0 || (since.0 == end.0 && since.1 >= end.1) {
+            return false;
+        }
+
+        self.stalled.store(true, Ordering::SeqCst);
+        self.stalls.fetch_add(1, Ordering::SeqCst);
+
+        let mut ids = Vec::new();
+        {
+            let mut quota = self.quota.lock();
+            let mut todo = self.todo.inner.lock();
+            while let Some(packet) = todo.pop_front() {
+                quota.release_queued(packet.uid);
+                ids.push(packet.id);
+            }
+        }
+
+        {
+            let mut fmap = self.fmap.lock();
+            for id in ids.iter() {
+                fmap.remove(id);
+            }
+        }
+
+        for id in ids.iter() {
+            self.done.send(*id, Error::mux(Err(Error::new(ETIME))));
+        }
+
+        self.space.notify();
+
+        true
+    }
+
+    /// Reserve a handle for the current context, before asking the scheme to open it
+    fn reserve_handle(&self) -> Result<usize> {
+        let pid = context::context_id();
+
+        let mut quota = self.quota.lock();
+        let client_open = quota.client_open.get(&pid).map_or(0, |count| *count);
+        if quota.open >= USER_SCHEME_MAX_HANDLES {
+            quota.rejected += 1;
+            Err(Error::new(ENFILE))
+        } else if client_open >= USER_SCHEME_MAX_CLIENT_HANDLES {
+            quota.rejected += 1;
+            Err(Error::new(EMFILE))
+        } else {
+            quota.open += 1;
+            *quota.client_open.entry(pid).or_insert(0) += 1;
+            Ok(pid)
+        }
+    }
+
+    /// Record the result of an open, releasing the reserved handle if it failed
+    fn opened(&self, pid: usize, result: Result<usize>) -> Result<usize> {
+        let mut quota = self.quota.lock();
+        match result {
+            Ok(file) => {
+                // A misbehaving scheme may return the ID of a handle that is already open
+                let old_pid_option = quota.handles.insert(file, pid);
+                if let Some(old_pid) = old_pid_option {
+                    quota.release_open(old_pid);
+                }
+            },
+            Err(_) => quota.release_open(pid)
+        }
+        result
+    }
+
+    /// Release the handle after a close
+    fn closed(&self, file: usize) {
+        let mut quota = self.quota.lock();
+        if let Some(pid) = quota.handles.remove(&file) {
+            quota.release_open(pid);
+        }
+    }
+
+    /// Wait until the queue has space for a request from the given user
+    fn reserve_queued(&self, uid: u32) -> Result<()> {
+        let mut throttled = false;
+        loop {
+            // Checked with the waiters of `space` locked, as the provider may read the queue or
+            // leave on another CPU at any time
+            let mut result = None;
+            self.space.wait_unless(|| {
+                if ! self.alive() {
+                    result = Some(Err(Error::new(ENODEV)));
+                    return true;
+                }
+
+                // Requests to a stalled provider fail at once, instead of waiting for the watchdog again
+                if self.stalled() {
+                    result = Some(Err(Error::new(ETIME)));
+                    return true;
+                }
+
+                let mut quota = self.quota.lock();
+                let user_queued = quota.user_queued.get(&uid).map_or(0, |count| *count);
+                if quota.queued < USER_SCHEME_MAX_QUEUED && user_queued < USER_SCHEME_MAX_USER_QUEUED {
+                    quota.queued += 1;
+                    *quota.user_queued.entry(uid).or_insert(0) += 1;
+                    result = Some(Ok(()));
+                    return true;
+                }
+
+                if ! throttled {
+                    quota.throttled += 1;
+                    throttled = true;
+                }
+
+                false
+            });
+
+            if let Some(result) = result {
+                return result;
+            }
         }
     }
 
@@ -64,36 +364,74 @@ impl UserInner {
 
     fn call_inner(&self, packet: Packet) -> Result<usize> {
         let id = packet.id;
+        let uid = packet.uid;
+
+        self.reserve_queued(uid)?;
+
+        // Queued with `pending` locked, as the provider may leave after the reservation. A request
+        // queued before `shutdown` is failed by it, and one after fails here
+        let len = {
+            let mut pending = self.pending.lock();
+            if ! self.alive() {
+                self.quota.lock().release_queued(uid);
+                return Err(Error::new(ENODEV));
+            }
+            pending.insert(id);
 
-        let len = self.todo.send(packet);
+            if self.todo.is_empty() {
+                *self.waiting_since.lock() = arch::time::monotonic();
+            }
+
+            self.todo.send(packet)
+        };
         context::event::trigger(ROOT_SCHEME_ID.load(Ordering::SeqCst), self.handle_id, EVENT_READ, mem::size_of::<Packet>() * len);
 
-        Error::demux(self.done.receive(&id))
+        let result = self.done.receive(&id);
+
+        self.pending.lock().remove(&id);
+
+        Error::demux(result)
     }
 
     pub fn capture(&self, buf: &[u8]) -> Result<usize> {
-        UserInner::capture_inner(&self.context, buf.as_ptr() as usize, buf.len(), false)
+        UserInner::capture_inner(&self.context, buf.as_ptr() as usize, buf.len(), false, self.request_name())
     }
 
     pub fn capture_mut(&self, buf: &mut [u8]) -> Result<usize> {
-        UserInner::capture_inner(&self.context, buf.as_mut_ptr() as usize, buf.len(), true)
+        UserInner::capture_inner(&self.context, buf.as_mut_ptr() as usize, buf.len(), true, self.request_name())
     }
 
-    fn capture_inner(context_weak: &Weak<RwLock<Context>>, address: usize, size: usize, writable: bool) -> Result<usize> {
+    /// The name of a grant of a buffer of the current context to the provider
+    fn request_name(&self) -> String {
+        format!("[{}: PID {}]", str::from_utf8(&self.name).unwrap_or(""), context::context_id())
+    }
+
+    fn capture_inner(context_weak: &Weak<RwLock<Context>>, address: usize, size: usize, writable: bool, name: String) -> Result<usize> {
         if size == 0 {
             Ok(0)
         } else {
+            if size > USER_SCHEME_MAX_COPY {
+                return Err(Error::new(EINVAL));
+            }
+
+            // The buffer is in the current context, which is the caller, or the scheme for fmap
+            if writable {
+                validate_slice_mut(address as *mut u8, size)?;
+            } else {
+                validate_slice(address as *const u8, size)?;
+            }
+
             let context_lock = context_weak.upgrade().ok_or(Error::new(ESRCH))?;
             let context = context_lock.read();
 
-            let mut grants = context.grants.lock();
+            let mut grants = context.grants.write();
 
             let mut new_table = unsafe { InactivePageTable::from_address(context.arch.get_page_table()) };
             let mut temporary_page = TemporaryPage::new(Page::containing_address(VirtualAddress::new(arch::USER_TMP_GRANT_OFFSET)));
 
             let from_address = (address/4096) * 4096;
             let offset = address - from_address;
-            let full_size = ((offset + size + 4095)/4096) * 4096;
+            let full_size = offset.checked_add(size).and_then(|end| end.checked_add(4095)).ok_or(Error::new(EFAULT))?/4096 * 4096;
             let mut to_address = arch::USER_GRANT_OFFSET;
 
             let mut flags = entry::PRESENT | entry::NO_EXECUTE | entry::USER_ACCESSIBLE;
@@ -109,9 +447,10 @@ impl UserInner {
                         VirtualAddress::new(to_address),
                         full_size,
                         flags,
+                        name,
                         &mut new_table,
                         &mut temporary_page
-                    ));
+                    )?);
 
                     return Ok(to_address + offset);
                 } else {
@@ -121,14 +460,20 @@ impl UserInner {
                 }
             }
 
+            // Grants are kept out of the guard below the stack
+            if to_address + full_size > arch::USER_STACK_OFFSET - arch::USER_STACK_GUARD_SIZE {
+                return Err(Error::new(ENOMEM));
+            }
+
             grants.push(Grant::map_inactive(
                 VirtualAddress::new(from_address),
                 VirtualAddress::new(to_address),
                 full_size,
                 flags,
+                name,
                 &mut new_table,
                 &mut temporary_page
-            ));
+            )?);
 
             Ok(to_address + offset)
         }
@@ -141,7 +486,7 @@ impl UserInner {
             let context_lock = self.context.upgrade().ok_or(Error::new(ESRCH))?;
             let context = context_lock.read();
 
-            let mut grants = context.grants.lock();
+            let mut grants = context.grants.write();
 
             let mut new_table = unsafe { InactivePageTable::from_address(context.arch.get_page_table()) };
             let mut temporary_page = TemporaryPage::new(Page::containing_address(VirtualAddress::new(arch::USER_TMP_GRANT_OFFSET)));
@@ -160,9 +505,44 @@ impl UserInner {
         }
     }
 
+    /// Share `buf` with the provider for a call of `a` on `file`, cut short to the copy limit
+    fn call_buf(&self, a: usize, file: usize, buf: &[u8]) -> Result<usize> {
+        let buf = &buf[..cmp::min(buf.len(), USER_SCHEME_MAX_COPY)];
+        let address = self.capture(buf)?;
+        let result = self.call(a, file, address, buf.len());
+        let _ = self.release(address);
+        checked_copy(result, buf.len())
+    }
+
+    /// Share `buf` with the provider for a call of `a` on `file`, which it writes into
+    fn call_buf_mut(&self, a: usize, file: usize, buf: &mut [u8]) -> Result<usize> {
+        let len = cmp::min(buf.len(), USER_SCHEME_MAX_COPY);
+        let buf = &mut buf[..len];
+        let address = self.capture_mut(buf)?;
+        let result = self.call(a, file, address, len);
+        let _ = self.release(address);
+        checked_copy(result, len)
+    }
+
     pub fn read(&self, buf: &mut [u8]) -> Result<usize> {
+        // A provider that reads its queue is serving requests again
+        self.stalled.store(false, Ordering::SeqCst);
+
         let packet_buf = unsafe { slice::from_raw_parts_mut(buf.as_mut_ptr() as *mut Packet, buf.len()/mem::size_of::<Packet>()) };
-        Ok(self.todo.receive_into(packet_buf, self.flags & O_NONBLOCK != O_NONBLOCK) * mem::size_of::<Packet>())
+        let count = self.todo.receive_into(packet_buf, self.flags & O_NONBLOCK != O_NONBLOCK);
+
+        if count > 0 {
+            {
+                let mut quota = self.quota.lock();
+                for packet in packet_buf[..count].iter() {
+                    quota.release_queued(packet.uid);
+                }
+            }
+            *self.waiting_since.lock() = arch::time::monotonic();
+            self.space.notify();
+        }
+
+        Ok(count * mem::size_of::<Packet>())
     }
 
     pub fn write(&self, buf: &[u8]) -> Result<usize> {
@@ -179,7 +559,8 @@ impl UserInner {
             } else {
                 if let Some((context_weak, size)) = self.fmap.lock().remove(&packet.id) {
                     if let Ok(address) = Error::demux(packet.a) {
-                        packet.a = Error::mux(UserInner::capture_inner(&context_weak, address, size, true));
+                        let name = format!("{}:", str::from_utf8(&self.name).unwrap_or(""));
+                        packet.a = Error::mux(UserInner::capture_inner(&context_weak, address, size, true, name));
                     }
                 }
 
@@ -200,30 +581,214 @@ impl UserInner {
     }
 }
 
+/// The registration of a userspace scheme, which outlives its provider while it is supervised
+pub struct UserSlot {
+    pub name: Box<[u8]>,
+    pub scheme_id: AtomicUsize,
+    /// The current provider, if any
+    provider: RwLock<Weak<UserInner>>,
+    /// Notified when a provider registers, or when the last supervisor leaves
+    registered: WaitCondition,
+    /// Number of open supervisor handles
+    pub supervisors: AtomicUsize,
+    /// Number of times the provider has closed the scheme
+    pub exits: AtomicUsize,
+    /// Number of times a provider has stalled
+    pub stalls: AtomicUsize,
+    /// Notified when the provider closes the scheme or stalls
+    pub exited: WaitCondition,
+    /// IDs of the supervisor handles in the root scheme, which are sent an event when the provider stalls
+    pub watchers: Mutex<BTreeSet<usize>>,
+    /// Open handles, with the provider that opened them and its ID for the handle.
+    /// This ensures that handles from a previous provider are never passed to a new one
+    files: RwLock<BTreeMap<usize, (Weak<UserInner>, usize)>>,
+    next_file: AtomicUsize
+}
+
+impl UserSlot {
+    pub fn new(name: Box<[u8]>) -> UserSlot {
+        UserSlot {
+            name: name,
+            scheme_id: AtomicUsize::new(0),
+            provider: RwLock::new(Weak::new()),
+            registered: WaitCondition::new(),
+            supervisors: AtomicUsize::new(0),
+            exits: AtomicUsize::new(0),
+            stalls: AtomicUsize::new(0),
+            exited: WaitCondition::new(),
+            watchers: Mutex::new(BTreeSet::new()),
+            files: RwLock::new(BTreeMap::new()),
+            next_file: AtomicUsize::new(0)
+        }
+    }
+
+    /// Get the current provider, if it is still serving requests
+    pub fn provider(&self) -> Option<Arc<UserInner>> {
+        self.provider.read().upgrade().and_then(|inner| if inner.alive() {
+            Some(inner)
+        } else {
+            None
+        })
+    }
+
+    /// Set the provider, if there is not one already
+    pub fn register(&self, inner: &Arc<UserInner>) -> Result<()> {
+        {
+            let mut provider = self.provider.write();
+            if provider.upgrade().map_or(false, |other| other.alive()) {
+                return Err(Error::new(EEXIST));
+            }
+            *provider = Arc::downgrade(inner);
+        }
+        self.registered.notify();
+        Ok(())
+    }
+
+    /// Called when the provider has closed the scheme
+    pub fn unregister(&self, inner: &UserInner) {
+        inner.shutdown();
+        {
+            let mut provider = self.provider.write();
+            if provider.upgrade().map_or(false, |other| &*other as *const UserInner == inner as *const UserInner) {
+                *provider = Weak::new();
+            }
+        }
+        self.exits.fetch_add(1, Ordering::SeqCst);
+        self.exited.notify();
+    }
+
+    /// Called by the watchdog when the provider has stalled
+    fn stalled(&self) {
+        self.stalls.fetch_add(1, Ordering::SeqCst);
+        self.exited.notify();
+        for id in self.watchers.lock().iter() {
+            context::event::trigger(ROOT_SCHEME_ID.load(Ordering::SeqCst), *id, EVENT_READ, mem::size_of::<usize>() * 2);
+        }
+    }
+
+    /// Called when a supervisor leaves, so that opens waiting for a provider can fail
+    pub fn unsupervise(&self) {
+        self.supervisors.fetch_sub(1, Ordering::SeqCst);
+        self.registered.notify();
+    }
+
+    /// Get the provider for an open. If the scheme is supervised, this waits for a new provider to
+    /// be registered
+    fn wait_provider(&self) -> Result<Arc<UserInner>> {
+        let start = arch::time::monotonic();
+        let end = (start.0 + USER_SCHEME_RESTART_TIMEOUT, start.1);
+        loop {
+            // Checked with the waiters of `registered` locked, so that a provider registered or a
+            // supervisor leaving on another CPU cannot be missed
+            let mut result = None;
+            self.registered.wait_until_unless(end, || {
+                if let Some(inner) = self.provider() {
+                    result = Some(Ok(inner));
+                    return true;
+                }
+
+                if self.supervisors.load(Ordering::SeqCst) == 0 {
+                    result = Some(Err(Error::new(ENODEV)));
+                    return true;
+                }
+
+                let current = arch::time::monotonic();
+                if current.0 > end.0 || (current.0 == end.0 && current.1 >= end.1) {
+                    result = Some(Err(Error::new(ETIMEDOUT)));
+                    return true;
+                }
+
+                false
+            });
+
+            if let Some(result) = result {
+                return result;
+            }
+        }
+    }
+
+    /// Get the provider of a handle, and its ID for the handle
+    fn file(&self, file: usize) -> Result<(Arc<UserInner>, usize)> {
+        let files = self.files.read();
+        let &(ref inner_weak, inner_file) = files.get(&file).ok_or(Error::new(EBADF))?;
+        let inner = inner_weak.upgrade().ok_or(Error::new(ENODEV))?;
+        if inner.alive() {
+            Ok((inner, inner_file))
+        } else {
+            Err(Error::new(ENODEV))
+        }
+    }
+
+    /// Add a handle opened by a provider
+    fn insert_file(&self, inner: &Arc<UserInner>, result: Result<usize>) -> Result<usize> {
+        let inner_file = result?;
+        let file = self.next_file.fetch_add(1, Ordering::SeqCst);
+        self.files.write().insert(file, (Arc::downgrade(inner), inner_file));
+        Ok(file)
+    }
+}
+
+/// Check every userspace scheme once a second, failing the requests of providers that have left
+/// them unread for longer than `USER_SCHEME_WATCHDOG_TIMEOUT`. This runs in its own context, so
+/// that one stuck driver cannot hang every process that uses it
+pub extern fn watchdog() {
+    loop {
+        let timeout = USER_SCHEME_WATCHDOG_TIMEOUT.load(Ordering::SeqCst);
+        let current = arch::time::monotonic();
+        if timeout > 0 && current.0 >= timeout {
+            let end = (current.0 - timeout, current.1);
+            for inner in user_schemes().iter() {
+                if inner.check_stalled(end) {
+                    println!("{}: provider stalled, failing its queued requests", unsafe { str::from_utf8_unchecked(&inner.name) });
+                    if let Some(slot) = inner.slot.upgrade() {
+                        slot.stalled();
+                    }
+                }
+            }
+        }
+
+        {
+            let contexts = context::contexts();
+            if let Some(context_lock) = contexts.current() {
+                let mut context = context_lock.write();
+                context.wake = Some((current.0 + 1, current.1));
+                context.block();
+            }
+        }
+
+        unsafe { context::switch(); }
+    }
+}
+
 /// UserInner has to be wrapped
 pub struct UserScheme {
-    inner: Weak<UserInner>
+    slot: Arc<UserSlot>
 }
 
 impl UserScheme {
-    pub fn new(inner: Weak<UserInner>) -> UserScheme {
+    pub fn new(slot: Arc<UserSlot>) -> UserScheme {
         UserScheme {
-            inner: inner
+            slot: slot
         }
     }
 }
 
 impl Scheme for UserScheme {
     fn open(&self, path: &[u8], flags: usize, _uid: u32, _gid: u32) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
-        let address = inner.capture(path)?;
+        let inner = self.slot.wait_provider()?;
+        let pid = inner.reserve_handle()?;
+        let address = match inner.capture(path) {
+            Ok(address) => address,
+            Err(err) => return inner.opened(pid, Err(err))
+        };
         let result = inner.call(SYS_OPEN, address, path.len(), flags);
         let _ = inner.release(address);
-        result
+        let result = inner.opened(pid, result);
+        self.slot.insert_file(&inner, result)
     }
 
     fn mkdir(&self, path: &[u8], mode: u16, _uid: u32, _gid: u32) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let inner = self.slot.wait_provider()?;
         let address = inner.capture(path)?;
         let result = inner.call(SYS_MKDIR, address, path.len(), mode as usize);
         let _ = inner.release(address);
@@ -231,7 +796,7 @@ impl Scheme for UserScheme {
     }
 
     fn rmdir(&self, path: &[u8], _uid: u32, _gid: u32) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let inner = self.slot.wait_provider()?;
         let address = inner.capture(path)?;
         let result = inner.call(SYS_RMDIR, address, path.len(), 0);
         let _ = inner.release(address);
@@ -239,7 +804,7 @@ impl Scheme for UserScheme {
     }
 
     fn unlink(&self, path: &[u8], _uid: u32, _gid: u32) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let inner = self.slot.wait_provider()?;
         let address = inner.capture(path)?;
         let result = inner.call(SYS_UNLINK, address, path.len(), 0);
         let _ = inner.release(address);
@@ -247,41 +812,40 @@ impl Scheme for UserScheme {
     }
 
     fn dup(&self, file: usize, buf: &[u8]) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
-        let address = inner.capture(buf)?;
+        let (inner, file) = self.slot.file(file)?;
+        let pid = inner.reserve_handle()?;
+        let address = match inner.capture(buf) {
+            Ok(address) => address,
+            Err(err) => return inner.opened(pid, Err(err))
+        };
         let result = inner.call(SYS_DUP, file, address, buf.len());
         let _ = inner.release(address);
-        result
+        let result = inner.opened(pid, result);
+        self.slot.insert_file(&inner, result)
     }
 
     fn read(&self, file: usize, buf: &mut [u8]) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
-        let address = inner.capture_mut(buf)?;
-        let result = inner.call(SYS_READ, file, address, buf.len());
-        let _ = inner.release(address);
-        result
+        let (inner, file) = self.slot.file(file)?;
+        inner.call_buf_mut(SYS_READ, file, buf)
     }
 
     fn write(&self, file: usize, buf: &[u8]) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
-        let address = inner.capture(buf)?;
-        let result = inner.call(SYS_WRITE, file, address, buf.len());
-        let _ = inner.release(address);
-        result
+        let (inner, file) = self.slot.file(file)?;
+        inner.call_buf(SYS_WRITE, file, buf)
     }
 
     fn seek(&self, file: usize, position: usize, whence: usize) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let (inner, file) = self.slot.file(file)?;
         inner.call(SYS_LSEEK, file, position, whence)
     }
 
     fn fevent(&self, file: usize, flags: usize) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let (inner, file) = self.slot.file(file)?;
         inner.call(SYS_FEVENT, file, flags, 0)
     }
 
     fn fmap(&self, file: usize, offset: usize, size: usize) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let (inner, file) = self.slot.file(file)?;
 
         let (pid, uid, gid, context_lock) = {
             let contexts = context::contexts();
@@ -294,7 +858,7 @@ impl Scheme for UserScheme {
 
         inner.fmap.lock().insert(id, (context_lock, size));
 
-        inner.call_inner(Packet {
+        let result = inner.call_inner(Packet {
             id: id,
             pid: pid,
             uid: uid,
@@ -303,19 +867,22 @@ impl Scheme for UserScheme {
             b: file,
             c: offset,
             d: size
-        })
+        });
+
+        // The reply removes the entry, but a call that fails before it is queued, or that the
+        // provider never answers, does not get one
+        inner.fmap.lock().remove(&id);
+
+        result
     }
 
     fn fpath(&self, file: usize, buf: &mut [u8]) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
-        let address = inner.capture_mut(buf)?;
-        let result = inner.call(SYS_FPATH, file, address, buf.len());
-        let _ = inner.release(address);
-        result
+        let (inner, file) = self.slot.file(file)?;
+        inner.call_buf_mut(SYS_FPATH, file, buf)
     }
 
     fn fstat(&self, file: usize, stat: &mut Stat) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let (inner, file) = self.slot.file(file)?;
         let address = inner.capture_mut(stat)?;
         let result = inner.call(SYS_FSTAT, file, address, 0);
         let _ = inner.release(address);
@@ -323,17 +890,26 @@ impl Scheme for UserScheme {
     }
 
     fn fsync(&self, file: usize) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let (inner, file) = self.slot.file(file)?;
         inner.call(SYS_FSYNC, file, 0, 0)
     }
 
     fn ftruncate(&self, file: usize, len: usize) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
+        let (inner, file) = self.slot.file(file)?;
         inner.call(SYS_FTRUNCATE, file, len, 0)
     }
 
     fn close(&self, file: usize) -> Result<usize> {
-        let inner = self.inner.upgrade().ok_or(Error::new(ENODEV))?;
-        inner.call(SYS_CLOSE, file, 0, 0)
+        // The handle is removed even if the provider has gone away
+        let (inner, file) = {
+            let (inner_weak, inner_file) = self.slot.files.write().remove(&file).ok_or(Error::new(EBADF))?;
+            (inner_weak.upgrade().ok_or(Error::new(ENODEV))?, inner_file)
+        };
+        if ! inner.alive() {
+            return Err(Error::new(ENODEV));
+        }
+        let result = inner.call(SYS_CLOSE, file, 0, 0);
+        inner.closed(file);
+        result
     }
 }
@@ -1,7 +1,34 @@
+pub use seqlock::{SeqLock, SeqLockGuard};
+
+pub use self::rw_lock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use self::ticket_mutex::{LockClass, TicketMutex, TicketMutexGuard};
 pub use self::wait_condition::WaitCondition;
 pub use self::wait_queue::WaitQueue;
 pub use self::wait_map::WaitMap;
 
+pub mod rw_lock;
+pub mod ticket_mutex;
 pub mod wait_condition;
 pub mod wait_queue;
 pub mod wait_map;
+
+/// IRQ counts and acknowledgements
+pub static IRQ_LOCKS: LockClass = LockClass::new("irq");
+/// Contexts blocked on a wait condition
+pub static WAIT_CONDITION_LOCKS: LockClass = LockClass::new("wait_condition");
+/// Items in wait queues, such as pipes and the debug input
+pub static WAIT_QUEUE_LOCKS: LockClass = LockClass::new("wait_queue");
+/// Items in wait maps, such as userspace scheme responses
+pub static WAIT_MAP_LOCKS: LockClass = LockClass::new("wait_map");
+/// The scheme registry, read on every path lookup
+pub static SCHEME_LOCKS: LockClass = LockClass::new("scheme");
+/// The mount table, read on every path lookup
+pub static MOUNT_LOCKS: LockClass = LockClass::new("mount");
+/// Grants of each address space, read when listing maps and written by fmap and physmap
+pub static GRANT_LOCKS: LockClass = LockClass::new("grant");
+
+/// Share counts of frames mapped copy-on-write, taken on fork and on writes to shared pages
+pub static SHARED_FRAME_LOCKS: LockClass = LockClass::new("shared_frame");
+
+/// Lock classes listed in `sys:locks`
+pub static LOCK_CLASSES: [&'static LockClass; 8] = [&IRQ_LOCKS, &WAIT_CONDITION_LOCKS, &WAIT_QUEUE_LOCKS, &WAIT_MAP_LOCKS, &SCHEME_LOCKS, &MOUNT_LOCKS, &GRANT_LOCKS, &SHARED_FRAME_LOCKS];
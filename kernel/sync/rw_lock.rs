@@ -0,0 +1,132 @@
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+
+use arch;
+use sync::LockClass;
+
+/// Set in the state while a writer holds the lock, the other bits count the readers
+const WRITER: usize = !(!0usize >> 1);
+
+/// A reader-writer spinlock. Readers only share a counter, so they do not wait for each other.
+/// Waiting writers stop new readers from taking the lock, so a steady stream of readers cannot
+/// starve them
+pub struct RwLock<T: ?Sized> {
+    state: AtomicUsize,
+    writers: AtomicUsize,
+    class: &'static LockClass,
+    data: UnsafeCell<T>
+}
+
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T, class: &'static LockClass) -> RwLock<T> {
+        RwLock {
+            state: ATOMIC_USIZE_INIT,
+            writers: ATOMIC_USIZE_INIT,
+            class: class,
+            data: UnsafeCell::new(value)
+        }
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// Take the lock for reading
+    pub fn read(&self) -> RwLockReadGuard<T> {
+        self.class.acquired.fetch_add(1, Ordering::Relaxed);
+
+        let mut contended = false;
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+            if state & WRITER == 0 && self.writers.load(Ordering::Relaxed) == 0 {
+                if self.state.compare_and_swap(state, state + 1, Ordering::Acquire) == state {
+                    break;
+                }
+            } else if ! contended {
+                contended = true;
+                self.class.contended.fetch_add(1, Ordering::Relaxed);
+            } else {
+                self.class.spins.fetch_add(1, Ordering::Relaxed);
+            }
+            arch::interrupt::pause();
+        }
+
+        RwLockReadGuard {
+            lock: self
+        }
+    }
+
+    /// Take the lock for writing, waiting for readers to leave
+    pub fn write(&self) -> RwLockWriteGuard<T> {
+        self.class.acquired.fetch_add(1, Ordering::Relaxed);
+
+        if self.state.compare_and_swap(0, WRITER, Ordering::Acquire) != 0 {
+            self.class.contended.fetch_add(1, Ordering::Relaxed);
+            self.writers.fetch_add(1, Ordering::Relaxed);
+            while self.state.compare_and_swap(0, WRITER, Ordering::Acquire) != 0 {
+                self.class.spins.fetch_add(1, Ordering::Relaxed);
+                arch::interrupt::pause();
+            }
+            self.writers.fetch_sub(1, Ordering::Relaxed);
+        }
+
+        RwLockWriteGuard {
+            lock: self
+        }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.state.load(Ordering::Relaxed) & WRITER == 0 {
+            write!(f, "RwLock {{ data: {:?} }}", &*self.read())
+        } else {
+            write!(f, "RwLock {{ <locked> }}")
+        }
+    }
+}
+
+pub struct RwLockReadGuard<'a, T: ?Sized + 'a> {
+    lock: &'a RwLock<T>
+}
+
+impl<'a, T: ?Sized> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RwLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_sub(1, Ordering::Release);
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized + 'a> {
+    lock: &'a RwLock<T>
+}
+
+impl<'a, T: ?Sized> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for RwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.state.store(0, Ordering::Release);
+    }
+}
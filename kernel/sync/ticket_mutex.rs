@@ -0,0 +1,145 @@
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+
+use arch;
+
+/// Contention counters, shared by all locks of one kind
+pub struct LockClass {
+    pub name: &'static str,
+    /// Times a lock was taken
+    pub acquired: AtomicUsize,
+    /// Times a lock was already held when it was taken
+    pub contended: AtomicUsize,
+    /// Times a waiter checked the lock again
+    pub spins: AtomicUsize
+}
+
+impl LockClass {
+    pub const fn new(name: &'static str) -> LockClass {
+        LockClass {
+            name: name,
+            acquired: ATOMIC_USIZE_INIT,
+            contended: ATOMIC_USIZE_INIT,
+            spins: ATOMIC_USIZE_INIT
+        }
+    }
+}
+
+/// A fair spinlock, a CPU takes a ticket and waits for it to be served, so CPUs get the lock in the
+/// order that they asked for it
+pub struct TicketMutex<T: ?Sized> {
+    next: AtomicUsize,
+    serving: AtomicUsize,
+    class: &'static LockClass,
+    data: UnsafeCell<T>
+}
+
+unsafe impl<T: ?Sized + Send> Sync for TicketMutex<T> {}
+unsafe impl<T: ?Sized + Send> Send for TicketMutex<T> {}
+
+impl<T> TicketMutex<T> {
+    pub const fn new(value: T, class: &'static LockClass) -> TicketMutex<T> {
+        TicketMutex {
+            next: ATOMIC_USIZE_INIT,
+            serving: ATOMIC_USIZE_INIT,
+            class: class,
+            data: UnsafeCell::new(value)
+        }
+    }
+}
+
+impl<T: ?Sized> TicketMutex<T> {
+    fn acquire(&self) {
+        let ticket = self.next.fetch_add(1, Ordering::Relaxed);
+        self.class.acquired.fetch_add(1, Ordering::Relaxed);
+
+        let mut serving = self.serving.load(Ordering::Acquire);
+        if serving != ticket {
+            self.class.contended.fetch_add(1, Ordering::Relaxed);
+            while serving != ticket {
+                // Back off in proportion to the number of CPUs ahead, so the cache line is not hammered
+                let ahead = ticket.wrapping_sub(serving);
+                for _ in 0..ahead * 16 {
+                    arch::interrupt::pause();
+                }
+                self.class.spins.fetch_add(1, Ordering::Relaxed);
+                serving = self.serving.load(Ordering::Acquire);
+            }
+        }
+    }
+
+    /// Take the lock, spinning until it is free
+    pub fn lock(&self) -> TicketMutexGuard<T> {
+        self.acquire();
+        TicketMutexGuard {
+            lock: self,
+            restore: false
+        }
+    }
+
+    /// Take the lock with interrupts disabled, they are enabled again when the guard is dropped if
+    /// they were enabled before. Locks taken from interrupt handlers that run with interrupts
+    /// enabled should use this, so the holder is not interrupted while others are spinning
+    pub fn lock_irqsave(&self) -> TicketMutexGuard<T> {
+        let restore = arch::interrupt::enabled();
+        unsafe { arch::interrupt::disable(); }
+        self.acquire();
+        TicketMutexGuard {
+            lock: self,
+            restore: restore
+        }
+    }
+
+    /// Take the lock only if it is free
+    pub fn try_lock(&self) -> Option<TicketMutexGuard<T>> {
+        let serving = self.serving.load(Ordering::Acquire);
+        if self.next.compare_and_swap(serving, serving.wrapping_add(1), Ordering::Acquire) == serving {
+            self.class.acquired.fetch_add(1, Ordering::Relaxed);
+            Some(TicketMutexGuard {
+                lock: self,
+                restore: false
+            })
+        } else {
+            None
+        }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for TicketMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => write!(f, "TicketMutex {{ data: {:?} }}", &*guard),
+            None => write!(f, "TicketMutex {{ <locked> }}")
+        }
+    }
+}
+
+pub struct TicketMutexGuard<'a, T: ?Sized + 'a> {
+    lock: &'a TicketMutex<T>,
+    restore: bool
+}
+
+impl<'a, T: ?Sized> Deref for TicketMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for TicketMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for TicketMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.serving.fetch_add(1, Ordering::Release);
+        if self.restore {
+            unsafe { arch::interrupt::enable(); }
+        }
+    }
+}
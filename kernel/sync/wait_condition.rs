@@ -1,23 +1,25 @@
 use alloc::arc::Arc;
 use collections::Vec;
-use spin::{Mutex, RwLock};
+use spin::RwLock;
 
 use context::{self, Context};
+use sync::{TicketMutex, WAIT_CONDITION_LOCKS};
 
 #[derive(Debug)]
 pub struct WaitCondition {
-    contexts: Mutex<Vec<Arc<RwLock<Context>>>>
+    contexts: TicketMutex<Vec<Arc<RwLock<Context>>>>
 }
 
 impl WaitCondition {
     pub fn new() -> WaitCondition {
         WaitCondition {
-            contexts: Mutex::new(Vec::with_capacity(16))
+            contexts: TicketMutex::new(Vec::with_capacity(16), &WAIT_CONDITION_LOCKS)
         }
     }
 
+    /// Unblock every waiting context, this is also called by device IRQ handlers
     pub fn notify(&self) -> usize {
-        let mut contexts = self.contexts.lock();
+        let mut contexts = self.contexts.lock_irqsave();
         let len = contexts.len();
         while let Some(context_lock) = contexts.pop() {
             context_lock.write().unblock();
@@ -39,6 +41,86 @@ impl WaitCondition {
         }
         unsafe { context::switch(); }
     }
+
+    /// Wait until notified, or until the monotonic time `end` has passed
+    pub fn wait_until(&self, end: (u64, u64)) {
+        let context_lock = {
+            let contexts = context::contexts();
+            let context_lock = contexts.current().expect("WaitCondition::wait_until: no context");
+            context_lock.clone()
+        };
+
+        {
+            let mut context = context_lock.write();
+            context.wake = Some(end);
+            context.block();
+        }
+
+        self.contexts.lock().push(context_lock.clone());
+
+        unsafe { context::switch(); }
+
+        // If woken by the timeout, the context has to be removed so that a later notify does not unblock it
+        self.contexts.lock().retain(|other| &**other as *const RwLock<Context> != &*context_lock as *const RwLock<Context>);
+        context_lock.write().wake = None;
+    }
+
+    /// Wait until notified, unless `ready` returns true. `ready` is called with the waiting contexts
+    /// locked and interrupts disabled, so a notify that follows a change to what it checks cannot be
+    /// missed, even when it comes from another CPU or an IRQ handler. Returns the result of `ready`
+    pub fn wait_unless<F>(&self, ready: F) -> bool where F: FnOnce() -> bool {
+        let context_lock = {
+            let contexts = context::contexts();
+            let context_lock = contexts.current().expect("WaitCondition::wait_unless: no context");
+            context_lock.clone()
+        };
+
+        {
+            let mut contexts = self.contexts.lock_irqsave();
+            if ready() {
+                return true;
+            }
+
+            context_lock.write().block();
+            contexts.push(context_lock);
+        }
+
+        unsafe { context::switch(); }
+
+        false
+    }
+
+    /// Wait until notified, or until the monotonic time `end` has passed, unless `ready` returns
+    /// true, as in `wait_unless`
+    pub fn wait_until_unless<F>(&self, end: (u64, u64), ready: F) -> bool where F: FnOnce() -> bool {
+        let context_lock = {
+            let contexts = context::contexts();
+            let context_lock = contexts.current().expect("WaitCondition::wait_until_unless: no context");
+            context_lock.clone()
+        };
+
+        {
+            let mut contexts = self.contexts.lock_irqsave();
+            if ready() {
+                return true;
+            }
+
+            {
+                let mut context = context_lock.write();
+                context.wake = Some(end);
+                context.block();
+            }
+            contexts.push(context_lock.clone());
+        }
+
+        unsafe { context::switch(); }
+
+        // If woken by the timeout, the context has to be removed so that a later notify does not unblock it
+        self.contexts.lock().retain(|other| &**other as *const RwLock<Context> != &*context_lock as *const RwLock<Context>);
+        context_lock.write().wake = None;
+
+        false
+    }
 }
 
 impl Drop for WaitCondition {
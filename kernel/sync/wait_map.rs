@@ -1,19 +1,18 @@
 use collections::BTreeMap;
 use core::mem;
-use spin::Mutex;
 
-use sync::WaitCondition;
+use sync::{TicketMutex, WaitCondition, WAIT_MAP_LOCKS};
 
 #[derive(Debug)]
 pub struct WaitMap<K, V> {
-    inner: Mutex<BTreeMap<K, V>>,
+    inner: TicketMutex<BTreeMap<K, V>>,
     condition: WaitCondition
 }
 
 impl<K, V> WaitMap<K, V> where K: Clone + Ord {
     pub fn new() -> WaitMap<K, V> {
         WaitMap {
-            inner: Mutex::new(BTreeMap::new()),
+            inner: TicketMutex::new(BTreeMap::new(), &WAIT_MAP_LOCKS),
             condition: WaitCondition::new()
         }
     }
@@ -22,12 +21,18 @@ impl<K, V> WaitMap<K, V> where K: Clone + Ord {
         self.inner.lock().remove(key)
     }
 
+    /// Take the value of `key`, waiting until it is sent. It is taken with the waiters locked, so a
+    /// send from another CPU cannot be missed
     pub fn receive(&self, key: &K) -> V {
         loop {
-            if let Some(value) = self.receive_nonblock(key) {
+            let mut value = None;
+            self.condition.wait_unless(|| {
+                value = self.receive_nonblock(key);
+                value.is_some()
+            });
+            if let Some(value) = value {
                 return value;
             }
-            self.condition.wait();
         }
     }
 
@@ -40,12 +45,17 @@ impl<K, V> WaitMap<K, V> where K: Clone + Ord {
         }
     }
 
+    /// Take any entry, waiting until one is sent, as in `receive`
     pub fn receive_any(&self) -> (K, V) {
         loop {
-            if let Some(entry) = self.receive_any_nonblock() {
+            let mut entry = None;
+            self.condition.wait_unless(|| {
+                entry = self.receive_any_nonblock();
+                entry.is_some()
+            });
+            if let Some(entry) = entry {
                 return entry;
             }
-            self.condition.wait();
         }
     }
 
@@ -1,25 +1,24 @@
 use collections::vec_deque::VecDeque;
-use spin::Mutex;
 
-use sync::WaitCondition;
+use sync::{TicketMutex, WaitCondition, WAIT_QUEUE_LOCKS};
 
 #[derive(Debug)]
 pub struct WaitQueue<T> {
-    pub inner: Mutex<VecDeque<T>>,
+    pub inner: TicketMutex<VecDeque<T>>,
     pub condition: WaitCondition,
 }
 
 impl<T> WaitQueue<T> {
     pub fn new() -> WaitQueue<T> {
         WaitQueue {
-            inner: Mutex::new(VecDeque::new()),
+            inner: TicketMutex::new(VecDeque::new(), &WAIT_QUEUE_LOCKS),
             condition: WaitCondition::new()
         }
     }
 
     pub fn clone(&self) -> WaitQueue<T> where T: Clone {
         WaitQueue {
-            inner: Mutex::new(self.inner.lock().clone()),
+            inner: TicketMutex::new(self.inner.lock().clone(), &WAIT_QUEUE_LOCKS),
             condition: WaitCondition::new()
         }
     }
@@ -28,12 +27,18 @@ impl<T> WaitQueue<T> {
         self.inner.lock().is_empty()
     }
 
+    /// Take the first value, waiting until one is sent. It is taken with the waiters locked, so a
+    /// send from another CPU cannot be missed
     pub fn receive(&self) -> T {
         loop {
-            if let Some(value) = self.inner.lock().pop_front() {
+            let mut value = None;
+            self.condition.wait_unless(|| {
+                value = self.inner.lock().pop_front();
+                value.is_some()
+            });
+            if let Some(value) = value {
                 return value;
             }
-            self.condition.wait();
         }
     }
 
@@ -1,4 +1,5 @@
 //! Filesystem syscalls
+use core::mem;
 use core::sync::atomic::Ordering;
 
 use context;
@@ -6,15 +7,15 @@ use scheme;
 use syscall;
 use syscall::data::{Packet, Stat};
 use syscall::error::*;
-use syscall::flag::{MODE_DIR, MODE_FILE};
+use syscall::flag::{MODE_DIR, MODE_FILE, O_RDONLY, O_WRONLY};
 
 pub fn file_op(a: usize, fd: usize, c: usize, d: usize) -> Result<usize> {
-    let (file, pid, uid, gid) = {
+    let (file, usage, pid, uid, gid) = {
         let contexts = context::contexts();
         let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
         let context = context_lock.read();
         let file = context.get_file(fd).ok_or(Error::new(EBADF))?;
-        (file, context.id, context.euid, context.egid)
+        (file, context.usage.clone(), context.id, context.euid, context.egid)
     };
 
     let scheme = {
@@ -36,7 +37,10 @@ pub fn file_op(a: usize, fd: usize, c: usize, d: usize) -> Result<usize> {
 
     scheme.handle(&mut packet);
 
-    Error::demux(packet.a)
+    let result = Error::demux(packet.a);
+    file.count(a, &result);
+    usage.count(a, &result);
+    result
 }
 
 pub fn file_op_slice(a: usize, fd: usize, slice: &[u8]) -> Result<usize> {
@@ -44,6 +48,10 @@ pub fn file_op_slice(a: usize, fd: usize, slice: &[u8]) -> Result<usize> {
 }
 
 pub fn file_op_mut_slice(a: usize, fd: usize, slice: &mut [u8]) -> Result<usize> {
+    // Schemes write a whole stat, whatever the length
+    if a == syscall::number::SYS_FSTAT && slice.len() < mem::size_of::<Stat>() {
+        return Err(Error::new(EINVAL));
+    }
     file_op(a, fd, slice.as_mut_ptr() as usize, slice.len())
 }
 
@@ -88,6 +96,7 @@ pub fn open(path: &[u8], flags: usize) -> Result<usize> {
         let context = context_lock.read();
         (context.canonicalize(path), context.euid, context.egid)
     };
+    let (path_canon, mount) = scheme::mount::mounts().resolve(path_canon);
 
     let mut parts = path_canon.splitn(2, |&b| b == b':');
     let namespace_opt = parts.next();
@@ -107,11 +116,7 @@ pub fn open(path: &[u8], flags: usize) -> Result<usize> {
     let contexts = context::contexts();
     let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
     let context = context_lock.read();
-    context.add_file(::context::file::File {
-        scheme: scheme_id,
-        number: file_id,
-        event: None,
-    }).ok_or(Error::new(EMFILE))
+    context.add_file(::context::file::File::new(scheme_id, file_id, mount, &path_canon, flags)).ok_or(Error::new(EMFILE))
 }
 
 pub fn pipe2(fds: &mut [usize], flags: usize) -> Result<usize> {
@@ -123,17 +128,9 @@ pub fn pipe2(fds: &mut [usize], flags: usize) -> Result<usize> {
         let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
         let context = context_lock.read();
 
-        let read_fd = context.add_file(::context::file::File {
-            scheme: scheme_id,
-            number: read_id,
-            event: None,
-        }).ok_or(Error::new(EMFILE))?;
+        let read_fd = context.add_file(::context::file::File::new(scheme_id, read_id, None, b"pipe:", flags | O_RDONLY)).ok_or(Error::new(EMFILE))?;
 
-        let write_fd = context.add_file(::context::file::File {
-            scheme: scheme_id,
-            number: write_id,
-            event: None,
-        }).ok_or(Error::new(EMFILE))?;
+        let write_fd = context.add_file(::context::file::File::new(scheme_id, write_id, None, b"pipe:", flags | O_WRONLY)).ok_or(Error::new(EMFILE))?;
 
         fds[0] = read_fd;
         fds[1] = write_fd;
@@ -152,6 +149,7 @@ pub fn mkdir(path: &[u8], mode: u16) -> Result<usize> {
         let context = context_lock.read();
         (context.canonicalize(path), context.euid, context.egid)
     };
+    let (path_canon, _mount) = scheme::mount::mounts().resolve(path_canon);
 
     let mut parts = path_canon.splitn(2, |&b| b == b':');
     let namespace_opt = parts.next();
@@ -174,6 +172,7 @@ pub fn rmdir(path: &[u8]) -> Result<usize> {
         let context = context_lock.read();
         (context.canonicalize(path), context.euid, context.egid)
     };
+    let (path_canon, _mount) = scheme::mount::mounts().resolve(path_canon);
 
     let mut parts = path_canon.splitn(2, |&b| b == b':');
     let namespace_opt = parts.next();
@@ -196,6 +195,7 @@ pub fn unlink(path: &[u8]) -> Result<usize> {
         let context = context_lock.read();
         (context.canonicalize(path), context.euid, context.egid)
     };
+    let (path_canon, _mount) = scheme::mount::mounts().resolve(path_canon);
 
     let mut parts = path_canon.splitn(2, |&b| b == b':');
     let namespace_opt = parts.next();
@@ -254,11 +254,7 @@ pub fn dup(fd: usize, buf: &[u8]) -> Result<usize> {
     let contexts = context::contexts();
     let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
     let context = context_lock.read();
-    context.add_file(::context::file::File {
-        scheme: file.scheme,
-        number: new_id,
-        event: None,
-    }).ok_or(Error::new(EMFILE))
+    context.add_file(file.dup(new_id)).ok_or(Error::new(EMFILE))
 }
 
 /// Register events for file
@@ -268,7 +264,7 @@ pub fn fevent(fd: usize, flags: usize) -> Result<usize> {
         let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
         let context = context_lock.read();
         let mut files = context.files.lock();
-        let mut file = files.get_mut(fd).ok_or(Error::new(EBADF))?.ok_or(Error::new(EBADF))?;
+        let file = files.get_mut(fd).and_then(|file_option| file_option.as_mut()).ok_or(Error::new(EBADF))?;
         if let Some(event_id) = file.event.take() {
             println!("{}: {}:{}: events already registered: {}", fd, file.scheme, file.number, event_id);
             context::event::unregister(fd, file.scheme, event_id);
@@ -287,7 +283,7 @@ pub fn fevent(fd: usize, flags: usize) -> Result<usize> {
         let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
         let context = context_lock.read();
         let mut files = context.files.lock();
-        let mut file = files.get_mut(fd).ok_or(Error::new(EBADF))?.ok_or(Error::new(EBADF))?;
+        let file = files.get_mut(fd).and_then(|file_option| file_option.as_mut()).ok_or(Error::new(EBADF))?;
         file.event = Some(event_id);
     }
     context::event::register(fd, file.scheme, event_id);
@@ -302,7 +298,7 @@ pub fn funmap(virtual_address: usize) -> Result<usize> {
         let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
         let context = context_lock.read();
 
-        let mut grants = context.grants.lock();
+        let mut grants = context.grants.write();
 
         for i in 0 .. grants.len() {
             let start = grants[i].start_address().get();
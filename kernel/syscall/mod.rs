@@ -10,6 +10,8 @@ pub use self::process::*;
 pub use self::time::*;
 pub use self::validate::*;
 
+use context;
+
 use self::data::TimeSpec;
 use self::error::{Error, Result, ENOSYS};
 use self::number::*;
@@ -83,7 +85,30 @@ pub extern fn syscall(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize
         }
     }
 
+    // The tracer is looked up again on return, as exit and exec do not return to drop it
+    let start = context::trace::tracer().map(|(pid, tracer)| context::trace::enter(pid, &tracer, a, b, c, d, e, f));
+
     let result = inner(a, b, c, d, e, f, stack);
+
+    if let Some(start) = start {
+        if let Some((pid, tracer)) = context::trace::tracer() {
+            context::trace::exit(pid, &tracer, a, &result, start);
+        }
+    }
+
+    // Contexts are frozen here, where they hold no locks and have finished with the kernel
+    let _ = context::freezer::safe_point(&result);
+
+    reload_io_bitmap();
+
+    // A context that was killed during the syscall exits instead of returning to userspace
+    let killed = {
+        let contexts = context::contexts();
+        contexts.current().and_then(|context_lock| context_lock.read().killed)
+    };
+    if let Some(status) = killed {
+        exit(status);
+    }
 /*
     if let Err(ref err) = result {
         println!("{}, {}, {}, {}: {}", a, b, c, d, err);
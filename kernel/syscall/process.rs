@@ -4,17 +4,21 @@ use alloc::boxed::Box;
 use collections::{BTreeMap, Vec};
 use core::{intrinsics, mem, str};
 use core::ops::DerefMut;
-use spin::Mutex;
+use spin::{Mutex, Once};
 
 use arch;
-use arch::memory::{allocate_frame, allocate_frames, deallocate_frames, Frame};
-use arch::paging::{ActivePageTable, InactivePageTable, Page, PhysicalAddress, VirtualAddress, entry};
+use arch::kaslr;
+use arch::memory::{allocate_frame, allocate_frames, deallocate_frames, is_ram, Frame};
+use arch::paging::{ActivePageTable, InactivePageTable, Page, PhysicalAddress, VirtualAddress, HUGE_PAGE_SIZE, entry};
 use arch::paging::temporary_page::TemporaryPage;
 use arch::start::usermode;
 use context;
+use context::kstack::KernelStack;
 use context::memory::Grant;
+use context::pidns::{self, PidNamespace};
 use elf::{self, program_header};
 use scheme;
+use sync::{RwLock, GRANT_LOCKS};
 use syscall;
 use syscall::data::Stat;
 use syscall::error::*;
@@ -66,6 +70,12 @@ pub fn clone(flags: usize, stack_base: usize) -> Result<usize> {
         let vfork;
         let mut kfx_option = None;
         let mut kstack_option = None;
+        let io_bitmap;
+        let merge;
+        let clock_offset;
+        let affinity;
+        let pid_ns;
+        let new_pid_ns;
         let mut offset = 0;
         let mut image = vec![];
         let mut heap_option = None;
@@ -73,6 +83,7 @@ pub fn clone(flags: usize, stack_base: usize) -> Result<usize> {
         let mut tls_option = None;
         let grants;
         let name;
+        let exe;
         let cwd;
         let env;
         let files;
@@ -95,6 +106,18 @@ pub fn clone(flags: usize, stack_base: usize) -> Result<usize> {
 
             arch = context.arch.clone();
 
+            io_bitmap = context.io_bitmap.clone();
+
+            merge = context.merge;
+
+            clock_offset = context.clock_offset;
+
+            affinity = context.affinity;
+
+            pid_ns = context.pid_ns.clone();
+
+            new_pid_ns = context.new_pid_ns;
+
             if let Some(ref fx) = context.kfx {
                 let mut new_fx = unsafe { Box::from_raw(::alloc::heap::allocate(512, 16) as *mut [u8; 512]) };
                 for (new_b, b) in new_fx.iter_mut().zip(fx.iter()) {
@@ -105,7 +128,8 @@ pub fn clone(flags: usize, stack_base: usize) -> Result<usize> {
 
             if let Some(ref stack) = context.kstack {
                 offset = stack_base - stack.as_ptr() as usize - mem::size_of::<usize>(); // Add clone ret
-                let mut new_stack = stack.clone();
+                let mut new_stack = KernelStack::new()?;
+                new_stack.copy_from_slice(stack);
 
                 unsafe {
                     let func_ptr = new_stack.as_mut_ptr().offset(offset as isize);
@@ -124,66 +148,24 @@ pub fn clone(flags: usize, stack_base: usize) -> Result<usize> {
                     heap_option = Some(heap_shared.clone());
                 }
             } else {
+                // Memory is shared copy-on-write, and copied a page at a time as either side writes
                 for memory_shared in context.image.iter() {
                     memory_shared.with(|memory| {
-                        let mut new_memory = context::memory::Memory::new(
-                            VirtualAddress::new(memory.start_address().get() + arch::USER_TMP_OFFSET),
-                            memory.size(),
-                            entry::PRESENT | entry::NO_EXECUTE | entry::WRITABLE,
-                            true,
-                            false
-                        );
-
-                        unsafe {
-                            intrinsics::copy(memory.start_address().get() as *const u8,
-                                            new_memory.start_address().get() as *mut u8,
-                                            memory.size());
-                        }
-
-                        new_memory.remap(memory.flags(), true);
+                        let new_memory = memory.share(VirtualAddress::new(memory.start_address().get() + arch::USER_TMP_OFFSET), true);
                         image.push(new_memory.to_shared());
                     });
                 }
 
                 if let Some(ref heap_shared) = context.heap {
                     heap_shared.with(|heap| {
-                        let mut new_heap = context::memory::Memory::new(
-                            VirtualAddress::new(arch::USER_TMP_HEAP_OFFSET),
-                            heap.size(),
-                            entry::PRESENT | entry::NO_EXECUTE | entry::WRITABLE,
-                            true,
-                            false
-                        );
-
-                        unsafe {
-                            intrinsics::copy(heap.start_address().get() as *const u8,
-                                            new_heap.start_address().get() as *mut u8,
-                                            heap.size());
-                        }
-
-                        new_heap.remap(heap.flags(), true);
+                        let new_heap = heap.share(VirtualAddress::new(arch::USER_TMP_HEAP_OFFSET), true);
                         heap_option = Some(new_heap.to_shared());
                     });
                 }
             }
 
             if let Some(ref stack) = context.stack {
-                let mut new_stack = context::memory::Memory::new(
-                    VirtualAddress::new(arch::USER_TMP_STACK_OFFSET),
-                    stack.size(),
-                    entry::PRESENT | entry::NO_EXECUTE | entry::WRITABLE,
-                    true,
-                    false
-                );
-
-                unsafe {
-                    intrinsics::copy(stack.start_address().get() as *const u8,
-                                    new_stack.start_address().get() as *mut u8,
-                                    stack.size());
-                }
-
-                new_stack.remap(stack.flags(), true);
-                stack_option = Some(new_stack);
+                stack_option = Some(stack.share(VirtualAddress::new(stack.start_address().get() - arch::USER_STACK_OFFSET + arch::USER_TMP_STACK_OFFSET), true));
             }
 
             if let Some(ref tls) = context.tls {
@@ -212,7 +194,7 @@ pub fn clone(flags: usize, stack_base: usize) -> Result<usize> {
             if flags & CLONE_VM == CLONE_VM {
                 grants = context.grants.clone();
             } else {
-                grants = Arc::new(Mutex::new(Vec::new()));
+                grants = Arc::new(RwLock::new(Vec::new(), &GRANT_LOCKS));
             }
 
             if flags & CLONE_VM == CLONE_VM {
@@ -221,6 +203,12 @@ pub fn clone(flags: usize, stack_base: usize) -> Result<usize> {
                 name = Arc::new(Mutex::new(context.name.lock().clone()));
             }
 
+            if flags & CLONE_VM == CLONE_VM {
+                exe = context.exe.clone();
+            } else {
+                exe = Arc::new(Mutex::new(context.exe.lock().clone()));
+            }
+
             if flags & CLONE_FS == CLONE_FS {
                 cwd = context.cwd.clone();
             } else {
@@ -248,7 +236,7 @@ pub fn clone(flags: usize, stack_base: usize) -> Result<usize> {
         // This has to be done outside the context lock to prevent deadlocks
         if flags & CLONE_FILES == 0 {
             for (fd, mut file_option) in files.lock().iter_mut().enumerate() {
-                let new_file_option = if let Some(file) = *file_option {
+                let new_file_option = if let Some(ref file) = *file_option {
                     let result = {
                         let scheme = {
                             let schemes = scheme::schemes();
@@ -259,13 +247,7 @@ pub fn clone(flags: usize, stack_base: usize) -> Result<usize> {
                         result
                     };
                     match result {
-                        Ok(new_number) => {
-                            Some(context::file::File {
-                                scheme: file.scheme,
-                                number: new_number,
-                                event: None,
-                            })
-                        },
+                        Ok(new_number) => Some(file.dup(new_number)),
                         Err(err) => {
                             println!("clone: failed to dup {}: {:?}", fd, err);
                             None
@@ -344,6 +326,22 @@ pub fn clone(flags: usize, stack_base: usize) -> Result<usize> {
                 context.kstack = Some(stack);
             }
 
+            context.io_bitmap = io_bitmap;
+
+            context.merge = merge;
+
+            context.clock_offset = clock_offset;
+
+            context.affinity = affinity;
+
+            // The first context of a new namespace is its init, with PID 1
+            context.pid_ns = if new_pid_ns {
+                Some(Arc::new(PidNamespace::new(pid_ns, pid)))
+            } else {
+                pid_ns
+            };
+            pidns::register(&context.pid_ns, pid);
+
             // Setup heap
             if flags & CLONE_VM == CLONE_VM {
                 // Copy user image mapping, if found
@@ -367,7 +365,7 @@ pub fn clone(flags: usize, stack_base: usize) -> Result<usize> {
                 }
 
                 // Copy grant mapping
-                if ! grants.lock().is_empty() {
+                if ! grants.read().is_empty() {
                     let frame = active_table.p4()[2].pointed_frame().expect("user grants not mapped");
                     let flags = active_table.p4()[2].flags();
                     active_table.with(&mut new_table, &mut temporary_page, |mapper| {
@@ -420,7 +418,9 @@ pub fn clone(flags: usize, stack_base: usize) -> Result<usize> {
 
             // Setup user stack
             if let Some(mut stack) = stack_option {
-                stack.move_to(VirtualAddress::new(arch::USER_STACK_OFFSET), &mut new_table, &mut temporary_page, true);
+                // The child keeps the randomized address of the stack of the parent
+                let start = stack.start_address().get() - arch::USER_TMP_STACK_OFFSET + arch::USER_STACK_OFFSET;
+                stack.move_to(VirtualAddress::new(start), &mut new_table, &mut temporary_page, true);
                 context.stack = Some(stack);
             }
 
@@ -432,6 +432,8 @@ pub fn clone(flags: usize, stack_base: usize) -> Result<usize> {
 
             context.name = name;
 
+            context.exe = exe;
+
             context.cwd = cwd;
 
             context.env = env;
@@ -440,14 +442,85 @@ pub fn clone(flags: usize, stack_base: usize) -> Result<usize> {
         }
     }
 
+    // Put the new context on a run queue, once it is set up
+    {
+        let contexts = context::contexts();
+        let context_lock = contexts.get(pid).ok_or(Error::new(ESRCH))?;
+        let mut context = context_lock.write();
+        context::sched::place(&mut context, &contexts);
+    }
+
+    // A new namespace is only started by the next context, which the parent names by its PID in
+    // the namespace of the parent
+    let local_pid = {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let mut context = context_lock.write();
+        context.new_pid_ns = false;
+        pidns::to_local(&context.pid_ns, pid).unwrap_or(pid)
+    };
+
     unsafe { context::switch(); }
 
-    Ok(pid)
+    Ok(local_pid)
+}
+
+/// Unmap the grants of the current context, unless they are shared with another context
+fn unmap_grants(context: &mut context::Context) {
+    if Arc::strong_count(&context.grants) == 1 {
+        for grant in context.grants.write().drain(..) {
+            grant.unmap();
+        }
+    }
+    context.grants = Arc::new(RwLock::new(Vec::new(), &GRANT_LOCKS));
+}
+
+/// Maximum number of script interpreters exec will follow, to prevent loops
+const EXEC_MAX_INTERPRETERS: usize = 4;
+
+/// Check that the current context may execute `path`, and read it
+/// Returns the file status, canonical path, and contents
+fn exec_read(path: &[u8]) -> Result<(Stat, Vec<u8>, Vec<u8>)> {
+    let (uid, gid, canonical) = {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+        (context.euid, context.egid, context.canonicalize(path))
+    };
+
+    let file = syscall::open(&canonical, 0)?;
+    let mut stat = Stat::default();
+    syscall::file_op_mut_slice(syscall::number::SYS_FSTAT, file, &mut stat)?;
+
+    let mut perm = stat.st_mode & 0o7;
+    if stat.st_uid == uid {
+        perm |= (stat.st_mode >> 6) & 0o7;
+    }
+    if stat.st_gid == gid {
+        perm |= (stat.st_mode >> 3) & 0o7;
+    }
+    if uid == 0 {
+        perm |= 0o7;
+    }
+
+    if perm & 0o1 != 0o1 {
+        let _ = syscall::close(file);
+        return Err(Error::new(EACCES));
+    }
+
+    //TODO: Only read elf header, not entire file. Then read required segments
+    let mut data = vec![0; stat.st_size as usize];
+    syscall::file_op_mut_slice(syscall::number::SYS_READ, file, &mut data)?;
+    let _ = syscall::close(file);
+
+    Ok((stat, canonical, data))
 }
 
 pub fn exec(path: &[u8], arg_ptrs: &[[usize; 2]]) -> Result<usize> {
     let entry;
-    let mut sp = arch::USER_STACK_OFFSET + arch::USER_STACK_SIZE - 256;
+    // The stack starts at a random page of its area
+    let stack_offset = arch::USER_STACK_OFFSET + kaslr::slide(arch::PML4_SIZE - arch::USER_STACK_SIZE, 4096);
+    let mut sp = stack_offset + arch::USER_STACK_SIZE - 256;
 
     {
         let mut args = Vec::new();
@@ -456,37 +529,47 @@ pub fn exec(path: &[u8], arg_ptrs: &[[usize; 2]]) -> Result<usize> {
             args.push(arg.to_vec()); // Must be moved into kernel space before exec unmaps all memory
         }
 
-        let (uid, gid, canonical) = {
-            let contexts = context::contexts();
-            let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
-            let context = context_lock.read();
-            (context.euid, context.egid, context.canonicalize(path))
-        };
+        let mut exec_path = path.to_vec();
+        let mut interpreters = 0;
+        let (mut stat, mut canonical, mut data) = exec_read(&exec_path)?;
 
-        let file = syscall::open(&canonical, 0)?;
-        let mut stat = Stat::default();
-        syscall::file_op_mut_slice(syscall::number::SYS_FSTAT, file, &mut stat)?;
+        // Scripts are run by the interpreter named in their first line, which receives the
+        // optional argument from that line, then the script path, then the remaining arguments
+        while data.starts_with(b"#!") {
+            if interpreters >= EXEC_MAX_INTERPRETERS {
+                return Err(Error::new(ELOOP));
+            }
+            interpreters += 1;
 
-        let mut perm = stat.st_mode & 0o7;
-        if stat.st_uid == uid {
-            perm |= (stat.st_mode >> 6) & 0o7;
-        }
-        if stat.st_gid == gid {
-            perm |= (stat.st_mode >> 3) & 0o7;
-        }
-        if uid == 0 {
-            perm |= 0o7;
-        }
+            {
+                let line_end = data.iter().position(|&b| b == b'\n').unwrap_or(data.len());
+                let line = str::from_utf8(&data[2..line_end]).map_err(|_err| Error::new(ENOEXEC))?.trim();
+                let (interpreter, interpreter_arg) = match line.find(|c: char| c == ' ' || c == '\t') {
+                    Some(i) => (&line[..i], line[i..].trim()),
+                    None => (line, "")
+                };
 
-        if perm & 0o1 != 0o1 {
-            let _ = syscall::close(file);
-            return Err(Error::new(EACCES));
-        }
+                if interpreter.is_empty() {
+                    return Err(Error::new(ENOEXEC));
+                }
 
-        //TODO: Only read elf header, not entire file. Then read required segments
-        let mut data = vec![0; stat.st_size as usize];
-        syscall::file_op_mut_slice(syscall::number::SYS_READ, file, &mut data)?;
-        let _ = syscall::close(file);
+                let mut new_args = Vec::new();
+                new_args.push(interpreter.as_bytes().to_vec());
+                if ! interpreter_arg.is_empty() {
+                    new_args.push(interpreter_arg.as_bytes().to_vec());
+                }
+                new_args.push(canonical.clone());
+                new_args.extend(args.drain(..).skip(1));
+                args = new_args;
+
+                exec_path = interpreter.as_bytes().to_vec();
+            }
+
+            let next = exec_read(&exec_path)?;
+            stat = next.0;
+            canonical = next.1;
+            data = next.2;
+        }
 
         match elf::Elf::from(&data) {
             Ok(elf) => {
@@ -500,13 +583,14 @@ pub fn exec(path: &[u8], arg_ptrs: &[[usize; 2]]) -> Result<usize> {
                     let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
                     let mut context = context_lock.write();
 
-                    // Set name
-                    context.name = Arc::new(Mutex::new(canonical));
+                    // Set name and executable path
+                    context.name = Arc::new(Mutex::new(canonical.clone()));
+                    context.exe = Arc::new(Mutex::new(canonical));
 
                     // Unmap previous image, heap, grants, stack, and tls
                     context.image.clear();
                     drop(context.heap.take());
-                    context.grants = Arc::new(Mutex::new(Vec::new()));
+                    unmap_grants(&mut context);
                     drop(context.stack.take());
                     drop(context.tls.take());
 
@@ -585,7 +669,7 @@ pub fn exec(path: &[u8], arg_ptrs: &[[usize; 2]]) -> Result<usize> {
 
                     // Map stack
                     context.stack = Some(context::memory::Memory::new(
-                        VirtualAddress::new(arch::USER_STACK_OFFSET),
+                        VirtualAddress::new(stack_offset),
                         arch::USER_STACK_SIZE,
                         entry::NO_EXECUTE | entry::WRITABLE | entry::USER_ACCESSIBLE,
                         true,
@@ -665,7 +749,7 @@ pub fn exec(path: &[u8], arg_ptrs: &[[usize; 2]]) -> Result<usize> {
 
                 // Duplicate current files using b"exec", close previous
                 for (fd, mut file_option) in files.lock().iter_mut().enumerate() {
-                    let new_file_option = if let Some(file) = *file_option {
+                    let new_file_option = if let Some(ref file) = *file_option {
                         // Duplicate
                         let result = {
                             let scheme_option = {
@@ -697,13 +781,7 @@ pub fn exec(path: &[u8], arg_ptrs: &[[usize; 2]]) -> Result<usize> {
 
                         // Return new descriptor
                         match result {
-                            Ok(new_number) => {
-                                Some(context::file::File {
-                                    scheme: file.scheme,
-                                    number: new_number,
-                                    event: None,
-                                })
-                            },
+                            Ok(new_number) => Some(file.dup(new_number)),
                             Err(err) => {
                                 println!("exec: failed to dup {}: {:?}", fd, err);
                                 None
@@ -746,14 +824,17 @@ pub fn exit(status: usize) -> ! {
             context_lock.clone()
         };
 
+        // Contexts frozen by this one are thawed first, as closing files may need their schemes
+        context::freezer::release(context_lock.read().id);
+
         let mut close_files = Vec::new();
-        let (pid, ppid) = {
+        let (pid, ppid, pid_ns) = {
             let mut context = context_lock.write();
             if Arc::strong_count(&context.files) == 1 {
                 mem::swap(context.files.lock().deref_mut(), &mut close_files);
             }
             context.files = Arc::new(Mutex::new(Vec::new()));
-            (context.id, context.ppid)
+            (context.id, context.ppid, context.pid_ns.clone())
         };
 
         /// Files must be closed while context is valid so that messages can be passed
@@ -773,26 +854,37 @@ pub fn exit(status: usize) -> ! {
             }
         }
 
-        /// Transfer child processes to parent
+        // The rest of a namespace is killed when its init exits
+        if let Some(ref ns) = pid_ns {
+            if ns.init == pid {
+                pidns::kill(ns);
+            }
+        }
+
+        /// Transfer child processes to parent, or to the init of the namespace
+        let reaper = pidns::reaper(&pid_ns, pid, ppid);
         {
             let contexts = context::contexts();
             for (_id, context_lock) in contexts.iter() {
                 let mut context = context_lock.write();
                 if context.ppid == pid {
-                    context.ppid = ppid;
+                    context.ppid = reaper;
                     context.vfork = false;
                 }
             }
         }
 
-        let (vfork, children) = {
+        let (vfork, children, tracer) = {
             let mut context = context_lock.write();
 
+            let pages = context::oom::resident_pages(&context);
+            context.usage.sample_rss(pages);
+
             context.image.clear();
             drop(context.heap.take());
             drop(context.stack.take());
             drop(context.tls.take());
-            context.grants = Arc::new(Mutex::new(Vec::new()));
+            unmap_grants(&mut context);
 
             let vfork = context.vfork;
             context.vfork = false;
@@ -801,9 +893,14 @@ pub fn exit(status: usize) -> ! {
 
             let children = context.waitpid.receive_all();
 
-            (vfork, children)
+            (vfork, children, context.trace.take())
         };
 
+        // Wake the tracer, so that it reads the end of the trace
+        if let Some(tracer) = tracer {
+            tracer.records.condition.notify();
+        }
+
         {
             let contexts = context::contexts();
             if let Some(parent_lock) = contexts.get(ppid) {
@@ -817,8 +914,16 @@ pub fn exit(status: usize) -> ! {
                     parent.waitpid.clone()
                 };
 
-                for (c_pid, c_status) in children {
-                    waitpid.send(c_pid, c_status);
+                // Children that have exited are reaped by the same context as the running ones
+                let reaper_waitpid = if reaper == ppid {
+                    Some(waitpid.clone())
+                } else {
+                    contexts.get(reaper).map(|reaper_lock| reaper_lock.read().waitpid.clone())
+                };
+                if let Some(reaper_waitpid) = reaper_waitpid {
+                    for (c_pid, c_status) in children {
+                        reaper_waitpid.send(c_pid, c_status);
+                    }
                 }
                 waitpid.send(pid, status);
             } else {
@@ -857,7 +962,7 @@ pub fn getpid() -> Result<usize> {
     let contexts = context::contexts();
     let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
     let context = context_lock.read();
-    Ok(context.id)
+    Ok(pidns::to_local(&context.pid_ns, context.id).unwrap_or(context.id))
 }
 
 pub fn getuid() -> Result<usize> {
@@ -867,14 +972,128 @@ pub fn getuid() -> Result<usize> {
     Ok(context.ruid as usize)
 }
 
-pub fn iopl(_level: usize) -> Result<usize> {
-    //TODO
+/// Number of I/O ports
+const IO_PORTS: usize = 65536;
+
+/// Grant access to all ports with a level of 3, or revoke access to all ports with a level of 0
+pub fn iopl(level: usize) -> Result<usize> {
+    let pid = {
+        let contexts = context::contexts();
+        let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+        let context = context_lock.read();
+        if context.euid != 0 {
+            return Err(Error::new(EPERM));
+        }
+        context.id
+    };
+
+    match level {
+        0 => ioperm(pid, 0, IO_PORTS, false),
+        3 => ioperm(pid, 0, IO_PORTS, true),
+        _ => Err(Error::new(EINVAL))
+    }
+}
+
+/// Allow or deny access to `count` ports starting at `port` for a context. The caller is
+/// responsible for checking that this is permitted
+pub fn ioperm(pid: usize, port: usize, count: usize, allow: bool) -> Result<usize> {
+    let end = port.checked_add(count).ok_or(Error::new(EINVAL))?;
+    if end > IO_PORTS {
+        return Err(Error::new(EINVAL));
+    }
+
+    let contexts = context::contexts();
+    let context_lock = contexts.get(pid).ok_or(Error::new(ESRCH))?;
+    let mut context = context_lock.write();
+
+    if context.io_bitmap.is_none() {
+        if ! allow {
+            return Ok(0);
+        }
+        context.io_bitmap = Some(vec![0xFF; arch::gdt::IO_BITMAP_SIZE].into_boxed_slice());
+    }
+
+    let mut empty = false;
+    if let Some(ref mut bitmap) = context.io_bitmap {
+        for i in port..end {
+            if allow {
+                bitmap[i/8] &= !(1 << (i % 8));
+            } else {
+                bitmap[i/8] |= 1 << (i % 8);
+            }
+        }
+        empty = bitmap.iter().all(|&byte| byte == 0xFF);
+    }
+    if empty {
+        context.io_bitmap = None;
+    }
+
+    println!("AUDIT: ioperm {:>04X}:{:X} for PID {} ({}) by PID {}: {}",
+             port, count, pid, str::from_utf8(&context.name.lock()).unwrap_or(""),
+             context::context_id(), if allow { "allowed" } else { "revoked" });
+
+    // The bitmap is loaded on context switch, so reload it if the context is already running. A
+    // context running on another CPU is interrupted, and reloads it before returning to userspace
+    let remote = if pid == context::context_id() {
+        unsafe { arch::gdt::set_io_bitmap(context.io_bitmap.as_ref().map(|bitmap| &bitmap[..])); }
+        None
+    } else if context.running {
+        context.io_bitmap_changed = true;
+        context.cpu_id
+    } else {
+        None
+    };
+
+    drop(context);
+    drop(contexts);
+
+    if let Some(cpu_id) = remote {
+        context::sched::reschedule(cpu_id);
+
+        // Revoked ports must not be usable once this returns, so wait until the bitmap is reloaded
+        // or the context is switched away from
+        loop {
+            {
+                let contexts = context::contexts();
+                match contexts.get(pid) {
+                    Some(context_lock) => {
+                        let context = context_lock.read();
+                        if ! context.io_bitmap_changed || ! context.running {
+                            break;
+                        }
+                    },
+                    None => break
+                }
+            }
+
+            if ! unsafe { context::switch() } {
+                arch::interrupt::pause();
+            }
+        }
+    }
+
     Ok(0)
 }
 
+/// Load the I/O bitmap of the current context again, if `ioperm` changed it while the context was
+/// running on this CPU. Called on the way back to userspace
+pub fn reload_io_bitmap() {
+    let contexts = context::contexts();
+    if let Some(context_lock) = contexts.current() {
+        if context_lock.read().io_bitmap_changed {
+            let mut context = context_lock.write();
+            context.io_bitmap_changed = false;
+            unsafe { arch::gdt::set_io_bitmap(context.io_bitmap.as_ref().map(|bitmap| &bitmap[..])); }
+        }
+    }
+}
+
 pub fn kill(pid: usize, sig: usize) -> Result<usize> {
     use syscall::flag::*;
 
+    // Contexts outside of the namespace of the caller cannot be named
+    let pid = pidns::resolve(pid).ok_or(Error::new(ESRCH))?;
+
     let _context_lock = {
         let contexts = context::contexts();
         let context_lock = contexts.get(pid).ok_or(Error::new(ESRCH))?;
@@ -935,38 +1154,108 @@ pub fn kill(pid: usize, sig: usize) -> Result<usize> {
     Ok(0)
 }
 
+/// Memory below this address holds data from the BIOS and bootloader, and may be mapped by drivers
+const PHYS_LEGACY_END: usize = 0x100000;
+
+/// Physical memory allocated by physalloc, by start address and number of frames
+static PHYS_ALLOCATIONS: Once<Mutex<BTreeMap<usize, usize>>> = Once::new();
+
+/// Initialize physical allocations, called if needed
+fn init_phys_allocations() -> Mutex<BTreeMap<usize, usize>> {
+    Mutex::new(BTreeMap::new())
+}
+
+/// Only privileged contexts may access physical memory. Returns the PID and name, for auditing
+fn phys_privileged() -> Result<(usize, Vec<u8>)> {
+    let contexts = context::contexts();
+    let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+    let context = context_lock.read();
+    if context.euid == 0 {
+        let name = context.name.lock().clone();
+        Ok((context.id, name))
+    } else {
+        Err(Error::new(EPERM))
+    }
+}
+
 pub fn physalloc(size: usize) -> Result<usize> {
-    allocate_frames((size + 4095)/4096).ok_or(Error::new(ENOMEM)).map(|frame| frame.start_address().get())
+    phys_privileged()?;
+
+    let count = (size + 4095)/4096;
+    let address = allocate_frames(count).ok_or(Error::new(ENOMEM))?.start_address().get();
+    PHYS_ALLOCATIONS.call_once(init_phys_allocations).lock().insert(address, count);
+    Ok(address)
 }
 
 pub fn physfree(physical_address: usize, size: usize) -> Result<usize> {
-    deallocate_frames(Frame::containing_address(PhysicalAddress::new(physical_address)), (size + 4095)/4096);
-    //TODO: Check that no double free occured
+    phys_privileged()?;
+
+    // Only free memory that was allocated by physalloc, and has not already been freed
+    let count = (size + 4095)/4096;
+    let mut allocations = PHYS_ALLOCATIONS.call_once(init_phys_allocations).lock();
+    if allocations.get(&physical_address) != Some(&count) {
+        return Err(Error::new(EINVAL));
+    }
+    allocations.remove(&physical_address);
+
+    deallocate_frames(Frame::containing_address(PhysicalAddress::new(physical_address)), count);
     Ok(0)
 }
 
+/// Check that a physical range is device memory, legacy memory, or memory allocated by physalloc
+fn physmap_allowed(address: usize, size: usize) -> bool {
+    if address.checked_add(size).is_none() {
+        false
+    } else if address + size <= PHYS_LEGACY_END || ! is_ram(address, size) {
+        true
+    } else {
+        let allocations = PHYS_ALLOCATIONS.call_once(init_phys_allocations).lock();
+        allocations.iter().any(|(&start, &count)| {
+            address >= start && address + size <= start + count * 4096
+        })
+    }
+}
+
 //TODO: verify exlusive access to physical memory
 pub fn physmap(physical_address: usize, size: usize, flags: usize) -> Result<usize> {
     if size == 0 {
         Ok(0)
     } else {
+        let (pid, name) = phys_privileged()?;
+
+        let allowed = physmap_allowed(physical_address, size);
+        println!("AUDIT: physmap {:>016X}:{:X} flags {:X} by PID {} ({}): {}",
+                 physical_address, size, flags, pid, unsafe { str::from_utf8_unchecked(&name) },
+                 if allowed { "allowed" } else { "denied" });
+        if ! allowed {
+            return Err(Error::new(EPERM));
+        }
+
         let contexts = context::contexts();
         let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
         let context = context_lock.read();
 
-        let mut grants = context.grants.lock();
+        let mut grants = context.grants.write();
 
         let from_address = (physical_address/4096) * 4096;
         let offset = physical_address - from_address;
         let full_size = ((offset + size + 4095)/4096) * 4096;
-        let mut to_address = arch::USER_GRANT_OFFSET;
+
+        // Large ranges are placed where they line up with the physical address in a huge page,
+        // so that they are mapped with huge pages
+        let align = |address: usize| if full_size >= HUGE_PAGE_SIZE {
+            address + (from_address % HUGE_PAGE_SIZE + HUGE_PAGE_SIZE - address % HUGE_PAGE_SIZE) % HUGE_PAGE_SIZE
+        } else {
+            address
+        };
+        let mut to_address = align(arch::USER_GRANT_OFFSET);
 
         let mut entry_flags = entry::PRESENT | entry::NO_EXECUTE | entry::USER_ACCESSIBLE;
         if flags & MAP_WRITE == MAP_WRITE {
             entry_flags |= entry::WRITABLE;
         }
         if flags & MAP_WRITE_COMBINE == MAP_WRITE_COMBINE {
-            entry_flags |= entry::HUGE_PAGE;
+            entry_flags |= entry::WRITE_COMBINING;
         }
 
         for i in 0 .. grants.len() {
@@ -983,10 +1272,15 @@ pub fn physmap(physical_address: usize, size: usize, flags: usize) -> Result<usi
             } else {
                 let pages = (grants[i].size() + 4095) / 4096;
                 let end = start + pages * 4096;
-                to_address = end;
+                to_address = align(end);
             }
         }
 
+        // Grants are kept out of the guard below the stack
+        if to_address + full_size > arch::USER_STACK_OFFSET - arch::USER_STACK_GUARD_SIZE {
+            return Err(Error::new(ENOMEM));
+        }
+
         grants.push(Grant::physmap(
             PhysicalAddress::new(from_address),
             VirtualAddress::new(to_address),
@@ -1006,7 +1300,7 @@ pub fn physunmap(virtual_address: usize) -> Result<usize> {
         let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
         let context = context_lock.read();
 
-        let mut grants = context.grants.lock();
+        let mut grants = context.grants.write();
 
         for i in 0 .. grants.len() {
             let start = grants[i].start_address().get();
@@ -1049,6 +1343,11 @@ pub fn setuid(uid: u32) -> Result<usize> {
 }
 
 pub fn virttophys(virtual_address: usize) -> Result<usize> {
+    // The physical address may be used for DMA, so it must not be the shared zero frame
+    if ! context::memory::make_private(VirtualAddress::new(virtual_address)) {
+        return Err(Error::new(ENOMEM));
+    }
+
     let active_table = unsafe { ActivePageTable::new() };
     match active_table.translate(VirtualAddress::new(virtual_address)) {
         Some(physical_address) => Ok(physical_address.get()),
@@ -1070,8 +1369,25 @@ fn reap(pid: usize) -> Result<usize> {
         arch::interrupt::pause();
     }
 
+    // The caller names the context by its PID in the namespace of the caller
+    let local_pid = pidns::to_local(&pidns::current(), pid).unwrap_or(pid);
+
     let mut contexts = context::contexts_mut();
-    contexts.remove(pid).ok_or(Error::new(ESRCH)).and(Ok(pid))
+    let context_lock = contexts.remove(pid).ok_or(Error::new(ESRCH))?;
+
+    // The reaper is charged for what the context, and the children it reaped, used
+    let rusage = {
+        let context = context_lock.read();
+        pidns::unregister(&context.pid_ns, pid);
+        let mut rusage = context.usage.rusage();
+        rusage.add(&context.children_usage);
+        rusage
+    };
+    if let Some(current_lock) = contexts.current() {
+        current_lock.write().children_usage.add(&rusage);
+    }
+
+    Ok(local_pid)
 }
 
 pub fn waitpid(pid: usize, status_ptr: usize, flags: usize) -> Result<usize> {
@@ -1082,6 +1398,13 @@ pub fn waitpid(pid: usize, status_ptr: usize, flags: usize) -> Result<usize> {
         context.waitpid.clone()
     };
 
+    // Children are named by their PID in the namespace of the caller
+    let pid = if pid == 0 {
+        0
+    } else {
+        pidns::resolve(pid).ok_or(Error::new(ESRCH))?
+    };
+
     let mut tmp = [0];
     let status_slice = if status_ptr != 0 {
         validate_slice_mut(status_ptr as *mut usize, 1)?
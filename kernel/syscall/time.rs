@@ -4,6 +4,18 @@ use syscall::data::TimeSpec;
 use syscall::error::*;
 use syscall::flag::{CLOCK_REALTIME, CLOCK_MONOTONIC};
 
+/// The monotonic time, in seconds and nanoseconds, as seen by a context with the clock offset
+/// `offset`. It does not go below zero
+pub fn monotonic(offset: i64) -> (u64, u64) {
+    let arch_time = arch::time::monotonic();
+    let nanoseconds = (arch_time.0 * 1000000000 + arch_time.1) as i64 + offset;
+    if nanoseconds > 0 {
+        (nanoseconds as u64 / 1000000000, nanoseconds as u64 % 1000000000)
+    } else {
+        (0, 0)
+    }
+}
+
 pub fn clock_gettime(clock: usize, time: &mut TimeSpec) -> Result<usize> {
     match clock {
         CLOCK_REALTIME => {
@@ -13,7 +25,13 @@ pub fn clock_gettime(clock: usize, time: &mut TimeSpec) -> Result<usize> {
             Ok(0)
         },
         CLOCK_MONOTONIC => {
-            let arch_time = arch::time::monotonic();
+            let offset = {
+                let contexts = context::contexts();
+                let context_lock = contexts.current().ok_or(Error::new(ESRCH))?;
+                let context = context_lock.read();
+                context.clock_offset
+            };
+            let arch_time = monotonic(offset);
             time.tv_sec = arch_time.0 as i64;
             time.tv_nsec = arch_time.1 as i32;
             Ok(0)
@@ -6,10 +6,17 @@ use syscall::error::*;
 fn validate(address: usize, size: usize, flags: entry::EntryFlags) -> Result<()> {
     let active_table = unsafe { ActivePageTable::new() };
 
+    // A range that wraps around the end of the address space would have no pages to check
+    let end = address.checked_add(size - 1).ok_or(Error::new(EFAULT))?;
+
     let start_page = Page::containing_address(VirtualAddress::new(address));
-    let end_page = Page::containing_address(VirtualAddress::new(address + size - 1));
+    let end_page = Page::containing_address(VirtualAddress::new(end));
     for page in Page::range_inclusive(start_page, end_page) {
-        let page_flags = active_table.translate_page_flags(page).ok_or(Error::new(EFAULT))?;
+        let mut page_flags = active_table.translate_page_flags(page).ok_or(Error::new(EFAULT))?;
+        // Copy-on-write pages become writable when the kernel first writes to them
+        if page_flags.contains(entry::COPY_ON_WRITE) {
+            page_flags.insert(entry::WRITABLE);
+        }
         if ! page_flags.contains(flags) {
             return Err(Error::new(EFAULT));
         }
@@ -23,7 +30,8 @@ pub fn validate_slice<T>(ptr: *const T, len: usize) -> Result<&'static [T]> {
     if len == 0 {
         Ok(&[])
     } else {
-        validate(ptr as usize, len * mem::size_of::<T>(), entry::PRESENT /* TODO | entry::USER_ACCESSIBLE */)?;
+        let size = len.checked_mul(mem::size_of::<T>()).ok_or(Error::new(EFAULT))?;
+        validate(ptr as usize, size, entry::PRESENT /* TODO | entry::USER_ACCESSIBLE */)?;
         Ok(unsafe { slice::from_raw_parts(ptr, len) })
     }
 }
@@ -33,7 +41,8 @@ pub fn validate_slice_mut<T>(ptr: *mut T, len: usize) -> Result<&'static mut [T]
     if len == 0 {
         Ok(&mut [])
     } else {
-        validate(ptr as usize, len * mem::size_of::<T>(), entry::PRESENT | entry::WRITABLE /* TODO | entry::USER_ACCESSIBLE */)?;
+        let size = len.checked_mul(mem::size_of::<T>()).ok_or(Error::new(EFAULT))?;
+        validate(ptr as usize, size, entry::PRESENT | entry::WRITABLE /* TODO | entry::USER_ACCESSIBLE */)?;
         Ok(unsafe { slice::from_raw_parts_mut(ptr, len) })
     }
 }
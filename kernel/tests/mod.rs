@@ -27,3 +27,146 @@ fn invalid_path() {
     assert_eq!(syscall::read(999, &mut []), Err(Error::new(EBADF)));
     assert_eq!(syscall::write(999, &[]), Err(Error::new(EBADF)));
 }
+
+/// Test CRC32C, SHA-256, AES and ChaCha20 against published vectors
+#[test]
+fn crypto_vectors() {
+    use crypto::{crc32c, sha256, Aes, ChaCha20, Sha256};
+
+    assert_eq!(crc32c(b"123456789"), 0xE3069283);
+    assert_eq!(crc32c::update(crc32c(b"1234"), b"56789"), 0xE3069283);
+
+    assert_eq!(&sha256(b"")[..], &[
+        0xe3, 0xb0, 0xc4, 0x42, 0x98, 0xfc, 0x1c, 0x14, 0x9a, 0xfb, 0xf4, 0xc8, 0x99, 0x6f, 0xb9, 0x24,
+        0x27, 0xae, 0x41, 0xe4, 0x64, 0x9b, 0x93, 0x4c, 0xa4, 0x95, 0x99, 0x1b, 0x78, 0x52, 0xb8, 0x55
+    ][..]);
+    assert_eq!(&sha256(b"abc")[..], &[
+        0xba, 0x78, 0x16, 0xbf, 0x8f, 0x01, 0xcf, 0xea, 0x41, 0x41, 0x40, 0xde, 0x5d, 0xae, 0x22, 0x23,
+        0xb0, 0x03, 0x61, 0xa3, 0x96, 0x17, 0x7a, 0x9c, 0xb4, 0x10, 0xff, 0x61, 0xf2, 0x00, 0x15, 0xad
+    ][..]);
+    let mut hash = Sha256::new();
+    for _ in 0..1000 {
+        hash.update(&[b'a'; 1000]);
+    }
+    assert_eq!(&hash.finish()[..], &[
+        0xcd, 0xc7, 0x6e, 0x5c, 0x99, 0x14, 0xfb, 0x92, 0x81, 0xa1, 0xc7, 0xe2, 0x84, 0xd7, 0x3e, 0x67,
+        0xf1, 0x80, 0x9a, 0x48, 0xa4, 0x97, 0x20, 0x0e, 0x04, 0x6d, 0x39, 0xcc, 0xc7, 0x11, 0x2c, 0xd0
+    ][..]);
+
+    // FIPS-197, appendix C.3
+    let mut key = [0; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    let mut block = [0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff];
+    let aes = Aes::new(&key).unwrap();
+    aes.encrypt_block(&mut block);
+    assert_eq!(block, [0x8e, 0xa2, 0xb7, 0xca, 0x51, 0x67, 0x45, 0xbf, 0xea, 0xfc, 0x49, 0x90, 0x4b, 0x49, 0x60, 0x89]);
+    aes.decrypt_block(&mut block);
+    assert_eq!(block, [0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff]);
+
+    // RFC 7539, section 2.4.2
+    let nonce = [0, 0, 0, 0, 0, 0, 0, 0x4a, 0, 0, 0, 0];
+    let mut data = *b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.";
+    ChaCha20::new(&key, &nonce, 1).apply(&mut data);
+    assert_eq!(&data[..16], &[0x6e, 0x2e, 0x35, 0x9a, 0x25, 0x68, 0xf9, 0x80, 0x41, 0xba, 0x07, 0x28, 0xdd, 0x0d, 0x69, 0x81]);
+    assert_eq!(&data[data.len() - 4..], &[0x5e, 0x42, 0x87, 0x4d]);
+}
+
+/// Test that paths are sent to the mount that covers the most of them
+#[test]
+fn mount_resolve() {
+    use scheme::mount::MountTable;
+
+    let mut mounts = MountTable::new();
+    let tmp = mounts.insert(b"file:/tmp/", b"tmp:").unwrap();
+    let home = mounts.insert(b"file:/tmp/home", b"file:/home/user").unwrap();
+    assert_eq!(mounts.insert(b"file:/tmp", b"zero:"), Err(Error::new(syscall::error::EEXIST)));
+
+    assert_eq!(mounts.resolve(b"file:/tmp".to_vec()), (b"tmp:".to_vec(), Some(tmp)));
+    assert_eq!(mounts.resolve(b"file:/tmp/a/b".to_vec()), (b"tmp:/a/b".to_vec(), Some(tmp)));
+    assert_eq!(mounts.resolve(b"file:/tmp/home/a".to_vec()), (b"file:/home/user/a".to_vec(), Some(home)));
+    assert_eq!(mounts.resolve(b"file:/tmpfile".to_vec()), (b"file:/tmpfile".to_vec(), None));
+}
+
+/// Test that buffers that wrap around or are not mapped are refused, and that schemes cannot claim
+/// to have copied more than the buffer holds
+#[test]
+fn copy_bounds() {
+    use core::usize;
+    use scheme::user::{checked_copy, USER_SCHEME_MAX_COPY};
+    use syscall::error::{EFAULT, EIO};
+
+    assert_eq!(syscall::validate_slice(usize::MAX as *const u8, 2).err(), Some(Error::new(EFAULT)));
+    assert_eq!(syscall::validate_slice(0x1000 as *const u64, usize::MAX / 4).err(), Some(Error::new(EFAULT)));
+    assert_eq!(syscall::validate_slice_mut(0 as *mut u8, 1).err(), Some(Error::new(EFAULT)));
+    assert_eq!(syscall::validate_slice(0 as *const u8, 0).map(|slice| slice.len()), Ok(0));
+
+    assert_eq!(checked_copy(Ok(4), 4), Ok(4));
+    assert_eq!(checked_copy(Ok(5), 4), Err(Error::new(EIO)));
+    assert_eq!(checked_copy(Ok(USER_SCHEME_MAX_COPY + 1), USER_SCHEME_MAX_COPY), Err(Error::new(EIO)));
+    assert_eq!(checked_copy(Err(Error::new(EFAULT)), 4), Err(Error::new(EFAULT)));
+}
+
+/// Test that PIDs in a namespace start at 1, are also given in the outer namespace, and that
+/// contexts outside of a namespace cannot be named from it
+#[test]
+fn pid_namespace() {
+    use alloc::arc::Arc;
+    use context::pidns::{self, PidNamespace};
+
+    let outer = Some(Arc::new(PidNamespace::new(None, 10)));
+    pidns::register(&outer, 10);
+    let inner = Some(Arc::new(PidNamespace::new(outer.clone(), 11)));
+    pidns::register(&inner, 11);
+    pidns::register(&inner, 12);
+
+    assert_eq!(pidns::to_local(&outer, 10), Some(1));
+    assert_eq!(pidns::to_local(&outer, 12), Some(3));
+    assert_eq!(pidns::to_local(&inner, 11), Some(1));
+    assert_eq!(pidns::to_local(&inner, 12), Some(2));
+    assert_eq!(pidns::to_local(&inner, 10), None);
+    assert_eq!(pidns::to_global(&inner, 2), Some(12));
+    assert_eq!(pidns::to_global(&None, 12), Some(12));
+
+    pidns::unregister(&inner, 12);
+    assert_eq!(pidns::to_global(&inner, 2), None);
+    assert_eq!(pidns::to_local(&outer, 12), None);
+}
+
+/// Test that the usage of reaped children is added up, keeping the largest resident size
+#[test]
+fn rusage_add() {
+    use context::usage::{Rusage, Usage};
+
+    let usage = Usage::new();
+    usage.sample_rss(4);
+    usage.sample_rss(2);
+    usage.count(syscall::number::SYS_READ, &Ok(10));
+    usage.count(syscall::number::SYS_READ, &Ok(0));
+    usage.count(syscall::number::SYS_WRITE, &Err(Error::new(EBADF)));
+
+    let mut children = Rusage::default();
+    children.max_rss = 3;
+    children.reads = 2;
+    children.add(&usage.rusage());
+
+    assert_eq!(children.max_rss, 4);
+    assert_eq!(children.reads, 3);
+    assert_eq!(children.writes, 0);
+}
+
+/// Test that the working set over a number of scans counts the pages younger than it
+#[test]
+fn working_set_pages() {
+    use context::working_set::{WorkingSet, WORKING_SET_MAX_AGE};
+
+    let mut working_set = WorkingSet::default();
+    working_set.ages.insert(0x1000, 0);
+    working_set.ages.insert(0x2000, 2);
+    working_set.ages.insert(0x3000, WORKING_SET_MAX_AGE);
+
+    assert_eq!(working_set.pages(1), 1);
+    assert_eq!(working_set.pages(3), 2);
+    assert_eq!(working_set.pages(WORKING_SET_MAX_AGE), 2);
+}
@@ -53,13 +53,21 @@ pub fn run(file: &str) -> Result<()> {
                         println!("init: failed to run: no argument");
                     },
                     "stdio" => if let Some(stdio) = args.next() {
-                        let _ = syscall::close(2);
-                        let _ = syscall::close(1);
-                        let _ = syscall::close(0);
+                        // Keep the current stdio if the new one is not available, such as the display on a serial only kernel
+                        match syscall::open(&stdio, syscall::flag::O_RDWR) {
+                            Ok(fd) => {
+                                let _ = syscall::close(fd);
 
-                        let _ = syscall::open(&stdio, syscall::flag::O_RDWR);
-                        let _ = syscall::open(&stdio, syscall::flag::O_RDWR);
-                        let _ = syscall::open(&stdio, syscall::flag::O_RDWR);
+                                let _ = syscall::close(2);
+                                let _ = syscall::close(1);
+                                let _ = syscall::close(0);
+
+                                let _ = syscall::open(&stdio, syscall::flag::O_RDWR);
+                                let _ = syscall::open(&stdio, syscall::flag::O_RDWR);
+                                let _ = syscall::open(&stdio, syscall::flag::O_RDWR);
+                            },
+                            Err(err) => println!("init: failed to set stdio to '{}': {}", stdio, err)
+                        }
                     } else {
                         println!("init: failed to set stdio: no argument");
                     },
@@ -0,0 +1,91 @@
+use std::cmp;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crypto::Xts;
+use syscall::error::{Error, Result, EIO};
+
+/// Size of a sector, each is encrypted with its number as the tweak
+pub const SECTOR_SIZE: usize = 512;
+
+fn io_error<T>(_err: T) -> Error {
+    Error::new(EIO)
+}
+
+/// A block device that encrypts another one
+pub struct CryptDevice {
+    pub path: String,
+    file: File,
+    size: u64,
+    xts: Xts
+}
+
+impl CryptDevice {
+    pub fn new(path: &str, xts: Xts) -> Result<CryptDevice> {
+        let mut file = OpenOptions::new().read(true).write(true).open(path).map_err(io_error)?;
+        let size = file.seek(SeekFrom::End(0)).map_err(io_error)?;
+
+        Ok(CryptDevice {
+            path: path.to_string(),
+            file: file,
+            // A partial sector at the end cannot be encrypted, so it is left out
+            size: size / SECTOR_SIZE as u64 * SECTOR_SIZE as u64,
+            xts: xts
+        })
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_sector(&mut self, sector: u64, buf: &mut [u8; SECTOR_SIZE]) -> Result<()> {
+        self.file.seek(SeekFrom::Start(sector * SECTOR_SIZE as u64)).map_err(io_error)?;
+        self.file.read_exact(buf).map_err(io_error)?;
+        self.xts.decrypt(sector, buf);
+        Ok(())
+    }
+
+    fn write_sector(&mut self, sector: u64, buf: &mut [u8; SECTOR_SIZE]) -> Result<()> {
+        self.xts.encrypt(sector, buf);
+        self.file.seek(SeekFrom::Start(sector * SECTOR_SIZE as u64)).map_err(io_error)?;
+        self.file.write_all(buf).map_err(io_error)
+    }
+
+    pub fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let mut sector = [0; SECTOR_SIZE];
+        let mut count = 0;
+        while count < buf.len() && offset + (count as u64) < self.size {
+            let position = offset + count as u64;
+            let sector_offset = (position % SECTOR_SIZE as u64) as usize;
+            let len = cmp::min(SECTOR_SIZE - sector_offset, buf.len() - count);
+
+            self.read_sector(position / SECTOR_SIZE as u64, &mut sector)?;
+            buf[count .. count + len].copy_from_slice(&sector[sector_offset .. sector_offset + len]);
+            count += len;
+        }
+        Ok(count)
+    }
+
+    pub fn write(&mut self, offset: u64, buf: &[u8]) -> Result<usize> {
+        let mut sector = [0; SECTOR_SIZE];
+        let mut count = 0;
+        while count < buf.len() && offset + (count as u64) < self.size {
+            let position = offset + count as u64;
+            let sector_offset = (position % SECTOR_SIZE as u64) as usize;
+            let len = cmp::min(SECTOR_SIZE - sector_offset, buf.len() - count);
+
+            // Part of a sector is changed by decrypting it first
+            if len < SECTOR_SIZE {
+                self.read_sector(position / SECTOR_SIZE as u64, &mut sector)?;
+            }
+            sector[sector_offset .. sector_offset + len].copy_from_slice(&buf[count .. count + len]);
+            self.write_sector(position / SECTOR_SIZE as u64, &mut sector)?;
+            count += len;
+        }
+        Ok(count)
+    }
+
+    pub fn sync(&mut self) -> Result<()> {
+        self.file.sync_all().map_err(io_error)
+    }
+}
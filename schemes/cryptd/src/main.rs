@@ -0,0 +1,39 @@
+//! Encrypted block devices, at `crypt:`
+//!
+//! Another block device, such as `disk:0`, is attached with a key by writing
+//! `attach <name> <path> <key>` to `crypt:`, and is then read and written decrypted at
+//! `crypt:<name>`. Sectors are encrypted with AES-XTS, using AES-NI when the CPU has it.
+//! Reading `crypt:` lists the attached devices. Only root may use the scheme.
+
+extern crate crypto;
+extern crate syscall;
+
+use std::fs::File;
+use std::io::{Read, Write};
+
+use syscall::data::Packet;
+use syscall::scheme::SchemeMut;
+
+use scheme::CryptScheme;
+
+mod device;
+mod scheme;
+
+fn main() {
+    // Daemonize
+    if unsafe { syscall::clone(0).unwrap() } == 0 {
+        let mut socket = File::create(":crypt").expect("crypt: failed to create crypt scheme");
+        let mut scheme = CryptScheme::new();
+
+        if crypto::aes::accelerated() {
+            println!("crypt: using AES-NI");
+        }
+
+        loop {
+            let mut packet = Packet::default();
+            socket.read(&mut packet).expect("crypt: failed to read events from crypt scheme");
+            scheme.handle(&mut packet);
+            socket.write(&packet).expect("crypt: failed to write responses to crypt scheme");
+        }
+    }
+}
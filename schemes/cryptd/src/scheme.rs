@@ -0,0 +1,258 @@
+use std::{cmp, str};
+use std::collections::BTreeMap;
+
+use crypto::Xts;
+use syscall::data::Stat;
+use syscall::error::{Error, Result, EACCES, EBADF, EBUSY, EEXIST, EINVAL, ENOENT};
+use syscall::flag::{MODE_DIR, MODE_FILE, SEEK_CUR, SEEK_END, SEEK_SET};
+use syscall::scheme::SchemeMut;
+
+use device::CryptDevice;
+
+enum Handle {
+    /// `crypt:`, which lists devices when read and takes commands when written
+    Control {
+        data: Vec<u8>,
+        seek: usize
+    },
+    Device {
+        name: String,
+        seek: u64
+    }
+}
+
+pub struct CryptScheme {
+    devices: BTreeMap<String, CryptDevice>,
+    handles: BTreeMap<usize, Handle>,
+    next_id: usize
+}
+
+/// Parse a key written in hexadecimal
+fn parse_key(hex: &str) -> Result<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return Err(Error::new(EINVAL));
+    }
+
+    let mut key = Vec::with_capacity(hex.len() / 2);
+    for i in 0 .. hex.len() / 2 {
+        let byte = u8::from_str_radix(&hex[i * 2 .. i * 2 + 2], 16).or(Err(Error::new(EINVAL)))?;
+        key.push(byte);
+    }
+    Ok(key)
+}
+
+impl CryptScheme {
+    pub fn new() -> CryptScheme {
+        CryptScheme {
+            devices: BTreeMap::new(),
+            handles: BTreeMap::new(),
+            next_id: 0
+        }
+    }
+
+    fn list(&self) -> Vec<u8> {
+        let mut string = String::new();
+        for (name, device) in self.devices.iter() {
+            string.push_str(&format!("{} {} {}\n", name, device.path, device.size()));
+        }
+        string.into_bytes()
+    }
+
+    fn attach(&mut self, name: &str, path: &str, hex: &str) -> Result<()> {
+        if name.is_empty() || name.contains('/') {
+            return Err(Error::new(EINVAL));
+        }
+        if self.devices.contains_key(name) {
+            return Err(Error::new(EEXIST));
+        }
+
+        let mut key = parse_key(hex)?;
+        let xts_option = Xts::new(&key);
+        for byte in key.iter_mut() {
+            *byte = 0;
+        }
+        let xts = xts_option.ok_or(Error::new(EINVAL))?;
+
+        let device = CryptDevice::new(path, xts)?;
+        println!("crypt: attached {} to {}, {} MB", name, path, device.size() / 1024 / 1024);
+        self.devices.insert(name.to_string(), device);
+        Ok(())
+    }
+
+    fn detach(&mut self, name: &str) -> Result<()> {
+        for handle in self.handles.values() {
+            if let Handle::Device { name: ref other, .. } = *handle {
+                if other == name {
+                    return Err(Error::new(EBUSY));
+                }
+            }
+        }
+
+        self.devices.remove(name).ok_or(Error::new(ENOENT))?;
+        println!("crypt: detached {}", name);
+        Ok(())
+    }
+
+    /// Run a command written to `crypt:`
+    ///
+    /// `attach <name> <path> <key>` opens the block device at `path` and serves it decrypted at
+    /// `crypt:<name>`. The key is in hexadecimal, 32 bytes for AES-128-XTS or 64 bytes for
+    /// AES-256-XTS. `detach <name>` removes a device that is not open
+    fn command(&mut self, line: &str) -> Result<()> {
+        let mut args = line.split_whitespace();
+        match (args.next(), args.next(), args.next(), args.next(), args.next()) {
+            (Some("attach"), Some(name), Some(path), Some(key), None) => self.attach(name, path, key),
+            (Some("detach"), Some(name), None, None, None) => self.detach(name),
+            _ => Err(Error::new(EINVAL))
+        }
+    }
+}
+
+impl SchemeMut for CryptScheme {
+    fn open(&mut self, path: &[u8], _flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        if uid != 0 {
+            return Err(Error::new(EACCES));
+        }
+
+        let path = str::from_utf8(path).or(Err(Error::new(ENOENT)))?.trim_matches('/');
+        let handle = if path.is_empty() {
+            Handle::Control {
+                data: self.list(),
+                seek: 0
+            }
+        } else if self.devices.contains_key(path) {
+            Handle::Device {
+                name: path.to_string(),
+                seek: 0
+            }
+        } else {
+            return Err(Error::new(ENOENT));
+        };
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.handles.insert(id, handle);
+        Ok(id)
+    }
+
+    fn dup(&mut self, id: usize, _buf: &[u8]) -> Result<usize> {
+        let handle = match *self.handles.get(&id).ok_or(Error::new(EBADF))? {
+            Handle::Control { ref data, seek } => Handle::Control {
+                data: data.clone(),
+                seek: seek
+            },
+            Handle::Device { ref name, seek } => Handle::Device {
+                name: name.clone(),
+                seek: seek
+            }
+        };
+
+        let new_id = self.next_id;
+        self.next_id += 1;
+        self.handles.insert(new_id, handle);
+        Ok(new_id)
+    }
+
+    fn read(&mut self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        match *self.handles.get_mut(&id).ok_or(Error::new(EBADF))? {
+            Handle::Control { ref data, ref mut seek } => {
+                let count = cmp::min(buf.len(), data.len() - *seek);
+                buf[.. count].copy_from_slice(&data[*seek .. *seek + count]);
+                *seek += count;
+                Ok(count)
+            },
+            Handle::Device { ref name, ref mut seek } => {
+                let device = self.devices.get_mut(name).ok_or(Error::new(EBADF))?;
+                let count = device.read(*seek, buf)?;
+                *seek += count as u64;
+                Ok(count)
+            }
+        }
+    }
+
+    fn write(&mut self, id: usize, buf: &[u8]) -> Result<usize> {
+        let is_control = match *self.handles.get_mut(&id).ok_or(Error::new(EBADF))? {
+            Handle::Control { .. } => true,
+            Handle::Device { ref name, ref mut seek } => {
+                let device = self.devices.get_mut(name).ok_or(Error::new(EBADF))?;
+                let count = device.write(*seek, buf)?;
+                *seek += count as u64;
+                return Ok(count);
+            }
+        };
+
+        if is_control {
+            let line = str::from_utf8(buf).or(Err(Error::new(EINVAL)))?;
+            self.command(line)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn seek(&mut self, id: usize, pos: usize, whence: usize) -> Result<usize> {
+        let (len, seek) = match *self.handles.get_mut(&id).ok_or(Error::new(EBADF))? {
+            Handle::Control { ref data, ref mut seek } => {
+                *seek = match whence {
+                    SEEK_SET => cmp::min(data.len(), pos),
+                    SEEK_CUR => cmp::max(0, cmp::min(data.len() as isize, *seek as isize + pos as isize)) as usize,
+                    SEEK_END => cmp::max(0, cmp::min(data.len() as isize, data.len() as isize + pos as isize)) as usize,
+                    _ => return Err(Error::new(EINVAL))
+                };
+                return Ok(*seek);
+            },
+            Handle::Device { ref name, ref mut seek } => {
+                let device = self.devices.get(name).ok_or(Error::new(EBADF))?;
+                (device.size() as i64, seek)
+            }
+        };
+
+        // Devices may be larger than a usize on 32 bit systems, so the position is kept as a u64
+        *seek = match whence {
+            SEEK_SET => cmp::min(len, pos as i64),
+            SEEK_CUR => cmp::max(0, cmp::min(len, *seek as i64 + pos as isize as i64)),
+            SEEK_END => cmp::max(0, cmp::min(len, len + pos as isize as i64)),
+            _ => return Err(Error::new(EINVAL))
+        } as u64;
+
+        Ok(*seek as usize)
+    }
+
+    fn fpath(&mut self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let path = match *self.handles.get(&id).ok_or(Error::new(EBADF))? {
+            Handle::Control { .. } => format!("crypt:"),
+            Handle::Device { ref name, .. } => format!("crypt:{}", name)
+        };
+
+        let count = cmp::min(buf.len(), path.len());
+        buf[.. count].copy_from_slice(&path.as_bytes()[.. count]);
+        Ok(count)
+    }
+
+    fn fstat(&mut self, id: usize, stat: &mut Stat) -> Result<usize> {
+        match *self.handles.get(&id).ok_or(Error::new(EBADF))? {
+            Handle::Control { ref data, .. } => {
+                stat.st_mode = MODE_DIR | 0o700;
+                stat.st_size = data.len() as u64;
+            },
+            Handle::Device { ref name, .. } => {
+                let device = self.devices.get(name).ok_or(Error::new(EBADF))?;
+                stat.st_mode = MODE_FILE | 0o600;
+                stat.st_size = device.size();
+            }
+        }
+        Ok(0)
+    }
+
+    fn fsync(&mut self, id: usize) -> Result<usize> {
+        match *self.handles.get(&id).ok_or(Error::new(EBADF))? {
+            Handle::Control { .. } => Ok(0),
+            Handle::Device { ref name, .. } => {
+                let device = self.devices.get_mut(name).ok_or(Error::new(EBADF))?;
+                device.sync().and(Ok(0))
+            }
+        }
+    }
+
+    fn close(&mut self, id: usize) -> Result<usize> {
+        self.handles.remove(&id).ok_or(Error::new(EBADF)).and(Ok(0))
+    }
+}
@@ -0,0 +1,133 @@
+//! Building DNS queries for IPv4 addresses, and reading the answers
+
+/// Record type of an IPv4 address
+const TYPE_A: u16 = 1;
+
+/// Internet class
+const CLASS_IN: u16 = 1;
+
+/// The answer to a query
+pub enum Answer {
+    /// An address, and the number of seconds it may be cached for
+    Address([u8; 4], u32),
+    /// The name does not exist, or has no address
+    NotFound
+}
+
+fn get_u16(data: &[u8], i: usize) -> Option<u16> {
+    if i + 2 <= data.len() {
+        Some((data[i] as u16) << 8 | data[i + 1] as u16)
+    } else {
+        None
+    }
+}
+
+fn get_u32(data: &[u8], i: usize) -> Option<u32> {
+    match (get_u16(data, i), get_u16(data, i + 2)) {
+        (Some(high), Some(low)) => Some((high as u32) << 16 | low as u32),
+        _ => None
+    }
+}
+
+/// Returns the offset after a name, which may end in a pointer to another name
+fn skip_name(data: &[u8], mut i: usize) -> Option<usize> {
+    loop {
+        let len = match data.get(i) {
+            Some(&len) => len as usize,
+            None => return None
+        };
+
+        if len == 0 {
+            return Some(i + 1);
+        } else if len & 0xC0 == 0xC0 {
+            return Some(i + 2);
+        } else {
+            i += 1 + len;
+        }
+    }
+}
+
+/// Build a recursive query for the address of `name`
+pub fn query(id: u16, name: &str) -> Option<Vec<u8>> {
+    let mut data = vec![
+        (id >> 8) as u8, id as u8,
+        // Recursion desired
+        0x01, 0x00,
+        // One question, no other records
+        0, 1, 0, 0, 0, 0, 0, 0
+    ];
+
+    for label in name.trim_right_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return None;
+        }
+        data.push(label.len() as u8);
+        data.extend_from_slice(label.as_bytes());
+    }
+    data.push(0);
+
+    data.extend_from_slice(&[(TYPE_A >> 8) as u8, TYPE_A as u8, (CLASS_IN >> 8) as u8, CLASS_IN as u8]);
+
+    if data.len() > 512 {
+        None
+    } else {
+        Some(data)
+    }
+}
+
+/// Read the response to the query with `id`, returning None if it is not one
+pub fn response(data: &[u8], id: u16) -> Option<Answer> {
+    if get_u16(data, 0) != Some(id) {
+        return None;
+    }
+
+    let flags = match get_u16(data, 2) {
+        Some(flags) => flags,
+        None => return None
+    };
+    if flags & 0x8000 == 0 {
+        return None;
+    }
+    if flags & 0xF != 0 {
+        return Some(Answer::NotFound);
+    }
+
+    let (questions, answers) = match (get_u16(data, 4), get_u16(data, 6)) {
+        (Some(questions), Some(answers)) => (questions, answers),
+        _ => return None
+    };
+
+    let mut i = 12;
+    for _question in 0..questions {
+        i = match skip_name(data, i) {
+            Some(i) => i + 4,
+            None => return None
+        };
+    }
+
+    // Aliases come first, followed by their addresses
+    for _answer in 0..answers {
+        i = match skip_name(data, i) {
+            Some(i) => i,
+            None => return None
+        };
+
+        let (kind, class, ttl, len) = match (get_u16(data, i), get_u16(data, i + 2), get_u32(data, i + 4), get_u16(data, i + 8)) {
+            (Some(kind), Some(class), Some(ttl), Some(len)) => (kind, class, ttl, len as usize),
+            _ => return None
+        };
+        i += 10;
+
+        if i + len > data.len() {
+            return None;
+        }
+
+        if kind == TYPE_A && class == CLASS_IN && len == 4 {
+            return Some(Answer::Address([data[i], data[i + 1], data[i + 2], data[i + 3]], ttl));
+        }
+
+        i += len;
+    }
+
+    Some(Answer::NotFound)
+}
@@ -0,0 +1,34 @@
+//! Stub resolver, at `dns:`
+//!
+//! Reading `dns:<name>` gives the IPv4 address of the name, asked of the nameserver in
+//! `/etc/net/dns` over `udp:`. Addresses are cached for their time to live. Reading `dns:` lists
+//! the cache, and writing `flush` to it empties the cache
+
+extern crate netutils;
+extern crate syscall;
+
+use std::fs::File;
+use std::io::{Read, Write};
+
+use syscall::data::Packet;
+use syscall::scheme::SchemeMut;
+
+use scheme::DnsScheme;
+
+mod dns;
+mod scheme;
+
+fn main() {
+    // Daemonize
+    if unsafe { syscall::clone(0).unwrap() } == 0 {
+        let mut socket = File::create(":dns").expect("dns: failed to create dns scheme");
+        let mut scheme = DnsScheme::new();
+
+        loop {
+            let mut packet = Packet::default();
+            socket.read(&mut packet).expect("dns: failed to read events from dns scheme");
+            scheme.handle(&mut packet);
+            socket.write(&packet).expect("dns: failed to write responses to dns scheme");
+        }
+    }
+}
@@ -0,0 +1,252 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::io::FromRawFd;
+use std::time::Duration;
+use std::{cmp, str, thread};
+
+use netutils::getcfg;
+use syscall;
+use syscall::data::{Stat, TimeSpec};
+use syscall::error::{Error, Result, EACCES, EBADF, EINVAL, EIO, ENOENT, ETIMEDOUT};
+use syscall::flag::{CLOCK_MONOTONIC, MODE_FILE, O_NONBLOCK, O_RDWR, SEEK_CUR, SEEK_END, SEEK_SET};
+use syscall::scheme::SchemeMut;
+
+use dns::{self, Answer};
+
+/// Limit on the number of names that are cached, the least recently used is dropped first
+const CACHE_MAX: usize = 64;
+
+/// Limit on how long an address is cached, in seconds
+const TTL_MAX: u32 = 24 * 60 * 60;
+
+/// Times a query is sent before giving up
+const RETRIES: usize = 3;
+
+/// Time to wait for each reply, checking every `POLL_MS`
+const TIMEOUT_MS: u64 = 2000;
+const POLL_MS: u64 = 50;
+
+struct Entry {
+    address: [u8; 4],
+    /// Monotonic time at which the address expires, in seconds
+    expires: u64,
+    /// When the entry was last used, as a count of lookups
+    used: u64,
+    hits: usize
+}
+
+struct Handle {
+    data: Vec<u8>,
+    seek: usize,
+    /// Writes to the root handle control the cache
+    root: bool,
+    uid: u32
+}
+
+pub struct DnsScheme {
+    cache: BTreeMap<String, Entry>,
+    /// Lookups so far, to order cache entries by use
+    lookups: u64,
+    handles: BTreeMap<usize, Handle>,
+    next_id: usize
+}
+
+fn monotonic() -> TimeSpec {
+    let mut time = TimeSpec::default();
+    let _ = syscall::clock_gettime(CLOCK_MONOTONIC, &mut time);
+    time
+}
+
+fn format_address(address: &[u8; 4]) -> String {
+    format!("{}.{}.{}.{}", address[0], address[1], address[2], address[3])
+}
+
+impl DnsScheme {
+    pub fn new() -> DnsScheme {
+        DnsScheme {
+            cache: BTreeMap::new(),
+            lookups: 0,
+            handles: BTreeMap::new(),
+            next_id: 0
+        }
+    }
+
+    /// Ask the nameserver for the address of a name, sending the query again if there is no reply
+    fn query(&self, name: &str) -> Result<Answer> {
+        let now = monotonic();
+        let id = (now.tv_nsec as u16) ^ (self.lookups as u16);
+        let query = dns::query(id, name).ok_or(Error::new(EINVAL))?;
+
+        // Read each time, as dhcpd may change it
+        let nameserver = getcfg("dns").map_err(|err| Error::new(err.raw_os_error().unwrap_or(EIO)))?;
+        let fd = syscall::open(&format!("udp:{}:53", nameserver.trim()), O_RDWR | O_NONBLOCK)?;
+        let mut socket = unsafe { File::from_raw_fd(fd) };
+
+        for _attempt in 0..RETRIES {
+            socket.write(&query).map_err(|err| Error::new(err.raw_os_error().unwrap_or(EIO)))?;
+
+            for _poll in 0..TIMEOUT_MS / POLL_MS {
+                let mut data = [0; 512];
+                let count = socket.read(&mut data).map_err(|err| Error::new(err.raw_os_error().unwrap_or(EIO)))?;
+                if count > 0 {
+                    if let Some(answer) = dns::response(&data[.. count], id) {
+                        return Ok(answer);
+                    }
+                } else {
+                    thread::sleep(Duration::from_millis(POLL_MS));
+                }
+            }
+        }
+
+        Err(Error::new(ETIMEDOUT))
+    }
+
+    /// Look up the address of a name, from the cache if it has not expired
+    fn resolve(&mut self, name: &str) -> Result<[u8; 4]> {
+        self.lookups += 1;
+        let now = monotonic().tv_sec as u64;
+
+        if let Some(entry) = self.cache.get_mut(name) {
+            if entry.expires > now {
+                entry.used = self.lookups;
+                entry.hits += 1;
+                return Ok(entry.address);
+            }
+        }
+
+        match self.query(name)? {
+            Answer::Address(address, ttl) => {
+                self.cache.remove(name);
+                if self.cache.len() >= CACHE_MAX {
+                    let oldest = self.cache.iter().min_by_key(|&(_name, entry)| entry.used).map(|(name, _entry)| name.clone());
+                    if let Some(oldest) = oldest {
+                        self.cache.remove(&oldest);
+                    }
+                }
+
+                self.cache.insert(name.to_string(), Entry {
+                    address: address,
+                    expires: now + cmp::min(ttl, TTL_MAX) as u64,
+                    used: self.lookups,
+                    hits: 0
+                });
+
+                Ok(address)
+            },
+            Answer::NotFound => Err(Error::new(ENOENT))
+        }
+    }
+
+    /// The cached names, with their addresses, seconds left and hits
+    fn cache_string(&self) -> String {
+        let now = monotonic().tv_sec as u64;
+
+        let mut string = format!("{:<32}{:<16}{:<8}{}\n", "NAME", "ADDRESS", "TTL", "HITS");
+        for (name, entry) in self.cache.iter() {
+            string.push_str(&format!("{:<32}{:<16}{:<8}{}\n",
+                                     name,
+                                     format_address(&entry.address),
+                                     entry.expires.saturating_sub(now),
+                                     entry.hits));
+        }
+        string
+    }
+}
+
+impl SchemeMut for DnsScheme {
+    /// Opening a name resolves it, and reading gives its address. Opening `dns:` lists the cache
+    fn open(&mut self, path: &[u8], _flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        let name = str::from_utf8(path).or(Err(Error::new(ENOENT)))?.trim_matches('/');
+
+        let handle = if name.is_empty() {
+            Handle {
+                data: self.cache_string().into_bytes(),
+                seek: 0,
+                root: true,
+                uid: uid
+            }
+        } else {
+            let address = self.resolve(name)?;
+            Handle {
+                data: format!("{}\n", format_address(&address)).into_bytes(),
+                seek: 0,
+                root: false,
+                uid: uid
+            }
+        };
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.handles.insert(id, handle);
+        Ok(id)
+    }
+
+    fn read(&mut self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let handle = self.handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+
+        let mut i = 0;
+        while i < buf.len() && handle.seek < handle.data.len() {
+            buf[i] = handle.data[handle.seek];
+            i += 1;
+            handle.seek += 1;
+        }
+
+        Ok(i)
+    }
+
+    /// Writing `flush` to `dns:` empties the cache
+    fn write(&mut self, id: usize, buf: &[u8]) -> Result<usize> {
+        let (root, uid) = {
+            let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+            (handle.root, handle.uid)
+        };
+
+        if ! root {
+            return Err(Error::new(EBADF));
+        }
+        if uid != 0 {
+            return Err(Error::new(EACCES));
+        }
+
+        let command = if buf.ends_with(b"\n") {
+            &buf[..buf.len() - 1]
+        } else {
+            buf
+        };
+
+        if command == b"flush" {
+            self.cache.clear();
+            Ok(buf.len())
+        } else {
+            Err(Error::new(EINVAL))
+        }
+    }
+
+    fn seek(&mut self, id: usize, pos: usize, whence: usize) -> Result<usize> {
+        let handle = self.handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+
+        let len = handle.data.len();
+        handle.seek = match whence {
+            SEEK_SET => cmp::min(len, pos),
+            SEEK_CUR => cmp::max(0, cmp::min(len as isize, handle.seek as isize + pos as isize)) as usize,
+            SEEK_END => cmp::max(0, cmp::min(len as isize, len as isize + pos as isize)) as usize,
+            _ => return Err(Error::new(EINVAL))
+        };
+
+        Ok(handle.seek)
+    }
+
+    fn fstat(&mut self, id: usize, stat: &mut Stat) -> Result<usize> {
+        let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+
+        stat.st_mode = MODE_FILE | if handle.root { 0o644 } else { 0o444 };
+        stat.st_size = handle.data.len() as u64;
+
+        Ok(0)
+    }
+
+    fn close(&mut self, id: usize) -> Result<usize> {
+        self.handles.remove(&id).ok_or(Error::new(EBADF)).and(Ok(0))
+    }
+}
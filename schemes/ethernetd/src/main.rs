@@ -13,8 +13,42 @@ use syscall::{Packet, SchemeMut, EWOULDBLOCK};
 
 use scheme::EthernetScheme;
 
+mod netcap;
 mod scheme;
 
+/// Answer reads of `netcap:` that were waiting for frames, and notify readers that have data
+fn netcap_events(scheme: &mut EthernetScheme, socket: &mut File, todo: &mut Vec<Packet>) -> Result<()> {
+    let mut i = 0;
+    while i < todo.len() {
+        let a = todo[i].a;
+        scheme.netcap.handle(&mut todo[i]);
+        if todo[i].a == (-EWOULDBLOCK) as usize {
+            todo[i].a = a;
+            i += 1;
+        } else {
+            socket.write(&mut todo[i])?;
+            todo.remove(i);
+        }
+    }
+
+    for (id, capture) in scheme.netcap.handles.iter() {
+        if ! capture.is_empty() {
+            socket.write(&Packet {
+                id: 0,
+                pid: 0,
+                uid: 0,
+                gid: 0,
+                a: syscall::number::SYS_FEVENT,
+                b: *id,
+                c: syscall::flag::EVENT_READ,
+                d: capture.len()
+            })?;
+        }
+    }
+
+    Ok(())
+}
+
 fn main() {
     // Daemonize
     if unsafe { syscall::clone(0).unwrap() } == 0 {
@@ -24,15 +58,21 @@ fn main() {
         let socket_fd = syscall::open(":ethernet", syscall::O_RDWR | syscall::O_CREAT | syscall::O_NONBLOCK).expect("ethernetd: failed to create ethernet scheme");
         let socket = Rc::new(RefCell::new(unsafe { File::from_raw_fd(socket_fd) }));
 
+        let netcap_fd = syscall::open(":netcap", syscall::O_RDWR | syscall::O_CREAT | syscall::O_NONBLOCK).expect("ethernetd: failed to create netcap scheme");
+        let netcap = Rc::new(RefCell::new(unsafe { File::from_raw_fd(netcap_fd) }));
+
         let scheme = Rc::new(RefCell::new(EthernetScheme::new(network)));
 
         let todo = Rc::new(RefCell::new(Vec::<Packet>::new()));
+        let netcap_todo = Rc::new(RefCell::new(Vec::<Packet>::new()));
 
         let mut event_queue = EventQueue::<()>::new().expect("ethernetd: failed to create event queue");
 
         let socket_net = socket.clone();
         let scheme_net = scheme.clone();
         let todo_net = todo.clone();
+        let netcap_net = netcap.clone();
+        let netcap_todo_net = netcap_todo.clone();
         event_queue.add(network_fd, move |_count: usize| -> Result<Option<()>> {
             if scheme_net.borrow_mut().input()? > 0 {
                 let mut todo = todo_net.borrow_mut();
@@ -63,10 +103,15 @@ fn main() {
                         })?;
                     }
                 }
+
+                netcap_events(&mut scheme_net.borrow_mut(), &mut netcap_net.borrow_mut(), &mut netcap_todo_net.borrow_mut())?;
             }
             Ok(None)
         }).expect("ethernetd: failed to listen for network events");
 
+        let scheme_socket = scheme.clone();
+        let netcap_socket = netcap.clone();
+        let netcap_todo_socket = netcap_todo.clone();
         event_queue.add(socket_fd, move |_count: usize| -> Result<Option<()>> {
             loop {
                 let mut packet = Packet::default();
@@ -75,7 +120,7 @@ fn main() {
                 }
 
                 let a = packet.a;
-                scheme.borrow_mut().handle(&mut packet);
+                scheme_socket.borrow_mut().handle(&mut packet);
                 if packet.a == (-EWOULDBLOCK) as usize {
                     packet.a = a;
                     todo.borrow_mut().push(packet);
@@ -84,9 +129,32 @@ fn main() {
                 }
             }
 
+            // Frames that were sent are captured
+            netcap_events(&mut scheme_socket.borrow_mut(), &mut netcap_socket.borrow_mut(), &mut netcap_todo_socket.borrow_mut())?;
+
             Ok(None)
         }).expect("ethernetd: failed to listen for scheme events");
 
+        event_queue.add(netcap_fd, move |_count: usize| -> Result<Option<()>> {
+            loop {
+                let mut packet = Packet::default();
+                if netcap.borrow_mut().read(&mut packet)? == 0 {
+                    break;
+                }
+
+                let a = packet.a;
+                scheme.borrow_mut().netcap.handle(&mut packet);
+                if packet.a == (-EWOULDBLOCK) as usize {
+                    packet.a = a;
+                    netcap_todo.borrow_mut().push(packet);
+                } else {
+                    netcap.borrow_mut().write(&mut packet)?;
+                }
+            }
+
+            Ok(None)
+        }).expect("ethernetd: failed to listen for netcap events");
+
         event_queue.trigger_all(0).expect("ethernetd: failed to trigger events");
 
         event_queue.run().expect("ethernetd: failed to run event loop");
@@ -0,0 +1,185 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::cmp;
+
+use syscall;
+use syscall::data::TimeSpec;
+use syscall::error::{Error, Result, EACCES, EBADF, ENOENT, EWOULDBLOCK};
+use syscall::flag::{CLOCK_REALTIME, O_NONBLOCK};
+use syscall::scheme::SchemeMut;
+
+/// Captured data kept for a reader that has not read it yet. Frames are dropped when it is full
+const CAPTURE_MAX: usize = 4 * 1024 * 1024;
+
+/// Longest frame that is captured, as the snapshot length in the pcap header
+const SNAPLEN: u32 = 65535;
+
+/// Link type of Linux cooked captures, which record the direction of each frame
+const LINKTYPE_LINUX_SLL: u32 = 113;
+
+/// Direction of a frame, as the packet type in a Linux cooked header
+const SLL_HOST: u16 = 0;
+const SLL_OUTGOING: u16 = 4;
+
+/// ARP hardware type of ethernet
+const ARPHRD_ETHER: u16 = 1;
+
+pub struct Capture {
+    flags: usize,
+    /// pcap data waiting to be read, starting with the file header
+    data: VecDeque<u8>,
+    /// Frames that did not fit in `data`
+    dropped: usize
+}
+
+/// Mirrors the frames ethernetd receives and sends to readers of `netcap:`, as a pcap file
+pub struct NetcapScheme {
+    next_id: usize,
+    pub handles: BTreeMap<usize, Capture>
+}
+
+fn push_u16(data: &mut VecDeque<u8>, value: u16) {
+    data.push_back(value as u8);
+    data.push_back((value >> 8) as u8);
+}
+
+fn push_u32(data: &mut VecDeque<u8>, value: u32) {
+    push_u16(data, value as u16);
+    push_u16(data, (value >> 16) as u16);
+}
+
+impl Capture {
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+}
+
+impl NetcapScheme {
+    pub fn new() -> NetcapScheme {
+        NetcapScheme {
+            next_id: 1,
+            handles: BTreeMap::new()
+        }
+    }
+
+    /// Record a frame, `outgoing` if it was sent rather than received
+    pub fn capture(&mut self, frame: &[u8], outgoing: bool) {
+        if self.handles.is_empty() || frame.len() < 14 {
+            return;
+        }
+
+        let mut time = TimeSpec::default();
+        let _ = syscall::clock_gettime(CLOCK_REALTIME, &mut time);
+
+        // The ethernet header is replaced by the 16 byte cooked header
+        let len = cmp::min(frame.len(), SNAPLEN as usize - 2);
+        for (_id, capture) in self.handles.iter_mut() {
+            if capture.data.len() + 32 + len > CAPTURE_MAX {
+                capture.dropped += 1;
+                continue;
+            }
+
+            // Record header
+            push_u32(&mut capture.data, time.tv_sec as u32);
+            push_u32(&mut capture.data, (time.tv_nsec / 1000) as u32);
+            push_u32(&mut capture.data, (len + 2) as u32);
+            push_u32(&mut capture.data, (frame.len() + 2) as u32);
+
+            // Linux cooked header, in network byte order, with the source address
+            let packet_type = if outgoing { SLL_OUTGOING } else { SLL_HOST };
+            capture.data.extend(&[(packet_type >> 8) as u8, packet_type as u8]);
+            capture.data.extend(&[(ARPHRD_ETHER >> 8) as u8, ARPHRD_ETHER as u8]);
+            capture.data.extend(&[0, 6]);
+            capture.data.extend(&frame[6 .. 12]);
+            capture.data.extend(&[0, 0]);
+            capture.data.extend(&frame[12 .. 14]);
+
+            capture.data.extend(&frame[14 .. len]);
+        }
+    }
+}
+
+impl SchemeMut for NetcapScheme {
+    fn open(&mut self, url: &[u8], flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        if uid != 0 {
+            return Err(Error::new(EACCES));
+        }
+
+        // There is one interface, which may be named
+        if ! url.is_empty() && url != b"network" {
+            return Err(Error::new(ENOENT));
+        }
+
+        let mut data = VecDeque::new();
+        push_u32(&mut data, 0xa1b2c3d4);
+        push_u16(&mut data, 2);
+        push_u16(&mut data, 4);
+        push_u32(&mut data, 0);
+        push_u32(&mut data, 0);
+        push_u32(&mut data, SNAPLEN);
+        push_u32(&mut data, LINKTYPE_LINUX_SLL);
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.handles.insert(id, Capture {
+            flags: flags,
+            data: data,
+            dropped: 0
+        });
+
+        Ok(id)
+    }
+
+    fn read(&mut self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let capture = self.handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+
+        if ! capture.data.is_empty() {
+            let mut i = 0;
+            while i < buf.len() {
+                match capture.data.pop_front() {
+                    Some(b) => buf[i] = b,
+                    None => break
+                }
+                i += 1;
+            }
+            Ok(i)
+        } else if capture.flags & O_NONBLOCK == O_NONBLOCK {
+            Ok(0)
+        } else {
+            Err(Error::new(EWOULDBLOCK))
+        }
+    }
+
+    fn fevent(&mut self, id: usize, _flags: usize) -> Result<usize> {
+        let _capture = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+
+        Ok(id)
+    }
+
+    fn fpath(&mut self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let _capture = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+
+        let path = b"netcap:network";
+
+        let mut i = 0;
+        while i < buf.len() && i < path.len() {
+            buf[i] = path[i];
+            i += 1;
+        }
+
+        Ok(i)
+    }
+
+    fn close(&mut self, id: usize) -> Result<usize> {
+        let capture = self.handles.remove(&id).ok_or(Error::new(EBADF))?;
+
+        if capture.dropped > 0 {
+            println!("netcap: dropped {} frames", capture.dropped);
+        }
+
+        Ok(0)
+    }
+}
@@ -1,15 +1,17 @@
 use std::collections::{BTreeMap, VecDeque};
 use std::fs::File;
 use std::io::{self, Read, Write};
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::{cmp, str, u16};
 
 use netutils::{getcfg, MacAddr, EthernetII};
 use syscall;
-use syscall::error::{Error, Result, EACCES, EBADF, EINVAL, EIO, EWOULDBLOCK};
-use syscall::flag::O_NONBLOCK;
+use syscall::error::{Error, Result, EACCES, EBADF, EINVAL, EIO, EMSGSIZE, EWOULDBLOCK};
+use syscall::flag::{O_NONBLOCK, O_RDWR};
 use syscall::scheme::SchemeMut;
 
+use netcap::NetcapScheme;
+
 #[derive(Clone)]
 pub struct Handle {
     /// The flags this handle was opened with
@@ -18,22 +20,35 @@ pub struct Handle {
     pub host_addr: MacAddr,
     /// The ethernet type
     pub ethertype: u16,
+    /// Raw handles write frames as they are, and read frames of every type if `ethertype` is 0
+    pub raw: bool,
+    /// Largest payload the network device sends, if the driver has set it
+    mtu: Option<usize>,
+    /// Frames are written to the device to have their checksums inserted and to be split into
+    /// segments, as set in /etc/net/offload
+    offload: bool,
     /// The data
     pub frames: VecDeque<EthernetII>,
 }
 
 pub struct EthernetScheme {
     network: File,
+    /// The device opened with `offload`, when there are offload handles
+    network_offload: Option<File>,
     next_id: usize,
-    pub handles: BTreeMap<usize, Handle>
+    pub handles: BTreeMap<usize, Handle>,
+    /// Readers of the frames that are received and sent
+    pub netcap: NetcapScheme
 }
 
 impl EthernetScheme {
     pub fn new(network: File) -> EthernetScheme {
         EthernetScheme {
             network: network,
+            network_offload: None,
             next_id: 1,
             handles: BTreeMap::new(),
+            netcap: NetcapScheme::new()
         }
     }
 
@@ -47,9 +62,10 @@ impl EthernetScheme {
             if count == 0 {
                 break;
             }
+            self.netcap.capture(&bytes[.. count], false);
             if let Some(frame) = EthernetII::from_bytes(&bytes[.. count]) {
                 for (_id, handle) in self.handles.iter_mut() {
-                    if frame.header.ethertype.get() == handle.ethertype {
+                    if frame.header.ethertype.get() == handle.ethertype || (handle.raw && handle.ethertype == 0) {
                         handle.frames.push_back(frame.clone());
                     }
                 }
@@ -61,11 +77,34 @@ impl EthernetScheme {
 }
 
 impl SchemeMut for EthernetScheme {
+    /// Open `ethernet:TYPE` for frames of an ethernet type, in hex, or `ethernet:raw` and
+    /// `ethernet:raw/TYPE` to send whole frames, for userspace network stacks and diagnostics.
+    /// Frames written to `ethernet:offload/TYPE` are sent with the offloads of the device
     fn open(&mut self, url: &[u8], flags: usize, uid: u32, _gid: u32) -> Result<usize> {
         if uid == 0 {
             let mac_addr = MacAddr::from_str(&getcfg("mac").map_err(|err| Error::new(err.raw_os_error().unwrap_or(EIO)))?);
+            let mtu = getcfg("mtu").ok().and_then(|mtu| mtu.trim().parse::<usize>().ok());
             let path = try!(str::from_utf8(url).or(Err(Error::new(EINVAL))));
 
+            let (raw, path) = if path == "raw" {
+                (true, "")
+            } else if path.starts_with("raw/") {
+                (true, &path[4..])
+            } else {
+                (false, path)
+            };
+
+            let (offload, path) = if path.starts_with("offload/") {
+                (true, &path[8..])
+            } else {
+                (false, path)
+            };
+
+            if offload && self.network_offload.is_none() {
+                let fd = syscall::open("network:offload", O_RDWR | O_NONBLOCK)?;
+                self.network_offload = Some(unsafe { File::from_raw_fd(fd) });
+            }
+
             let ethertype = u16::from_str_radix(path, 16).unwrap_or(0);
 
             let next_id = self.next_id;
@@ -75,6 +114,9 @@ impl SchemeMut for EthernetScheme {
                 flags: flags,
                 host_addr: mac_addr,
                 ethertype: ethertype,
+                raw: raw,
+                mtu: mtu,
+                offload: offload,
                 frames: VecDeque::new()
             });
 
@@ -118,10 +160,30 @@ impl SchemeMut for EthernetScheme {
     fn write(&mut self, id: usize, buf: &[u8]) -> Result<usize> {
         let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
 
-        if let Some(mut frame) = EthernetII::from_bytes(buf) {
+        // The device splits offloaded frames that are larger
+        if let Some(mtu) = handle.mtu {
+            if buf.len() > mtu + 14 && ! handle.offload {
+                return Err(Error::new(EMSGSIZE));
+            }
+        }
+
+        if handle.raw {
+            if EthernetII::from_bytes(buf).is_some() {
+                self.netcap.capture(buf, true);
+                self.network.write(buf).map_err(|err| Error::new(err.raw_os_error().unwrap_or(EIO)))
+            } else {
+                Err(Error::new(EINVAL))
+            }
+        } else if let Some(mut frame) = EthernetII::from_bytes(buf) {
             frame.header.src = handle.host_addr;
             frame.header.ethertype.set(handle.ethertype);
-            self.network.write(&frame.to_bytes()).map_err(|err| Error::new(err.raw_os_error().unwrap_or(EIO)))
+            let data = frame.to_bytes();
+            self.netcap.capture(&data, true);
+            let network = match self.network_offload {
+                Some(ref mut network_offload) if handle.offload => network_offload,
+                _ => &mut self.network
+            };
+            network.write(&data).map_err(|err| Error::new(err.raw_os_error().unwrap_or(EIO)))
         } else {
             Err(Error::new(EINVAL))
         }
@@ -136,7 +198,15 @@ impl SchemeMut for EthernetScheme {
     fn fpath(&mut self, id: usize, buf: &mut [u8]) -> Result<usize> {
         let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
 
-        let path_string = format!("ethernet:{:X}", handle.ethertype);
+        let path_string = if handle.offload {
+            format!("ethernet:offload/{:X}", handle.ethertype)
+        } else if ! handle.raw {
+            format!("ethernet:{:X}", handle.ethertype)
+        } else if handle.ethertype != 0 {
+            format!("ethernet:raw/{:X}", handle.ethertype)
+        } else {
+            "ethernet:raw".to_string()
+        };
         let path = path_string.as_bytes();
 
         let mut i = 0;
@@ -0,0 +1,171 @@
+//! IPv6 addresses and headers, and the ICMPv6 checksum
+
+use std::fmt;
+
+/// Length of the fixed IPv6 header
+pub const HEADER_LEN: usize = 40;
+
+/// Next header value of ICMPv6
+pub const NEXT_ICMPV6: u8 = 58;
+
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct Ipv6Addr {
+    pub bytes: [u8; 16]
+}
+
+/// All routers on the link, ff02::2
+pub const ALL_ROUTERS: Ipv6Addr = Ipv6Addr { bytes: [0xff, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2] };
+
+impl Ipv6Addr {
+    pub fn from_slice(data: &[u8]) -> Ipv6Addr {
+        let mut bytes = [0; 16];
+        bytes.copy_from_slice(&data[.. 16]);
+        Ipv6Addr { bytes: bytes }
+    }
+
+    /// An address from a 64 bit prefix and an interface identifier made from a MAC address
+    pub fn from_prefix_mac(prefix: &[u8], mac: &[u8; 6]) -> Ipv6Addr {
+        let mut bytes = [0; 16];
+        bytes[.. 8].copy_from_slice(&prefix[.. 8]);
+        bytes[8] = mac[0] ^ 2;
+        bytes[9] = mac[1];
+        bytes[10] = mac[2];
+        bytes[11] = 0xff;
+        bytes[12] = 0xfe;
+        bytes[13] = mac[3];
+        bytes[14] = mac[4];
+        bytes[15] = mac[5];
+        Ipv6Addr { bytes: bytes }
+    }
+
+    /// The link-local address of an interface, in fe80::/64
+    pub fn link_local(mac: &[u8; 6]) -> Ipv6Addr {
+        Ipv6Addr::from_prefix_mac(&[0xfe, 0x80, 0, 0, 0, 0, 0, 0], mac)
+    }
+
+    /// The solicited-node multicast address, which neighbor solicitations for this address are sent to
+    pub fn solicited_node(&self) -> Ipv6Addr {
+        let mut bytes = [0xff, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0xff, 0, 0, 0];
+        bytes[13] = self.bytes[13];
+        bytes[14] = self.bytes[14];
+        bytes[15] = self.bytes[15];
+        Ipv6Addr { bytes: bytes }
+    }
+
+    pub fn is_multicast(&self) -> bool {
+        self.bytes[0] == 0xff
+    }
+
+    /// The ethernet address that a multicast address is sent to
+    pub fn multicast_mac(&self) -> [u8; 6] {
+        [0x33, 0x33, self.bytes[12], self.bytes[13], self.bytes[14], self.bytes[15]]
+    }
+
+    fn group(&self, i: usize) -> u16 {
+        (self.bytes[i * 2] as u16) << 8 | self.bytes[i * 2 + 1] as u16
+    }
+}
+
+/// Formats the address with the longest run of zero groups shortened to `::`
+impl fmt::Display for Ipv6Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut best = (0, 0);
+        let mut i = 0;
+        while i < 8 {
+            let start = i;
+            while i < 8 && self.group(i) == 0 {
+                i += 1;
+            }
+            if i - start > best.1 - best.0 {
+                best = (start, i);
+            }
+            i += 1;
+        }
+
+        if best.1 - best.0 < 2 {
+            best = (8, 8);
+        }
+
+        for i in 0..8 {
+            if i == best.0 {
+                write!(f, "::")?;
+            } else if i > best.0 && i < best.1 {
+                continue;
+            } else {
+                if i > 0 && i != best.1 {
+                    write!(f, ":")?;
+                }
+                write!(f, "{:x}", self.group(i))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A packet, with the fields of the fixed header that are used
+pub struct Ipv6 {
+    pub next_header: u8,
+    pub hop_limit: u8,
+    pub src: Ipv6Addr,
+    pub dst: Ipv6Addr,
+    pub data: Vec<u8>
+}
+
+impl Ipv6 {
+    pub fn from_bytes(bytes: &[u8]) -> Option<Ipv6> {
+        if bytes.len() < HEADER_LEN || bytes[0] >> 4 != 6 {
+            return None;
+        }
+
+        let len = (bytes[4] as usize) << 8 | bytes[5] as usize;
+        if HEADER_LEN + len > bytes.len() {
+            return None;
+        }
+
+        Some(Ipv6 {
+            next_header: bytes[6],
+            hop_limit: bytes[7],
+            src: Ipv6Addr::from_slice(&bytes[8 .. 24]),
+            dst: Ipv6Addr::from_slice(&bytes[24 .. 40]),
+            data: bytes[HEADER_LEN .. HEADER_LEN + len].to_vec()
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0x60, 0, 0, 0,
+                             (self.data.len() >> 8) as u8, self.data.len() as u8,
+                             self.next_header, self.hop_limit];
+        bytes.extend_from_slice(&self.src.bytes);
+        bytes.extend_from_slice(&self.dst.bytes);
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+}
+
+/// The checksum of an upper layer message, over the pseudo-header and the message with its
+/// checksum field zeroed
+pub fn checksum(src: &Ipv6Addr, dst: &Ipv6Addr, next_header: u8, data: &[u8]) -> u16 {
+    let mut sum = 0u32;
+
+    {
+        let mut add = |bytes: &[u8]| {
+            for chunk in bytes.chunks(2) {
+                let high = chunk[0] as u32;
+                let low = chunk.get(1).map_or(0, |&b| b as u32);
+                sum += high << 8 | low;
+            }
+        };
+
+        add(&src.bytes);
+        add(&dst.bytes);
+        add(&[(data.len() >> 24) as u8, (data.len() >> 16) as u8, (data.len() >> 8) as u8, data.len() as u8]);
+        add(&[0, 0, 0, next_header]);
+        add(data);
+    }
+
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
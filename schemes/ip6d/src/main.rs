@@ -0,0 +1,381 @@
+//! IPv6 on the ethernet interface, at `ip6:`
+//!
+//! Answers neighbor solicitations and echo requests, and configures addresses from router
+//! advertisements (SLAAC). Reading `ip6:` lists the addresses, the router and the neighbors.
+//! There is no transport yet, so `tcp:` and `udp:` remain IPv4 only
+
+extern crate event;
+extern crate netutils;
+extern crate syscall;
+
+use event::EventQueue;
+use netutils::getcfg;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::FromRawFd;
+use std::rc::Rc;
+use std::{cmp, u64};
+
+use syscall::data::{Packet, TimeSpec};
+use syscall::error::{Error, Result, EBADF, ENOENT};
+use syscall::flag::{CLOCK_MONOTONIC, O_CREAT, O_NONBLOCK, O_RDWR};
+use syscall::scheme::SchemeMut;
+
+use ipv6::{Ipv6, Ipv6Addr, ALL_ROUTERS, NEXT_ICMPV6};
+
+mod ipv6;
+
+/// ICMPv6 message types
+const ECHO_REQUEST: u8 = 128;
+const ECHO_REPLY: u8 = 129;
+const ROUTER_SOLICITATION: u8 = 133;
+const ROUTER_ADVERTISEMENT: u8 = 134;
+const NEIGHBOR_SOLICITATION: u8 = 135;
+const NEIGHBOR_ADVERTISEMENT: u8 = 136;
+
+/// Neighbor discovery options
+const OPTION_SOURCE_MAC: u8 = 1;
+const OPTION_TARGET_MAC: u8 = 2;
+const OPTION_PREFIX: u8 = 3;
+
+/// All nodes on the link, ff02::1
+const ALL_NODES: Ipv6Addr = Ipv6Addr { bytes: [0xff, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1] };
+
+struct Address {
+    address: Ipv6Addr,
+    /// Monotonic time at which the address stops being valid, in seconds
+    expires: u64
+}
+
+struct Ip6d {
+    scheme_file: File,
+    network: File,
+    mac: [u8; 6],
+    addresses: Vec<Address>,
+    router: Option<Ipv6Addr>,
+    neighbors: BTreeMap<Ipv6Addr, [u8; 6]>,
+    next_id: usize,
+    handles: BTreeMap<usize, Vec<u8>>
+}
+
+fn monotonic() -> u64 {
+    let mut time = TimeSpec::default();
+    let _ = syscall::clock_gettime(CLOCK_MONOTONIC, &mut time);
+    time.tv_sec as u64
+}
+
+fn format_mac(mac: &[u8; 6]) -> String {
+    format!("{:>02x}:{:>02x}:{:>02x}:{:>02x}:{:>02x}:{:>02x}", mac[0], mac[1], mac[2], mac[3], mac[4], mac[5])
+}
+
+/// Find a link-layer address option
+fn option_mac(options: &[u8], kind: u8) -> Option<[u8; 6]> {
+    let mut i = 0;
+    while i + 2 <= options.len() {
+        let len = options[i + 1] as usize * 8;
+        if len == 0 || i + len > options.len() {
+            break;
+        }
+        if options[i] == kind && len >= 8 {
+            let mut mac = [0; 6];
+            mac.copy_from_slice(&options[i + 2 .. i + 8]);
+            return Some(mac);
+        }
+        i += len;
+    }
+    None
+}
+
+impl Ip6d {
+    fn new(scheme_file: File, network: File, mac: [u8; 6]) -> Ip6d {
+        Ip6d {
+            scheme_file: scheme_file,
+            network: network,
+            mac: mac,
+            addresses: vec![Address {
+                address: Ipv6Addr::link_local(&mac),
+                expires: u64::MAX
+            }],
+            router: None,
+            neighbors: BTreeMap::new(),
+            next_id: 0,
+            handles: BTreeMap::new()
+        }
+    }
+
+    fn link_local(&self) -> Ipv6Addr {
+        self.addresses[0].address
+    }
+
+    fn is_local(&self, address: &Ipv6Addr) -> bool {
+        self.addresses.iter().any(|entry| entry.address == *address)
+    }
+
+    /// Returns true if packets to the address are for this interface
+    fn accepts(&self, address: &Ipv6Addr) -> bool {
+        *address == ALL_NODES || self.addresses.iter().any(|entry| {
+            entry.address == *address || entry.address.solicited_node() == *address
+        })
+    }
+
+    /// Send an ICMPv6 message, filling in its checksum
+    fn send_icmp(&mut self, dst_mac: [u8; 6], src: Ipv6Addr, dst: Ipv6Addr, mut message: Vec<u8>) -> io::Result<()> {
+        message[2] = 0;
+        message[3] = 0;
+        let checksum = ipv6::checksum(&src, &dst, NEXT_ICMPV6, &message);
+        message[2] = (checksum >> 8) as u8;
+        message[3] = checksum as u8;
+
+        let packet = Ipv6 {
+            next_header: NEXT_ICMPV6,
+            // Neighbor discovery messages must not have been forwarded, which receivers check with this
+            hop_limit: 255,
+            src: src,
+            dst: dst,
+            data: message
+        };
+
+        let mut frame = Vec::with_capacity(14 + ipv6::HEADER_LEN + packet.data.len());
+        frame.extend_from_slice(&dst_mac);
+        frame.extend_from_slice(&self.mac);
+        frame.extend_from_slice(&[0x86, 0xDD]);
+        frame.extend_from_slice(&packet.to_bytes());
+        self.network.write(&frame).and(Ok(()))
+    }
+
+    /// Ask routers to advertise, so that an address can be configured without waiting
+    fn solicit_router(&mut self) -> io::Result<()> {
+        let mut message = vec![ROUTER_SOLICITATION, 0, 0, 0, 0, 0, 0, 0, OPTION_SOURCE_MAC, 1];
+        message.extend_from_slice(&self.mac);
+
+        let src = self.link_local();
+        self.send_icmp(ALL_ROUTERS.multicast_mac(), src, ALL_ROUTERS, message)
+    }
+
+    fn icmp(&mut self, packet: &Ipv6, src_mac: [u8; 6]) -> io::Result<()> {
+        let message = &packet.data;
+        if message.len() < 8 || ipv6::checksum(&packet.src, &packet.dst, NEXT_ICMPV6, message) != 0 {
+            return Ok(());
+        }
+
+        match message[0] {
+            ECHO_REQUEST => {
+                let src = if packet.dst.is_multicast() {
+                    self.link_local()
+                } else {
+                    packet.dst
+                };
+
+                let mut reply = message.clone();
+                reply[0] = ECHO_REPLY;
+                self.send_icmp(src_mac, src, packet.src, reply)?;
+            },
+            NEIGHBOR_SOLICITATION => if message.len() >= 24 && packet.hop_limit == 255 {
+                let target = Ipv6Addr::from_slice(&message[8 .. 24]);
+                if let Some(mac) = option_mac(&message[24 ..], OPTION_SOURCE_MAC) {
+                    self.neighbors.insert(packet.src, mac);
+                }
+
+                if self.is_local(&target) {
+                    // Solicitations from an unspecified address are duplicate address detection,
+                    // and are answered to all nodes
+                    let (dst, dst_mac, flags) = if packet.src == Ipv6Addr::from_slice(&[0; 16]) {
+                        (ALL_NODES, ALL_NODES.multicast_mac(), 0x20)
+                    } else {
+                        (packet.src, src_mac, 0x60)
+                    };
+
+                    let mut advertisement = vec![NEIGHBOR_ADVERTISEMENT, 0, 0, 0, flags, 0, 0, 0];
+                    advertisement.extend_from_slice(&target.bytes);
+                    advertisement.extend_from_slice(&[OPTION_TARGET_MAC, 1]);
+                    advertisement.extend_from_slice(&self.mac);
+                    self.send_icmp(dst_mac, target, dst, advertisement)?;
+                }
+            },
+            NEIGHBOR_ADVERTISEMENT => if message.len() >= 24 && packet.hop_limit == 255 {
+                let target = Ipv6Addr::from_slice(&message[8 .. 24]);
+                if let Some(mac) = option_mac(&message[24 ..], OPTION_TARGET_MAC) {
+                    self.neighbors.insert(target, mac);
+                }
+            },
+            ROUTER_ADVERTISEMENT => if message.len() >= 16 && packet.hop_limit == 255 {
+                let lifetime = (message[6] as u16) << 8 | message[7] as u16;
+                self.router = if lifetime > 0 {
+                    Some(packet.src)
+                } else {
+                    None
+                };
+
+                let options = &message[16 ..];
+                if let Some(mac) = option_mac(options, OPTION_SOURCE_MAC) {
+                    self.neighbors.insert(packet.src, mac);
+                }
+
+                let mut i = 0;
+                while i + 2 <= options.len() {
+                    let len = options[i + 1] as usize * 8;
+                    if len == 0 || i + len > options.len() {
+                        break;
+                    }
+                    if options[i] == OPTION_PREFIX && len == 32 {
+                        self.prefix(&options[i .. i + len]);
+                    }
+                    i += len;
+                }
+            },
+            _ => ()
+        }
+
+        Ok(())
+    }
+
+    /// Configure an address from a prefix information option, if it allows it
+    fn prefix(&mut self, option: &[u8]) {
+        let prefix_len = option[2];
+        let autonomous = option[3] & 0x40 == 0x40;
+        let valid = (option[4] as u32) << 24 | (option[5] as u32) << 16 | (option[6] as u32) << 8 | option[7] as u32;
+        if ! autonomous || prefix_len != 64 || option[16] == 0xfe && option[17] & 0xc0 == 0x80 {
+            return;
+        }
+
+        let address = Ipv6Addr::from_prefix_mac(&option[16 .. 24], &self.mac);
+        let expires = if valid == 0xFFFFFFFF {
+            u64::MAX
+        } else {
+            monotonic() + valid as u64
+        };
+
+        self.addresses.retain(|entry| entry.address != address);
+        if valid > 0 {
+            println!("ip6: {}", address);
+            self.addresses.push(Address {
+                address: address,
+                expires: expires
+            });
+        }
+    }
+
+    fn network_event(&mut self) -> io::Result<()> {
+        loop {
+            let mut bytes = [0; 65536];
+            let count = self.network.read(&mut bytes)?;
+            if count == 0 {
+                break;
+            }
+            if count < 14 {
+                continue;
+            }
+
+            let now = monotonic();
+            self.addresses.retain(|entry| entry.expires > now);
+
+            let mut src_mac = [0; 6];
+            src_mac.copy_from_slice(&bytes[6 .. 12]);
+            if let Some(packet) = Ipv6::from_bytes(&bytes[14 .. count]) {
+                if self.accepts(&packet.dst) && packet.next_header == NEXT_ICMPV6 {
+                    self.icmp(&packet, src_mac)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn info_string(&self) -> String {
+        let now = monotonic();
+
+        let mut string = format!("mac: {}\n", format_mac(&self.mac));
+        for entry in self.addresses.iter() {
+            if entry.expires == u64::MAX {
+                string.push_str(&format!("address: {}\n", entry.address));
+            } else {
+                string.push_str(&format!("address: {} valid {}s\n", entry.address, entry.expires.saturating_sub(now)));
+            }
+        }
+        if let Some(router) = self.router {
+            string.push_str(&format!("router: {}\n", router));
+        }
+        for (address, mac) in self.neighbors.iter() {
+            string.push_str(&format!("neighbor: {} {}\n", address, format_mac(mac)));
+        }
+        string
+    }
+}
+
+impl SchemeMut for Ip6d {
+    fn open(&mut self, url: &[u8], _flags: usize, _uid: u32, _gid: u32) -> Result<usize> {
+        if ! url.is_empty() {
+            return Err(Error::new(ENOENT));
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let data = self.info_string().into_bytes();
+        self.handles.insert(id, data);
+
+        Ok(id)
+    }
+
+    fn read(&mut self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let data = self.handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+
+        let count = cmp::min(buf.len(), data.len());
+        buf[.. count].copy_from_slice(&data[.. count]);
+        data.drain(.. count);
+        Ok(count)
+    }
+
+    fn close(&mut self, id: usize) -> Result<usize> {
+        self.handles.remove(&id).ok_or(Error::new(EBADF)).and(Ok(0))
+    }
+}
+
+fn main() {
+    let mac_string = getcfg("mac").expect("ip6d: failed to read mac");
+    let mut mac = [0; 6];
+    for (b, part) in mac.iter_mut().zip(mac_string.trim().split('.')) {
+        *b = u8::from_str_radix(part, 16).unwrap_or(0);
+    }
+
+    // Daemonize
+    if unsafe { syscall::clone(0).unwrap() } == 0 {
+        let scheme_fd = syscall::open(":ip6", O_RDWR | O_CREAT | O_NONBLOCK).expect("ip6d: failed to create :ip6");
+        let scheme_file = unsafe { File::from_raw_fd(scheme_fd) };
+
+        let network_fd = syscall::open("ethernet:86DD", O_RDWR | O_NONBLOCK).expect("ip6d: failed to open ethernet:86DD");
+        let network = unsafe { File::from_raw_fd(network_fd) };
+
+        let ip6d = Rc::new(RefCell::new(Ip6d::new(scheme_file, network, mac)));
+
+        println!("ip6: {}", ip6d.borrow().link_local());
+        ip6d.borrow_mut().solicit_router().expect("ip6d: failed to solicit router");
+
+        let mut event_queue = EventQueue::<()>::new().expect("ip6d: failed to create event queue");
+
+        let network_ip6d = ip6d.clone();
+        event_queue.add(network_fd, move |_count: usize| -> io::Result<Option<()>> {
+            network_ip6d.borrow_mut().network_event()?;
+            Ok(None)
+        }).expect("ip6d: failed to listen to events on ethernet:86DD");
+
+        event_queue.add(scheme_fd, move |_count: usize| -> io::Result<Option<()>> {
+            let mut ip6d = ip6d.borrow_mut();
+            loop {
+                let mut packet = Packet::default();
+                if ip6d.scheme_file.read(&mut packet)? == 0 {
+                    break;
+                }
+                ip6d.handle(&mut packet);
+                ip6d.scheme_file.write(&packet)?;
+            }
+            Ok(None)
+        }).expect("ip6d: failed to listen to events on :ip6");
+
+        event_queue.trigger_all(0).expect("ip6d: failed to trigger event queue");
+
+        event_queue.run().expect("ip6d: failed to run event queue");
+    }
+}
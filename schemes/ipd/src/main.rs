@@ -3,19 +3,141 @@ extern crate netutils;
 extern crate syscall;
 
 use event::EventQueue;
-use netutils::{getcfg, n16, Ipv4Addr, MacAddr, Ipv4, EthernetII, EthernetIIHeader, Arp, Tcp};
+use netutils::{getcfg, n16, Ipv4Addr, MacAddr, Ipv4, EthernetII, EthernetIIHeader, Arp, Tcp, TCP_FIN, TCP_PSH};
 use std::cell::RefCell;
 use std::collections::{BTreeMap, VecDeque};
 use std::fs::File;
 use std::io::{self, Read, Write};
 use std::os::unix::io::FromRawFd;
 use std::rc::Rc;
-use std::{slice, str};
+use std::{cmp, slice, str};
 use syscall::data::Packet;
-use syscall::error::{Error, Result, EACCES, EADDRNOTAVAIL, EBADF, EIO, EINVAL, ENOENT, EWOULDBLOCK};
+use syscall::error::{Error, Result, EACCES, EADDRNOTAVAIL, EBADF, EIO, EINVAL, ENOENT, EPERM, EWOULDBLOCK};
 use syscall::flag::{EVENT_READ, O_NONBLOCK};
 use syscall::scheme::SchemeMut;
 
+use netfilter::{Chain, NetfilterScheme};
+
+mod netfilter;
+
+/// Counters of an interface, listed in `ip:stats`
+#[derive(Default)]
+struct Stats {
+    rx_packets: usize,
+    rx_bytes: usize,
+    tx_packets: usize,
+    tx_bytes: usize,
+    /// Packets received that could not be parsed, or that no handle wanted
+    drops: usize,
+    checksum_errors: usize,
+    icmp: usize,
+    /// Packets dropped by netfilter rules
+    filtered: usize,
+}
+
+/// Returns true if the checksum of an IPv4 header is correct
+fn header_checksum_valid(data: &[u8]) -> bool {
+    let len = match data.get(0) {
+        Some(b) => ((b & 0xF) as usize) * 4,
+        None => return false
+    };
+    if len < 20 || len > data.len() {
+        return false;
+    }
+
+    let mut sum = 0u32;
+    for i in 0..len/2 {
+        sum += (data[i * 2] as u32) << 8 | data[i * 2 + 1] as u32;
+    }
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    sum == 0xFFFF
+}
+
+/// What the network device does in place of the stack, from /etc/net/offload
+#[derive(Clone, Copy, Default)]
+struct Offload {
+    /// Frames with wrong IPv4 checksums are dropped by the device
+    rx_checksum: bool,
+    /// The device inserts IPv4 and TCP checksums
+    tx_checksum: bool,
+    /// The device splits TCP segments that are larger than the MTU
+    tso: bool,
+}
+
+impl Offload {
+    fn from_cfg() -> Offload {
+        let mut offload = Offload::default();
+        if let Ok(cfg) = getcfg("offload") {
+            for word in cfg.split_whitespace() {
+                match word {
+                    "rx_checksum" => offload.rx_checksum = true,
+                    "tx_checksum" => offload.tx_checksum = true,
+                    "tso" => offload.tso = true,
+                    _ => ()
+                }
+            }
+        }
+        offload
+    }
+}
+
+/// The sum of the TCP pseudo-header, which a device that inserts checksums adds the segment to.
+/// For segmentation the length is left out, as the device adds the length of each segment
+fn pseudo_header_sum(ip: &Ipv4, len: Option<usize>) -> u16 {
+    let mut sum = 0u32;
+    for bytes in [ip.header.src.bytes, ip.header.dst.bytes].iter() {
+        sum += (bytes[0] as u32) << 8 | bytes[1] as u32;
+        sum += (bytes[2] as u32) << 8 | bytes[3] as u32;
+    }
+    sum += ip.header.proto as u32;
+    if let Some(len) = len {
+        sum += len as u32;
+    }
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    sum as u16
+}
+
+/// Split a TCP segment into segments that fit in the MTU, as tcpd sends each write as one segment
+fn split_tcp(ip: Ipv4, mtu: usize) -> Vec<Ipv4> {
+    let tcp = match Tcp::from_bytes(&ip.data) {
+        Some(tcp) => tcp,
+        None => return vec![ip]
+    };
+
+    let hdrlen = 20 + ip.options.len() + 20 + tcp.options.len();
+    if hdrlen + tcp.data.len() <= mtu || hdrlen >= mtu {
+        return vec![ip];
+    }
+
+    let mss = mtu - hdrlen;
+    let mut segments = Vec::new();
+    for (i, data) in tcp.data.chunks(mss).enumerate() {
+        let mut segment = Tcp {
+            header: tcp.header,
+            options: tcp.options.clone(),
+            data: data.to_vec()
+        };
+        segment.header.sequence.set(tcp.header.sequence.get().wrapping_add((i * mss) as u32));
+        if (i + 1) * mss < tcp.data.len() {
+            segment.header.flags.set(tcp.header.flags.get() & !(TCP_FIN | TCP_PSH));
+        }
+
+        let mut segment_ip = Ipv4 {
+            header: ip.header,
+            options: ip.options.clone(),
+            data: segment.to_bytes()
+        };
+        segment_ip.header.id.set(ip.header.id.get().wrapping_add(i as u16));
+        segment_ip.header.len.set((20 + segment_ip.options.len() + segment_ip.data.len()) as u16);
+        segments.push(segment_ip);
+    }
+    segments
+}
+
 struct Interface {
     mac: MacAddr,
     ip: Ipv4Addr,
@@ -25,10 +147,14 @@ struct Interface {
     ip_file: File,
     arp: BTreeMap<Ipv4Addr, MacAddr>,
     rarp: BTreeMap<MacAddr, Ipv4Addr>,
+    stats: Stats,
+    /// Largest packet the device sends
+    mtu: usize,
+    offload: Offload,
 }
 
 impl Interface {
-    fn new(arp_fd: usize, ip_fd: usize) -> Self {
+    fn new(arp_fd: usize, ip_fd: usize, offload: Offload) -> Self {
         Interface {
             mac: MacAddr::from_str(&getcfg("mac").unwrap()),
             ip: Ipv4Addr::from_str(&getcfg("ip").unwrap()),
@@ -38,8 +164,53 @@ impl Interface {
             ip_file: unsafe { File::from_raw_fd(ip_fd) },
             arp: BTreeMap::new(),
             rarp: BTreeMap::new(),
+            stats: Stats::default(),
+            mtu: getcfg("mtu").ok().and_then(|mtu| mtu.trim().parse::<usize>().ok()).unwrap_or(1500),
+            offload: offload,
         }
     }
+
+    /// Fill in the checksums of a packet, or leave them to the device, and send it
+    fn send(&mut self, mut ip: Ipv4) -> Result<()> {
+        let len = 20 + ip.options.len() + ip.data.len();
+
+        if ip.header.proto == 0x06 && ip.data.len() >= 20 && (self.offload.tx_checksum || self.offload.tso) {
+            ip.header.checksum.data = 0;
+            let sum = if len > self.mtu {
+                pseudo_header_sum(&ip, None)
+            } else {
+                pseudo_header_sum(&ip, Some(ip.data.len()))
+            };
+            ip.data[16] = (sum >> 8) as u8;
+            ip.data[17] = sum as u8;
+        } else {
+            if ip.header.proto == 0x06 {
+                if let Some(mut tcp) = Tcp::from_bytes(&ip.data) {
+                    tcp.checksum(&ip.header.src, &ip.header.dst);
+                    ip.data = tcp.to_bytes();
+                }
+            }
+
+            ip.checksum();
+        }
+
+        let frame = EthernetII {
+            header: EthernetIIHeader {
+                //TODO: Get real dst
+                dst: MacAddr::BROADCAST,
+                src: self.mac,
+                ethertype: n16::new(0x800),
+            },
+            data: ip.to_bytes()
+        };
+
+        let data = frame.to_bytes();
+        self.ip_file.write(&data).map_err(|err| Error::new(err.raw_os_error().unwrap_or(EIO)))?;
+        self.stats.tx_packets += 1;
+        self.stats.tx_bytes += data.len();
+
+        Ok(())
+    }
 }
 
 struct Handle {
@@ -55,6 +226,10 @@ struct Ipd {
     interfaces: Vec<Interface>,
     next_id: usize,
     handles: BTreeMap<usize, Handle>,
+    /// Handles of `ip:stats`, with what is left to read
+    stats: BTreeMap<usize, Vec<u8>>,
+    /// Firewall rules, served at `netfilter:`
+    netfilter: NetfilterScheme,
 }
 
 impl Ipd {
@@ -64,9 +239,24 @@ impl Ipd {
             interfaces: Vec::new(),
             next_id: 1,
             handles: BTreeMap::new(),
+            stats: BTreeMap::new(),
+            netfilter: NetfilterScheme::new(),
         }
     }
 
+    fn stats_string(&self) -> String {
+        let mut string = format!("{:<4}{:<16}{:<12}{:<12}{:<12}{:<12}{:<8}{:<8}{:<8}{}\n",
+                                 "IF", "IP", "RX_PACKETS", "RX_BYTES", "TX_PACKETS", "TX_BYTES", "DROPS", "CHKSUM", "ICMP", "FILTER");
+        for (if_id, interface) in self.interfaces.iter().enumerate() {
+            let stats = &interface.stats;
+            string.push_str(&format!("{:<4}{:<16}{:<12}{:<12}{:<12}{:<12}{:<8}{:<8}{:<8}{}\n",
+                                     if_id, interface.ip.to_string(),
+                                     stats.rx_packets, stats.rx_bytes, stats.tx_packets, stats.tx_bytes,
+                                     stats.drops, stats.checksum_errors, stats.icmp, stats.filtered));
+        }
+        string
+    }
+
     fn scheme_event(&mut self) -> io::Result<()> {
         loop {
             let mut packet = Packet::default();
@@ -138,12 +328,30 @@ impl Ipd {
                 if count == 0 {
                     break;
                 }
+                interface.stats.rx_packets += 1;
+                interface.stats.rx_bytes += count;
+
+                let mut wanted = false;
                 if let Some(frame) = EthernetII::from_bytes(&bytes[.. count]) {
+                    if ! interface.offload.rx_checksum && ! header_checksum_valid(&frame.data) {
+                        interface.stats.checksum_errors += 1;
+                        continue;
+                    }
                     if let Some(ip) = Ipv4::from_bytes(&frame.data) {
                         if ip.header.dst == interface.ip || ip.header.dst == Ipv4Addr::BROADCAST {
+                            if ! self.netfilter.accept(Chain::Input, &ip) {
+                                interface.stats.filtered += 1;
+                                continue;
+                            }
+
+                            if ip.header.proto == 1 {
+                                interface.stats.icmp += 1;
+                            }
+
                             //TODO: Handle ping here
                             for (id, handle) in self.handles.iter_mut() {
                                 if ip.header.proto == handle.proto {
+                                    wanted = true;
                                     handle.data.push_back(frame.data.clone());
 
                                     while ! handle.todo.is_empty() && ! handle.data.is_empty() {
@@ -177,9 +385,15 @@ impl Ipd {
                                     }
                                 }
                             }
+                        } else {
+                            // Not for this interface, which is not a drop
+                            wanted = true;
                         }
                     }
                 }
+                if ! wanted {
+                    interface.stats.drops += 1;
+                }
             }
         }
 
@@ -192,6 +406,16 @@ impl SchemeMut for Ipd {
         if uid == 0 {
             let path = str::from_utf8(url).or(Err(Error::new(EINVAL)))?;
 
+            if path == "stats" {
+                let id = self.next_id;
+                self.next_id += 1;
+
+                let data = self.stats_string().into_bytes();
+                self.stats.insert(id, data);
+
+                return Ok(id);
+            }
+
             let proto = u8::from_str_radix(path, 16).or(Err(Error::new(ENOENT)))?;
 
             let id = self.next_id;
@@ -232,6 +456,13 @@ impl SchemeMut for Ipd {
     }
 
     fn read(&mut self, file: usize, buf: &mut [u8]) -> Result<usize> {
+        if let Some(data) = self.stats.get_mut(&file) {
+            let count = cmp::min(buf.len(), data.len());
+            buf[.. count].copy_from_slice(&data[.. count]);
+            data.drain(.. count);
+            return Ok(count);
+        }
+
         let mut handle = self.handles.get_mut(&file).ok_or(Error::new(EBADF))?;
 
         if let Some(data) = handle.data.pop_front() {
@@ -258,24 +489,21 @@ impl SchemeMut for Ipd {
                     ip.header.src = interface.ip;
                     ip.header.proto = handle.proto;
 
-                    if let Some(mut tcp) = Tcp::from_bytes(&ip.data) {
-                        tcp.checksum(&ip.header.src, &ip.header.dst);
-                        ip.data = tcp.to_bytes();
+                    if ! self.netfilter.accept(Chain::Output, &ip) {
+                        interface.stats.filtered += 1;
+                        return Err(Error::new(EPERM));
                     }
 
-                    ip.checksum();
-
-                    let frame = EthernetII {
-                        header: EthernetIIHeader {
-                            //TODO: Get real dst
-                            dst: MacAddr::BROADCAST,
-                            src: interface.mac,
-                            ethertype: n16::new(0x800),
-                        },
-                        data: ip.to_bytes()
+                    // Segments that are too large are split here if the device does not
+                    let segments = if ip.header.proto == 0x06 && ! interface.offload.tso {
+                        split_tcp(ip, interface.mtu)
+                    } else {
+                        vec![ip]
                     };
 
-                    interface.ip_file.write(&frame.to_bytes()).map_err(|err| Error::new(err.raw_os_error().unwrap_or(EIO)))?;
+                    for segment in segments {
+                        interface.send(segment)?;
+                    }
 
                     return Ok(buf.len());
                 }
@@ -317,6 +545,10 @@ impl SchemeMut for Ipd {
     }
 
     fn close(&mut self, file: usize) -> Result<usize> {
+        if self.stats.remove(&file).is_some() {
+            return Ok(0);
+        }
+
         let handle = self.handles.remove(&file).ok_or(Error::new(EBADF))?;
 
         drop(handle);
@@ -338,11 +570,27 @@ fn main() {
         //TODO: Multiple interfaces
         {
             let arp_fd = syscall::open("ethernet:806", syscall::O_RDWR | syscall::O_NONBLOCK).expect("ipd: failed to open ethernet:806");
-            let ip_fd = syscall::open("ethernet:800", syscall::O_RDWR | syscall::O_NONBLOCK).expect("ipd: failed to open ethernet:800");
+
+            // Checksums and segmentation are done here if the device can not be opened for offloads
+            let mut offload = Offload::from_cfg();
+            let offload_fd = if offload.tx_checksum || offload.tso {
+                syscall::open("ethernet:offload/800", syscall::O_RDWR | syscall::O_NONBLOCK).ok()
+            } else {
+                None
+            };
+            let ip_fd = match offload_fd {
+                Some(fd) => fd,
+                None => {
+                    offload.tx_checksum = false;
+                    offload.tso = false;
+                    syscall::open("ethernet:800", syscall::O_RDWR | syscall::O_NONBLOCK).expect("ipd: failed to open ethernet:800")
+                }
+            };
+
             let if_id = {
                 let mut ipd = ipd.borrow_mut();
                 let if_id = ipd.interfaces.len();
-                ipd.interfaces.push(Interface::new(arp_fd, ip_fd));
+                ipd.interfaces.push(Interface::new(arp_fd, ip_fd, offload));
                 if_id
             };
 
@@ -359,11 +607,27 @@ fn main() {
             }).expect("ipd: failed to listen to events on ethernet:800");
         }
 
+        let scheme_ipd = ipd.clone();
         event_queue.add(scheme_fd, move |_count: usize| -> io::Result<Option<()>> {
-            ipd.borrow_mut().scheme_event()?;
+            scheme_ipd.borrow_mut().scheme_event()?;
             Ok(None)
         }).expect("ipd: failed to listen to events on :ip");
 
+        let netfilter_fd = syscall::open(":netfilter", syscall::O_RDWR | syscall::O_CREAT | syscall::O_NONBLOCK).expect("ipd: failed to create :netfilter");
+        let mut netfilter_file = unsafe { File::from_raw_fd(netfilter_fd) };
+        event_queue.add(netfilter_fd, move |_count: usize| -> io::Result<Option<()>> {
+            loop {
+                let mut packet = Packet::default();
+                if netfilter_file.read(&mut packet)? == 0 {
+                    break;
+                }
+
+                ipd.borrow_mut().netfilter.handle(&mut packet);
+                netfilter_file.write(&packet)?;
+            }
+            Ok(None)
+        }).expect("ipd: failed to listen to events on :netfilter");
+
         // Make sure that all descriptors are at EOF
         event_queue.trigger_all(0).expect("ipd: failed to trigger event queue");
 
@@ -0,0 +1,290 @@
+use std::{cmp, str};
+use std::collections::BTreeMap;
+
+use netutils::{Ipv4, Ipv4Addr};
+use syscall::error::{Error, Result, EACCES, EBADF, EINVAL};
+use syscall::scheme::SchemeMut;
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum Chain {
+    /// Packets received for this host
+    Input,
+    /// Packets sent by this host
+    Output
+}
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum Action {
+    Accept,
+    Drop,
+    /// Print the packet and go on to the next rule
+    Log
+}
+
+/// A rule, which matches packets that have every field that is set
+struct Rule {
+    chain: Chain,
+    action: Action,
+    proto: Option<u8>,
+    /// The remote address, which is the source on input and the destination on output, and the
+    /// length of its prefix
+    addr: Option<(Ipv4Addr, u8)>,
+    /// The TCP or UDP destination port
+    port: Option<u16>,
+    hits: usize
+}
+
+struct Handle {
+    data: Vec<u8>,
+    seek: usize
+}
+
+/// Rules that ipd checks before delivering or sending a packet, in order, accepting packets that
+/// no rule drops. Rules are added by writing lines to `netfilter:`, like
+/// `input drop proto tcp addr 10.0.2.0/24 port 22`, and listed by reading it
+pub struct NetfilterScheme {
+    rules: Vec<Rule>,
+    next_id: usize,
+    handles: BTreeMap<usize, Handle>
+}
+
+fn chain_name(chain: Chain) -> &'static str {
+    match chain {
+        Chain::Input => "input",
+        Chain::Output => "output"
+    }
+}
+
+fn action_name(action: Action) -> &'static str {
+    match action {
+        Action::Accept => "accept",
+        Action::Drop => "drop",
+        Action::Log => "log"
+    }
+}
+
+fn parse_proto(proto: &str) -> Option<u8> {
+    match proto {
+        "icmp" => Some(1),
+        "tcp" => Some(6),
+        "udp" => Some(17),
+        _ => proto.parse::<u8>().ok()
+    }
+}
+
+fn parse_addr(addr: &str) -> Option<(Ipv4Addr, u8)> {
+    let mut parts = addr.splitn(2, '/');
+    let ip = Ipv4Addr::from_str(parts.next().unwrap_or(""));
+    let prefix = match parts.next() {
+        Some(prefix) => match prefix.parse::<u8>() {
+            Ok(prefix) if prefix <= 32 => prefix,
+            _ => return None
+        },
+        None => 32
+    };
+    Some((ip, prefix))
+}
+
+/// Returns true if the first `prefix` bits of the addresses are the same
+fn prefix_matches(a: &Ipv4Addr, b: &Ipv4Addr, prefix: u8) -> bool {
+    let to_u32 = |addr: &Ipv4Addr| (addr.bytes[0] as u32) << 24 | (addr.bytes[1] as u32) << 16 | (addr.bytes[2] as u32) << 8 | addr.bytes[3] as u32;
+    let mask = if prefix == 0 { 0 } else { !0u32 << (32 - prefix) };
+    to_u32(a) & mask == to_u32(b) & mask
+}
+
+impl Rule {
+    fn from_str(line: &str) -> Result<Rule> {
+        let mut words = line.split_whitespace();
+
+        let chain = match words.next() {
+            Some("input") => Chain::Input,
+            Some("output") => Chain::Output,
+            _ => return Err(Error::new(EINVAL))
+        };
+
+        let action = match words.next() {
+            Some("accept") => Action::Accept,
+            Some("drop") => Action::Drop,
+            Some("log") => Action::Log,
+            _ => return Err(Error::new(EINVAL))
+        };
+
+        let mut rule = Rule {
+            chain: chain,
+            action: action,
+            proto: None,
+            addr: None,
+            port: None,
+            hits: 0
+        };
+
+        while let Some(key) = words.next() {
+            let value = words.next().ok_or(Error::new(EINVAL))?;
+            match key {
+                "proto" => rule.proto = Some(parse_proto(value).ok_or(Error::new(EINVAL))?),
+                "addr" => rule.addr = Some(parse_addr(value).ok_or(Error::new(EINVAL))?),
+                "port" => rule.port = Some(value.parse::<u16>().or(Err(Error::new(EINVAL)))?),
+                _ => return Err(Error::new(EINVAL))
+            }
+        }
+
+        Ok(rule)
+    }
+
+    fn matches(&self, chain: Chain, ip: &Ipv4) -> bool {
+        if self.chain != chain {
+            return false;
+        }
+
+        if let Some(proto) = self.proto {
+            if ip.header.proto != proto {
+                return false;
+            }
+        }
+
+        if let Some((addr, prefix)) = self.addr {
+            let remote = if chain == Chain::Input { ip.header.src } else { ip.header.dst };
+            if ! prefix_matches(&remote, &addr, prefix) {
+                return false;
+            }
+        }
+
+        if let Some(port) = self.port {
+            // Only TCP and UDP have ports, both with the destination port in the same place
+            if (ip.header.proto != 6 && ip.header.proto != 17) || ip.data.len() < 4 {
+                return false;
+            }
+            if (ip.data[2] as u16) << 8 | ip.data[3] as u16 != port {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    fn to_string(&self) -> String {
+        let proto = self.proto.map_or("*".to_string(), |proto| format!("{}", proto));
+        let addr = self.addr.map_or("*".to_string(), |(addr, prefix)| format!("{}/{}", addr.to_string(), prefix));
+        let port = self.port.map_or("*".to_string(), |port| format!("{}", port));
+        format!("{:<8}{:<8}{:<8}{:<20}{:<8}{}", chain_name(self.chain), action_name(self.action), proto, addr, port, self.hits)
+    }
+}
+
+impl NetfilterScheme {
+    pub fn new() -> NetfilterScheme {
+        NetfilterScheme {
+            rules: Vec::new(),
+            next_id: 1,
+            handles: BTreeMap::new()
+        }
+    }
+
+    /// Check a packet against the rules, returning false if it is dropped
+    pub fn accept(&mut self, chain: Chain, ip: &Ipv4) -> bool {
+        for rule in self.rules.iter_mut() {
+            if rule.matches(chain, ip) {
+                rule.hits += 1;
+                match rule.action {
+                    Action::Accept => return true,
+                    Action::Drop => return false,
+                    Action::Log => println!("netfilter: {} proto {} {} -> {} len {}",
+                                            chain_name(chain), ip.header.proto,
+                                            ip.header.src.to_string(), ip.header.dst.to_string(), ip.data.len())
+                }
+            }
+        }
+
+        true
+    }
+
+    fn rules_string(&self) -> String {
+        let mut string = format!("{:<4}{:<8}{:<8}{:<8}{:<20}{:<8}{}\n", "NUM", "CHAIN", "ACTION", "PROTO", "ADDR", "PORT", "HITS");
+        for (i, rule) in self.rules.iter().enumerate() {
+            string.push_str(&format!("{:<4}{}\n", i, rule.to_string()));
+        }
+        string
+    }
+
+    /// Add a rule, or run `flush` to remove every rule or `delete NUM` to remove one
+    fn command(&mut self, line: &str) -> Result<()> {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            None => Ok(()),
+            Some("flush") => {
+                self.rules.clear();
+                Ok(())
+            },
+            Some("delete") => {
+                let num = words.next().and_then(|num| num.parse::<usize>().ok()).ok_or(Error::new(EINVAL))?;
+                if num < self.rules.len() {
+                    self.rules.remove(num);
+                    Ok(())
+                } else {
+                    Err(Error::new(EINVAL))
+                }
+            },
+            Some(_) => {
+                let rule = Rule::from_str(line)?;
+                self.rules.push(rule);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl SchemeMut for NetfilterScheme {
+    fn open(&mut self, _path: &[u8], _flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        if uid != 0 {
+            return Err(Error::new(EACCES));
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.handles.insert(id, Handle {
+            data: self.rules_string().into_bytes(),
+            seek: 0
+        });
+
+        Ok(id)
+    }
+
+    fn read(&mut self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let handle = self.handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+
+        let count = cmp::min(buf.len(), handle.data.len() - handle.seek);
+        buf[.. count].copy_from_slice(&handle.data[handle.seek .. handle.seek + count]);
+        handle.seek += count;
+
+        Ok(count)
+    }
+
+    fn write(&mut self, id: usize, buf: &[u8]) -> Result<usize> {
+        let _handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+
+        let text = str::from_utf8(buf).or(Err(Error::new(EINVAL)))?;
+        for line in text.lines() {
+            self.command(line)?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn fpath(&mut self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let _handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+
+        let path = b"netfilter:";
+
+        let mut i = 0;
+        while i < buf.len() && i < path.len() {
+            buf[i] = path[i];
+            i += 1;
+        }
+
+        Ok(i)
+    }
+
+    fn close(&mut self, id: usize) -> Result<usize> {
+        self.handles.remove(&id).ok_or(Error::new(EBADF)).and(Ok(0))
+    }
+}
@@ -0,0 +1,48 @@
+//! Overlay filesystem, combining a read-only lower layer with a writable upper layer
+//!
+//! Files are looked up in the upper layer first, then in the lower layer. A file from the lower
+//! layer is copied up before it is changed, and removing one leaves a whiteout in the upper layer,
+//! so the lower layer is never written. This lets a live image on `initfs:` keep its changes in a
+//! directory of a writable filesystem.
+//!
+//! Usage: overlayd <name> <lower> <upper>, then the overlay is at `<name>:`, and can be attached
+//! to the namespace with sys:mounts
+
+extern crate syscall;
+
+use std::env;
+use std::fs::File;
+use std::io::{Read, Write};
+
+use syscall::data::Packet;
+use syscall::scheme::SchemeMut;
+
+use scheme::OverlayScheme;
+
+mod scheme;
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let (name, lower, upper) = match (args.next(), args.next(), args.next()) {
+        (Some(name), Some(lower), Some(upper)) => (name, lower, upper),
+        _ => {
+            println!("overlay: usage: overlayd <name> <lower> <upper>");
+            return;
+        }
+    };
+
+    // Daemonize
+    if unsafe { syscall::clone(0).unwrap() } == 0 {
+        let mut socket = File::create(&format!(":{}", name)).expect("overlay: failed to create overlay scheme");
+
+        println!("overlay: {} on {}:, writing to {}", lower, name, upper);
+        let mut scheme = OverlayScheme::new(name, lower, upper);
+
+        loop {
+            let mut packet = Packet::default();
+            socket.read(&mut packet).expect("overlay: failed to read events from overlay scheme");
+            scheme.handle(&mut packet);
+            socket.write(&packet).expect("overlay: failed to write responses to overlay scheme");
+        }
+    }
+}
@@ -0,0 +1,512 @@
+use std::{cmp, str};
+use std::collections::BTreeMap;
+
+use syscall;
+use syscall::data::Stat;
+use syscall::error::{Error, Result, EACCES, EBADF, EEXIST, EINVAL, EISDIR, ENOENT, ENOTDIR, ENOTEMPTY};
+use syscall::flag::{MODE_DIR, O_ACCMODE, O_CREAT, O_EXCL, O_RDONLY, O_TRUNC, O_WRONLY, SEEK_CUR, SEEK_END, SEEK_SET};
+use syscall::scheme::SchemeMut;
+
+/// Prefix of the file left in the upper layer when a file of the lower layer is removed
+const WHITEOUT: &'static str = ".wh.";
+
+/// Marks a directory of the upper layer that hides the lower directory of the same name
+const OPAQUE: &'static str = ".wh..opq";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Layer {
+    Lower,
+    Upper
+}
+
+enum Handle {
+    /// A file, read and written through the layer it is in
+    File {
+        path: String,
+        fd: usize
+    },
+    /// A directory, listing the entries of both layers
+    Directory {
+        path: String,
+        fd: usize,
+        data: Vec<u8>,
+        seek: usize
+    }
+}
+
+pub struct OverlayScheme {
+    name: String,
+    lower: String,
+    upper: String,
+    handles: BTreeMap<usize, Handle>,
+    next_id: usize
+}
+
+fn join(root: &str, path: &str) -> String {
+    if path.is_empty() {
+        root.to_string()
+    } else {
+        format!("{}/{}", root.trim_right_matches('/'), path)
+    }
+}
+
+/// Split a path into its parent and name
+fn split(path: &str) -> (&str, &str) {
+    match path.rfind('/') {
+        Some(i) => (&path[.. i], &path[i + 1 ..]),
+        None => ("", path)
+    }
+}
+
+fn stat(path: &str) -> Option<Stat> {
+    match syscall::open(path, O_RDONLY) {
+        Ok(fd) => {
+            let mut stat = Stat::default();
+            let result = syscall::fstat(fd, &mut stat);
+            let _ = syscall::close(fd);
+            result.ok().map(|_| stat)
+        },
+        Err(_) => None
+    }
+}
+
+fn read_all(path: &str) -> Result<Vec<u8>> {
+    let fd = syscall::open(path, O_RDONLY)?;
+    let mut data = Vec::new();
+    let mut buf = [0; 4096];
+    loop {
+        match syscall::read(fd, &mut buf) {
+            Ok(0) => break,
+            Ok(count) => data.extend_from_slice(&buf[.. count]),
+            Err(err) => {
+                let _ = syscall::close(fd);
+                return Err(err);
+            }
+        }
+    }
+    let _ = syscall::close(fd);
+    Ok(data)
+}
+
+/// Check that a caller may read a file, by its status
+fn check_read(stat: &Stat, uid: u32, gid: u32) -> Result<()> {
+    let mut perm = stat.st_mode & 0o7;
+    if stat.st_uid == uid {
+        perm |= (stat.st_mode >> 6) & 0o7;
+    }
+    if stat.st_gid == gid {
+        perm |= (stat.st_mode >> 3) & 0o7;
+    }
+    if uid == 0 || perm & 0o4 == 0o4 {
+        Ok(())
+    } else {
+        Err(Error::new(EACCES))
+    }
+}
+
+/// Files created in the upper layer are owned by the daemon, so only root may change the overlay
+fn check_write(uid: u32) -> Result<()> {
+    if uid == 0 {
+        Ok(())
+    } else {
+        Err(Error::new(EACCES))
+    }
+}
+
+impl OverlayScheme {
+    pub fn new(name: String, lower: String, upper: String) -> OverlayScheme {
+        OverlayScheme {
+            name: name,
+            lower: lower,
+            upper: upper,
+            handles: BTreeMap::new(),
+            next_id: 0
+        }
+    }
+
+    fn layer_path(&self, layer: Layer, path: &str) -> String {
+        match layer {
+            Layer::Lower => join(&self.lower, path),
+            Layer::Upper => join(&self.upper, path)
+        }
+    }
+
+    /// Check if the lower layer is hidden at `path`, by a whiteout of it or of a parent, or by an
+    /// opaque parent
+    fn whited_out(&self, path: &str) -> bool {
+        let names: Vec<&str> = path.split('/').filter(|name| ! name.is_empty()).collect();
+        for i in 0 .. names.len() {
+            let parent = names[.. i].join("/");
+            if stat(&join(&self.upper, &join(&parent, &format!("{}{}", WHITEOUT, names[i])))).is_some() {
+                return true;
+            }
+            if i > 0 && stat(&join(&self.upper, &join(&parent, OPAQUE))).is_some() {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Find the layer that `path` is in, and its status
+    fn lookup(&self, path: &str) -> Option<(Layer, Stat)> {
+        if let Some(stat) = stat(&self.layer_path(Layer::Upper, path)) {
+            Some((Layer::Upper, stat))
+        } else if self.whited_out(path) {
+            None
+        } else {
+            stat(&self.layer_path(Layer::Lower, path)).map(|stat| (Layer::Lower, stat))
+        }
+    }
+
+    fn lower_visible(&self, path: &str) -> bool {
+        ! self.whited_out(path) && stat(&self.layer_path(Layer::Lower, path)).is_some()
+    }
+
+    /// The entries of a directory in both layers, without whiteouts or entries that they hide
+    fn list(&self, path: &str) -> Result<Vec<u8>> {
+        let mut entries = BTreeMap::new();
+        let mut hidden = Vec::new();
+        let mut opaque = false;
+
+        if let Ok(data) = read_all(&self.layer_path(Layer::Upper, path)) {
+            for line in String::from_utf8_lossy(&data).lines() {
+                let name = line.trim_right_matches('/');
+                if name == OPAQUE {
+                    opaque = true;
+                } else if name.starts_with(WHITEOUT) {
+                    hidden.push(name[WHITEOUT.len() ..].to_string());
+                } else if ! name.is_empty() {
+                    entries.insert(name.to_string(), line.to_string());
+                }
+            }
+        }
+
+        if ! opaque && ! self.whited_out(path) {
+            if let Ok(data) = read_all(&self.layer_path(Layer::Lower, path)) {
+                for line in String::from_utf8_lossy(&data).lines() {
+                    let name = line.trim_right_matches('/');
+                    if ! name.is_empty() && ! hidden.iter().any(|other| other == name) && ! entries.contains_key(name) {
+                        entries.insert(name.to_string(), line.to_string());
+                    }
+                }
+            }
+        }
+
+        let mut data = Vec::new();
+        for line in entries.values() {
+            if ! data.is_empty() {
+                data.push(b'\n');
+            }
+            data.extend_from_slice(line.as_bytes());
+        }
+        Ok(data)
+    }
+
+    /// Create a directory and its parents in the upper layer, with the modes of the lower layer
+    fn copy_up_dir(&self, path: &str) -> Result<()> {
+        let names: Vec<&str> = path.split('/').filter(|name| ! name.is_empty()).collect();
+        for i in 1 .. names.len() + 1 {
+            let dir = names[.. i].join("/");
+            if stat(&self.layer_path(Layer::Upper, &dir)).is_none() {
+                let mode = stat(&self.layer_path(Layer::Lower, &dir)).map_or(0o755, |stat| stat.st_mode & 0o7777);
+                syscall::mkdir(&self.layer_path(Layer::Upper, &dir), mode)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy a file of the lower layer to the upper layer, so that it can be changed
+    fn copy_up(&self, path: &str, stat: &Stat) -> Result<()> {
+        self.copy_up_dir(split(path).0)?;
+
+        let from = syscall::open(&self.layer_path(Layer::Lower, path), O_RDONLY)?;
+        // The permissions of a new file are passed with the flags
+        let to = match syscall::open(&self.layer_path(Layer::Upper, path), O_CREAT | O_WRONLY | O_TRUNC | (stat.st_mode & 0o7777) as usize) {
+            Ok(to) => to,
+            Err(err) => {
+                let _ = syscall::close(from);
+                return Err(err);
+            }
+        };
+
+        let mut buf = [0; 4096];
+        let mut result = Ok(());
+        loop {
+            match syscall::read(from, &mut buf) {
+                Ok(0) => break,
+                Ok(count) => if let Err(err) = syscall::write(to, &buf[.. count]) {
+                    result = Err(err);
+                    break;
+                },
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            }
+        }
+
+        let _ = syscall::close(from);
+        let _ = syscall::close(to);
+        result
+    }
+
+    fn whiteout_path(path: &str) -> String {
+        let (parent, name) = split(path);
+        join(parent, &format!("{}{}", WHITEOUT, name))
+    }
+
+    /// Hide `path` in the lower layer
+    fn whiteout(&self, path: &str) -> Result<()> {
+        self.copy_up_dir(split(path).0)?;
+        let fd = syscall::open(&self.layer_path(Layer::Upper, &OverlayScheme::whiteout_path(path)), O_CREAT | O_WRONLY | O_TRUNC)?;
+        let _ = syscall::close(fd);
+        Ok(())
+    }
+
+    /// Check that the parent of `path` is a directory, and create it in the upper layer
+    fn prepare_parent(&self, path: &str) -> Result<()> {
+        let parent = split(path).0;
+        match self.lookup(parent) {
+            Some((_layer, stat)) => if stat.st_mode & MODE_DIR != MODE_DIR {
+                return Err(Error::new(ENOTDIR));
+            },
+            None => return Err(Error::new(ENOENT))
+        }
+        self.copy_up_dir(parent)
+    }
+
+    fn insert(&mut self, handle: Handle) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.handles.insert(id, handle);
+        id
+    }
+}
+
+/// Parse a path, which may not name the files used to keep track of the layers
+fn parse(path: &[u8]) -> Result<String> {
+    let path = str::from_utf8(path).or(Err(Error::new(ENOENT)))?.trim_matches('/');
+    if path.split('/').any(|name| name.starts_with(WHITEOUT)) {
+        return Err(Error::new(ENOENT));
+    }
+    Ok(path.to_string())
+}
+
+impl SchemeMut for OverlayScheme {
+    fn open(&mut self, path: &[u8], flags: usize, uid: u32, gid: u32) -> Result<usize> {
+        let path = parse(path)?;
+        let write = flags & O_ACCMODE != O_RDONLY || flags & O_TRUNC == O_TRUNC;
+        if write {
+            check_write(uid)?;
+        }
+
+        let handle = match self.lookup(&path) {
+            Some((layer, stat)) => {
+                if flags & (O_CREAT | O_EXCL) == O_CREAT | O_EXCL {
+                    return Err(Error::new(EEXIST));
+                }
+                check_read(&stat, uid, gid)?;
+
+                if stat.st_mode & MODE_DIR == MODE_DIR {
+                    if write {
+                        return Err(Error::new(EISDIR));
+                    }
+                    let data = self.list(&path)?;
+                    Handle::Directory {
+                        fd: syscall::open(&self.layer_path(layer, &path), O_RDONLY)?,
+                        path: path,
+                        data: data,
+                        seek: 0
+                    }
+                } else {
+                    let layer = if write && layer == Layer::Lower {
+                        self.copy_up(&path, &stat)?;
+                        Layer::Upper
+                    } else {
+                        layer
+                    };
+                    Handle::File {
+                        fd: syscall::open(&self.layer_path(layer, &path), flags & ! (O_CREAT | O_EXCL))?,
+                        path: path
+                    }
+                }
+            },
+            None => if flags & O_CREAT == O_CREAT {
+                check_write(uid)?;
+                self.prepare_parent(&path)?;
+                let _ = syscall::unlink(&self.layer_path(Layer::Upper, &OverlayScheme::whiteout_path(&path)));
+                Handle::File {
+                    fd: syscall::open(&self.layer_path(Layer::Upper, &path), flags)?,
+                    path: path
+                }
+            } else {
+                return Err(Error::new(ENOENT));
+            }
+        };
+
+        Ok(self.insert(handle))
+    }
+
+    fn mkdir(&mut self, path: &[u8], mode: u16, uid: u32, _gid: u32) -> Result<usize> {
+        check_write(uid)?;
+        let path = parse(path)?;
+        if self.lookup(&path).is_some() {
+            return Err(Error::new(EEXIST));
+        }
+        self.prepare_parent(&path)?;
+
+        let _ = syscall::unlink(&self.layer_path(Layer::Upper, &OverlayScheme::whiteout_path(&path)));
+        syscall::mkdir(&self.layer_path(Layer::Upper, &path), mode)?;
+
+        // A directory that was removed from the lower layer must not show its old entries
+        if stat(&self.layer_path(Layer::Lower, &path)).is_some() {
+            let fd = syscall::open(&self.layer_path(Layer::Upper, &join(&path, OPAQUE)), O_CREAT | O_WRONLY | O_TRUNC)?;
+            let _ = syscall::close(fd);
+        }
+
+        Ok(0)
+    }
+
+    fn rmdir(&mut self, path: &[u8], uid: u32, _gid: u32) -> Result<usize> {
+        check_write(uid)?;
+        let path = parse(path)?;
+        let (layer, stat) = self.lookup(&path).ok_or(Error::new(ENOENT))?;
+        if stat.st_mode & MODE_DIR != MODE_DIR {
+            return Err(Error::new(ENOTDIR));
+        }
+        if ! self.list(&path)?.is_empty() {
+            return Err(Error::new(ENOTEMPTY));
+        }
+
+        let lower_visible = self.lower_visible(&path);
+        if layer == Layer::Upper {
+            // Only whiteouts are left in the upper directory
+            let data = read_all(&self.layer_path(Layer::Upper, &path))?;
+            for name in String::from_utf8_lossy(&data).lines() {
+                if name.starts_with(WHITEOUT) {
+                    syscall::unlink(&self.layer_path(Layer::Upper, &join(&path, name)))?;
+                }
+            }
+            syscall::rmdir(&self.layer_path(Layer::Upper, &path))?;
+        }
+        if lower_visible {
+            self.whiteout(&path)?;
+        }
+
+        Ok(0)
+    }
+
+    fn unlink(&mut self, path: &[u8], uid: u32, _gid: u32) -> Result<usize> {
+        check_write(uid)?;
+        let path = parse(path)?;
+        let (layer, stat) = self.lookup(&path).ok_or(Error::new(ENOENT))?;
+        if stat.st_mode & MODE_DIR == MODE_DIR {
+            return Err(Error::new(EISDIR));
+        }
+
+        let lower_visible = self.lower_visible(&path);
+        if layer == Layer::Upper {
+            syscall::unlink(&self.layer_path(Layer::Upper, &path))?;
+        }
+        if lower_visible {
+            self.whiteout(&path)?;
+        }
+
+        Ok(0)
+    }
+
+    fn dup(&mut self, id: usize, buf: &[u8]) -> Result<usize> {
+        let handle = match *self.handles.get(&id).ok_or(Error::new(EBADF))? {
+            Handle::File { ref path, fd } => Handle::File {
+                path: path.clone(),
+                fd: syscall::dup(fd, buf)?
+            },
+            Handle::Directory { ref path, fd, ref data, seek } => Handle::Directory {
+                path: path.clone(),
+                fd: syscall::dup(fd, buf)?,
+                data: data.clone(),
+                seek: seek
+            }
+        };
+
+        Ok(self.insert(handle))
+    }
+
+    fn read(&mut self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        match *self.handles.get_mut(&id).ok_or(Error::new(EBADF))? {
+            Handle::File { fd, .. } => syscall::read(fd, buf),
+            Handle::Directory { ref data, ref mut seek, .. } => {
+                let count = cmp::min(buf.len(), data.len() - *seek);
+                buf[.. count].copy_from_slice(&data[*seek .. *seek + count]);
+                *seek += count;
+                Ok(count)
+            }
+        }
+    }
+
+    fn write(&mut self, id: usize, buf: &[u8]) -> Result<usize> {
+        match *self.handles.get(&id).ok_or(Error::new(EBADF))? {
+            Handle::File { fd, .. } => syscall::write(fd, buf),
+            Handle::Directory { .. } => Err(Error::new(EISDIR))
+        }
+    }
+
+    fn seek(&mut self, id: usize, pos: usize, whence: usize) -> Result<usize> {
+        match *self.handles.get_mut(&id).ok_or(Error::new(EBADF))? {
+            Handle::File { fd, .. } => syscall::lseek(fd, pos as isize, whence),
+            Handle::Directory { ref data, ref mut seek, .. } => {
+                *seek = match whence {
+                    SEEK_SET => cmp::min(data.len(), pos),
+                    SEEK_CUR => cmp::max(0, cmp::min(data.len() as isize, *seek as isize + pos as isize)) as usize,
+                    SEEK_END => cmp::max(0, cmp::min(data.len() as isize, data.len() as isize + pos as isize)) as usize,
+                    _ => return Err(Error::new(EINVAL))
+                };
+                Ok(*seek)
+            }
+        }
+    }
+
+    fn fpath(&mut self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let path = match *self.handles.get(&id).ok_or(Error::new(EBADF))? {
+            Handle::File { ref path, .. } => path,
+            Handle::Directory { ref path, .. } => path
+        };
+        let path = format!("{}:/{}", self.name, path);
+
+        let count = cmp::min(buf.len(), path.len());
+        buf[.. count].copy_from_slice(&path.as_bytes()[.. count]);
+        Ok(count)
+    }
+
+    fn fstat(&mut self, id: usize, stat: &mut Stat) -> Result<usize> {
+        match *self.handles.get(&id).ok_or(Error::new(EBADF))? {
+            Handle::File { fd, .. } => syscall::fstat(fd, stat),
+            Handle::Directory { fd, ref data, .. } => {
+                syscall::fstat(fd, stat)?;
+                stat.st_size = data.len() as u64;
+                Ok(0)
+            }
+        }
+    }
+
+    fn fsync(&mut self, id: usize) -> Result<usize> {
+        match *self.handles.get(&id).ok_or(Error::new(EBADF))? {
+            Handle::File { fd, .. } => syscall::fsync(fd),
+            Handle::Directory { .. } => Ok(0)
+        }
+    }
+
+    fn ftruncate(&mut self, id: usize, len: usize) -> Result<usize> {
+        match *self.handles.get(&id).ok_or(Error::new(EBADF))? {
+            Handle::File { fd, .. } => syscall::ftruncate(fd, len),
+            Handle::Directory { .. } => Err(Error::new(EISDIR))
+        }
+    }
+
+    fn close(&mut self, id: usize) -> Result<usize> {
+        match self.handles.remove(&id).ok_or(Error::new(EBADF))? {
+            Handle::File { fd, .. } => syscall::close(fd),
+            Handle::Directory { fd, .. } => syscall::close(fd)
+        }
+    }
+}
@@ -1,23 +1,52 @@
 #![feature(asm)]
-#![feature(rand)]
 
+extern crate crypto;
 extern crate syscall;
 extern crate raw_cpuid;
-extern crate rand;
 
 use std::fs::File;
 use std::io::{Read, Write};
 
-use rand::chacha::ChaChaRng;
-use rand::Rng;
+use crypto::{ChaCha20, Sha256};
 
 use raw_cpuid::CpuId;
 
 use syscall::{Packet, Result, SchemeMut};
 
-//TODO: Use a CSPRNG, allow write of entropy
+/// Entropy is hashed into a pool, and the output comes from ChaCha20 keyed with the pool's hash.
+/// Writes add entropy
 struct RandScheme {
-   prng: ChaChaRng
+    pool: Sha256,
+    prng: ChaCha20,
+    reseeds: u64
+}
+
+impl RandScheme {
+    fn new() -> RandScheme {
+        RandScheme {
+            pool: Sha256::new(),
+            prng: ChaCha20::new(&[0; 32], &[0; 12], 0),
+            reseeds: 0
+        }
+    }
+
+    /// Add entropy to the pool, and key the generator from it
+    fn add_entropy(&mut self, data: &[u8]) {
+        self.pool.update(data);
+
+        // Output of the old key is mixed in, so the new key depends on all earlier entropy
+        let mut previous = [0; 32];
+        self.prng.fill(&mut previous);
+        self.pool.update(&previous);
+
+        let key = self.pool.clone().finish();
+        self.reseeds += 1;
+        let mut nonce = [0; 12];
+        for i in 0..8 {
+            nonce[i] = (self.reseeds >> (i * 8)) as u8;
+        }
+        self.prng = ChaCha20::new(&key, &nonce, 0);
+    }
 }
 
 impl SchemeMut for RandScheme {
@@ -30,16 +59,13 @@ impl SchemeMut for RandScheme {
     }
 
     fn read(&mut self, _file: usize, buf: &mut [u8]) -> Result<usize> {
-        let mut i = 0;
-        for chunk in buf.chunks_mut(8) {
-            let mut rand = self.prng.next_u64();
-            for b in chunk.iter_mut() {
-                *b = rand as u8;
-                rand = rand >> 8;
-                i += 1;
-            }
-        }
-        Ok(i)
+        self.prng.fill(buf);
+        Ok(buf.len())
+    }
+
+    fn write(&mut self, _file: usize, buf: &[u8]) -> Result<usize> {
+        self.add_entropy(buf);
+        Ok(buf.len())
     }
 
     fn close(&mut self, _file: usize) -> Result<usize> {
@@ -47,6 +73,31 @@ impl SchemeMut for RandScheme {
     }
 }
 
+fn rdrand() -> u64 {
+    let rand: u64;
+    unsafe {
+        asm!("rdrand rax"
+            : "={rax}"(rand)
+            :
+            :
+            : "intel", "volatile");
+    }
+    rand
+}
+
+fn rdtsc() -> u64 {
+    let low: u32;
+    let high: u32;
+    unsafe {
+        asm!("rdtsc"
+            : "={eax}"(low), "={edx}"(high)
+            :
+            :
+            : "intel", "volatile");
+    }
+    (high as u64) << 32 | low as u64
+}
+
 fn main(){
     let has_rdrand = CpuId::new().get_feature_info().unwrap().has_rdrand();
 
@@ -54,24 +105,26 @@ fn main(){
     if unsafe { syscall::clone(0).unwrap() } == 0 {
         let mut socket = File::create(":rand").expect("rand: failed to create rand scheme");
 
-        let mut rng = ChaChaRng::new_unseeded();
+        let mut scheme = RandScheme::new();
 
+        let mut seed = [0; 40];
         if has_rdrand {
             println!("rand: seeding with rdrand");
-            let rand: u64;
-            unsafe {
-                asm!("rdrand rax"
-                    : "={rax}"(rand)
-                    :
-                    :
-                    : "intel", "volatile");
+            for i in 0..4 {
+                let rand = rdrand();
+                for j in 0..8 {
+                    seed[i * 8 + j] = (rand >> (j * 8)) as u8;
+                }
             }
-            rng.set_counter(0, rand);
         } else {
-            println!("rand: unseeded");
+            println!("rand: seeding with the time stamp counter, write to rand: to add entropy");
+        }
+        let tsc = rdtsc();
+        for i in 0..8 {
+            seed[32 + i] = (tsc >> (i * 8)) as u8;
         }
+        scheme.add_entropy(&seed);
 
-        let mut scheme = RandScheme{prng: rng};
         loop {
             let mut packet = Packet::default();
             socket.read(&mut packet).expect("rand: failed to read events from rand scheme");
@@ -8,14 +8,14 @@ use std::collections::{BTreeMap, VecDeque};
 use std::cell::RefCell;
 use std::fs::File;
 use std::io::{self, Read, Write};
-use std::{mem, slice, str};
+use std::{cmp, mem, slice, str};
 use std::os::unix::io::FromRawFd;
 use std::rc::Rc;
 
 use event::EventQueue;
 use netutils::{n16, n32, Ipv4, Ipv4Addr, Ipv4Header, Tcp, TcpHeader, Checksum, TCP_FIN, TCP_SYN, TCP_RST, TCP_PSH, TCP_ACK};
 use syscall::data::Packet;
-use syscall::error::{Error, Result, EACCES, EADDRINUSE, EBADF, EIO, EINVAL, EISCONN, EMSGSIZE, ENOTCONN, EWOULDBLOCK};
+use syscall::error::{Error, Result, EACCES, EADDRINUSE, EBADF, EIO, EINVAL, EISCONN, EMSGSIZE, ENOPROTOOPT, ENOTCONN, EWOULDBLOCK};
 use syscall::flag::{EVENT_READ, O_CREAT, O_RDWR, O_NONBLOCK};
 use syscall::scheme::SchemeMut;
 
@@ -26,6 +26,59 @@ fn parse_socket(socket: &str) -> (Ipv4Addr, u16) {
     (host, port)
 }
 
+/// Parse a remote socket, which may name the host rather than give its address. Names are looked
+/// up with `dns:`
+fn resolve_socket(socket: &str) -> Result<(Ipv4Addr, u16)> {
+    let host = socket.split(":").next().unwrap_or("");
+    if host.chars().all(|c| c.is_digit(10) || c == '.') {
+        return Ok(parse_socket(socket));
+    }
+
+    let mut address = String::new();
+    File::open(&format!("dns:{}", host)).and_then(|mut file| file.read_to_string(&mut address)).map_err(|err| Error::new(err.raw_os_error().unwrap_or(EIO)))?;
+
+    Ok((Ipv4Addr::from_str(address.trim()), parse_socket(socket).1))
+}
+
+/// Socket options, set by duplicating a handle with `NAME=VALUE`, and listed by duplicating it
+/// with `options`
+#[derive(Copy, Clone)]
+struct Options {
+    /// Let other handles open the local port while this one has it
+    reuse_addr: bool,
+    /// Segments are always sent as soon as they are written, so this is only recorded
+    no_delay: bool,
+    /// Limit on received data waiting to be read, in bytes, or zero for no limit
+    rcvbuf: usize,
+}
+
+impl Options {
+    fn new() -> Options {
+        Options {
+            reuse_addr: false,
+            no_delay: false,
+            rcvbuf: 0,
+        }
+    }
+
+    fn set(&mut self, option: &str) -> Result<()> {
+        let mut parts = option.splitn(2, '=');
+        let name = parts.next().unwrap_or("");
+        let value = parts.next().unwrap_or("").parse::<usize>().or(Err(Error::new(EINVAL)))?;
+        match name {
+            "SO_REUSEADDR" => self.reuse_addr = value != 0,
+            "TCP_NODELAY" => self.no_delay = value != 0,
+            "SO_RCVBUF" => self.rcvbuf = value,
+            _ => return Err(Error::new(ENOPROTOOPT))
+        }
+        Ok(())
+    }
+
+    fn to_string(&self) -> String {
+        format!("SO_REUSEADDR={}\nTCP_NODELAY={}\nSO_RCVBUF={}\n", self.reuse_addr as usize, self.no_delay as usize, self.rcvbuf)
+    }
+}
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 enum State {
     Listen,
@@ -53,6 +106,10 @@ struct Handle {
     todo_dup: VecDeque<Packet>,
     todo_read: VecDeque<Packet>,
     todo_write: VecDeque<Packet>,
+    /// Segments from the remote that did not start where the last one ended, which are usually
+    /// retransmissions
+    retransmits: usize,
+    options: Options,
 }
 
 impl Handle {
@@ -118,6 +175,8 @@ struct Tcpd {
     ports: BTreeMap<u16, usize>,
     next_id: usize,
     handles: BTreeMap<usize, Handle>,
+    /// Handles of `tcp:stats` and of socket options, with what is left to read
+    info: BTreeMap<usize, Vec<u8>>,
     rng: OsRng,
 }
 
@@ -129,10 +188,29 @@ impl Tcpd {
             ports: BTreeMap::new(),
             next_id: 1,
             handles: BTreeMap::new(),
+            info: BTreeMap::new(),
             rng: OsRng::new().expect("tcpd: failed to open RNG")
         }
     }
 
+    fn stats_string(&self) -> String {
+        let mut string = format!("{:<22}{:<22}{:<12}{:<12}{:<12}{:<8}{:<8}{:<8}{}\n",
+                                 "LOCAL", "REMOTE", "STATE", "SEQ", "ACK", "RECVQ", "READS", "WRITES", "RETRANS");
+        for (_id, handle) in self.handles.iter() {
+            string.push_str(&format!("{:<22}{:<22}{:<12}{:<12}{:<12}{:<8}{:<8}{:<8}{}\n",
+                                     format!("{}:{}", handle.local.0.to_string(), handle.local.1),
+                                     format!("{}:{}", handle.remote.0.to_string(), handle.remote.1),
+                                     format!("{:?}", handle.state),
+                                     handle.seq,
+                                     handle.ack,
+                                     handle.data.len(),
+                                     handle.todo_read.len(),
+                                     handle.todo_write.len(),
+                                     handle.retransmits));
+        }
+        string
+    }
+
     fn scheme_event(&mut self) -> io::Result<()> {
         loop {
             let mut packet = Packet::default();
@@ -198,15 +276,22 @@ impl Tcpd {
                                     self.tcp_file.write(&ip.to_bytes())?;
                                 },
                                 State::Established => if tcp.header.flags.get() & (TCP_SYN | TCP_ACK) == TCP_ACK && tcp.header.ack_num.get() == handle.seq {
+                                    if ! tcp.data.is_empty() && tcp.header.sequence.get() != handle.ack {
+                                        handle.retransmits += 1;
+                                    }
                                     handle.ack = tcp.header.sequence.get();
 
                                     if ! tcp.data.is_empty() {
-                                        handle.data.push_back((ip.clone(), tcp.clone()));
-                                        handle.ack += tcp.data.len() as u32;
-
-                                        let tcp = handle.create_tcp(TCP_ACK, Vec::new());
-                                        let ip = handle.create_ip(self.rng.gen(), tcp.to_bytes());
-                                        self.tcp_file.write(&ip.to_bytes())?;
+                                        // Segments that do not fit are not acknowledged, so the remote sends them again
+                                        let queued = handle.data.iter().fold(0, |sum, &(ref _ip, ref tcp)| sum + tcp.data.len());
+                                        if handle.options.rcvbuf == 0 || queued + tcp.data.len() <= handle.options.rcvbuf {
+                                            handle.data.push_back((ip.clone(), tcp.clone()));
+                                            handle.ack += tcp.data.len() as u32;
+
+                                            let tcp = handle.create_tcp(TCP_ACK, Vec::new());
+                                            let ip = handle.create_ip(self.rng.gen(), tcp.to_bytes());
+                                            self.tcp_file.write(&ip.to_bytes())?;
+                                        }
                                     } else if tcp.header.flags.get() & TCP_FIN == TCP_FIN {
                                         handle.state = State::CloseWait;
 
@@ -304,7 +389,7 @@ impl Tcpd {
                         let handle = self.handles.remove(&file).unwrap();
 
                         let remove = if let Some(mut port) = self.ports.get_mut(&handle.local.1) {
-                            *port = *port + 1;
+                            *port = *port - 1;
                             *port == 0
                         } else {
                             false
@@ -338,6 +423,8 @@ impl Tcpd {
                                         todo_dup: VecDeque::new(),
                                         todo_read: VecDeque::new(),
                                         todo_write: VecDeque::new(),
+                                        retransmits: 0,
+                                        options: handle.options,
                                     };
 
                                     let tcp = new_handle.create_tcp(TCP_SYN | TCP_ACK, Vec::new());
@@ -385,8 +472,22 @@ impl SchemeMut for Tcpd {
     fn open(&mut self, url: &[u8], flags: usize, uid: u32, _gid: u32) -> Result<usize> {
         let path = str::from_utf8(url).or(Err(Error::new(EINVAL)))?;
 
+        if path == "stats" {
+            if uid != 0 {
+                return Err(Error::new(EACCES));
+            }
+
+            let id = self.next_id;
+            self.next_id += 1;
+
+            let data = self.stats_string().into_bytes();
+            self.info.insert(id, data);
+
+            return Ok(id);
+        }
+
         let mut parts = path.split("/");
-        let remote = parse_socket(parts.next().unwrap_or(""));
+        let remote = resolve_socket(parts.next().unwrap_or(""))?;
         let mut local = parse_socket(parts.next().unwrap_or(""));
 
         if local.1 == 0 {
@@ -397,7 +498,7 @@ impl SchemeMut for Tcpd {
             return Err(Error::new(EACCES));
         }
 
-        if self.ports.contains_key(&local.1) {
+        if self.handles.values().any(|handle| handle.local.1 == local.1 && ! handle.options.reuse_addr) {
             return Err(Error::new(EADDRINUSE));
         }
 
@@ -413,6 +514,8 @@ impl SchemeMut for Tcpd {
             todo_dup: VecDeque::new(),
             todo_read: VecDeque::new(),
             todo_write: VecDeque::new(),
+            retransmits: 0,
+            options: Options::new(),
         };
 
         if handle.is_connected() {
@@ -427,7 +530,7 @@ impl SchemeMut for Tcpd {
             handle.seq += 1;
         }
 
-        self.ports.insert(local.1, 1);
+        *self.ports.entry(local.1).or_insert(0) += 1;
 
         let id = self.next_id;
         self.next_id += 1;
@@ -440,6 +543,17 @@ impl SchemeMut for Tcpd {
     fn dup(&mut self, file: usize, buf: &[u8]) -> Result<usize> {
         let path = str::from_utf8(buf).or(Err(Error::new(EINVAL)))?;
 
+        if path == "options" {
+            let data = self.handles.get(&file).ok_or(Error::new(EBADF))?.options.to_string().into_bytes();
+
+            let id = self.next_id;
+            self.next_id += 1;
+
+            self.info.insert(id, data);
+
+            return Ok(id);
+        }
+
         let handle = {
             let mut handle = self.handles.get_mut(&file).ok_or(Error::new(EBADF))?;
 
@@ -455,6 +569,8 @@ impl SchemeMut for Tcpd {
                 todo_dup: VecDeque::new(),
                 todo_read: VecDeque::new(),
                 todo_write: VecDeque::new(),
+                retransmits: 0,
+                options: handle.options,
             };
 
             if path == "listen" {
@@ -483,6 +599,9 @@ impl SchemeMut for Tcpd {
                         true
                     }
                 });
+            } else if path.contains('=') {
+                new_handle.data = handle.data.clone();
+                new_handle.options.set(path)?;
             } else if path.is_empty() {
                 new_handle.data = handle.data.clone();
             } else if handle.is_connected() {
@@ -528,6 +647,13 @@ impl SchemeMut for Tcpd {
     }
 
     fn read(&mut self, file: usize, buf: &mut [u8]) -> Result<usize> {
+        if let Some(data) = self.info.get_mut(&file) {
+            let count = cmp::min(buf.len(), data.len());
+            buf[.. count].copy_from_slice(&data[.. count]);
+            data.drain(.. count);
+            return Ok(count);
+        }
+
         let mut handle = self.handles.get_mut(&file).ok_or(Error::new(EBADF))?;
 
         if ! handle.is_connected() {
@@ -600,6 +726,10 @@ impl SchemeMut for Tcpd {
     }
 
     fn close(&mut self, file: usize) -> Result<usize> {
+        if self.info.remove(&file).is_some() {
+            return Ok(0);
+        }
+
         let closed = {
             let mut handle = self.handles.get_mut(&file).ok_or(Error::new(EBADF))?;
 
@@ -636,7 +766,7 @@ impl SchemeMut for Tcpd {
             let handle = self.handles.remove(&file).ok_or(Error::new(EBADF))?;
 
             let remove = if let Some(mut port) = self.ports.get_mut(&handle.local.1) {
-                *port = *port + 1;
+                *port = *port - 1;
                 *port == 0
             } else {
                 false
@@ -0,0 +1,51 @@
+//! Temporary filesystem in memory, at `tmp:`
+//!
+//! Files and directories are kept in the memory of the daemon, up to a size limit, and are lost
+//! when it exits. Memory is given back when files are truncated or removed. The filesystem can be
+//! attached at /tmp by writing `mount tmp: /tmp` to sys:mounts
+//!
+//! Usage: tmpfsd [size in MB]
+
+extern crate syscall;
+
+use std::env;
+use std::fs::File;
+use std::io::{Read, Write};
+
+use syscall::data::Packet;
+use syscall::scheme::SchemeMut;
+
+use scheme::TmpScheme;
+
+mod scheme;
+
+/// Size limit if none is given, in MB
+const DEFAULT_SIZE: usize = 64;
+
+fn main() {
+    let size = match env::args().nth(1) {
+        Some(arg) => match arg.parse::<usize>() {
+            Ok(size) => size,
+            Err(_) => {
+                println!("tmpfs: invalid size {}", arg);
+                return;
+            }
+        },
+        None => DEFAULT_SIZE
+    };
+
+    // Daemonize
+    if unsafe { syscall::clone(0).unwrap() } == 0 {
+        let mut socket = File::create(":tmp").expect("tmpfs: failed to create tmp scheme");
+        let mut scheme = TmpScheme::new(size * 1024 * 1024);
+
+        println!("tmpfs: {} MB", size);
+
+        loop {
+            let mut packet = Packet::default();
+            socket.read(&mut packet).expect("tmpfs: failed to read events from tmp scheme");
+            scheme.handle(&mut packet);
+            socket.write(&packet).expect("tmpfs: failed to write responses to tmp scheme");
+        }
+    }
+}
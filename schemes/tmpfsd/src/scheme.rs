@@ -0,0 +1,564 @@
+use std::{cmp, str};
+use std::collections::BTreeMap;
+
+use syscall::data::Stat;
+use syscall::error::{Error, Result, EACCES, EBADF, EBUSY, EEXIST, EINVAL, EISDIR, ENOENT, ENOSPC, ENOTDIR, ENOTEMPTY};
+use syscall::flag::{MODE_DIR, MODE_FILE, O_ACCMODE, O_APPEND, O_CREAT, O_EXCL, O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY, SEEK_CUR, SEEK_END, SEEK_SET};
+use syscall::scheme::SchemeMut;
+
+pub const PAGE_SIZE: usize = 4096;
+
+/// The root directory, which always exists
+const ROOT: usize = 0;
+
+enum Data {
+    /// Pages of a file and its length. Pages that were never written take no memory
+    File(Vec<Option<Box<[u8; PAGE_SIZE]>>>, usize),
+    Directory(BTreeMap<String, usize>)
+}
+
+struct Node {
+    data: Data,
+    /// Permissions, without the file type
+    mode: u16,
+    uid: u32,
+    gid: u32,
+    /// Set when the node is removed from its directory, it is freed when the last handle closes
+    removed: bool,
+    handles: usize
+}
+
+struct Handle {
+    node: usize,
+    path: String,
+    flags: usize,
+    seek: usize,
+    /// Entries of a directory, listed when it was opened
+    listing: Option<Vec<u8>>
+}
+
+pub struct TmpScheme {
+    nodes: BTreeMap<usize, Node>,
+    handles: BTreeMap<usize, Handle>,
+    next_node: usize,
+    next_id: usize,
+    /// Limit on the number of pages, and the number used
+    pages: usize,
+    used: usize
+}
+
+/// Split a path into its parent and name
+fn split(path: &str) -> (&str, &str) {
+    match path.rfind('/') {
+        Some(i) => (&path[.. i], &path[i + 1 ..]),
+        None => ("", path)
+    }
+}
+
+/// Add unallocated pages to the end of a file, which read as zero
+fn grow(pages: &mut Vec<Option<Box<[u8; PAGE_SIZE]>>>, count: usize) {
+    while pages.len() < count {
+        pages.push(None);
+    }
+}
+
+fn can_read(flags: usize) -> bool {
+    flags & O_ACCMODE == O_RDONLY || flags & O_ACCMODE == O_RDWR
+}
+
+fn can_write(flags: usize) -> bool {
+    flags & O_ACCMODE == O_WRONLY || flags & O_ACCMODE == O_RDWR
+}
+
+impl Node {
+    fn new(data: Data, mode: u16, uid: u32, gid: u32) -> Node {
+        Node {
+            data: data,
+            mode: mode & 0o7777,
+            uid: uid,
+            gid: gid,
+            removed: false,
+            handles: 0
+        }
+    }
+
+    /// Check that a caller has all of the permissions in `access`, given as the bits for others
+    fn check(&self, uid: u32, gid: u32, access: u16) -> Result<()> {
+        let mut perm = self.mode & 0o7;
+        if self.uid == uid {
+            perm |= (self.mode >> 6) & 0o7;
+        }
+        if self.gid == gid {
+            perm |= (self.mode >> 3) & 0o7;
+        }
+        if uid == 0 || perm & access == access {
+            Ok(())
+        } else {
+            Err(Error::new(EACCES))
+        }
+    }
+
+    fn is_dir(&self) -> bool {
+        match self.data {
+            Data::File(..) => false,
+            Data::Directory(_) => true
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self.data {
+            Data::File(_, len) => len,
+            Data::Directory(ref entries) => entries.len()
+        }
+    }
+}
+
+impl TmpScheme {
+    pub fn new(size: usize) -> TmpScheme {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(ROOT, Node::new(Data::Directory(BTreeMap::new()), 0o1777, 0, 0));
+
+        TmpScheme {
+            nodes: nodes,
+            handles: BTreeMap::new(),
+            next_node: ROOT + 1,
+            next_id: 0,
+            pages: size / PAGE_SIZE,
+            used: 0
+        }
+    }
+
+    fn node(&self, node: usize) -> Result<&Node> {
+        self.nodes.get(&node).ok_or(Error::new(EBADF))
+    }
+
+    fn node_mut(&mut self, node: usize) -> Result<&mut Node> {
+        self.nodes.get_mut(&node).ok_or(Error::new(EBADF))
+    }
+
+    fn lookup(&self, path: &str) -> Result<usize> {
+        let mut node = ROOT;
+        for name in path.split('/').filter(|name| ! name.is_empty()) {
+            node = match self.node(node)?.data {
+                Data::Directory(ref entries) => *entries.get(name).ok_or(Error::new(ENOENT))?,
+                Data::File(..) => return Err(Error::new(ENOTDIR))
+            };
+        }
+        Ok(node)
+    }
+
+    /// Find the directory that will hold `path`, checking that the caller may change it
+    fn parent(&self, path: &str, uid: u32, gid: u32) -> Result<usize> {
+        let (parent_path, name) = split(path);
+        if name.is_empty() {
+            return Err(Error::new(EINVAL));
+        }
+
+        let parent = self.lookup(parent_path)?;
+        let node = self.node(parent)?;
+        if ! node.is_dir() {
+            return Err(Error::new(ENOTDIR));
+        }
+        node.check(uid, gid, 0o3)?;
+        Ok(parent)
+    }
+
+    /// Add a node to a directory
+    fn create(&mut self, path: &str, node: Node, uid: u32, gid: u32) -> Result<usize> {
+        let parent = self.parent(path, uid, gid)?;
+
+        let id = self.next_node;
+        self.next_node += 1;
+
+        if let Data::Directory(ref mut entries) = self.node_mut(parent)?.data {
+            entries.insert(split(path).1.to_string(), id);
+        }
+        self.nodes.insert(id, node);
+        Ok(id)
+    }
+
+    /// Remove a node from its directory, freeing it unless it is open
+    fn remove(&mut self, path: &str, uid: u32, gid: u32) -> Result<()> {
+        let parent = self.parent(path, uid, gid)?;
+        let name = split(path).1;
+
+        let node = self.lookup(path)?;
+        {
+            let parent_node = self.node(parent)?;
+            let node = self.node(node)?;
+            // Only the owner of a file may remove it from a sticky directory
+            if parent_node.mode & 0o1000 == 0o1000 && uid != 0 && uid != node.uid && uid != parent_node.uid {
+                return Err(Error::new(EACCES));
+            }
+        }
+
+        if let Data::Directory(ref mut entries) = self.node_mut(parent)?.data {
+            entries.remove(name);
+        }
+        self.node_mut(node)?.removed = true;
+        self.release(node);
+        Ok(())
+    }
+
+    /// Free a node if it is removed and no longer open
+    fn release(&mut self, node: usize) {
+        let free = self.nodes.get(&node).map_or(false, |node| node.removed && node.handles == 0);
+        if free {
+            if let Some(Node { data: Data::File(pages, _), .. }) = self.nodes.remove(&node) {
+                self.used -= pages.iter().filter(|page| page.is_some()).count();
+            }
+        }
+    }
+
+    /// Change the length of a file, freeing the pages past the end
+    fn truncate(&mut self, node: usize, len: usize) -> Result<()> {
+        let mut freed = 0;
+        if let Data::File(ref mut pages, ref mut file_len) = self.nodes.get_mut(&node).ok_or(Error::new(EBADF))?.data {
+            let count = (len + PAGE_SIZE - 1) / PAGE_SIZE;
+            while pages.len() > count {
+                if pages.pop().and_then(|page| page).is_some() {
+                    freed += 1;
+                }
+            }
+            pages.shrink_to_fit();
+
+            // Bytes past the end must read as zero if the file grows again
+            if len % PAGE_SIZE != 0 {
+                if let Some(&mut Some(ref mut page)) = pages.last_mut() {
+                    for byte in page[len % PAGE_SIZE ..].iter_mut() {
+                        *byte = 0;
+                    }
+                }
+            }
+
+            *file_len = len;
+        } else {
+            return Err(Error::new(EISDIR));
+        }
+        self.used -= freed;
+        Ok(())
+    }
+
+    fn read_file(&self, node: usize, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        if let Data::File(ref pages, len) = self.node(node)?.data {
+            let mut count = 0;
+            while count < buf.len() && offset + count < len {
+                let position = offset + count;
+                let page_offset = position % PAGE_SIZE;
+                let size = cmp::min(cmp::min(PAGE_SIZE - page_offset, buf.len() - count), len - position);
+
+                match pages[position / PAGE_SIZE] {
+                    Some(ref page) => buf[count .. count + size].copy_from_slice(&page[page_offset .. page_offset + size]),
+                    None => for byte in buf[count .. count + size].iter_mut() {
+                        *byte = 0;
+                    }
+                }
+                count += size;
+            }
+            Ok(count)
+        } else {
+            Err(Error::new(EISDIR))
+        }
+    }
+
+    fn write_file(&mut self, node: usize, offset: usize, buf: &[u8]) -> Result<usize> {
+        let limit = self.pages;
+        let mut used = self.used;
+
+        let result = if let Data::File(ref mut pages, ref mut len) = self.nodes.get_mut(&node).ok_or(Error::new(EBADF))?.data {
+            let end = offset + buf.len();
+            grow(pages, (end + PAGE_SIZE - 1) / PAGE_SIZE);
+
+            let mut count = 0;
+            while count < buf.len() {
+                let position = offset + count;
+                let page_offset = position % PAGE_SIZE;
+                let size = cmp::min(PAGE_SIZE - page_offset, buf.len() - count);
+
+                let page = &mut pages[position / PAGE_SIZE];
+                if page.is_none() {
+                    if used >= limit {
+                        break;
+                    }
+                    used += 1;
+                    *page = Some(Box::new([0; PAGE_SIZE]));
+                }
+                if let Some(ref mut page) = *page {
+                    page[page_offset .. page_offset + size].copy_from_slice(&buf[count .. count + size]);
+                }
+                count += size;
+            }
+
+            *len = cmp::max(*len, offset + count);
+            // Pages reserved past what could be written are not kept
+            let needed = (*len + PAGE_SIZE - 1) / PAGE_SIZE;
+            if pages.len() > needed {
+                pages.truncate(needed);
+            }
+
+            if count == 0 && ! buf.is_empty() {
+                Err(Error::new(ENOSPC))
+            } else {
+                Ok(count)
+            }
+        } else {
+            Err(Error::new(EISDIR))
+        };
+
+        self.used = used;
+        result
+    }
+
+    fn listing(&self, node: usize) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        if let Data::Directory(ref entries) = self.node(node)?.data {
+            for (name, &child) in entries.iter() {
+                if ! data.is_empty() {
+                    data.push(b'\n');
+                }
+                data.extend_from_slice(name.as_bytes());
+                if self.node(child)?.is_dir() {
+                    data.push(b'/');
+                }
+            }
+        }
+        Ok(data)
+    }
+
+    fn insert(&mut self, handle: Handle) -> Result<usize> {
+        self.node_mut(handle.node)?.handles += 1;
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.handles.insert(id, handle);
+        Ok(id)
+    }
+}
+
+impl SchemeMut for TmpScheme {
+    fn open(&mut self, path: &[u8], flags: usize, uid: u32, gid: u32) -> Result<usize> {
+        let path = str::from_utf8(path).or(Err(Error::new(ENOENT)))?.trim_matches('/');
+
+        let node = match self.lookup(path) {
+            Ok(node) => {
+                if flags & (O_CREAT | O_EXCL) == O_CREAT | O_EXCL {
+                    return Err(Error::new(EEXIST));
+                }
+
+                let mut access = 0;
+                if can_read(flags) {
+                    access |= 0o4;
+                }
+                if can_write(flags) || flags & O_TRUNC == O_TRUNC {
+                    if self.node(node)?.is_dir() {
+                        return Err(Error::new(EISDIR));
+                    }
+                    access |= 0o2;
+                }
+                self.node(node)?.check(uid, gid, access)?;
+
+                if flags & O_TRUNC == O_TRUNC {
+                    self.truncate(node, 0)?;
+                }
+                node
+            },
+            Err(err) => if err == Error::new(ENOENT) && flags & O_CREAT == O_CREAT {
+                // The permissions of a new file are passed with the flags
+                let node = Node::new(Data::File(Vec::new(), 0), (flags & 0o7777) as u16, uid, gid);
+                self.create(path, node, uid, gid)?
+            } else {
+                return Err(err);
+            }
+        };
+
+        let listing = if self.node(node)?.is_dir() {
+            Some(self.listing(node)?)
+        } else {
+            None
+        };
+
+        self.insert(Handle {
+            node: node,
+            path: path.to_string(),
+            flags: flags,
+            seek: 0,
+            listing: listing
+        })
+    }
+
+    fn mkdir(&mut self, path: &[u8], mode: u16, uid: u32, gid: u32) -> Result<usize> {
+        let path = str::from_utf8(path).or(Err(Error::new(ENOENT)))?.trim_matches('/');
+        if self.lookup(path).is_ok() {
+            return Err(Error::new(EEXIST));
+        }
+
+        self.create(path, Node::new(Data::Directory(BTreeMap::new()), mode, uid, gid), uid, gid).and(Ok(0))
+    }
+
+    fn rmdir(&mut self, path: &[u8], uid: u32, gid: u32) -> Result<usize> {
+        let path = str::from_utf8(path).or(Err(Error::new(ENOENT)))?.trim_matches('/');
+        let node = self.lookup(path)?;
+        if node == ROOT {
+            return Err(Error::new(EBUSY));
+        }
+        match self.node(node)?.data {
+            Data::Directory(ref entries) => if ! entries.is_empty() {
+                return Err(Error::new(ENOTEMPTY));
+            },
+            Data::File(..) => return Err(Error::new(ENOTDIR))
+        }
+
+        self.remove(path, uid, gid).and(Ok(0))
+    }
+
+    fn unlink(&mut self, path: &[u8], uid: u32, gid: u32) -> Result<usize> {
+        let path = str::from_utf8(path).or(Err(Error::new(ENOENT)))?.trim_matches('/');
+        let node = self.lookup(path)?;
+        if self.node(node)?.is_dir() {
+            return Err(Error::new(EISDIR));
+        }
+
+        self.remove(path, uid, gid).and(Ok(0))
+    }
+
+    fn dup(&mut self, id: usize, _buf: &[u8]) -> Result<usize> {
+        let handle = {
+            let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+            Handle {
+                node: handle.node,
+                path: handle.path.clone(),
+                flags: handle.flags,
+                seek: handle.seek,
+                listing: handle.listing.clone()
+            }
+        };
+
+        self.insert(handle)
+    }
+
+    fn read(&mut self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let (node, offset) = match *self.handles.get_mut(&id).ok_or(Error::new(EBADF))? {
+            Handle { listing: Some(ref data), ref mut seek, .. } => {
+                let count = cmp::min(buf.len(), data.len() - *seek);
+                buf[.. count].copy_from_slice(&data[*seek .. *seek + count]);
+                *seek += count;
+                return Ok(count);
+            },
+            Handle { node, seek, flags, .. } => if can_read(flags) {
+                (node, seek)
+            } else {
+                return Err(Error::new(EBADF));
+            }
+        };
+
+        let count = self.read_file(node, offset, buf)?;
+        if let Some(handle) = self.handles.get_mut(&id) {
+            handle.seek += count;
+        }
+        Ok(count)
+    }
+
+    fn write(&mut self, id: usize, buf: &[u8]) -> Result<usize> {
+        let (node, seek, flags) = {
+            let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+            (handle.node, handle.seek, handle.flags)
+        };
+        if ! can_write(flags) {
+            return Err(Error::new(EBADF));
+        }
+
+        let offset = if flags & O_APPEND == O_APPEND {
+            self.node(node)?.size()
+        } else {
+            seek
+        };
+
+        let count = self.write_file(node, offset, buf)?;
+        if let Some(handle) = self.handles.get_mut(&id) {
+            handle.seek = offset + count;
+        }
+        Ok(count)
+    }
+
+    fn seek(&mut self, id: usize, pos: usize, whence: usize) -> Result<usize> {
+        let len = {
+            let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+            match handle.listing {
+                Some(ref data) => data.len(),
+                None => self.node(handle.node)?.size()
+            }
+        };
+
+        let handle = self.handles.get_mut(&id).ok_or(Error::new(EBADF))?;
+        // Files may be extended by seeking past the end and writing
+        handle.seek = match whence {
+            SEEK_SET => pos,
+            SEEK_CUR => cmp::max(0, handle.seek as isize + pos as isize) as usize,
+            SEEK_END => cmp::max(0, len as isize + pos as isize) as usize,
+            _ => return Err(Error::new(EINVAL))
+        };
+        if handle.listing.is_some() {
+            handle.seek = cmp::min(handle.seek, len);
+        }
+
+        Ok(handle.seek)
+    }
+
+    fn fpath(&mut self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+        let path = format!("tmp:/{}", handle.path);
+
+        let count = cmp::min(buf.len(), path.len());
+        buf[.. count].copy_from_slice(&path.as_bytes()[.. count]);
+        Ok(count)
+    }
+
+    fn fstat(&mut self, id: usize, stat: &mut Stat) -> Result<usize> {
+        let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+        let node = self.node(handle.node)?;
+
+        stat.st_mode = node.mode | if node.is_dir() { MODE_DIR } else { MODE_FILE };
+        stat.st_uid = node.uid;
+        stat.st_gid = node.gid;
+        stat.st_size = match handle.listing {
+            Some(ref data) => data.len() as u64,
+            None => node.size() as u64
+        };
+        Ok(0)
+    }
+
+    fn fsync(&mut self, id: usize) -> Result<usize> {
+        self.handles.get(&id).ok_or(Error::new(EBADF)).and(Ok(0))
+    }
+
+    fn ftruncate(&mut self, id: usize, len: usize) -> Result<usize> {
+        let (node, flags) = {
+            let handle = self.handles.get(&id).ok_or(Error::new(EBADF))?;
+            (handle.node, handle.flags)
+        };
+        if ! can_write(flags) {
+            return Err(Error::new(EBADF));
+        }
+
+        // Growing a file only changes its length, the new pages are allocated when written
+        let pages = (len + PAGE_SIZE - 1) / PAGE_SIZE;
+        if let Data::File(ref mut file_pages, ref mut file_len) = self.node_mut(node)?.data {
+            if len > *file_len {
+                grow(file_pages, pages);
+                *file_len = len;
+                return Ok(0);
+            }
+        }
+
+        self.truncate(node, len).and(Ok(0))
+    }
+
+    fn close(&mut self, id: usize) -> Result<usize> {
+        let handle = self.handles.remove(&id).ok_or(Error::new(EBADF))?;
+        if let Some(node) = self.nodes.get_mut(&handle.node) {
+            node.handles -= 1;
+        }
+        self.release(handle.node);
+        Ok(0)
+    }
+}
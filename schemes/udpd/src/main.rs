@@ -8,14 +8,14 @@ use std::collections::{BTreeMap, VecDeque};
 use std::cell::RefCell;
 use std::fs::File;
 use std::io::{self, Read, Write};
-use std::{mem, slice, str};
+use std::{cmp, mem, slice, str};
 use std::os::unix::io::FromRawFd;
 use std::rc::Rc;
 
 use event::EventQueue;
 use netutils::{n16, Ipv4, Ipv4Addr, Ipv4Header, Udp, UdpHeader, Checksum};
 use syscall::data::Packet;
-use syscall::error::{Error, Result, EACCES, EADDRINUSE, EBADF, EIO, EINVAL, EMSGSIZE, ENOTCONN, EWOULDBLOCK};
+use syscall::error::{Error, Result, EACCES, EADDRINUSE, EBADF, EIO, EINVAL, EMSGSIZE, ENOPROTOOPT, ENOTCONN, EWOULDBLOCK};
 use syscall::flag::{EVENT_READ, O_CREAT, O_RDWR, O_NONBLOCK};
 use syscall::scheme::SchemeMut;
 
@@ -26,6 +26,41 @@ fn parse_socket(socket: &str) -> (Ipv4Addr, u16) {
     (host, port)
 }
 
+/// Socket options, set by duplicating a handle with `NAME=VALUE`, and listed by duplicating it
+/// with `options`
+#[derive(Copy, Clone)]
+struct Options {
+    /// Let other handles open the local port while this one has it
+    reuse_addr: bool,
+    /// Limit on received data waiting to be read, in bytes, or zero for no limit
+    rcvbuf: usize,
+}
+
+impl Options {
+    fn new() -> Options {
+        Options {
+            reuse_addr: false,
+            rcvbuf: 0,
+        }
+    }
+
+    fn set(&mut self, option: &str) -> Result<()> {
+        let mut parts = option.splitn(2, '=');
+        let name = parts.next().unwrap_or("");
+        let value = parts.next().unwrap_or("").parse::<usize>().or(Err(Error::new(EINVAL)))?;
+        match name {
+            "SO_REUSEADDR" => self.reuse_addr = value != 0,
+            "SO_RCVBUF" => self.rcvbuf = value,
+            _ => return Err(Error::new(ENOPROTOOPT))
+        }
+        Ok(())
+    }
+
+    fn to_string(&self) -> String {
+        format!("SO_REUSEADDR={}\nSO_RCVBUF={}\n", self.reuse_addr as usize, self.rcvbuf)
+    }
+}
+
 struct Handle {
     local: (Ipv4Addr, u16),
     remote: (Ipv4Addr, u16),
@@ -33,6 +68,7 @@ struct Handle {
     events: usize,
     data: VecDeque<Vec<u8>>,
     todo: VecDeque<Packet>,
+    options: Options,
 }
 
 struct Udpd {
@@ -41,6 +77,8 @@ struct Udpd {
     ports: BTreeMap<u16, usize>,
     next_id: usize,
     handles: BTreeMap<usize, Handle>,
+    /// Handles of socket options, with what is left to read
+    info: BTreeMap<usize, Vec<u8>>,
     rng: OsRng,
 }
 
@@ -52,6 +90,7 @@ impl Udpd {
             ports: BTreeMap::new(),
             next_id: 1,
             handles: BTreeMap::new(),
+            info: BTreeMap::new(),
             rng: OsRng::new().expect("udpd: failed to open RNG")
         }
     }
@@ -97,6 +136,12 @@ impl Udpd {
                             // Remote port not set or UDP src matches
                             && (handle.remote.1 == 0 || udp.header.src.get() == handle.remote.1)
                         {
+                            // Datagrams that do not fit are dropped
+                            let queued = handle.data.iter().fold(0, |sum, data| sum + data.len());
+                            if handle.options.rcvbuf > 0 && queued + udp.data.len() > handle.options.rcvbuf {
+                                continue;
+                            }
+
                             handle.data.push_back(udp.data.clone());
 
                             while ! handle.todo.is_empty() && ! handle.data.is_empty() {
@@ -154,11 +199,11 @@ impl SchemeMut for Udpd {
             return Err(Error::new(EACCES));
         }
 
-        if self.ports.contains_key(&local.1) {
+        if self.handles.values().any(|handle| handle.local.1 == local.1 && ! handle.options.reuse_addr) {
             return Err(Error::new(EADDRINUSE));
         }
 
-        self.ports.insert(local.1, 1);
+        *self.ports.entry(local.1).or_insert(0) += 1;
 
         let id = self.next_id;
         self.next_id += 1;
@@ -170,12 +215,26 @@ impl SchemeMut for Udpd {
             events: 0,
             data: VecDeque::new(),
             todo: VecDeque::new(),
+            options: Options::new(),
         });
 
         Ok(id)
     }
 
     fn dup(&mut self, file: usize, buf: &[u8]) -> Result<usize> {
+        let path = str::from_utf8(buf).or(Err(Error::new(EINVAL)))?;
+
+        if path == "options" {
+            let data = self.handles.get(&file).ok_or(Error::new(EBADF))?.options.to_string().into_bytes();
+
+            let id = self.next_id;
+            self.next_id += 1;
+
+            self.info.insert(id, data);
+
+            return Ok(id);
+        }
+
         let mut handle = {
             let handle = self.handles.get(&file).ok_or(Error::new(EBADF))?;
             Handle {
@@ -185,12 +244,13 @@ impl SchemeMut for Udpd {
                 events: 0,
                 data: handle.data.clone(),
                 todo: VecDeque::new(),
+                options: handle.options,
             }
         };
 
-        let path = str::from_utf8(buf).or(Err(Error::new(EINVAL)))?;
-
-        if handle.remote.0 == Ipv4Addr::NULL || handle.remote.1 == 0 {
+        if path.contains('=') {
+            handle.options.set(path)?;
+        } else if handle.remote.0 == Ipv4Addr::NULL || handle.remote.1 == 0 {
             handle.remote = parse_socket(path);
         }
 
@@ -207,6 +267,13 @@ impl SchemeMut for Udpd {
     }
 
     fn read(&mut self, file: usize, buf: &mut [u8]) -> Result<usize> {
+        if let Some(data) = self.info.get_mut(&file) {
+            let count = cmp::min(buf.len(), data.len());
+            buf[.. count].copy_from_slice(&data[.. count]);
+            data.drain(.. count);
+            return Ok(count);
+        }
+
         let mut handle = self.handles.get_mut(&file).ok_or(Error::new(EBADF))?;
 
         if handle.remote.0 == Ipv4Addr::NULL || handle.remote.1 == 0 {
@@ -299,10 +366,14 @@ impl SchemeMut for Udpd {
     }
 
     fn close(&mut self, file: usize) -> Result<usize> {
+        if self.info.remove(&file).is_some() {
+            return Ok(0);
+        }
+
         let handle = self.handles.remove(&file).ok_or(Error::new(EBADF))?;
 
         let remove = if let Some(mut port) = self.ports.get_mut(&handle.local.1) {
-            *port = *port + 1;
+            *port = *port - 1;
             *port == 0
         } else {
             false
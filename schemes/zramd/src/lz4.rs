@@ -0,0 +1,182 @@
+//! LZ4 block format, without the frame around it
+//!
+//! Each sequence is a token, literals copied as they are, and a match copied from earlier output.
+//! The high half of the token is the number of literals, the low half is the match length minus
+//! four, and either is followed by extra length bytes when it is 15
+
+use std::cmp;
+
+const MIN_MATCH: usize = 4;
+/// The last five bytes are always literals
+const LAST_LITERALS: usize = 5;
+/// No match starts in the last twelve bytes
+const MF_LIMIT: usize = 12;
+const MAX_OFFSET: usize = 65535;
+const HASH_LOG: usize = 12;
+
+fn read_u32(data: &[u8], i: usize) -> u32 {
+    data[i] as u32 | (data[i + 1] as u32) << 8 | (data[i + 2] as u32) << 16 | (data[i + 3] as u32) << 24
+}
+
+fn hash(value: u32) -> usize {
+    (value.wrapping_mul(2654435761) >> (32 - HASH_LOG)) as usize
+}
+
+struct Output<'a> {
+    data: &'a mut [u8],
+    len: usize
+}
+
+impl<'a> Output<'a> {
+    fn push(&mut self, byte: u8) -> Result<(), ()> {
+        *self.data.get_mut(self.len).ok_or(())? = byte;
+        self.len += 1;
+        Ok(())
+    }
+
+    fn extend(&mut self, bytes: &[u8]) -> Result<(), ()> {
+        let end = self.len + bytes.len();
+        if end > self.data.len() {
+            return Err(());
+        }
+        self.data[self.len .. end].copy_from_slice(bytes);
+        self.len = end;
+        Ok(())
+    }
+
+    /// Write the part of a length that did not fit in the token
+    fn push_length(&mut self, mut length: usize) -> Result<(), ()> {
+        while length >= 255 {
+            self.push(255)?;
+            length -= 255;
+        }
+        self.push(length as u8)
+    }
+
+    fn sequence(&mut self, literals: &[u8], offset: usize, match_len: usize) -> Result<(), ()> {
+        let match_code = match_len - MIN_MATCH;
+        let token = (cmp::min(literals.len(), 15) << 4 | cmp::min(match_code, 15)) as u8;
+        self.push(token)?;
+        if literals.len() >= 15 {
+            self.push_length(literals.len() - 15)?;
+        }
+        self.extend(literals)?;
+        self.push(offset as u8)?;
+        self.push((offset >> 8) as u8)?;
+        if match_code >= 15 {
+            self.push_length(match_code - 15)?;
+        }
+        Ok(())
+    }
+
+    fn last_literals(&mut self, literals: &[u8]) -> Result<(), ()> {
+        self.push((cmp::min(literals.len(), 15) << 4) as u8)?;
+        if literals.len() >= 15 {
+            self.push_length(literals.len() - 15)?;
+        }
+        self.extend(literals)
+    }
+}
+
+/// Compress `input` into `output`, returning the compressed size, or `None` if it does not fit
+pub fn compress(input: &[u8], output: &mut [u8]) -> Option<usize> {
+    compress_inner(input, output).ok()
+}
+
+fn compress_inner(input: &[u8], output: &mut [u8]) -> Result<usize, ()> {
+    let mut output = Output {
+        data: output,
+        len: 0
+    };
+
+    // Positions plus one of the last four bytes with each hash, zero if there are none
+    let mut table = [0usize; 1 << HASH_LOG];
+    let mut anchor = 0;
+    let mut pos = 0;
+
+    if input.len() > MF_LIMIT {
+        let limit = input.len() - MF_LIMIT;
+        while pos < limit {
+            let value = read_u32(input, pos);
+            let entry = &mut table[hash(value)];
+            let candidate = *entry;
+            *entry = pos + 1;
+
+            if candidate > 0 && pos - (candidate - 1) <= MAX_OFFSET && read_u32(input, candidate - 1) == value {
+                let start = candidate - 1;
+                let mut len = MIN_MATCH;
+                while pos + len < input.len() - LAST_LITERALS && input[start + len] == input[pos + len] {
+                    len += 1;
+                }
+
+                output.sequence(&input[anchor .. pos], pos - start, len)?;
+                pos += len;
+                anchor = pos;
+            } else {
+                pos += 1;
+            }
+        }
+    }
+
+    output.last_literals(&input[anchor ..])?;
+    Ok(output.len)
+}
+
+/// Read a length that continues after the token
+fn read_length(input: &[u8], i: &mut usize, mut length: usize) -> Result<usize, ()> {
+    if length == 15 {
+        loop {
+            let byte = *input.get(*i).ok_or(())?;
+            *i += 1;
+            length += byte as usize;
+            if byte != 255 {
+                break;
+            }
+        }
+    }
+    Ok(length)
+}
+
+/// Decompress `input` into `output`, returning the decompressed size, or `None` if the input is
+/// not valid or the output does not fit
+pub fn decompress(input: &[u8], output: &mut [u8]) -> Option<usize> {
+    decompress_inner(input, output).ok()
+}
+
+fn decompress_inner(input: &[u8], output: &mut [u8]) -> Result<usize, ()> {
+    let mut i = 0;
+    let mut o = 0;
+
+    loop {
+        let token = *input.get(i).ok_or(())?;
+        i += 1;
+
+        let literals = read_length(input, &mut i, (token >> 4) as usize)?;
+        if i + literals > input.len() || o + literals > output.len() {
+            return Err(());
+        }
+        output[o .. o + literals].copy_from_slice(&input[i .. i + literals]);
+        i += literals;
+        o += literals;
+
+        if i == input.len() {
+            return Ok(o);
+        }
+
+        let offset = *input.get(i).ok_or(())? as usize | (*input.get(i + 1).ok_or(())? as usize) << 8;
+        i += 2;
+        if offset == 0 || offset > o {
+            return Err(());
+        }
+
+        let match_len = read_length(input, &mut i, (token & 0xF) as usize)? + MIN_MATCH;
+        if o + match_len > output.len() {
+            return Err(());
+        }
+        // The match may overlap the bytes it is writing, so it is copied forwards one at a time
+        for _ in 0..match_len {
+            output[o] = output[o - offset];
+            o += 1;
+        }
+    }
+}
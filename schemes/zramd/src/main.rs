@@ -0,0 +1,52 @@
+//! Compressed RAM block device, at `zram:`
+//!
+//! Pages are compressed with LZ4 and kept in memory, pages of zeros take no memory at all. This
+//! gives memory constrained machines a fast swap target. Compression statistics are in
+//! `zram:stats`
+//!
+//! Usage: zramd [size in MB]
+
+extern crate syscall;
+
+use std::env;
+use std::fs::File;
+use std::io::{Read, Write};
+
+use syscall::data::Packet;
+use syscall::scheme::SchemeMut;
+
+use scheme::ZramScheme;
+
+mod lz4;
+mod scheme;
+
+/// Size of the device if none is given, in MB
+const DEFAULT_SIZE: usize = 64;
+
+fn main() {
+    let size = match env::args().nth(1) {
+        Some(arg) => match arg.parse::<usize>() {
+            Ok(size) => size,
+            Err(_) => {
+                println!("zram: invalid size {}", arg);
+                return;
+            }
+        },
+        None => DEFAULT_SIZE
+    };
+
+    // Daemonize
+    if unsafe { syscall::clone(0).unwrap() } == 0 {
+        let mut socket = File::create(":zram").expect("zram: failed to create zram scheme");
+        let mut scheme = ZramScheme::new(size * 1024 * 1024);
+
+        println!("zram: {} MB", size);
+
+        loop {
+            let mut packet = Packet::default();
+            socket.read(&mut packet).expect("zram: failed to read events from zram scheme");
+            scheme.handle(&mut packet);
+            socket.write(&packet).expect("zram: failed to write responses to zram scheme");
+        }
+    }
+}
@@ -0,0 +1,285 @@
+use std::{cmp, str};
+use std::collections::BTreeMap;
+
+use syscall::data::Stat;
+use syscall::error::{Error, Result, EACCES, EBADF, EINVAL, EIO, ENOENT};
+use syscall::flag::{MODE_FILE, SEEK_CUR, SEEK_END, SEEK_SET};
+use syscall::scheme::SchemeMut;
+
+use lz4;
+
+pub const PAGE_SIZE: usize = 4096;
+
+/// A page of the device
+enum Page {
+    /// Never written, or written with zeros, which takes no memory
+    Zero,
+    Compressed(Box<[u8]>),
+    /// Data that did not get smaller when compressed
+    Raw(Box<[u8]>)
+}
+
+enum Handle {
+    Device {
+        seek: usize
+    },
+    Stats {
+        data: Vec<u8>,
+        seek: usize
+    }
+}
+
+pub struct ZramScheme {
+    pages: Vec<Page>,
+    handles: BTreeMap<usize, Handle>,
+    next_id: usize,
+    /// Pages read and written
+    reads: usize,
+    writes: usize
+}
+
+impl ZramScheme {
+    pub fn new(size: usize) -> ZramScheme {
+        let mut pages = Vec::with_capacity(size / PAGE_SIZE);
+        for _ in 0..size / PAGE_SIZE {
+            pages.push(Page::Zero);
+        }
+
+        ZramScheme {
+            pages: pages,
+            handles: BTreeMap::new(),
+            next_id: 0,
+            reads: 0,
+            writes: 0
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.pages.len() * PAGE_SIZE
+    }
+
+    fn load(&mut self, index: usize, buf: &mut [u8; PAGE_SIZE]) -> Result<()> {
+        self.reads += 1;
+        match self.pages[index] {
+            Page::Zero => {
+                for byte in buf.iter_mut() {
+                    *byte = 0;
+                }
+            },
+            Page::Compressed(ref data) => {
+                if lz4::decompress(data, buf) != Some(PAGE_SIZE) {
+                    println!("zram: page {} is corrupt", index);
+                    return Err(Error::new(EIO));
+                }
+            },
+            Page::Raw(ref data) => buf.copy_from_slice(data)
+        }
+        Ok(())
+    }
+
+    fn store(&mut self, index: usize, buf: &[u8; PAGE_SIZE]) {
+        self.writes += 1;
+        self.pages[index] = if buf.iter().all(|&byte| byte == 0) {
+            Page::Zero
+        } else {
+            let mut compressed = [0; PAGE_SIZE];
+            match lz4::compress(buf, &mut compressed[.. PAGE_SIZE - 1]) {
+                Some(len) => Page::Compressed(compressed[.. len].to_vec().into_boxed_slice()),
+                None => Page::Raw(buf.to_vec().into_boxed_slice())
+            }
+        };
+    }
+
+    fn read_device(&mut self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let mut page = [0; PAGE_SIZE];
+        let mut count = 0;
+        while count < buf.len() && offset + count < self.size() {
+            let position = offset + count;
+            let page_offset = position % PAGE_SIZE;
+            let len = cmp::min(PAGE_SIZE - page_offset, buf.len() - count);
+
+            self.load(position / PAGE_SIZE, &mut page)?;
+            buf[count .. count + len].copy_from_slice(&page[page_offset .. page_offset + len]);
+            count += len;
+        }
+        Ok(count)
+    }
+
+    fn write_device(&mut self, offset: usize, buf: &[u8]) -> Result<usize> {
+        let mut page = [0; PAGE_SIZE];
+        let mut count = 0;
+        while count < buf.len() && offset + count < self.size() {
+            let position = offset + count;
+            let page_offset = position % PAGE_SIZE;
+            let len = cmp::min(PAGE_SIZE - page_offset, buf.len() - count);
+
+            // Part of a page is changed by reading it first
+            if len < PAGE_SIZE {
+                self.load(position / PAGE_SIZE, &mut page)?;
+            }
+            page[page_offset .. page_offset + len].copy_from_slice(&buf[count .. count + len]);
+            self.store(position / PAGE_SIZE, &page);
+            count += len;
+        }
+        Ok(count)
+    }
+
+    fn stats(&self) -> Vec<u8> {
+        let mut zero = 0;
+        let mut compressed = 0;
+        let mut raw = 0;
+        let mut stored = 0;
+        for page in self.pages.iter() {
+            match *page {
+                Page::Zero => zero += 1,
+                Page::Compressed(ref data) => {
+                    compressed += 1;
+                    stored += data.len();
+                },
+                Page::Raw(ref data) => {
+                    raw += 1;
+                    stored += data.len();
+                }
+            }
+        }
+
+        let original = (compressed + raw) * PAGE_SIZE;
+        let ratio = if stored > 0 { original * 100 / stored } else { 100 };
+
+        format!("Size: {} KB\nOriginal: {} KB\nStored: {} KB\nRatio: {}.{:02}\nZero Pages: {}\nCompressed Pages: {}\nRaw Pages: {}\nReads: {}\nWrites: {}\n",
+                self.size() / 1024,
+                original / 1024,
+                stored / 1024,
+                ratio / 100, ratio % 100,
+                zero,
+                compressed,
+                raw,
+                self.reads,
+                self.writes).into_bytes()
+    }
+}
+
+impl SchemeMut for ZramScheme {
+    fn open(&mut self, path: &[u8], _flags: usize, uid: u32, _gid: u32) -> Result<usize> {
+        let path = str::from_utf8(path).or(Err(Error::new(ENOENT)))?.trim_matches('/');
+
+        let handle = match path {
+            "" => if uid == 0 {
+                Handle::Device {
+                    seek: 0
+                }
+            } else {
+                return Err(Error::new(EACCES));
+            },
+            "stats" => Handle::Stats {
+                data: self.stats(),
+                seek: 0
+            },
+            _ => return Err(Error::new(ENOENT))
+        };
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.handles.insert(id, handle);
+        Ok(id)
+    }
+
+    fn dup(&mut self, id: usize, _buf: &[u8]) -> Result<usize> {
+        let handle = match *self.handles.get(&id).ok_or(Error::new(EBADF))? {
+            Handle::Device { seek } => Handle::Device {
+                seek: seek
+            },
+            Handle::Stats { ref data, seek } => Handle::Stats {
+                data: data.clone(),
+                seek: seek
+            }
+        };
+
+        let new_id = self.next_id;
+        self.next_id += 1;
+        self.handles.insert(new_id, handle);
+        Ok(new_id)
+    }
+
+    fn read(&mut self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let offset = match *self.handles.get_mut(&id).ok_or(Error::new(EBADF))? {
+            Handle::Device { seek } => seek,
+            Handle::Stats { ref data, ref mut seek } => {
+                let count = cmp::min(buf.len(), data.len() - *seek);
+                buf[.. count].copy_from_slice(&data[*seek .. *seek + count]);
+                *seek += count;
+                return Ok(count);
+            }
+        };
+
+        let count = self.read_device(offset, buf)?;
+        if let Some(&mut Handle::Device { ref mut seek }) = self.handles.get_mut(&id) {
+            *seek += count;
+        }
+        Ok(count)
+    }
+
+    fn write(&mut self, id: usize, buf: &[u8]) -> Result<usize> {
+        let offset = match *self.handles.get(&id).ok_or(Error::new(EBADF))? {
+            Handle::Device { seek } => seek,
+            Handle::Stats { .. } => return Err(Error::new(EBADF))
+        };
+
+        let count = self.write_device(offset, buf)?;
+        if let Some(&mut Handle::Device { ref mut seek }) = self.handles.get_mut(&id) {
+            *seek += count;
+        }
+        Ok(count)
+    }
+
+    fn seek(&mut self, id: usize, pos: usize, whence: usize) -> Result<usize> {
+        let size = self.size();
+        let (len, seek) = match *self.handles.get_mut(&id).ok_or(Error::new(EBADF))? {
+            Handle::Device { ref mut seek } => (size, seek),
+            Handle::Stats { ref data, ref mut seek } => (data.len(), seek)
+        };
+
+        *seek = match whence {
+            SEEK_SET => cmp::min(len, pos),
+            SEEK_CUR => cmp::max(0, cmp::min(len as isize, *seek as isize + pos as isize)) as usize,
+            SEEK_END => cmp::max(0, cmp::min(len as isize, len as isize + pos as isize)) as usize,
+            _ => return Err(Error::new(EINVAL))
+        };
+
+        Ok(*seek)
+    }
+
+    fn fpath(&mut self, id: usize, buf: &mut [u8]) -> Result<usize> {
+        let path: &[u8] = match *self.handles.get(&id).ok_or(Error::new(EBADF))? {
+            Handle::Device { .. } => b"zram:",
+            Handle::Stats { .. } => b"zram:stats"
+        };
+
+        let count = cmp::min(buf.len(), path.len());
+        buf[.. count].copy_from_slice(&path[.. count]);
+        Ok(count)
+    }
+
+    fn fstat(&mut self, id: usize, stat: &mut Stat) -> Result<usize> {
+        let size = self.size();
+        match *self.handles.get(&id).ok_or(Error::new(EBADF))? {
+            Handle::Device { .. } => {
+                stat.st_mode = MODE_FILE | 0o600;
+                stat.st_size = size as u64;
+            },
+            Handle::Stats { ref data, .. } => {
+                stat.st_mode = MODE_FILE | 0o444;
+                stat.st_size = data.len() as u64;
+            }
+        }
+        Ok(0)
+    }
+
+    fn fsync(&mut self, id: usize) -> Result<usize> {
+        self.handles.get(&id).ok_or(Error::new(EBADF)).and(Ok(0))
+    }
+
+    fn close(&mut self, id: usize) -> Result<usize> {
+        self.handles.remove(&id).ok_or(Error::new(EBADF)).and(Ok(0))
+    }
+}